@@ -7,13 +7,19 @@ use std::os::unix::io::AsRawFd;
 use std::sync::Mutex;
 use std::time::Duration;
 use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+use vmsh::kvm::hypervisor::userfaultfd::{PageFault, PageFaultHandler};
 use vmsh::kvm::hypervisor::{get_hypervisor, memory::PhysMem};
 use vmsh::kvm::kvm_ioregionfd::{self, Cmd};
+use vmsh::page_math::page_size;
 use vmsh::result::Result;
 use vmsh::tracer::wrap_syscall::KvmRunWrapper;
 
 fn inject(pid: Pid) -> Result<()> {
-    let vm = try_with!(get_hypervisor(pid), "cannot get vms for process {}", pid);
+    let vm = try_with!(
+        get_hypervisor(pid, None),
+        "cannot get vms for process {}",
+        pid
+    );
 
     print!("check_extensions");
     for _ in 1..100 {
@@ -43,7 +49,11 @@ fn inject(pid: Pid) -> Result<()> {
 }
 
 fn alloc_mem(pid: Pid) -> Result<()> {
-    let vm = try_with!(get_hypervisor(pid), "cannot get vms for process {}", pid);
+    let vm = try_with!(
+        get_hypervisor(pid, None),
+        "cannot get vms for process {}",
+        pid
+    );
 
     vm.stop()?;
     let mem = try_with!(vm.alloc_mem::<u32>(), "mmap failed");
@@ -59,7 +69,11 @@ fn guest_add_mem(pid: Pid, re_get_slots: bool) -> Result<()> {
     let memslots_a_len;
 
     {
-        let vm = try_with!(get_hypervisor(pid), "cannot get vms for process {}", pid);
+        let vm = try_with!(
+            get_hypervisor(pid, None),
+            "cannot get vms for process {}",
+            pid
+        );
         vm.stop()?;
 
         // count memslots
@@ -92,7 +106,11 @@ fn guest_add_mem(pid: Pid, re_get_slots: bool) -> Result<()> {
     }
 
     // VmMem is out of scope and should thus have removed the memory again.
-    let vm = try_with!(get_hypervisor(pid), "cannot get vms for process {}", pid);
+    let vm = try_with!(
+        get_hypervisor(pid, None),
+        "cannot get vms for process {}",
+        pid
+    );
     vm.stop()?;
 
     if re_get_slots {
@@ -112,7 +130,11 @@ fn guest_add_mem(pid: Pid, re_get_slots: bool) -> Result<()> {
 fn fd_transfer(pid: Pid) -> Result<()> {
     use std::path::Path;
 
-    let mut vm = try_with!(get_hypervisor(pid), "cannot get vms for process {}", pid);
+    let mut vm = try_with!(
+        get_hypervisor(pid, None),
+        "cannot get vms for process {}",
+        pid
+    );
     vm.stop()?;
     try_with!(
         vm.setup_transfer_sockets(),
@@ -143,7 +165,11 @@ fn fd_transfer(pid: Pid) -> Result<()> {
 }
 
 fn cpuid2(pid: Pid) -> Result<()> {
-    let vm = try_with!(get_hypervisor(pid), "cannot get vms for process {}", pid);
+    let vm = try_with!(
+        get_hypervisor(pid, None),
+        "cannot get vms for process {}",
+        pid
+    );
     vm.stop()?;
 
     let cpuid2 = try_with!(vm.get_cpuid2(&vm.vcpus[0]), "cannot get cpuid2");
@@ -156,29 +182,47 @@ fn cpuid2(pid: Pid) -> Result<()> {
     Ok(())
 }
 
-/// Some parts of this implementation are still missing.
+struct Pattern(u8);
+
+impl PageFaultHandler for Pattern {
+    fn fault(&mut self, fault: &PageFault, page: &mut [u8]) -> Result<bool> {
+        println!(
+            "page fault at {:#x} (write: {})",
+            fault.addr, fault.is_write
+        );
+        page.fill(self.0);
+        Ok(true)
+    }
+}
+
 fn guest_userfaultfd(pid: Pid) -> Result<()> {
-    let vm = try_with!(get_hypervisor(pid), "cannot get vms for process {}", pid);
+    let vm = try_with!(
+        get_hypervisor(pid, None),
+        "cannot get vms for process {}",
+        pid
+    );
     vm.stop()?;
 
     let vm_mem = vm.vm_add_mem::<u64>(0xd0000000, size_of::<u64>(), true)?;
-    vm_mem.mem.write(&0xdeadbeef)?;
-    assert_eq!(vm_mem.mem.read()?, 0xdeadbeef);
-
-    // register userfaultfd which always returns something else
-    vm.userfaultfd()?;
+    // the memory is not populated yet, the first access is served by us
+    let mut uffd = vm.userfaultfd()?;
+    uffd.register(vm_mem.mem.ptr, page_size())?;
 
     vm.resume()?;
 
     println!("pause");
-    nix::unistd::pause();
-    // pytest shall now check that the memory does not contain 0xdeadbeef on read
-
-    Ok(())
+    // pytest shall now check that the memory contains 0x1313131313131313 on read
+    loop {
+        uffd.handle_faults(&mut Pattern(0x13), 300)?;
+    }
 }
 
 fn guest_ioeventfd(pid: Pid) -> Result<()> {
-    let vm = try_with!(get_hypervisor(pid), "cannot get vms for process {}", pid);
+    let vm = try_with!(
+        get_hypervisor(pid, None),
+        "cannot get vms for process {}",
+        pid
+    );
     vm.stop()?;
 
     let has_cap = try_with!(
@@ -224,7 +268,11 @@ fn guest_ioeventfd(pid: Pid) -> Result<()> {
 }
 
 fn ioregionfd(pid: Pid) -> Result<()> {
-    let vm = try_with!(get_hypervisor(pid), "cannot get vms for process {}", pid);
+    let vm = try_with!(
+        get_hypervisor(pid, None),
+        "cannot get vms for process {}",
+        pid
+    );
     vm.stop()?;
 
     let has_cap = try_with!(
@@ -258,7 +306,8 @@ fn ioregionfd(pid: Pid) -> Result<()> {
         );
         match cmd.info.cmd() {
             Cmd::Read => rawiorefd.write(0xFF)?,
-            Cmd::Write => rawiorefd.write(0)?,
+            Cmd::Write if !rawiorefd.ioregion.posted_writes() => rawiorefd.write(0)?,
+            Cmd::Write => {}
         };
     }
 
@@ -267,7 +316,11 @@ fn ioregionfd(pid: Pid) -> Result<()> {
 }
 
 fn guest_kvm_exits(pid: Pid) -> Result<()> {
-    let vm = try_with!(get_hypervisor(pid), "cannot get vms for process {}", pid);
+    let vm = try_with!(
+        get_hypervisor(pid, None),
+        "cannot get vms for process {}",
+        pid
+    );
     vm.kvmrun_wrapped(|wrapper_r: &Mutex<Option<KvmRunWrapper>>| {
         let mut wrapper_go = wrapper_r.lock().unwrap();
         let wrapper = wrapper_go.as_mut().unwrap();
@@ -290,7 +343,11 @@ fn guest_kvm_exits(pid: Pid) -> Result<()> {
 }
 
 fn vcpu_maps(pid: Pid) -> Result<()> {
-    let vm = try_with!(get_hypervisor(pid), "cannot get vms for process {}", pid);
+    let vm = try_with!(
+        get_hypervisor(pid, None),
+        "cannot get vms for process {}",
+        pid
+    );
     vm.stop()?;
 
     let kvm_run_len = size_of::<kvm_bindings::kvm_run>();
@@ -116,7 +116,8 @@
             }).json;
 
             inherit (inputs'.microvm.packages)
-              firecracker-example crosvm-example kvmtool-example qemu-example;
+              firecracker-example crosvm-example kvmtool-example qemu-example
+              cloud-hypervisor-example;
 
             # see justfile/nixos-image
             nixos-image = pkgs.callPackage ./nix/nixos-image.nix { };
@@ -0,0 +1,382 @@
+//! What vmsh needs from an architecture. The ioctls to read and write vcpu registers
+//! (`get_regs`, `set_regs`, `get_fpu_regs`) and the relocations of the stage1 library
+//! (`relocation`) live in this module, the other pieces in a `mod arch` of the module that uses
+//! them, selected with `cfg(target_arch)`:
+//!
+//! - `cpu`: `Regs` as returned by ptrace (implementing `Registers`), `FpuRegs`, the syscall
+//!   instruction (`SYSCALL_TEXT`, `SYSCALL_SIZE`) and `BATCH_TEXT`
+//! - `page_table`: the format of page table entries
+//! - stage1 and stage2, which are built for the guest
+//!
+//! x86_64 and aarch64 implement this module. For powerpc64 and s390x it only has
+//! `unimplemented!()` stubs, building for them lists what else is missing.
+
+use elfloader::RelocationType;
+
+use crate::cpu::{FpuRegs, Regs};
+use crate::kvm::hypervisor::{Hypervisor, VCPU};
+use crate::result::Result;
+
+/// Registers of a thread of the hypervisor, used to inject syscalls and to find where stage1
+/// returns to.
+pub trait Registers: Copy {
+    /// true if the cpu executes userspace code
+    fn is_userspace(&self) -> bool;
+
+    fn set_ip(&mut self, ip: u64);
+
+    fn ip(&self) -> u64;
+
+    /// Registers to run `BATCH_TEXT` at `ip` on `count` entries of the table at `table`.
+    fn prepare_batch(&self, ip: u64, table: u64, count: u64) -> Self;
+
+    /// Registers to run the syscall `args[0]` with the arguments `args[1..]`.
+    fn prepare_syscall(&self, args: &[u64; 7]) -> Self;
+
+    /// Syscall number set by `prepare_syscall`
+    fn syscall_nr(&self) -> u64;
+
+    fn syscall_ret(&self) -> u64;
+
+    fn set_syscall_ret(&mut self, ret: u64);
+
+    /// To be used during wrap_syscall.
+    /// return (syscall_nr, arg1, ..., arg6)
+    fn get_syscall_params(&self) -> (u64, u64, u64, u64, u64, u64, u64);
+}
+
+/// Relocations of the stage1 library, independent of the architecture
+pub enum Relocation {
+    /// base address + addend
+    Relative,
+    /// symbol address + addend
+    Symbol,
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86_64 {
+    use elfloader::arch::x86_64::RelocationTypes;
+    use elfloader::RelocationType;
+    use kvm_bindings as kvmb;
+    use std::ptr;
+
+    use super::Relocation;
+    use crate::cpu::{FpuRegs, Regs};
+    use crate::kvm::hypervisor::{Hypervisor, VCPU};
+    use crate::kvm::ioctls;
+    use crate::result::Result;
+
+    pub fn relocation(rtype: &RelocationType) -> Option<Relocation> {
+        match rtype {
+            RelocationType::x86_64(RelocationTypes::R_AMD64_RELATIVE) => Some(Relocation::Relative),
+            RelocationType::x86_64(RelocationTypes::R_AMD64_GLOB_DAT) => Some(Relocation::Symbol),
+            _ => None,
+        }
+    }
+
+    pub fn get_regs(hv: &Hypervisor, vcpu: &VCPU) -> Result<Regs> {
+        let (_, regs) = hv.vcpu_ioctl(vcpu, ioctls::KVM_GET_REGS(), &kvmb::kvm_regs::default())?;
+        Ok(Regs {
+            r15: regs.r15,
+            r14: regs.r14,
+            r13: regs.r13,
+            r12: regs.r12,
+            rbp: regs.rbp,
+            rbx: regs.rbx,
+            r11: regs.r11,
+            r10: regs.r10,
+            r9: regs.r9,
+            r8: regs.r8,
+            rax: regs.rax,
+            rcx: regs.rcx,
+            rdx: regs.rdx,
+            rsi: regs.rsi,
+            rdi: regs.rdi,
+            orig_rax: regs.rax,
+            rip: regs.rip,
+            cs: 0,
+            eflags: regs.rflags,
+            rsp: regs.rsp,
+            ss: 0,
+            fs_base: 0,
+            gs_base: 0,
+            ds: 0,
+            es: 0,
+            fs: 0,
+            gs: 0,
+        })
+    }
+
+    pub fn set_regs(hv: &Hypervisor, vcpu: &VCPU, regs: &Regs) -> Result<()> {
+        let regs = kvmb::kvm_regs {
+            rax: regs.rax,
+            rbx: regs.rbx,
+            rcx: regs.rcx,
+            rdx: regs.rdx,
+            rsi: regs.rsi,
+            rdi: regs.rdi,
+            rsp: regs.rsp,
+            rbp: regs.rbp,
+            r8: regs.r8,
+            r9: regs.r9,
+            r10: regs.r10,
+            r11: regs.r11,
+            r12: regs.r12,
+            r13: regs.r13,
+            r14: regs.r14,
+            r15: regs.r15,
+            rip: regs.rip,
+            rflags: regs.eflags,
+        };
+        hv.vcpu_ioctl(vcpu, ioctls::KVM_SET_REGS(), &regs)?;
+        Ok(())
+    }
+
+    pub fn get_fpu_regs(hv: &Hypervisor, vcpu: &VCPU) -> Result<FpuRegs> {
+        let (_, regs) = hv.vcpu_ioctl(vcpu, ioctls::KVM_GET_FPU(), &kvmb::kvm_fpu::default())?;
+        let st_space = unsafe { ptr::read(&regs.fpr as *const [u8; 16] as *const [u32; 32]) };
+        let xmm_space =
+            unsafe { ptr::read(&regs.xmm as *const [[u8; 16]; 16] as *const [u32; 64]) };
+
+        Ok(FpuRegs {
+            cwd: regs.fcw,
+            swd: regs.fsw,
+            twd: regs.ftwx as u16,
+            fop: regs.last_opcode,
+            rip: regs.last_ip,
+            rdp: regs.last_dp,
+            mxcsr: regs.mxcsr,
+            mxcsr_mask: 0,
+            st_space,
+            xmm_space,
+            padding: [0; 12],
+            padding1: [0; 12],
+        })
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+use self::x86_64 as imp;
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64 {
+    use elfloader::arch::aarch64::RelocationTypes;
+    use elfloader::RelocationType;
+    use kvm_bindings as kvmb;
+
+    use super::Relocation;
+    use crate::cpu::{FpuRegs, Regs};
+    use crate::kvm::hypervisor::memory::HvMem;
+    use crate::kvm::hypervisor::{Hypervisor, VCPU};
+    use crate::kvm::ioctls;
+    use crate::result::Result;
+
+    // KVM_REG_ARM64 | KVM_REG_ARM_CORE, ORed with the size and the offset of the register in
+    // struct kvm_regs in 32-bit words
+    const KVM_REG_ARM_CORE: u64 = 0x6000_0000_0010_0000;
+    const KVM_REG_SIZE_U32: u64 = 0x0020_0000_0000_0000;
+    const KVM_REG_SIZE_U64: u64 = 0x0030_0000_0000_0000;
+    const KVM_REG_SIZE_U128: u64 = 0x0040_0000_0000_0000;
+
+    // offsets in struct kvm_regs
+    const SP: usize = 31 * 8;
+    const PC: usize = 32 * 8;
+    const PSTATE: usize = 33 * 8;
+    const VREGS: usize = 336;
+    const FPSR: usize = VREGS + 32 * 16;
+    const FPCR: usize = FPSR + 4;
+
+    fn core_reg(size: u64, offset: usize) -> u64 {
+        KVM_REG_ARM_CORE | size | (offset / 4) as u64
+    }
+
+    /// Reads the register `id` through `value`, which is large enough for all of them.
+    fn get_one_reg(hv: &Hypervisor, vcpu: &VCPU, value: &HvMem<u128>, id: u64) -> Result<u128> {
+        let reg = kvmb::kvm_one_reg {
+            id,
+            addr: value.ptr as u64,
+        };
+        hv.vcpu_ioctl(vcpu, ioctls::KVM_GET_ONE_REG(), &reg)?;
+        // smaller registers are written to the low bytes
+        value.read()
+    }
+
+    fn set_one_reg(
+        hv: &Hypervisor,
+        vcpu: &VCPU,
+        value: &HvMem<u128>,
+        id: u64,
+        v: u128,
+    ) -> Result<()> {
+        value.write(&v)?;
+        let reg = kvmb::kvm_one_reg {
+            id,
+            addr: value.ptr as u64,
+        };
+        hv.vcpu_ioctl(vcpu, ioctls::KVM_SET_ONE_REG(), &reg)?;
+        Ok(())
+    }
+
+    pub fn relocation(rtype: &RelocationType) -> Option<Relocation> {
+        match rtype {
+            RelocationType::AArch64(RelocationTypes::R_AARCH64_RELATIVE) => {
+                Some(Relocation::Relative)
+            }
+            RelocationType::AArch64(RelocationTypes::R_AARCH64_GLOB_DAT)
+            | RelocationType::AArch64(RelocationTypes::R_AARCH64_JUMP_SLOT)
+            | RelocationType::AArch64(RelocationTypes::R_AARCH64_ABS64) => Some(Relocation::Symbol),
+            _ => None,
+        }
+    }
+
+    pub fn get_regs(hv: &Hypervisor, vcpu: &VCPU) -> Result<Regs> {
+        let value = hv.alloc_mem::<u128>()?;
+        let get = |offset: usize| {
+            get_one_reg(hv, vcpu, &value, core_reg(KVM_REG_SIZE_U64, offset)).map(|v| v as u64)
+        };
+        let mut regs = Regs::default();
+        for (i, reg) in regs.regs.iter_mut().enumerate() {
+            *reg = get(i * 8)?;
+        }
+        regs.sp = get(SP)?;
+        regs.pc = get(PC)?;
+        regs.pstate = get(PSTATE)?;
+        Ok(regs)
+    }
+
+    pub fn set_regs(hv: &Hypervisor, vcpu: &VCPU, regs: &Regs) -> Result<()> {
+        let value = hv.alloc_mem::<u128>()?;
+        let set = |offset: usize, v: u64| {
+            set_one_reg(
+                hv,
+                vcpu,
+                &value,
+                core_reg(KVM_REG_SIZE_U64, offset),
+                v as u128,
+            )
+        };
+        for (i, reg) in regs.regs.iter().enumerate() {
+            set(i * 8, *reg)?;
+        }
+        set(SP, regs.sp)?;
+        set(PC, regs.pc)?;
+        set(PSTATE, regs.pstate)
+    }
+
+    pub fn get_fpu_regs(hv: &Hypervisor, vcpu: &VCPU) -> Result<FpuRegs> {
+        let value = hv.alloc_mem::<u128>()?;
+        let mut vregs = [0; 32];
+        for (i, reg) in vregs.iter_mut().enumerate() {
+            *reg = get_one_reg(
+                hv,
+                vcpu,
+                &value,
+                core_reg(KVM_REG_SIZE_U128, VREGS + i * 16),
+            )?;
+        }
+        let fpsr = get_one_reg(hv, vcpu, &value, core_reg(KVM_REG_SIZE_U32, FPSR))? as u32;
+        let fpcr = get_one_reg(hv, vcpu, &value, core_reg(KVM_REG_SIZE_U32, FPCR))? as u32;
+        Ok(FpuRegs { vregs, fpsr, fpcr })
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+use self::aarch64 as imp;
+
+#[cfg(target_arch = "powerpc64")]
+compile_error!(
+    "vmsh does not support powerpc64 yet. Missing: cpu::Regs for struct pt_regs, \
+     cpu::SYSCALL_TEXT (sc), cpu::BATCH_TEXT, page_table::arch for radix page tables, \
+     the stubs in src/arch.rs, stage1 and stage2 builds"
+);
+
+#[cfg(target_arch = "powerpc64")]
+mod powerpc64 {
+    use elfloader::RelocationType;
+
+    use super::Relocation;
+    use crate::cpu::{FpuRegs, Regs};
+    use crate::kvm::hypervisor::{Hypervisor, VCPU};
+    use crate::result::Result;
+
+    pub fn relocation(_rtype: &RelocationType) -> Option<Relocation> {
+        unimplemented!("R_PPC64_RELATIVE and R_PPC64_GLOB_DAT relocations")
+    }
+
+    pub fn get_regs(_hv: &Hypervisor, _vcpu: &VCPU) -> Result<Regs> {
+        unimplemented!("KVM_GET_REGS of powerpc64")
+    }
+
+    pub fn set_regs(_hv: &Hypervisor, _vcpu: &VCPU, _regs: &Regs) -> Result<()> {
+        unimplemented!("KVM_SET_REGS of powerpc64")
+    }
+
+    pub fn get_fpu_regs(_hv: &Hypervisor, _vcpu: &VCPU) -> Result<FpuRegs> {
+        unimplemented!("KVM_GET_FPU of powerpc64")
+    }
+}
+
+#[cfg(target_arch = "powerpc64")]
+use self::powerpc64 as imp;
+
+#[cfg(target_arch = "s390x")]
+compile_error!(
+    "vmsh does not support s390x yet. Missing: cpu::Regs for s390_regs, \
+     cpu::SYSCALL_TEXT (svc 0), cpu::BATCH_TEXT, page_table::arch for DAT tables, \
+     the stubs in src/arch.rs, stage1 and stage2 builds"
+);
+
+#[cfg(target_arch = "s390x")]
+mod s390x {
+    use elfloader::RelocationType;
+
+    use super::Relocation;
+    use crate::cpu::{FpuRegs, Regs};
+    use crate::kvm::hypervisor::{Hypervisor, VCPU};
+    use crate::result::Result;
+
+    pub fn relocation(_rtype: &RelocationType) -> Option<Relocation> {
+        unimplemented!("R_390_RELATIVE and R_390_GLOB_DAT relocations")
+    }
+
+    pub fn get_regs(_hv: &Hypervisor, _vcpu: &VCPU) -> Result<Regs> {
+        unimplemented!("KVM_GET_REGS and KVM_GET_SREGS of s390x")
+    }
+
+    pub fn set_regs(_hv: &Hypervisor, _vcpu: &VCPU, _regs: &Regs) -> Result<()> {
+        unimplemented!("KVM_SET_REGS and KVM_SET_SREGS of s390x")
+    }
+
+    pub fn get_fpu_regs(_hv: &Hypervisor, _vcpu: &VCPU) -> Result<FpuRegs> {
+        unimplemented!("KVM_GET_FPU of s390x")
+    }
+}
+
+#[cfg(target_arch = "s390x")]
+use self::s390x as imp;
+
+/// How the loader applies a relocation of type `rtype`, None if it is not supported.
+pub fn relocation(rtype: &RelocationType) -> Option<Relocation> {
+    imp::relocation(rtype)
+}
+
+/// The general purpose registers of `vcpu`, in the format ptrace uses for the hypervisor.
+pub fn get_regs(hv: &Hypervisor, vcpu: &VCPU) -> Result<Regs> {
+    imp::get_regs(hv, vcpu)
+}
+
+pub fn set_regs(hv: &Hypervisor, vcpu: &VCPU, regs: &Regs) -> Result<()> {
+    imp::set_regs(hv, vcpu, regs)
+}
+
+pub fn get_fpu_regs(hv: &Hypervisor, vcpu: &VCPU) -> Result<FpuRegs> {
+    imp::get_fpu_regs(hv, vcpu)
+}
+
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "powerpc64",
+    target_arch = "s390x"
+)))]
+compile_error!("vmsh only supports x86_64 and aarch64, see src/arch.rs to add an architecture");
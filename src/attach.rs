@@ -1,110 +1,639 @@
-use log::{error, info};
+use log::{error, info, warn};
 use nix::unistd::Pid;
-use simple_error::{require_with, try_with};
-use std::fs::read_to_string;
+use simple_error::{bail, require_with, simple_error, try_with};
+use stage1_interface::{DeviceState, MAX_DEVICES};
+use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::sync::mpsc::channel;
-use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Weak};
 use std::time::Duration;
 
-use crate::devices::use_ioregionfd;
-use crate::devices::DeviceSet;
-use crate::result::Result;
-use crate::stage1::Stage1;
+use crate::boot;
+use crate::devices::virtio::pci::Transport;
+use crate::devices::MmioTransport;
+use crate::devices::{DeviceContext, DeviceOptions, DeviceSet, ShareMode};
+use crate::forward::{self, PortForward};
+use crate::guest_mem::{GuestMem, KernelMem};
+use crate::kernel::{find_kernel, Lockdown};
+use crate::kvm::hypervisor::flavor::{self, Flavor};
+use crate::kvm::hypervisor::ioregionfd::IoRegionFd;
+use crate::kvm::hypervisor::{Hypervisor, VmSelector};
+use crate::metrics;
+use crate::oci;
+use crate::page_table::VirtMem;
+use crate::progress::Progress;
+use crate::result::{Result, VmshError};
+use crate::seccomp;
+use crate::session::Session;
+use crate::stage1::{self, Stage1};
+use crate::watchdog::{self, Failure};
 use crate::{kvm, signal_handler};
 
 pub struct AttachOptions {
     pub pid: Pid,
+    /// Required if the hypervisor runs more than one VM.
+    pub vm: Option<VmSelector>,
+    /// stage2 path followed by the command and its arguments
     pub command: Vec<String>,
+    /// Paths stage2 is written to if writing to the first path fails.
+    pub stage2_fallback_paths: Vec<String>,
+    /// `KEY=VALUE` pairs added to the environment of the command.
+    pub env: Vec<String>,
+    /// Working directory of the command in the guest.
+    pub workdir: Option<PathBuf>,
+    /// Run the command as this user (name or uid, optionally `:group`) instead of the user of
+    /// the target process.
+    pub user: Option<String>,
+    /// Namespaces of the target process the command does not join, i.e. `net`.
+    pub skip_namespaces: Vec<String>,
+    /// Cgroup the command is moved to instead of the ones of the target process.
+    pub cgroup: Option<String>,
+    /// OCI seccomp profile applied to the command.
+    pub seccomp: Option<PathBuf>,
     pub backing: PathBuf,
+    pub share_mode: ShareMode,
     pub pts: Option<PathBuf>,
+    /// Record the console session as asciinema cast.
+    pub record: Option<PathBuf>,
+    /// Pseudoterminals connected to additional ports of the console.
+    pub console_ports: Vec<PathBuf>,
+    /// Bytes of console output replayed to consoles connecting later.
+    pub scrollback: usize,
+    /// Host tap device to back a network device in the VM.
+    pub tap: Option<String>,
+    /// Forward guest vsock connections to unix sockets with this path prefix.
+    pub vsock: Option<PathBuf>,
+    /// Context id of the guest on the vsock device.
+    pub vsock_cid: u64,
+    /// Host ports forwarded into the guest, requires `vsock`.
+    pub forwards: Vec<PortForward>,
+    /// Provide an entropy device to the VM.
+    pub rng: bool,
+    /// Expose the devices over virtio-mmio or virtio-pci.
+    pub transport: Transport,
+    pub mmio_transport: MmioTransport,
+    /// Number of request queues of the block device.
+    pub blk_queues: u16,
+    /// Number of descriptors in each virtio queue.
+    pub queue_size: u16,
+    /// Additional files served as block devices.
+    pub disks: Vec<PathBuf>,
+    /// Files exposed read-only to the guest as virtio-pmem devices.
+    pub pmem: Vec<PathBuf>,
+    /// Number of block devices that can be added while attached.
+    pub hotplug_slots: usize,
+    /// Discard writes to block devices on detach instead of modifying the backing files.
+    pub snapshot: bool,
+    /// Serve the counters of `metrics` over HTTP on this address.
+    pub metrics_addr: Option<SocketAddr>,
+    /// Hypervisor running the VM, detected from the process if not given.
+    pub hypervisor: Option<Flavor>,
+    /// Kill the command in the guest when vmsh stops, requires `vsock`.
+    pub kill_on_detach: bool,
+    /// Wait until the guest booted before loading stage1, i.e. for VMs that did not start yet.
+    pub wait_for_boot: bool,
+    /// Load stage1 even if the guest kernel is locked down.
+    pub ignore_lockdown: bool,
+    /// Write how long each phase of the attach took as JSON to this file.
+    pub timing_out: Option<PathBuf>,
 }
 
-pub fn get_irq_num(pid: Pid) -> Result<usize> {
-    let mut comm_path = PathBuf::from("/proc");
-    comm_path.push(pid.as_raw().to_string());
-    comm_path.push("comm");
-    let comm = try_with!(
-        read_to_string(&comm_path),
-        "failed to read {}",
-        comm_path.display()
-    );
-    // dirty hack until we have a better way to find out what IRQs we can use
-    if comm.contains("crosvm") {
-        Ok(4)
-    } else {
-        Ok(6)
+impl AttachOptions {
+    /// Command line of stage2: options for the command are passed before it.
+    fn stage2_argv(&self) -> Result<Vec<String>> {
+        let (stage2, command) = match self.command.split_first() {
+            Some(split) => split,
+            None => return Ok(vec![]),
+        };
+        let mut argv = vec![stage2.clone()];
+        for var in &self.env {
+            argv.push("--env".into());
+            argv.push(var.clone());
+        }
+        if let Some(workdir) = &self.workdir {
+            argv.push("--workdir".into());
+            argv.push(workdir.display().to_string());
+        }
+        if let Some(user) = &self.user {
+            argv.push("--user".into());
+            argv.push(user.clone());
+        }
+        for ns in &self.skip_namespaces {
+            argv.push("--skip-namespace".into());
+            argv.push(ns.clone());
+        }
+        if let Some(cgroup) = &self.cgroup {
+            argv.push("--cgroup".into());
+            argv.push(cgroup.clone());
+        }
+        if let Some(profile) = &self.seccomp {
+            argv.push("--seccomp".into());
+            argv.push(seccomp::compile_file(profile)?);
+        }
+        argv.push("--".into());
+        argv.extend(command.iter().cloned());
+        Ok(argv)
     }
 }
 
-pub fn attach(opts: &AttachOptions) -> Result<()> {
-    info!("attaching");
+/// How long `--kill-on-detach` waits for the command to exit before the devices are removed.
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// ISA interrupt lines we may take, in order of preference. Lines of legacy devices that are
+/// commonly emulated (timer, keyboard, cascade, rtc, mouse, fpu, ide, acpi) are left out.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const IRQ_CANDIDATES: [usize; 6] = [5, 6, 7, 10, 11, 3];
 
+/// Lines of the IOAPIC the guest has not unmasked, i.e. that have no driver attached.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn free_irq_nums(vm: &Hypervisor) -> Result<Vec<usize>> {
+    // interrupt mask bit of a redirection table entry
+    const IOAPIC_MASKED: u64 = 1 << 16;
+    let ioapic = try_with!(vm.get_irqchip(2), "cannot get ioapic state");
+    let ioapic = unsafe { ioapic.chip.ioapic };
+    Ok(IRQ_CANDIDATES
+        .iter()
+        .copied()
+        .filter(|&irq| unsafe { ioapic.redirtbl[irq].bits } & IOAPIC_MASKED != 0)
+        .collect())
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn free_irq_nums(_vm: &Hypervisor) -> Result<Vec<usize>> {
+    bail!("not implemented for this architecture")
+}
+
+/// Interrupt line for each device slot of stage1. Devices share lines if the guest has fewer
+/// free lines than we have devices.
+pub fn get_irq_nums(vm: &Hypervisor) -> Result<Vec<usize>> {
+    let free = match free_irq_nums(vm) {
+        Ok(free) if !free.is_empty() => free,
+        Ok(_) => {
+            warn!("guest has no free interrupt line, share one with another device");
+            vec![Flavor::detect(vm.pid).fallback_irq_num()]
+        }
+        Err(e) => {
+            warn!("cannot determine free interrupt lines: {}", e);
+            vec![Flavor::detect(vm.pid).fallback_irq_num()]
+        }
+    };
+    info!("use interrupt lines {:?}", free);
+    Ok(free.iter().copied().cycle().take(MAX_DEVICES).collect())
+}
+
+/// Attaches to the VM until vmsh is stopped. With SIGUSR1 (`vmsh detach`) vmsh leaves the
+/// devices and stage2 running in the guest; the next `attach` to the same VM takes them over.
+///
+/// With `vsock`, vmsh also stops once the command exited and returns its exit code.
+pub fn attach(opts: &AttachOptions) -> Result<Option<i32>> {
     let (sender, receiver) = channel();
 
-    signal_handler::setup(sender.clone());
+    signal_handler::setup_detachable(sender.clone());
+    // listen before stage2 starts
+    let _signal_channel = match &opts.vsock {
+        Some(vsock) => Some(signal_handler::listen(vsock, sender.clone())?),
+        None if opts.kill_on_detach => bail!("--kill-on-detach requires a vsock device"),
+        None => None,
+    };
 
-    let mut vm = try_with!(
-        kvm::hypervisor::get_hypervisor(opts.pid),
-        "cannot get vms for process {}",
+    attach_session(opts, sender, receiver, |_| {}, true)?;
+    Ok(signal_handler::exit_code())
+}
+
+/// Like `attach`, but instead of waiting for SIGTERM/SIGINT detaches as soon as
+/// a message is received on `receiver`. `started` is called with the devices once they
+/// are running, i.e. to add or remove block devices at runtime.
+pub fn attach_until(
+    opts: &AttachOptions,
+    sender: Sender<()>,
+    receiver: Receiver<()>,
+    started: impl FnOnce(Weak<DeviceContext>),
+) -> Result<()> {
+    attach_session(opts, sender, receiver, started, false)
+}
+
+/// Decides per VM whether mmio is served with ioregionfd or by intercepting mmio exits.
+fn select_mmio_transport(vm: &Hypervisor, transport: MmioTransport) -> Result<()> {
+    let ioregionfd = match transport {
+        MmioTransport::WrapSyscall => false,
+        MmioTransport::IoRegionFd => {
+            if !IoRegionFd::capability_present(vm)? {
+                bail!("ioregionfd was requested, but KVM does not have KVM_CAP_IOREGIONFD");
+            }
+            true
+        }
+        MmioTransport::Auto => IoRegionFd::capability_present(vm)?,
+    };
+    if ioregionfd {
+        info!("serve mmio with ioregionfd");
+    } else {
+        info!("serve mmio by intercepting mmio exits");
+    }
+    vm.set_use_ioregionfd(ioregionfd);
+    Ok(())
+}
+
+/// A session that vmsh detached from earlier, if any
+fn detached_session(pid: Pid, device_opts: &DeviceOptions) -> Result<Option<Session>> {
+    let session = match Session::load(pid)? {
+        Some(session) => session,
+        None => return Ok(None),
+    };
+    if session.attached() {
+        bail!(
+            "process {} is already attached by vmsh process {}",
+            pid,
+            session.vmsh_pid
+        );
+    }
+    if session.vmsh_pid != 0 {
+        warn!(
+            "vmsh process {} exited without detaching, ignore its session",
+            session.vmsh_pid
+        );
+        Session::remove(pid)?;
+        return Ok(None);
+    }
+    if !device_opts.detachable() {
+        bail!(
+            "cannot re-attach to the detached session with the pci transport, hotplug slots \
+             or pmem devices"
+        );
+    }
+    info!("re-attaching to detached session");
+    Ok(Some(session))
+}
+
+type Undo = Box<dyn FnOnce() -> Result<()>>;
+
+/// Changes an attach made to the VM so far. If the attach fails before the devices run, they
+/// are undone in reverse order when the rollback is dropped, so the guest continues as if vmsh
+/// never was there.
+///
+/// Ioeventfds and memory of the devices are owned by `DeviceSet`, which removes them when
+/// dropped. It is created after the rollback and therefore dropped before it, while the
+/// hypervisor is still stopped.
+struct Rollback {
+    steps: Vec<(&'static str, Undo)>,
+    /// Step after which the attach fails, to test undoing it
+    fail_at: Option<String>,
+}
+
+impl Rollback {
+    /// Debug builds fail after the step named in `VMSH_FAIL_AT`, see tests/test_attach.py.
+    fn new() -> Rollback {
+        Rollback {
+            steps: vec![],
+            fail_at: if cfg!(debug_assertions) {
+                std::env::var("VMSH_FAIL_AT").ok()
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Records how to undo `what`, which was just done.
+    fn record(
+        &mut self,
+        what: &'static str,
+        undo: impl FnOnce() -> Result<()> + 'static,
+    ) -> Result<()> {
+        self.steps.push((what, Box::new(undo)));
+        if self.fail_at.as_deref() == Some(what) {
+            bail!("fail after {}, as requested by VMSH_FAIL_AT", what);
+        }
+        Ok(())
+    }
+
+    /// The attach succeeded, from now on the session cleans up.
+    fn commit(mut self) {
+        self.steps.clear();
+    }
+}
+
+impl Drop for Rollback {
+    fn drop(&mut self) {
+        // a failing step must not keep us from undoing the ones before it
+        while let Some((what, undo)) = self.steps.pop() {
+            info!("attach failed, undo: {}", what);
+            if let Err(e) = undo() {
+                error!("cannot undo {}: {}", what, e);
+            }
+        }
+    }
+}
+
+/// A kernel in lockdown is meant to prevent changes to the running kernel, which is what
+/// stage1 does, and may refuse to run an unsigned stage2. We fail with a clear error instead.
+/// If the kernel log cannot be read the state is unknown and we attach anyway. Needs a stopped
+/// VM.
+fn check_lockdown(vm: &Hypervisor) -> Result<()> {
+    let guest_mem = try_with!(GuestMem::new(vm), "cannot access guest memory");
+    let kernel = find_kernel(&guest_mem, vm)?;
+    let lockdown = KernelMem::new(vm, &guest_mem).and_then(|mem| kernel.lockdown(&mem));
+    match lockdown {
+        Ok(Lockdown {
+            locked_down_by: Some(by),
+            ..
+        }) => Err(VmshError::UnsupportedKernel(format!(
+            "kernel lockdown active (enabled by {}), use --ignore-lockdown to attach anyway",
+            by
+        ))),
+        Ok(_) => Ok(()),
+        Err(e) => {
+            warn!("cannot tell whether the guest kernel is locked down: {}", e);
+            Ok(())
+        }
+    }
+}
+
+/// `detachable` sessions are recorded in a `Session`, so that `vmsh detach` can find them.
+pub(crate) fn attach_session(
+    opts: &AttachOptions,
+    sender: Sender<()>,
+    receiver: Receiver<()>,
+    started: impl FnOnce(Weak<DeviceContext>),
+    detachable: bool,
+) -> Result<()> {
+    info!("attaching");
+    let mut progress = Progress::new(opts.pid.as_raw(), opts.timing_out.clone());
+    progress.phase("discover");
+    // crosvm device processes and the firecracker jailer have no access to the VM
+    let pid = try_with!(
+        flavor::vm_process(opts.pid, opts.hypervisor),
+        "cannot find the VM process of {}",
         opts.pid
     );
-    vm.stop()?;
+    let flavor = opts.hypervisor.unwrap_or_else(|| Flavor::detect(pid));
+    info!("hypervisor: {:?}", flavor);
+    flavor::check_seccomp(pid, flavor)?;
+    // covers the setup until the devices run, not the whole session
+    let attach_span = tracing::info_span!("attach", pid = pid.as_raw()).entered();
+    // fails on invalid seccomp profiles before we touch the VM
+    let stage2_argv = opts.stage2_argv()?;
+    if let Some(addr) = opts.metrics_addr {
+        metrics::serve(addr)?;
+    }
+    metrics::log_on_signal()?;
+
+    // pulling the image can take a while, the VM keeps running meanwhile
+    let oci_image = match oci::image_reference(&opts.backing) {
+        Some(_) if opts.share_mode != ShareMode::Block => {
+            bail!("oci images can only be served as block device")
+        }
+        Some(reference) => Some(oci::build_image(reference)?),
+        None => None,
+    };
+    let device_opts = DeviceOptions {
+        backing: oci_image
+            .as_ref()
+            .map_or_else(|| opts.backing.clone(), |image| image.path.clone()),
+        share_mode: opts.share_mode,
+        pts: opts.pts.clone(),
+        record: opts.record.clone(),
+        console_ports: opts.console_ports.clone(),
+        scrollback: opts.scrollback,
+        tap: opts.tap.clone(),
+        vsock: opts.vsock.clone(),
+        vsock_cid: opts.vsock_cid,
+        rng: opts.rng,
+        transport: opts.transport,
+        blk_queues: opts.blk_queues,
+        queue_size: opts.queue_size,
+        disks: opts.disks.clone(),
+        pmem: opts.pmem.clone(),
+        hotplug_slots: opts.hotplug_slots,
+        snapshot: opts.snapshot,
+    };
+    let previous = if detachable {
+        detached_session(pid, &device_opts)?
+    } else {
+        None
+    };
+    let detachable = detachable && device_opts.detachable();
+
+    // Everything that does not need the VM is prepared before the hypervisor is stopped, so
+    // that the guest only pauses for memory writes and ioctls.
+    let stage1_binary = match previous {
+        Some(_) => None,
+        None => Some(try_with!(stage1::parse_binary(), "cannot parse stage1")),
+    };
+    // listen before stage2 starts, it only connects once
+    let _forwarder = match &opts.vsock {
+        Some(vsock) if !opts.forwards.is_empty() => Some(forward::start(vsock, &opts.forwards)?),
+        None if !opts.forwards.is_empty() => bail!("port forwarding requires a vsock device"),
+        _ => None,
+    };
+
+    let attach_error = |e: VmshError| VmshError::Attach {
+        pid: pid.as_raw(),
+        source: Box::new(e),
+    };
+    let vm = Arc::new(kvm::hypervisor::get_hypervisor(pid, opts.vm).map_err(attach_error)?);
+    vm.check_writable("attaching devices")?;
+    let watchdog = watchdog::spawn(pid, sender.clone())?;
+    if opts.wait_for_boot {
+        progress.phase("wait-for-boot");
+        if let Err(e) = boot::wait_for_boot(&vm, &receiver) {
+            return Err(watchdog.failure().map_or(e, VmshError::HypervisorFailed));
+        }
+    }
+    let mut rollback = Rollback::new();
+    progress.phase("stop");
+    vm.stop().map_err(attach_error)?;
+    let hv = Arc::clone(&vm);
+    rollback.record("stop the hypervisor", move || hv.resume())?;
+    // encrypted memory is only detected once the hypervisor is stopped
+    vm.check_writable("attaching devices")?;
     try_with!(
         vm.setup_transfer_sockets(),
         "failed to setup unix sockets for fd transfer"
     );
-    let vm = Arc::new(vm);
+    let hv = Arc::clone(&vm);
+    rollback.record("open transfer sockets", move || hv.close_transfer_sockets())?;
+    select_mmio_transport(&vm, opts.mmio_transport)?;
+    // a stage1 of a detached session already runs
+    if previous.is_none() && !opts.ignore_lockdown {
+        check_lockdown(&vm)?;
+    }
 
+    progress.phase("alloc");
     let mut allocator = try_with!(
         kvm::PhysMemAllocator::new(Arc::clone(&vm)),
         "cannot create allocator"
     );
 
-    let irq_num = try_with!(get_irq_num(opts.pid), "failed to get irq num");
+    let irq_nums = match &previous {
+        Some(previous) => previous.irq_nums.clone(),
+        None => try_with!(get_irq_nums(&vm), "failed to get irq nums"),
+    };
 
-    let devices = try_with!(
-        DeviceSet::new(
-            &vm,
-            &mut allocator,
-            irq_num,
-            &opts.backing,
-            opts.pts.clone()
-        ),
-        "cannot create devices"
-    );
+    // the allocator hands out the same addresses as long as the devices are the same
+    let mut devices = DeviceSet::new(&vm, &mut allocator, &irq_nums, &device_opts)
+        .map_err(|e| VmshError::Device(format!("cannot create devices: {}", e)))?;
+    let hv = Arc::clone(&vm);
+    rollback.record("register irqfds", move || hv.unregister_irqfds())?;
 
     if receiver.recv_timeout(Duration::from_millis(0)).is_ok() {
         return Ok(());
     }
 
+    progress.phase("load");
+    let context = devices.context();
     let addrs = devices.mmio_addrs()?;
-    let mut stage1 = try_with!(
-        Stage1::new(allocator, &opts.command, irq_num, addrs),
-        "failed to initialize stage1"
-    );
-    let driver_status = require_with!(stage1.driver_status.take(), "no driver status set");
-    let stage1_thread = try_with!(
-        stage1.spawn(Arc::clone(&vm), driver_status.clone(), sender.clone()),
-        "failed to spawn stage1"
-    );
-    let device_status = require_with!(stage1.device_status.take(), "device status is not set");
-    let (threads, driver_notifier) = try_with!(
-        devices.start(&vm, device_status, driver_status, sender),
-        "failed to start devices"
-    );
+    let pci_window = devices.pci_window()?;
+    // memory of the stage1 of a detached session
+    let mut kept_stage1 = None;
+    let (stage1, stage1_thread, device_status, driver_status, device_slots) = match previous {
+        Some(previous) => {
+            if addrs != previous.mmio_addrs {
+                bail!("devices differ from the detached session, use the same options as before");
+            }
+            let (device_status, driver_status, device_slots) = previous.stage1_fields();
+            let state = try_with!(driver_status.check(&vm), "cannot check stage1 state");
+            if state != DeviceState::Ready {
+                bail!("stage1 of the detached session is not running: {:?}", state);
+            }
+            devices.restore(previous.mmio_writes);
+            kept_stage1 = previous.stage1_mem;
+            (None, None, device_status, driver_status, device_slots)
+        }
+        None => {
+            let binary = require_with!(stage1_binary.as_ref(), "stage1 was not parsed");
+            let mut stage1 = try_with!(
+                Stage1::new(
+                    binary,
+                    allocator,
+                    &stage2_argv,
+                    &opts.stage2_fallback_paths,
+                    &irq_nums,
+                    addrs.clone(),
+                    pci_window
+                ),
+                "failed to initialize stage1"
+            );
+            let (hv, kept) = (Arc::clone(&vm), stage1.mem().clone());
+            rollback.record("add the memory slot of stage1", move || {
+                hv.reclaim_mem(kept.phys_mem()).map(drop)
+            })?;
+            let (hv, kept) = (Arc::clone(&vm), stage1.mem().clone());
+            rollback.record("map stage1 into the guest page tables", move || {
+                kept.restore_page_tables(&hv)
+            })?;
+            progress.phase("inject");
+            let driver_status = require_with!(stage1.driver_status.take(), "no driver status set");
+            // the vcpu would enter stage1 after its memory was removed
+            let regs = try_with!(vm.get_regs(&vm.vcpus[0]), "failed to get vm registers");
+            let hv = Arc::clone(&vm);
+            rollback.record("set the registers of stage1", move || {
+                hv.set_regs(&hv.vcpus[0], &regs)
+            })?;
+            let stage1_thread = try_with!(
+                stage1.spawn(Arc::clone(&vm), driver_status.clone(), sender.clone()),
+                "failed to spawn stage1"
+            );
+            let device_status =
+                require_with!(stage1.device_status.take(), "device status is not set");
+            let device_slots =
+                require_with!(stage1.device_slots.take(), "device slots are not set");
+            (
+                Some(stage1),
+                Some(stage1_thread),
+                device_status,
+                driver_status,
+                device_slots,
+            )
+        }
+    };
+    let session = if detachable {
+        let mut session = Session::new(
+            pid,
+            &irq_nums,
+            addrs,
+            &device_status,
+            &driver_status,
+            &device_slots,
+        )?;
+        session.stage1_mem = kept_stage1.clone();
+        session.save(pid)?;
+        rollback.record("save the session", move || Session::remove(pid))?;
+        Some(session)
+    } else {
+        None
+    };
+    progress.phase("wait-for-driver");
+    let (threads, driver_notifier) = devices
+        .start(
+            &vm,
+            device_status,
+            driver_status,
+            device_slots,
+            sender,
+            &receiver,
+        )
+        .map_err(|e| match (watchdog.failure(), e) {
+            // waiting for the devices was cancelled by the watchdog
+            (Some(failure), _) => VmshError::HypervisorFailed(failure),
+            (None, e @ (VmshError::GuestTimeout { .. } | VmshError::Cancelled(_))) => e,
+            (None, e) => VmshError::Device(format!("failed to start devices: {}", e)),
+        })?;
+    rollback.commit();
+    if let Err(e) = progress.finish() {
+        warn!("cannot write attach timings: {}", e);
+    }
 
     info!("blkdev queue ready.");
+    drop(attach_span);
+    // only a weak reference is handed out, the devices have to be dropped below while we are
+    // tracing the hypervisor
+    started(Arc::downgrade(&context));
+    drop(context);
 
     // termination wait or vmsh_stop()
     let _ = receiver.recv();
-    stage1_thread.shutdown();
-    if let Err(e) = stage1_thread.join() {
-        error!("{}", e);
-    };
-    if let Err(e) = driver_notifier.terminate() {
-        error!("failed to stop device: {}", e);
+    if let Some(failure) = watchdog.failure() {
+        // nothing in the hypervisor can be cleaned up anymore
+        stage1_thread.iter().for_each(|t| t.shutdown());
+        threads.iter().for_each(|t| t.shutdown());
+        // threads waiting for a hung hypervisor do not return
+        if matches!(failure, Failure::Exited(_)) {
+            for t in stage1_thread {
+                let _ = t.join();
+            }
+            for t in threads {
+                let _ = t.join();
+            }
+        }
+        if let Some(stage1) = stage1 {
+            stage1.keep_in_guest();
+        }
+        if session.is_some() {
+            if let Err(e) = Session::remove(pid) {
+                error!("cannot remove session: {}", e);
+            }
+        }
+        return Err(VmshError::HypervisorFailed(failure));
+    }
+    let detach = session.is_some() && signal_handler::detach_requested();
+    // stage2 needs the vsock device to receive the signal
+    if opts.kill_on_detach && !detach {
+        signal_handler::kill_guest_command(KILL_TIMEOUT);
     }
+    if let Some(stage1_thread) = stage1_thread {
+        stage1_thread.shutdown();
+        if let Err(e) = stage1_thread.join() {
+            error!("{}", e);
+        };
+    }
+    let unloaded = if detach {
+        info!("detach, the devices stay registered in the guest");
+        false
+    } else if let Err(e) = driver_notifier.terminate() {
+        error!("failed to stop device: {}", e);
+        false
+    } else {
+        true
+    };
     threads.iter().for_each(|t| t.shutdown());
     let contexts = threads
         .into_iter()
@@ -123,14 +652,114 @@ pub fn attach(opts: &AttachOptions) -> Result<()> {
     // MMIO exit handler thread took over pthread control
     // We need ptrace the process again before we can finish.
     vm.stop()?;
-    if !use_ioregionfd() {
+    if !vm.use_ioregionfd() {
         vm.finish_thread_transfer()?;
     }
     // now that we got the tracer back, we can cleanup physical memory and file descriptors
-    drop(stage1);
+    let kept_stage1 = match (stage1, kept_stage1) {
+        (Some(stage1), _) if detach => Some(stage1.keep_in_guest()),
+        (Some(stage1), _) if !unloaded => {
+            // removing the memory of code the guest still runs would crash its kernel
+            warn!("stage1 might still be running, its memory stays in the guest");
+            stage1.keep_in_guest();
+            None
+        }
+        (Some(stage1), _) => {
+            if let Err(e) = stage1.remove(Arc::clone(&vm)) {
+                warn!("cannot remove memory of stage1: {}", e);
+            }
+            None
+        }
+        (None, kept) if detach => kept,
+        (None, _) if !unloaded => {
+            warn!("stage1 might still be running, its memory stays in the guest");
+            None
+        }
+        (None, Some(kept)) => {
+            if let Err(e) = VirtMem::reclaim(Arc::clone(&vm), &kept).map(drop) {
+                warn!("cannot remove memory of the re-attached stage1: {}", e);
+            }
+            None
+        }
+        // sessions of earlier vmsh versions did not record it
+        (None, None) => {
+            warn!("memory of the re-attached stage1 stays in the guest");
+            None
+        }
+    };
+    if let Some(mut session) = session {
+        let res = if detach {
+            session.vmsh_pid = 0;
+            session.stage1_mem = kept_stage1;
+            match contexts.iter().flatten().next() {
+                Some(ctx) => ctx.mmio_writes().and_then(|writes| {
+                    session.mmio_writes = writes;
+                    session.save(pid)
+                }),
+                None => Err(simple_error!("no devices left to detach from").into()),
+            }
+        } else {
+            Session::remove(pid)
+        };
+        if let Err(e) = res {
+            error!("cannot update session: {}", e);
+        }
+    }
     drop(contexts);
     try_with!(vm.close_transfer_sockets(), "cannot close transfer sockets");
     vm.resume()?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// The steps of a fresh attach, in order
+    const STEPS: [&str; 7] = [
+        "stop the hypervisor",
+        "open transfer sockets",
+        "register irqfds",
+        "add the memory slot of stage1",
+        "map stage1 into the guest page tables",
+        "set the registers of stage1",
+        "save the session",
+    ];
+
+    /// Runs the steps of an attach with `VMSH_FAIL_AT` set to `fail_at` and returns the steps
+    /// undone afterwards.
+    fn attach_failing_at(fail_at: Option<&str>) -> Vec<&'static str> {
+        let undone = Arc::new(Mutex::new(vec![]));
+        let res = (|| -> Result<()> {
+            let mut rollback = Rollback {
+                steps: vec![],
+                fail_at: fail_at.map(String::from),
+            };
+            for &step in STEPS.iter() {
+                let undone = Arc::clone(&undone);
+                rollback.record(step, move || {
+                    undone.lock().unwrap().push(step);
+                    // earlier steps are still undone
+                    bail!("cannot undo {}", step)
+                })?;
+            }
+            rollback.commit();
+            Ok(())
+        })();
+        assert_eq!(res.is_err(), fail_at.is_some());
+        let undone = undone.lock().unwrap();
+        undone.clone()
+    }
+
+    #[test]
+    fn rollback() {
+        for (i, step) in STEPS.iter().enumerate() {
+            let mut expected = STEPS[..=i].to_vec();
+            expected.reverse();
+            assert_eq!(attach_failing_at(Some(step)), expected);
+        }
+        assert!(attach_failing_at(None).is_empty());
+    }
+}
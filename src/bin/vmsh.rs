@@ -1,15 +1,43 @@
 use log::*;
+use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::sync::atomic::Ordering;
+use std::time::Duration;
 
+use clap::builder::PossibleValue;
+use clap::parser::ValueSource;
 use clap::{crate_authors, crate_version, Arg, ArgAction, ArgMatches, Command};
 use nix::unistd::Pid;
 
 use vmsh::attach::{self, AttachOptions};
-use vmsh::coredump::CoredumpOptions;
-use vmsh::devices::USE_IOREGIONFD;
-use vmsh::inspect::InspectOptions;
-use vmsh::{console, coredump, inspect};
+use vmsh::check::CheckOptions;
+use vmsh::clock::ClockOptions;
+use vmsh::control::ControlOptions;
+use vmsh::coredump::{CoredumpOptions, DumpFormat};
+use vmsh::cp::{CpDirection, CpOptions};
+use vmsh::crash::{CrashMethod, CrashOptions};
+use vmsh::daemon::DaemonOptions;
+use vmsh::devices::virtio::block::MAX_BLK_QUEUES;
+use vmsh::devices::virtio::console::DEFAULT_SCROLLBACK;
+use vmsh::devices::virtio::pci::Transport;
+use vmsh::devices::virtio::vsock::VSOCK_DEFAULT_GUEST_CID;
+use vmsh::devices::virtio::DEFAULT_QUEUE_SIZE;
+use vmsh::devices::{MmioTransport, ShareMode};
+use vmsh::dmesg::DmesgOptions;
+use vmsh::exec::ExecOptions;
+use vmsh::forward::PortForward;
+use vmsh::gdbserver::GdbServerOptions;
+use vmsh::inspect::{InspectFormat, InspectOptions};
+use vmsh::kvm::hypervisor::{self, VmSelector};
+use vmsh::mem::{MemAction, MemOptions};
+use vmsh::ps::{PsOptions, TaskOffsetOverrides};
+use vmsh::regs::RegsOptions;
+use vmsh::session::DetachOptions;
+use vmsh::snapshot::{RestoreOptions, SnapshotOptions};
+use vmsh::tracer::audit_log;
+use vmsh::{
+    check, chrome_trace, clock, console, control, coredump, cp, crash, daemon, dmesg, exec,
+    gdbserver, guest_wait, inspect, kata, mem, ps, regs, session, snapshot,
+};
 
 const VM_TYPES: &[&str] = &["process_id", "kubernetes", "vhive", "vhive_fc_vmid"];
 
@@ -39,6 +67,12 @@ fn vmid_type_arg() -> Arg {
 }
 
 fn parse_vmid_arg(args: &ArgMatches) -> Pid {
+    let container_name = args.get_one::<String>("id").expect("`id` is required"); // safe, because container id is .required
+    lookup_vmid(args, container_name)
+}
+
+/// Resolves a VM id with the lookups given in `type`.
+fn lookup_vmid(args: &ArgMatches, container_name: &str) -> Pid {
     let mut container_types = vec![];
     if args.contains_id("type") {
         container_types = args
@@ -48,7 +82,6 @@ fn parse_vmid_arg(args: &ArgMatches) -> Pid {
             .collect();
     }
 
-    let container_name = args.get_one::<String>("id").expect("`id` is required"); // safe, because container id is .required
     match container_pid::lookup_container_pid(container_name, &container_types) {
         Err(e) => {
             error!("{}", e);
@@ -58,6 +91,56 @@ fn parse_vmid_arg(args: &ArgMatches) -> Pid {
     }
 }
 
+fn vm_select_args() -> [Arg; 2] {
+    [
+        Arg::new("vm-fd")
+            .long("vm-fd")
+            .num_args(1)
+            .value_parser(clap::value_parser!(i32))
+            .conflicts_with("vm-index")
+            .help("File descriptor of the VM in the hypervisor, required if it runs multiple VMs"),
+        Arg::new("vm-index")
+            .long("vm-index")
+            .num_args(1)
+            .value_parser(clap::value_parser!(usize))
+            .help("Select the n-th VM of the hypervisor (ordered by file descriptor)"),
+    ]
+}
+
+fn symbols_arg() -> Arg {
+    Arg::new("symbols")
+        .long("symbols")
+        .value_name("FILE")
+        .value_parser(clap::value_parser!(PathBuf))
+        .help("vmlinux or System.map of the guest kernel to symbolize backtraces. Defaults to the symbols exported by the kernel")
+}
+
+fn parse_vm_selector(args: &ArgMatches) -> Option<VmSelector> {
+    if let Some(fd) = args.get_one::<i32>("vm-fd") {
+        Some(VmSelector::Fd(*fd))
+    } else {
+        args.get_one::<usize>("vm-index")
+            .map(|idx| VmSelector::Index(*idx))
+    }
+}
+
+fn stage2_path_arg() -> Arg {
+    Arg::new("stage2-path")
+        .long("stage2-path")
+        .num_args(1)
+        .action(ArgAction::Append)
+        .default_values(["/dev/.vmsh", "/.vmsh"])
+        .help("Path where Stage2 is written to in the VM. Can be passed multiple times, the next path is tried if writing to a path fails, i.e. on read-only filesystems")
+}
+
+/// The paths of `--stage2-path` in the order they are tried.
+fn stage2_paths(args: &ArgMatches) -> Vec<String> {
+    args.get_many::<String>("stage2-path")
+        .expect("`stage2-path` has a default")
+        .cloned()
+        .collect()
+}
+
 fn command_args(index: usize) -> Arg {
     Arg::new("command")
         .help("Command to run in the VM")
@@ -66,9 +149,67 @@ fn command_args(index: usize) -> Arg {
         .index(index)
 }
 
+fn command_env_args() -> [Arg; 6] {
+    [
+        Arg::new("env")
+            .short('e')
+            .long("env")
+            .value_name("KEY=VALUE")
+            .num_args(1)
+            .action(ArgAction::Append)
+            .value_parser(|s: &str| match s.split_once('=') {
+                Some((key, _)) if !key.is_empty() => Ok(s.to_string()),
+                _ => Err(format!("expected KEY=VALUE, got {}", s)),
+            })
+            .help("Set an environment variable for the command, can be passed multiple times"),
+        Arg::new("workdir")
+            .short('w')
+            .long("workdir")
+            .num_args(1)
+            .value_parser(clap::value_parser!(PathBuf))
+            .help("Working directory of the command in the VM"),
+        Arg::new("user")
+            .short('u')
+            .long("user")
+            .value_name("USER[:GROUP]")
+            .num_args(1)
+            .help("Run the command as this user or uid instead of the owner of the target process, looked up in /etc/passwd of the VM"),
+        Arg::new("skip-namespace")
+            .long("skip-namespace")
+            .num_args(1)
+            .action(ArgAction::Append)
+            .value_parser(["uts", "cgroup", "pid", "net", "ipc", "user"])
+            .help("Do not join this namespace of the target process, can be passed multiple times. Skipping user keeps root privileges and all capabilities."),
+        Arg::new("cgroup")
+            .long("cgroup")
+            .value_name("PATH")
+            .num_args(1)
+            .value_parser(|s: &str| {
+                if s.starts_with('/') {
+                    Ok(s.to_string())
+                } else {
+                    Err(format!("expected an absolute cgroup path, got {}", s))
+                }
+            })
+            .help("Move the command to this cgroup, i.e. /system.slice/debug.service, instead of the cgroups of the target process"),
+        Arg::new("seccomp")
+            .long("seccomp")
+            .value_name("PROFILE")
+            .num_args(1)
+            .value_parser(clap::value_parser!(PathBuf))
+            .help("Apply this seccomp profile to the command, in the JSON format of linux.seccomp in OCI runtime configurations"),
+    ]
+}
+
 fn inspect(args: &ArgMatches) {
     let opts = InspectOptions {
         pid: parse_vmid_arg(args),
+        vm: parse_vm_selector(args),
+        symbols: args.get_one::<PathBuf>("symbols").cloned(),
+        format: match args.get_one::<String>("format").map(|s| s.as_str()) {
+            Some("json") => InspectFormat::Json,
+            _ => InspectFormat::Text,
+        },
     };
 
     if let Err(err) = inspect::inspect(&opts) {
@@ -82,44 +223,356 @@ fn attach_options(args: &ArgMatches) -> AttachOptions {
         .get_many::<String>("command")
         .unwrap_or_default()
         .collect::<Vec<_>>();
-    let stage2_path = args
-        .get_one::<String>("stage2-path")
-        .expect("`stage2-path` is required");
-    command.insert(0, stage2_path);
+    let mut stage2_paths = stage2_paths(args);
+    let stage2_fallback_paths = stage2_paths.split_off(1);
+    command.insert(0, &stage2_paths[0]);
+
+    let kata = args
+        .try_get_one::<bool>("kata")
+        .ok()
+        .flatten()
+        .copied()
+        .unwrap_or(false)
+        .then(|| {
+            let id = args.get_one::<String>("id").expect("`id` is required");
+            kata::lookup(id).unwrap_or_else(|e| {
+                error!("{}", e);
+                std::process::exit(1);
+            })
+        });
+    // serve the root filesystem of the container unless a backing file was given
+    let kata_rootfs = kata
+        .as_ref()
+        .and_then(|sandbox| sandbox.rootfs.clone())
+        .filter(|_| args.value_source("backing-file") == Some(ValueSource::DefaultValue));
 
     AttachOptions {
-        pid: parse_vmid_arg(args),
+        pid: kata
+            .as_ref()
+            .map_or_else(|| parse_vmid_arg(args), |sandbox| sandbox.pid),
+        vm: parse_vm_selector(args),
         command: command.into_iter().map(Clone::clone).collect::<Vec<_>>(),
-        backing: args
-            .get_one::<PathBuf>("backing-file")
-            .expect("`backing-file` is required")
-            .clone(),
+        stage2_fallback_paths,
+        env: args
+            .try_get_many::<String>("env")
+            .ok()
+            .flatten()
+            .map_or_else(Vec::new, |vars| vars.cloned().collect()),
+        workdir: args
+            .try_get_one::<PathBuf>("workdir")
+            .ok()
+            .flatten()
+            .cloned(),
+        user: args.try_get_one::<String>("user").ok().flatten().cloned(),
+        skip_namespaces: args
+            .try_get_many::<String>("skip-namespace")
+            .ok()
+            .flatten()
+            .map_or_else(Vec::new, |namespaces| namespaces.cloned().collect()),
+        cgroup: args.try_get_one::<String>("cgroup").ok().flatten().cloned(),
+        seccomp: args
+            .try_get_one::<PathBuf>("seccomp")
+            .ok()
+            .flatten()
+            .cloned(),
+        share_mode: if kata_rootfs.is_some() {
+            ShareMode::P9
+        } else {
+            args.try_get_one::<String>("share-mode")
+                .ok()
+                .flatten()
+                .map_or(ShareMode::Block, |mode| {
+                    mode.parse().expect("share mode is validated by clap")
+                })
+        },
+        backing: kata_rootfs.unwrap_or_else(|| {
+            args.get_one::<PathBuf>("backing-file")
+                .expect("`backing-file` is required")
+                .clone()
+        }),
         pts: args
             .get_one::<Option<PathBuf>>("pts")
             .map_or_else(|| None, Clone::clone),
+        // `console` does not support recording
+        record: args
+            .try_get_one::<PathBuf>("record")
+            .ok()
+            .flatten()
+            .cloned(),
+        console_ports: args
+            .try_get_many::<PathBuf>("console-port")
+            .ok()
+            .flatten()
+            .map_or_else(Vec::new, |ports| ports.cloned().collect()),
+        scrollback: args
+            .try_get_one::<usize>("scrollback")
+            .ok()
+            .flatten()
+            .copied()
+            .unwrap_or(DEFAULT_SCROLLBACK),
+        tap: args.try_get_one::<String>("net").ok().flatten().cloned(),
+        vsock: args.try_get_one::<PathBuf>("vsock").ok().flatten().cloned(),
+        vsock_cid: args
+            .try_get_one::<u64>("vsock-cid")
+            .ok()
+            .flatten()
+            .copied()
+            .unwrap_or(VSOCK_DEFAULT_GUEST_CID),
+        forwards: args
+            .try_get_many::<PortForward>("forward")
+            .ok()
+            .flatten()
+            .map_or_else(Vec::new, |forwards| forwards.copied().collect()),
+        // `console` does not support extra devices
+        rng: args
+            .try_get_one::<bool>("rng")
+            .ok()
+            .flatten()
+            .copied()
+            .unwrap_or(false),
+        transport: args
+            .try_get_one::<String>("transport")
+            .ok()
+            .flatten()
+            .map_or(Transport::Mmio, |transport| {
+                transport.parse().expect("transport is validated by clap")
+            }),
+        mmio_transport: args
+            .try_get_one::<String>("mmio-transport")
+            .ok()
+            .flatten()
+            .map_or(MmioTransport::Auto, |transport| {
+                transport
+                    .parse()
+                    .expect("mmio transport is validated by clap")
+            }),
+        blk_queues: args
+            .try_get_one::<u16>("blk-queues")
+            .ok()
+            .flatten()
+            .copied()
+            .unwrap_or(1),
+        queue_size: args
+            .try_get_one::<u16>("queue-size")
+            .ok()
+            .flatten()
+            .copied()
+            .unwrap_or(DEFAULT_QUEUE_SIZE),
+        disks: args
+            .try_get_many::<PathBuf>("disk")
+            .ok()
+            .flatten()
+            .map_or_else(Vec::new, |disks| disks.cloned().collect()),
+        pmem: args
+            .try_get_many::<PathBuf>("pmem")
+            .ok()
+            .flatten()
+            .map_or_else(Vec::new, |files| files.cloned().collect()),
+        // block devices can only be added at runtime through the daemon
+        hotplug_slots: 0,
+        snapshot: args
+            .try_get_one::<bool>("snapshot")
+            .ok()
+            .flatten()
+            .copied()
+            .unwrap_or(false),
+        metrics_addr: args
+            .try_get_one::<SocketAddr>("metrics-addr")
+            .ok()
+            .flatten()
+            .copied(),
+        hypervisor: args
+            .try_get_one::<String>("hypervisor")
+            .ok()
+            .flatten()
+            .and_then(|hypervisor| match hypervisor.as_str() {
+                "auto" => kata.as_ref().map(|sandbox| sandbox.hypervisor),
+                name => Some(name.parse().expect("hypervisor is validated by clap")),
+            }),
+        kill_on_detach: args
+            .try_get_one::<bool>("kill-on-detach")
+            .ok()
+            .flatten()
+            .copied()
+            .unwrap_or(false),
+        wait_for_boot: args
+            .try_get_one::<bool>("wait-for-boot")
+            .ok()
+            .flatten()
+            .copied()
+            .unwrap_or(false),
+        ignore_lockdown: args
+            .try_get_one::<bool>("ignore-lockdown")
+            .ok()
+            .flatten()
+            .copied()
+            .unwrap_or(false),
+        timing_out: args
+            .try_get_one::<PathBuf>("timing-out")
+            .ok()
+            .flatten()
+            .cloned(),
     }
 }
 
-fn attach(args: &ArgMatches) {
+/// Returns the exit code of the command run in the guest, 0 if there was none.
+fn attach(args: &ArgMatches) -> i32 {
     let opts = attach_options(args);
-    USE_IOREGIONFD.store(
-        args.get_one::<String>("mmio").expect("`mmio` is required") == "ioregionfd",
-        Ordering::Release,
-    );
+    if let Ok(Some(secs)) = args.try_get_one::<u64>("guest-timeout") {
+        guest_wait::set_timeout(Duration::from_secs(*secs));
+    }
+
+    let res = if args.get_flag("daemon") {
+        control::attach_daemon(opts).map(|_| None)
+    } else {
+        attach::attach(&opts)
+    };
+    match res {
+        // exit with the code of the command
+        Ok(Some(code)) => code,
+        Ok(None) => 0,
+        Err(err) => {
+            error!("{}", err);
+            1
+        }
+    }
+}
+
+fn detach(args: &ArgMatches) {
+    let opts = DetachOptions {
+        pid: parse_vmid_arg(args),
+    };
+
+    if let Err(err) = session::detach(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn control(args: &ArgMatches) {
+    let command = args
+        .get_one::<String>("command")
+        .expect("`command` is required")
+        .parse()
+        .expect("command is validated by clap");
+    let opts = ControlOptions {
+        pid: parse_vmid_arg(args),
+        command,
+    };
 
-    if let Err(err) = attach::attach(&opts) {
+    if let Err(err) = control::control(&opts) {
         error!("{}", err);
         std::process::exit(1);
     };
 }
 
+/// Splits `<id>:<path>` of a guest path. Host paths have no id or contain `/` before the `:`.
+fn split_guest_path(arg: &str) -> Option<(&str, &str)> {
+    match arg.split_once(':') {
+        Some((id, path)) if !id.is_empty() && !id.contains('/') => Some((id, path)),
+        _ => None,
+    }
+}
+
+fn cp(args: &ArgMatches) {
+    let source = args
+        .get_one::<String>("source")
+        .expect("`source` is required");
+    let dest = args
+        .get_one::<String>("destination")
+        .expect("`destination` is required");
+    let (id, guest_path, host_path, direction) =
+        match (split_guest_path(source), split_guest_path(dest)) {
+            (Some((id, path)), None) => (id, path, dest.as_str(), CpDirection::FromGuest),
+            (None, Some((id, path))) => (id, path, source.as_str(), CpDirection::ToGuest),
+            _ => {
+                error!("exactly one of source and destination must be a guest path <id>:<path>");
+                std::process::exit(1);
+            }
+        };
+    let opts = CpOptions {
+        pid: lookup_vmid(args, id),
+        guest_path: PathBuf::from(guest_path),
+        host_path: PathBuf::from(host_path),
+        direction,
+    };
+
+    if let Err(err) = cp::cp(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+/// Returns the exit code of the command.
+fn exec(args: &ArgMatches) -> i32 {
+    let opts = ExecOptions {
+        pid: parse_vmid_arg(args),
+        command: args
+            .get_many::<String>("command")
+            .expect("`command` is required")
+            .cloned()
+            .collect(),
+    };
+
+    match exec::exec(&opts) {
+        Ok(code) => code,
+        Err(err) => {
+            error!("{}", err);
+            // like a shell, if the command cannot be run
+            127
+        }
+    }
+}
+
 fn coredump(args: &ArgMatches) {
     let pid = parse_vmid_arg(args);
-    let path = args
-        .get_one::<PathBuf>("PATH")
-        .map_or_else(|| PathBuf::from(format!("core.{}", pid)), Clone::clone);
+    let compress = args.get_flag("compress");
+    let path = args.get_one::<PathBuf>("PATH").map_or_else(
+        || PathBuf::from(coredump::default_path(pid, compress)),
+        Clone::clone,
+    );
+
+    let phys_ranges = args
+        .get_many::<String>("phys-range")
+        .unwrap_or_default()
+        .map(|r| coredump::parse_phys_range(r))
+        .collect::<vmsh::result::Result<Vec<_>>>();
+    let phys_ranges = match phys_ranges {
+        Ok(ranges) => ranges,
+        Err(err) => {
+            error!("invalid --phys-range: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let process_cr3 = match args
+        .get_one::<String>("process-cr3")
+        .map(|cr3| coredump::parse_cr3(cr3))
+    {
+        Some(Ok(cr3)) => Some(cr3),
+        Some(Err(err)) => {
+            error!("invalid --process-cr3: {}", err);
+            std::process::exit(1);
+        }
+        None => None,
+    };
 
-    let opts = CoredumpOptions { pid, path };
+    let opts = CoredumpOptions {
+        pid,
+        vm: parse_vm_selector(args),
+        path,
+        format: args
+            .get_one::<String>("format")
+            .map_or(DumpFormat::Elf, |format| {
+                format.parse().expect("format is validated by clap")
+            }),
+        kernel_virtual: args.get_flag("kernel-virtual"),
+        compress,
+        sparse: args.get_flag("sparse"),
+        phys_ranges,
+        process_cr3,
+        backtrace: args.get_flag("backtrace"),
+        symbols: args.get_one::<PathBuf>("symbols").cloned(),
+    };
 
     if let Err(err) = coredump::generate_coredump(&opts) {
         error!("{}", err);
@@ -127,6 +580,199 @@ fn coredump(args: &ArgMatches) {
     };
 }
 
+fn gdbserver(args: &ArgMatches) {
+    let port = *args.get_one::<u16>("port").expect("`port` has a default");
+    let opts = GdbServerOptions {
+        pid: parse_vmid_arg(args),
+        vm: parse_vm_selector(args),
+        listen: SocketAddr::from(([127, 0, 0, 1], port)),
+    };
+
+    if let Err(err) = gdbserver::gdbserver(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn parse_addr_arg(args: &ArgMatches, name: &str) -> Option<usize> {
+    let value = args.get_one::<String>(name)?;
+    match mem::parse_addr(value) {
+        Ok(addr) => Some(addr),
+        Err(err) => {
+            error!("invalid {}: {}", name, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn mem(args: &ArgMatches) {
+    let action = match args.get_one::<PathBuf>("write") {
+        Some(input) => MemAction::Write {
+            input: input.clone(),
+        },
+        None => MemAction::Read {
+            len: *args
+                .get_one::<usize>("length")
+                .expect("`length` has a default"),
+            output: args.get_one::<PathBuf>("output").cloned(),
+        },
+    };
+    let opts = MemOptions {
+        pid: parse_vmid_arg(args),
+        vm: parse_vm_selector(args),
+        addr: parse_addr_arg(args, "ADDRESS").expect("`ADDRESS` is required"),
+        virtual_addr: args.get_flag("virtual"),
+        cr3: parse_addr_arg(args, "cr3"),
+        action,
+    };
+
+    if let Err(err) = mem::mem(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn clock(args: &ArgMatches) {
+    let opts = ClockOptions {
+        pid: parse_vmid_arg(args),
+        vm: parse_vm_selector(args),
+        advance: args.get_one::<i64>("advance").copied(),
+        tsc_adjust: args.get_one::<i64>("tsc-adjust").copied(),
+    };
+
+    if let Err(err) = clock::clock(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn regs(args: &ArgMatches) {
+    let set = args
+        .get_many::<String>("set")
+        .unwrap_or_default()
+        .map(|a| regs::parse_assignment(a))
+        .collect::<vmsh::result::Result<Vec<_>>>();
+    let set = match set {
+        Ok(set) => set,
+        Err(err) => {
+            error!("invalid --set: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let opts = RegsOptions {
+        pid: parse_vmid_arg(args),
+        vm: parse_vm_selector(args),
+        vcpu: args.get_one::<usize>("vcpu").copied(),
+        set,
+    };
+
+    if let Err(err) = regs::regs(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn snapshot(args: &ArgMatches) {
+    let opts = SnapshotOptions {
+        pid: parse_vmid_arg(args),
+        vm: parse_vm_selector(args),
+        path: args
+            .get_one::<PathBuf>("out")
+            .expect("`out` is required")
+            .clone(),
+        live: args.get_flag("live"),
+        base: args.get_one::<PathBuf>("base").cloned(),
+    };
+
+    if let Err(err) = snapshot::snapshot(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn restore(args: &ArgMatches) {
+    let opts = RestoreOptions {
+        pid: parse_vmid_arg(args),
+        vm: parse_vm_selector(args),
+        path: args
+            .get_one::<PathBuf>("from")
+            .expect("`from` is required")
+            .clone(),
+    };
+
+    if let Err(err) = snapshot::restore(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn ps(args: &ArgMatches) {
+    let offsets = match args.get_one::<String>("offsets") {
+        Some(offsets) => match ps::parse_offsets(offsets) {
+            Ok(offsets) => offsets,
+            Err(err) => {
+                error!("invalid offsets: {}", err);
+                std::process::exit(1);
+            }
+        },
+        None => TaskOffsetOverrides::default(),
+    };
+    let opts = PsOptions {
+        pid: parse_vmid_arg(args),
+        vm: parse_vm_selector(args),
+        offsets,
+    };
+
+    if let Err(err) = ps::ps(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn check(args: &ArgMatches) {
+    let opts = CheckOptions {
+        pid: parse_vmid_arg(args),
+        vm: parse_vm_selector(args),
+        hypervisor: args
+            .get_one::<String>("hypervisor")
+            .map(|name| name.parse().expect("hypervisor is validated by clap")),
+    };
+
+    if let Err(err) = check::check(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn dmesg(args: &ArgMatches) {
+    let opts = DmesgOptions {
+        pid: parse_vmid_arg(args),
+        vm: parse_vm_selector(args),
+    };
+
+    if let Err(err) = dmesg::dmesg(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn crash(args: &ArgMatches) {
+    let opts = CrashOptions {
+        pid: parse_vmid_arg(args),
+        vm: parse_vm_selector(args),
+        method: args
+            .get_one::<String>("method")
+            .map_or(CrashMethod::Nmi, |method| {
+                method.parse().expect("method is validated by clap")
+            }),
+    };
+
+    if let Err(err) = crash::crash(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
 fn console(args: &ArgMatches) {
     let opts = attach_options(args);
     if let Err(err) = console::console(&opts) {
@@ -135,6 +781,21 @@ fn console(args: &ArgMatches) {
     };
 }
 
+fn daemon(args: &ArgMatches) {
+    let opts = DaemonOptions {
+        listen: args
+            .get_one::<PathBuf>("listen")
+            .expect("`listen` is required")
+            .clone(),
+        token_file: args.get_one::<PathBuf>("token-file").cloned(),
+        stage2_paths: stage2_paths(args),
+    };
+    if let Err(err) = daemon::daemon(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
 fn setup_logging(matches: &clap::ArgMatches) {
     if matches.contains_id("verbose") {
         env_logger::Builder::new().parse_filters("debug").init();
@@ -168,27 +829,54 @@ fn cli() -> Command {
              .short('l')
              .num_args(1)
              .help("Finegrained verbosity control. See docs.rs/env_logger. Examples: [error, warn, info, debug, trace]"))
+        .arg(Arg::new("trace-file")
+             .long("trace-file")
+             .value_name("PATH")
+             .global(true)
+             .value_parser(clap::value_parser!(PathBuf))
+             .help("Record spans (attach, mmio exits, queue handlers) in Chrome trace format to PATH. Open it in chrome://tracing or ui.perfetto.dev"))
+        .arg(Arg::new("audit-log")
+             .long("audit-log")
+             .value_name("PATH")
+             .global(true)
+             .value_parser(clap::value_parser!(PathBuf))
+             .help("Append every syscall injected into the hypervisor (number, arguments, return value, time) as JSON lines to PATH"))
+        .arg(Arg::new("read-only")
+             .long("read-only")
+             .global(true)
+             .action(ArgAction::SetTrue)
+             .help("Only read from the hypervisor: refuse to write guest memory, change vcpu registers or inject syscalls with side effects. For inspecting production VMs with inspect, coredump, dmesg, ps or mem read"))
+        .arg(Arg::new("allow-encrypted")
+             .long("allow-encrypted")
+             .global(true)
+             .action(ArgAction::SetTrue)
+             .help("Do not refuse VMs with encrypted memory (AMD SEV, Intel TDX), but only read from them as with --read-only. Works for regs and for coredump of the memory the guest shares with the host"))
         .subcommand(
             Command::new("inspect")
             .about("Inspect a virtual machine.")
             .version(crate_version!())
             .author(crate_authors!("\n"))
             .arg(vmid_arg(1))
-            .arg(vmid_type_arg()))
+            .arg(vmid_type_arg())
+            .args(vm_select_args())
+            .arg(symbols_arg())
+            .arg(
+                Arg::new("format")
+                .long("format")
+                .value_parser(["text", "json"])
+                .default_value("text")
+                .help("Output format. json writes a document describing all VMs (or the selected one) to stdout")
+            ))
         .subcommand(Command::new("attach")
                     .about("Attach (a block device) to a virtual machine.")
                     .version(crate_version!())
                     .author(crate_authors!("\n"))
                     .arg(vmid_arg(1))
                     .arg(vmid_type_arg())
-                    .arg(
-                        Arg::new("stage2-path")
-                        .long("stage2-path")
-                        .num_args(1)
-                        .default_value("/dev/.vmsh")
-                        .help("Path where Stage2 is written to in the VM"),
-                        )
+                    .args(vm_select_args())
+                    .arg(stage2_path_arg())
                     .arg(command_args(2))
+                    .args(command_env_args())
                     .arg(
                         Arg::new("backing-file")
                         .short('f')
@@ -196,15 +884,138 @@ fn cli() -> Command {
                         .num_args(1)
                         .default_value("/dev/null")
                         .value_parser(clap::value_parser!(PathBuf))
-                        .help("File which shall be served as a block device."),
+                        .visible_alias("backing")
+                        .help("File which shall be served as a block device or directory which shall be exported with --share-mode 9p. oci://<image> pulls a container image (requires skopeo) and serves it as ext4 image."),
+                        )
+                    .arg(
+                        Arg::new("share-mode")
+                        .long("share-mode")
+                        .num_args(1)
+                        .value_parser(["block", "9p"])
+                        .default_value("block")
+                        .help("How to provide the backing file to the VM. 9p works with kernels that lack virtio-fs support."),
                         )
                     .arg(
-                        Arg::new("mmio")
-                        .long("mmio")
+                        Arg::new("blk-queues")
+                        .long("blk-queues")
                         .num_args(1)
-                        .value_parser(clap::builder::PossibleValuesParser::new(["wrap_syscall", "ioregionfd"]))
-                        .default_value("wrap_syscall")
-                        .long_help("Backend used to serve Virtio MMIO memory of devices."),
+                        .value_parser(clap::value_parser!(u16).range(1..=MAX_BLK_QUEUES as i64))
+                        .default_value("1")
+                        .help("Number of request queues of the block device. More queues let guest CPUs submit I/O in parallel."),
+                        )
+                    .arg(
+                        Arg::new("queue-size")
+                        .long("queue-size")
+                        .num_args(1)
+                        .value_parser(clap::value_parser!(u16))
+                        .default_value("256")
+                        .help("Number of descriptors in each virtio queue (power of two)"),
+                        )
+                    .arg(
+                        Arg::new("disk")
+                        .long("disk")
+                        .num_args(1)
+                        .action(ArgAction::Append)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Serve an additional file as block device (serial vmsh1, vmsh2, ...). Can be passed multiple times."),
+                        )
+                    .arg(
+                        Arg::new("pmem")
+                        .long("pmem")
+                        .num_args(1)
+                        .action(ArgAction::Append)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Expose a file read-only as virtio-pmem device, which the guest can mount with DAX (-o dax). Its size must be a multiple of the page size. Can be passed multiple times."),
+                        )
+                    .arg(
+                        Arg::new("daemon")
+                        .long("daemon")
+                        .action(ArgAction::SetTrue)
+                        .help("Attach in the background and serve a control socket in /run/vmsh/<pid>.sock, see the control subcommand"),
+                        )
+                    .arg(
+                        Arg::new("snapshot")
+                        .long("snapshot")
+                        .action(ArgAction::SetTrue)
+                        .help("Never modify the backing files of block devices: writes of the VM are kept in memory and discarded on detach"),
+                        )
+                    .arg(
+                        Arg::new("kill-on-detach")
+                        .long("kill-on-detach")
+                        .action(ArgAction::SetTrue)
+                        .requires("vsock")
+                        .help("Kill the command in the guest when vmsh stops. Without it the command keeps running. Requires --vsock"),
+                        )
+                    .arg(
+                        Arg::new("wait-for-boot")
+                        .long("wait-for-boot")
+                        .action(ArgAction::SetTrue)
+                        .help("Attach to a VM that did not boot yet (i.e. QEMU with -S) and load stage1 once the guest kernel started init"),
+                        )
+                    .arg(
+                        Arg::new("ignore-lockdown")
+                        .long("ignore-lockdown")
+                        .action(ArgAction::SetTrue)
+                        .help("Load stage1 even if the guest kernel is locked down (i.e. by UEFI Secure Boot). stage1 or stage2 might then fail in the guest"),
+                        )
+                    .arg(
+                        Arg::new("timing-out")
+                        .long("timing-out")
+                        .value_name("PATH")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Write how long each phase of the attach (discover, stop, alloc, load, inject, wait-for-driver) took as JSON to PATH. -v logs the phases as they run"),
+                        )
+                    .arg(
+                        Arg::new("guest-timeout")
+                        .long("guest-timeout")
+                        .value_name("SECONDS")
+                        .num_args(1)
+                        .value_parser(clap::value_parser!(u64))
+                        .help("Give up if the guest does not start or stop the devices within SECONDS (default: 30)"),
+                        )
+                    .arg(
+                        Arg::new("metrics-addr")
+                        .long("metrics-addr")
+                        .value_name("ADDR")
+                        .num_args(1)
+                        .value_parser(clap::value_parser!(SocketAddr))
+                        .help("Serve counters (mmio exits, irqs, block and console I/O) in Prometheus format on ADDR, i.e. 127.0.0.1:9100. They are also logged on SIGUSR2."),
+                        )
+                    .arg(
+                        Arg::new("kata")
+                        .long("kata")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("type")
+                        .help("Treat the id as the id of a Kata container and attach to the VM of its sandbox. Serves the root filesystem of the container with 9p unless --backing-file is given."),
+                        )
+                    .arg(
+                        Arg::new("hypervisor")
+                        .long("hypervisor")
+                        .num_args(1)
+                        .value_parser(["auto", "qemu", "firecracker", "cloud-hypervisor", "crosvm", "kvmtool"])
+                        .default_value("auto")
+                        .long_help("Hypervisor running the VM. auto detects it from the process name. With firecracker, the pid of the jailer can be given, vmsh attaches to the firecracker process it started."),
+                        )
+                    .arg(
+                        Arg::new("mmio-transport")
+                        .long("mmio-transport")
+                        .alias("mmio")
+                        .num_args(1)
+                        .value_parser(clap::builder::PossibleValuesParser::new([
+                            PossibleValue::new("auto"),
+                            PossibleValue::new("ioregionfd"),
+                            PossibleValue::new("wrap").alias("wrap_syscall"),
+                        ]))
+                        .default_value("auto")
+                        .long_help("Backend used to serve Virtio MMIO memory of devices. auto uses ioregionfd if KVM supports it and intercepts MMIO exits otherwise."),
+                        )
+                    .arg(
+                        Arg::new("transport")
+                        .long("transport")
+                        .num_args(1)
+                        .value_parser(["mmio", "pci"])
+                        .default_value("mmio")
+                        .help("Expose devices as virtio-mmio platform devices or as virtio-pci devices on a new PCI root bus (requires Linux 4.9 or newer with virtio-pci)"),
                         )
                     .arg(
                         Arg::new("pts")
@@ -213,6 +1024,70 @@ fn cli() -> Command {
                         .value_parser(clap::value_parser!(PathBuf))
                         .help("Pseudoterminal seat to use for the command run in the VM. Use this when interactivity is required. ")
                         )
+                    .arg(
+                        Arg::new("record")
+                        .long("record")
+                        .visible_alias("log-console")
+                        .value_name("FILE")
+                        .num_args(1)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Record the console of the session with timestamps to FILE (asciinema v2 format): output, typed input and resizes. Play it back with `asciinema play FILE`.")
+                        )
+                    .arg(
+                        Arg::new("scrollback")
+                        .long("scrollback")
+                        .value_name("BYTES")
+                        .num_args(1)
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("65536")
+                        .help("Console output kept for `vmsh control <pid> new-console`, which replays it on connect. 0 disables it."),
+                        )
+                    .arg(
+                        Arg::new("console-port")
+                        .long("console-port")
+                        .num_args(1)
+                        .action(ArgAction::Append)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Connect a pseudoterminal to an additional console port, available as /dev/virtio-ports/vmsh.port1, vmsh.port2, ... in the VM. Can be passed multiple times.")
+                        )
+                    .arg(
+                        Arg::new("net")
+                        .long("net")
+                        .num_args(1)
+                        .value_name("TAP")
+                        .help("Add a virtio-net device to the VM that is backed by the given host tap device (i.e. tap0)")
+                        )
+                    .arg(
+                        Arg::new("vsock")
+                        .long("vsock")
+                        .num_args(1)
+                        .value_name("UDS_PATH")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Add a virtio-vsock device to the VM. Guest connections to host port N are forwarded to the unix socket UDS_PATH_N")
+                        )
+                    .arg(
+                        Arg::new("vsock-cid")
+                        .long("vsock-cid")
+                        .num_args(1)
+                        .value_parser(clap::value_parser!(u64))
+                        .help("Context id of the VM on the vsock device (default: 3)")
+                        )
+                    .arg(
+                        Arg::new("forward")
+                        .long("forward")
+                        .value_name("HOST_PORT:GUEST_PORT")
+                        .num_args(1)
+                        .action(ArgAction::Append)
+                        .requires("vsock")
+                        .value_parser(|s: &str| s.parse::<PortForward>().map_err(|e| e.to_string()))
+                        .help("Forward TCP connections to HOST_PORT on localhost to GUEST_PORT in the network namespace of the command. Requires --vsock, can be passed multiple times.")
+                        )
+                    .arg(
+                        Arg::new("rng")
+                        .long("rng")
+                        .action(ArgAction::SetTrue)
+                        .help("Add a virtio-rng device to the VM that feeds entropy from the host's /dev/urandom")
+                        )
        )
         .subcommand(
             Command::new("coredump")
@@ -221,28 +1096,342 @@ fn cli() -> Command {
                     .author(crate_authors!("\n"))
                     .arg(vmid_arg(1))
                     .arg(vmid_type_arg())
+                    .args(vm_select_args())
                     .arg(
                         Arg::new("PATH")
-                        .help("path to coredump. Defaults to core.${pid}")
+                        .help("path to coredump. Defaults to core.${pid} (core.${pid}.zst with --compress)")
                         .value_parser(clap::value_parser!(PathBuf))
                         .index(2)
                     )
+                    .arg(
+                        Arg::new("format")
+                        .long("format")
+                        .value_parser(["elf", "raw", "windows"])
+                        .default_value("elf")
+                        .help("elf: core file for gdb and crash. raw: physical memory at the offsets of its addresses, registers in PATH.json, for any guest. windows: full memory dump for WinDbg")
+                    )
+                    .arg(
+                        Arg::new("kernel-virtual")
+                        .long("kernel-virtual")
+                        .action(ArgAction::SetTrue)
+                        .help("Dump the kernel address space organized by virtual addresses (walks the guest page tables)")
+                    )
+                    .arg(
+                        Arg::new("compress")
+                        .long("compress")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("sparse")
+                        .help("Write a zstd compressed core file")
+                    )
+                    .arg(
+                        Arg::new("sparse")
+                        .long("sparse")
+                        .action(ArgAction::SetTrue)
+                        .help("Do not allocate disk space for pages of the VM that only contain zeros")
+                    )
+                    .arg(
+                        Arg::new("phys-range")
+                        .long("phys-range")
+                        .value_name("START-END")
+                        .action(ArgAction::Append)
+                        .conflicts_with_all(["kernel-virtual", "process-cr3"])
+                        .help("Only dump this guest-physical address range, i.e. 0x100000-0x200000. Can be passed multiple times")
+                    )
+                    .arg(
+                        Arg::new("process-cr3")
+                        .long("process-cr3")
+                        .value_name("CR3")
+                        .conflicts_with("kernel-virtual")
+                        .help("Dump the user address space of the guest process with this page table address (walks the guest page tables)")
+                    )
+                    .arg(
+                        Arg::new("backtrace")
+                        .long("backtrace")
+                        .action(ArgAction::SetTrue)
+                        .help("Log a backtrace of each vcpu that was stopped in the kernel")
+                    )
+                    .arg(symbols_arg())
         )
         .subcommand(
-            Command::new("console")
-                    .about("Uses the current console connected as potential target for vmsh")
+            Command::new("clock")
+                    .about("Show the kvmclock and time stamp counters of a running virtual machine, or shift them to fix time drift.")
+                    .version(crate_version!())
+                    .author(crate_authors!("\n"))
+                    .arg(vmid_arg(1))
+                    .arg(vmid_type_arg())
+                    .args(vm_select_args())
+                    .arg(
+                        Arg::new("advance")
+                        .long("advance")
+                        .value_name("NANOSECONDS")
+                        .value_parser(clap::value_parser!(i64))
+                        .allow_negative_numbers(true)
+                        .help("Add this many nanoseconds to the kvmclock, negative values set it back")
+                    )
+                    .arg(
+                        Arg::new("tsc-adjust")
+                        .long("tsc-adjust")
+                        .value_name("TICKS")
+                        .value_parser(clap::value_parser!(i64))
+                        .allow_negative_numbers(true)
+                        .help("Add this many ticks to the time stamp counter of every vcpu (IA32_TSC_ADJUST)")
+                    )
+        )
+        .subcommand(
+            Command::new("regs")
+                    .about("Print the registers and important MSRs of the vcpus of a running virtual machine.")
+                    .version(crate_version!())
+                    .author(crate_authors!("\n"))
+                    .arg(vmid_arg(1))
+                    .arg(vmid_type_arg())
+                    .args(vm_select_args())
+                    .arg(
+                        Arg::new("vcpu")
+                        .long("vcpu")
+                        .value_parser(clap::value_parser!(usize))
+                        .help("Only show this vcpu")
+                    )
+                    .arg(
+                        Arg::new("set")
+                        .long("set")
+                        .value_name("REGISTER=VALUE")
+                        .action(ArgAction::Append)
+                        .requires("vcpu")
+                        .help("Change a general purpose register of the vcpu selected with --vcpu before printing, i.e. rip=0xffffffff81000000. Can be passed multiple times")
+                    )
+        )
+        .subcommand(
+            Command::new("snapshot")
+                    .about("Save guest memory and vcpu registers of a virtual machine to a directory.")
+                    .version(crate_version!())
+                    .author(crate_authors!("\n"))
+                    .arg(vmid_arg(1))
+                    .arg(vmid_type_arg())
+                    .args(vm_select_args())
+                    .arg(
+                        Arg::new("out")
+                        .long("out")
+                        .value_name("DIR")
+                        .required(true)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Directory to write the snapshot to")
+                    )
+                    .arg(
+                        Arg::new("live")
+                        .long("live")
+                        .action(ArgAction::SetTrue)
+                        .help("Copy memory while the VM keeps running and only pause it to copy pages written in the meantime")
+                    )
+                    .arg(
+                        Arg::new("base")
+                        .long("base")
+                        .value_name("DIR")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Full snapshot to compare with, only pages that differ from it are saved. Restoring needs the base as well")
+                    )
+        )
+        .subcommand(
+            Command::new("restore")
+                    .about("Restore guest memory and vcpu registers of a virtual machine from a snapshot.")
+                    .version(crate_version!())
+                    .author(crate_authors!("\n"))
+                    .arg(vmid_arg(1))
+                    .arg(vmid_type_arg())
+                    .args(vm_select_args())
+                    .arg(
+                        Arg::new("from")
+                        .long("from")
+                        .value_name("DIR")
+                        .required(true)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Directory written by `vmsh snapshot`")
+                    )
+        )
+        .subcommand(
+            Command::new("gdbserver")
+                    .about("Serve the GDB remote protocol to debug the kernel of a virtual machine.")
+                    .version(crate_version!())
+                    .author(crate_authors!("\n"))
+                    .arg(vmid_arg(1))
+                    .arg(vmid_type_arg())
+                    .args(vm_select_args())
+                    .arg(
+                        Arg::new("port")
+                        .short('p')
+                        .long("port")
+                        .default_value("1234")
+                        .value_parser(clap::value_parser!(u16))
+                        .help("TCP port on localhost that gdb connects to")
+                    )
+        )
+        .subcommand(
+            Command::new("mem")
+                    .about("Read or write memory of a running virtual machine.")
+                    .version(crate_version!())
+                    .author(crate_authors!("\n"))
+                    .arg(vmid_arg(1))
+                    .arg(vmid_type_arg())
+                    .args(vm_select_args())
+                    .arg(
+                        Arg::new("ADDRESS")
+                        .required(true)
+                        .help("Guest-physical address, or virtual address with --virtual")
+                        .index(2)
+                    )
+                    .arg(
+                        Arg::new("length")
+                        .short('l')
+                        .long("length")
+                        .default_value("256")
+                        .value_parser(clap::value_parser!(usize))
+                        .help("Number of bytes to read")
+                    )
+                    .arg(
+                        Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Write the raw memory to this file instead of printing a hexdump")
+                    )
+                    .arg(
+                        Arg::new("write")
+                        .long("write")
+                        .value_name("FILE")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .conflicts_with("output")
+                        .help("Write the content of FILE to the address instead of reading")
+                    )
+                    .arg(
+                        Arg::new("virtual")
+                        .long("virtual")
+                        .action(ArgAction::SetTrue)
+                        .help("Translate the address with the page tables of the kernel")
+                    )
+                    .arg(
+                        Arg::new("cr3")
+                        .long("cr3")
+                        .requires("virtual")
+                        .help("Translate with the page tables at this address instead, i.e. of a process")
+                    )
+        )
+        .subcommand(
+            Command::new("detach")
+                    .about("Detach vmsh from a virtual machine, but keep the session in the guest running. Attaching again resumes it.")
+                    .version(crate_version!())
+                    .author(crate_authors!("\n"))
+                    .arg(vmid_arg(1))
+                    .arg(vmid_type_arg())
+        )
+        .subcommand(
+            Command::new("control")
+                    .about("Control a vmsh process started with attach --daemon.")
+                    .version(crate_version!())
+                    .author(crate_authors!("\n"))
+                    .arg(vmid_arg(1))
+                    .arg(vmid_type_arg())
+                    .arg(
+                        Arg::new("command")
+                        .required(true)
+                        .index(2)
+                        .value_parser(["status", "new-console", "new-shell", "detach", "stop"])
+                        .help("status prints the session, new-console connects to the console of the command, new-shell starts another shell in the guest (requires --vsock, leave both with Ctrl-]), detach and stop end the session"),
+                        )
+        )
+        .subcommand(
+            Command::new("exec")
+                    .about("Run a command in a VM attached with --daemon and --vsock. Exits with the exit code of the command.")
+                    .version(crate_version!())
+                    .author(crate_authors!("\n"))
+                    .arg(vmid_arg(1))
+                    .arg(vmid_type_arg())
+                    .arg(command_args(2).required(true))
+        )
+        .subcommand(
+            Command::new("cp")
+                    .about("Copy a file between host and a VM attached with --daemon and --vsock. The guest side is written as <id>:<path>.")
+                    .version(crate_version!())
+                    .author(crate_authors!("\n"))
+                    .arg(
+                        Arg::new("source")
+                        .required(true)
+                        .index(1)
+                        .help("File to copy, i.e. <id>:/etc/os-release or ./script.sh"),
+                        )
+                    .arg(
+                        Arg::new("destination")
+                        .required(true)
+                        .index(2)
+                        .help("Target file or directory, guest directories need a trailing /"),
+                        )
+                    .arg(vmid_type_arg())
+        )
+        .subcommand(
+            Command::new("ps")
+                    .about("List the processes of a running virtual machine.")
                     .version(crate_version!())
                     .author(crate_authors!("\n"))
                     .arg(vmid_arg(1))
                     .arg(vmid_type_arg())
+                    .args(vm_select_args())
                     .arg(
-                        Arg::new("stage2-path")
-                        .long("stage2-path")
+                        Arg::new("offsets")
+                        .long("offsets")
+                        .value_name("FIELD=OFFSET,...")
+                        .help("Offsets of tasks, pid, comm or state in struct task_struct, for kernels where they cannot be detected")
+                    )
+        )
+        .subcommand(
+            Command::new("check")
+                    .about("Check whether vmsh can attach to a virtual machine, without changing it.")
+                    .version(crate_version!())
+                    .author(crate_authors!("\n"))
+                    .arg(vmid_arg(1))
+                    .arg(vmid_type_arg())
+                    .args(vm_select_args())
+                    .arg(
+                        Arg::new("hypervisor")
+                        .long("hypervisor")
                         .num_args(1)
-                        .default_value("/dev/.vmsh")
-                        .help("Path where Stage2 is written to in the VM"),
+                        .value_parser(["qemu", "firecracker", "cloud-hypervisor", "crosvm", "kvmtool"])
+                        .help("Hypervisor running the VM, detected from the process name by default"),
                         )
+        )
+        .subcommand(
+            Command::new("dmesg")
+                    .about("Print the kernel log of a running virtual machine.")
+                    .version(crate_version!())
+                    .author(crate_authors!("\n"))
+                    .arg(vmid_arg(1))
+                    .arg(vmid_type_arg())
+                    .args(vm_select_args())
+        )
+        .subcommand(
+            Command::new("crash")
+                    .about("Deliberately crash a hung virtual machine, i.e. to capture it with kdump.")
+                    .version(crate_version!())
+                    .author(crate_authors!("\n"))
+                    .arg(vmid_arg(1))
+                    .arg(vmid_type_arg())
+                    .args(vm_select_args())
+                    .arg(
+                        Arg::new("method")
+                        .long("method")
+                        .value_parser(["nmi", "sysrq"])
+                        .default_value("nmi")
+                        .help("nmi: inject an NMI, linux guests need kernel.unknown_nmi_panic=1 to panic. sysrq: write c to /proc/sysrq-trigger, needs a VM attached with --daemon and --vsock")
+                    )
+        )
+        .subcommand(
+            Command::new("console")
+                    .about("Uses the current console connected as potential target for vmsh")
+                    .version(crate_version!())
+                    .author(crate_authors!("\n"))
+                    .arg(vmid_arg(1))
+                    .arg(vmid_type_arg())
+                    .args(vm_select_args())
+                    .arg(stage2_path_arg())
                     .arg(command_args(2))
+                    .args(command_env_args())
                     .arg(
                         Arg::new("backing-file")
                         .short('f')
@@ -258,19 +1447,84 @@ fn cli() -> Command {
                         .help("Pseudoterminal seat to use for the command run in the VM. Use this when interactivity is required. ")
                     )
         )
+        .subcommand(
+            Command::new("daemon")
+                    .about("Serve a JSON-RPC API on a unix socket to attach to virtual machines.")
+                    .version(crate_version!())
+                    .author(crate_authors!("\n"))
+                    .arg(
+                        Arg::new("listen")
+                        .long("listen")
+                        .num_args(1)
+                        .default_value("/run/vmsh.sock")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Unix socket to listen on"),
+                        )
+                    .arg(
+                        Arg::new("token-file")
+                        .long("token-file")
+                        .num_args(1)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("File containing a token that clients have to pass in every request"),
+                        )
+                    .arg(stage2_path_arg())
+        )
 }
 
 fn main() {
     let matches = cli().get_matches();
     setup_logging(&matches);
+    // flushed when dropped, subcommands that exit with an error lose the end of the trace
+    let trace = matches.get_one::<PathBuf>("trace-file").map(|path| {
+        match chrome_trace::record_to_file(path) {
+            Ok(guard) => guard,
+            Err(err) => {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+    });
+    if let Some(path) = matches.get_one::<PathBuf>("audit-log") {
+        if let Err(err) = audit_log::open(path) {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    }
+    if matches.get_flag("read-only") {
+        hypervisor::set_read_only();
+    }
+    if matches.get_flag("allow-encrypted") {
+        hypervisor::allow_encrypted();
+    }
+    let mut code = 0;
     match matches.subcommand() {
         Some(("inspect", sub_matches)) => inspect(sub_matches),
-        Some(("attach", sub_matches)) => attach(sub_matches),
+        Some(("attach", sub_matches)) => code = attach(sub_matches),
+        Some(("detach", sub_matches)) => detach(sub_matches),
+        Some(("control", sub_matches)) => control(sub_matches),
+        Some(("cp", sub_matches)) => cp(sub_matches),
+        Some(("exec", sub_matches)) => code = exec(sub_matches),
         Some(("coredump", sub_matches)) => coredump(sub_matches),
+        Some(("gdbserver", sub_matches)) => gdbserver(sub_matches),
+        Some(("mem", sub_matches)) => mem(sub_matches),
+        Some(("regs", sub_matches)) => regs(sub_matches),
+        Some(("clock", sub_matches)) => clock(sub_matches),
+        Some(("snapshot", sub_matches)) => snapshot(sub_matches),
+        Some(("restore", sub_matches)) => restore(sub_matches),
+        Some(("ps", sub_matches)) => ps(sub_matches),
+        Some(("dmesg", sub_matches)) => dmesg(sub_matches),
+        Some(("crash", sub_matches)) => crash(sub_matches),
+        Some(("check", sub_matches)) => check(sub_matches),
         Some(("console", sub_matches)) => console(sub_matches),
+        Some(("daemon", sub_matches)) => daemon(sub_matches),
         Some((_, _)) => unreachable!(),
         None => unreachable!(),
     }
+    // attach and exec pass on the exit code of the guest command, write the trace first
+    drop(trace);
+    if code != 0 {
+        std::process::exit(code);
+    }
 }
 
 #[cfg(test)]
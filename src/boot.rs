@@ -0,0 +1,77 @@
+//! Attaching to VMs that did not boot yet, i.e. QEMU started with `-S` or a guest that is still
+//! in its firmware or early kernel. stage1 needs a kernel that finished booting, so
+//! `vmsh attach --wait-for-boot` polls the guest until its kernel started the init program.
+
+use log::{info, warn};
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+use crate::guest_mem::{GuestMem, KernelMem};
+use crate::kernel::find_kernel;
+use crate::kvm::hypervisor::Hypervisor;
+use crate::ps;
+use crate::result::{Result, VmshError};
+
+/// The VM is stopped for every check, it keeps running in between.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, PartialEq)]
+enum BootState {
+    /// Firmware, bootloader or decompressor, or paging is not set up yet.
+    NoKernel,
+    /// The kernel runs, but did not start init yet.
+    Kernel,
+    /// init runs with this command, None if the kernel does not tell us.
+    Booted(Option<String>),
+}
+
+/// Needs a stopped VM.
+fn boot_state(vm: &Hypervisor) -> BootState {
+    let guest_mem = match GuestMem::new(vm) {
+        Ok(mem) => mem,
+        Err(_) => return BootState::NoKernel,
+    };
+    let kernel = match find_kernel(&guest_mem, vm) {
+        Ok(kernel) => kernel,
+        Err(_) => return BootState::NoKernel,
+    };
+    let init_task = match kernel.symbols.get("init_task") {
+        Some(init_task) => *init_task,
+        None => return BootState::Booted(None),
+    };
+    let comm = KernelMem::new(vm, &guest_mem).and_then(|mem| ps::init_comm(&mem, init_task));
+    match comm {
+        // init is a copy of init_task until it executes the init program
+        Ok(comm) if !comm.starts_with("swapper") => BootState::Booted(Some(comm)),
+        _ => BootState::Kernel,
+    }
+}
+
+/// Returns once the kernel of the guest started init. Fails with `VmshError::Cancelled` once a
+/// message arrives on `stop`, i.e. if vmsh is stopped.
+pub fn wait_for_boot(vm: &Hypervisor, stop: &Receiver<()>) -> Result<()> {
+    info!("waiting for the guest to boot");
+    let mut last = BootState::NoKernel;
+    loop {
+        vm.stop()?;
+        let state = boot_state(vm);
+        vm.resume()?;
+        if state != last {
+            match &state {
+                BootState::NoKernel => {}
+                BootState::Kernel => info!("found the guest kernel, waiting for init"),
+                BootState::Booted(Some(comm)) => info!("guest booted, init runs {}", comm),
+                BootState::Booted(None) => {
+                    warn!("kernel does not export init_task, assume that it booted")
+                }
+            }
+        }
+        if let BootState::Booted(_) = state {
+            return Ok(());
+        }
+        last = state;
+        if stop.recv_timeout(POLL_INTERVAL).is_ok() {
+            return Err(VmshError::Cancelled("the guest to boot".into()));
+        }
+    }
+}
@@ -0,0 +1,209 @@
+//! Reads the BPF Type Format (BTF) that kernels built with CONFIG_DEBUG_INFO_BTF carry in their
+//! `.BTF` section (also exposed as /sys/kernel/btf/vmlinux). We only need the layout of a few
+//! structs stage1 passes to the kernel, see `loader::layouts`.
+//!
+//! Format: https://www.kernel.org/doc/html/latest/bpf/btf.html
+
+use simple_error::{bail, require_with};
+use std::convert::TryInto;
+
+use crate::result::Result;
+
+pub const BTF_MAGIC: u16 = 0xeb9f;
+/// Size of `struct btf_header` of version 1
+pub const HEADER_LEN: usize = 24;
+/// Larger sections are not taken for BTF, vmlinux has a few MiB.
+const MAX_LEN: usize = 64 << 20;
+
+const KIND_INT: u32 = 1;
+const KIND_ARRAY: u32 = 3;
+const KIND_STRUCT: u32 = 4;
+const KIND_UNION: u32 = 5;
+const KIND_ENUM: u32 = 6;
+const KIND_FUNC_PROTO: u32 = 13;
+const KIND_VAR: u32 = 14;
+const KIND_DATASEC: u32 = 15;
+const KIND_DECL_TAG: u32 = 17;
+const KIND_ENUM64: u32 = 19;
+
+/// Size of `struct btf_type`
+const TYPE_LEN: usize = 12;
+
+fn u32_at(data: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(off..off + 4)?.try_into().ok()?))
+}
+
+/// Length of the BTF data (header, types and strings) starting at `data`, None if it does not
+/// start with a BTF header.
+pub fn data_len(data: &[u8]) -> Option<usize> {
+    let magic = u16::from_le_bytes(data.get(0..2)?.try_into().ok()?);
+    if magic != BTF_MAGIC || data.get(2) != Some(&1) || u32_at(data, 4)? as usize != HEADER_LEN {
+        return None;
+    }
+    let type_off = u32_at(data, 8)? as usize;
+    let type_len = u32_at(data, 12)? as usize;
+    let str_off = u32_at(data, 16)? as usize;
+    let str_len = u32_at(data, 20)? as usize;
+    let len = HEADER_LEN + (type_off + type_len).max(str_off + str_len);
+    if type_len == 0 || str_len == 0 || len > MAX_LEN {
+        return None;
+    }
+    Some(len)
+}
+
+/// A struct as described by BTF. Offsets are in bytes, bitfields are not supported.
+#[derive(Debug, PartialEq)]
+pub struct Struct {
+    pub name: String,
+    pub size: u32,
+    pub members: Vec<(String, u32)>,
+}
+
+impl Struct {
+    /// Offset of `member`
+    pub fn offset(&self, member: &str) -> Result<u32> {
+        let offset = self
+            .members
+            .iter()
+            .find(|(name, _)| name == member)
+            .map(|(_, offset)| *offset);
+        Ok(require_with!(
+            offset,
+            "struct {} has no member {}",
+            self.name,
+            member
+        ))
+    }
+}
+
+pub struct Btf<'a> {
+    types: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> Btf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Btf<'a>> {
+        let len = require_with!(data_len(data), "no BTF header found");
+        if data.len() < len {
+            bail!("BTF data is truncated: {} < {} bytes", data.len(), len);
+        }
+        // data_len checked that the header fields exist and the sections fit into `len`
+        let section = |field: usize| {
+            let off = HEADER_LEN + u32_at(data, field).unwrap_or(0) as usize;
+            let len = u32_at(data, field + 4).unwrap_or(0) as usize;
+            &data[off..off + len]
+        };
+        Ok(Btf {
+            types: section(8),
+            strings: section(16),
+        })
+    }
+
+    fn name(&self, off: u32) -> Option<&'a str> {
+        let rest = self.strings.get(off as usize..)?;
+        let len = rest.iter().position(|c| *c == 0)?;
+        std::str::from_utf8(&rest[..len]).ok()
+    }
+
+    /// Finds the definition of `struct name`, forward declarations are skipped.
+    pub fn find_struct(&self, name: &str) -> Result<Struct> {
+        let mut off = 0;
+        while off + TYPE_LEN <= self.types.len() {
+            let (name_off, info, size) = match (
+                u32_at(self.types, off),
+                u32_at(self.types, off + 4),
+                u32_at(self.types, off + 8),
+            ) {
+                (Some(name_off), Some(info), Some(size)) => (name_off, info, size),
+                _ => break,
+            };
+            let vlen = (info & 0xffff) as usize;
+            let kind = (info >> 24) & 0x1f;
+            let kind_flag = info >> 31 == 1;
+            let members = off + TYPE_LEN;
+            off = members
+                + match kind {
+                    KIND_INT | KIND_VAR | KIND_DECL_TAG => 4,
+                    KIND_ARRAY => 12,
+                    KIND_STRUCT | KIND_UNION | KIND_DATASEC | KIND_ENUM64 => vlen * 12,
+                    KIND_ENUM | KIND_FUNC_PROTO => vlen * 8,
+                    _ => 0,
+                };
+            if kind != KIND_STRUCT || self.name(name_off) != Some(name) {
+                continue;
+            }
+            let mut fields = vec![];
+            for i in 0..vlen {
+                let member = members + i * 12;
+                let (field_name, bits) =
+                    match (u32_at(self.types, member), u32_at(self.types, member + 8)) {
+                        (Some(name), Some(bits)) => (name, bits),
+                        _ => bail!("member {} of struct {} is truncated", i, name),
+                    };
+                // with kind_flag, the upper 8 bits hold the size of bitfields
+                let bits = if kind_flag { bits & 0xff_ffff } else { bits };
+                if bits % 8 != 0 {
+                    continue;
+                }
+                let field_name = self.name(field_name).unwrap_or_default();
+                fields.push((field_name.to_string(), bits / 8));
+            }
+            return Ok(Struct {
+                name: name.to_string(),
+                size,
+                members: fields,
+            });
+        }
+        bail!("no struct {} in BTF", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_u32(buf: &mut Vec<u8>, vals: &[u32]) {
+        for v in vals {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+    }
+
+    #[test]
+    fn find_struct() {
+        let strings = b"\0int\0resource\0start\0end\0flags\0".to_vec();
+        let mut types = vec![];
+        // [1] int, 8 bytes
+        push_u32(&mut types, &[1, KIND_INT << 24, 8, 64]);
+        // forward declaration of struct resource
+        push_u32(&mut types, &[5, 7 << 24, 0]);
+        // [3] struct resource { start; end; flags : 4 (bitfield) }
+        push_u32(&mut types, &[5, KIND_STRUCT << 24 | 1 << 31 | 3, 24]);
+        push_u32(&mut types, &[14, 1, 0, 20, 1, 64, 24, 1, 4 << 24 | 128]);
+
+        let mut data = vec![];
+        data.extend_from_slice(&BTF_MAGIC.to_le_bytes());
+        data.extend_from_slice(&[1, 0]);
+        push_u32(
+            &mut data,
+            &[
+                HEADER_LEN as u32,
+                0,
+                types.len() as u32,
+                types.len() as u32,
+                strings.len() as u32,
+            ],
+        );
+        data.extend_from_slice(&types);
+        data.extend_from_slice(&strings);
+        assert_eq!(data_len(&data), Some(data.len()));
+
+        let btf = Btf::parse(&data).unwrap();
+        let resource = btf.find_struct("resource").unwrap();
+        assert_eq!(resource.size, 24);
+        assert_eq!(resource.offset("end").ok(), Some(8));
+        assert_eq!(resource.offset("flags").ok(), Some(16));
+        assert!(resource.offset("desc").is_err());
+        assert!(btf.find_struct("work_struct").is_err());
+        assert!(Btf::parse(&data[..data.len() - 1]).is_err());
+    }
+}
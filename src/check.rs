@@ -0,0 +1,329 @@
+//! `vmsh check`: tests whether `vmsh attach` can work on a VM without changing anything, so
+//! unsupported setups are found before an attach fails halfway. The hypervisor is attached in
+//! read-only mode (see `kvm::hypervisor::set_read_only`).
+
+use kvm_bindings as kvmb;
+use libc::c_int;
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::guest_mem::{GuestMem, KernelMem};
+use crate::kernel::{find_kernel, Lockdown};
+use crate::kvm;
+use crate::kvm::hypervisor::flavor::{self, Flavor};
+use crate::kvm::hypervisor::{Hypervisor, VmSelector};
+use crate::kvm::kvm_ioregionfd::KVM_CAP_IOREGIONFD;
+use crate::loader::{compat, layouts, module};
+use crate::result::Result;
+use crate::stage1;
+use crate::tracer::proc;
+
+/// KVM capabilities attach cannot do without.
+const REQUIRED_CAPS: &[(u32, &str)] = &[
+    (kvmb::KVM_CAP_USER_MEMORY, "USER_MEMORY"),
+    (kvmb::KVM_CAP_IOEVENTFD, "IOEVENTFD"),
+    (kvmb::KVM_CAP_IRQFD, "IRQFD"),
+];
+
+/// KVM capabilities some devices or transports use if they are available.
+const OPTIONAL_CAPS: &[(u32, &str)] = &[
+    (kvmb::KVM_CAP_IRQFD_RESAMPLE, "IRQFD_RESAMPLE"),
+    (kvmb::KVM_CAP_SIGNAL_MSI, "SIGNAL_MSI"),
+    (KVM_CAP_IOREGIONFD, "IOREGIONFD"),
+];
+
+pub struct CheckOptions {
+    pub pid: Pid,
+    pub vm: Option<VmSelector>,
+    pub hypervisor: Option<Flavor>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+struct Check {
+    name: &'static str,
+    status: Status,
+    detail: String,
+}
+
+#[derive(Default)]
+struct Report {
+    checks: Vec<Check>,
+}
+
+impl Report {
+    fn add(&mut self, name: &'static str, status: Status, detail: impl fmt::Display) {
+        self.checks.push(Check {
+            name,
+            status,
+            detail: detail.to_string(),
+        });
+    }
+
+    /// Adds `res` as failed check with the error, or as passed one with the message it holds.
+    fn require(&mut self, name: &'static str, res: Result<String>) -> bool {
+        match res {
+            Ok(detail) => self.add(name, Status::Ok, detail),
+            Err(e) => self.add(name, Status::Fail, e),
+        }
+        self.checks.last().map_or(false, |c| c.status == Status::Ok)
+    }
+
+    fn failed(&self) -> usize {
+        self.checks
+            .iter()
+            .filter(|c| c.status == Status::Fail)
+            .count()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for check in &self.checks {
+            let status = match check.status {
+                Status::Ok => "ok",
+                Status::Warn => "warn",
+                Status::Fail => "FAIL",
+            };
+            writeln!(f, "[{:<4}] {:<12} {}", status, check.name, check.detail)?;
+        }
+        Ok(())
+    }
+}
+
+/// Injected syscalls kill hypervisors whose threads all have a seccomp filter that does not
+/// expect them, see `flavor::check_seccomp`.
+fn check_seccomp(report: &mut Report, pid: Pid, flavor: Flavor) {
+    let threads = match proc::threads(pid) {
+        Ok(threads) => threads,
+        Err(e) => return report.add("seccomp", Status::Warn, e),
+    };
+    let filtered = threads
+        .iter()
+        .all(|tid| proc::has_seccomp_filter(pid, *tid).unwrap_or(false));
+    if !filtered {
+        report.add("seccomp", Status::Ok, "not all threads are filtered");
+    } else if flavor == Flavor::Firecracker {
+        report.add("seccomp", Status::Fail, flavor.seccomp_hint());
+    } else {
+        let hint = format!(
+            "all threads have a filter. If attaching fails, {}",
+            flavor.seccomp_hint()
+        );
+        report.add("seccomp", Status::Warn, hint);
+    }
+}
+
+fn check_caps(report: &mut Report, vm: &Hypervisor) {
+    let has_cap = |cap: u32| vm.check_extension(cap as c_int).map_or(false, |r| r > 0);
+    let missing = REQUIRED_CAPS
+        .iter()
+        .filter(|(cap, _)| !has_cap(*cap))
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        report.add("kvm", Status::Ok, "all required capabilities are present");
+    } else {
+        report.add(
+            "kvm",
+            Status::Fail,
+            format!("missing {}", missing.join(", ")),
+        );
+    }
+    let optional = OPTIONAL_CAPS
+        .iter()
+        .map(|(cap, name)| format!("{}{}", if has_cap(*cap) { "+" } else { "-" }, name))
+        .collect::<Vec<_>>();
+    report.add(
+        "kvm",
+        Status::Ok,
+        format!("optional: {}", optional.join(" ")),
+    );
+
+    // every device needs memory for its queues and stage1 needs one for its code
+    let memslots = vm.get_maps().and_then(|maps| {
+        let max = vm.check_extension(kvmb::KVM_CAP_NR_MEMSLOTS as c_int)?;
+        let used = maps.len();
+        if used >= max.max(0) as usize {
+            bail!("all {} memslots are in use", max);
+        }
+        Ok(format!("{} of {} in use", used, max))
+    });
+    report.require("memslots", memslots);
+}
+
+fn check_kernel(report: &mut Report, vm: &Hypervisor) {
+    let guest_mem = match GuestMem::new(vm) {
+        Ok(mem) => mem,
+        Err(e) => return report.add("kernel", Status::Fail, e),
+    };
+    let kernel = match find_kernel(&guest_mem, vm) {
+        Ok(kernel) => kernel,
+        Err(e) => return report.add("kernel", Status::Fail, e),
+    };
+    let found = format!(
+        "found at {:#x}-{:#x} with {} exported symbols",
+        kernel.range.start,
+        kernel.range.end,
+        kernel.symbols.len()
+    );
+    match kernel.version(vm) {
+        Ok(release) => report.add(
+            "kernel",
+            Status::Ok,
+            format!("Linux {}, {}", release, found),
+        ),
+        Err(e) => report.add(
+            "kernel",
+            Status::Warn,
+            format!("{}, unknown version: {}", found, e),
+        ),
+    }
+
+    let lockdown = KernelMem::new(vm, &guest_mem).and_then(|mem| kernel.lockdown(&mem));
+    match lockdown {
+        Ok(Lockdown {
+            locked_down_by: Some(by),
+            ..
+        }) => report.add(
+            "lockdown",
+            Status::Fail,
+            format!("kernel is locked down by {}", by),
+        ),
+        Ok(lockdown) if lockdown.secure_boot => {
+            report.add("lockdown", Status::Ok, "secure boot, but not locked down")
+        }
+        Ok(_) => report.add("lockdown", Status::Ok, "not locked down"),
+        Err(e) => report.add("lockdown", Status::Warn, format!("unknown: {}", e)),
+    }
+
+    let binary = match stage1::parse_binary() {
+        Ok(binary) => binary,
+        Err(e) => return report.add("stage1", Status::Fail, e),
+    };
+    let missing = binary.missing_symbols(&kernel.symbols);
+    if missing.is_empty() {
+        report.add(
+            "stage1",
+            Status::Ok,
+            "kernel exports all symbols stage1 needs",
+        );
+    } else {
+        let detail = format!(
+            "kernel does not export {}",
+            compat::describe_missing(&missing)
+        );
+        report.add("stage1", Status::Fail, detail);
+    }
+
+    match layouts::read(&kernel, vm) {
+        Ok(_) => report.add("layouts", Status::Ok, "read from the BTF of the kernel"),
+        Err(e) => report.add(
+            "layouts",
+            Status::Warn,
+            format!("{}, stage1 assumes them from the kernel version", e),
+        ),
+    }
+
+    match module::probe(&kernel, vm) {
+        Ok(_) => report.add(
+            "module",
+            Status::Ok,
+            "stage1 registers its devices through the module loader",
+        ),
+        Err(e) => report.add(
+            "module",
+            Status::Ok,
+            format!("{}, stage1 registers its devices directly", e),
+        ),
+    }
+
+    // stage1 is loaded below the kernel, the strings of its arguments follow it
+    let needed = binary.load_size();
+    let free = kernel.largest_gap.len();
+    let status = if free > needed {
+        Status::Ok
+    } else {
+        Status::Fail
+    };
+    let detail = format!(
+        "{} KiB free in the kernel address range, stage1 needs {} KiB",
+        free / 1024,
+        needed / 1024
+    );
+    report.add("space", status, detail);
+}
+
+fn run_checks(report: &mut Report, opts: &CheckOptions) {
+    let pid = match flavor::vm_process(opts.pid, opts.hypervisor) {
+        Ok(pid) => pid,
+        Err(e) => return report.add("hypervisor", Status::Fail, e),
+    };
+    let flavor = opts.hypervisor.unwrap_or_else(|| Flavor::detect(pid));
+    let vm = match kvm::hypervisor::get_hypervisor(pid, opts.vm) {
+        Ok(vm) => vm,
+        Err(e) => return report.add("hypervisor", Status::Fail, e),
+    };
+    report.add(
+        "hypervisor",
+        Status::Ok,
+        format!("{:?} process {} with {} vcpus", flavor, pid, vm.vcpus.len()),
+    );
+    check_seccomp(report, pid, flavor);
+
+    if let Err(e) = vm.stop() {
+        let detail = format!(
+            "{}. vmsh needs CAP_SYS_PTRACE or a kernel.yama.ptrace_scope that allows tracing",
+            e
+        );
+        return report.add("ptrace", Status::Fail, detail);
+    }
+    report.add("ptrace", Status::Ok, "can trace the hypervisor");
+    check_caps(report, &vm);
+    check_kernel(report, &vm);
+}
+
+pub fn check(opts: &CheckOptions) -> Result<()> {
+    kvm::hypervisor::set_read_only();
+    let mut report = Report::default();
+    run_checks(&mut report, opts);
+    try_with!(
+        write!(io::stdout().lock(), "{}", report),
+        "cannot write to stdout"
+    );
+    match report.failed() {
+        0 => Ok(()),
+        n => bail!("{} of {} checks failed", n, report.checks.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report() {
+        let mut report = Report::default();
+        report.add("kvm", Status::Ok, "all required capabilities are present");
+        assert!(!report.require("stage1", bail_with("missing symbols")));
+        report.add("seccomp", Status::Warn, "filtered");
+        assert_eq!(report.failed(), 1);
+        assert_eq!(
+            report.to_string(),
+            "[ok  ] kvm          all required capabilities are present\n\
+             [FAIL] stage1       missing symbols\n\
+             [warn] seccomp      filtered\n"
+        );
+    }
+
+    fn bail_with(msg: &str) -> Result<String> {
+        bail!("{}", msg)
+    }
+}
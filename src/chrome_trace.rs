@@ -0,0 +1,33 @@
+//! Records the `tracing` spans of vmsh (attach, mmio-exit, queue-handler, ioregionfd) in the
+//! Chrome trace event format. The file can be opened in chrome://tracing or
+//! https://ui.perfetto.dev to see where time goes, i.e. during slow attaches.
+//!
+//! Log messages are not affected and still go through `log`.
+
+use simple_error::try_with;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use tracing_chrome::{ChromeLayerBuilder, FlushGuard};
+use tracing_subscriber::prelude::*;
+
+use crate::result::Result;
+
+/// Writes the remaining events and closes the trace when dropped.
+pub struct TraceGuard {
+    _guard: FlushGuard,
+}
+
+/// Starts recording spans of all threads to `path`.
+pub fn record_to_file(path: &Path) -> Result<TraceGuard> {
+    let file = try_with!(File::create(path), "cannot create {}", path.display());
+    let (layer, guard) = ChromeLayerBuilder::new()
+        .writer(BufWriter::new(file))
+        .include_args(true)
+        .build();
+    try_with!(
+        tracing_subscriber::registry().with(layer).try_init(),
+        "cannot install trace subscriber"
+    );
+    Ok(TraceGuard { _guard: guard })
+}
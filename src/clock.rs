@@ -0,0 +1,169 @@
+//! `vmsh clock`: shows the kvmclock and time stamp counters of a VM and shifts them, i.e. to
+//! fix the time drift of a guest that was stopped for long by ptrace during an attach.
+
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::io::{self, Write};
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use kvm_bindings as kvmb;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use libc::c_int;
+
+use crate::kvm;
+use crate::kvm::hypervisor::{Hypervisor, VmSelector};
+use crate::result::Result;
+
+pub struct ClockOptions {
+    pub pid: Pid,
+    pub vm: Option<VmSelector>,
+    /// Nanoseconds to add to the kvmclock, negative to set it back
+    pub advance: Option<i64>,
+    /// Ticks to add to IA32_TSC_ADJUST of every vcpu
+    pub tsc_adjust: Option<i64>,
+}
+
+const KVM_CLOCK_TSC_STABLE: u32 = 1 << 1;
+const KVM_CLOCK_REALTIME: u32 = 1 << 2;
+const KVM_CLOCK_HOST_TSC: u32 = 1 << 3;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const MSR_IA32_TSC: u32 = 0x10;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const MSR_IA32_TSC_ADJUST: u32 = 0x3b;
+
+/// Names of the KVM_CLOCK_* `flags` of kvm_clock_data
+fn flag_names(flags: u32) -> String {
+    let names = [
+        (KVM_CLOCK_TSC_STABLE, "tsc-stable"),
+        (KVM_CLOCK_REALTIME, "realtime"),
+        (KVM_CLOCK_HOST_TSC, "host-tsc"),
+    ]
+    .iter()
+    .filter(|(flag, _)| flags & flag != 0)
+    .map(|(_, name)| *name)
+    .collect::<Vec<_>>();
+    if names.is_empty() {
+        "none".to_string()
+    } else {
+        names.join(", ")
+    }
+}
+
+fn format_ns(ns: u64) -> String {
+    format!("{}.{:09}s", ns / 1_000_000_000, ns % 1_000_000_000)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn msr(index: u32) -> kvmb::kvm_msr_entry {
+    kvmb::kvm_msr_entry {
+        index,
+        ..Default::default()
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn adjust(vm: &Hypervisor, opts: &ClockOptions) -> Result<()> {
+    if let Some(ns) = opts.advance {
+        let has_cap = vm.check_extension(kvmb::KVM_CAP_ADJUST_CLOCK as c_int)?;
+        if has_cap <= 0 {
+            bail!("kvm cannot adjust the clock (KVM_CAP_ADJUST_CLOCK)");
+        }
+        let mut clock = vm.get_clock()?;
+        clock.clock = if ns < 0 {
+            clock.clock.saturating_sub(ns.unsigned_abs())
+        } else {
+            clock.clock.saturating_add(ns as u64)
+        };
+        // without KVM_CLOCK_REALTIME, the clock is set to exactly this value
+        clock.flags = 0;
+        try_with!(vm.set_clock(&clock), "cannot set kvmclock");
+    }
+    if let Some(ticks) = opts.tsc_adjust {
+        for vcpu in &vm.vcpus {
+            let mut entry = vm.get_msr(vcpu, &msr(MSR_IA32_TSC_ADJUST))?;
+            entry.data = (entry.data as i64).wrapping_add(ticks) as u64;
+            try_with!(
+                vm.set_msr(vcpu, &entry),
+                "cannot adjust the tsc of vcpu {}",
+                vcpu.idx
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn write_clock(out: &mut dyn Write, vm: &Hypervisor) -> Result<()> {
+    let clock = vm.get_clock()?;
+    try_with!(
+        writeln!(
+            out,
+            "kvmclock: {} (flags: {})",
+            format_ns(clock.clock),
+            flag_names(clock.flags)
+        ),
+        "cannot write to stdout"
+    );
+    if clock.flags & KVM_CLOCK_REALTIME != 0 {
+        try_with!(
+            writeln!(out, "host realtime: {}", format_ns(clock.realtime)),
+            "cannot write to stdout"
+        );
+    }
+    if clock.flags & KVM_CLOCK_HOST_TSC != 0 {
+        try_with!(
+            writeln!(out, "host tsc: {}", clock.host_tsc),
+            "cannot write to stdout"
+        );
+    }
+    for vcpu in &vm.vcpus {
+        let tsc = vm.get_msr(vcpu, &msr(MSR_IA32_TSC))?;
+        let tsc_adjust = vm.get_msr(vcpu, &msr(MSR_IA32_TSC_ADJUST))?;
+        try_with!(
+            writeln!(
+                out,
+                "vcpu {}: tsc {} tsc_adjust {}",
+                vcpu.idx, tsc.data, tsc_adjust.data as i64
+            ),
+            "cannot write to stdout"
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn adjust(_vm: &Hypervisor, _opts: &ClockOptions) -> Result<()> {
+    bail!("adjusting the clock is only supported on x86")
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn write_clock(_out: &mut dyn Write, _vm: &Hypervisor) -> Result<()> {
+    bail!("reading the clock is only supported on x86")
+}
+
+pub fn clock(opts: &ClockOptions) -> Result<()> {
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid, opts.vm),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    vm.stop()?;
+    adjust(&vm, opts)?;
+    write_clock(&mut io::stdout().lock(), &vm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_flags() {
+        assert_eq!(flag_names(0), "none");
+        assert_eq!(
+            flag_names(KVM_CLOCK_TSC_STABLE | KVM_CLOCK_HOST_TSC),
+            "tsc-stable, host-tsc"
+        );
+        assert_eq!(format_ns(1_500_000_000), "1.500000000s");
+    }
+}
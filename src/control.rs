@@ -0,0 +1,613 @@
+//! Control socket of `vmsh attach --daemon`.
+//!
+//! The attached vmsh process runs in the background and serves JSON-RPC (see `rpc`) on
+//! `/run/vmsh/<pid>.sock`. The console of the guest is a pseudoterminal owned by that
+//! process, interactive consoles are clients of the socket. Supported methods:
+//!
+//! - `status`: pids of the VM and of vmsh, the command and whether a console is connected
+//! - `new-console`: once the response is sent, the connection carries the raw console. A new
+//!   console replaces the previous one. Output while no console is connected is only kept in
+//!   the scrollback of the console device (`--scrollback`), which is replayed on connect.
+//! - `new-shell`: like `new-console`, but connects to a new login shell with its own pty in
+//!   stage2. Requires `--vsock`: stage2 keeps an idle connection to `SHELL_VSOCK_PORT` that
+//!   the next shell takes over. Optional params are `cols` and `rows` of the terminal.
+//! - `files`: like `new-shell`, the connection is handed over to stage2 on `FILES_VSOCK_PORT`,
+//!   which speaks `ioutils::filecopy` for `vmsh cp`.
+//! - `exec`: the same for `EXEC_VSOCK_PORT` and `ioutils::exec` of `vmsh exec`.
+//! - `detach`: like `vmsh detach`, the session can be resumed with `vmsh attach`
+//! - `stop`: stop the session
+
+use log::{info, warn};
+use nix::sys::stat::{umask, Mode};
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg, Termios};
+use nix::unistd::{self, ForkResult, Pid};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use simple_error::{bail, try_with, SimpleError};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::attach::{self, AttachOptions};
+use crate::daemon::open_console;
+use crate::devices::virtio::console::{terminal_size, Scrollback};
+use crate::devices::virtio::vsock::{EXEC_VSOCK_PORT, FILES_VSOCK_PORT, SHELL_VSOCK_PORT};
+use crate::devices::DeviceContext;
+use crate::result::Result;
+use crate::rpc::{self, parse_params, read_request, write_response, RpcError, METHOD_NOT_FOUND};
+use crate::session::{create_session_dir, SESSION_DIR};
+use crate::signal_handler;
+
+/// Ctrl-], leaves `new-console` like in telnet
+const ESCAPE_CHAR: u8 = 0x1d;
+
+/// How long `new-shell` and `files` wait for stage2 to offer a connection
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn socket_path(pid: Pid) -> PathBuf {
+    PathBuf::from(SESSION_DIR).join(format!("{}.sock", pid))
+}
+
+/// Client side commands of the control socket
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ControlCommand {
+    Status,
+    NewConsole,
+    NewShell,
+    Detach,
+    Stop,
+}
+
+impl std::str::FromStr for ControlCommand {
+    type Err = SimpleError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "status" => Ok(ControlCommand::Status),
+            "new-console" => Ok(ControlCommand::NewConsole),
+            "new-shell" => Ok(ControlCommand::NewShell),
+            "detach" => Ok(ControlCommand::Detach),
+            "stop" => Ok(ControlCommand::Stop),
+            _ => Err(SimpleError::new(format!("unknown command: {}", s))),
+        }
+    }
+}
+
+impl ControlCommand {
+    fn method(self) -> &'static str {
+        match self {
+            ControlCommand::Status => "status",
+            ControlCommand::NewConsole => "new-console",
+            ControlCommand::NewShell => "new-shell",
+            ControlCommand::Detach => "detach",
+            ControlCommand::Stop => "stop",
+        }
+    }
+}
+
+pub struct ControlOptions {
+    pub pid: Pid,
+    pub command: ControlCommand,
+}
+
+struct Control {
+    pid: Pid,
+    command: Vec<String>,
+    stop: Mutex<Sender<()>>,
+    /// master side of the console
+    console: File,
+    /// connection that receives the console output
+    client: Mutex<Option<(usize, UnixStream)>>,
+    /// bytes read from `console`, only updated while `client` is locked
+    output_read: AtomicU64,
+    /// set once the devices are running
+    scrollback: Mutex<Option<Arc<Mutex<Scrollback>>>>,
+    next_client: AtomicUsize,
+    /// connections of stage2 for the next shell, file transfer and command, requires `--vsock`
+    idle_shell: Option<Arc<IdleConnection>>,
+    idle_files: Option<Arc<IdleConnection>>,
+    idle_exec: Option<Arc<IdleConnection>>,
+    /// number of connected shells
+    shells: AtomicUsize,
+}
+
+/// Connection that stage2 keeps open to one of our vsock sockets until a client takes it over.
+struct IdleConnection {
+    path: PathBuf,
+    conn: Mutex<Option<UnixStream>>,
+    cond: Condvar,
+}
+
+impl IdleConnection {
+    /// Accepts connections of stage2 to `port`, each one replaces the previous.
+    fn listen(vsock: &Path, port: u32) -> Result<Arc<IdleConnection>> {
+        let path = PathBuf::from(format!("{}_{}", vsock.display(), port));
+        let listener = bind(&path)?;
+        let idle = Arc::new(IdleConnection {
+            path,
+            conn: Mutex::new(None),
+            cond: Condvar::new(),
+        });
+        let i = Arc::clone(&idle);
+        let _ = thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(s) => {
+                        *lock(&i.conn) = Some(s);
+                        i.cond.notify_all();
+                    }
+                    Err(e) => warn!("cannot accept connection of stage2: {}", e),
+                }
+            }
+        });
+        Ok(idle)
+    }
+
+    fn take(idle: &Option<Arc<IdleConnection>>) -> Result<UnixStream> {
+        let idle = match idle {
+            Some(idle) => idle,
+            None => bail!("vmsh was attached without --vsock"),
+        };
+        let start = Instant::now();
+        let mut conn = lock(&idle.conn);
+        loop {
+            if let Some(conn) = conn.take() {
+                return Ok(conn);
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= IDLE_TIMEOUT {
+                bail!("stage2 did not connect, does the guest support vsock?");
+            }
+            conn = match idle.cond.wait_timeout(conn, IDLE_TIMEOUT - elapsed) {
+                Ok((guard, _)) => guard,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ShellParams {
+    cols: u16,
+    rows: u16,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    // neither the client nor the sender can be left in an inconsistent state
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+impl Control {
+    fn status(&self) -> Value {
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "pid": self.pid.as_raw(),
+            "vmsh_pid": unistd::getpid().as_raw(),
+            "command": self.command,
+            "console": lock(&self.client).is_some(),
+            "shells": self.shells.load(Ordering::SeqCst),
+        })
+    }
+
+    fn stop(&self, detach: bool) {
+        let stop = lock(&self.stop);
+        if detach {
+            signal_handler::request_detach(&stop);
+        } else {
+            // the session might already be shutting down
+            let _ = stop.send(());
+        }
+    }
+
+    /// Forwards console input of `reader` until the client hangs up. Output is written by
+    /// `forward_output`.
+    fn new_console(&self, reader: BufReader<UnixStream>) -> Result<()> {
+        let mut client = try_with!(reader.get_ref().try_clone(), "cannot clone connection");
+        let id = self.next_client.fetch_add(1, Ordering::SeqCst);
+        {
+            let mut current = lock(&self.client);
+            // Everything `forward_output` read so far, later output follows without gaps.
+            let replay = lock(&self.scrollback).as_ref().map(|scrollback| {
+                lock(&**scrollback).replay(self.output_read.load(Ordering::Acquire))
+            });
+            if let Some(replay) = replay {
+                try_with!(client.write_all(&replay), "cannot replay scrollback");
+            }
+            if let Some((_, previous)) = current.replace((id, client)) {
+                info!("replace previous console client");
+                let _ = previous.shutdown(Shutdown::Both);
+            }
+        }
+        let mut console = try_with!(self.console.try_clone(), "cannot clone console");
+        let pending = reader.buffer().to_vec();
+        let mut client_in = reader.into_inner();
+        let res = console
+            .write_all(&pending)
+            .and_then(|_| io::copy(&mut client_in, &mut console));
+        if let Err(e) = res {
+            warn!("cannot forward console input: {}", e);
+        }
+        let mut current = lock(&self.client);
+        if current
+            .as_ref()
+            .map_or(false, |(current, _)| *current == id)
+        {
+            *current = None;
+        }
+        Ok(())
+    }
+
+    /// Takes the idle connection of stage2 and requests a shell of the given size on it.
+    fn take_shell(&self, params: ShellParams) -> Result<UnixStream> {
+        let mut conn = IdleConnection::take(&self.idle_shell)?;
+        try_with!(
+            writeln!(conn, "{} {}", params.cols, params.rows),
+            "cannot request shell"
+        );
+        Ok(conn)
+    }
+
+    fn forward_shell(&self, reader: BufReader<UnixStream>, shell: UnixStream) -> Result<()> {
+        self.shells.fetch_add(1, Ordering::SeqCst);
+        let res = proxy(reader, shell);
+        self.shells.fetch_sub(1, Ordering::SeqCst);
+        res
+    }
+
+    fn forward_output(&self) {
+        let mut console = match self.console.try_clone() {
+            Ok(console) => console,
+            Err(e) => {
+                warn!("cannot clone console: {}", e);
+                return;
+            }
+        };
+        let mut buf = [0u8; 4096];
+        loop {
+            // returns EIO once the console device closed its side of the pseudoterminal
+            let n = match console.read(&mut buf) {
+                Ok(0) | Err(_) => return,
+                Ok(n) => n,
+            };
+            let mut client = lock(&self.client);
+            self.output_read.fetch_add(n as u64, Ordering::Release);
+            if let Some((_, stream)) = client.as_mut() {
+                if stream.write_all(&buf[..n]).is_err() {
+                    *client = None;
+                }
+            }
+        }
+    }
+}
+
+/// Forwards between a client and stage2 until either side hangs up.
+fn proxy(reader: BufReader<UnixStream>, guest: UnixStream) -> Result<()> {
+    let mut guest_in = try_with!(guest.try_clone(), "cannot clone connection");
+    let pending = reader.buffer().to_vec();
+    let mut client_in = reader.into_inner();
+    let mut client_out = try_with!(client_in.try_clone(), "cannot clone connection");
+    let mut guest_out = try_with!(guest.try_clone(), "cannot clone connection");
+    let output = thread::spawn(move || {
+        let _ = io::copy(&mut guest_out, &mut client_out);
+        let _ = client_out.shutdown(Shutdown::Both);
+    });
+    let _ = guest_in
+        .write_all(&pending)
+        .and_then(|_| io::copy(&mut client_in, &mut guest_in));
+    // stage2 ends the shell or transfer once we close the connection
+    let _ = guest.shutdown(Shutdown::Both);
+    let _ = output.join();
+    Ok(())
+}
+
+fn shell_params(params: &Value) -> std::result::Result<ShellParams, RpcError> {
+    if params.is_null() {
+        return Ok(ShellParams::default());
+    }
+    parse_params(params)
+}
+
+fn handle_connection(stream: UnixStream, control: Arc<Control>) -> Result<()> {
+    let mut writer = try_with!(stream.try_clone(), "cannot clone connection");
+    let mut reader = BufReader::new(stream);
+    while let Some(req) = read_request(&mut reader, &mut writer)? {
+        let res = match req.method.as_str() {
+            "status" => Ok(control.status()),
+            "new-console" => {
+                write_response(&mut writer, req.id, Ok(Value::Null))?;
+                return control.new_console(reader);
+            }
+            "new-shell" => match shell_params(&req.params)
+                .and_then(|params| control.take_shell(params).map_err(RpcError::from))
+            {
+                Ok(shell) => {
+                    write_response(&mut writer, req.id, Ok(Value::Null))?;
+                    return control.forward_shell(reader, shell);
+                }
+                Err(e) => Err(e),
+            },
+            "files" | "exec" => {
+                let idle = match req.method.as_str() {
+                    "files" => &control.idle_files,
+                    _ => &control.idle_exec,
+                };
+                match IdleConnection::take(idle) {
+                    Ok(conn) => {
+                        write_response(&mut writer, req.id, Ok(Value::Null))?;
+                        return proxy(reader, conn);
+                    }
+                    Err(e) => Err(RpcError::from(e)),
+                }
+            }
+            "detach" => {
+                control.stop(true);
+                Ok(Value::Null)
+            }
+            "stop" => {
+                control.stop(false);
+                Ok(Value::Null)
+            }
+            m => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("unknown method: {}", m),
+            )),
+        };
+        write_response(&mut writer, req.id, res)?;
+    }
+    Ok(())
+}
+
+fn bind(path: &Path) -> Result<UnixListener> {
+    // remove stale socket from previous runs
+    if path.exists() {
+        try_with!(fs::remove_file(path), "cannot remove {}", path.display());
+    }
+    // The socket is created with mode 0600, changing the permissions after bind() would let
+    // other users connect in between. No other threads run yet that could create files.
+    let old_umask = umask(Mode::from_bits_truncate(0o177));
+    let listener = UnixListener::bind(path);
+    umask(old_umask);
+    Ok(try_with!(listener, "cannot listen on {}", path.display()))
+}
+
+fn listen(pid: Pid) -> Result<(UnixListener, PathBuf)> {
+    create_session_dir()?;
+    let path = socket_path(pid);
+    Ok((bind(&path)?, path))
+}
+
+/// Runs in the forked process, `ready` is written to once the devices are running.
+fn serve(mut opts: AttachOptions, mut ready: File) -> Result<()> {
+    let (master, slave, pts) = open_console()?;
+    opts.pts = Some(pts);
+    let (listener, path) = listen(opts.pid)?;
+    let (idle_shell, idle_files, idle_exec) = match &opts.vsock {
+        Some(vsock) => (
+            Some(IdleConnection::listen(vsock, SHELL_VSOCK_PORT)?),
+            Some(IdleConnection::listen(vsock, FILES_VSOCK_PORT)?),
+            Some(IdleConnection::listen(vsock, EXEC_VSOCK_PORT)?),
+        ),
+        None => (None, None, None),
+    };
+
+    let (sender, receiver) = channel();
+    signal_handler::setup_detachable(sender.clone());
+    let control = Arc::new(Control {
+        pid: opts.pid,
+        command: opts.command.clone(),
+        stop: Mutex::new(sender.clone()),
+        console: master,
+        client: Mutex::new(None),
+        output_read: AtomicU64::new(0),
+        scrollback: Mutex::new(None),
+        next_client: AtomicUsize::new(0),
+        idle_shell,
+        idle_files,
+        idle_exec,
+        shells: AtomicUsize::new(0),
+    });
+
+    let c = Arc::clone(&control);
+    let _ = thread::spawn(move || c.forward_output());
+    let c = Arc::clone(&control);
+    let _ = thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("cannot accept connection: {}", e);
+                    continue;
+                }
+            };
+            let control = Arc::clone(&c);
+            let _ = thread::spawn(move || {
+                if let Err(e) = handle_connection(stream, control) {
+                    warn!("{}", e);
+                }
+            });
+        }
+    });
+
+    info!("control socket at {}", path.display());
+    let c = Arc::clone(&control);
+    let started = move |devices: Weak<DeviceContext>| {
+        if let Some(devices) = devices.upgrade() {
+            *lock(&c.scrollback) = lock(&*devices.console).scrollback();
+        }
+        let _ = ready.write_all(b"1");
+    };
+    let res = attach::attach_session(&opts, sender, receiver, started, true);
+    let _ = fs::remove_file(&path);
+    let idle = [&control.idle_shell, &control.idle_files, &control.idle_exec];
+    for idle in idle.iter().copied().flatten() {
+        let _ = fs::remove_file(&idle.path);
+    }
+    drop(slave);
+    res
+}
+
+/// Forks a vmsh process that attaches in the background and returns once its devices are
+/// running. The session is controlled through `socket_path`.
+pub fn attach_daemon(opts: AttachOptions) -> Result<()> {
+    let (ready_r, ready_w) = try_with!(unistd::pipe(), "cannot create pipe");
+    let (mut ready_r, ready_w) =
+        unsafe { (File::from_raw_fd(ready_r), File::from_raw_fd(ready_w)) };
+    match try_with!(unsafe { unistd::fork() }, "cannot fork") {
+        ForkResult::Parent { child } => {
+            drop(ready_w);
+            let mut buf = [0u8; 1];
+            match ready_r.read(&mut buf) {
+                Ok(1) => {
+                    let mut stdout = io::stdout().lock();
+                    try_with!(
+                        writeln!(
+                            stdout,
+                            "vmsh {} attached, control socket: {}",
+                            child,
+                            socket_path(opts.pid).display()
+                        ),
+                        "cannot write to stdout"
+                    );
+                    Ok(())
+                }
+                _ => bail!("vmsh process {} failed to attach", child),
+            }
+        }
+        ForkResult::Child => {
+            drop(ready_r);
+            try_with!(unistd::setsid(), "cannot create new session");
+            let null = try_with!(
+                fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open("/dev/null"),
+                "cannot open /dev/null"
+            );
+            // logs still go to stderr
+            for fd in &[libc::STDIN_FILENO, libc::STDOUT_FILENO] {
+                try_with!(unistd::dup2(null.as_raw_fd(), *fd), "cannot redirect stdio");
+            }
+            serve(opts, ready_w)
+        }
+    }
+}
+
+/// Restores the terminal settings when dropped.
+struct RawTerminal {
+    fd: RawFd,
+    orig: Termios,
+}
+
+impl RawTerminal {
+    fn new(fd: RawFd) -> Option<RawTerminal> {
+        let orig = tcgetattr(fd).ok()?;
+        let mut raw = orig.clone();
+        cfmakeraw(&mut raw);
+        tcsetattr(fd, SetArg::TCSANOW, &raw).ok()?;
+        Some(RawTerminal { fd, orig })
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let _ = tcsetattr(self.fd, SetArg::TCSANOW, &self.orig);
+    }
+}
+
+/// Copies stdin to the console until the escape character or EOF.
+fn console_input(mut conn: UnixStream) {
+    let mut stdin = io::stdin();
+    let mut buf = [0u8; 1024];
+    loop {
+        let n = match stdin.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        let (data, escaped) = match buf[..n].iter().position(|c| *c == ESCAPE_CHAR) {
+            Some(pos) => (&buf[..pos], true),
+            None => (&buf[..n], false),
+        };
+        if conn.write_all(data).is_err() || escaped {
+            break;
+        }
+    }
+    let _ = conn.shutdown(Shutdown::Both);
+}
+
+/// Connects the terminal to the raw stream that follows the response in `reader`.
+fn interact(reader: BufReader<UnixStream>) -> Result<()> {
+    let conn = try_with!(reader.get_ref().try_clone(), "cannot clone connection");
+    let _raw = RawTerminal::new(libc::STDIN_FILENO);
+    let _ = thread::spawn(move || console_input(conn));
+    let mut stdout = io::stdout();
+    try_with!(stdout.write_all(reader.buffer()), "cannot write to stdout");
+    let mut conn = reader.into_inner();
+    let mut buf = [0u8; 4096];
+    // ends once the console was replaced, stopped or left with the escape character
+    loop {
+        let n = match conn.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        try_with!(
+            stdout.write_all(&buf[..n]).and_then(|_| stdout.flush()),
+            "cannot write to stdout"
+        );
+    }
+    Ok(())
+}
+
+pub(crate) fn connect(pid: Pid) -> Result<BufReader<UnixStream>> {
+    let path = socket_path(pid);
+    let stream = try_with!(
+        UnixStream::connect(&path),
+        "cannot connect to {}, is vmsh attached with --daemon?",
+        path.display()
+    );
+    Ok(BufReader::new(stream))
+}
+
+/// Client of the control socket
+pub fn control(opts: &ControlOptions) -> Result<()> {
+    let mut reader = connect(opts.pid)?;
+    let params = match opts.command {
+        ControlCommand::NewShell => match terminal_size(libc::STDIN_FILENO) {
+            Some((cols, rows)) => json!({"cols": cols, "rows": rows}),
+            None => Value::Null,
+        },
+        _ => Value::Null,
+    };
+    let result = rpc::call(&mut reader, opts.command.method(), params)?;
+    match opts.command {
+        ControlCommand::Status => {
+            let status = try_with!(
+                serde_json::to_string_pretty(&result),
+                "cannot serialize status"
+            );
+            let mut stdout = io::stdout().lock();
+            try_with!(writeln!(stdout, "{}", status), "cannot write to stdout");
+        }
+        ControlCommand::NewConsole => {
+            info!("connected to console, press Ctrl-] to leave");
+            interact(reader)?;
+        }
+        ControlCommand::NewShell => {
+            info!("connected to new shell, press Ctrl-] to leave");
+            interact(reader)?;
+        }
+        ControlCommand::Detach | ControlCommand::Stop => {}
+    }
+    Ok(())
+}
@@ -1,35 +1,176 @@
 use crate::cpu::{FpuRegs, Regs};
-use crate::kvm::hypervisor::VCPU;
+use crate::kvm::hypervisor::{VmSelector, VCPU};
 use kvm_bindings as kvmb;
 use libc::{off_t, timeval, PT_LOAD, PT_NOTE};
-use nix::sys::{
-    mman::{mmap, MapFlags, ProtFlags},
-    uio::{process_vm_readv, RemoteIoVec},
-};
+use log::warn;
+use nix::sys::mman::{mmap, MapFlags, ProtFlags};
 use nix::unistd::Pid;
-use simple_error::{require_with, try_with};
+use serde::Serialize;
+use simple_error::{bail, require_with, try_with, SimpleError};
+use std::cmp::{max, min};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
 use std::fs::OpenOptions;
-use std::io::IoSliceMut;
 use std::num::NonZeroUsize;
-use std::path::PathBuf;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::{fs::File, io::Write, ptr, slice::from_raw_parts_mut};
-use std::{mem::size_of, os::unix::prelude::AsRawFd};
+use std::{mem::size_of, os::unix::fs::FileExt, os::unix::prelude::AsRawFd};
 
 use crate::elf::{
     elf_prpsinfo, elf_prstatus, elf_siginfo, Ehdr, Elf_Addr, Elf_Half, Elf_Off, Elf_Word, Nhdr,
     Phdr, Shdr, ELFARCH, ELFCLASS, ELFDATA2, ELFMAG0, ELFMAG1, ELFMAG2, ELFMAG3, ELF_NGREG,
     ET_CORE, EV_CURRENT, NT_PRPSINFO, NT_PRSTATUS, NT_PRXREG, PF_W, PF_X, SHN_UNDEF,
 };
+use crate::guest_mem::{GuestMem, MappedMemory};
+use crate::kernel::find_kernel;
+use crate::kvm::hypervisor::shared_ram::SharedRam;
 use crate::kvm::hypervisor::Hypervisor;
-use crate::page_math::{page_align, page_size};
+use crate::mem::parse_addr;
+use crate::page_math::{page_align, page_size, page_start};
 use crate::result::Result;
-use crate::{kvm, tracer::proc::Mapping};
+use crate::{kvm, symbols, tracer::proc::Mapping, windump};
+
+/// File format of a coredump
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DumpFormat {
+    /// ELF core file with Linux notes for the registers, for gdb and crash
+    Elf,
+    /// Physical memory at the file offsets of its addresses, the registers are written to
+    /// `<path>.json`. Works for any guest.
+    Raw,
+    /// Windows full memory dump, for WinDbg
+    Windows,
+}
+
+impl std::str::FromStr for DumpFormat {
+    type Err = SimpleError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "elf" => Ok(DumpFormat::Elf),
+            "raw" => Ok(DumpFormat::Raw),
+            "windows" => Ok(DumpFormat::Windows),
+            _ => Err(SimpleError::new(format!("unknown coredump format: {}", s))),
+        }
+    }
+}
 
 pub struct CoredumpOptions {
     pub pid: Pid,
+    pub vm: Option<VmSelector>,
     pub path: PathBuf,
+    pub format: DumpFormat,
+    /// Dump the kernel address space by virtual addresses instead of physical memory
+    pub kernel_virtual: bool,
+    /// Write a zstd compressed core file
+    pub compress: bool,
+    /// Leave holes in the core file for pages that only contain zeros
+    pub sparse: bool,
+    /// Only dump these guest-physical ranges, all memory if empty
+    pub phys_ranges: Vec<Range<usize>>,
+    /// Dump the user address space of the process with this page table instead of physical
+    /// memory
+    pub process_cr3: Option<usize>,
+    /// Log where the vcpus stopped in the kernel
+    pub backtrace: bool,
+    /// vmlinux or System.map of the guest kernel, used for backtraces
+    pub symbols: Option<PathBuf>,
+}
+
+fn log_backtraces(vm: &Hypervisor, symbols_path: Option<&Path>) -> Result<()> {
+    let mem = try_with!(GuestMem::new(vm), "cannot access guest memory");
+    let kernel = find_kernel(&mem, vm)?;
+    let symbols = symbols::kernel_symbols(&kernel, symbols_path)?;
+    symbols::log_backtraces(vm, &mem, &kernel, &symbols);
+    Ok(())
+}
+
+/// Parses `START-END`, i.e. `0x100000-0x200000`. The range is widened to page boundaries.
+pub fn parse_phys_range(s: &str) -> Result<Range<usize>> {
+    let (start, end) = require_with!(s.split_once('-'), "expected START-END, got {}", s);
+    let start = parse_addr(start)?;
+    let end = parse_addr(end)?;
+    if start >= end {
+        bail!("empty range: {}", s);
+    }
+    Ok(page_start(start)..page_align(end))
+}
+
+/// Parses an address like `0x1000` or `4096`, i.e. the CR3 value of a process.
+pub fn parse_cr3(s: &str) -> Result<usize> {
+    parse_addr(s)
+}
+
+/// File name used if no path is given.
+pub fn default_path(pid: Pid, compress: bool) -> String {
+    if compress {
+        format!("core.{}.zst", pid)
+    } else {
+        format!("core.{}", pid)
+    }
+}
+
+/// Memory that is written to the core file as PT_LOAD segment
+struct LoadSegment {
+    /// Address in the hypervisor
+    host_addr: usize,
+    size: usize,
+    vaddr: usize,
+    paddr: usize,
+    prot: ProtFlags,
+}
+
+impl From<&Mapping> for LoadSegment {
+    fn from(m: &Mapping) -> LoadSegment {
+        LoadSegment {
+            host_addr: m.start,
+            size: m.size(),
+            vaddr: m.phys_addr,
+            paddr: m.phys_addr,
+            prot: m.prot_flags,
+        }
+    }
+}
+
+impl From<&MappedMemory> for LoadSegment {
+    fn from(m: &MappedMemory) -> LoadSegment {
+        LoadSegment {
+            host_addr: m.phys_start.host_addr(),
+            size: m.len,
+            vaddr: m.virt_start,
+            paddr: m.phys_start.value,
+            prot: m.prot,
+        }
+    }
+}
+
+/// Returns the parts of `segments` that are within `ranges` (guest-physical).
+fn clip_segments(segments: &[LoadSegment], ranges: &[Range<usize>]) -> Vec<LoadSegment> {
+    let mut clipped = vec![];
+    for s in segments {
+        for r in ranges {
+            let start = max(s.paddr, r.start);
+            let end = min(s.paddr + s.size, r.end);
+            if start >= end {
+                continue;
+            }
+            clipped.push(LoadSegment {
+                host_addr: s.host_addr + (start - s.paddr),
+                size: end - start,
+                vaddr: start,
+                paddr: start,
+                prot: s.prot,
+            });
+        }
+    }
+    clipped.sort_by_key(|s| s.paddr);
+    clipped
 }
 
+/// Bytes read from the hypervisor at once when the core file is not written through a mapping
+const STREAM_CHUNK: usize = 1 << 20;
+
 #[repr(C)]
 #[derive(Clone)]
 pub struct core_user {
@@ -62,10 +203,11 @@ unsafe fn any_as_bytes<T: Sized>(p: &T) -> &[u8] {
 
 fn dump_mappings(
     pid: Pid,
+    ram: &SharedRam,
     core_file: &mut File,
     core_size: off_t,
     file_offset: off_t,
-    maps: &[Mapping],
+    segments: &[LoadSegment],
 ) -> Result<()> {
     let buf_size = core_size - file_offset;
     let buf_size = require_with!(
@@ -86,22 +228,83 @@ fn dump_mappings(
     let raw_buf = try_with!(res, "cannot mmap core file");
     let buf = unsafe { from_raw_parts_mut(raw_buf as *mut u8, buf_size.get()) };
 
-    let mut dst_iovs = vec![IoSliceMut::new(buf)];
-    let src_iovs = maps
-        .iter()
-        .map(|m| RemoteIoVec {
-            base: m.start,
-            len: m.size(),
-        })
-        .collect::<Vec<_>>();
-
+    let mut reads = Vec::with_capacity(segments.len());
+    let mut rest = buf;
+    for s in segments {
+        let (dst, tail) = std::mem::take(&mut rest).split_at_mut(s.size);
+        reads.push((s.host_addr, dst));
+        rest = tail;
+    }
     try_with!(
-        process_vm_readv(pid, dst_iovs.as_mut_slice(), src_iovs.as_slice()),
+        ram.read_vectored(pid, &mut reads),
         "cannot read hypervisor memory"
     );
     Ok(())
 }
 
+/// Reads the segments in chunks and passes them to `sink` in file order. Segments in memory
+/// that is mapped into vmsh are passed without copying them first.
+fn stream_mappings(
+    pid: Pid,
+    ram: &SharedRam,
+    segments: &[LoadSegment],
+    mut sink: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<()> {
+    let mut buf = vec![0u8; STREAM_CHUNK];
+    for s in segments {
+        // the hypervisor is stopped, so the guest cannot change the memory underneath us
+        if let Some(mem) = unsafe { ram.slice(s.host_addr, s.size) } {
+            for chunk in mem.chunks(STREAM_CHUNK) {
+                sink(chunk)?;
+            }
+            continue;
+        }
+        let mut done = 0;
+        while done < s.size {
+            let len = min(s.size - done, buf.len());
+            try_with!(
+                ram.read_vectored(pid, &mut [(s.host_addr + done, &mut buf[..len])]),
+                "cannot read hypervisor memory at {:#x}",
+                s.host_addr + done
+            );
+            sink(&buf[..len])?;
+            done += len;
+        }
+    }
+    Ok(())
+}
+
+/// Like `dump_mappings`, but only writes pages that are not all zeros. The core file was
+/// truncated to its final size before, so skipped pages become holes.
+fn dump_mappings_sparse(
+    pid: Pid,
+    ram: &SharedRam,
+    core_file: &File,
+    file_offset: off_t,
+    segments: &[LoadSegment],
+) -> Result<()> {
+    let mut offset = file_offset as u64;
+    stream_mappings(pid, ram, segments, |chunk| {
+        write_sparse(core_file, chunk, offset)?;
+        offset += chunk.len() as u64;
+        Ok(())
+    })
+}
+
+/// Writes the pages of `chunk` that are not all zeros at `offset`.
+fn write_sparse(core_file: &File, chunk: &[u8], mut offset: u64) -> Result<()> {
+    for page in chunk.chunks(page_size()) {
+        if page.iter().any(|b| *b != 0) {
+            try_with!(
+                core_file.write_all_at(page, offset),
+                "cannot write to core file"
+            );
+        }
+        offset += page.len() as u64;
+    }
+    Ok(())
+}
+
 fn elf_header(phnum: Elf_Half) -> Ehdr {
     Ehdr {
         e_ident: [
@@ -136,20 +339,24 @@ fn pt_note_header(core_size: Elf_Off, file_size: Elf_Off) -> Phdr {
     }
 }
 
-fn pt_load_header(m: &Mapping, offset: Elf_Off) -> Phdr {
+fn pt_load_header(s: &LoadSegment, offset: Elf_Off) -> Phdr {
     Phdr {
         p_type: PT_LOAD,
-        p_flags: protection_flags(&m.prot_flags),
+        p_flags: protection_flags(&s.prot),
         p_offset: offset,
-        p_vaddr: m.phys_addr as Elf_Addr,
-        p_paddr: m.phys_addr as Elf_Addr,
-        p_filesz: m.size() as Elf_Addr,
-        p_memsz: m.size() as Elf_Addr,
+        p_vaddr: s.vaddr as Elf_Addr,
+        p_paddr: s.paddr as Elf_Addr,
+        p_filesz: s.size as Elf_Addr,
+        p_memsz: s.size as Elf_Addr,
         p_align: page_size() as Elf_Addr,
     }
 }
 
-fn write_note_section<T: Sized>(core_file: &mut File, ntype: Elf_Word, payload: &T) -> Result<()> {
+fn write_note_section<T: Sized>(
+    core_file: &mut dyn Write,
+    ntype: Elf_Word,
+    payload: &T,
+) -> Result<()> {
     let hdr = &Nhdr {
         n_namesz: 5,
         n_descsz: size_of::<T>() as Elf_Word,
@@ -171,7 +378,7 @@ fn write_note_section<T: Sized>(core_file: &mut File, ntype: Elf_Word, payload:
 }
 
 #[cfg(target_arch = "x86_64")]
-fn write_fpu_registers(core_file: &mut File, regs: &FpuRegs) -> Result<()> {
+fn write_fpu_registers(core_file: &mut dyn Write, regs: &FpuRegs) -> Result<()> {
     use crate::elf::NT_PRXFPREG;
     let hdr = &Nhdr {
         n_namesz: 5,
@@ -194,7 +401,7 @@ fn write_fpu_registers(core_file: &mut File, regs: &FpuRegs) -> Result<()> {
 }
 
 #[cfg(not(target_arch = "x86_64"))]
-fn write_fpu_registers(core_file: &mut File, regs: &FpuRegs) -> Result<()> {
+fn write_fpu_registers(core_file: &mut dyn Write, regs: &FpuRegs) -> Result<()> {
     use crate::elf::NT_PRFPREG;
     try_with!(
         write_note_section(
@@ -207,7 +414,7 @@ fn write_fpu_registers(core_file: &mut File, regs: &FpuRegs) -> Result<()> {
     Ok(())
 }
 
-fn write_note_sections(core_file: &mut File, vcpus: &[VcpuState]) -> Result<()> {
+fn write_note_sections(core_file: &mut dyn Write, vcpus: &[VcpuState]) -> Result<()> {
     try_with!(
         write_note_section(
             core_file,
@@ -289,14 +496,16 @@ pub fn note_size<T>() -> usize {
     size_of::<Nhdr>() + name_size + size_of::<T>()
 }
 
-fn write_corefile(
-    pid: Pid,
-    core_file: &mut File,
-    maps: &[Mapping],
-    vcpus: &[VcpuState],
-) -> Result<()> {
+/// ELF header, program headers and notes, padded to the offset of the first segment.
+fn elf_metadata(segments: &[LoadSegment], vcpus: &[VcpuState]) -> Result<Vec<u8>> {
     // +1 == PT_NOTE section
-    let ehdr = elf_header((maps.len() + 1) as Elf_Half);
+    if segments.len() + 1 >= Elf_Half::MAX as usize {
+        bail!(
+            "too many memory segments for a core file: {}",
+            segments.len()
+        );
+    }
+    let ehdr = elf_header((segments.len() + 1) as Elf_Half);
 
     let metadata_size = size_of::<Ehdr>() + (size_of::<Phdr>() * ehdr.e_phnum as usize);
     let mut core_size = metadata_size;
@@ -307,40 +516,139 @@ fn write_corefile(
     let mut section_headers = vec![pt_note_header(core_size as Elf_Off, pt_note_size as u64)];
     core_size += pt_note_size;
     core_size = page_align(core_size);
+    let data_offset = core_size;
 
-    for m in maps {
-        let phdr = pt_load_header(m, core_size as Elf_Off);
-        core_size += m.size();
+    for s in segments {
+        let phdr = pt_load_header(s, core_size as Elf_Off);
+        core_size += s.size;
         section_headers.push(phdr);
     }
 
-    try_with!(
-        core_file.set_len(core_size as u64),
-        "cannot truncate core file"
-    );
-    try_with!(
-        core_file.write_all(unsafe { any_as_bytes(&ehdr) }),
-        "cannot write elf header"
-    );
+    let mut metadata = Vec::with_capacity(data_offset);
+    metadata.extend_from_slice(unsafe { any_as_bytes(&ehdr) });
     for header in section_headers {
-        try_with!(
-            core_file.write_all(unsafe { any_as_bytes(&header) }),
-            "cannot write elf header"
+        metadata.extend_from_slice(unsafe { any_as_bytes(&header) });
+    }
+    write_note_sections(&mut metadata, vcpus)?;
+    // pad the metadata to the start of the first segment
+    metadata.resize(data_offset, 0);
+    Ok(metadata)
+}
+
+/// Writes `metadata` followed by the content of all segments.
+fn write_corefile(
+    pid: Pid,
+    ram: &SharedRam,
+    mut core_file: File,
+    metadata: &[u8],
+    segments: &[LoadSegment],
+    opts: &CoredumpOptions,
+) -> Result<()> {
+    let data_offset = metadata.len();
+    let core_size = data_offset + segments.iter().map(|s| s.size).sum::<usize>();
+    if opts.compress {
+        let mut encoder = try_with!(
+            zstd::Encoder::new(core_file, 0),
+            "cannot create zstd encoder"
         );
+        try_with!(encoder.write_all(metadata), "cannot write header");
+        stream_mappings(pid, ram, segments, |chunk| {
+            try_with!(encoder.write_all(chunk), "cannot write to core file");
+            Ok(())
+        })?;
+        try_with!(encoder.finish(), "cannot finish zstd stream");
+        return Ok(());
     }
-    write_note_sections(core_file, vcpus)?;
 
+    try_with!(
+        core_file.set_len(core_size as u64),
+        "cannot truncate core file"
+    );
+    try_with!(core_file.write_all(metadata), "cannot write header");
     try_with!(core_file.flush(), "cannot flush core file");
 
+    if opts.sparse {
+        return dump_mappings_sparse(pid, ram, &core_file, data_offset as off_t, segments);
+    }
     dump_mappings(
         pid,
-        core_file,
+        ram,
+        &mut core_file,
         core_size as off_t,
-        page_align(metadata_size + pt_note_size) as off_t,
-        maps,
+        data_offset as off_t,
+        segments,
     )
 }
 
+/// Written next to a raw dump
+#[derive(Serialize)]
+struct RawMetadata {
+    memory: Vec<RawRegion>,
+    vcpus: Vec<BTreeMap<&'static str, u64>>,
+}
+
+/// Guest-physical memory in a raw dump, at the file offset of `phys_addr`
+#[derive(Serialize)]
+struct RawRegion {
+    phys_addr: usize,
+    size: usize,
+}
+
+/// Path of the registers of a raw dump at `path`
+pub fn raw_metadata_path(path: &Path) -> PathBuf {
+    let mut metadata = OsString::from(path.as_os_str());
+    metadata.push(".json");
+    PathBuf::from(metadata)
+}
+
+/// Writes each segment at the file offset of its guest-physical address, gaps between them
+/// become holes. The memory ranges and vcpu registers are written to `raw_metadata_path`.
+fn write_raw(
+    pid: Pid,
+    ram: &SharedRam,
+    core_file: File,
+    segments: &[LoadSegment],
+    vcpus: &[VcpuState],
+    opts: &CoredumpOptions,
+) -> Result<()> {
+    let end = segments.iter().map(|s| s.paddr + s.size).max().unwrap_or(0);
+    try_with!(core_file.set_len(end as u64), "cannot truncate core file");
+    for s in segments {
+        let mut offset = s.paddr as u64;
+        stream_mappings(pid, ram, std::slice::from_ref(s), |chunk| {
+            if opts.sparse {
+                write_sparse(&core_file, chunk, offset)?;
+            } else {
+                try_with!(
+                    core_file.write_all_at(chunk, offset),
+                    "cannot write to core file"
+                );
+            }
+            offset += chunk.len() as u64;
+            Ok(())
+        })?;
+    }
+
+    let metadata = RawMetadata {
+        memory: segments
+            .iter()
+            .map(|s| RawRegion {
+                phys_addr: s.paddr,
+                size: s.size,
+            })
+            .collect(),
+        vcpus: vcpus.iter().map(VcpuState::registers).collect(),
+    };
+    let path = raw_metadata_path(&opts.path);
+    let file = try_with!(File::create(&path), "cannot create {}", path.display());
+    try_with!(
+        serde_json::to_writer_pretty(file, &metadata),
+        "cannot write {}",
+        path.display()
+    );
+    Ok(())
+}
+
 const MSR_EFER: u32 = 0xc0000080;
 struct VcpuState {
     regs: Regs,
@@ -370,35 +678,150 @@ impl VcpuState {
             msrs: [msr],
         })
     }
+
+    /// Registers by name, for dumps without a format of their own.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn registers(&self) -> BTreeMap<&'static str, u64> {
+        let r = &self.regs;
+        let s = &self.sregs;
+        [
+            ("rax", r.rax),
+            ("rbx", r.rbx),
+            ("rcx", r.rcx),
+            ("rdx", r.rdx),
+            ("rsi", r.rsi),
+            ("rdi", r.rdi),
+            ("rbp", r.rbp),
+            ("rsp", r.rsp),
+            ("r8", r.r8),
+            ("r9", r.r9),
+            ("r10", r.r10),
+            ("r11", r.r11),
+            ("r12", r.r12),
+            ("r13", r.r13),
+            ("r14", r.r14),
+            ("r15", r.r15),
+            ("rip", r.rip),
+            ("rflags", r.eflags),
+            ("cs", r.cs),
+            ("ss", r.ss),
+            ("fs_base", r.fs_base),
+            ("gs_base", r.gs_base),
+            ("cr0", s.cr0),
+            ("cr2", s.cr2),
+            ("cr3", s.cr3),
+            ("cr4", s.cr4),
+            ("efer", self.msrs[0].data),
+        ]
+        .iter()
+        .copied()
+        .collect()
+    }
 }
 
 #[allow(clippy::print_stdout)]
 pub fn generate_coredump(opts: &CoredumpOptions) -> Result<()> {
     println!("Write {}", opts.path.display());
-    let mut core_file = try_with!(
+    if opts.compress && opts.sparse {
+        bail!("sparse core files cannot be compressed");
+    }
+    if opts.process_cr3.is_some() && opts.kernel_virtual {
+        bail!("cannot dump a process and the kernel address space at the same time");
+    }
+    if !opts.phys_ranges.is_empty() && (opts.process_cr3.is_some() || opts.kernel_virtual) {
+        bail!("physical ranges can only be selected when dumping physical memory");
+    }
+    if opts.format != DumpFormat::Elf && (opts.process_cr3.is_some() || opts.kernel_virtual) {
+        bail!("{:?} dumps can only contain physical memory", opts.format);
+    }
+    if opts.format == DumpFormat::Raw && opts.compress {
+        bail!("raw dumps cannot be compressed");
+    }
+    let core_file = try_with!(
         OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
+            .truncate(true)
             .open(&opts.path),
         "cannot open core_file: {}",
         opts.path.display()
     );
     let vm = try_with!(
-        kvm::hypervisor::get_hypervisor(opts.pid),
+        kvm::hypervisor::get_hypervisor(opts.pid, opts.vm),
         "cannot get vms for process {}",
         opts.pid
     );
     vm.stop()?;
     let maps = vm.get_maps()?;
-    let res = vm
-        .vcpus
-        .iter()
-        .map(|vcpu| VcpuState::new(vcpu, &vm))
-        .collect::<Result<Vec<VcpuState>>>();
-    let vcpu_states = try_with!(res, "fail to dump vcpu registers");
+    let ram = SharedRam::map(opts.pid, &maps);
+    let segments = if let Some(cr3) = opts.process_cr3 {
+        let mem = try_with!(GuestMem::new(&vm), "cannot access guest memory");
+        let mappings = try_with!(
+            mem.process_mappings(&vm, cr3),
+            "cannot read page tables at {:#x}",
+            cr3
+        );
+        mappings.iter().map(LoadSegment::from).collect::<Vec<_>>()
+    } else if opts.kernel_virtual {
+        let mem = try_with!(GuestMem::new(&vm), "cannot access guest memory");
+        let mappings = try_with!(mem.kernel_mappings(&vm), "cannot read kernel page tables");
+        mappings.iter().map(LoadSegment::from).collect::<Vec<_>>()
+    } else {
+        let segments = maps.iter().map(LoadSegment::from).collect::<Vec<_>>();
+        if opts.phys_ranges.is_empty() {
+            segments
+        } else {
+            clip_segments(&segments, &opts.phys_ranges)
+        }
+    };
+    if segments.is_empty() {
+        bail!("no guest memory selected for the core file");
+    }
+    if opts.backtrace || opts.symbols.is_some() {
+        // the core file is still useful without
+        if let Err(e) = log_backtraces(&vm, opts.symbols.as_deref()) {
+            warn!("cannot create backtraces: {}", e);
+        }
+    }
+    let encryption = vm.encryption()?;
+    let vcpu_states = if encryption.has_protected_state() {
+        warn!(
+            "{} protects the vcpu registers, the dump has none",
+            encryption
+        );
+        vec![]
+    } else {
+        let res = vm
+            .vcpus
+            .iter()
+            .map(|vcpu| VcpuState::new(vcpu, &vm))
+            .collect::<Result<Vec<VcpuState>>>();
+        try_with!(res, "fail to dump vcpu registers")
+    };
+    if encryption.is_encrypted() {
+        warn!(
+            "{} encrypts guest memory, only pages shared with the host are readable",
+            encryption
+        );
+    }
+    let metadata = match opts.format {
+        DumpFormat::Elf => elf_metadata(&segments, &vcpu_states)?,
+        DumpFormat::Windows => {
+            let first = require_with!(vcpu_states.first(), "vm has no vcpus");
+            let runs = segments
+                .iter()
+                .map(|s| (s.paddr, s.size))
+                .collect::<Vec<_>>();
+            windump::header(&runs, vcpu_states.len(), &first.regs, first.sregs.cr3)?
+        }
+        DumpFormat::Raw => {
+            let res = write_raw(opts.pid, &ram, core_file, &segments, &vcpu_states, opts);
+            return Ok(try_with!(res, "cannot write raw dump"));
+        }
+    };
     try_with!(
-        write_corefile(opts.pid, &mut core_file, &maps, vcpu_states.as_slice()),
+        write_corefile(opts.pid, &ram, core_file, &metadata, &segments, opts),
         "cannot write core file"
     );
     Ok(())
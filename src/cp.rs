@@ -0,0 +1,143 @@
+//! `vmsh cp`: copies regular files between host and guest. The attached vmsh process hands
+//! the connection to stage2, which speaks `ioutils::filecopy` and accesses files with the
+//! namespaces and credentials of the command.
+
+use ioutils::filecopy::{self, Request};
+use log::info;
+use nix::unistd::Pid;
+use serde_json::Value;
+use simple_error::{bail, require_with, try_with};
+use std::fs::{self, File, Permissions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::control;
+use crate::result::Result;
+use crate::rpc;
+
+pub enum CpDirection {
+    FromGuest,
+    ToGuest,
+}
+
+pub struct CpOptions {
+    pub pid: Pid,
+    pub guest_path: PathBuf,
+    pub host_path: PathBuf,
+    pub direction: CpDirection,
+}
+
+/// Copies into `dir` if `path` is a directory, like cp(1).
+fn target_path(path: &Path, source: &Path, is_dir: bool) -> Result<PathBuf> {
+    if !is_dir {
+        return Ok(path.to_path_buf());
+    }
+    let name = require_with!(
+        source.file_name(),
+        "cannot copy {} into a directory",
+        source.display()
+    );
+    Ok(path.join(name))
+}
+
+fn copy_from_guest(
+    writer: &mut impl Write,
+    reader: &mut impl Read,
+    opts: &CpOptions,
+) -> Result<()> {
+    let host_path = target_path(&opts.host_path, &opts.guest_path, opts.host_path.is_dir())?;
+    try_with!(
+        Request::Get {
+            path: opts.guest_path.clone()
+        }
+        .write(writer),
+        "cannot send request"
+    );
+    let fields = try_with!(
+        filecopy::read_response(reader),
+        "cannot read {} in the guest",
+        opts.guest_path.display()
+    );
+    let (mode, size) = match fields.as_slice() {
+        [mode, size] => (
+            try_with!(u32::from_str_radix(mode, 8), "invalid mode {}", mode),
+            try_with!(size.parse::<u64>(), "invalid size {}", size),
+        ),
+        _ => bail!("invalid response: {:?}", fields),
+    };
+    let mut file = try_with!(
+        File::create(&host_path),
+        "cannot create {}",
+        host_path.display()
+    );
+    let written = try_with!(
+        io::copy(&mut reader.take(size), &mut file),
+        "cannot write {}",
+        host_path.display()
+    );
+    if written != size {
+        bail!(
+            "{} was truncated: received {} of {} bytes",
+            opts.guest_path.display(),
+            written,
+            size
+        );
+    }
+    try_with!(
+        fs::set_permissions(&host_path, Permissions::from_mode(mode)),
+        "cannot set permissions of {}",
+        host_path.display()
+    );
+    info!("copied {} bytes to {}", size, host_path.display());
+    Ok(())
+}
+
+fn copy_to_guest(writer: &mut impl Write, reader: &mut impl Read, opts: &CpOptions) -> Result<()> {
+    let mut file = try_with!(
+        File::open(&opts.host_path),
+        "cannot open {}",
+        opts.host_path.display()
+    );
+    let metadata = try_with!(file.metadata(), "cannot stat {}", opts.host_path.display());
+    if !metadata.is_file() {
+        bail!("{} is not a regular file", opts.host_path.display());
+    }
+    // stage2 cannot tell us whether the path is a directory beforehand
+    let is_dir = opts.guest_path.as_os_str().to_string_lossy().ends_with('/');
+    let guest_path = target_path(&opts.guest_path, &opts.host_path, is_dir)?;
+    let size = metadata.len();
+    try_with!(
+        Request::Put {
+            path: guest_path.clone(),
+            mode: metadata.permissions().mode() & 0o7777,
+            size,
+        }
+        .write(writer),
+        "cannot send request"
+    );
+    try_with!(
+        io::copy(&mut (&mut file).take(size), writer),
+        "cannot send {}",
+        opts.host_path.display()
+    );
+    try_with!(
+        filecopy::read_response(reader),
+        "cannot write {} in the guest",
+        guest_path.display()
+    );
+    info!("copied {} bytes to {}", size, guest_path.display());
+    Ok(())
+}
+
+pub fn cp(opts: &CpOptions) -> Result<()> {
+    let mut reader = control::connect(opts.pid)?;
+    rpc::call(&mut reader, "files", Value::Null)?;
+    // stage2 only writes after our request, so nothing is buffered yet
+    let mut conn = reader.into_inner();
+    let mut writer = try_with!(conn.try_clone(), "cannot clone connection");
+    match opts.direction {
+        CpDirection::FromGuest => copy_from_guest(&mut writer, &mut conn, opts),
+        CpDirection::ToGuest => copy_to_guest(&mut writer, &mut conn, opts),
+    }
+}
@@ -1,7 +1,13 @@
+//! Registers and syscall instructions of the architectures vmsh runs on, see `arch` for what a
+//! new architecture has to provide.
+
 #[cfg(target_arch = "aarch64")]
 mod arch {
+    use crate::arch::Registers;
+
+    /// `struct user_pt_regs` as returned by PTRACE_GETREGSET/NT_PRSTATUS
     #[repr(C)]
-    #[derive(Clone, Copy, Debug)]
+    #[derive(Clone, Copy, Debug, Default)]
     pub struct Regs {
         pub regs: [u64; 31],
         pub sp: u64,
@@ -17,39 +23,100 @@ mod arch {
         pub fpcr: u32,
     }
 
-    impl Regs {
-        pub fn set_ip(&mut self, ip: u64) {
+    const PSR_MODE_MASK: u64 = 0xf;
+    const PSR_MODE_EL0T: u64 = 0;
+
+    impl Registers for Regs {
+        /// true if the cpu executes in EL0 (userspace)
+        fn is_userspace(&self) -> bool {
+            self.pstate & PSR_MODE_MASK == PSR_MODE_EL0T
+        }
+
+        fn set_ip(&mut self, ip: u64) {
             self.pc = ip
         }
 
-        pub fn ip(&self) -> u64 {
+        fn ip(&self) -> u64 {
             self.pc
         }
 
-        pub fn prepare_syscall(&self, args: &[u64; 7]) -> Regs {
-            let mut copy = self.clone();
-            copy.regs[0] = args[8];
-            copy.regs[1] = args[0];
-            copy.regs[2] = args[1];
-            copy.regs[3] = args[2];
-            copy.regs[4] = args[3];
-            copy.regs[5] = args[4];
-            copy.regs[6] = args[5];
-            return copy;
+        /// Registers to run `BATCH_TEXT` at `ip` on `count` entries of the table at `table`.
+        fn prepare_batch(&self, ip: u64, table: u64, count: u64) -> Regs {
+            let mut copy = *self;
+            copy.pc = ip;
+            copy.regs[19] = table;
+            copy.regs[20] = count;
+            copy
         }
 
-        pub fn syscall_ret(&self) -> u64 {
+        fn prepare_syscall(&self, args: &[u64; 7]) -> Regs {
+            let mut copy = *self;
+            // the syscall number goes to x8, arguments to x0-x5
+            copy.regs[8] = args[0];
+            copy.regs[..6].copy_from_slice(&args[1..]);
+            copy
+        }
+
+        fn syscall_nr(&self) -> u64 {
+            self.regs[8]
+        }
+
+        fn syscall_ret(&self) -> u64 {
             self.regs[0]
         }
+
+        fn set_syscall_ret(&mut self, ret: u64) {
+            self.regs[0] = ret
+        }
+
+        /// To be used during wrap_syscall.
+        /// return (syscall_nr, arg1, ..., arg6)
+        /// x0 holds the return value after the syscall, arg1 is only valid on syscall entry.
+        fn get_syscall_params(&self) -> (u64, u64, u64, u64, u64, u64, u64) {
+            (
+                self.regs[8],
+                self.regs[0],
+                self.regs[1],
+                self.regs[2],
+                self.regs[3],
+                self.regs[4],
+                self.regs[5],
+            )
+        }
     }
 
     // $ rasm2  -a arm -b 64 'svc 0'
-    pub const SYSCALL_TEXT: u64 = 0x010000D4;
-    pub const SYSCALL_SIZE: u64 = 8;
+    // d4000001 as little endian word
+    pub const SYSCALL_TEXT: u64 = 0xD400_0001;
+    pub const SYSCALL_SIZE: u64 = 4;
+
+    /// Runs the syscalls of a table with entries of `[nr, arg1, ..., arg6, ret]` and stops.
+    /// x19 points to the table, x20 holds the number of entries.
+    ///
+    /// ```text
+    /// loop: cbz x20, done
+    ///       ldp x8, x0, [x19]
+    ///       ldp x1, x2, [x19, #16]
+    ///       ldp x3, x4, [x19, #32]
+    ///       ldr x5, [x19, #48]
+    ///       svc #0
+    ///       str x0, [x19, #56]
+    ///       add x19, x19, #64
+    ///       sub x20, x20, #1
+    ///       b loop
+    /// done: brk #0
+    /// ```
+    pub const BATCH_TEXT: &[u8] = &[
+        0x54, 0x01, 0x00, 0xb4, 0x68, 0x02, 0x40, 0xa9, 0x61, 0x0a, 0x41, 0xa9, 0x63, 0x12, 0x42,
+        0xa9, 0x65, 0x1a, 0x40, 0xf9, 0x01, 0x00, 0x00, 0xd4, 0x60, 0x1e, 0x00, 0xf9, 0x73, 0x02,
+        0x01, 0x91, 0x94, 0x06, 0x00, 0xd1, 0xf7, 0xff, 0xff, 0x17, 0x00, 0x00, 0x20, 0xd4,
+    ];
 }
 
 #[cfg(target_arch = "x86_64")]
 mod arch {
+    use crate::arch::Registers;
+
     #[repr(C)]
     #[derive(Clone, Copy, Debug, Default)]
     pub struct Regs {
@@ -82,13 +149,6 @@ mod arch {
         pub gs: u64,
     }
 
-    impl Regs {
-        /// true if current cpu privilege level is userspace
-        pub fn is_userspace(&self) -> bool {
-            self.cs & 3 == 3
-        }
-    }
-
     // from arch/x86/include/asm/fpu/types.h
     #[repr(C)]
     #[derive(Clone, Copy, Debug)]
@@ -113,16 +173,32 @@ mod arch {
         pub padding1: [u32; 12],
     }
 
-    impl Regs {
-        pub fn set_ip(&mut self, ip: u64) {
+    impl Registers for Regs {
+        /// true if current cpu privilege level is userspace
+        fn is_userspace(&self) -> bool {
+            self.cs & 3 == 3
+        }
+
+        fn set_ip(&mut self, ip: u64) {
             self.rip = ip
         }
 
-        pub fn ip(&self) -> u64 {
+        fn ip(&self) -> u64 {
             self.rip
         }
 
-        pub fn prepare_syscall(&self, args: &[u64; 7]) -> Regs {
+        /// Registers to run `BATCH_TEXT` at `ip` on `count` entries of the table at `table`.
+        fn prepare_batch(&self, ip: u64, table: u64, count: u64) -> Regs {
+            let mut copy = *self;
+            copy.rip = ip;
+            copy.r12 = table;
+            copy.r13 = count;
+            // not a valid syscall number, so that an interrupted syscall is not restarted
+            copy.orig_rax = u64::MAX;
+            copy
+        }
+
+        fn prepare_syscall(&self, args: &[u64; 7]) -> Regs {
             let mut copy = *self;
             copy.rax = args[0];
             copy.rdi = args[1];
@@ -134,13 +210,21 @@ mod arch {
             copy
         }
 
-        pub fn syscall_ret(&self) -> u64 {
+        fn syscall_nr(&self) -> u64 {
             self.rax
         }
 
+        fn syscall_ret(&self) -> u64 {
+            self.rax
+        }
+
+        fn set_syscall_ret(&mut self, ret: u64) {
+            self.rax = ret
+        }
+
         /// To be used during wrap_syscall.
         /// return (syscall_nr, arg1, ..., arg6)
-        pub fn get_syscall_params(&self) -> (u64, u64, u64, u64, u64, u64, u64) {
+        fn get_syscall_params(&self) -> (u64, u64, u64, u64, u64, u64, u64) {
             // self.rax contains return value of `syscall` instruction.
             // old rax (before `syscall` instruction) is rax_old or orig_rax.
             // also: https://lkml.org/lkml/2006/8/29/350:
@@ -162,6 +246,90 @@ mod arch {
     // $ rasm2  -a x86 -b 64 'syscall'
     pub const SYSCALL_TEXT: u64 = 0x050F;
     pub const SYSCALL_SIZE: u64 = 2;
+
+    /// Runs the syscalls of a table with entries of `[nr, arg1, ..., arg6, ret]` and stops.
+    /// r12 points to the table, r13 holds the number of entries.
+    ///
+    /// ```text
+    /// loop: test r13, r13
+    ///       jz done
+    ///       mov rax, [r12]
+    ///       mov rdi, [r12+8]
+    ///       mov rsi, [r12+16]
+    ///       mov rdx, [r12+24]
+    ///       mov r10, [r12+32]
+    ///       mov r8, [r12+40]
+    ///       mov r9, [r12+48]
+    ///       syscall
+    ///       mov [r12+56], rax
+    ///       add r12, 64
+    ///       dec r13
+    ///       jmp loop
+    /// done: int3
+    /// ```
+    pub const BATCH_TEXT: &[u8] = &[
+        0x4d, 0x85, 0xed, 0x74, 0x32, 0x49, 0x8b, 0x04, 0x24, 0x49, 0x8b, 0x7c, 0x24, 0x08, 0x49,
+        0x8b, 0x74, 0x24, 0x10, 0x49, 0x8b, 0x54, 0x24, 0x18, 0x4d, 0x8b, 0x54, 0x24, 0x20, 0x4d,
+        0x8b, 0x44, 0x24, 0x28, 0x4d, 0x8b, 0x4c, 0x24, 0x30, 0x0f, 0x05, 0x49, 0x89, 0x44, 0x24,
+        0x38, 0x49, 0x83, 0xc4, 0x40, 0x49, 0xff, 0xcd, 0xeb, 0xc9, 0xcc,
+    ];
+}
+
+/// Placeholder for architectures vmsh does not support yet, so that `arch` can report everything
+/// that is missing at once instead of failing on the first use of `Regs`.
+#[cfg(any(target_arch = "powerpc64", target_arch = "s390x"))]
+mod arch {
+    use crate::arch::Registers;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct Regs {}
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct FpuRegs {}
+
+    impl Registers for Regs {
+        fn is_userspace(&self) -> bool {
+            unimplemented!()
+        }
+
+        fn set_ip(&mut self, _ip: u64) {
+            unimplemented!()
+        }
+
+        fn ip(&self) -> u64 {
+            unimplemented!()
+        }
+
+        fn prepare_batch(&self, _ip: u64, _table: u64, _count: u64) -> Regs {
+            unimplemented!()
+        }
+
+        fn prepare_syscall(&self, _args: &[u64; 7]) -> Regs {
+            unimplemented!()
+        }
+
+        fn syscall_nr(&self) -> u64 {
+            unimplemented!()
+        }
+
+        fn syscall_ret(&self) -> u64 {
+            unimplemented!()
+        }
+
+        fn set_syscall_ret(&mut self, _ret: u64) {
+            unimplemented!()
+        }
+
+        fn get_syscall_params(&self) -> (u64, u64, u64, u64, u64, u64, u64) {
+            unimplemented!()
+        }
+    }
+
+    pub const SYSCALL_TEXT: u64 = 0;
+    pub const SYSCALL_SIZE: u64 = 0;
+    pub const BATCH_TEXT: &[u8] = &[];
 }
 
 pub use arch::*;
@@ -0,0 +1,111 @@
+//! Deliberately crashes a hung guest, so that a configured kdump kernel (or `vmsh coredump`
+//! afterwards) captures its state for postmortem analysis.
+
+use ioutils::exec::{self as stage2_exec, Message};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use kvm_bindings as kvmb;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use libc::c_int;
+use log::{info, warn};
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with, SimpleError};
+
+use crate::exec;
+use crate::kvm;
+use crate::kvm::hypervisor::VmSelector;
+use crate::result::Result;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CrashMethod {
+    /// Inject a non-maskable interrupt into the first vcpu, works while the guest hangs with
+    /// interrupts disabled.
+    Nmi,
+    /// Write `c` to /proc/sysrq-trigger in a VM attached with --daemon and --vsock
+    Sysrq,
+}
+
+impl std::str::FromStr for CrashMethod {
+    type Err = SimpleError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "nmi" => Ok(CrashMethod::Nmi),
+            "sysrq" => Ok(CrashMethod::Sysrq),
+            _ => Err(SimpleError::new(format!("unknown crash method: {}", s))),
+        }
+    }
+}
+
+pub struct CrashOptions {
+    pub pid: Pid,
+    pub vm: Option<VmSelector>,
+    pub method: CrashMethod,
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn inject_nmi(opts: &CrashOptions) -> Result<()> {
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid, opts.vm),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    vm.stop()?;
+    let has_nmi = vm.check_extension(kvmb::KVM_CAP_USER_NMI as c_int)?;
+    if has_nmi <= 0 {
+        bail!("kvm does not support injecting NMIs (KVM_CAP_USER_NMI)");
+    }
+    let vcpu = require_with!(vm.vcpus.first(), "vm has no vcpus");
+    try_with!(vm.nmi(vcpu), "cannot inject NMI into vcpu {}", vcpu.idx);
+    vm.resume()?;
+    info!("injected NMI into vcpu {}", vcpu.idx);
+    // an unknown NMI is only logged by default
+    info!("linux guests panic on it if kernel.unknown_nmi_panic is set");
+    Ok(())
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn inject_nmi(_opts: &CrashOptions) -> Result<()> {
+    bail!("injecting NMIs is only supported on x86")
+}
+
+fn write_sysrq(opts: &CrashOptions) -> Result<()> {
+    let command = ["/bin/sh", "-c", "echo c > /proc/sysrq-trigger"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+    let mut conn = exec::start(opts.pid, &command)?;
+    loop {
+        let msg = match stage2_exec::read_message(&mut conn) {
+            Ok(msg) => msg,
+            Err(e) => {
+                // the guest panics before stage2 can answer
+                info!("connection to stage2 lost after triggering sysrq: {}", e);
+                return Ok(());
+            }
+        };
+        match msg {
+            Some(Message::Stdout(_)) | Some(Message::Stderr(_)) => {}
+            Some(Message::Exit(0)) => {
+                warn!("sysrq was triggered, but the guest is still running");
+                return Ok(());
+            }
+            Some(Message::Exit(code)) => bail!(
+                "writing to /proc/sysrq-trigger failed with exit code {}",
+                code
+            ),
+            Some(Message::Error(e)) => bail!("cannot write to /proc/sysrq-trigger: {}", e),
+            Some(msg) => bail!("unexpected message from stage2: {:?}", msg),
+            None => {
+                info!("stage2 closed the connection after triggering sysrq");
+                return Ok(());
+            }
+        }
+    }
+}
+
+pub fn crash(opts: &CrashOptions) -> Result<()> {
+    match opts.method {
+        CrashMethod::Nmi => inject_nmi(opts),
+        CrashMethod::Sysrq => write_sysrq(opts),
+    }
+}
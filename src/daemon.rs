@@ -0,0 +1,643 @@
+//! JSON-RPC 2.0 interface to drive vmsh from other programs.
+//!
+//! Requests are newline-delimited JSON objects sent over a unix socket. The
+//! socket can be forwarded through a bastion host (i.e. `ssh -L`) to operate
+//! vmsh remotely. Supported methods:
+//!
+//! - `status`: list running sessions
+//! - `attach`/`exec`: attach to a VM. Once the response is sent, the connection
+//!   carries the raw console of the spawned command until either side hangs up.
+//! - `detach`: stop a running session
+//! - `coredump`: write a coredump of a VM on the host
+//! - `add_disk`/`remove_disk`: add or remove a block device of a running session.
+//!   The session has to be attached with `hotplug_slots` > 0.
+//!
+//! `attach`, `exec` and `coredump` accept either `vm_fd` or `vm_index` to select
+//! one VM of a hypervisor process that runs several.
+//!
+//! If the daemon was started with a token file, every request needs to provide
+//! the token as `token` parameter.
+
+use log::{error, info, warn};
+use nix::pty::openpty;
+use nix::sys::stat::{umask, Mode};
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+use nix::unistd::{ttyname, Pid};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use simple_error::{bail, require_with, try_with};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Write};
+use std::net::Shutdown;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::attach::{self, AttachOptions};
+use crate::coredump::{self, CoredumpOptions};
+use crate::devices::virtio::pci::Transport;
+use crate::devices::virtio::vsock::VSOCK_DEFAULT_GUEST_CID;
+use crate::devices::virtio::DEFAULT_QUEUE_SIZE;
+use crate::devices::{DeviceContext, MmioTransport, ShareMode};
+use crate::kvm::hypervisor::VmSelector;
+use crate::result::Result;
+use crate::rpc::{
+    parse_params, read_request, write_response, RpcError, INVALID_PARAMS, METHOD_NOT_FOUND,
+    UNAUTHORIZED,
+};
+use crate::signal_handler;
+
+/// How long `shutdown` waits for the sessions to detach from their VMs.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct DaemonOptions {
+    /// Path of the unix socket to listen on.
+    pub listen: PathBuf,
+    /// File containing the token clients have to present.
+    pub token_file: Option<PathBuf>,
+    /// Paths where Stage2 is written to in the VM, the first one that is writable is used.
+    pub stage2_paths: Vec<String>,
+}
+
+fn default_backing() -> PathBuf {
+    PathBuf::from("/dev/null")
+}
+
+fn default_vsock_cid() -> u64 {
+    VSOCK_DEFAULT_GUEST_CID
+}
+
+fn default_blk_queues() -> u16 {
+    1
+}
+
+fn default_queue_size() -> u16 {
+    DEFAULT_QUEUE_SIZE
+}
+
+#[derive(Deserialize)]
+struct AttachParams {
+    /// VM/Hypervisor pid or pod name to target
+    vm: String,
+    #[serde(default)]
+    types: Vec<String>,
+    /// selects the VM if the hypervisor runs more than one
+    #[serde(default)]
+    vm_fd: Option<RawFd>,
+    #[serde(default)]
+    vm_index: Option<usize>,
+    #[serde(default)]
+    command: Vec<String>,
+    #[serde(default)]
+    env: Vec<String>,
+    #[serde(default)]
+    workdir: Option<PathBuf>,
+    #[serde(default)]
+    user: Option<String>,
+    #[serde(default)]
+    skip_namespaces: Vec<String>,
+    #[serde(default)]
+    cgroup: Option<String>,
+    #[serde(default)]
+    seccomp: Option<PathBuf>,
+    #[serde(default = "default_backing")]
+    backing: PathBuf,
+    #[serde(default)]
+    share_mode: Option<String>,
+    #[serde(default)]
+    mmio: Option<String>,
+    #[serde(default)]
+    transport: Option<String>,
+    #[serde(default = "default_blk_queues")]
+    blk_queues: u16,
+    #[serde(default = "default_queue_size")]
+    queue_size: u16,
+    #[serde(default)]
+    disks: Vec<PathBuf>,
+    #[serde(default)]
+    pmem: Vec<PathBuf>,
+    #[serde(default)]
+    hotplug_slots: usize,
+    #[serde(default)]
+    snapshot: bool,
+    #[serde(default)]
+    record: Option<PathBuf>,
+    #[serde(default)]
+    console_ports: Vec<PathBuf>,
+    #[serde(default)]
+    net: Option<String>,
+    #[serde(default)]
+    vsock: Option<PathBuf>,
+    #[serde(default = "default_vsock_cid")]
+    vsock_cid: u64,
+    #[serde(default)]
+    rng: bool,
+}
+
+#[derive(Deserialize)]
+struct CoredumpParams {
+    vm: String,
+    #[serde(default)]
+    types: Vec<String>,
+    #[serde(default)]
+    vm_fd: Option<RawFd>,
+    #[serde(default)]
+    vm_index: Option<usize>,
+    path: Option<PathBuf>,
+    #[serde(default)]
+    kernel_virtual: bool,
+    #[serde(default)]
+    compress: bool,
+    #[serde(default)]
+    sparse: bool,
+    /// `START-END` guest-physical ranges, see `vmsh coredump --phys-range`
+    #[serde(default)]
+    phys_ranges: Vec<String>,
+    #[serde(default)]
+    process_cr3: Option<usize>,
+    #[serde(default)]
+    backtrace: bool,
+    symbols: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct DetachParams {
+    session: usize,
+}
+
+#[derive(Deserialize)]
+struct AddDiskParams {
+    session: usize,
+    path: PathBuf,
+    #[serde(default)]
+    read_only: bool,
+}
+
+#[derive(Deserialize)]
+struct RemoveDiskParams {
+    session: usize,
+    /// as returned by `add_disk`
+    id: usize,
+}
+
+#[derive(Serialize)]
+struct SessionInfo {
+    session: usize,
+    pid: i32,
+    command: Vec<String>,
+}
+
+struct Session {
+    pid: Pid,
+    command: Vec<String>,
+    stop: Sender<()>,
+    /// set once the devices are running
+    devices: Option<Weak<DeviceContext>>,
+}
+
+struct ConsoleSession {
+    id: usize,
+    console: File,
+    stop: Sender<()>,
+}
+
+struct Daemon {
+    token: Option<String>,
+    stage2_paths: Vec<String>,
+    next_session: AtomicUsize,
+    sessions: Mutex<HashMap<usize, Session>>,
+}
+
+fn lookup_vm(vm: &str, types: &[String]) -> Result<Pid> {
+    let types = types
+        .iter()
+        .filter_map(|t| container_pid::lookup_container_type(t))
+        .collect::<Vec<_>>();
+    match container_pid::lookup_container_pid(vm, &types) {
+        Ok(pid) => Ok(Pid::from_raw(pid)),
+        Err(e) => bail!("cannot find vm {}: {}", vm, e),
+    }
+}
+
+fn vm_selector(vm_fd: Option<RawFd>, vm_index: Option<usize>) -> Result<Option<VmSelector>> {
+    match (vm_fd, vm_index) {
+        (Some(_), Some(_)) => bail!("vm_fd and vm_index are mutually exclusive"),
+        (Some(fd), None) => Ok(Some(VmSelector::Fd(fd))),
+        (None, Some(idx)) => Ok(Some(VmSelector::Index(idx))),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Compares in constant time, so that response times do not reveal how much of the token a
+/// client guessed correctly.
+fn token_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Allocates a pseudoterminal in raw mode and returns master and slave side.
+pub(crate) fn open_console() -> Result<(File, File, PathBuf)> {
+    let pty = try_with!(openpty(None, None), "cannot allocate pseudoterminal");
+    let master = unsafe { File::from_raw_fd(pty.master) };
+    let slave = unsafe { File::from_raw_fd(pty.slave) };
+    let path = try_with!(ttyname(pty.slave), "cannot get name of pseudoterminal");
+    let mut attrs = try_with!(tcgetattr(pty.slave), "cannot get terminal attributes");
+    cfmakeraw(&mut attrs);
+    try_with!(
+        tcsetattr(pty.slave, SetArg::TCSANOW, &attrs),
+        "cannot set terminal attributes"
+    );
+    Ok((master, slave, path))
+}
+
+impl Daemon {
+    fn sessions(&self) -> MutexGuard<HashMap<usize, Session>> {
+        // a panicking session thread does not leave the map in an inconsistent state
+        match self.sessions.lock() {
+            Ok(s) => s,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn authenticate(&self, params: &Value) -> std::result::Result<(), RpcError> {
+        let expected = match &self.token {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+        match params.get("token").and_then(Value::as_str) {
+            Some(token) if token_eq(token.as_bytes(), expected.as_bytes()) => Ok(()),
+            _ => Err(RpcError::new(UNAUTHORIZED, "invalid or missing token")),
+        }
+    }
+
+    fn status(&self) -> Value {
+        let list = self
+            .sessions()
+            .iter()
+            .map(|(id, s)| SessionInfo {
+                session: *id,
+                pid: s.pid.as_raw(),
+                command: s.command.clone(),
+            })
+            .collect::<Vec<_>>();
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "sessions": list,
+        })
+    }
+
+    fn detach(&self, params: DetachParams) -> Result<()> {
+        match self.sessions().get(&params.session) {
+            Some(s) => {
+                // the session might already be shutting down
+                let _ = s.stop.send(());
+                Ok(())
+            }
+            None => bail!("no session with id {}", params.session),
+        }
+    }
+
+    fn session_devices(&self, session: usize) -> Result<Arc<DeviceContext>> {
+        let sessions = self.sessions();
+        let s = match sessions.get(&session) {
+            Some(s) => s,
+            None => bail!("no session with id {}", session),
+        };
+        match s.devices.as_ref().and_then(Weak::upgrade) {
+            Some(devices) => Ok(devices),
+            None => bail!("devices of session {} are not running", session),
+        }
+    }
+
+    fn add_disk(&self, params: AddDiskParams) -> Result<Value> {
+        // do not hold the sessions lock while the guest probes the device
+        let devices = self.session_devices(params.session)?;
+        let id = devices.add_disk(&params.path, params.read_only)?;
+        Ok(json!({ "id": id }))
+    }
+
+    fn remove_disk(&self, params: RemoveDiskParams) -> Result<()> {
+        let devices = self.session_devices(params.session)?;
+        devices.remove_disk(params.id)
+    }
+
+    fn coredump(&self, params: CoredumpParams) -> Result<Value> {
+        let pid = lookup_vm(&params.vm, &params.types)?;
+        let path = params
+            .path
+            .unwrap_or_else(|| PathBuf::from(coredump::default_path(pid, params.compress)));
+        let opts = CoredumpOptions {
+            pid,
+            vm: vm_selector(params.vm_fd, params.vm_index)?,
+            path: path.clone(),
+            kernel_virtual: params.kernel_virtual,
+            compress: params.compress,
+            sparse: params.sparse,
+            phys_ranges: params
+                .phys_ranges
+                .iter()
+                .map(|r| coredump::parse_phys_range(r))
+                .collect::<Result<Vec<_>>>()?,
+            process_cr3: params.process_cr3,
+            backtrace: params.backtrace,
+            symbols: params.symbols,
+        };
+        coredump::generate_coredump(&opts)?;
+        Ok(json!({ "path": path }))
+    }
+
+    /// Serves `attach` and `exec`, the latter only differs by requiring a command.
+    fn attach(
+        self: &Arc<Self>,
+        params: AttachParams,
+        stream: &UnixStream,
+    ) -> Result<ConsoleSession> {
+        let mmio_transport = match &params.mmio {
+            Some(transport) => transport.parse()?,
+            None => MmioTransport::Auto,
+        };
+        let share_mode = match &params.share_mode {
+            Some(mode) => mode.parse()?,
+            None => ShareMode::Block,
+        };
+        let transport = match &params.transport {
+            Some(transport) => transport.parse()?,
+            None => Transport::Mmio,
+        };
+        let pid = lookup_vm(&params.vm, &params.types)?;
+        let vm = vm_selector(params.vm_fd, params.vm_index)?;
+        let (master, slave, pts) = open_console()?;
+
+        let (stage2_path, stage2_fallback_paths) =
+            require_with!(self.stage2_paths.split_first(), "no stage2 path given");
+        let mut command = params.command;
+        command.insert(0, stage2_path.clone());
+        let opts = AttachOptions {
+            pid,
+            vm,
+            command: command.clone(),
+            stage2_fallback_paths: stage2_fallback_paths.to_vec(),
+            env: params.env,
+            workdir: params.workdir,
+            user: params.user,
+            skip_namespaces: params.skip_namespaces,
+            cgroup: params.cgroup,
+            seccomp: params.seccomp,
+            backing: params.backing,
+            share_mode,
+            pts: Some(pts),
+            record: params.record,
+            console_ports: params.console_ports,
+            // the console of a session is streamed to a single client
+            scrollback: 0,
+            tap: params.net,
+            vsock: params.vsock,
+            vsock_cid: params.vsock_cid,
+            // the daemon does not forward ports
+            forwards: vec![],
+            rng: params.rng,
+            transport,
+            mmio_transport,
+            blk_queues: params.blk_queues,
+            queue_size: params.queue_size,
+            disks: params.disks,
+            pmem: params.pmem,
+            hotplug_slots: params.hotplug_slots,
+            snapshot: params.snapshot,
+            // all vms of the daemon share the counters, they are only logged on SIGUSR2
+            metrics_addr: None,
+            hypervisor: None,
+            kill_on_detach: false,
+            wait_for_boot: false,
+            ignore_lockdown: false,
+            timing_out: None,
+        };
+
+        let (sender, receiver) = channel();
+        let id = self.next_session.fetch_add(1, Ordering::SeqCst);
+        self.sessions().insert(
+            id,
+            Session {
+                pid,
+                command,
+                stop: sender.clone(),
+                devices: None,
+            },
+        );
+
+        let daemon = Arc::clone(self);
+        let stop = sender.clone();
+        let client = try_with!(stream.try_clone(), "cannot clone client connection");
+        let res = thread::Builder::new()
+            .name(format!("session-{}", id))
+            .spawn(move || {
+                info!("session {}: attach to {}", id, opts.pid);
+                let started = |devices| {
+                    if let Some(s) = daemon.sessions().get_mut(&id) {
+                        s.devices = Some(devices);
+                    }
+                };
+                if let Err(e) = attach::attach_until(&opts, sender, receiver, started) {
+                    error!("session {}: {}", id, e);
+                }
+                info!("session {}: detached", id);
+                daemon.sessions().remove(&id);
+                // closing the last slave fd unblocks readers on the master side
+                drop(slave);
+                let _ = client.shutdown(Shutdown::Both);
+            });
+        if let Err(e) = res {
+            self.sessions().remove(&id);
+            bail!("cannot spawn session thread: {}", e);
+        }
+
+        Ok(ConsoleSession {
+            id,
+            console: master,
+            stop,
+        })
+    }
+
+    /// Detaches all sessions. Sessions that do not finish within `SHUTDOWN_TIMEOUT` (i.e.
+    /// because their VM is stuck) are abandoned.
+    fn shutdown(&self) {
+        for s in self.sessions().values() {
+            let _ = s.stop.send(());
+        }
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        while !self.sessions().is_empty() {
+            if Instant::now() >= deadline {
+                let ids = self.sessions().keys().copied().collect::<Vec<_>>();
+                warn!("sessions {:?} did not detach in time, exit anyway", ids);
+                return;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+/// Forwards console output to the client and client input to the console until
+/// one of both sides hangs up.
+fn stream_console(reader: BufReader<UnixStream>, session: ConsoleSession) -> Result<()> {
+    let mut console_in = try_with!(session.console.try_clone(), "cannot clone console");
+    let mut console_out = session.console;
+    let mut client_out = try_with!(reader.get_ref().try_clone(), "cannot clone connection");
+    let id = session.id;
+    let output = thread::spawn(move || {
+        // returns EIO once the session closed its side of the pseudoterminal
+        let _ = io::copy(&mut console_out, &mut client_out);
+        let _ = client_out.shutdown(Shutdown::Write);
+    });
+
+    // input that was sent together with the request
+    let pending = reader.buffer().to_vec();
+    let mut client_in = reader.into_inner();
+    let res = console_in
+        .write_all(&pending)
+        .and_then(|_| io::copy(&mut client_in, &mut console_in));
+    if let Err(e) = res {
+        warn!("session {}: cannot forward console input: {}", id, e);
+    }
+    // client has hung up, also stop the session
+    let _ = session.stop.send(());
+    if output.join().is_err() {
+        bail!("session {}: console output thread panicked", id);
+    }
+    Ok(())
+}
+
+fn handle_connection(stream: UnixStream, daemon: Arc<Daemon>) -> Result<()> {
+    let mut writer = try_with!(stream.try_clone(), "cannot clone connection");
+    let mut reader = BufReader::new(stream);
+    while let Some(req) = read_request(&mut reader, &mut writer)? {
+        if let Err(e) = daemon.authenticate(&req.params) {
+            write_response(&mut writer, req.id, Err(e))?;
+            continue;
+        }
+
+        let res = match req.method.as_str() {
+            "status" => Ok(daemon.status()),
+            "detach" => {
+                parse_params(&req.params).and_then(|p| Ok(daemon.detach(p).map(|_| Value::Null)?))
+            }
+            "coredump" => parse_params(&req.params).and_then(|p| Ok(daemon.coredump(p)?)),
+            "add_disk" => parse_params(&req.params).and_then(|p| Ok(daemon.add_disk(p)?)),
+            "remove_disk" => parse_params(&req.params)
+                .and_then(|p| Ok(daemon.remove_disk(p).map(|_| Value::Null)?)),
+            "attach" | "exec" => {
+                let session = parse_params::<AttachParams>(&req.params).and_then(|p| {
+                    if req.method == "exec" && p.command.is_empty() {
+                        return Err(RpcError::new(INVALID_PARAMS, "exec requires a command"));
+                    }
+                    Ok(daemon.attach(p, reader.get_ref())?)
+                });
+                match session {
+                    Ok(session) => {
+                        write_response(&mut writer, req.id, Ok(json!({ "session": session.id })))?;
+                        return stream_console(reader, session);
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            m => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("unknown method: {}", m),
+            )),
+        };
+        write_response(&mut writer, req.id, res)?;
+    }
+    Ok(())
+}
+
+pub fn daemon(opts: &DaemonOptions) -> Result<()> {
+    let token = match &opts.token_file {
+        Some(path) => {
+            let token = try_with!(
+                fs::read_to_string(path),
+                "cannot read token file {}",
+                path.display()
+            );
+            let token = token.trim().to_string();
+            if token.is_empty() {
+                bail!("token file {} is empty", path.display());
+            }
+            Some(token)
+        }
+        None => None,
+    };
+
+    // remove stale socket from previous runs
+    if opts.listen.exists() {
+        try_with!(
+            fs::remove_file(&opts.listen),
+            "cannot remove {}",
+            opts.listen.display()
+        );
+    }
+    // The socket is created with mode 0600, changing the permissions after bind() would let
+    // other users connect in between. No other threads run yet that could create files.
+    let old_umask = umask(Mode::from_bits_truncate(0o177));
+    let listener = UnixListener::bind(&opts.listen);
+    umask(old_umask);
+    let listener = try_with!(listener, "cannot listen on {}", opts.listen.display());
+
+    let daemon = Arc::new(Daemon {
+        token,
+        stage2_paths: opts.stage2_paths.clone(),
+        next_session: AtomicUsize::new(0),
+        sessions: Mutex::new(HashMap::new()),
+    });
+
+    let (sender, receiver) = channel();
+    signal_handler::setup(sender);
+    let d = Arc::clone(&daemon);
+    let listen = opts.listen.clone();
+    let _ = thread::spawn(move || {
+        if receiver.recv().is_err() {
+            return;
+        }
+        // detach from all vms before exiting so that they keep running
+        d.shutdown();
+        let _ = fs::remove_file(&listen);
+        std::process::exit(0);
+    });
+
+    info!("listening on {}", opts.listen.display());
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("cannot accept connection: {}", e);
+                continue;
+            }
+        };
+        let daemon = Arc::clone(&daemon);
+        let _ = thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, daemon) {
+                warn!("{}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_comparison() {
+        assert!(token_eq(b"secret", b"secret"));
+        assert!(!token_eq(b"secret", b"secreT"));
+        assert!(!token_eq(b"secret", b"secret2"));
+        assert!(!token_eq(b"", b"secret"));
+    }
+}
@@ -0,0 +1,159 @@
+use log::info;
+use simple_error::{bail, map_err_with, require_with, try_with};
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use vm_device::device_manager::MmioManager;
+
+use crate::devices::virtio::block::BlockSlot;
+use crate::devices::virtio::IrqAckHandler;
+use crate::devices::{Block, DeviceContext};
+use crate::kvm::hypervisor::Hypervisor;
+use crate::result::Result;
+use crate::stage1::DeviceSlots;
+
+/// A block device slot reserved at attach time (`DeviceOptions::hotplug_slots`).
+pub(super) struct HotplugSlot {
+    /// position of the device in the device addresses of stage1
+    pub index: usize,
+    /// the guest sees the device with serial `vmsh<id>`
+    pub id: usize,
+    pub irq_ack_handler: Arc<Mutex<IrqAckHandler>>,
+    /// reserved resources, taken when a device is added
+    pub resources: Option<BlockSlot>,
+    pub block: Option<Arc<Mutex<Block>>>,
+    /// Removed devices are kept until vmsh detaches, because their ioeventfds can only be
+    /// freed once vmsh controls the hypervisor process again. Each slot can be used once.
+    pub removed: bool,
+}
+
+impl HotplugSlot {
+    pub fn new(index: usize, id: usize, resources: BlockSlot) -> HotplugSlot {
+        HotplugSlot {
+            index,
+            id,
+            irq_ack_handler: resources.irq_ack_handler.clone(),
+            resources: Some(resources),
+            block: None,
+            removed: false,
+        }
+    }
+}
+
+#[derive(Default)]
+pub(super) struct Hotplug {
+    pub slots: Vec<HotplugSlot>,
+    /// set once stage1 is loaded
+    pub stage1: Option<(Arc<Hypervisor>, DeviceSlots)>,
+    /// see `DeviceOptions::snapshot`
+    pub snapshot: bool,
+}
+
+impl DeviceContext {
+    /// Tells the device context where stage1 expects the device addresses. Required before
+    /// devices can be added or removed.
+    pub(crate) fn set_device_slots(&self, vm: Arc<Hypervisor>, slots: DeviceSlots) -> Result<()> {
+        let mut hotplug = try_with!(self.hotplug.lock(), "cannot lock hotplug slots");
+        hotplug.stage1 = Some((vm, slots));
+        Ok(())
+    }
+
+    /// Number of block devices that can still be added with `add_disk`.
+    pub fn free_hotplug_slots(&self) -> Result<usize> {
+        let hotplug = try_with!(self.hotplug.lock(), "cannot lock hotplug slots");
+        Ok(hotplug
+            .slots
+            .iter()
+            .filter(|slot| slot.resources.is_some())
+            .count())
+    }
+
+    /// Adds a block device backed by `path` to the running VM and waits until the guest has
+    /// registered it. Returns the id of the device, the guest sees it with serial `vmsh<id>`.
+    pub fn add_disk(&self, path: &Path, read_only: bool) -> Result<usize> {
+        let mut hotplug = try_with!(self.hotplug.lock(), "cannot lock hotplug slots");
+        let (vm, stage1) = match &hotplug.stage1 {
+            Some((vm, stage1)) => (Arc::clone(vm), stage1.clone()),
+            None => bail!("devices are not started yet"),
+        };
+
+        let snapshot = hotplug.snapshot;
+
+        // check the backing file before taking the slot, a failure after that loses the slot
+        try_with!(
+            OpenOptions::new()
+                .read(true)
+                .write(!read_only && !snapshot)
+                .open(path),
+            "cannot open {}",
+            path.display()
+        );
+
+        let slot = match hotplug
+            .slots
+            .iter_mut()
+            .find(|slot| slot.resources.is_some())
+        {
+            Some(slot) => slot,
+            None => bail!("no free hotplug slot left, see the `hotplug_slots` attach parameter"),
+        };
+        let resources = require_with!(slot.resources.take(), "hotplug slot is already used");
+        let mmio_cfg = resources.mmio_cfg;
+        let serial = format!("vmsh{}", slot.id);
+        let block = match Block::from_slot(
+            resources,
+            path.to_path_buf(),
+            read_only,
+            snapshot,
+            false,
+            true,
+            &serial,
+        ) {
+            Ok(v) => Arc::new(Mutex::new(v)),
+            Err(e) => bail!("cannot create block device for {}: {:?}", path.display(), e),
+        };
+        {
+            let mut mmio_mgr = try_with!(self.mmio_mgr.lock(), "cannot lock mmio manager");
+            map_err_with!(
+                mmio_mgr.register_mmio(mmio_cfg.range, block.clone()),
+                "cannot register block device on mmio bus"
+            )?;
+        }
+        slot.block = Some(block);
+
+        // The guest probes the device while we wait, the mmio manager must not be locked here.
+        if let Err(e) = stage1.update(&vm, slot.index, mmio_cfg.range.base().0) {
+            slot.removed = true;
+            bail!("cannot add block device to guest: {}", e);
+        }
+        info!("added block device {} as {}", path.display(), serial);
+        Ok(slot.id)
+    }
+
+    /// Removes a block device added with `add_disk` from the running VM.
+    pub fn remove_disk(&self, id: usize) -> Result<()> {
+        let mut hotplug = try_with!(self.hotplug.lock(), "cannot lock hotplug slots");
+        let (vm, stage1) = match &hotplug.stage1 {
+            Some((vm, stage1)) => (Arc::clone(vm), stage1.clone()),
+            None => bail!("devices are not started yet"),
+        };
+
+        let slot = match hotplug
+            .slots
+            .iter_mut()
+            .find(|slot| slot.id == id && slot.block.is_some() && !slot.removed)
+        {
+            Some(slot) => slot,
+            None => bail!("no hotplugged block device with id {}", id),
+        };
+        // Unregistering the device in the guest resets it, which stops its queue handlers.
+        // The device stays on the mmio bus in case the guest still accesses it.
+        try_with!(
+            stage1.update(&vm, slot.index, 0),
+            "cannot remove block device from guest"
+        );
+        slot.removed = true;
+        info!("removed block device vmsh{}", id);
+        Ok(())
+    }
+}
@@ -2,7 +2,9 @@ use crate::kvm::hypervisor::ioregionfd::RawIoRegionFd;
 use crate::kvm::kvm_ioregionfd::{ioregionfd_cmd, Cmd};
 use crate::result::Result;
 use crate::tracer::wrap_syscall::{MmioRw, MMIO_RW_DATA_MAX};
+use serde::{Deserialize, Serialize};
 use simple_error::{map_err_with, try_with};
+use std::collections::HashMap;
 use std::sync::Arc;
 use vm_device::bus::{Bus, BusManager, MmioAddress};
 use vm_device::device_manager::MmioManager;
@@ -10,17 +12,91 @@ use vm_device::DeviceMmio;
 
 type MmioPirateBus<D> = Bus<MmioAddress, D>;
 
+/// Size of the register space of a virtio-mmio device
+const VIRTIO_MMIO_SIZE: u64 = 0x1000;
+// virtio-mmio registers that change the state of the device
+const VIRTIO_MMIO_QUEUE_SEL: u64 = 0x30;
+const VIRTIO_MMIO_QUEUE_READY: u64 = 0x44;
+const VIRTIO_MMIO_QUEUE_NOTIFY: u64 = 0x50;
+const VIRTIO_MMIO_INTERRUPT_ACK: u64 = 0x64;
+const VIRTIO_MMIO_STATUS: u64 = 0x70;
+const STATUS_DRIVER_OK: u32 = 4;
+
+/// A register write of the guest driver.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MmioWrite {
+    pub addr: u64,
+    pub data: Vec<u8>,
+}
+
+impl MmioWrite {
+    fn base(&self) -> u64 {
+        self.addr & !(VIRTIO_MMIO_SIZE - 1)
+    }
+
+    fn offset(&self) -> u64 {
+        self.addr & (VIRTIO_MMIO_SIZE - 1)
+    }
+
+    fn value(&self) -> u32 {
+        let mut bytes = [0u8; 4];
+        let len = self.data.len().min(bytes.len());
+        bytes[..len].copy_from_slice(&self.data[..len]);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn is_driver_ok(&self) -> bool {
+        self.offset() == VIRTIO_MMIO_STATUS && self.value() & STATUS_DRIVER_OK != 0
+    }
+}
+
+/// Configuration writes of the guest drivers to virtio-mmio devices since their last reset.
+/// Replaying them brings new devices into the state the driver expects, which is how vmsh
+/// re-attaches to a detached session.
+#[derive(Default)]
+pub struct MmioLog {
+    writes: Vec<MmioWrite>,
+}
+
+impl MmioLog {
+    fn record(&mut self, addr: u64, data: &[u8]) {
+        let write = MmioWrite {
+            addr,
+            data: data.to_vec(),
+        };
+        match write.offset() {
+            // notifications and interrupt acknowledgements do not change the configuration
+            VIRTIO_MMIO_QUEUE_NOTIFY | VIRTIO_MMIO_INTERRUPT_ACK => return,
+            // a reset starts the negotiation from scratch
+            VIRTIO_MMIO_STATUS if write.value() == 0 => {
+                let base = write.base();
+                self.writes.retain(|w| w.base() != base);
+                return;
+            }
+            _ => {}
+        }
+        self.writes.push(write);
+    }
+
+    pub fn writes(&self) -> &[MmioWrite] {
+        &self.writes
+    }
+}
+
 /// Replacement for vm_device::device_manager::IoManager.
 /// Can implement MmioManager via vm_device::device_manager::MmioManager.
 pub struct IoPirate {
     /// mmio device spaces typically accessed by VM exit mmio
     mmio_bus: MmioPirateBus<Arc<dyn DeviceMmio + Send + Sync>>,
+    /// Only kept for virtio-mmio devices, see `MmioLog`
+    pub log: Option<MmioLog>,
 }
 
 impl Default for IoPirate {
     fn default() -> IoPirate {
         IoPirate {
             mmio_bus: Bus::new(),
+            log: None,
         }
     }
 }
@@ -38,14 +114,70 @@ impl IoPirate {
     //    Ok(())
     //}
 
+    /// Writes to the device at `addr` and records the write in the log.
+    fn write(&mut self, addr: u64, data: &[u8]) -> Result<()> {
+        map_err_with!(
+            self.mmio_write(MmioAddress(addr), data),
+            "write to mmio device ({:#x}) failed",
+            addr
+        )?;
+        if let Some(log) = &mut self.log {
+            log.record(addr, data);
+        }
+        Ok(())
+    }
+
+    /// Brings freshly created devices into the state that the guest drivers negotiated with
+    /// a previous vmsh process. `before_activate` is called once all queues are configured,
+    /// but before the devices are activated. Afterwards every ready queue is notified, since
+    /// the driver might have made buffers available while no device was listening.
+    pub fn replay(
+        &mut self,
+        writes: &[MmioWrite],
+        before_activate: impl FnOnce() -> Result<()>,
+    ) -> Result<()> {
+        let mut activations = vec![];
+        let mut ready_queues = vec![];
+        let mut queue_sel = HashMap::new();
+        for write in writes {
+            match write.offset() {
+                VIRTIO_MMIO_QUEUE_SEL => {
+                    queue_sel.insert(write.base(), write.value());
+                }
+                VIRTIO_MMIO_QUEUE_READY if write.value() == 1 => {
+                    let queue = queue_sel.get(&write.base()).copied().unwrap_or(0);
+                    ready_queues.push((write.base(), queue));
+                }
+                _ => {}
+            }
+            if write.is_driver_ok() {
+                activations.push(write);
+                continue;
+            }
+            self.write(write.addr, &write.data)?;
+        }
+        before_activate()?;
+        for write in activations {
+            self.write(write.addr, &write.data)?;
+        }
+        for (base, queue) in ready_queues {
+            map_err_with!(
+                self.mmio_write(
+                    MmioAddress(base + VIRTIO_MMIO_QUEUE_NOTIFY),
+                    &queue.to_le_bytes()
+                ),
+                "cannot notify queue {} of device {:#x}",
+                queue,
+                base
+            )?;
+        }
+        Ok(())
+    }
+
     /// Used with MmioExitWrapper.
     pub fn handle_mmio_rw(&mut self, mmio_rw: &mut MmioRw) -> Result<()> {
         if mmio_rw.is_write {
-            map_err_with!(
-                self.mmio_write(MmioAddress(mmio_rw.addr), mmio_rw.data()),
-                "write to mmio device ({:#x}) failed",
-                mmio_rw.addr
-            )?;
+            self.write(mmio_rw.addr, mmio_rw.data())?;
         } else {
             let mut data = [0u8; MMIO_RW_DATA_MAX];
             let len = mmio_rw.data().len();
@@ -60,6 +192,32 @@ impl IoPirate {
         Ok(())
     }
 
+    /// Used with IoRegionFd. Handles a batch of commands read at once. Consecutive identical
+    /// posted writes are only executed once: the vcpus do not wait for them and repeating a
+    /// register write without reading in between (i.e. queue notifications of several vcpus) has
+    /// no additional effect.
+    pub fn handle_ioregion_rws(
+        &mut self,
+        ioregionfd: &RawIoRegionFd,
+        cmds: &[ioregionfd_cmd],
+    ) -> Result<()> {
+        let posted_writes = ioregionfd.ioregion.posted_writes();
+        let mut last_write: Option<&ioregionfd_cmd> = None;
+        for cmd in cmds {
+            if posted_writes {
+                if last_write.map_or(false, |last| last.same_write(cmd)) {
+                    continue;
+                }
+                last_write = match cmd.info.cmd() {
+                    Cmd::Write => Some(cmd),
+                    Cmd::Read => None,
+                };
+            }
+            self.handle_ioregion_rw(ioregionfd, *cmd)?;
+        }
+        Ok(())
+    }
+
     /// Used with IoRegionFd.
     pub fn handle_ioregion_rw(
         &mut self,
@@ -69,12 +227,10 @@ impl IoPirate {
         let addr = ioregionfd.ioregion.guest_paddr + rw.offset;
         let res = match rw.info.cmd() {
             Cmd::Write => {
-                let data = rw.data();
-                map_err_with!(
-                    self.mmio_write(MmioAddress(addr), data),
-                    "write to mmio device ({:#x}) failed",
-                    addr
-                )?;
+                self.write(addr, rw.data())?;
+                if ioregionfd.ioregion.posted_writes() {
+                    return Ok(());
+                }
                 // must be acknowledged with an arbitrary response
                 ioregionfd.write(0)
             }
@@ -1,23 +1,38 @@
+mod hotplug;
 pub mod mmio;
 mod threads;
 pub mod virtio;
 
-use crate::devices::mmio::IoPirate;
+use crate::devices::hotplug::{Hotplug, HotplugSlot};
+use crate::devices::mmio::{IoPirate, MmioLog, MmioWrite};
 use crate::devices::threads::SubscriberEventManager;
-use crate::devices::virtio::block::{self, BlockArgs};
+use crate::devices::virtio::block::{self, BlockArgs, BlockSlot};
 use crate::devices::virtio::console::{self, ConsoleArgs};
-use crate::devices::virtio::{CommonArgs, MmioConfig};
+use crate::devices::virtio::interrupt::{GsiBackend, InterruptBackend};
+use crate::devices::virtio::net::{self, NetArgs};
+use crate::devices::virtio::p9::{self, P9Args, VMSH_MOUNT_TAG};
+use crate::devices::virtio::pci::{PciBus, PciWindow, Transport, PCI_BAR_SIZE, PCI_SLOT_SIZE};
+use crate::devices::virtio::pmem::{self, PmemArgs, PMEM_ALIGN};
+use crate::devices::virtio::rng::{self, RngArgs};
+use crate::devices::virtio::vsock::{self, VsockArgs};
+use crate::devices::virtio::IrqAckHandler;
+use crate::devices::virtio::{resume_queues, validate_queue_size, CommonArgs, MmioConfig};
 use crate::kvm::hypervisor::ioregionfd::IoRegionFd;
+use crate::kvm::hypervisor::shared_ram::SharedRam;
 use crate::kvm::hypervisor::Hypervisor;
 use crate::kvm::PhysMemAllocator;
 use crate::result::Result;
 use crate::tracer::proc::Mapping;
 use libc::pid_t;
-use simple_error::{bail, try_with};
-use std::path::{Path, PathBuf};
-use std::sync::atomic::AtomicBool;
-use std::sync::atomic::Ordering;
+use nix::unistd::getpid;
+use simple_error::{bail, map_err_with, require_with, try_with, SimpleError};
+use stage1_interface::MAX_DEVICES;
+use std::borrow::BorrowMut;
+use std::fs::File;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use virtio_device::VirtioConfig;
+use virtio_queue::Queue;
 use vm_device::device_manager::MmioManager;
 use vm_memory::guest_memory::GuestAddress;
 use vm_memory::mmap::MmapRegion;
@@ -26,25 +41,139 @@ use vm_memory::{GuestMemoryMmap, GuestRegionMmap};
 
 pub use self::threads::DeviceSet;
 
-/// Should be initialized by the argument parser.
-pub static USE_IOREGIONFD: AtomicBool = AtomicBool::new(false);
+pub type Block = block::Block;
+pub type Console = console::Console;
+pub type Net = net::Net;
+pub type P9 = p9::P9;
+pub type Vsock = vsock::Vsock;
+pub type Rng = rng::Rng;
+pub type Pmem = pmem::Pmem;
 
-pub fn use_ioregionfd() -> bool {
-    USE_IOREGIONFD.load(Ordering::Relaxed)
+/// How the backing filesystem is shared with the VM.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShareMode {
+    /// Serve an image file as virtio-blk device.
+    Block,
+    /// Export a directory with virtio-9p. Works with kernels that lack virtio-fs support.
+    P9,
 }
 
-pub type Block = block::Block;
-pub type Console = console::Console;
+impl std::str::FromStr for ShareMode {
+    type Err = SimpleError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(ShareMode::Block),
+            "9p" => Ok(ShareMode::P9),
+            _ => Err(SimpleError::new(format!("unsupported share mode: {}", s))),
+        }
+    }
+}
+
+/// How guest accesses to the MMIO memory of our devices reach vmsh.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MmioTransport {
+    /// Use ioregionfd if KVM supports it, otherwise intercept MMIO exits.
+    Auto,
+    /// Requires KVM_CAP_IOREGIONFD, which is not in mainline Linux.
+    IoRegionFd,
+    /// Intercept the MMIO exits of `ioctl(KVM_RUN)` with ptrace.
+    WrapSyscall,
+}
+
+impl std::str::FromStr for MmioTransport {
+    type Err = SimpleError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(MmioTransport::Auto),
+            "ioregionfd" => Ok(MmioTransport::IoRegionFd),
+            "wrap" | "wrap_syscall" => Ok(MmioTransport::WrapSyscall),
+            _ => Err(SimpleError::new(format!(
+                "unsupported mmio transport: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Configuration of the devices vmsh provides to the VM.
+pub struct DeviceOptions {
+    /// File served as block device or directory exported via 9p.
+    pub backing: PathBuf,
+    pub share_mode: ShareMode,
+    /// Pseudoterminal connected to the console. Uses stdout if not set.
+    pub pts: Option<PathBuf>,
+    /// Record the console output as asciinema cast.
+    pub record: Option<PathBuf>,
+    /// Pseudoterminals connected to additional console ports (`/dev/virtio-ports/vmsh.portN`).
+    pub console_ports: Vec<PathBuf>,
+    /// Bytes of console output kept for clients that connect later.
+    pub scrollback: usize,
+    /// Host tap device backing the network device. No network device is created if not set.
+    pub tap: Option<String>,
+    /// Unix socket path prefix for guest vsock connections. No vsock device is created if not set.
+    pub vsock: Option<PathBuf>,
+    /// Context id of the guest on the vsock device.
+    pub vsock_cid: u64,
+    /// Provide an entropy device backed by the host's /dev/urandom.
+    pub rng: bool,
+    /// Expose the devices as virtio-mmio or virtio-pci devices.
+    pub transport: Transport,
+    /// Number of request queues of the block device.
+    pub blk_queues: u16,
+    /// Number of descriptors in each virtio queue.
+    pub queue_size: u16,
+    /// Additional files served as block devices, with serial `vmsh1`, `vmsh2`, ...
+    pub disks: Vec<PathBuf>,
+    /// Files exposed read-only as virtio-pmem devices, which the guest can mount with DAX.
+    pub pmem: Vec<PathBuf>,
+    /// Number of block devices that can be added at runtime, see `DeviceContext::add_disk`.
+    pub hotplug_slots: usize,
+    /// Keep writes to block devices in memory, the backing files are never modified.
+    pub snapshot: bool,
+}
 
-fn convert(pid: pid_t, mappings: &[Mapping]) -> Result<GuestMemoryMmap> {
+impl DeviceOptions {
+    /// Number of virtio devices created for these options, including hotplug slots.
+    pub fn device_count(&self) -> usize {
+        // root device (block or 9p) and console
+        2 + self.tap.is_some() as usize
+            + self.vsock.is_some() as usize
+            + self.rng as usize
+            + self.disks.len()
+            + self.pmem.len()
+            + self.hotplug_slots
+    }
+
+    /// Whether vmsh can detach from the devices and re-attach to them later. This relies on
+    /// `MmioLog`, hotplug slots would need their disks back and pmem devices their memslots.
+    pub fn detachable(&self) -> bool {
+        self.transport == Transport::Mmio && self.hotplug_slots == 0 && self.pmem.is_empty()
+    }
+}
+
+/// Guest memory of the devices. If all memslots are also mapped into vmsh, the regions point to
+/// our own mapping and are accessed within this process, otherwise all of them are accessed
+/// remotely in the hypervisor.
+fn convert(pid: pid_t, mappings: &[Mapping], ram: &SharedRam) -> Result<GuestMemoryMmap> {
     let mut regions: Vec<Arc<GuestRegionMmap>> = vec![];
 
-    for mapping in mappings {
+    let local = mappings
+        .iter()
+        .map(|m| ram.local_ptr(m.start, m.size()))
+        .collect::<Option<Vec<_>>>();
+    let (pid, hosts) = match local {
+        Some(ptrs) => (getpid().as_raw(), ptrs),
+        None => (pid, mappings.iter().map(|m| m.start as *mut u8).collect()),
+    };
+
+    for (mapping, host) in mappings.iter().zip(hosts) {
         // TODO need reason for why this is safe. ("a smart human wrote it")
         let mmap_region = try_with!(
             unsafe {
                 MmapRegion::build_raw(
-                    mapping.start as *mut u8,
+                    host,
                     mapping.end - mapping.start,
                     mapping.prot_flags.bits(),
                     mapping.map_flags.bits(),
@@ -71,14 +200,44 @@ fn convert(pid: pid_t, mappings: &[Mapping]) -> Result<GuestMemoryMmap> {
     ))
 }
 
+fn alloc_mmio_cfg(
+    allocator: &mut PhysMemAllocator,
+    irq_num: usize,
+    transport: Transport,
+) -> Result<MmioConfig> {
+    let range = match transport {
+        Transport::Mmio => allocator.alloc_mmio_range(0x1000)?,
+        Transport::Pci => {
+            allocator.alloc_aligned_mmio_range(PCI_BAR_SIZE as usize, PCI_BAR_SIZE as usize)?
+        }
+    };
+    Ok(MmioConfig {
+        range,
+        gsi: irq_num as u32,
+        transport,
+    })
+}
+
 trait MaybeIoRegionFd {
     fn get_ioregionfd(&mut self) -> &mut Option<IoRegionFd>;
 }
 
 pub struct DeviceContext {
-    pub blkdev: Arc<Mutex<Block>>,
+    pub blkdev: Option<Arc<Mutex<Block>>>,
+    pub p9: Option<Arc<Mutex<P9>>>,
     pub console: Arc<Mutex<Console>>,
+    pub net: Option<Arc<Mutex<Net>>>,
+    pub vsock: Option<Arc<Mutex<Vsock>>>,
+    pub rng: Option<Arc<Mutex<Rng>>>,
+    /// additional block devices
+    pub disks: Vec<Arc<Mutex<Block>>>,
+    pub pmem: Vec<Arc<Mutex<Pmem>>>,
+    /// block devices added or removed at runtime
+    hotplug: Mutex<Hotplug>,
+    /// Configuration space of the devices if they use the PCI transport
+    pub pci: Option<Arc<Mutex<PciBus>>>,
     pub mmio_mgr: Arc<Mutex<IoPirate>>,
+    mem: Arc<GuestMemoryMmap>,
     /// start address of mmio space
     pub first_mmio_addr: u64,
     /// start address of mmio space
@@ -87,69 +246,396 @@ pub struct DeviceContext {
 
 impl DeviceContext {
     pub fn mmio_addrs(&self) -> Result<Vec<u64>> {
-        Ok(vec![
-            try_with!(self.blkdev.lock(), "cannot lock block device")
-                .mmio_cfg
-                .range
-                .base()
-                .0,
+        let mut addrs = vec![];
+        if let Some(blkdev) = &self.blkdev {
+            addrs.push(
+                try_with!(blkdev.lock(), "cannot lock block device")
+                    .mmio_cfg
+                    .range
+                    .base()
+                    .0,
+            );
+        }
+        if let Some(p9) = &self.p9 {
+            addrs.push(
+                try_with!(p9.lock(), "cannot lock 9p device")
+                    .mmio_cfg
+                    .range
+                    .base()
+                    .0,
+            );
+        }
+        addrs.push(
             try_with!(self.console.lock(), "cannot lock console device")
                 .mmio_cfg
                 .range
                 .base()
                 .0,
-        ])
+        );
+        if let Some(net) = &self.net {
+            addrs.push(
+                try_with!(net.lock(), "cannot lock net device")
+                    .mmio_cfg
+                    .range
+                    .base()
+                    .0,
+            );
+        }
+        if let Some(vsock) = &self.vsock {
+            addrs.push(
+                try_with!(vsock.lock(), "cannot lock vsock device")
+                    .mmio_cfg
+                    .range
+                    .base()
+                    .0,
+            );
+        }
+        if let Some(rng) = &self.rng {
+            addrs.push(
+                try_with!(rng.lock(), "cannot lock rng device")
+                    .mmio_cfg
+                    .range
+                    .base()
+                    .0,
+            );
+        }
+        for disk in &self.disks {
+            addrs.push(
+                try_with!(disk.lock(), "cannot lock block device")
+                    .mmio_cfg
+                    .range
+                    .base()
+                    .0,
+            );
+        }
+        for pmem in &self.pmem {
+            addrs.push(
+                try_with!(pmem.lock(), "cannot lock pmem device")
+                    .mmio_cfg
+                    .range
+                    .base()
+                    .0,
+            );
+        }
+        Ok(addrs)
+    }
+
+    /// Where stage1 finds the PCI devices, if the PCI transport is used
+    pub fn pci_window(&self) -> Result<Option<PciWindow>> {
+        match &self.pci {
+            Some(pci) => {
+                let bars = self.mmio_addrs()?;
+                Ok(Some(
+                    try_with!(pci.lock(), "cannot lock pci bus").window(&bars),
+                ))
+            }
+            None => Ok(None),
+        }
     }
+
+    /// Register writes of the guest drivers, see `MmioLog`.
+    pub fn mmio_writes(&self) -> Result<Vec<MmioWrite>> {
+        let mmio_mgr = try_with!(self.mmio_mgr.lock(), "cannot lock mmio manager");
+        let log = require_with!(mmio_mgr.log.as_ref(), "mmio writes are not recorded");
+        Ok(log.writes().to_vec())
+    }
+
+    /// Brings the devices into the state the drivers negotiated with the devices of a vmsh
+    /// process that detached from the VM. Must be called before the guest is resumed.
+    pub fn restore(&self, writes: &[MmioWrite]) -> Result<()> {
+        let mut mmio_mgr = try_with!(self.mmio_mgr.lock(), "cannot lock mmio manager");
+        mmio_mgr.replay(writes, || self.resume_queues())
+    }
+
+    fn resume_queues(&self) -> Result<()> {
+        fn resume<D: BorrowMut<VirtioConfig<Queue>>>(
+            device: &Mutex<D>,
+            mem: &GuestMemoryMmap,
+        ) -> Result<()> {
+            let mut device = try_with!(device.lock(), "cannot lock device");
+            resume_queues(BorrowMut::borrow_mut(&mut *device), mem)
+        }
+        if let Some(blkdev) = &self.blkdev {
+            resume(blkdev, &self.mem)?;
+        }
+        if let Some(p9) = &self.p9 {
+            resume(p9, &self.mem)?;
+        }
+        resume(&self.console, &self.mem)?;
+        if let Some(net) = &self.net {
+            resume(net, &self.mem)?;
+        }
+        if let Some(vsock) = &self.vsock {
+            resume(vsock, &self.mem)?;
+        }
+        if let Some(rng) = &self.rng {
+            resume(rng, &self.mem)?;
+        }
+        for disk in &self.disks {
+            resume(disk, &self.mem)?;
+        }
+        for pmem in &self.pmem {
+            resume(pmem, &self.mem)?;
+        }
+        Ok(())
+    }
+
+    /// Interrupt acknowledgement handlers of all devices
+    pub fn irq_ack_handlers(&self) -> Result<Vec<Arc<Mutex<IrqAckHandler>>>> {
+        let mut handlers = vec![];
+        if let Some(blkdev) = &self.blkdev {
+            handlers.push(
+                try_with!(blkdev.lock(), "cannot lock block device")
+                    .irq_ack_handler
+                    .clone(),
+            );
+        }
+        if let Some(p9) = &self.p9 {
+            handlers.push(
+                try_with!(p9.lock(), "cannot lock 9p device")
+                    .irq_ack_handler
+                    .clone(),
+            );
+        }
+        handlers.push(
+            try_with!(self.console.lock(), "cannot lock console device")
+                .irq_ack_handler
+                .clone(),
+        );
+        if let Some(net) = &self.net {
+            handlers.push(
+                try_with!(net.lock(), "cannot lock net device")
+                    .irq_ack_handler
+                    .clone(),
+            );
+        }
+        if let Some(vsock) = &self.vsock {
+            handlers.push(
+                try_with!(vsock.lock(), "cannot lock vsock device")
+                    .irq_ack_handler
+                    .clone(),
+            );
+        }
+        if let Some(rng) = &self.rng {
+            handlers.push(
+                try_with!(rng.lock(), "cannot lock rng device")
+                    .irq_ack_handler
+                    .clone(),
+            );
+        }
+        for disk in &self.disks {
+            handlers.push(
+                try_with!(disk.lock(), "cannot lock block device")
+                    .irq_ack_handler
+                    .clone(),
+            );
+        }
+        for pmem in &self.pmem {
+            handlers.push(
+                try_with!(pmem.lock(), "cannot lock pmem device")
+                    .irq_ack_handler
+                    .clone(),
+            );
+        }
+        let hotplug = try_with!(self.hotplug.lock(), "cannot lock hotplug slots");
+        for slot in &hotplug.slots {
+            handlers.push(slot.irq_ack_handler.clone());
+        }
+        Ok(handlers)
+    }
+
     pub fn new(
         vmm: &Arc<Hypervisor>,
         allocator: &mut PhysMemAllocator,
         event_mgr: &mut SubscriberEventManager,
-        irq_num: usize,
-        backing: &Path,
-        pts: Option<PathBuf>,
+        irq_nums: &[usize],
+        opts: &DeviceOptions,
     ) -> Result<DeviceContext> {
         let guest_memory = try_with!(vmm.get_maps(), "cannot get guests memory");
+        let ram = Arc::new(SharedRam::map(vmm.pid, &guest_memory));
         let mem = Arc::new(try_with!(
-            convert(vmm.pid.as_raw(), &guest_memory),
+            convert(vmm.pid.as_raw(), &guest_memory, &ram),
             "cannot convert Mapping to GuestMemoryMmap"
         ));
 
-        let block_mmio_cfg = MmioConfig {
-            range: allocator.alloc_mmio_range(0x1000)?,
-            gsi: irq_num as u32,
+        // stage1 has a fixed number of device slots
+        if opts.device_count() > MAX_DEVICES {
+            bail!(
+                "cannot create {} devices, stage1 supports at most {}",
+                opts.device_count(),
+                MAX_DEVICES
+            );
+        }
+        try_with!(validate_queue_size(opts.queue_size), "invalid queue size");
+        if opts.hotplug_slots > 0 {
+            // stage1 only supports adding virtio-mmio devices and the ioregion handler threads
+            // are started together with the devices
+            if opts.transport != Transport::Mmio {
+                bail!("block device hotplug requires the mmio transport");
+            }
+            if vmm.use_ioregionfd() {
+                bail!("block device hotplug is not supported with ioregionfd");
+            }
+        }
+
+        let transport = opts.transport;
+        // devices are allocated in the order of their stage1 slot, each slot has its own line
+        let mut irqs = irq_nums.iter().copied();
+        let mut next_irq =
+            || -> Result<usize> { Ok(require_with!(irqs.next(), "no interrupt line left")) };
+        // either the block or the 9p device
+        let root_mmio_cfg = alloc_mmio_cfg(allocator, next_irq()?, transport)?;
+        let console_mmio_cfg = alloc_mmio_cfg(allocator, next_irq()?, transport)?;
+
+        let net_mmio_cfg = match opts.tap {
+            Some(_) => Some(alloc_mmio_cfg(allocator, next_irq()?, transport)?),
+            None => None,
         };
 
-        let console_mmio_cfg = MmioConfig {
-            range: allocator.alloc_mmio_range(0x1000)?,
-            gsi: irq_num as u32,
+        let vsock_mmio_cfg = match opts.vsock {
+            Some(_) => Some(alloc_mmio_cfg(allocator, next_irq()?, transport)?),
+            None => None,
         };
 
-        let first_mmio_addr = console_mmio_cfg.range.base().0;
-        let last_mmio_addr = block_mmio_cfg.range.last().0;
+        let rng_mmio_cfg = if opts.rng {
+            Some(alloc_mmio_cfg(allocator, next_irq()?, transport)?)
+        } else {
+            None
+        };
+
+        let disk_mmio_cfgs = opts
+            .disks
+            .iter()
+            .map(|_| alloc_mmio_cfg(allocator, next_irq()?, transport))
+            .collect::<Result<Vec<_>>>()?;
+
+        let pmem_mmio_cfgs = opts
+            .pmem
+            .iter()
+            .map(|_| alloc_mmio_cfg(allocator, next_irq()?, transport))
+            .collect::<Result<Vec<_>>>()?;
+
+        let hotplug_mmio_cfgs = (0..opts.hotplug_slots)
+            .map(|_| alloc_mmio_cfg(allocator, next_irq()?, transport))
+            .collect::<Result<Vec<_>>>()?;
+
+        // The devices in the order they appear in `mmio_addrs`, which also determines
+        // their PCI slot.
+        let root_device_id = match opts.share_mode {
+            ShareMode::Block => block::BLOCK_DEVICE_ID,
+            ShareMode::P9 => p9::P9_DEVICE_ID,
+        };
+        let mut pci_devices = vec![
+            Some((root_device_id, &root_mmio_cfg)),
+            Some((console::CONSOLE_DEVICE_ID, &console_mmio_cfg)),
+            net_mmio_cfg.as_ref().map(|cfg| (net::NET_DEVICE_ID, cfg)),
+            vsock_mmio_cfg
+                .as_ref()
+                .map(|cfg| (vsock::VSOCK_DEVICE_ID, cfg)),
+            rng_mmio_cfg.as_ref().map(|cfg| (rng::RNG_DEVICE_ID, cfg)),
+        ];
+        pci_devices.extend(
+            disk_mmio_cfgs
+                .iter()
+                .map(|cfg| Some((block::BLOCK_DEVICE_ID, cfg))),
+        );
+        pci_devices.extend(
+            pmem_mmio_cfgs
+                .iter()
+                .map(|cfg| Some((pmem::PMEM_DEVICE_ID, cfg))),
+        );
+        let pci_range = match transport {
+            Transport::Mmio => None,
+            Transport::Pci => {
+                Some(allocator.alloc_mmio_range(MAX_DEVICES * PCI_SLOT_SIZE as usize)?)
+            }
+        };
+
+        // mmio ranges are allocated top-down
+        let first_mmio_addr = pci_range.map_or_else(
+            || {
+                pci_devices
+                    .iter()
+                    .flatten()
+                    .map(|(_, cfg)| cfg.range.base().0)
+                    .chain(hotplug_mmio_cfgs.iter().map(|cfg| cfg.range.base().0))
+                    .min()
+                    .unwrap_or_else(|| console_mmio_cfg.range.base().0)
+            },
+            |range| range.base().0,
+        );
+        let last_mmio_addr = root_mmio_cfg.range.last().0;
+
+        let interrupts: Arc<dyn InterruptBackend> = Arc::new(GsiBackend::new(vmm.clone()));
 
         // IoManager replacement:
-        let device_manager = Arc::new(Mutex::new(IoPirate::default()));
-        let blkdev = {
+        let mut io_pirate = IoPirate::default();
+        if transport == Transport::Mmio {
+            io_pirate.log = Some(MmioLog::default());
+        }
+        let device_manager = Arc::new(Mutex::new(io_pirate));
+
+        let pci = match pci_range {
+            Some(range) => {
+                let mut bus = PciBus::new(range);
+                for (device_id, cfg) in pci_devices.iter().flatten() {
+                    bus.add_device(*device_id, cfg.range, cfg.gsi);
+                }
+                if vmm.use_ioregionfd() {
+                    bus.ioregionfd = Some(vmm.ioregionfd(range.base().0, range.size() as usize)?);
+                }
+                let bus = Arc::new(Mutex::new(bus));
+                let mut guard = try_with!(device_manager.lock(), "cannot lock device manager");
+                map_err_with!(
+                    guard.register_mmio(range, bus.clone()),
+                    "cannot register pci configuration space"
+                )?;
+                Some(bus)
+            }
+            None => None,
+        };
+        let (blkdev, p9) = {
             let guard = try_with!(device_manager.lock(), "cannot lock device manager");
-            guard.mmio_device(block_mmio_cfg.range.base());
+            guard.mmio_device(root_mmio_cfg.range.base());
 
             let common = CommonArgs {
                 mem: Arc::clone(&mem),
+                ram: Arc::clone(&ram),
                 vmm: vmm.clone(),
                 event_mgr,
                 mmio_mgr: guard,
-                mmio_cfg: block_mmio_cfg,
-            };
-            let args = BlockArgs {
-                common,
-                file_path: backing.to_path_buf(),
-                read_only: false,
-                root_device: true,
-                advertise_flush: true,
+                mmio_cfg: root_mmio_cfg,
+                interrupts: interrupts.clone(),
+                queue_size: opts.queue_size,
             };
-            match Block::new(args) {
-                Ok(v) => v,
-                Err(e) => bail!("cannot create block device: {:?}", e),
+            match opts.share_mode {
+                ShareMode::Block => {
+                    let args = BlockArgs {
+                        common,
+                        file_path: opts.backing.clone(),
+                        read_only: false,
+                        snapshot: opts.snapshot,
+                        root_device: true,
+                        advertise_flush: true,
+                        num_queues: opts.blk_queues,
+                        serial: "vmsh0".to_string(),
+                    };
+                    match Block::new(args) {
+                        Ok(v) => (Some(v), None),
+                        Err(e) => bail!("cannot create block device: {:?}", e),
+                    }
+                }
+                ShareMode::P9 => {
+                    let args = P9Args {
+                        common,
+                        root: opts.backing.clone(),
+                        tag: VMSH_MOUNT_TAG.to_string(),
+                    };
+                    match P9::new(args) {
+                        Ok(v) => (None, Some(v)),
+                        Err(e) => bail!("cannot create 9p device: {:?}", e),
+                    }
+                }
             }
         };
         let console = {
@@ -157,13 +643,22 @@ impl DeviceContext {
             guard.mmio_device(console_mmio_cfg.range.base());
 
             let common = CommonArgs {
-                mem,
+                mem: Arc::clone(&mem),
+                ram: Arc::clone(&ram),
                 vmm: vmm.clone(),
                 event_mgr,
                 mmio_mgr: guard,
                 mmio_cfg: console_mmio_cfg,
+                interrupts: interrupts.clone(),
+                queue_size: opts.queue_size,
+            };
+            let args = ConsoleArgs {
+                common,
+                pts: opts.pts.clone(),
+                record: opts.record.clone(),
+                ports: opts.console_ports.clone(),
+                scrollback: opts.scrollback,
             };
-            let args = ConsoleArgs { common, pts };
 
             match Console::new(args) {
                 Ok(v) => v,
@@ -171,10 +666,190 @@ impl DeviceContext {
             }
         };
 
+        let net = match (&opts.tap, net_mmio_cfg) {
+            (Some(tap), Some(mmio_cfg)) => {
+                let guard = try_with!(device_manager.lock(), "cannot lock device manager");
+                guard.mmio_device(mmio_cfg.range.base());
+
+                let common = CommonArgs {
+                    mem: Arc::clone(&mem),
+                    ram: Arc::clone(&ram),
+                    vmm: vmm.clone(),
+                    event_mgr,
+                    mmio_mgr: guard,
+                    mmio_cfg,
+                    interrupts: interrupts.clone(),
+                    queue_size: opts.queue_size,
+                };
+                let args = NetArgs {
+                    common,
+                    tap: tap.clone(),
+                };
+
+                match Net::new(args) {
+                    Ok(v) => Some(v),
+                    Err(e) => bail!("cannot create net device: {:?}", e),
+                }
+            }
+            _ => None,
+        };
+
+        let vsock = match (&opts.vsock, vsock_mmio_cfg) {
+            (Some(uds_path), Some(mmio_cfg)) => {
+                let guard = try_with!(device_manager.lock(), "cannot lock device manager");
+                guard.mmio_device(mmio_cfg.range.base());
+
+                let common = CommonArgs {
+                    mem: Arc::clone(&mem),
+                    ram: Arc::clone(&ram),
+                    vmm: vmm.clone(),
+                    event_mgr,
+                    mmio_mgr: guard,
+                    mmio_cfg,
+                    interrupts: interrupts.clone(),
+                    queue_size: opts.queue_size,
+                };
+                let args = VsockArgs {
+                    common,
+                    guest_cid: opts.vsock_cid,
+                    uds_path: uds_path.clone(),
+                };
+
+                match Vsock::new(args) {
+                    Ok(v) => Some(v),
+                    Err(e) => bail!("cannot create vsock device: {:?}", e),
+                }
+            }
+            _ => None,
+        };
+
+        let rng = match rng_mmio_cfg {
+            Some(mmio_cfg) => {
+                let guard = try_with!(device_manager.lock(), "cannot lock device manager");
+                guard.mmio_device(mmio_cfg.range.base());
+
+                let common = CommonArgs {
+                    mem: Arc::clone(&mem),
+                    ram: Arc::clone(&ram),
+                    vmm: vmm.clone(),
+                    event_mgr,
+                    mmio_mgr: guard,
+                    mmio_cfg,
+                    interrupts: interrupts.clone(),
+                    queue_size: opts.queue_size,
+                };
+                match Rng::new(RngArgs { common }) {
+                    Ok(v) => Some(v),
+                    Err(e) => bail!("cannot create rng device: {:?}", e),
+                }
+            }
+            None => None,
+        };
+
+        let mut disks = vec![];
+        for (i, (path, mmio_cfg)) in opts.disks.iter().zip(disk_mmio_cfgs).enumerate() {
+            let guard = try_with!(device_manager.lock(), "cannot lock device manager");
+            guard.mmio_device(mmio_cfg.range.base());
+
+            let common = CommonArgs {
+                mem: Arc::clone(&mem),
+                ram: Arc::clone(&ram),
+                vmm: vmm.clone(),
+                event_mgr,
+                mmio_mgr: guard,
+                mmio_cfg,
+                interrupts: interrupts.clone(),
+                queue_size: opts.queue_size,
+            };
+            let args = BlockArgs {
+                common,
+                file_path: path.clone(),
+                read_only: false,
+                snapshot: opts.snapshot,
+                root_device: false,
+                advertise_flush: true,
+                num_queues: opts.blk_queues,
+                serial: format!("vmsh{}", i + 1),
+            };
+            match Block::new(args) {
+                Ok(v) => disks.push(v),
+                Err(e) => bail!("cannot create block device for {}: {:?}", path.display(), e),
+            }
+        }
+
+        let mut pmems = vec![];
+        for (path, mmio_cfg) in opts.pmem.iter().zip(pmem_mmio_cfgs) {
+            let file = try_with!(File::open(path), "cannot open {}", path.display());
+            let phys_mem = try_with!(
+                allocator.phys_alloc_file(&file, PMEM_ALIGN),
+                "cannot map {} into the guest",
+                path.display()
+            );
+            let guard = try_with!(device_manager.lock(), "cannot lock device manager");
+            guard.mmio_device(mmio_cfg.range.base());
+
+            let common = CommonArgs {
+                mem: Arc::clone(&mem),
+                ram: Arc::clone(&ram),
+                vmm: vmm.clone(),
+                event_mgr,
+                mmio_mgr: guard,
+                mmio_cfg,
+                interrupts: interrupts.clone(),
+                queue_size: opts.queue_size,
+            };
+            let args = PmemArgs {
+                common,
+                mem: phys_mem,
+            };
+            match Pmem::new(args) {
+                Ok(v) => pmems.push(v),
+                Err(e) => bail!("cannot create pmem device for {}: {:?}", path.display(), e),
+            }
+        }
+
+        // hotplug slots come after all other devices in the device addresses of stage1
+        let first_hotplug_index = opts.device_count() - opts.hotplug_slots;
+        let mut hotplug_slots = vec![];
+        for (i, mmio_cfg) in hotplug_mmio_cfgs.into_iter().enumerate() {
+            let guard = try_with!(device_manager.lock(), "cannot lock device manager");
+            let mut common = CommonArgs {
+                mem: Arc::clone(&mem),
+                ram: Arc::clone(&ram),
+                vmm: vmm.clone(),
+                event_mgr,
+                mmio_mgr: guard,
+                mmio_cfg,
+                interrupts: interrupts.clone(),
+                queue_size: opts.queue_size,
+            };
+            match BlockSlot::new(&mut common, opts.blk_queues) {
+                Ok(v) => hotplug_slots.push(HotplugSlot::new(
+                    first_hotplug_index + i,
+                    opts.disks.len() + 1 + i,
+                    v,
+                )),
+                Err(e) => bail!("cannot reserve hotplug slot: {:?}", e),
+            }
+        }
+
         let device = DeviceContext {
             blkdev,
+            p9,
             console,
+            net,
+            vsock,
+            rng,
+            disks,
+            pmem: pmems,
+            hotplug: Mutex::new(Hotplug {
+                slots: hotplug_slots,
+                stage1: None,
+                snapshot: opts.snapshot,
+            }),
+            pci,
             mmio_mgr: device_manager,
+            mem,
             first_mmio_addr,
             last_mmio_addr,
         };
@@ -1,4 +1,5 @@
-use crate::devices::mmio::IoPirate;
+use crate::devices::mmio::{IoPirate, MmioWrite};
+use crate::stage1::DeviceSlots;
 use crate::stage1::DeviceStatus;
 use crate::stage1::DriverStatus;
 use event_manager::EventManager;
@@ -6,31 +7,48 @@ use event_manager::MutEventSubscriber;
 use log::debug;
 use log::error;
 use log::{info, log_enabled, trace, Level};
+use signal_hook::consts::signal::SIGWINCH;
+use signal_hook::iterator::Signals;
 use simple_error::{bail, require_with, simple_error, try_with};
 use stage1_interface::DeviceState;
-use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{Receiver, Sender};
 use std::sync::Arc;
 use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
 use virtio_device::{VirtioDevice, WithDriverSelect};
 
-use crate::devices;
+use crate::devices::virtio::pci::PciWindow;
+use crate::devices::Block;
+use crate::devices::Console;
 use crate::devices::DeviceContext;
+use crate::devices::DeviceOptions;
 use crate::devices::MaybeIoRegionFd;
+use crate::guest_wait;
 use crate::interrutable_thread::InterrutableThread;
 use crate::kvm::hypervisor::Hypervisor;
 use crate::kvm::PhysMemAllocator;
+use crate::metrics::{self, METRICS};
 use crate::result::Result;
 use crate::tracer::wrap_syscall::KvmRunWrapper;
 
-const EVENT_LOOP_TIMEOUT_MS: i32 = 1;
+/// Only bounds how long stopping the event loop takes, all work is signaled by fds.
+const EVENT_LOOP_TIMEOUT_MS: i32 = 100;
+/// How often the block device state is logged with debug logging
+const BLKDEV_MONITOR_INTERVAL: Duration = Duration::from_secs(10);
+/// How often we check for SIGWINCH
+const RESIZE_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+/// How often the terminal size is read even without SIGWINCH
+const TERMINAL_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 // Arc<Mutex<>> because the same device (a dyn DevicePio/DeviceMmio from IoManager's
 // perspective, and a dyn MutEventSubscriber from EventManager's) is managed by the 2 entities,
 // and isn't Copy-able; so once one of them gets ownership, the other one can't anymore.
 pub type SubscriberEventManager = EventManager<Arc<Mutex<dyn MutEventSubscriber + Send>>>;
 
+/// How often waiting for the devices checks whether it was cancelled.
+const WAIT_INTERVAL: Duration = Duration::from_millis(100);
+
 /// data structure to wait for block device to become ready
 pub struct DriverNotifier {
     // supress warning because of https://github.com/rust-lang/rust-clippy/issues/1516
@@ -69,40 +87,60 @@ impl DriverNotifier {
         Ok(())
     }
 
+    /// Asks stage1 to unregister its devices and to stop running. Only once this succeeded,
+    /// the memory of stage1 can be removed from the guest.
     pub fn terminate(&self) -> Result<()> {
         let mut state_guard = try_with!(self.lock.lock(), "failed to lock");
         if *state_guard == DeviceState::Initializing {
             bail!("cannot terminate unitialized device");
         }
 
-        *state_guard = DeviceState::Terminating;
+        *state_guard = DeviceState::Unloading;
         try_with!(
-            self.device_status
-                .update(&self.hv, DeviceState::Terminating),
+            self.device_status.update(&self.hv, DeviceState::Unloading),
             "failed to notify stage1 in VM about termination"
         );
 
-        loop {
-            match try_with!(
+        // stage1 polls for requests at most every 500ms, slow guests take much longer
+        guest_wait::poll(
+            "stage1 to unload",
+            Duration::from_millis(10),
+            || false,
+            || match try_with!(
                 self.driver_status.check(&self.hv),
                 "cannot check device state"
             ) {
-                DeviceState::Ready => {}
-                DeviceState::Terminating => break,
-                s => {
-                    bail!("unexpected driver state: {:?}", s);
-                }
-            }
-        }
-
+                // still running or unregistering devices
+                DeviceState::Ready | DeviceState::Terminating => Ok(None),
+                DeviceState::Unloading => Ok(Some(())),
+                s => bail!("unexpected driver state: {:?}", s),
+            },
+        )?;
+        // stage1 leaves the last store to kernel code, it does not run anymore
         Ok(())
     }
 
-    /// Blocks until block device is ready
-    fn wait(&self) -> Result<()> {
-        let mut state = try_with!(self.lock.lock(), "failed to lock");
-        while *state == DeviceState::Initializing {
-            state = try_with!(self.condvar.wait(state), "failed to wait for condvar");
+    /// Blocks until the devices are ready. Gives up once a message arrives on `stop`, i.e. if
+    /// vmsh is stopped or one of the device threads failed.
+    fn wait(&self, stop: &Receiver<()>) -> Result<()> {
+        let state = guest_wait::poll(
+            "stage1 to initialize the devices",
+            Duration::ZERO,
+            || stop.try_recv().is_ok(),
+            || {
+                let state = try_with!(self.lock.lock(), "failed to lock");
+                let (state, _) = try_with!(
+                    self.condvar
+                        .wait_timeout_while(state, WAIT_INTERVAL, |state| {
+                            *state == DeviceState::Initializing
+                        }),
+                    "failed to wait for condvar"
+                );
+                Ok(Some(*state).filter(|state| *state != DeviceState::Initializing))
+            },
+        )?;
+        if state == DeviceState::Error {
+            bail!("devices failed to start");
         }
         Ok(())
     }
@@ -124,15 +162,30 @@ impl Drop for DriverNotifier {
     }
 }
 
+fn log_blkdev_state(blkdev: &Mutex<Block>) -> Result<()> {
+    let blkdev = try_with!(blkdev.lock(), "cannot lock block device");
+    debug!(
+        "dev queue {}: irq status b{:b}",
+        blkdev.queue_select(),
+        blkdev.interrupt_status().load(Ordering::SeqCst),
+    );
+    Ok(())
+}
+
+/// Waits in epoll for ioeventfds, sockets, irq ack timers and other fds of the devices, so an
+/// idle vmsh does not use any cpu.
 fn event_thread(
     mut event_mgr: SubscriberEventManager,
     device_space: &DeviceContext,
     err_sender: Sender<()>,
 ) -> Result<InterrutableThread<(), Option<Arc<DeviceContext>>>> {
-    let blkdev = device_space.blkdev.clone();
-    let ack_handler = {
-        let blkdev = try_with!(blkdev.lock(), "cannot unlock thread");
-        blkdev.irq_ack_handler.clone()
+    for ack_handler in device_space.irq_ack_handlers()? {
+        event_mgr.add_subscriber(ack_handler);
+    }
+    let monitored_blkdev = if log_enabled!(Level::Debug) {
+        device_space.blkdev.clone()
+    } else {
+        None
     };
     log::debug!("event thread started");
 
@@ -140,6 +193,7 @@ fn event_thread(
         "event-manager",
         err_sender,
         move |_ctx: &Option<Arc<DeviceContext>>, should_stop: Arc<AtomicBool>| {
+            let mut last_monitor = Instant::now();
             loop {
                 match event_mgr.run_with_timeout(EVENT_LOOP_TIMEOUT_MS) {
                     Ok(nr) => {
@@ -149,9 +203,11 @@ fn event_thread(
                     }
                     Err(e) => log::warn!("Failed to handle events: {:?}", e),
                 }
-                {
-                    let mut ack_handler = try_with!(ack_handler.lock(), "failed to lock");
-                    ack_handler.handle_timeouts();
+                if let Some(blkdev) = &monitored_blkdev {
+                    if last_monitor.elapsed() >= BLKDEV_MONITOR_INTERVAL {
+                        last_monitor = Instant::now();
+                        log_blkdev_state(blkdev)?;
+                    }
                 }
                 if should_stop.load(Ordering::Relaxed) {
                     break;
@@ -164,63 +220,34 @@ fn event_thread(
     Ok(try_with!(res, "failed to spawn event-manager thread"))
 }
 
-/// Periodically print block device state
-fn blkdev_monitor_thread(
-    device: &DeviceContext,
+/// Forwards size changes of the terminal to the console device. We get SIGWINCH for our own
+/// terminal, a pts given with `--pts` belongs to another session and is polled instead.
+fn console_resize_thread(
+    console: Arc<Mutex<Console>>,
     err_sender: Sender<()>,
 ) -> Result<InterrutableThread<(), Option<Arc<DeviceContext>>>> {
-    let blkdev = device.blkdev.clone();
     let res = InterrutableThread::spawn(
-        "blkdev-monitor",
+        "console-resize",
         err_sender,
         move |_ctx: &Option<Arc<DeviceContext>>, should_stop: Arc<AtomicBool>| {
-            //std::thread::sleep(std::time::Duration::from_millis(10000));
-            loop {
-                {
-                    let blkdev = try_with!(blkdev.lock(), "cannot unlock thread");
-                    // debug!("");
-                    // debug!("dev type {}", blkdev.device_type());
-                    // debug!("dev features b{:b}", blkdev.device_features());
-                    // debug!(
-                    //     "dev interrupt stat b{:b}",
-                    //     blkdev.interrupt_status().load(Ordering::Relaxed)
-                    // );
-                    // debug!("dev status b{:b}", blkdev.device_status());
-                    // debug!("dev config gen {}", blkdev.config_generation());
-                    // debug!(
-                    //     "dev queue {}: max size: {}, ready: {}, is valid: {}",
-                    //     //blkdev.selected_queue().map(Queue::max_size).unwrap()
-                    //     blkdev.queue_select(),
-                    //     blkdev.selected_queue().unwrap().max_size(),
-                    //     blkdev.selected_queue().unwrap().ready,
-                    //     blkdev.selected_queue().unwrap().is_valid() // crashes before activate()
-                    // );
-                    // debug!(
-                    //     "dev queue {}: avail idx {}, next avail idx {}",
-                    //     blkdev.queue_select(),
-                    //     blkdev.selected_queue().unwrap().avail_idx(Ordering::Relaxed).unwrap(),
-                    //     blkdev.selected_queue().unwrap().next_avail(),
-                    //     );
-                    debug!(
-                        "dev queue {}: irq status b{:b}",
-                        blkdev.queue_select(),
-                        blkdev.interrupt_status().load(Ordering::SeqCst),
-                    );
-
-                    //debug!("occasional irqfd << 1");
-                    //blkdev.irqfd.write(1).unwrap();
-                }
-
-                if should_stop.load(Ordering::Relaxed) {
-                    return Ok(());
+            let mut signals = try_with!(Signals::new([SIGWINCH]), "cannot handle SIGWINCH");
+            let mut last_poll = Instant::now();
+            while !should_stop.load(Ordering::Relaxed) {
+                let resized = signals.pending().count() > 0;
+                if resized || last_poll.elapsed() >= TERMINAL_POLL_INTERVAL {
+                    last_poll = Instant::now();
+                    let mut console = try_with!(console.lock(), "cannot lock console device");
+                    if let Err(e) = console.update_size() {
+                        log::warn!("cannot resize console: {:?}", e);
+                    }
                 }
-                std::thread::sleep(std::time::Duration::from_millis(10000));
+                std::thread::sleep(RESIZE_CHECK_INTERVAL);
             }
+            Ok(())
         },
         None,
     );
-
-    Ok(try_with!(res, "failed to spawn blkdev-monitor"))
+    Ok(try_with!(res, "failed to spawn console-resize thread"))
 }
 
 /// Traps KVM_MMIO_EXITs with ptrace and forward them as needed to our block and console device driver
@@ -230,7 +257,6 @@ fn handle_mmio_exits(
     ctx: &DeviceContext,
     driver_notifier: &Arc<DriverNotifier>,
 ) -> Result<()> {
-    let mut mmio_mgr = try_with!(ctx.mmio_mgr.lock(), "cannot lock mmio manager");
     let mut wrapper_go = try_with!(wrapper_mo.lock(), "cannot obtain wrapper mutex");
     let wrapper_g = require_with!(wrapper_go.as_mut(), "KvmRunWrapper not initialized");
     try_with!(
@@ -249,8 +275,11 @@ fn handle_mmio_exits(
         if let Some(mmio_rw) = &mut kvm_exit {
             if ctx.first_mmio_addr <= mmio_rw.addr && mmio_rw.addr < ctx.last_mmio_addr {
                 // intercept op
-                trace!("mmio access: {:#x}", mmio_rw.addr);
+                let _span = tracing::trace_span!("mmio-exit", addr = mmio_rw.addr).entered();
+                // only locked per access, so that devices can be added at runtime
+                let mut mmio_mgr = try_with!(ctx.mmio_mgr.lock(), "cannot lock mmio manager");
                 try_with!(mmio_mgr.handle_mmio_rw(mmio_rw), "failed to handle MmioRw");
+                metrics::add(&METRICS.mmio_exits, 1);
             } else {
                 // do nothing, just continue to ignore and pass to hv
                 trace!("ignore addr: {:#x}", mmio_rw.addr)
@@ -311,8 +340,13 @@ fn mmio_exit_handler_thread(
 pub struct DeviceSet {
     context: Arc<DeviceContext>,
     event_manager: SubscriberEventManager,
+    /// Driver configuration replayed on start, see `DeviceSet::restore`
+    restore: Vec<MmioWrite>,
 }
 
+/// Maximum number of ioregionfd commands handled with one lock of the mmio manager.
+const IOREGION_BATCH: usize = 32;
+
 fn ioregion_event_loop(
     should_stop: &Arc<AtomicBool>,
     mmio_mgr: Arc<Mutex<IoPirate>>,
@@ -327,6 +361,7 @@ fn ioregion_event_loop(
         ioregion.fdclone()
     };
 
+    let mut cmds = Vec::with_capacity(IOREGION_BATCH);
     loop {
         let cmd = try_with!(
             ioregionfd.read(),
@@ -334,12 +369,27 @@ fn ioregion_event_loop(
             ioregionfd
         );
         if let Some(cmd) = cmd {
+            cmds.push(cmd);
+            // With posted writes, vcpus may queue up several commands until we get to them.
+            while cmds.len() < IOREGION_BATCH {
+                match try_with!(
+                    ioregionfd.try_read(),
+                    "cannot read mmio command from ioregionfd (fd {:?})",
+                    ioregionfd
+                ) {
+                    Some(cmd) => cmds.push(cmd),
+                    None => break,
+                }
+            }
+            let _span = tracing::trace_span!("ioregionfd", cmds = cmds.len()).entered();
             let mut mmio_mgr = try_with!(
                 mmio_mgr.lock(),
                 "cannot lock mmio manager to handle mmio command"
             );
-            mmio_mgr.handle_ioregion_rw(&ioregionfd, cmd)?;
+            mmio_mgr.handle_ioregion_rws(&ioregionfd, &cmds)?;
+            metrics::add(&METRICS.mmio_exits, cmds.len() as u64);
             drop(mmio_mgr);
+            cmds.clear();
         }
 
         if should_stop.load(Ordering::Relaxed) {
@@ -380,40 +430,52 @@ impl DeviceSet {
         self.context.mmio_addrs()
     }
 
+    pub fn pci_window(&self) -> Result<Option<PciWindow>> {
+        self.context.pci_window()
+    }
+
+    /// The devices, also used to add or remove block devices once started.
+    pub fn context(&self) -> Arc<DeviceContext> {
+        Arc::clone(&self.context)
+    }
+
     pub fn new(
         vm: &Arc<Hypervisor>,
         allocator: &mut PhysMemAllocator,
-        irq_num: usize,
-        backing_file: &Path,
-        pts: Option<PathBuf>,
+        irq_nums: &[usize],
+        opts: &DeviceOptions,
     ) -> Result<DeviceSet> {
         let mut event_manager =
             try_with!(SubscriberEventManager::new(), "cannot create event manager");
         // instantiate blkdev
         let context = Arc::new(try_with!(
-            DeviceContext::new(
-                vm,
-                allocator,
-                &mut event_manager,
-                irq_num,
-                backing_file,
-                pts
-            ),
+            DeviceContext::new(vm, allocator, &mut event_manager, irq_nums, opts),
             "cannot create device context"
         ));
         Ok(DeviceSet {
             context,
             event_manager,
+            restore: vec![],
         })
     }
 
+    /// Resumes the devices of a detached session: the register writes of the drivers are
+    /// replayed before the guest can access the devices.
+    pub fn restore(&mut self, writes: Vec<MmioWrite>) {
+        self.restore = writes;
+    }
+
     pub fn start(
         self,
         vm: &Arc<Hypervisor>,
         device_status: DeviceStatus,
         driver_status: DriverStatus,
+        device_slots: DeviceSlots,
         err_sender: Sender<()>,
+        stop: &Receiver<()>,
     ) -> Result<(Threads, Arc<DriverNotifier>)> {
+        self.context
+            .set_device_slots(Arc::clone(vm), device_slots)?;
         let driver_notifier = Arc::new(DriverNotifier::new(
             device_status,
             driver_status,
@@ -424,12 +486,22 @@ impl DeviceSet {
             &self.context,
             err_sender.clone(),
         )?];
+        // activating the devices requires the event manager
+        if !self.restore.is_empty() {
+            try_with!(
+                self.context.restore(&self.restore),
+                "cannot restore device state"
+            );
+        }
 
-        if log_enabled!(Level::Debug) {
-            threads.push(blkdev_monitor_thread(&self.context, err_sender.clone())?);
+        if try_with!(self.context.console.lock(), "cannot lock console device").is_terminal() {
+            threads.push(console_resize_thread(
+                Arc::clone(&self.context.console),
+                err_sender.clone(),
+            )?);
         }
 
-        if devices::use_ioregionfd() {
+        if vm.use_ioregionfd() {
             vm.resume()?;
             // Device was ready already before that but this way,
             // we only only indicate readiness just before we create our io threads.
@@ -437,24 +509,103 @@ impl DeviceSet {
                 driver_notifier.notify(DeviceState::Ready),
                 "cannot update device status"
             );
-            threads.push(try_with!(
-                ioregion_handler_thread(
-                    self.context.clone(),
-                    self.context.blkdev.clone(),
-                    self.context.mmio_mgr.clone(),
-                    err_sender.clone(),
-                ),
-                "cannot spawn block ioregion handler"
-            ));
+            if let Some(blkdev) = &self.context.blkdev {
+                threads.push(try_with!(
+                    ioregion_handler_thread(
+                        self.context.clone(),
+                        blkdev.clone(),
+                        self.context.mmio_mgr.clone(),
+                        err_sender.clone(),
+                    ),
+                    "cannot spawn block ioregion handler"
+                ));
+            }
+            if let Some(p9) = &self.context.p9 {
+                threads.push(try_with!(
+                    ioregion_handler_thread(
+                        self.context.clone(),
+                        p9.clone(),
+                        self.context.mmio_mgr.clone(),
+                        err_sender.clone(),
+                    ),
+                    "cannot spawn 9p ioregion handler"
+                ));
+            }
             threads.push(try_with!(
                 ioregion_handler_thread(
                     self.context.clone(),
                     self.context.console.clone(),
                     self.context.mmio_mgr.clone(),
-                    err_sender,
+                    err_sender.clone(),
                 ),
                 "cannot spawn console ioregion handler"
             ));
+            if let Some(net) = &self.context.net {
+                threads.push(try_with!(
+                    ioregion_handler_thread(
+                        self.context.clone(),
+                        net.clone(),
+                        self.context.mmio_mgr.clone(),
+                        err_sender.clone(),
+                    ),
+                    "cannot spawn net ioregion handler"
+                ));
+            }
+            if let Some(vsock) = &self.context.vsock {
+                threads.push(try_with!(
+                    ioregion_handler_thread(
+                        self.context.clone(),
+                        vsock.clone(),
+                        self.context.mmio_mgr.clone(),
+                        err_sender.clone(),
+                    ),
+                    "cannot spawn vsock ioregion handler"
+                ));
+            }
+            if let Some(rng) = &self.context.rng {
+                threads.push(try_with!(
+                    ioregion_handler_thread(
+                        self.context.clone(),
+                        rng.clone(),
+                        self.context.mmio_mgr.clone(),
+                        err_sender.clone(),
+                    ),
+                    "cannot spawn rng ioregion handler"
+                ));
+            }
+            for disk in &self.context.disks {
+                threads.push(try_with!(
+                    ioregion_handler_thread(
+                        self.context.clone(),
+                        disk.clone(),
+                        self.context.mmio_mgr.clone(),
+                        err_sender.clone(),
+                    ),
+                    "cannot spawn block ioregion handler"
+                ));
+            }
+            for pmem in &self.context.pmem {
+                threads.push(try_with!(
+                    ioregion_handler_thread(
+                        self.context.clone(),
+                        pmem.clone(),
+                        self.context.mmio_mgr.clone(),
+                        err_sender.clone(),
+                    ),
+                    "cannot spawn pmem ioregion handler"
+                ));
+            }
+            if let Some(pci) = &self.context.pci {
+                threads.push(try_with!(
+                    ioregion_handler_thread(
+                        self.context.clone(),
+                        pci.clone(),
+                        self.context.mmio_mgr.clone(),
+                        err_sender,
+                    ),
+                    "cannot spawn pci ioregion handler"
+                ));
+            }
         } else {
             threads.push(mmio_exit_handler_thread(
                 vm,
@@ -464,7 +615,7 @@ impl DeviceSet {
             )?);
         }
 
-        driver_notifier.wait()?;
+        driver_notifier.wait(stop)?;
         Ok((threads, driver_notifier))
     }
 }
@@ -0,0 +1,365 @@
+//! Storage behind a block device. Requests are checked against the size of the backend before
+//! they reach it, so offsets and lengths are always within the disk.
+//!
+//! `FileBackend` maps a local image into vmsh, which lets the executor copy between the disk and
+//! guest buffers without a bounce buffer. Other backends (i.e. `NbdBackend`) are accessed
+//! through `read_at` and `write_at`.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::num::NonZeroUsize;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+use std::sync::Arc;
+
+use libc::c_void;
+use log::warn;
+use nix::errno::Errno;
+use nix::fcntl::{fallocate, FallocateFlags};
+use nix::sys::mman::{mmap, msync, munmap, MapFlags, MsFlags, ProtFlags};
+use simple_error::{bail, try_with};
+use vmm_sys_util::file_traits::FileSync;
+use vmm_sys_util::write_zeroes::{PunchHole, WriteZeroesAt};
+
+use super::executor::Segment;
+use super::nbd::NbdBackend;
+use crate::result::Result;
+
+pub trait BlockBackend: Send + Sync {
+    /// Size of the disk in bytes.
+    fn size(&self) -> u64;
+
+    /// Whether the backend rejects writes, the device is read-only then.
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> io::Result<()>;
+
+    /// Makes all completed writes durable.
+    fn flush(&self) -> io::Result<()>;
+
+    /// Deallocates the range if possible, it reads back as zeros afterwards.
+    fn discard(&self, segment: &Segment) -> io::Result<()>;
+
+    /// Zeroes the range, deallocating it if `segment.unmap` is set.
+    fn write_zeroes(&self, segment: &Segment) -> io::Result<()>;
+
+    /// Start of the disk if it is mapped into vmsh.
+    fn mapping(&self) -> Option<*mut u8> {
+        None
+    }
+}
+
+/// Opens the backend for `path`: `nbd://` and `nbd+unix://` urls connect to an NBD server,
+/// everything else is a local file.
+pub fn open(path: &Path, read_only: bool, snapshot: bool) -> Result<Arc<dyn BlockBackend>> {
+    if let Some(url) = path.to_str().filter(|p| p.starts_with("nbd")) {
+        if let Some(addr) = super::nbd::parse_url(url)? {
+            if snapshot {
+                bail!("snapshot mode is not supported with nbd");
+            }
+            return Ok(Arc::new(NbdBackend::connect(&addr)?));
+        }
+    }
+    Ok(Arc::new(FileBackend::open(path, read_only, snapshot)?))
+}
+
+struct Mmap {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if let Err(e) = unsafe { munmap(self.ptr, self.len) } {
+            warn!("Failed to munmap block device: {}", e);
+        }
+    }
+}
+
+/// A disk image mapped into vmsh.
+pub struct FileBackend {
+    file: File,
+    /// None if the file is empty, i.e. `/dev/null`
+    mmap: Option<Mmap>,
+    /// Writes to a private mapping never reach the file.
+    shared: bool,
+}
+
+unsafe impl Send for FileBackend {}
+// the mapping is shared by the handlers of all queues
+unsafe impl Sync for FileBackend {}
+
+impl FileBackend {
+    /// Writes of the guest go to a private copy-on-write mapping in snapshot mode, which is
+    /// discarded once the backend is dropped.
+    pub fn open(path: &Path, read_only: bool, snapshot: bool) -> Result<FileBackend> {
+        let shared = !read_only && !snapshot;
+        let mut file = try_with!(
+            OpenOptions::new().read(true).write(shared).open(path),
+            "cannot open {}",
+            path.display()
+        );
+        let size = try_with!(
+            file.seek(SeekFrom::End(0)),
+            "cannot seek {}",
+            path.display()
+        );
+        let len = match NonZeroUsize::new(size as usize) {
+            Some(len) => len,
+            None => {
+                return Ok(FileBackend {
+                    file,
+                    mmap: None,
+                    shared,
+                })
+            }
+        };
+        let ptr = try_with!(
+            unsafe {
+                mmap(
+                    None,
+                    len,
+                    ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                    if shared {
+                        MapFlags::MAP_SHARED
+                    } else {
+                        MapFlags::MAP_PRIVATE
+                    },
+                    file.as_raw_fd(),
+                    0,
+                )
+            },
+            "cannot mmap {}",
+            path.display()
+        );
+        Ok(FileBackend {
+            file,
+            mmap: Some(Mmap {
+                ptr,
+                len: len.get(),
+            }),
+            shared,
+        })
+    }
+
+    /// Pointer to `len` bytes at `offset`, `None` for empty ranges of an empty file.
+    fn at(&self, offset: u64, len: usize) -> Option<*mut u8> {
+        match &self.mmap {
+            Some(mmap) if offset as usize + len <= mmap.len => {
+                Some(unsafe { (mmap.ptr as *mut u8).add(offset as usize) })
+            }
+            _ => None,
+        }
+    }
+
+    fn fill_zeroes(&self, segment: &Segment) {
+        if let Some(ptr) = self.at(segment.offset, segment.len as usize) {
+            unsafe { ptr::write_bytes(ptr, 0, segment.len as usize) };
+        }
+    }
+
+    // Unmapped ranges are punched out of the file, so they read back as zeros.
+    fn fallocate(&self, segment: &Segment) -> nix::Result<()> {
+        let mode = if segment.unmap {
+            FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE
+        } else {
+            FallocateFlags::FALLOC_FL_ZERO_RANGE | FallocateFlags::FALLOC_FL_KEEP_SIZE
+        };
+        fallocate(
+            self.file.as_raw_fd(),
+            mode,
+            segment.offset as libc::off_t,
+            segment.len as libc::off_t,
+        )
+    }
+}
+
+impl BlockBackend for FileBackend {
+    fn size(&self) -> u64 {
+        self.mmap.as_ref().map_or(0, |mmap| mmap.len as u64)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        if let Some(src) = self.at(offset, buf.len()) {
+            unsafe { ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len()) };
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        if let Some(dst) = self.at(offset, buf.len()) {
+            unsafe { ptr::copy_nonoverlapping(buf.as_ptr(), dst, buf.len()) };
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        // Writes only reach the page cache through the mapping, so flushing has to write back
+        // the mapping before the data of the file is synced to the disk.
+        if let Some(mmap) = &self.mmap {
+            unsafe { msync(mmap.ptr, mmap.len, MsFlags::MS_SYNC) }
+                .map_err(|e| io::Error::from_raw_os_error(e as i32))?;
+        }
+        self.file.sync_data()
+    }
+
+    fn discard(&self, segment: &Segment) -> io::Result<()> {
+        // the file must not be modified
+        if !self.shared {
+            return Ok(());
+        }
+        match self.fallocate(segment) {
+            // discarding is only a hint
+            Ok(()) | Err(Errno::EOPNOTSUPP) => Ok(()),
+            Err(e) => Err(io::Error::from_raw_os_error(e as i32)),
+        }
+    }
+
+    fn write_zeroes(&self, segment: &Segment) -> io::Result<()> {
+        if !self.shared {
+            // only zero our copy
+            self.fill_zeroes(segment);
+            return Ok(());
+        }
+        match self.fallocate(segment) {
+            Ok(()) => Ok(()),
+            Err(Errno::EOPNOTSUPP) => {
+                // the filesystem cannot zero ranges, write the zeros ourselves
+                self.fill_zeroes(segment);
+                Ok(())
+            }
+            Err(e) => Err(io::Error::from_raw_os_error(e as i32)),
+        }
+    }
+
+    fn mapping(&self) -> Option<*mut u8> {
+        self.mmap.as_ref().map(|mmap| mmap.ptr as *mut u8)
+    }
+}
+
+/// Gives the `StdIoBackend` of virtio-blk, which only executes the requests we do not handle
+/// ourselves (i.e. get id), access to a backend.
+pub struct BackendIo {
+    backend: Arc<dyn BlockBackend>,
+    pos: u64,
+}
+
+impl BackendIo {
+    pub fn new(backend: Arc<dyn BlockBackend>) -> BackendIo {
+        BackendIo { backend, pos: 0 }
+    }
+
+    fn left(&self, len: usize) -> usize {
+        (self.backend.size().saturating_sub(self.pos)).min(len as u64) as usize
+    }
+}
+
+impl Read for BackendIo {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.left(buf.len());
+        self.backend.read_at(self.pos, &mut buf[..len])?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for BackendIo {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.left(buf.len());
+        self.backend.write_at(self.pos, &buf[..len])?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.backend.flush()
+    }
+}
+
+// The file traits StdIoBackend asks for, it never discards or zeroes ranges itself though.
+impl FileSync for BackendIo {
+    fn fsync(&mut self) -> io::Result<()> {
+        self.backend.flush()
+    }
+}
+
+impl PunchHole for BackendIo {
+    fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        self.backend.discard(&Segment {
+            offset,
+            len: length,
+            unmap: true,
+        })
+    }
+}
+
+impl WriteZeroesAt for BackendIo {
+    fn write_zeroes_at(&mut self, offset: u64, length: usize) -> io::Result<usize> {
+        self.backend.write_zeroes(&Segment {
+            offset,
+            len: length as u64,
+            unmap: false,
+        })?;
+        Ok(length)
+    }
+}
+
+fn add_offset(pos: u64, off: i64) -> Option<u64> {
+    if off >= 0 {
+        pos.checked_add(off as u64)
+    } else {
+        pos.checked_sub(off.unsigned_abs())
+    }
+}
+
+impl Seek for BackendIo {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(off) => add_offset(self.backend.size(), off),
+            SeekFrom::Current(off) => add_offset(self.pos, off),
+        };
+        self.pos = pos.ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::*;
+
+    #[test]
+    fn file_backend() {
+        let tmp = TempFile::new().unwrap();
+        tmp.as_file().write_all(&[1u8; 4096]).unwrap();
+
+        let snapshot = FileBackend::open(tmp.as_path(), false, true).unwrap();
+        snapshot.write_at(8, &[2, 3]).unwrap();
+        snapshot
+            .write_zeroes(&Segment {
+                offset: 0,
+                len: 4,
+                unmap: true,
+            })
+            .unwrap();
+        let mut buf = [0u8; 12];
+        snapshot.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf, [0, 0, 0, 0, 1, 1, 1, 1, 2, 3, 1, 1]);
+        // the file is untouched
+        let backend = FileBackend::open(tmp.as_path(), false, false).unwrap();
+        backend.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf, [1; 12]);
+
+        let mut io = BackendIo::new(Arc::new(backend));
+        assert_eq!(io.seek(SeekFrom::End(0)).unwrap(), 4096);
+        io.seek(SeekFrom::Start(4094)).unwrap();
+        assert_eq!(io.read(&mut buf).unwrap(), 2);
+    }
+}
@@ -3,14 +3,12 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
 use nix::unistd::Pid;
-use simple_error::SimpleError;
 use std::borrow::{Borrow, BorrowMut};
-use std::fs::OpenOptions;
-use std::io::{Seek, SeekFrom};
 use std::ops::DerefMut;
 use std::path::PathBuf;
+use std::sync::atomic::AtomicU8;
 use std::sync::{Arc, Mutex};
-use virtio_device::{VirtioDevice, VirtioDeviceType};
+use virtio_device::VirtioDeviceType;
 
 use event_manager::{MutEventSubscriber, RemoteEndpoint, Result as EvmgrResult, SubscriberId};
 use virtio_blk::stdio_executor::StdIoBackend;
@@ -23,125 +21,171 @@ use vm_device::{DeviceMmio, MutDeviceMmio};
 use vm_memory::GuestMemoryMmap;
 use vmm_sys_util::eventfd::EventFd;
 
-use crate::devices::use_ioregionfd;
-use crate::devices::virtio::block::inorder_handler::Mmap;
+use crate::devices::virtio::block::backend::{self, BackendIo, BlockBackend};
+use crate::devices::virtio::block::executor::AsyncExecutor;
 use crate::devices::virtio::block::{
-    BLOCK_DEVICE_ID, SECTOR_SHIFT, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_RO,
+    BLOCK_DEVICE_ID, MAX_BLK_QUEUES, SECTOR_SHIFT, VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_FLUSH,
+    VIRTIO_BLK_F_MQ, VIRTIO_BLK_F_RO, VIRTIO_BLK_F_WRITE_ZEROES, VIRTIO_BLK_ID_BYTES,
 };
 use crate::devices::virtio::features::{
     VIRTIO_F_IN_ORDER, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_VERSION_1,
 };
-use crate::devices::virtio::{IrqAckHandler, MmioConfig, SingleFdSignalQueue, QUEUE_MAX_SIZE};
+use crate::devices::virtio::interrupt::DeviceInterrupt;
+use crate::devices::virtio::pci::{Transport, VirtioPciDevice};
+use crate::devices::virtio::{
+    reset_virtio_config, CommonArgs, IrqAckHandler, MmioConfig, SingleFdSignalQueue,
+};
 use crate::devices::MaybeIoRegionFd;
 use crate::kvm::hypervisor::{
-    ioevent::IoEvent, ioregionfd::IoRegionFd, userspaceioeventfd::UserspaceIoEventFd,
+    ioevent::IoEvent, ioregionfd::IoRegionFd, shared_ram::SharedRam,
+    userspaceioeventfd::UserspaceIoEventFd,
 };
+use crate::result::VmshError;
 
 use super::inorder_handler::InOrderQueueHandler;
 use super::queue_handler::QueueHandler;
+use super::worker::QueueWorker;
 use super::{build_config_space, BlockArgs, Error, Result};
 
-// This Block device can only use the MMIO transport for now, but we plan to reuse large parts of
-// the functionality when we implement virtio PCI as well, for example by having a base generic
-// type, and then separate concrete instantiations for `MmioConfig` and `PciConfig`.
+// The register layout used to access this Block device depends on `mmio_cfg.transport`, both
+// virtio-mmio and virtio-pci are served from the same trapped memory range.
 pub struct Block {
     virtio_cfg: VirtioConfig<Queue>,
     pub mmio_cfg: MmioConfig,
     endpoint: RemoteEndpoint<Arc<Mutex<dyn MutEventSubscriber + Send>>>,
     pub irq_ack_handler: Arc<Mutex<IrqAckHandler>>,
-    irqfd: Arc<EventFd>,
+    /// one irqfd per queue, all of them are connected to the same gsi
+    irqfds: Vec<Arc<EventFd>>,
     pub ioregionfd: Option<IoRegionFd>,
-    ioeventfd: Option<IoEvent>,
+    /// one ioeventfd per queue, shared with the queue handlers. They stay registered when the
+    /// driver resets the device.
+    ioeventfds: Vec<Arc<IoEvent>>,
     pub uioefd: UserspaceIoEventFd,
-    /// only used when ioregionfd != None
-    file_path: PathBuf,
+    queue_size: u16,
+    backend: Arc<dyn BlockBackend>,
     read_only: bool,
+    serial: [u8; VIRTIO_BLK_ID_BYTES],
     sub_id: Option<SubscriberId>,
+    /// only used with more than one queue
+    workers: Vec<QueueWorker>,
     guest_memory: Arc<GuestMemoryMmap>,
+    ram: Arc<SharedRam>,
     pid: Pid,
 
-    // Before resetting we return the handler to the mmio thread for cleanup
-    #[allow(dead_code)]
-    handler: Option<Arc<Mutex<dyn MutEventSubscriber + Send>>>,
     // We'll prob need to remember this for state save/restore unless we pass the info from
     // the outside.
     _root_device: bool,
 }
 
-impl Block {
-    pub fn new<B>(mut args: BlockArgs<B>) -> Result<Arc<Mutex<Self>>>
-    where
-        // We're using this (more convoluted) bound so we can pass both references and smart
-        // pointers such as mutex guards here.
-        B: DerefMut,
-        B::Target: MmioManager<D = Arc<dyn DeviceMmio + Send + Sync>>,
-    {
-        // The queue handling logic for this device uses the buffers in order, so we enable the
-        // corresponding feature as well.
-        let mut device_features =
-            1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_F_IN_ORDER | 1 << VIRTIO_F_RING_EVENT_IDX;
+/// Resources of a block device that are registered with the hypervisor in advance.
+///
+/// Once the devices are started, ptrace control over the hypervisor belongs to the mmio exit
+/// handler and no more irqfds or ioeventfds can be registered. Block devices added at runtime
+/// are created from a slot that was reserved at attach time instead.
+pub struct BlockSlot {
+    pub mmio_cfg: MmioConfig,
+    num_queues: u16,
+    queue_size: u16,
+    endpoint: RemoteEndpoint<Arc<Mutex<dyn MutEventSubscriber + Send>>>,
+    interrupt_status: Arc<AtomicU8>,
+    pub irq_ack_handler: Arc<Mutex<IrqAckHandler>>,
+    irqfds: Vec<Arc<EventFd>>,
+    ioregionfd: Option<IoRegionFd>,
+    ioeventfds: Vec<Arc<IoEvent>>,
+    uioefd: UserspaceIoEventFd,
+    guest_memory: Arc<GuestMemoryMmap>,
+    ram: Arc<SharedRam>,
+    pid: Pid,
+}
 
-        if args.read_only {
-            device_features |= 1 << VIRTIO_BLK_F_RO;
+impl BlockSlot {
+    pub fn new<B>(common: &mut CommonArgs<B>, num_queues: u16) -> Result<BlockSlot> {
+        if num_queues == 0 || num_queues > MAX_BLK_QUEUES {
+            return Err(Error::Simple(VmshError::from(format!(
+                "block device supports 1 to {} queues, got {}",
+                MAX_BLK_QUEUES, num_queues
+            ))));
         }
 
-        if args.advertise_flush {
-            device_features |= 1 << VIRTIO_BLK_F_FLUSH;
+        // Used to send notifications to the driver.
+        // only the first irqfd is resampled, all queues share the interrupt
+        let DeviceInterrupt { irqfd, resamplefd } = common
+            .interrupts
+            .connect(&common.mmio_cfg)
+            .map_err(Error::Simple)?;
+        let mut irqfds = vec![irqfd];
+        for _ in 1..num_queues {
+            irqfds.push(Arc::new(
+                common
+                    .interrupts
+                    .irqfd(&common.mmio_cfg)
+                    .map_err(Error::Simple)?,
+            ));
         }
 
-        // A block device has a single queue.
-        let mem = args.common.mem.clone();
-        let queues = vec![Queue::new(QUEUE_MAX_SIZE).map_err(Error::QueueCreation)?];
-        let config_space = build_config_space(&args.file_path)?;
-        let virtio_cfg = VirtioConfig::new(device_features, queues, config_space);
+        let mmio_cfg = common.mmio_cfg;
 
-        // Used to send notifications to the driver.
-        //let irqfd = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFd)?;
-        log::debug!("register irqfd on gsi {}", args.common.mmio_cfg.gsi);
-        let irqfd = Arc::new(
-            args.common
-                .vmm
-                .irqfd(args.common.mmio_cfg.gsi)
+        let interrupt_status = Arc::new(AtomicU8::new(0));
+        let irq_ack_handler = Arc::new(Mutex::new(
+            IrqAckHandler::new(interrupt_status.clone(), irqfds[0].clone(), resamplefd)
                 .map_err(Error::Simple)?,
-        );
-
-        let mmio_cfg = args.common.mmio_cfg;
-
-        let irq_ack_handler = Arc::new(Mutex::new(IrqAckHandler::new(
-            virtio_cfg.interrupt_status.clone(),
-            irqfd.clone(),
-        )));
+        ));
 
         let mut ioregionfd = None;
-        if use_ioregionfd() {
+        if common.vmm.use_ioregionfd() {
             ioregionfd = Some(
-                args.common
+                common
                     .vmm
                     .ioregionfd(mmio_cfg.range.base().0, mmio_cfg.range.size() as usize)
                     .map_err(Error::Simple)?,
             );
         }
         let mut uioefd = UserspaceIoEventFd::default();
-        let ioeventfd = IoEvent::register(&args.common.vmm, &mut uioefd, &mmio_cfg, 0)
-            .map_err(Error::Simple)?;
+        let ioeventfds = (0..num_queues)
+            .map(|idx| {
+                IoEvent::register(&common.vmm, &mut uioefd, &mmio_cfg, idx as u64)
+                    .map(Arc::new)
+                    .map_err(Error::Simple)
+            })
+            .collect::<Result<Vec<_>>>()?;
 
-        let block = Arc::new(Mutex::new(Block {
-            virtio_cfg,
+        Ok(BlockSlot {
             mmio_cfg,
-            endpoint: args.common.event_mgr.remote_endpoint(),
+            num_queues,
+            queue_size: common.queue_size,
+            endpoint: common.event_mgr.remote_endpoint(),
+            interrupt_status,
             irq_ack_handler,
-            irqfd,
+            irqfds,
             ioregionfd,
-            ioeventfd: Some(ioeventfd),
+            ioeventfds,
             uioefd,
-            file_path: args.file_path,
-            read_only: args.read_only,
-            pid: args.common.vmm.pid,
-            sub_id: None,
-            handler: None,
-            _root_device: args.root_device,
-            guest_memory: mem,
-        }));
+            guest_memory: common.mem.clone(),
+            ram: common.ram.clone(),
+            pid: common.vmm.pid,
+        })
+    }
+}
+
+impl Block {
+    pub fn new<B>(mut args: BlockArgs<B>) -> Result<Arc<Mutex<Self>>>
+    where
+        // We're using this (more convoluted) bound so we can pass both references and smart
+        // pointers such as mutex guards here.
+        B: DerefMut,
+        B::Target: MmioManager<D = Arc<dyn DeviceMmio + Send + Sync>>,
+    {
+        let slot = BlockSlot::new(&mut args.common, args.num_queues)?;
+        let mmio_cfg = slot.mmio_cfg;
+        let block = Arc::new(Mutex::new(Block::from_slot(
+            slot,
+            args.file_path,
+            args.read_only,
+            args.snapshot,
+            args.root_device,
+            args.advertise_flush,
+            &args.serial,
+        )?));
 
         // Register the device on the MMIO bus.
         args.common
@@ -152,6 +196,79 @@ impl Block {
         Ok(block)
     }
 
+    /// Creates a block device from resources reserved in advance. The caller has to register
+    /// the device on the MMIO bus.
+    pub fn from_slot(
+        slot: BlockSlot,
+        file_path: PathBuf,
+        read_only: bool,
+        snapshot: bool,
+        root_device: bool,
+        advertise_flush: bool,
+        serial: &str,
+    ) -> Result<Block> {
+        let backend = backend::open(&file_path, read_only, snapshot).map_err(Error::Simple)?;
+        let read_only = read_only || backend.read_only();
+
+        // The queue handling logic for this device uses the buffers in order, so we enable the
+        // corresponding feature as well.
+        let mut device_features =
+            1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_F_IN_ORDER | 1 << VIRTIO_F_RING_EVENT_IDX;
+
+        if read_only {
+            device_features |= 1 << VIRTIO_BLK_F_RO;
+        } else {
+            // lets the guest trim the backing file
+            device_features |= 1 << VIRTIO_BLK_F_DISCARD | 1 << VIRTIO_BLK_F_WRITE_ZEROES;
+        }
+
+        if advertise_flush {
+            device_features |= 1 << VIRTIO_BLK_F_FLUSH;
+        }
+
+        if slot.num_queues > 1 {
+            device_features |= 1 << VIRTIO_BLK_F_MQ;
+        }
+
+        if serial.len() > VIRTIO_BLK_ID_BYTES {
+            return Err(Error::Simple(VmshError::from(format!(
+                "block device serial {} exceeds {} bytes",
+                serial, VIRTIO_BLK_ID_BYTES
+            ))));
+        }
+        let mut serial_bytes = [0u8; VIRTIO_BLK_ID_BYTES];
+        serial_bytes[..serial.len()].copy_from_slice(serial.as_bytes());
+
+        let queues = (0..slot.num_queues)
+            .map(|_| Queue::new(slot.queue_size).map_err(Error::QueueCreation))
+            .collect::<Result<Vec<_>>>()?;
+        let config_space = build_config_space(backend.size(), slot.num_queues, !read_only)?;
+        let mut virtio_cfg = VirtioConfig::new(device_features, queues, config_space);
+        // the interrupt acknowledgement handler of the slot watches this status
+        virtio_cfg.interrupt_status = slot.interrupt_status;
+
+        Ok(Block {
+            virtio_cfg,
+            mmio_cfg: slot.mmio_cfg,
+            endpoint: slot.endpoint,
+            irq_ack_handler: slot.irq_ack_handler,
+            irqfds: slot.irqfds,
+            ioregionfd: slot.ioregionfd,
+            ioeventfds: slot.ioeventfds,
+            uioefd: slot.uioefd,
+            queue_size: slot.queue_size,
+            backend,
+            read_only,
+            serial: serial_bytes,
+            pid: slot.pid,
+            sub_id: None,
+            workers: vec![],
+            _root_device: root_device,
+            guest_memory: slot.guest_memory,
+            ram: slot.ram,
+        })
+    }
+
     fn _activate(&mut self) -> Result<()> {
         if self.virtio_cfg.device_activated {
             return Err(Error::AlreadyActivated);
@@ -162,23 +279,7 @@ impl Block {
             return Err(Error::BadFeatures(self.virtio_cfg.driver_features));
         }
 
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(!self.read_only)
-            .open(&self.file_path)
-            .map_err(Error::OpenFile)?;
-
-        let disk_size = file.seek(SeekFrom::End(0)).map_err(Error::Seek)?;
-
-        let mmap = match Mmap::new(&file, disk_size as usize) {
-            Ok(m) => m,
-            Err(e) => {
-                return Err(Error::Simple(SimpleError::new(format!(
-                    "cannot mmap disk: {:?}",
-                    e
-                ))))
-            }
-        };
+        let disk_size = self.backend.size();
 
         let mut features = self.virtio_cfg.driver_features;
         if self.read_only {
@@ -187,68 +288,98 @@ impl Block {
             features |= 1 << VIRTIO_BLK_F_RO;
         }
 
-        // TODO: Create the backend earlier (as part of `Block::new`)?
-        let disk = StdIoBackend::new(file, features)
-            .map_err(Error::Backend)?
-            .with_device_id(*b"vmsh0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0");
-
-        let driver_notify = SingleFdSignalQueue {
-            irqfd: self.irqfd.clone(),
-            interrupt_status: self.virtio_cfg.interrupt_status.clone(),
-            ack_handler: self.irq_ack_handler.clone(),
+        // Without VIRTIO_BLK_F_MQ the driver only uses the first queue.
+        let num_queues = if features & (1 << VIRTIO_BLK_F_MQ) != 0 {
+            self.virtio_cfg.queues.len()
+        } else {
+            1
         };
+        if self.ioeventfds.len() < num_queues {
+            return Err(Error::Simple(VmshError::from("ioeventfds not set")));
+        }
+        let queues = self.virtio_cfg.queues.drain(..).collect::<Vec<_>>();
+
+        let mut handlers = vec![];
+        for (idx, (queue, ioeventfd)) in queues
+            .into_iter()
+            .zip(self.ioeventfds.iter().cloned())
+            .take(num_queues)
+            .enumerate()
+        {
+            let executor = AsyncExecutor::new(
+                idx as u16,
+                Arc::clone(&self.backend),
+                Arc::clone(&self.ram),
+                self.pid,
+            )?;
+            let disk = StdIoBackend::new(BackendIo::new(Arc::clone(&self.backend)), features)
+                .map_err(Error::Backend)?
+                .with_device_id(self.serial);
+
+            let driver_notify = SingleFdSignalQueue {
+                irqfd: self.irqfds[idx].clone(),
+                interrupt_status: self.virtio_cfg.interrupt_status.clone(),
+                ack_handler: self.irq_ack_handler.clone(),
+            };
+
+            let inner = InOrderQueueHandler::new(
+                driver_notify,
+                queue,
+                disk,
+                disk_size >> SECTOR_SHIFT,
+                executor,
+                Arc::clone(&self.guest_memory),
+            );
+            handlers.push(QueueHandler { inner, ioeventfd });
+        }
 
-        let queue = self.virtio_cfg.queues.remove(0);
-        let inner = InOrderQueueHandler {
-            pid: self.pid,
-            driver_notify,
-            queue,
-            disk,
-            sectors: disk_size >> SECTOR_SHIFT,
-            mmap,
-            mem: Arc::clone(&self.guest_memory),
-            remote_iovs: vec![],
-        };
-        let handler = Arc::new(Mutex::new(QueueHandler {
-            inner,
-            ioeventfd: match self.ioeventfd.take() {
-                Some(fd) => fd,
-                None => return Err(Error::Simple(SimpleError::new("ioeventfd not set"))),
-            },
-        }));
-
-        // Register the queue handler with the `EventManager`. We record the `sub_id`
-        // (and/or keep a handler clone) to remove the subscriber when resetting the device
-        let sub_id = self
-            .endpoint
-            .call_blocking(move |mgr| -> EvmgrResult<SubscriberId> {
-                Ok(mgr.add_subscriber(handler))
-            })
-            .map_err(|e| {
-                log::warn!("{}", e);
-                Error::Endpoint(e)
-            })?;
-        self.sub_id = Some(sub_id);
+        if handlers.len() == 1 {
+            let handler = Arc::new(Mutex::new(handlers.remove(0)));
+            // Register the queue handler with the `EventManager`. We record the `sub_id`
+            // (and/or keep a handler clone) to remove the subscriber when resetting the device
+            let sub_id = self
+                .endpoint
+                .call_blocking(move |mgr| -> EvmgrResult<SubscriberId> {
+                    Ok(mgr.add_subscriber(handler))
+                })
+                .map_err(|e| {
+                    log::warn!("{}", e);
+                    Error::Endpoint(e)
+                })?;
+            self.sub_id = Some(sub_id);
+        } else {
+            for (idx, handler) in handlers.into_iter().enumerate() {
+                self.workers.push(QueueWorker::spawn(idx as u16, handler)?);
+            }
+        }
 
         log::debug!("activating device: ok");
         self.virtio_cfg.device_activated = true;
 
         Ok(())
     }
+
     fn _reset(&mut self) -> Result<()> {
-        // we remove the handler here, since we need to free up the ioeventfd resources
-        // in the mmio thread rather the eventmanager thread.
+        // Dropping a queue handler waits until its io threads executed all submitted
+        // requests, so they no longer write to buffers the driver may reuse after the reset.
+        // The ioeventfds stay registered with the hypervisor for the next activation.
         if let Some(sub_id) = self.sub_id.take() {
-            let handler = self
-                .endpoint
+            self.endpoint
                 .call_blocking(move |mgr| mgr.remove_subscriber(sub_id))
                 .map_err(|e| {
                     log::warn!("{}", e);
                     Error::Endpoint(e)
                 })?;
-            self.handler = Some(handler);
         }
-        Ok(())
+        for mut worker in self.workers.drain(..) {
+            worker.stop();
+        }
+        // notifications of the old queues must not be processed by the next handlers
+        for ioeventfd in self.ioeventfds.iter() {
+            let _ = ioeventfd.read();
+        }
+        reset_virtio_config(&mut self.virtio_cfg, self.ioeventfds.len(), self.queue_size)
+            .map_err(Error::QueueCreation)
     }
 }
 
@@ -292,29 +423,38 @@ impl VirtioDeviceActions for Block {
     }
 
     fn reset(&mut self) -> Result<()> {
-        self.set_device_status(0);
-        self._reset()?;
-        Ok(())
+        let ret = self._reset();
+        if let Err(ref e) = ret {
+            log::warn!("failed to reset block device: {:?}", e);
+        }
+        ret
     }
 }
 
 impl VirtioQueueNotifiable for Block {
     fn queue_notify(&mut self, val: u32) {
-        if use_ioregionfd() {
-            self.uioefd.queue_notify(val);
-            log::trace!("queue_notify {}", val);
-        }
+        // with ioeventfds in KVM, only notifications replayed by vmsh end up here
+        self.uioefd.queue_notify(val);
+        log::trace!("queue_notify {}", val);
     }
 }
 
 impl VirtioMmioDevice for Block {}
 
+impl VirtioPciDevice for Block {}
+
 impl MutDeviceMmio for Block {
     fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
-        self.read(offset, data);
+        match self.mmio_cfg.transport {
+            Transport::Mmio => self.read(offset, data),
+            Transport::Pci => self.pci_read(offset, data),
+        }
     }
 
     fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
-        self.write(offset, data);
+        match self.mmio_cfg.transport {
+            Transport::Mmio => self.write(offset, data),
+            Transport::Pci => self.pci_write(offset, data),
+        }
     }
 }
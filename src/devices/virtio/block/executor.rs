@@ -0,0 +1,283 @@
+use std::io::{self, IoSlice, IoSliceMut};
+use std::sync::mpsc::{channel, Receiver, Sender, TryIter};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::{ptr, slice};
+
+use log::warn;
+use nix::sys::uio::{process_vm_readv, process_vm_writev, RemoteIoVec};
+use nix::unistd::Pid;
+use virtio_blk::stdio_executor;
+use vm_memory::GuestMemoryError;
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+use super::backend::BlockBackend;
+use super::{Error, Result};
+use crate::kvm::hypervisor::shared_ram::SharedRam;
+
+/// Number of threads per queue that copy data between the disk and the guest.
+pub const BLK_IO_THREADS: usize = 4;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Op {
+    Read,
+    Write,
+    Flush,
+    Discard,
+    WriteZeroes,
+}
+
+/// A range of the disk in bytes that is discarded or zeroed.
+pub struct Segment {
+    pub offset: u64,
+    pub len: u64,
+    /// whether the blocks may be deallocated
+    pub unmap: bool,
+}
+
+/// A request whose data is transferred by the io threads. The caller has checked that
+/// `offset` and `len` are within the disk.
+pub struct Job {
+    pub seq: u64,
+    pub op: Op,
+    pub offset: usize,
+    pub len: usize,
+    /// guest buffers as addresses in the hypervisor process
+    pub iovs: Vec<RemoteIoVec>,
+    /// only used by discard and write zeroes
+    pub segments: Vec<Segment>,
+}
+
+pub struct Completion {
+    pub seq: u64,
+    /// number of bytes written to guest memory
+    pub result: stdio_executor::Result<u32>,
+}
+
+fn io_error(op: Op, e: io::Error) -> stdio_executor::Error {
+    match op {
+        Op::Read => stdio_executor::Error::Read(GuestMemoryError::IOError(e), 0),
+        Op::Write | Op::Discard | Op::WriteZeroes => {
+            stdio_executor::Error::Write(GuestMemoryError::IOError(e))
+        }
+        Op::Flush => stdio_executor::Error::Flush(e),
+    }
+}
+
+struct Disk {
+    backend: Arc<dyn BlockBackend>,
+    /// guest buffers that are mapped here are copied directly instead of with process_vm_*
+    ram: Arc<SharedRam>,
+    pid: Pid,
+}
+
+impl Disk {
+    /// Copies between the mapped disk and guest buffers mapped into vmsh. Returns `None` if not
+    /// all buffers of the job are mapped.
+    fn copy_shared(&self, disk: *mut u8, job: &Job) -> Option<u32> {
+        let iovs = self.ram.local_iovs(&job.iovs)?;
+        let mut disk = unsafe { disk.add(job.offset) };
+        let mut left = job.len;
+        for (ptr, len) in iovs {
+            let len = len.min(left);
+            unsafe {
+                match job.op {
+                    Op::Read => ptr::copy_nonoverlapping(disk, ptr, len),
+                    _ => ptr::copy_nonoverlapping(ptr, disk, len),
+                }
+                disk = disk.add(len);
+            }
+            left -= len;
+        }
+        match job.op {
+            Op::Read => Some((job.len - left) as u32),
+            // nothing is written to guest memory
+            _ => Some(0),
+        }
+    }
+
+    /// Copies between the mapped disk and the guest buffers in the hypervisor.
+    fn copy_mapped(&self, disk: *mut u8, job: &Job) -> io::Result<u32> {
+        let res = match job.op {
+            Op::Read => {
+                let local_iovs = [IoSlice::new(unsafe {
+                    slice::from_raw_parts(disk.add(job.offset) as *const u8, job.len)
+                })];
+                process_vm_writev(self.pid, &local_iovs, &job.iovs).map(|n| n as u32)
+            }
+            _ => {
+                let mut local_iovs = [IoSliceMut::new(unsafe {
+                    slice::from_raw_parts_mut(disk.add(job.offset), job.len)
+                })];
+                // nothing is written to guest memory
+                process_vm_readv(self.pid, &mut local_iovs, &job.iovs).map(|_| 0)
+            }
+        };
+        res.map_err(|e| io::Error::from_raw_os_error(e as i32))
+    }
+
+    /// Copies through a bounce buffer for backends that are not mapped.
+    fn copy_buffered(&self, job: &Job) -> io::Result<u32> {
+        let guest_error = |e| io::Error::new(io::ErrorKind::Other, format!("{}", e));
+        let mut buf = vec![0u8; job.len];
+        match job.op {
+            Op::Read => {
+                self.backend.read_at(job.offset as u64, &mut buf)?;
+                let mut writes = Vec::with_capacity(job.iovs.len());
+                let mut rest = &buf[..];
+                for iov in &job.iovs {
+                    let (head, tail) = rest.split_at(iov.len.min(rest.len()));
+                    writes.push((iov.base, head));
+                    rest = tail;
+                }
+                self.ram
+                    .write_vectored(self.pid, &writes)
+                    .map_err(guest_error)?;
+                Ok((job.len - rest.len()) as u32)
+            }
+            _ => {
+                let mut reads = Vec::with_capacity(job.iovs.len());
+                let mut rest = &mut buf[..];
+                for iov in &job.iovs {
+                    let (head, tail) = rest.split_at_mut(iov.len.min(rest.len()));
+                    reads.push((iov.base, head));
+                    rest = tail;
+                }
+                self.ram
+                    .read_vectored(self.pid, &mut reads)
+                    .map_err(guest_error)?;
+                self.backend.write_at(job.offset as u64, &buf)?;
+                // nothing is written to guest memory
+                Ok(0)
+            }
+        }
+    }
+
+    fn execute(&self, job: &Job) -> stdio_executor::Result<u32> {
+        let res = match job.op {
+            Op::Read | Op::Write => match self.backend.mapping() {
+                Some(disk) => match self.copy_shared(disk, job) {
+                    Some(len) => Ok(len),
+                    None => self.copy_mapped(disk, job),
+                },
+                None => self.copy_buffered(job),
+            },
+            Op::Flush => self.backend.flush().map(|_| 0),
+            Op::Discard | Op::WriteZeroes => job
+                .segments
+                .iter()
+                .try_for_each(|segment| match job.op {
+                    Op::Discard => self.backend.discard(segment),
+                    _ => self.backend.write_zeroes(segment),
+                })
+                .map(|_| 0),
+        };
+        res.map_err(|e| io_error(job.op, e))
+    }
+}
+
+fn io_thread(
+    disk: &Disk,
+    jobs: &Mutex<Receiver<Job>>,
+    completions: &Sender<Completion>,
+    completion_fd: &EventFd,
+) {
+    loop {
+        let job = match jobs.lock() {
+            Ok(jobs) => jobs.recv(),
+            Err(_) => return,
+        };
+        // the executor was dropped
+        let job = match job {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        let completion = Completion {
+            seq: job.seq,
+            result: disk.execute(&job),
+        };
+        if completions.send(completion).is_err() {
+            return;
+        }
+        if let Err(e) = completion_fd.write(1) {
+            warn!("cannot signal block request completion: {}", e);
+        }
+    }
+}
+
+/// Executes reads, writes, flushes and discards of a block queue in a pool of threads, so that
+/// large requests do not stall the event loop that serves the queue notifications. Finished
+/// requests are signaled through `completion_fd`, not necessarily in the order they were
+/// submitted.
+pub struct AsyncExecutor {
+    jobs: Option<Sender<Job>>,
+    completions: Receiver<Completion>,
+    pub completion_fd: Arc<EventFd>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl AsyncExecutor {
+    pub fn new(
+        queue_idx: u16,
+        backend: Arc<dyn BlockBackend>,
+        ram: Arc<SharedRam>,
+        pid: Pid,
+    ) -> Result<AsyncExecutor> {
+        let completion_fd = Arc::new(EventFd::new(EFD_NONBLOCK).map_err(Error::EventFd)?);
+        let disk = Arc::new(Disk { backend, ram, pid });
+        let (job_sender, job_receiver) = channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let (completion_sender, completions) = channel();
+
+        let mut executor = AsyncExecutor {
+            jobs: Some(job_sender),
+            completions,
+            completion_fd,
+            threads: Vec::with_capacity(BLK_IO_THREADS),
+        };
+        for i in 0..BLK_IO_THREADS {
+            let disk = Arc::clone(&disk);
+            let jobs = Arc::clone(&job_receiver);
+            let completions = completion_sender.clone();
+            let completion_fd = Arc::clone(&executor.completion_fd);
+            let thread = thread::Builder::new()
+                .name(format!("blk-io-{}-{}", queue_idx, i))
+                .spawn(move || io_thread(&disk, &jobs, &completions, &completion_fd))
+                .map_err(Error::Thread)?;
+            executor.threads.push(thread);
+        }
+        Ok(executor)
+    }
+
+    pub fn submit(&self, job: Job) -> stdio_executor::Result<()> {
+        let op = job.op;
+        let sent = match &self.jobs {
+            Some(jobs) => jobs.send(job).is_ok(),
+            None => false,
+        };
+        if !sent {
+            return Err(io_error(
+                op,
+                io::Error::new(io::ErrorKind::BrokenPipe, "block io threads stopped"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Requests finished since the last call.
+    pub fn completions(&self) -> TryIter<'_, Completion> {
+        self.completions.try_iter()
+    }
+}
+
+impl Drop for AsyncExecutor {
+    fn drop(&mut self) {
+        // closing the channel stops the threads once they finished their current request
+        self.jobs.take();
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() {
+                warn!("block io thread panicked");
+            }
+        }
+    }
+}
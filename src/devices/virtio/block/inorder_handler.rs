@@ -2,28 +2,24 @@
 // Author of further modifications: Peter Okelmann
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
-use std::fs::File;
-use std::io::{IoSlice, IoSliceMut};
-use std::num::NonZeroUsize;
+use std::collections::VecDeque;
+use std::result;
 use std::sync::Arc;
-use std::{io, result, slice};
 
-use libc::c_void;
 use log::warn;
-use nix::sys::mman::{mmap, msync, munmap, MapFlags, MsFlags, ProtFlags};
-use nix::sys::uio::{process_vm_readv, process_vm_writev, RemoteIoVec};
-use nix::unistd::Pid;
-use simple_error::{require_with, try_with};
-use std::os::unix::io::AsRawFd;
+use nix::sys::uio::RemoteIoVec;
 use virtio_blk::defs::{SECTOR_SHIFT, SECTOR_SIZE};
 use virtio_blk::request::{Request, RequestType};
 use virtio_blk::stdio_executor::{self, StdIoBackend};
 use virtio_queue::{DescriptorChain, Queue, QueueOwnedT, QueueT};
 use vm_memory::GuestMemoryMmap;
-use vm_memory::{self, Bytes, GuestAddressSpace, GuestMemory, GuestMemoryError};
+use vm_memory::{self, Address, Bytes, GuestAddress, GuestAddressSpace, GuestMemory};
 
+use super::backend::BackendIo;
+use super::executor::{AsyncExecutor, Job, Op, Segment};
+use super::MAX_DISCARD_SEGMENTS;
 use crate::devices::virtio::SignalUsedQueue;
-use crate::result::Result;
+use crate::metrics::{self, METRICS};
 
 #[derive(Debug)]
 pub enum Error {
@@ -43,66 +39,84 @@ impl From<virtio_queue::Error> for Error {
     }
 }
 
-pub struct Mmap {
-    ptr: *mut c_void,
-    len: usize,
+// Request types as defined by the standard, reported for unsupported flags.
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+
+// Size of `struct virtio_blk_discard_write_zeroes { le64 sector; le32 num_sectors; le32 flags; }`
+const SEGMENT_SIZE: u64 = 16;
+const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 1;
+
+// A descriptor chain taken from the queue that was not returned to the driver yet.
+struct Inflight {
+    head_index: u16,
+    /// None if the request could not be parsed
+    status_addr: Option<GuestAddress>,
+    /// status and used length, once the request is done
+    done: Option<(u8, u32)>,
 }
 
-unsafe impl Send for Mmap {}
-
-impl Mmap {
-    pub fn new(file: &File, len: usize) -> Result<Mmap> {
-        let len = require_with!(NonZeroUsize::new(len), "lenght is zero");
-        let ptr = unsafe {
-            try_with!(
-                mmap(
-                    None,
-                    len,
-                    ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-                    MapFlags::MAP_SHARED,
-                    file.as_raw_fd(),
-                    0,
-                ),
-                "mmap failed"
-            )
-        };
-        Ok(Mmap {
-            ptr,
-            len: len.get(),
-        })
-    }
-}
-
-impl Drop for Mmap {
-    fn drop(&mut self) {
-        if let Err(e) = unsafe { munmap(self.ptr, self.len) } {
-            warn!("Failed to munmap block device: {}", e);
+fn request_status(result: stdio_executor::Result<u32>) -> (u8, u32) {
+    match result {
+        // TODO: Using `saturating_add` until we consume the recent changes
+        // proposed for the executor upstream.
+        // VIRTIO_BLK_S_OK defined as 0 in the standard.
+        Ok(len) => (0, len.saturating_add(1)),
+        Err(e) => {
+            warn!("failed to execute block request: {:?}", e);
+            // TODO: add `status` or similar method to executor error.
+            if let stdio_executor::Error::Unsupported(_) = e {
+                // UNSUPP
+                (2, 1)
+            } else {
+                // IOERR
+                (1, 1)
+            }
         }
     }
 }
 
 // This object is used to process the queue of a block device without making any assumptions
-// about the notification mechanism. We're using a specific backend for now (the `StdIoBackend`
-// object), but the aim is to have a way of working with generic backends and turn this into
-// a more flexible building block. The name comes from processing and returning descriptor
-// chains back to the device in the same order they are received.
+// about the notification mechanism. Reads, writes and flushes are executed asynchronously by
+// the `AsyncExecutor`, every other request by the `StdIoBackend`. The name comes from returning
+// descriptor chains back to the device in the same order they are received, even though
+// requests may finish out of order.
 pub struct InOrderQueueHandler<S: SignalUsedQueue> {
     pub driver_notify: S,
     pub queue: Queue,
-    pub disk: StdIoBackend<File>,
+    pub disk: StdIoBackend<BackendIo>,
     pub sectors: u64,
-    pub mmap: Mmap,
-    //pub guest_memory: Arc<Mutex<Option<M>>>,
-    pub pid: Pid,
-
-    // we have those here to safe reallocations across requests
-    pub remote_iovs: Vec<RemoteIoVec>,
+    pub executor: AsyncExecutor,
     pub mem: Arc<GuestMemoryMmap>,
+
+    /// sequence number of the first request in `inflight`
+    first_seq: u64,
+    inflight: VecDeque<Inflight>,
 }
 
 unsafe impl<S: SignalUsedQueue> Send for InOrderQueueHandler<S> {}
 
 impl<S: SignalUsedQueue> InOrderQueueHandler<S> {
+    pub fn new(
+        driver_notify: S,
+        queue: Queue,
+        disk: StdIoBackend<BackendIo>,
+        sectors: u64,
+        executor: AsyncExecutor,
+        mem: Arc<GuestMemoryMmap>,
+    ) -> Self {
+        InOrderQueueHandler {
+            driver_notify,
+            queue,
+            disk,
+            sectors,
+            executor,
+            mem,
+            first_seq: 0,
+            inflight: VecDeque::new(),
+        }
+    }
+
     fn check_access(&self, mut sectors_count: u64, sector: u64) -> stdio_executor::Result<()> {
         sectors_count = sectors_count
             .checked_add(sector)
@@ -113,9 +127,8 @@ impl<S: SignalUsedQueue> InOrderQueueHandler<S> {
         Ok(())
     }
 
-    fn prepare_iovs(&mut self, request: &Request) -> stdio_executor::Result<()> {
-        self.remote_iovs.clear();
-        self.remote_iovs.reserve(request.data().len());
+    fn prepare_iovs(&self, request: &Request) -> stdio_executor::Result<Vec<RemoteIoVec>> {
+        let mut remote_iovs = Vec::with_capacity(request.data().len());
         for (data_addr, data_len) in request.data() {
             let hv_addr = match self.mem.memory().get_host_address(*data_addr) {
                 // TODO length check
@@ -125,25 +138,83 @@ impl<S: SignalUsedQueue> InOrderQueueHandler<S> {
                 }
             };
 
-            self.remote_iovs.push(RemoteIoVec {
+            remote_iovs.push(RemoteIoVec {
                 base: hv_addr as usize,
                 len: *data_len as usize,
             });
         }
 
-        Ok(())
+        Ok(remote_iovs)
     }
 
-    fn execute(&mut self, mem: &GuestMemoryMmap, request: &Request) -> stdio_executor::Result<u32> {
+    fn parse_segments(
+        &self,
+        mem: &GuestMemoryMmap,
+        request: &Request,
+    ) -> stdio_executor::Result<Vec<Segment>> {
+        let discard = request.request_type() == RequestType::Discard;
+        let (request_type, allowed_flags) = if discard {
+            (VIRTIO_BLK_T_DISCARD, 0)
+        } else {
+            (
+                VIRTIO_BLK_T_WRITE_ZEROES,
+                VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
+            )
+        };
+
+        let mut segments = vec![];
+        for (data_addr, data_len) in request.data() {
+            let data_len = u64::from(*data_len);
+            if data_len % SEGMENT_SIZE != 0 {
+                return Err(stdio_executor::Error::InvalidDataLength);
+            }
+            for i in 0..data_len / SEGMENT_SIZE {
+                let addr = data_addr
+                    .checked_add(i * SEGMENT_SIZE)
+                    .ok_or(stdio_executor::Error::InvalidAccess)?;
+                let sector = u64::from_le(
+                    mem.read_obj(addr)
+                        .map_err(stdio_executor::Error::GuestMemory)?,
+                );
+                let num_sectors = u32::from_le(
+                    mem.read_obj(addr.unchecked_add(8))
+                        .map_err(stdio_executor::Error::GuestMemory)?,
+                );
+                let flags = u32::from_le(
+                    mem.read_obj(addr.unchecked_add(12))
+                        .map_err(stdio_executor::Error::GuestMemory)?,
+                );
+                if flags & !allowed_flags != 0 {
+                    return Err(stdio_executor::Error::Unsupported(request_type));
+                }
+                self.check_access(u64::from(num_sectors), sector)?;
+                segments.push(Segment {
+                    offset: sector << SECTOR_SHIFT,
+                    len: u64::from(num_sectors) << SECTOR_SHIFT,
+                    unmap: discard || flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0,
+                });
+            }
+        }
+        if segments.len() > MAX_DISCARD_SEGMENTS as usize {
+            return Err(stdio_executor::Error::InvalidDataLength);
+        }
+        Ok(segments)
+    }
+
+    /// Returns the used length if the request was executed right away, None if it was
+    /// submitted to the executor.
+    fn execute(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        seq: u64,
+        request: &Request,
+    ) -> stdio_executor::Result<Option<u32>> {
         let offset = request
             .sector()
             .checked_shl(u32::from(SECTOR_SHIFT))
             .ok_or(stdio_executor::Error::InvalidAccess)?;
 
         let total_len = request.total_data_len();
-        // This will count the number of bytes written by the device to the memory. It must fit in
-        // an u32 for further writing in the used ring.
-        let mut bytes_to_mem: u32 = 0;
         let request_type = request.request_type();
 
         if (request_type == RequestType::In || request_type == RequestType::Out)
@@ -152,120 +223,115 @@ impl<S: SignalUsedQueue> InOrderQueueHandler<S> {
             return Err(stdio_executor::Error::InvalidDataLength);
         }
 
-        match request_type {
+        let mut segments = vec![];
+        let op = match request_type {
             RequestType::In => {
                 self.check_access(total_len / SECTOR_SIZE, request.sector())?;
                 // Total data length should fit in an u32 for further writing in the used ring.
                 if total_len > u32::MAX as u64 {
                     return Err(stdio_executor::Error::InvalidDataLength);
                 }
-                self.prepare_iovs(request)?;
-                let local_iovs = vec![IoSlice::new(unsafe {
-                    slice::from_raw_parts(
-                        self.mmap.ptr.add(offset as usize) as *mut u8,
-                        request.total_data_len() as usize,
-                    )
-                })];
-
-                bytes_to_mem =
-                    process_vm_writev(self.pid, local_iovs.as_slice(), self.remote_iovs.as_slice())
-                        .map_err(|e| {
-                            stdio_executor::Error::Read(
-                                GuestMemoryError::IOError(io::Error::from_raw_os_error(e as i32)),
-                                0,
-                            )
-                        })? as u32;
+                Op::Read
             }
             RequestType::Out => {
                 self.check_access(total_len / SECTOR_SIZE, request.sector())?;
-                self.prepare_iovs(request)?;
-                let mut local_iovs = vec![IoSliceMut::new(unsafe {
-                    slice::from_raw_parts_mut(
-                        self.mmap.ptr.add(offset as usize) as *mut u8,
-                        request.total_data_len() as usize,
-                    )
-                })];
-                bytes_to_mem = process_vm_readv(
-                    self.pid,
-                    local_iovs.as_mut_slice(),
-                    self.remote_iovs.as_slice(),
-                )
-                .map_err(|e| {
-                    stdio_executor::Error::Write(GuestMemoryError::IOError(
-                        io::Error::from_raw_os_error(e as i32),
-                    ))
-                })? as u32;
+                Op::Write
             }
-            RequestType::Flush => {
-                self.check_access(total_len / SECTOR_SIZE, request.sector())?;
-                let res = unsafe {
-                    msync(
-                        self.mmap.ptr.add(offset as usize),
-                        total_len as usize,
-                        MsFlags::MS_SYNC,
-                    )
-                };
-                res.map_err(|e| {
-                    stdio_executor::Error::Flush(io::Error::from_raw_os_error(e as i32))
-                })?
+            // flushes the whole disk, the sector is reserved
+            RequestType::Flush => Op::Flush,
+            RequestType::Discard => {
+                segments = self.parse_segments(mem, request)?;
+                Op::Discard
+            }
+            RequestType::WriteZeroes => {
+                segments = self.parse_segments(mem, request)?;
+                Op::WriteZeroes
+            }
+            _ => return self.disk.execute(mem, request).map(Some),
+        };
+        match op {
+            Op::Read => {
+                metrics::add(&METRICS.block_reads, 1);
+                metrics::add(&METRICS.block_read_bytes, total_len);
             }
-            _ => return self.disk.execute(mem, request),
+            Op::Write => {
+                metrics::add(&METRICS.block_writes, 1);
+                metrics::add(&METRICS.block_write_bytes, total_len);
+            }
+            _ => metrics::add(&METRICS.block_other_ops, 1),
         }
-        Ok(bytes_to_mem)
+        let job = Job {
+            seq,
+            op,
+            offset: offset as usize,
+            len: total_len as usize,
+            iovs: self.prepare_iovs(request)?,
+            segments,
+        };
+        self.executor.submit(job)?;
+        Ok(None)
     }
+
     fn process_chain(
         &mut self,
         mut chain: DescriptorChain<&GuestMemoryMmap>,
     ) -> result::Result<(), Error> {
-        let len;
+        let seq = self.first_seq.wrapping_add(self.inflight.len() as u64);
+        let head_index = chain.head_index();
 
         log::trace!("process_chain");
-        match Request::parse(&mut chain) {
+        let inflight = match Request::parse(&mut chain) {
             Ok(request) => {
                 log::trace!("request: {:?}", request);
-                let status = match self.execute(chain.memory(), &request) {
-                    Ok(l) => {
-                        // TODO: Using `saturating_add` until we consume the recent changes
-                        // proposed for the executor upstream.
-                        len = l.saturating_add(1);
-                        // VIRTIO_BLK_S_OK defined as 0 in the standard.
-                        0
-                    }
-                    Err(e) => {
-                        warn!("failed to execute block request: {:?}", e);
-                        len = 1;
-                        // TODO: add `status` or similar method to executor error.
-                        if let stdio_executor::Error::Unsupported(_) = e {
-                            // UNSUPP
-                            2
-                        } else {
-                            // IOERR
-                            1
-                        }
-                    }
+                let done = match self.execute(chain.memory(), seq, &request) {
+                    Ok(Some(len)) => Some(request_status(Ok(len))),
+                    Ok(None) => None,
+                    Err(e) => Some(request_status(Err(e))),
                 };
-
-                chain
-                    .memory()
-                    .write_obj(status as u8, request.status_addr())?;
+                Inflight {
+                    head_index,
+                    status_addr: Some(request.status_addr()),
+                    done,
+                }
             }
             Err(e) => {
-                len = 0;
                 warn!("block request parse error: {:?}", e);
+                Inflight {
+                    head_index,
+                    status_addr: None,
+                    done: Some((0, 0)),
+                }
             }
-        }
+        };
+        self.inflight.push_back(inflight);
 
-        self.queue
-            .add_used(self.mem.as_ref(), chain.head_index(), len)?;
+        log::trace!("process_chain done");
+        Ok(())
+    }
 
-        if self.queue.needs_notification(self.mem.as_ref())? {
-            log::trace!("notification needed: yes");
-            self.driver_notify.signal_used_queue(0);
-        } else {
-            log::trace!("notification needed: no");
+    // Returns the finished requests at the start of `inflight` to the driver.
+    fn return_done(&mut self) -> result::Result<(), Error> {
+        let mem = Arc::clone(&self.mem);
+        while let Some(Inflight {
+            head_index,
+            status_addr,
+            done: Some((status, len)),
+        }) = self.inflight.front()
+        {
+            if let Some(status_addr) = status_addr {
+                mem.write_obj(*status, *status_addr)?;
+            }
+            self.queue.add_used(mem.as_ref(), *head_index, *len)?;
+            self.inflight.pop_front();
+            self.first_seq = self.first_seq.wrapping_add(1);
+
+            if self.queue.needs_notification(mem.as_ref())? {
+                log::trace!("notification needed: yes");
+                self.driver_notify.signal_used_queue(0);
+            } else {
+                log::trace!("notification needed: no");
+            }
         }
-
-        log::trace!("process_chain done");
         Ok(())
     }
 
@@ -286,7 +352,19 @@ impl<S: SignalUsedQueue> InOrderQueueHandler<S> {
             }
         }
 
-        Ok(())
+        self.return_done()
+    }
+
+    /// Collects the requests finished by the executor.
+    pub fn process_completions(&mut self) -> result::Result<(), Error> {
+        for completion in self.executor.completions() {
+            let idx = completion.seq.wrapping_sub(self.first_seq) as usize;
+            match self.inflight.get_mut(idx) {
+                Some(inflight) => inflight.done = Some(request_status(completion.result)),
+                None => warn!("completion of unknown block request {}", completion.seq),
+            }
+        }
+        self.return_done()
     }
 }
 
@@ -2,13 +2,16 @@
 // Author of further modifications: Peter Okelmann
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
+mod backend;
 mod device;
+mod executor;
 mod inorder_handler;
+mod nbd;
 mod queue_handler;
+mod worker;
 
-use std::fs::File;
-use std::io::{self, Seek, SeekFrom};
-use std::path::{Path, PathBuf};
+use std::io;
+use std::path::PathBuf;
 
 use event_manager::Error as EvmgrError;
 use virtio_blk::stdio_executor;
@@ -16,9 +19,9 @@ use vm_device::bus;
 use vmm_sys_util::errno;
 
 use crate::devices::virtio::CommonArgs;
-use simple_error::SimpleError;
+use crate::result::VmshError;
 
-pub use device::Block;
+pub use device::{Block, BlockSlot};
 
 // TODO: Move relevant defines to vm-virtio crate.
 
@@ -29,10 +32,33 @@ pub const BLOCK_DEVICE_ID: u32 = 2;
 pub const VIRTIO_BLK_F_RO: u64 = 5;
 // Block device FLUSH feature.
 pub const VIRTIO_BLK_F_FLUSH: u64 = 9;
+// Block device multi-queue feature.
+pub const VIRTIO_BLK_F_MQ: u64 = 12;
+// Block device discard feature.
+pub const VIRTIO_BLK_F_DISCARD: u64 = 13;
+// Block device write zeroes feature.
+pub const VIRTIO_BLK_F_WRITE_ZEROES: u64 = 14;
+
+// Upper limit for the number of request queues of a block device.
+pub const MAX_BLK_QUEUES: u16 = 16;
+
+// Offset of `num_queues` in `struct virtio_blk_config`.
+const CONFIG_NUM_QUEUES_OFFSET: usize = 34;
+// Offset of `max_discard_sectors` in `struct virtio_blk_config`, followed by the other discard
+// and write zeroes limits.
+const CONFIG_DISCARD_OFFSET: usize = 36;
+
+// Upper limit for the number of segments in a discard or write zeroes request.
+pub const MAX_DISCARD_SEGMENTS: u32 = 32;
+// Discarded ranges should be aligned to pages (in sectors).
+const DISCARD_SECTOR_ALIGNMENT: u32 = 4096 >> SECTOR_SHIFT;
 
 // The sector size is 512 bytes (1 << 9).
 const SECTOR_SHIFT: u8 = 9;
 
+// Length of the device id returned for VIRTIO_BLK_T_GET_ID requests.
+pub const VIRTIO_BLK_ID_BYTES: usize = 20;
+
 #[derive(Debug)]
 pub enum Error {
     AlreadyActivated,
@@ -50,35 +76,55 @@ pub enum Error {
     #[allow(dead_code)] // FIXME
     RegisterIrqfd(errno::Error),
     Seek(io::Error),
-    Simple(SimpleError),
+    Simple(VmshError),
+    Thread(io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 // TODO: Add a helper abstraction to rust-vmm for building the device configuration space.
 // The one we build below for the block device contains the minimally required `capacity` member,
-// but other fields can be present as well depending on the negotiated features.
-fn build_config_space<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
-    // TODO: right now, the file size is computed by the StdioBackend as well. Maybe we should
-    // create the backend as early as possible, and get the size information from there.
-    let file_size = File::open(path)
-        .map_err(Error::OpenFile)?
-        .seek(SeekFrom::End(0))
-        .map_err(Error::Seek)?;
-    // If the file size is actually not a multiple of sector size, then data at the very end
+// `num_queues` if the device has more than one queue and the discard and write zeroes limits if
+// `discard` is set.
+fn build_config_space(disk_size: u64, num_queues: u16, discard: bool) -> Result<Vec<u8>> {
+    // If the disk size is actually not a multiple of sector size, then data at the very end
     // will be ignored.
-    let num_sectors = file_size >> SECTOR_SHIFT;
+    let num_sectors = disk_size >> SECTOR_SHIFT;
     // This has to be in little endian btw.
-    Ok(num_sectors.to_le_bytes().to_vec())
+    let mut config = num_sectors.to_le_bytes().to_vec();
+    if num_queues > 1 || discard {
+        config.resize(CONFIG_NUM_QUEUES_OFFSET, 0);
+        config.extend_from_slice(&num_queues.to_le_bytes());
+    }
+    if discard {
+        let max_sectors = u32::MAX;
+        // max_discard_sectors, max_discard_seg, discard_sector_alignment
+        config.extend_from_slice(&max_sectors.to_le_bytes());
+        config.extend_from_slice(&MAX_DISCARD_SEGMENTS.to_le_bytes());
+        config.extend_from_slice(&DISCARD_SECTOR_ALIGNMENT.to_le_bytes());
+        // max_write_zeroes_sectors, max_write_zeroes_seg
+        config.extend_from_slice(&max_sectors.to_le_bytes());
+        config.extend_from_slice(&MAX_DISCARD_SEGMENTS.to_le_bytes());
+        // write_zeroes_may_unmap and padding
+        config.extend_from_slice(&[1, 0, 0, 0]);
+    }
+    Ok(config)
 }
 
 // Arguments required when building a block device.
 pub struct BlockArgs<'a, B> {
     pub common: CommonArgs<'a, B>,
+    /// Image file or `nbd://` url, see `backend::open`.
     pub file_path: PathBuf,
     pub read_only: bool,
+    /// Never modify the backing file, writes are kept in memory until the device is dropped.
+    pub snapshot: bool,
     pub root_device: bool,
     pub advertise_flush: bool,
+    /// Number of request queues, more than one enables VIRTIO_BLK_F_MQ.
+    pub num_queues: u16,
+    /// Serial number reported to the guest, at most `VIRTIO_BLK_ID_BYTES` long.
+    pub serial: String,
 }
 
 #[cfg(test)]
@@ -90,6 +136,10 @@ mod tests {
 
     use super::*;
 
+    fn file_size(tmp: &TempFile) -> u64 {
+        tmp.as_file().metadata().unwrap().len()
+    }
+
     #[test]
     fn test_build_config_space() {
         let tmp = TempFile::new().unwrap();
@@ -102,7 +152,7 @@ mod tests {
         }
 
         {
-            let config_space = build_config_space(tmp.as_path()).unwrap();
+            let config_space = build_config_space(file_size(&tmp), 1, false).unwrap();
 
             // The config space is only populated with the `capacity` field for now.
             assert_eq!(config_space.len(), size_of::<u64>());
@@ -114,9 +164,31 @@ mod tests {
         tmp.as_file().write_all(&[1u8, 2, 3]).unwrap();
 
         {
-            let config_space = build_config_space(tmp.as_path()).unwrap();
+            let config_space = build_config_space(file_size(&tmp), 1, false).unwrap();
             // We should get the same value of capacity, as the extra bytes are ignored.
             assert_eq!(config_space[..8], num_sectors.to_le_bytes());
         }
+
+        {
+            let config_space = build_config_space(file_size(&tmp), 4, false).unwrap();
+            assert_eq!(config_space.len(), CONFIG_NUM_QUEUES_OFFSET + 2);
+            assert_eq!(config_space[..8], num_sectors.to_le_bytes());
+            assert_eq!(config_space[CONFIG_NUM_QUEUES_OFFSET..], 4u16.to_le_bytes());
+        }
+
+        {
+            let config_space = build_config_space(file_size(&tmp), 1, true).unwrap();
+            // `struct virtio_blk_config` up to `write_zeroes_may_unmap` and its padding
+            assert_eq!(config_space.len(), 60);
+            assert_eq!(
+                config_space[CONFIG_NUM_QUEUES_OFFSET..CONFIG_DISCARD_OFFSET],
+                1u16.to_le_bytes()
+            );
+            assert_eq!(
+                config_space[CONFIG_DISCARD_OFFSET + 4..CONFIG_DISCARD_OFFSET + 8],
+                MAX_DISCARD_SEGMENTS.to_le_bytes()
+            );
+            assert_eq!(config_space[56], 1);
+        }
     }
 }
@@ -0,0 +1,452 @@
+//! Client for the network block device protocol, so that images can live on a remote server
+//! (i.e. `qemu-nbd` or `nbdkit`). Only the fixed newstyle handshake and simple replies are
+//! implemented. Requests of all io threads share one connection and are sent one at a time.
+//!
+//! See https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use log::{info, warn};
+use simple_error::{bail, try_with};
+
+use super::backend::BlockBackend;
+use super::executor::Segment;
+use crate::result::Result;
+
+const NBD_DEFAULT_PORT: u16 = 10809;
+
+const NBDMAGIC: u64 = 0x4e42_444d_4147_4943;
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+// handshake flags
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+// client flags
+const NBD_FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+const NBD_OPT_EXPORT_NAME: u32 = 1;
+
+// transmission flags
+const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+const NBD_FLAG_SEND_TRIM: u16 = 1 << 5;
+const NBD_FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+const NBD_CMD_TRIM: u16 = 4;
+const NBD_CMD_WRITE_ZEROES: u16 = 6;
+
+const NBD_CMD_FLAG_NO_HOLE: u16 = 1 << 1;
+
+/// Servers may reject larger requests, bigger ones are split.
+const NBD_MAX_REQUEST: usize = 32 * 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum NbdAddr {
+    Tcp {
+        host: String,
+        port: u16,
+        export: String,
+    },
+    Unix {
+        socket: PathBuf,
+        export: String,
+    },
+}
+
+/// Parses `nbd://host[:port][/export]` and `nbd+unix:///[export]?socket=<path>`. Returns
+/// `None` for other urls.
+pub fn parse_url(url: &str) -> Result<Option<NbdAddr>> {
+    if let Some(rest) = url.strip_prefix("nbd+unix://") {
+        let (export, query) = match rest.split_once('?') {
+            Some(split) => split,
+            None => bail!("{} lacks ?socket=<path>", url),
+        };
+        let socket = match query.strip_prefix("socket=") {
+            Some(socket) if !socket.is_empty() => socket,
+            _ => bail!("{} lacks ?socket=<path>", url),
+        };
+        return Ok(Some(NbdAddr::Unix {
+            socket: PathBuf::from(socket),
+            export: export.trim_start_matches('/').to_string(),
+        }));
+    }
+    let rest = match url.strip_prefix("nbd://") {
+        Some(rest) => rest,
+        None => return Ok(None),
+    };
+    let (authority, export) = rest.split_once('/').unwrap_or((rest, ""));
+    let (host, port) = match authority.rsplit_once(':') {
+        // [::1] without port
+        Some((_, port)) if port.ends_with(']') => (authority, NBD_DEFAULT_PORT),
+        Some((host, port)) => (
+            host,
+            try_with!(port.parse::<u16>(), "invalid port in {}", url),
+        ),
+        None => (authority, NBD_DEFAULT_PORT),
+    };
+    if host.is_empty() {
+        bail!("{} lacks a host", url);
+    }
+    Ok(Some(NbdAddr::Tcp {
+        host: host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string(),
+        port,
+        export: export.to_string(),
+    }))
+}
+
+/// Splits a discarded or zeroed range into requests of at most `NBD_MAX_REQUEST` bytes.
+fn chunks(segment: &Segment) -> impl Iterator<Item = (u64, u32)> {
+    let end = segment.offset + segment.len;
+    (segment.offset..end)
+        .step_by(NBD_MAX_REQUEST)
+        .map(move |offset| (offset, (end - offset).min(NBD_MAX_REQUEST as u64) as u32))
+}
+
+trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+struct Connection {
+    stream: Box<dyn Stream>,
+    next_handle: u64,
+}
+
+pub struct NbdBackend {
+    conn: Mutex<Connection>,
+    size: u64,
+    flags: u16,
+}
+
+fn read_u16(stream: &mut dyn Stream) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32(stream: &mut dyn Stream) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64(stream: &mut dyn Stream) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    stream.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+/// Negotiates the export and returns its size and transmission flags.
+fn handshake(stream: &mut dyn Stream, export: &str) -> io::Result<(u64, u16)> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    if read_u64(stream)? != NBDMAGIC || read_u64(stream)? != IHAVEOPT {
+        return Err(invalid("not a newstyle nbd server"));
+    }
+    let server_flags = read_u16(stream)?;
+    if server_flags & NBD_FLAG_FIXED_NEWSTYLE == 0 {
+        return Err(invalid(
+            "server does not support the fixed newstyle handshake",
+        ));
+    }
+    let no_zeroes = server_flags & NBD_FLAG_NO_ZEROES != 0;
+    let mut client_flags = NBD_FLAG_C_FIXED_NEWSTYLE;
+    if no_zeroes {
+        client_flags |= NBD_FLAG_C_NO_ZEROES;
+    }
+    let mut msg = client_flags.to_be_bytes().to_vec();
+    msg.extend_from_slice(&IHAVEOPT.to_be_bytes());
+    msg.extend_from_slice(&NBD_OPT_EXPORT_NAME.to_be_bytes());
+    msg.extend_from_slice(&(export.len() as u32).to_be_bytes());
+    msg.extend_from_slice(export.as_bytes());
+    stream.write_all(&msg)?;
+
+    // the server closes the connection if it does not know the export
+    let size = read_u64(stream)?;
+    let flags = read_u16(stream)?;
+    if !no_zeroes {
+        let mut zeroes = [0u8; 124];
+        stream.read_exact(&mut zeroes)?;
+    }
+    Ok((size, flags))
+}
+
+impl NbdBackend {
+    pub fn connect(addr: &NbdAddr) -> Result<NbdBackend> {
+        let (mut stream, export): (Box<dyn Stream>, &str) = match addr {
+            NbdAddr::Tcp { host, port, export } => {
+                let stream = try_with!(
+                    TcpStream::connect((host.as_str(), *port)),
+                    "cannot connect to nbd server {}:{}",
+                    host,
+                    port
+                );
+                try_with!(stream.set_nodelay(true), "cannot set TCP_NODELAY");
+                (Box::new(stream), export)
+            }
+            NbdAddr::Unix { socket, export } => (
+                Box::new(try_with!(
+                    UnixStream::connect(socket),
+                    "cannot connect to nbd server {}",
+                    socket.display()
+                )),
+                export,
+            ),
+        };
+        let (size, flags) = try_with!(
+            handshake(&mut *stream, export),
+            "nbd handshake for export '{}' failed",
+            export
+        );
+        info!(
+            "nbd export '{}': {} bytes, flags {:#x}",
+            export, size, flags
+        );
+        Ok(NbdBackend::new(stream, size, flags))
+    }
+
+    fn new(stream: Box<dyn Stream>, size: u64, flags: u16) -> NbdBackend {
+        NbdBackend {
+            conn: Mutex::new(Connection {
+                stream,
+                next_handle: 0,
+            }),
+            size,
+            flags,
+        }
+    }
+
+    /// Sends a request and waits for its reply. Data of writes is taken from `out`, data of
+    /// reads is stored in `reply`.
+    fn request(
+        &self,
+        cmd: u16,
+        flags: u16,
+        offset: u64,
+        len: u32,
+        out: &[u8],
+        reply: &mut [u8],
+    ) -> io::Result<()> {
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "nbd connection poisoned"))?;
+        let handle = conn.next_handle;
+        conn.next_handle = conn.next_handle.wrapping_add(1);
+
+        let mut msg = Vec::with_capacity(28 + out.len());
+        msg.extend_from_slice(&NBD_REQUEST_MAGIC.to_be_bytes());
+        msg.extend_from_slice(&flags.to_be_bytes());
+        msg.extend_from_slice(&cmd.to_be_bytes());
+        msg.extend_from_slice(&handle.to_be_bytes());
+        msg.extend_from_slice(&offset.to_be_bytes());
+        msg.extend_from_slice(&len.to_be_bytes());
+        msg.extend_from_slice(out);
+        conn.stream.write_all(&msg)?;
+        if cmd == NBD_CMD_DISC {
+            return Ok(());
+        }
+
+        let stream = &mut *conn.stream;
+        if read_u32(stream)? != NBD_SIMPLE_REPLY_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid nbd reply magic",
+            ));
+        }
+        let error = read_u32(stream)?;
+        if read_u64(stream)? != handle {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "nbd reply for unknown request",
+            ));
+        }
+        if error != 0 {
+            // error values are errno numbers
+            return Err(io::Error::from_raw_os_error(error as i32));
+        }
+        stream.read_exact(reply)
+    }
+
+    fn has_flag(&self, flag: u16) -> bool {
+        self.flags & flag != 0
+    }
+}
+
+impl BlockBackend for NbdBackend {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_only(&self) -> bool {
+        self.has_flag(NBD_FLAG_READ_ONLY)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut offset = offset;
+        for chunk in buf.chunks_mut(NBD_MAX_REQUEST) {
+            self.request(NBD_CMD_READ, 0, offset, chunk.len() as u32, &[], chunk)?;
+            offset += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let mut offset = offset;
+        for chunk in buf.chunks(NBD_MAX_REQUEST) {
+            self.request(NBD_CMD_WRITE, 0, offset, chunk.len() as u32, chunk, &mut [])?;
+            offset += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        if !self.has_flag(NBD_FLAG_SEND_FLUSH) {
+            return Ok(());
+        }
+        self.request(NBD_CMD_FLUSH, 0, 0, 0, &[], &mut [])
+    }
+
+    fn discard(&self, segment: &Segment) -> io::Result<()> {
+        // discarding is only a hint
+        if !self.has_flag(NBD_FLAG_SEND_TRIM) {
+            return Ok(());
+        }
+        for (offset, len) in chunks(segment) {
+            self.request(NBD_CMD_TRIM, 0, offset, len, &[], &mut [])?;
+        }
+        Ok(())
+    }
+
+    fn write_zeroes(&self, segment: &Segment) -> io::Result<()> {
+        if !self.has_flag(NBD_FLAG_SEND_WRITE_ZEROES) {
+            let zeroes = vec![0u8; NBD_MAX_REQUEST.min(segment.len as usize)];
+            for (offset, len) in chunks(segment) {
+                self.write_at(offset, &zeroes[..len as usize])?;
+            }
+            return Ok(());
+        }
+        let flags = if segment.unmap {
+            0
+        } else {
+            NBD_CMD_FLAG_NO_HOLE
+        };
+        for (offset, len) in chunks(segment) {
+            self.request(NBD_CMD_WRITE_ZEROES, flags, offset, len, &[], &mut [])?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for NbdBackend {
+    fn drop(&mut self) {
+        if let Err(e) = self.request(NBD_CMD_DISC, 0, 0, 0, &[], &mut []) {
+            warn!("cannot disconnect from nbd server: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn parse_urls() {
+        assert_eq!(
+            parse_url("nbd://localhost/disk").unwrap(),
+            Some(NbdAddr::Tcp {
+                host: "localhost".into(),
+                port: NBD_DEFAULT_PORT,
+                export: "disk".into()
+            })
+        );
+        assert_eq!(
+            parse_url("nbd://[::1]:10810").unwrap(),
+            Some(NbdAddr::Tcp {
+                host: "::1".into(),
+                port: 10810,
+                export: "".into()
+            })
+        );
+        assert_eq!(
+            parse_url("nbd+unix:///disk?socket=/run/nbd.sock").unwrap(),
+            Some(NbdAddr::Unix {
+                socket: PathBuf::from("/run/nbd.sock"),
+                export: "disk".into()
+            })
+        );
+        assert!(parse_url("nbd+unix:///disk").is_err());
+        assert!(parse_url("nbd://host:port").is_err());
+        assert_eq!(parse_url("/dev/null").unwrap(), None);
+    }
+
+    /// Serves an export of 1024 bytes for one read and one write.
+    fn serve(mut stream: UnixStream) {
+        let mut disk = vec![7u8; 1024];
+        let mut hello = NBDMAGIC.to_be_bytes().to_vec();
+        hello.extend_from_slice(&IHAVEOPT.to_be_bytes());
+        hello.extend_from_slice(&(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES).to_be_bytes());
+        stream.write_all(&hello).unwrap();
+        let mut option = [0u8; 4 + 8 + 4 + 4];
+        stream.read_exact(&mut option).unwrap();
+        let mut name = [0u8; 4];
+        stream.read_exact(&mut name).unwrap();
+        assert_eq!(&name, b"disk");
+        stream.write_all(&1024u64.to_be_bytes()).unwrap();
+        stream.write_all(&0u16.to_be_bytes()).unwrap();
+
+        loop {
+            let mut header = [0u8; 28];
+            stream.read_exact(&mut header).unwrap();
+            let cmd = u16::from_be_bytes([header[6], header[7]]);
+            let offset = u64::from_be_bytes(header[16..24].try_into().unwrap()) as usize;
+            let len = u32::from_be_bytes(header[24..28].try_into().unwrap()) as usize;
+            if cmd == NBD_CMD_DISC {
+                return;
+            }
+            if cmd == NBD_CMD_WRITE {
+                stream.read_exact(&mut disk[offset..offset + len]).unwrap();
+            }
+            let mut reply = NBD_SIMPLE_REPLY_MAGIC.to_be_bytes().to_vec();
+            reply.extend_from_slice(&0u32.to_be_bytes());
+            reply.extend_from_slice(&header[8..16]);
+            if cmd == NBD_CMD_READ {
+                reply.extend_from_slice(&disk[offset..offset + len]);
+            }
+            stream.write_all(&reply).unwrap();
+        }
+    }
+
+    #[test]
+    fn read_write() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || serve(server));
+        let mut stream: Box<dyn Stream> = Box::new(client);
+        let (size, flags) = handshake(&mut *stream, "disk").unwrap();
+        assert_eq!(size, 1024);
+        let backend = NbdBackend::new(stream, size, flags);
+
+        backend.write_at(512, &[1, 2, 3]).unwrap();
+        let mut buf = [0u8; 4];
+        backend.read_at(511, &mut buf).unwrap();
+        assert_eq!(buf, [7, 1, 2, 3]);
+        // without NBD_FLAG_SEND_FLUSH nothing is sent
+        backend.flush().unwrap();
+        drop(backend);
+        server.join().unwrap();
+    }
+}
@@ -2,6 +2,8 @@
 // Author of further modifications: Peter Okelmann
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
+use std::sync::Arc;
+
 use event_manager::{EventOps, Events, MutEventSubscriber};
 use log::error;
 
@@ -12,32 +14,46 @@ use crate::devices::virtio::SingleFdSignalQueue;
 use crate::kvm::hypervisor::ioevent::IoEvent;
 
 const IOEVENT_DATA: u32 = 0;
+const COMPLETION_DATA: u32 = 1;
 
 // This object simply combines the more generic `InOrderQueueHandler` with a concrete queue
 // signalling implementation based on `EventFd`s, and then also implements `MutEventSubscriber`
 // to interact with the event manager. `ioeventfd` is the `EventFd` connected to queue
-// notifications coming from the driver.
+// notifications coming from the driver, the completion fd of the executor signals finished
+// requests.
 pub(crate) struct QueueHandler {
     pub inner: InOrderQueueHandler<SingleFdSignalQueue>,
-    pub ioeventfd: IoEvent,
+    pub ioeventfd: Arc<IoEvent>,
 }
 
 impl MutEventSubscriber for QueueHandler {
     fn process(&mut self, events: Events, ops: &mut EventOps) {
+        let _span =
+            tracing::trace_span!("queue-handler", device = "block", data = events.data()).entered();
         let mut error = true;
 
         // TODO: Have a look at any potential performance impact caused by these conditionals
         // just to be sure.
         if events.event_set() != EventSet::IN {
             error!("unexpected event_set");
-        } else if events.data() != IOEVENT_DATA {
-            error!("unexpected events data {}", events.data());
-        } else if self.ioeventfd.read().is_err() {
-            error!("ioeventfd read error")
-        } else if let Err(e) = self.inner.process_queue() {
-            error!("error processing block queue {:?}", e);
+        } else if events.data() == IOEVENT_DATA {
+            if self.ioeventfd.read().is_err() {
+                error!("ioeventfd read error")
+            } else if let Err(e) = self.inner.process_queue() {
+                error!("error processing block queue {:?}", e);
+            } else {
+                error = false;
+            }
+        } else if events.data() == COMPLETION_DATA {
+            if self.inner.executor.completion_fd.read().is_err() {
+                error!("completion fd read error")
+            } else if let Err(e) = self.inner.process_completions() {
+                error!("error completing block requests {:?}", e);
+            } else {
+                error = false;
+            }
         } else {
-            error = false;
+            error!("unexpected events data {}", events.data());
         }
 
         if error {
@@ -48,11 +64,17 @@ impl MutEventSubscriber for QueueHandler {
 
     fn init(&mut self, ops: &mut EventOps) {
         ops.add(Events::with_data(
-            &self.ioeventfd,
+            self.ioeventfd.as_ref(),
             IOEVENT_DATA,
             EventSet::IN,
         ))
         .expect("Failed to init block queue handler");
+        ops.add(Events::with_data(
+            self.inner.executor.completion_fd.as_ref(),
+            COMPLETION_DATA,
+            EventSet::IN,
+        ))
+        .expect("Failed to init block queue handler");
     }
 }
 
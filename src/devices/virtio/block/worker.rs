@@ -0,0 +1,66 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use event_manager::{EventManager, MutEventSubscriber};
+
+use super::queue_handler::QueueHandler;
+use super::{Error, Result};
+
+/// Only bounds how long stopping a worker takes, requests wake up the thread.
+const EVENT_LOOP_TIMEOUT_MS: i32 = 100;
+
+// Runs the handler of a single queue in its own thread, so that requests submitted by different
+// guest CPUs to different queues are processed in parallel.
+pub(crate) struct QueueWorker {
+    should_stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl QueueWorker {
+    pub fn spawn(queue_idx: u16, handler: QueueHandler) -> Result<QueueWorker> {
+        let handler = Arc::new(Mutex::new(handler));
+        let mut event_mgr = EventManager::<Arc<Mutex<dyn MutEventSubscriber + Send>>>::new()
+            .map_err(Error::Endpoint)?;
+        event_mgr.add_subscriber(handler);
+
+        let should_stop = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&should_stop);
+        let thread = thread::Builder::new()
+            .name(format!("blk-queue-{}", queue_idx))
+            .spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    if let Err(e) = event_mgr.run_with_timeout(EVENT_LOOP_TIMEOUT_MS) {
+                        log::warn!(
+                            "block queue {}: failed to handle events: {:?}",
+                            queue_idx,
+                            e
+                        );
+                    }
+                }
+            })
+            .map_err(Error::Thread)?;
+
+        Ok(QueueWorker {
+            should_stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Stops the worker thread. The queue handler is owned by the event loop of the thread and
+    /// dropped once it exits.
+    pub fn stop(&mut self) {
+        self.should_stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                log::warn!("block queue worker panicked");
+            }
+        }
+    }
+}
+
+impl Drop for QueueWorker {
+    fn drop(&mut self) {
+        self.should_stop.store(true, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::VecDeque;
+use std::result;
+use std::sync::Arc;
+
+use log::{debug, error, warn};
+use virtio_queue::Queue;
+use virtio_queue::{QueueOwnedT, QueueT};
+use vm_memory::{Bytes, GuestMemoryMmap};
+
+use super::device::{CONTROL_RX_QUEUE_IDX, CONTROL_TX_QUEUE_IDX};
+use super::log_handler::Error;
+use crate::devices::virtio::SignalUsedQueue;
+use crate::kvm::hypervisor::ioevent::IoEvent;
+
+// Control events as defined by the standard (5.3.6.2 Multiport Device Operation).
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+#[allow(unused)]
+const VIRTIO_CONSOLE_DEVICE_REMOVE: u16 = 2;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_RESIZE: u16 = 5;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+/// Size of `struct virtio_console_control { le32 id; le16 event; le16 value; }`
+const CONTROL_MSG_SIZE: usize = 8;
+
+/// Name of an additional port, the guest finds it in `/dev/virtio-ports/`.
+pub fn port_name(id: u32) -> String {
+    format!("vmsh.port{}", id)
+}
+
+fn control_msg(id: u32, event: u16, value: u16) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(CONTROL_MSG_SIZE);
+    msg.extend_from_slice(&id.to_le_bytes());
+    msg.extend_from_slice(&event.to_le_bytes());
+    msg.extend_from_slice(&value.to_le_bytes());
+    msg
+}
+
+/// Handles the control queues of a console device with VIRTIO_CONSOLE_F_MULTIPORT.
+///
+/// The driver announces itself with DEVICE_READY, we answer with a DEVICE_ADD for every port.
+/// Once the driver reports a port as ready, port 0 is turned into the console (hvc) and every
+/// other port gets its name. All ports are reported as opened by the host.
+pub(crate) struct ControlQueues {
+    /// notified by the driver when it adds buffers to the receive queue
+    pub rx_fd: Arc<IoEvent>,
+    pub tx_fd: Arc<IoEvent>,
+    /// control receiveq (device to driver)
+    rxq: Queue,
+    /// control transmitq (driver to device)
+    txq: Queue,
+    nr_ports: u32,
+    /// messages waiting for a buffer in the receive queue
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl ControlQueues {
+    pub fn new(
+        rx_fd: Arc<IoEvent>,
+        tx_fd: Arc<IoEvent>,
+        rxq: Queue,
+        txq: Queue,
+        nr_ports: u32,
+    ) -> Self {
+        ControlQueues {
+            rx_fd,
+            tx_fd,
+            rxq,
+            txq,
+            nr_ports,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn handle_msg(&mut self, id: u32, event: u16, value: u16) {
+        debug!("console control: id {} event {} value {}", id, event, value);
+        match event {
+            VIRTIO_CONSOLE_DEVICE_READY => {
+                if value != 1 {
+                    error!("console driver failed to initialize");
+                    return;
+                }
+                for port in 0..self.nr_ports {
+                    self.pending
+                        .push_back(control_msg(port, VIRTIO_CONSOLE_DEVICE_ADD, 1));
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY => {
+                if value != 1 {
+                    warn!("console driver failed to add port {}", id);
+                    return;
+                }
+                if id >= self.nr_ports {
+                    warn!("console driver reported unknown port {}", id);
+                    return;
+                }
+                if id == 0 {
+                    self.pending
+                        .push_back(control_msg(id, VIRTIO_CONSOLE_CONSOLE_PORT, 1));
+                } else {
+                    let mut msg = control_msg(id, VIRTIO_CONSOLE_PORT_NAME, 1);
+                    msg.extend_from_slice(port_name(id).as_bytes());
+                    self.pending.push_back(msg);
+                }
+                self.pending
+                    .push_back(control_msg(id, VIRTIO_CONSOLE_PORT_OPEN, 1));
+            }
+            VIRTIO_CONSOLE_PORT_OPEN => {
+                debug!("console port {} opened by guest: {}", id, value == 1);
+            }
+            _ => debug!("ignore console control event {}", event),
+        }
+    }
+
+    /// Queues a resize of the console `port`, followed by `struct { le16 rows; le16 cols; }`.
+    pub fn resize(&mut self, port: u32, cols: u16, rows: u16) {
+        let mut msg = control_msg(port, VIRTIO_CONSOLE_RESIZE, 0);
+        msg.extend_from_slice(&rows.to_le_bytes());
+        msg.extend_from_slice(&cols.to_le_bytes());
+        self.pending.push_back(msg);
+    }
+
+    /// Processes the messages of the driver.
+    pub fn process_txq<S: SignalUsedQueue>(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        driver_notify: &S,
+    ) -> result::Result<(), Error> {
+        loop {
+            self.txq.disable_notification(mem)?;
+
+            while let Some(mut chain) = self.txq.iter(mem)?.next() {
+                let mut buf = [0u8; CONTROL_MSG_SIZE];
+                match chain.next() {
+                    Some(desc) if desc.len() as usize >= CONTROL_MSG_SIZE => {
+                        chain.memory().read_slice(&mut buf, desc.addr())?;
+                        let id = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+                        let event = u16::from_le_bytes([buf[4], buf[5]]);
+                        let value = u16::from_le_bytes([buf[6], buf[7]]);
+                        self.handle_msg(id, event, value);
+                    }
+                    _ => warn!("console control message too short"),
+                }
+                self.txq.add_used(mem, chain.head_index(), 0)?;
+
+                if self.txq.needs_notification(mem)? {
+                    driver_notify.signal_used_queue(CONTROL_TX_QUEUE_IDX);
+                }
+            }
+
+            if !self.txq.enable_notification(mem)? {
+                break;
+            }
+        }
+        self.process_rxq(mem, driver_notify)
+    }
+
+    /// Sends pending messages to the driver as long as it provides buffers.
+    pub fn process_rxq<S: SignalUsedQueue>(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        driver_notify: &S,
+    ) -> result::Result<(), Error> {
+        while let Some(msg) = self.pending.front() {
+            let mut chain = match self.rxq.iter(mem)?.next() {
+                Some(chain) => chain,
+                // we get notified through rx_fd when the driver adds new buffers
+                None => break,
+            };
+            let mut len = 0;
+            match chain.next() {
+                Some(desc) if desc.len() as usize >= msg.len() => {
+                    chain.memory().write_slice(msg, desc.addr())?;
+                    len = msg.len();
+                }
+                _ => warn!("console control buffer too small, dropping message"),
+            }
+            self.rxq.add_used(mem, chain.head_index(), len as u32)?;
+            self.pending.pop_front();
+
+            if self.rxq.needs_notification(mem)? {
+                driver_notify.signal_used_queue(CONTROL_RX_QUEUE_IDX);
+            }
+        }
+        Ok(())
+    }
+}
@@ -3,16 +3,18 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
 use std::borrow::{Borrow, BorrowMut};
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::Write;
 use std::ops::DerefMut;
-use std::path::PathBuf;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
 use event_manager::{MutEventSubscriber, RemoteEndpoint, Result as EvmgrResult, SubscriberId};
+use virtio_device::VirtioDeviceType;
 use virtio_device::{VirtioConfig, VirtioDeviceActions, VirtioMmioDevice, VirtioQueueNotifiable};
-use virtio_device::{VirtioDevice, VirtioDeviceType};
 use virtio_queue::Queue;
 use virtio_queue::QueueT;
 use vm_device::bus::MmioAddress;
@@ -21,24 +23,73 @@ use vm_device::{DeviceMmio, MutDeviceMmio};
 use vm_memory::GuestMemoryMmap;
 use vmm_sys_util::eventfd::EventFd;
 
-use crate::devices::use_ioregionfd;
-use crate::devices::virtio::console::log_handler::LogQueueHandler;
-use crate::devices::virtio::console::VIRTIO_CONSOLE_F_SIZE;
+use crate::devices::virtio::console::control::ControlQueues;
+use crate::devices::virtio::console::log_handler::{LogQueueHandler, Port, Scrollback};
+use crate::devices::virtio::console::recorder::{Recorder, RecordingWriter};
+use crate::devices::virtio::console::{
+    terminal_size, CONSOLE_COLS, CONSOLE_ROWS, MAX_CONSOLE_PORTS, VIRTIO_CONSOLE_F_MULTIPORT,
+    VIRTIO_CONSOLE_F_SIZE,
+};
 use crate::devices::virtio::features::{
     VIRTIO_F_IN_ORDER, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_VERSION_1,
 };
-use crate::devices::virtio::{IrqAckHandler, MmioConfig, SingleFdSignalQueue, QUEUE_MAX_SIZE};
+use crate::devices::virtio::interrupt::DeviceInterrupt;
+use crate::devices::virtio::pci::{Transport, VirtioPciDevice};
+use crate::devices::virtio::{
+    reset_virtio_config, update_config_space, IrqAckHandler, MmioConfig, SingleFdSignalQueue,
+};
 use crate::devices::MaybeIoRegionFd;
 use crate::kvm::hypervisor::{
     ioevent::IoEvent, ioregionfd::IoRegionFd, userspaceioeventfd::UserspaceIoEventFd,
 };
+use crate::result::VmshError;
 
 //use super::queue_handler::QueueHandler;
 use super::{build_config_space, ConsoleArgs, Error, Result, CONSOLE_DEVICE_ID};
-use simple_error::{map_err_with, SimpleError};
+use simple_error::map_err_with;
 
 pub(super) const RX_QUEUE_IDX: u16 = 0;
 pub(super) const TX_QUEUE_IDX: u16 = 1;
+pub(super) const CONTROL_RX_QUEUE_IDX: u16 = 2;
+pub(super) const CONTROL_TX_QUEUE_IDX: u16 = 3;
+
+// Port 0 uses the first queue pair, followed by the control queues and the queue pairs of the
+// other ports.
+pub(super) fn rx_queue_idx(port: u32) -> u16 {
+    if port == 0 {
+        RX_QUEUE_IDX
+    } else {
+        2 + 2 * port as u16
+    }
+}
+
+pub(super) fn tx_queue_idx(port: u32) -> u16 {
+    rx_queue_idx(port) + 1
+}
+
+/// Returns the port of a (non-control) queue and whether it is the transmit queue.
+pub(super) fn queue_port(queue_idx: u16) -> (u32, bool) {
+    let port = if queue_idx < CONTROL_RX_QUEUE_IDX {
+        0
+    } else {
+        (queue_idx as u32 - 2) / 2
+    };
+    (port, queue_idx % 2 == 1)
+}
+
+fn open_pts(pts: &Path) -> Result<(File, File)> {
+    let console_in = map_err_with!(
+        OpenOptions::new().read(true).open(pts),
+        "could not open read console"
+    )
+    .map_err(|e| Error::Simple(e.into()))?;
+    let console_out = map_err_with!(
+        OpenOptions::new().write(true).open(pts),
+        "could not open write console"
+    )
+    .map_err(|e| Error::Simple(e.into()))?;
+    Ok((console_in, console_out))
+}
 
 pub struct Console {
     virtio_cfg: VirtioConfig<Queue>,
@@ -49,14 +100,27 @@ pub struct Console {
     pub ioregionfd: Option<IoRegionFd>,
     pub uioefd: UserspaceIoEventFd,
     mem: Arc<GuestMemoryMmap>,
-    tx_fd: Option<IoEvent>,
+    queue_size: u16,
+    /// transmit ioevents, one per port. Like all ioevents they are shared with the queue
+    /// handler and stay registered when the driver resets the device.
+    tx_fds: Vec<Arc<IoEvent>>,
+    /// receive and transmit ioevents of the control queues
+    control_fds: Option<(Arc<IoEvent>, Arc<IoEvent>)>,
     /// only used when ioregionfd != None
     sub_id: Option<SubscriberId>,
     pts: Option<PathBuf>,
-
-    // Before resetting we return the handler to the mmio thread for cleanup
-    #[allow(dead_code)]
-    handler: Option<Arc<Mutex<dyn MutEventSubscriber + Send>>>,
+    /// `pts` opened to query its size, stdout is used if not set
+    terminal: Option<File>,
+    /// columns and rows currently advertised to the guest
+    size: (u16, u16),
+    /// pseudoterminals of the ports after the console
+    ports: Vec<PathBuf>,
+    /// shared with the queue handler, which records input and output
+    recorder: Option<Arc<Mutex<Recorder>>>,
+    /// output of the console port, kept across resets of the device
+    scrollback: Option<Arc<Mutex<Scrollback>>>,
+    /// set while the device is activated, to send resize messages on the control queue
+    log_handler: Option<Arc<Mutex<LogQueueHandler<SingleFdSignalQueue>>>>,
 }
 
 impl Console {
@@ -67,40 +131,74 @@ impl Console {
         B: DerefMut,
         B::Target: MmioManager<D = Arc<dyn DeviceMmio + Send + Sync>>,
     {
+        let nr_ports = 1 + args.ports.len();
+        if nr_ports > MAX_CONSOLE_PORTS {
+            return Err(Error::Simple(VmshError::from(format!(
+                "console supports at most {} ports, got {}",
+                MAX_CONSOLE_PORTS, nr_ports
+            ))));
+        }
+        let multiport = nr_ports > 1;
+
         // The queue handling logic for this device uses the buffers in order, so we enable the
         // corresponding feature as well.
-        let device_features = 1 << VIRTIO_F_VERSION_1
+        let mut device_features = 1 << VIRTIO_F_VERSION_1
             | 1 << VIRTIO_F_IN_ORDER
             | 1 << VIRTIO_F_RING_EVENT_IDX
             | 1 << VIRTIO_CONSOLE_F_SIZE;
+        if multiport {
+            device_features |= 1 << VIRTIO_CONSOLE_F_MULTIPORT;
+        }
 
-        // A console device has two queue.
-        let queues = vec![
-            Queue::new(QUEUE_MAX_SIZE).map_err(Error::QueueCreation)?,
-            Queue::new(QUEUE_MAX_SIZE).map_err(Error::QueueCreation)?,
-        ];
+        // A console device has two queues per port and two control queues if it has more than
+        // one port.
+        let num_queues = if multiport { 2 * (nr_ports + 1) } else { 2 };
+        let queues = (0..num_queues)
+            .map(|_| Queue::new(args.common.queue_size).map_err(Error::QueueCreation))
+            .collect::<Result<Vec<_>>>()?;
+
+        let terminal = match &args.pts {
+            Some(pts) => Some(
+                map_err_with!(
+                    OpenOptions::new()
+                        .read(true)
+                        .custom_flags(libc::O_NOCTTY)
+                        .open(pts),
+                    "could not open {}",
+                    pts.display()
+                )
+                .map_err(|e| Error::Simple(e.into()))?,
+            ),
+            None => None,
+        };
+        let terminal_fd = terminal
+            .as_ref()
+            .map_or(libc::STDOUT_FILENO, |f| f.as_raw_fd());
+        let size = terminal_size(terminal_fd).unwrap_or((CONSOLE_COLS, CONSOLE_ROWS));
 
-        let config_space = build_config_space();
+        let config_space = build_config_space(nr_ports as u32, size.0, size.1);
         let virtio_cfg = VirtioConfig::new(device_features, queues, config_space);
 
         // Used to send notifications to the driver.
-        log::debug!("register irqfd on gsi {}", args.common.mmio_cfg.gsi);
-        let irqfd = Arc::new(
-            args.common
-                .vmm
-                .irqfd(args.common.mmio_cfg.gsi)
-                .map_err(Error::Simple)?,
-        );
+        let DeviceInterrupt { irqfd, resamplefd } = args
+            .common
+            .interrupts
+            .connect(&args.common.mmio_cfg)
+            .map_err(Error::Simple)?;
 
         let mmio_cfg = args.common.mmio_cfg;
 
-        let irq_ack_handler = Arc::new(Mutex::new(IrqAckHandler::new(
-            virtio_cfg.interrupt_status.clone(),
-            Arc::clone(&irqfd),
-        )));
+        let irq_ack_handler = Arc::new(Mutex::new(
+            IrqAckHandler::new(
+                virtio_cfg.interrupt_status.clone(),
+                Arc::clone(&irqfd),
+                resamplefd,
+            )
+            .map_err(Error::Simple)?,
+        ));
 
         let mut ioregionfd = None;
-        if use_ioregionfd() {
+        if args.common.vmm.use_ioregionfd() {
             ioregionfd = Some(
                 args.common
                     .vmm
@@ -112,16 +210,41 @@ impl Console {
         let pts = args.pts;
         log::info!("pts is {:?}", pts);
 
+        let recorder = match &args.record {
+            Some(path) => Some(Arc::new(Mutex::new(
+                Recorder::new(path, size.0, size.1).map_err(|e| Error::Simple(e.into()))?,
+            ))),
+            None => None,
+        };
+
         //let rx_fd = IoEvent::register(&self.vmm, &mut self.uioefd, &self.mmio_cfg, RX_QUEUE_IDX as u64)
         //.map_err(Error::Simple)?;
         let mut uioefd = UserspaceIoEventFd::default();
-        let tx_fd = IoEvent::register(
-            &args.common.vmm,
-            &mut uioefd,
-            &mmio_cfg,
-            TX_QUEUE_IDX as u64,
-        )
-        .map_err(Error::Simple)?;
+        let tx_fds = (0..nr_ports)
+            .map(|port| {
+                IoEvent::register(
+                    &args.common.vmm,
+                    &mut uioefd,
+                    &mmio_cfg,
+                    tx_queue_idx(port as u32) as u64,
+                )
+                .map(Arc::new)
+                .map_err(Error::Simple)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let control_fds = if multiport {
+            let mut register = |idx: u16| {
+                IoEvent::register(&args.common.vmm, &mut uioefd, &mmio_cfg, idx as u64)
+                    .map(Arc::new)
+                    .map_err(Error::Simple)
+            };
+            Some((
+                register(CONTROL_RX_QUEUE_IDX)?,
+                register(CONTROL_TX_QUEUE_IDX)?,
+            ))
+        } else {
+            None
+        };
 
         let console = Arc::new(Mutex::new(Console {
             virtio_cfg,
@@ -131,11 +254,22 @@ impl Console {
             irqfd,
             ioregionfd,
             mem: Arc::clone(&args.common.mem),
-            tx_fd: Some(tx_fd),
+            queue_size: args.common.queue_size,
+            tx_fds,
+            control_fds,
             uioefd,
             sub_id: None,
-            handler: None,
             pts,
+            terminal,
+            size,
+            ports: args.ports,
+            recorder,
+            scrollback: if args.scrollback > 0 {
+                Some(Arc::new(Mutex::new(Scrollback::new(args.scrollback))))
+            } else {
+                None
+            },
+            log_handler: None,
         }));
 
         // Register the device on the MMIO bus.
@@ -164,45 +298,94 @@ impl Console {
         };
 
         let console_in;
-        let console_out: Box<dyn Write + Send>;
+        let mut console_out: Box<dyn Write + Send>;
         match &self.pts {
             Some(pts) => {
-                console_in = Some(
-                    map_err_with!(
-                        OpenOptions::new().read(true).open(pts),
-                        "could not open read console"
-                    )
-                    .map_err(Error::Simple)?,
-                );
-                console_out = Box::new(
-                    map_err_with!(
-                        OpenOptions::new().write(true).open(pts),
-                        "could not open write console"
-                    )
-                    .map_err(Error::Simple)?,
-                );
+                let (read, write) = open_pts(pts)?;
+                console_in = Some(read);
+                console_out = Box::new(write);
             }
             None => {
                 console_in = None;
                 console_out = Box::new(io::stdout());
             }
         };
+        // the recording continues if the driver resets and activates the device again
+        if let Some(recorder) = &self.recorder {
+            console_out = Box::new(RecordingWriter {
+                inner: console_out,
+                recorder: Arc::clone(recorder),
+            });
+        }
 
-        let rxq = self.virtio_cfg.queues.remove(RX_QUEUE_IDX.into());
-        let txq = self.virtio_cfg.queues.remove(RX_QUEUE_IDX.into());
+        // Without VIRTIO_CONSOLE_F_MULTIPORT the driver only uses the console port.
+        let multiport = self.virtio_cfg.driver_features & (1 << VIRTIO_CONSOLE_F_MULTIPORT) != 0;
+        if !multiport && !self.ports.is_empty() {
+            log::warn!("console driver does not support multiple ports, ignore additional ports");
+        }
+        let mut tx_fds = self.tx_fds.iter().cloned();
+        let tx_fd = match tx_fds.next() {
+            Some(tx_fd) => tx_fd,
+            None => return Err(Error::Simple(VmshError::from("no tx_fd set"))),
+        };
+        let mut queues = self
+            .virtio_cfg
+            .queues
+            .drain(..)
+            .map(Some)
+            .collect::<Vec<_>>();
+        let mut take_queue = |idx: u16| {
+            queues
+                .get_mut(idx as usize)
+                .and_then(Option::take)
+                .ok_or_else(|| Error::Simple(VmshError::from(format!("no queue {}", idx))))
+        };
+
+        let mut ports = vec![Port {
+            tx_fd,
+            rxq: take_queue(RX_QUEUE_IDX)?,
+            txq: take_queue(TX_QUEUE_IDX)?,
+            console_out,
+            console_in,
+            scrollback: self.scrollback.clone(),
+            recorder: self.recorder.clone(),
+        }];
+
+        let mut control = None;
+        if multiport {
+            let (rx_fd, tx_fd) = match self.control_fds.clone() {
+                Some(fds) => fds,
+                None => return Err(Error::Simple(VmshError::from("no control fds set"))),
+            };
+            control = Some(ControlQueues::new(
+                rx_fd,
+                tx_fd,
+                take_queue(CONTROL_RX_QUEUE_IDX)?,
+                take_queue(CONTROL_TX_QUEUE_IDX)?,
+                (self.ports.len() + 1) as u32,
+            ));
+            for (id, (pts, tx_fd)) in self.ports.iter().zip(tx_fds).enumerate() {
+                let id = id as u32 + 1;
+                let (console_in, console_out) = open_pts(pts)?;
+                ports.push(Port {
+                    tx_fd,
+                    rxq: take_queue(rx_queue_idx(id))?,
+                    txq: take_queue(tx_queue_idx(id))?,
+                    console_out: Box::new(console_out),
+                    console_in: Some(console_in),
+                    scrollback: None,
+                    recorder: None,
+                });
+            }
+        }
 
         let handler = Arc::new(Mutex::new(LogQueueHandler {
             driver_notify,
-            tx_fd: match self.tx_fd.take() {
-                Some(tx_fd) => tx_fd,
-                None => return Err(Error::Simple(SimpleError::new("no tx_fd set"))),
-            },
+            ports,
+            control,
             mem: Arc::clone(&self.mem),
-            rxq,
-            txq,
-            console_out,
-            console_in,
         }));
+        self.log_handler = Some(Arc::clone(&handler));
 
         // Register the queue handler with the `EventManager`. We record the `sub_id`
         // (and/or keep a handler clone) to remove the subscriber when resetting the device
@@ -223,17 +406,99 @@ impl Console {
         Ok(())
     }
     fn _reset(&mut self) -> Result<()> {
-        // we remove the handler here, since we need to free up the ioeventfd resources
-        // in the mmio thread rather the eventmanager thread.
+        self.log_handler = None;
+        // Once removed, the handler no longer processes the queues and can be dropped, which
+        // closes the pseudoterminals it opened. The ioeventfds stay registered with the
+        // hypervisor for the next activation.
         if let Some(sub_id) = self.sub_id.take() {
-            let handler = self
-                .endpoint
+            self.endpoint
                 .call_blocking(move |mgr| mgr.remove_subscriber(sub_id))
                 .map_err(|e| {
                     log::warn!("{}", e);
                     Error::Endpoint(e)
                 })?;
-            self.handler = Some(handler);
+        }
+        // notifications of the old queues must not be processed by the next handler
+        for fd in self.tx_fds.iter() {
+            let _ = fd.read();
+        }
+        let num_queues = match &self.control_fds {
+            Some((rx_fd, tx_fd)) => {
+                let _ = rx_fd.read();
+                let _ = tx_fd.read();
+                2 * (self.tx_fds.len() + 1)
+            }
+            None => 2,
+        };
+        reset_virtio_config(&mut self.virtio_cfg, num_queues, self.queue_size)
+            .map_err(Error::QueueCreation)
+    }
+}
+
+impl Console {
+    /// true if the console is connected to a terminal whose size is forwarded to the guest
+    pub fn is_terminal(&self) -> bool {
+        self.terminal_size().is_some()
+    }
+
+    /// Recent output of the console, None if it is not kept.
+    pub fn scrollback(&self) -> Option<Arc<Mutex<Scrollback>>> {
+        self.scrollback.clone()
+    }
+
+    fn terminal_size(&self) -> Option<(u16, u16)> {
+        let fd = self
+            .terminal
+            .as_ref()
+            .map_or(libc::STDOUT_FILENO, |f| f.as_raw_fd());
+        terminal_size(fd)
+    }
+
+    /// Tells the guest about the new size if the terminal was resized. With multiple ports the
+    /// driver expects a resize message on the control queue, otherwise it re-reads the
+    /// configuration space after a config change interrupt.
+    pub fn update_size(&mut self) -> Result<()> {
+        let (cols, rows) = match self.terminal_size() {
+            Some(size) if size != self.size => size,
+            _ => return Ok(()),
+        };
+        log::debug!("console resized to {}x{}", cols, rows);
+        self.size = (cols, rows);
+        if let Some(recorder) = &self.recorder {
+            let res = match recorder.lock() {
+                Ok(mut recorder) => recorder.record_resize(cols, rows),
+                Err(_) => Ok(()),
+            };
+            if let Err(e) = res {
+                log::warn!("cannot record console resize: {}", e);
+            }
+        }
+        // struct virtio_console_config starts with cols and rows
+        let mut size = cols.to_le_bytes().to_vec();
+        size.extend_from_slice(&rows.to_le_bytes());
+        let changed = update_config_space(&mut self.virtio_cfg, 0, &size).map_err(Error::Simple)?;
+        if !changed || !self.virtio_cfg.device_activated {
+            return Ok(());
+        }
+
+        if self.virtio_cfg.driver_features & (1 << VIRTIO_CONSOLE_F_MULTIPORT) != 0 {
+            if let Some(handler) = &self.log_handler {
+                let mut handler = map_err_with!(handler.lock(), "cannot lock console handler")
+                    .map_err(|e| Error::Simple(e.into()))?;
+                handler.resize(cols, rows).map_err(|e| {
+                    Error::Simple(VmshError::from(format!(
+                        "cannot send console resize: {:?}",
+                        e
+                    )))
+                })?;
+            }
+        } else {
+            SingleFdSignalQueue {
+                irqfd: Arc::clone(&self.irqfd),
+                interrupt_status: self.virtio_cfg.interrupt_status.clone(),
+                ack_handler: Arc::clone(&self.irq_ack_handler),
+            }
+            .signal_config_change();
         }
         Ok(())
     }
@@ -279,29 +544,54 @@ impl VirtioDeviceActions for Console {
     }
 
     fn reset(&mut self) -> Result<()> {
-        self.set_device_status(0);
-        self._reset()?;
-        Ok(())
+        let ret = self._reset();
+        if let Err(ref e) = ret {
+            log::warn!("failed to reset console device: {:?}", e);
+        }
+        ret
     }
 }
 
 impl VirtioQueueNotifiable for Console {
     fn queue_notify(&mut self, val: u32) {
-        if use_ioregionfd() {
-            self.uioefd.queue_notify(val);
-            log::trace!("queue_notify {}", val);
-        }
+        // with ioeventfds in KVM, only notifications replayed by vmsh end up here
+        self.uioefd.queue_notify(val);
+        log::trace!("queue_notify {}", val);
     }
 }
 
 impl VirtioMmioDevice for Console {}
 
+impl VirtioPciDevice for Console {}
+
 impl MutDeviceMmio for Console {
     fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
-        self.read(offset, data);
+        match self.mmio_cfg.transport {
+            Transport::Mmio => self.read(offset, data),
+            Transport::Pci => self.pci_read(offset, data),
+        }
     }
 
     fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
-        self.write(offset, data);
+        match self.mmio_cfg.transport {
+            Transport::Mmio => self.write(offset, data),
+            Transport::Pci => self.pci_write(offset, data),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_port_queues() {
+        assert_eq!((rx_queue_idx(0), tx_queue_idx(0)), (0, 1));
+        assert_eq!((rx_queue_idx(1), tx_queue_idx(1)), (4, 5));
+        assert_eq!((rx_queue_idx(3), tx_queue_idx(3)), (8, 9));
+        for port in 0..MAX_CONSOLE_PORTS as u32 {
+            assert_eq!(queue_port(rx_queue_idx(port)), (port, false));
+            assert_eq!(queue_port(tx_queue_idx(port)), (port, true));
+        }
     }
 }
@@ -2,10 +2,12 @@
 // Author of further modifications: Peter Okelmann
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
+use std::collections::VecDeque;
 use std::fs::File;
 use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
 use std::result;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use event_manager::EventOps;
 use event_manager::EventSet;
@@ -16,9 +18,14 @@ use virtio_queue::Queue;
 use virtio_queue::{QueueOwnedT, QueueT};
 use vm_memory::{self, Bytes, GuestMemoryMmap};
 
-use super::device::{RX_QUEUE_IDX, TX_QUEUE_IDX};
+use super::control::ControlQueues;
+use super::device::{
+    queue_port, rx_queue_idx, tx_queue_idx, CONTROL_RX_QUEUE_IDX, CONTROL_TX_QUEUE_IDX,
+};
+use super::recorder::Recorder;
 use crate::devices::virtio::SignalUsedQueue;
 use crate::kvm::hypervisor::ioevent::IoEvent;
+use crate::metrics::{self, METRICS};
 
 #[derive(Debug)]
 pub enum Error {
@@ -38,74 +45,140 @@ impl From<virtio_queue::Error> for Error {
     }
 }
 
-pub(crate) struct LogQueueHandler<S: SignalUsedQueue> {
-    pub tx_fd: IoEvent,
-    pub driver_notify: S,
-    #[allow(unused)]
+/// The last `capacity` bytes of console output, replayed to clients that connect later (see
+/// `vmsh control <pid> new-console`) so they see what was written while nobody was reading.
+pub struct Scrollback {
+    buf: VecDeque<u8>,
+    capacity: usize,
+    /// total number of bytes pushed, the last one is at offset `written - 1`
+    written: u64,
+}
+
+impl Scrollback {
+    pub fn new(capacity: usize) -> Scrollback {
+        Scrollback {
+            buf: VecDeque::with_capacity(capacity),
+            capacity,
+            written: 0,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.written += data.len() as u64;
+        let data = &data[data.len().saturating_sub(self.capacity)..];
+        let overflow = (self.buf.len() + data.len()).saturating_sub(self.capacity);
+        self.buf.drain(..overflow);
+        self.buf.extend(data);
+    }
+
+    /// Buffered output before offset `until`, i.e. everything a reader consumed so far that is
+    /// still buffered.
+    pub fn replay(&self, until: u64) -> Vec<u8> {
+        let start = self.written - self.buf.len() as u64;
+        let end = until.clamp(start, self.written);
+        self.buf
+            .iter()
+            .take((end - start) as usize)
+            .copied()
+            .collect()
+    }
+}
+
+/// A port of the console device. Port 0 is the console, additional ports only exist with
+/// VIRTIO_CONSOLE_F_MULTIPORT.
+pub(crate) struct Port {
+    pub tx_fd: Arc<IoEvent>,
     pub rxq: Queue,
     pub txq: Queue,
     pub console_out: Box<dyn Write + Send>,
     pub console_in: Option<File>,
+    /// keeps the output of the console port even if writing it to `console_out` fails
+    pub scrollback: Option<Arc<Mutex<Scrollback>>>,
+    /// records the input of the console port, the output is recorded by `console_out`
+    pub recorder: Option<Arc<Mutex<Recorder>>>,
+}
+
+pub(crate) struct LogQueueHandler<S: SignalUsedQueue> {
+    pub driver_notify: S,
+    pub ports: Vec<Port>,
+    pub control: Option<ControlQueues>,
     pub mem: Arc<GuestMemoryMmap>,
 }
 
-impl<S> LogQueueHandler<S>
-where
-    S: SignalUsedQueue,
-{
-    fn handle_error<Msg: AsRef<str>>(&self, s: Msg, ops: &mut EventOps) {
-        error!("{}", s.as_ref());
-        ops.remove(Events::empty(&self.tx_fd))
-            .expect("Failed to remove tx ioevent");
-    }
+fn handle_error<Msg: AsRef<str>, T: AsRawFd>(s: Msg, source: &T, ops: &mut EventOps) {
+    error!("{}", s.as_ref());
+    ops.remove(Events::empty(source))
+        .expect("Failed to remove console event");
+}
 
-    pub fn process_txq(&mut self) -> result::Result<(), Error> {
+impl Port {
+    pub fn process_txq<S: SignalUsedQueue>(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        driver_notify: &S,
+        queue_idx: u16,
+    ) -> result::Result<(), Error> {
         // To see why this is done in a loop, please look at the `Queue::enable_notification`
         // comments in `vm_virtio`.
         loop {
-            self.txq.disable_notification(self.mem.as_ref())?;
+            self.txq.disable_notification(mem)?;
 
             // Guest console sends (tx), we write to self.console_out fd
-            while let Some(mut chain) = self.txq.iter(self.mem.as_ref())?.next() {
+            while let Some(mut chain) = self.txq.iter(mem)?.next() {
                 log::debug!("process_chain");
 
                 let mut i = 0;
                 while let Some(desc) = chain.next() {
                     log::debug!("chain.next()");
                     let mem = chain.memory();
-                    if let Err(e) =
-                        mem.write_to(desc.addr(), &mut self.console_out, desc.len() as usize)
-                    {
-                        error!("error logging console tx (stdout/err): {}", e)
+                    let mut data = vec![0u8; desc.len() as usize];
+                    if let Err(e) = mem.read_slice(&mut data, desc.addr()) {
+                        error!("error reading console tx: {}", e);
+                        i += 1;
+                        continue;
+                    }
+                    if let Some(scrollback) = &self.scrollback {
+                        match scrollback.lock() {
+                            Ok(mut scrollback) => scrollback.push(&data),
+                            Err(_) => error!("cannot lock console scrollback"),
+                        }
+                    }
+                    match self.console_out.write_all(&data) {
+                        Ok(()) => metrics::add(&METRICS.console_tx_bytes, data.len() as u64),
+                        Err(e) => error!("error logging console tx (stdout/err): {}", e),
                     }
                     i += 1;
                 }
-                self.txq
-                    .add_used(self.mem.as_ref(), chain.head_index(), i as u32)?;
+                self.txq.add_used(mem, chain.head_index(), i as u32)?;
 
-                if self.txq.needs_notification(self.mem.as_ref())? {
+                if self.txq.needs_notification(mem)? {
                     log::debug!("notification needed: yes");
-                    self.driver_notify.signal_used_queue(0);
+                    driver_notify.signal_used_queue(queue_idx);
                 } else {
                     log::debug!("notification needed: no");
                 }
             }
 
-            if !self.txq.enable_notification(self.mem.as_ref())? {
+            if !self.txq.enable_notification(mem)? {
                 break;
             }
         }
         Ok(())
     }
 
-    pub fn process_rxq(&mut self) -> result::Result<(), Error> {
+    pub fn process_rxq<S: SignalUsedQueue>(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        driver_notify: &S,
+        queue_idx: u16,
+    ) -> result::Result<(), Error> {
         // To see why this is done in a loop, please look at the `Queue::enable_notification`
         // comments in `vm_virtio`.
         //loop {
         log::debug!("loop");
-        self.rxq.disable_notification(self.mem.as_ref())?;
+        self.rxq.disable_notification(mem)?;
 
-        if let Some(mut chain) = self.rxq.iter(self.mem.as_ref())?.next() {
+        if let Some(mut chain) = self.rxq.iter(mem)?.next() {
             // Guest console reads (rx), we read from self.console_in fd
             log::debug!("process_chain");
             const LEN: usize = 128;
@@ -130,22 +203,31 @@ where
                 };
                 let buf = &mut buf[..count];
                 log::debug!("buf {:?} count {}", buf, count);
+                if let Some(recorder) = &self.recorder {
+                    let res = match recorder.lock() {
+                        Ok(mut recorder) => recorder.record_input(buf),
+                        Err(_) => Ok(()),
+                    };
+                    if let Err(e) = res {
+                        log::warn!("cannot record console input: {}", e);
+                    }
+                }
                 if let Err(e) = mem.write_slice(buf, desc.addr()) {
                     error!("error logging console rx (stdin): {}", e)
                 }
             }
-            self.rxq
-                .add_used(self.mem.as_ref(), chain.head_index(), count as u32)?;
+            metrics::add(&METRICS.console_rx_bytes, count as u64);
+            self.rxq.add_used(mem, chain.head_index(), count as u32)?;
 
-            if self.rxq.needs_notification(self.mem.as_ref())? {
+            if self.rxq.needs_notification(mem)? {
                 log::debug!("notification needed: yes");
-                self.driver_notify.signal_used_queue(0);
+                driver_notify.signal_used_queue(queue_idx);
             } else {
                 log::debug!("notification needed: no");
             }
         }
 
-        if !self.rxq.enable_notification(self.mem.as_ref())? {
+        if !self.rxq.enable_notification(mem)? {
             log::debug!("loop break");
             //break;
         }
@@ -154,46 +236,141 @@ where
     }
 }
 
+impl<S: SignalUsedQueue> LogQueueHandler<S> {
+    /// Sends the new size of the console port to a multiport driver.
+    pub fn resize(&mut self, cols: u16, rows: u16) -> result::Result<(), Error> {
+        if let Some(control) = &mut self.control {
+            control.resize(0, cols, rows);
+            control.process_rxq(&self.mem, &self.driver_notify)?;
+        }
+        Ok(())
+    }
+}
+
 impl<S: SignalUsedQueue> MutEventSubscriber for LogQueueHandler<S> {
     fn process(&mut self, events: Events, ops: &mut EventOps) {
+        let _span = tracing::trace_span!("queue-handler", device = "console", data = events.data())
+            .entered();
         if events.event_set() != EventSet::IN {
-            self.handle_error("Unexpected event_set", ops);
+            error!("Unexpected event_set");
             return;
         }
 
-        match events.data() as u16 {
-            RX_QUEUE_IDX => {
-                if let Err(e) = self.process_rxq() {
-                    self.handle_error(format!("Process rx error {:?}", e), ops);
+        let queue_idx = events.data() as u16;
+        let mem = self.mem.as_ref();
+        if queue_idx == CONTROL_RX_QUEUE_IDX || queue_idx == CONTROL_TX_QUEUE_IDX {
+            let control = match &mut self.control {
+                Some(control) => control,
+                None => {
+                    error!("Unexpected data");
+                    return;
                 }
-            }
-            TX_QUEUE_IDX => {
-                if self.tx_fd.read().is_err() {
-                    self.handle_error("Tx ioevent read", ops);
+            };
+            if queue_idx == CONTROL_RX_QUEUE_IDX {
+                if control.rx_fd.read().is_err() {
+                    error!("Control rx ioevent read");
                 }
-                if let Err(e) = self.process_txq() {
-                    self.handle_error(format!("Process tx error {:?}", e), ops);
+                if let Err(e) = control.process_rxq(mem, &self.driver_notify) {
+                    handle_error(
+                        format!("Process control rx error {:?}", e),
+                        control.rx_fd.as_ref(),
+                        ops,
+                    );
+                }
+            } else {
+                if control.tx_fd.read().is_err() {
+                    error!("Control tx ioevent read");
                 }
+                if let Err(e) = control.process_txq(mem, &self.driver_notify) {
+                    handle_error(
+                        format!("Process control tx error {:?}", e),
+                        control.tx_fd.as_ref(),
+                        ops,
+                    );
+                }
+            }
+            return;
+        }
+
+        let (port_id, is_tx) = queue_port(queue_idx);
+        let port = match self.ports.get_mut(port_id as usize) {
+            Some(port) => port,
+            None => {
+                error!("Unexpected data");
+                return;
+            }
+        };
+        if is_tx {
+            if port.tx_fd.read().is_err() {
+                error!("Tx ioevent read");
+            }
+            if let Err(e) = port.process_txq(mem, &self.driver_notify, queue_idx) {
+                handle_error(
+                    format!("Process tx error {:?}", e),
+                    port.tx_fd.as_ref(),
+                    ops,
+                );
+            }
+        } else if let Err(e) = port.process_rxq(mem, &self.driver_notify, queue_idx) {
+            match &port.console_in {
+                Some(console) => handle_error(format!("Process rx error {:?}", e), console, ops),
+                None => error!("Process rx error {:?}", e),
             }
-            _ => self.handle_error("Unexpected data", ops),
         }
     }
 
     fn init(&mut self, ops: &mut EventOps) {
-        if let Some(console) = &self.console_in {
+        for (id, port) in self.ports.iter().enumerate() {
+            if let Some(console) = &port.console_in {
+                ops.add(Events::with_data(
+                    console,
+                    rx_queue_idx(id as u32) as u32,
+                    EventSet::IN,
+                ))
+                .expect("Failed to register rx ioeventfd for console queue handler");
+            }
+
             ops.add(Events::with_data(
-                console,
-                RX_QUEUE_IDX as u32,
+                port.tx_fd.as_ref(),
+                tx_queue_idx(id as u32) as u32,
                 EventSet::IN,
             ))
-            .expect("Failed to register rx ioeventfd for console queue handler");
+            .expect("Failed to register tx ioeventfd for console queue handler");
         }
 
-        ops.add(Events::with_data(
-            &self.tx_fd,
-            TX_QUEUE_IDX as u32,
-            EventSet::IN,
-        ))
-        .expect("Failed to register tx ioeventfd for console queue handler");
+        if let Some(control) = &self.control {
+            ops.add(Events::with_data(
+                control.rx_fd.as_ref(),
+                CONTROL_RX_QUEUE_IDX as u32,
+                EventSet::IN,
+            ))
+            .expect("Failed to register control rx ioeventfd for console queue handler");
+            ops.add(Events::with_data(
+                control.tx_fd.as_ref(),
+                CONTROL_TX_QUEUE_IDX as u32,
+                EventSet::IN,
+            ))
+            .expect("Failed to register control tx ioeventfd for console queue handler");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scrollback_ring() {
+        let mut scrollback = Scrollback::new(4);
+        scrollback.push(b"ab");
+        assert_eq!(scrollback.replay(2), b"ab");
+        scrollback.push(b"cdef");
+        assert_eq!(scrollback.replay(6), b"cdef");
+        // the reader consumed only the first 4 bytes
+        assert_eq!(scrollback.replay(4), b"cd");
+        // older than the buffer
+        assert_eq!(scrollback.replay(1), b"");
+        scrollback.push(b"0123456789");
+        assert_eq!(scrollback.replay(u64::MAX), b"6789");
     }
 }
@@ -1,7 +1,10 @@
+mod control;
 mod device;
 mod log_handler;
+mod recorder;
 
 use std::io;
+use std::os::unix::io::RawFd;
 use std::path::PathBuf;
 
 use event_manager::Error as EvmgrError;
@@ -9,22 +12,32 @@ use vm_device::bus;
 use vmm_sys_util::errno;
 
 use crate::devices::virtio::CommonArgs;
-use simple_error::SimpleError;
+use crate::result::VmshError;
 
 pub use device::Console;
+pub use log_handler::Scrollback;
 
 /// Console device ID as defined by the standard.
 pub const CONSOLE_DEVICE_ID: u32 = 3;
 
+/// Terminal size advertised to the guest if the console is not connected to a terminal.
+pub(crate) const CONSOLE_COLS: u16 = 80;
+pub(crate) const CONSOLE_ROWS: u16 = 24;
+
 /// Does host provide console size?
 pub const VIRTIO_CONSOLE_F_SIZE: u32 = 0;
 /// Does host provide multiple ports?
-#[allow(unused)]
 pub const VIRTIO_CONSOLE_F_MULTIPORT: u32 = 1;
 /// Does host support emergency write?
 #[allow(unused)]
 pub const VIRTIO_CONSOLE_F_EMERG_WRITE: u32 = 2;
 
+/// Bytes of console output kept for clients that connect later, if not specified otherwise.
+pub const DEFAULT_SCROLLBACK: usize = 64 * 1024;
+
+/// Maximum number of ports of a console device, including the console itself.
+pub const MAX_CONSOLE_PORTS: usize = 8;
+
 #[derive(Debug)]
 pub enum Error {
     AlreadyActivated,
@@ -39,7 +52,7 @@ pub enum Error {
     RegisterIoevent(errno::Error),
     #[allow(dead_code)] // FIXME
     RegisterIrqfd(errno::Error),
-    Simple(SimpleError),
+    Simple(VmshError),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -60,38 +73,20 @@ unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
     ::std::slice::from_raw_parts((p as *const T) as *const u8, ::std::mem::size_of::<T>())
 }
 
-//fn get_winsize(term_fd: RawFd) -> winsize {
-//    use std::mem::zeroed;
-//    unsafe {
-//        let mut ws: winsize = zeroed();
-//        match libc::ioctl(term_fd, libc::TIOCGWINSZ, &mut ws) {
-//            0 => ws,
-//            _ => winsize {
-//                ws_row: 80,
-//                ws_col: 25,
-//                ws_xpixel: 0,
-//                ws_ypixel: 0,
-//            },
-//        }
-//    }
-//}
-//
-//fn resize_pty(pty_master: RawFd) {
-//    unsafe {
-//        libc::ioctl(
-//            pty_master,
-//            libc::TIOCSWINSZ,
-//            &mut get_winsize(libc::STDOUT_FILENO),
-//        );
-//    }
-//}
-
-fn build_config_space() -> Vec<u8> {
-    // FIXME think about terminal size
+/// Returns columns and rows of the terminal `fd` refers to.
+pub(crate) fn terminal_size(fd: RawFd) -> Option<(u16, u16)> {
+    let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+    match unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut ws) } {
+        0 if ws.ws_col > 0 && ws.ws_row > 0 => Some((ws.ws_col, ws.ws_row)),
+        _ => None,
+    }
+}
+
+fn build_config_space(nr_ports: u32, cols: u16, rows: u16) -> Vec<u8> {
     let config = virtio_console_config {
-        cols: 80,
-        rows: 24,
-        max_nr_ports: 2,
+        cols,
+        rows,
+        max_nr_ports: nr_ports,
         emerg_wr: 0,
     };
     unsafe { any_as_u8_slice(&config) }.to_vec()
@@ -102,4 +97,10 @@ pub struct ConsoleArgs<'a, B> {
     pub common: CommonArgs<'a, B>,
     /// None shall be interpreted as "sane default".
     pub pts: Option<PathBuf>,
+    /// Record console output as asciinema cast to this file.
+    pub record: Option<PathBuf>,
+    /// Pseudoterminals connected to additional ports. Requires a driver with multiport support.
+    pub ports: Vec<PathBuf>,
+    /// Bytes of console output kept in a `Scrollback`, 0 disables it.
+    pub scrollback: usize,
 }
@@ -0,0 +1,151 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+use simple_error::{map_err_with, SimpleError};
+
+/// Writes console output, input and resizes in the asciinema v2 format
+/// (https://docs.asciinema.org/manual/asciicast/v2/).
+pub struct Recorder {
+    file: BufWriter<File>,
+    start: Instant,
+    /// bytes of an utf-8 sequence that was split between two writes
+    pending: Vec<u8>,
+    /// the same for input
+    pending_input: Vec<u8>,
+}
+
+impl Recorder {
+    pub fn new(path: &Path, cols: u16, rows: u16) -> Result<Recorder, SimpleError> {
+        let file = map_err_with!(
+            File::create(path),
+            "cannot create recording {}",
+            path.display()
+        )?;
+        let mut file = BufWriter::new(file);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let header = json!({
+            "version": 2,
+            "width": cols,
+            "height": rows,
+            "timestamp": timestamp,
+        });
+        map_err_with!(
+            writeln!(file, "{}", header).and_then(|_| file.flush()),
+            "cannot write recording header"
+        )?;
+        Ok(Recorder {
+            file,
+            start: Instant::now(),
+            pending: vec![],
+            pending_input: vec![],
+        })
+    }
+
+    fn event(&mut self, code: &str, data: &str) -> io::Result<()> {
+        let event = json!([self.start.elapsed().as_secs_f64(), code, data]);
+        writeln!(self.file, "{}", event)?;
+        self.file.flush()
+    }
+
+    /// Records an output frame.
+    pub fn record(&mut self, buf: &[u8]) -> io::Result<()> {
+        let data = take_utf8(&mut self.pending, buf);
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.event("o", &data)
+    }
+
+    /// Records what was typed into the console, so that the recording shows who did what.
+    pub fn record_input(&mut self, buf: &[u8]) -> io::Result<()> {
+        let data = take_utf8(&mut self.pending_input, buf);
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.event("i", &data)
+    }
+
+    /// Records a new size of the terminal.
+    pub fn record_resize(&mut self, cols: u16, rows: u16) -> io::Result<()> {
+        self.event("r", &format!("{}x{}", cols, rows))
+    }
+}
+
+/// Appends `buf` to `pending` and returns the complete utf-8 sequences of it.
+fn take_utf8(pending: &mut Vec<u8>, buf: &[u8]) -> String {
+    pending.extend_from_slice(buf);
+    let valid = match std::str::from_utf8(pending) {
+        // incomplete sequence at the end: keep it for the next frame
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        _ => pending.len(),
+    };
+    let rest = pending.split_off(valid);
+    let data = String::from_utf8_lossy(pending).into_owned();
+    *pending = rest;
+    data
+}
+
+/// Forwards console output to `inner` and records it.
+pub struct RecordingWriter {
+    pub inner: Box<dyn Write + Send>,
+    pub recorder: Arc<Mutex<Recorder>>,
+}
+
+impl Write for RecordingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        let res = match self.recorder.lock() {
+            Ok(mut recorder) => recorder.record(&buf[..n]),
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "recorder poisoned")),
+        };
+        if let Err(e) = res {
+            log::warn!("cannot record console output: {}", e);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::*;
+
+    #[test]
+    fn record_events() {
+        let tmp = TempFile::new().unwrap();
+        let mut recorder = Recorder::new(tmp.as_path(), 80, 24).unwrap();
+        // "ä" split between two writes
+        recorder.record(&[b'a', 0xc3]).unwrap();
+        recorder.record(&[0xa4]).unwrap();
+        recorder.record_input(b"ls\r").unwrap();
+        recorder.record_resize(100, 30).unwrap();
+
+        let content = std::fs::read_to_string(tmp.as_path()).unwrap();
+        let lines = content
+            .lines()
+            .map(|l| serde_json::from_str::<Value>(l).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines[0]["version"], 2);
+        let events = lines[1..]
+            .iter()
+            .map(|e| (e[1].as_str().unwrap(), e[2].as_str().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            [("o", "a"), ("o", "ä"), ("i", "ls\r"), ("r", "100x30")]
+        );
+    }
+}
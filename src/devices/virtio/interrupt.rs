@@ -0,0 +1,57 @@
+//! Connects the interrupts of the devices to the guest. Devices only see irqfds, the backend
+//! decides which interrupt KVM injects when they are written to.
+
+use std::sync::Arc;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::devices::virtio::MmioConfig;
+use crate::kvm::hypervisor::Hypervisor;
+use crate::result::Result;
+
+/// The interrupt of one device.
+pub struct DeviceInterrupt {
+    pub irqfd: Arc<EventFd>,
+    /// Signaled once the guest acknowledged the interrupt, so that it can be raised again if the
+    /// device still has pending work. Not every backend supports this.
+    pub resamplefd: Option<EventFd>,
+}
+
+/// How the interrupts of the devices reach the guest.
+pub trait InterruptBackend: Send + Sync {
+    /// Connects the interrupt of the device at `mmio_cfg`.
+    fn connect(&self, mmio_cfg: &MmioConfig) -> Result<DeviceInterrupt>;
+
+    /// Another irqfd for the interrupt of the device at `mmio_cfg`, i.e. for a device that
+    /// serves each queue in its own thread.
+    fn irqfd(&self, mmio_cfg: &MmioConfig) -> Result<EventFd>;
+}
+
+/// Lines of the interrupt controller of the VM (`MmioConfig::gsi`), picked from the ones the
+/// guest does not use (see `attach::get_irq_nums`).
+///
+/// Routing the irqfds to MSIs instead would need `KVM_SET_GSI_ROUTING`, which replaces the
+/// routes of the hypervisor as well. KVM cannot report them, so we could not restore them.
+pub struct GsiBackend {
+    vmm: Arc<Hypervisor>,
+}
+
+impl GsiBackend {
+    pub fn new(vmm: Arc<Hypervisor>) -> GsiBackend {
+        GsiBackend { vmm }
+    }
+}
+
+impl InterruptBackend for GsiBackend {
+    fn connect(&self, mmio_cfg: &MmioConfig) -> Result<DeviceInterrupt> {
+        log::debug!("register irqfd on gsi {}", mmio_cfg.gsi);
+        let (irqfd, resamplefd) = self.vmm.irqfd_resample(mmio_cfg.gsi)?;
+        Ok(DeviceInterrupt {
+            irqfd: Arc::new(irqfd),
+            resamplefd,
+        })
+    }
+
+    fn irqfd(&self, mmio_cfg: &MmioConfig) -> Result<EventFd> {
+        self.vmm.irqfd(mmio_cfg.gsi)
+    }
+}
@@ -2,23 +2,39 @@
 // Author of further modifications: Peter Okelmann
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
-// We're only providing virtio over MMIO devices for now, but we aim to add PCI support as well.
+// Devices are provided over MMIO by default, or over PCI (see `pci`) when requested.
 
 pub mod block;
 pub mod console;
+pub mod interrupt;
+pub mod net;
+pub mod p9;
+pub mod pci;
+pub mod pmem;
+pub mod rng;
+pub mod vsock;
 
+use std::cmp::max;
+use std::result;
 use std::sync::atomic::{AtomicU8, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
-use crate::kvm::hypervisor::{ioeventfd::IoEventFd, Hypervisor};
+use self::interrupt::InterruptBackend;
+use self::pci::{Transport, PCI_NOTIFY_OFFSET};
+use crate::kvm::hypervisor::{ioeventfd::IoEventFd, shared_ram::SharedRam, Hypervisor};
+use crate::metrics::{self, METRICS};
 use crate::result::Result;
-use event_manager::{EventManager, MutEventSubscriber};
+use event_manager::{EventManager, EventOps, EventSet, Events, MutEventSubscriber};
 use log::error;
+use simple_error::{bail, try_with};
 
+use virtio_device::VirtioConfig;
+use virtio_queue::{Queue, QueueT};
 use vm_device::bus::MmioRange;
-use vm_memory::GuestMemoryMmap;
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
 use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::timerfd::TimerFd;
 
 // TODO: Move virtio-related defines from the local modules to the `vm-virtio` crate upstream.
 
@@ -36,19 +52,37 @@ mod features {
 // TODO: There seem to be similar semantics when the PCI transport is used with MSI-X cap
 // disabled. Let's figure out at some point if having MMIO as part of the name is necessary.
 const VIRTIO_MMIO_INT_VRING: u8 = 0x01;
+// Set when the configuration space of the device changed.
+const VIRTIO_MMIO_INT_CONFIG: u8 = 0x02;
 
 // The driver will write to the register at this offset in the MMIO region to notify the device
 // about available queue events.
 const VIRTIO_MMIO_QUEUE_NOTIFY_OFFSET: u64 = 0x50;
 
-// TODO: Make configurable for each device maybe?
-const QUEUE_MAX_SIZE: u16 = 256;
+/// Default number of descriptors per queue.
+pub const DEFAULT_QUEUE_SIZE: u16 = 256;
+/// Largest queue size allowed by the virtio specification.
+pub const MAX_QUEUE_SIZE: u16 = 32768;
+
+/// Queue sizes have to be a power of two and must not exceed `MAX_QUEUE_SIZE`.
+pub fn validate_queue_size(size: u16) -> Result<()> {
+    if !size.is_power_of_two() || size > MAX_QUEUE_SIZE {
+        bail!(
+            "queue size must be a power of two between 1 and {}, got {}",
+            MAX_QUEUE_SIZE,
+            size
+        );
+    }
+    Ok(())
+}
 
 #[derive(Copy, Clone)]
 pub struct MmioConfig {
     pub range: MmioRange,
     // The interrupt assigned to the device.
     pub gsi: u32,
+    // Whether `range` holds the virtio-mmio registers or the BAR of a virtio-pci device.
+    pub transport: Transport,
 }
 
 // These arguments are common for all virtio devices. We're always passing a mmio_cfg object
@@ -56,6 +90,8 @@ pub struct MmioConfig {
 pub struct CommonArgs<'a, B> {
     // The objects used for guest memory accesses and other operations.
     pub mem: Arc<GuestMemoryMmap>,
+    // Guest RAM that is also mapped into vmsh, used to copy large buffers without syscalls.
+    pub ram: Arc<SharedRam>,
     // Used by the devices to register ioevents and irqfds.
     pub vmm: Arc<Hypervisor>,
     // Mutable handle to the event manager the device is supposed to register with. There could be
@@ -66,6 +102,10 @@ pub struct CommonArgs<'a, B> {
     pub mmio_mgr: B,
     // The virtio MMIO device parameters (MMIO range and interrupt to be used).
     pub mmio_cfg: MmioConfig,
+    // Connects the interrupt of `mmio_cfg` to the guest.
+    pub interrupts: Arc<dyn InterruptBackend>,
+    // Maximum number of descriptors in each queue of the device.
+    pub queue_size: u16,
     // We pass a mutable reference to the kernel cmdline `String` so the device can add any
     // required arguments (i.e. for virtio over MMIO discovery). This means we need to create
     // the devices before loading he kernel cmdline into memory, but that's not a significant
@@ -89,11 +129,15 @@ pub struct SingleFdSignalQueue {
     pub ack_handler: Arc<Mutex<IrqAckHandler>>,
 }
 
-impl SignalUsedQueue for SingleFdSignalQueue {
-    fn signal_used_queue(&self, _index: u16) {
-        log::trace!("irqfd << {}", _index);
-        self.interrupt_status
-            .fetch_or(VIRTIO_MMIO_INT_VRING, Ordering::SeqCst);
+impl SingleFdSignalQueue {
+    /// Tells the driver to re-read the configuration space.
+    pub fn signal_config_change(&self) {
+        log::trace!("irqfd << config");
+        self.signal(VIRTIO_MMIO_INT_CONFIG);
+    }
+
+    fn signal(&self, status: u8) {
+        self.interrupt_status.fetch_or(status, Ordering::SeqCst);
         if let Err(e) = self.irqfd.write(1) {
             error!("Failed write to eventfd when signalling queue: {}", e);
         } else {
@@ -105,60 +149,213 @@ impl SignalUsedQueue for SingleFdSignalQueue {
     }
 }
 
-/// Note: `device::threads::EVENT_LOOP_TIMEOUT_MS` typically determines how often the irq ack
-/// timeout is handled and thus is typically the lower bound.
+impl SignalUsedQueue for SingleFdSignalQueue {
+    fn signal_used_queue(&self, _index: u16) {
+        log::trace!("irqfd << {}", _index);
+        self.signal(VIRTIO_MMIO_INT_VRING);
+    }
+}
+
+/// The timer of `IrqAckHandler` expires once this passed without an ack. Only used without
+/// resamplefd.
 const INTERRUPT_ACK_TIMEOUT: Duration = Duration::from_millis(1);
 const RESEND_RATELIMIT: Duration = Duration::from_millis(0);
 
+const RESAMPLE_DATA: u32 = 0;
+const TIMER_DATA: u32 = 1;
+
+/// Re-sends interrupts the guest did not acknowledge. With a resamplefd, KVM tells us when the
+/// guest signaled the end of the interrupt (EOI), otherwise we guess with a timeout. Either
+/// wakes up the event loop the handler is registered with.
 pub struct IrqAckHandler {
     last_sent: Instant,
     resent: Instant,
     interrupt_status: Arc<AtomicU8>,
     irqfd: Arc<EventFd>,
+    resamplefd: Option<EventFd>,
+    /// Armed for every irq sent without resamplefd
+    timer: TimerFd,
     total_sent: usize,
     total_ack_timeouted: usize,
 }
 
 impl IrqAckHandler {
-    pub fn new(interrupt_status: Arc<AtomicU8>, irqfd: Arc<EventFd>) -> Self {
-        IrqAckHandler {
+    pub fn new(
+        interrupt_status: Arc<AtomicU8>,
+        irqfd: Arc<EventFd>,
+        resamplefd: Option<EventFd>,
+    ) -> Result<Self> {
+        Ok(IrqAckHandler {
             last_sent: Instant::now(),
             resent: Instant::now(),
             interrupt_status,
             irqfd,
+            resamplefd,
+            timer: try_with!(TimerFd::new(), "cannot create irq ack timer"),
             total_sent: 0,
             total_ack_timeouted: 0,
-        }
+        })
     }
 
     /// Must be called whenever a new irq is sent for which an ack is expected.
     pub fn irq_sent(&mut self) {
         self.total_sent += 1;
+        metrics::add(&METRICS.irqs_sent, 1);
         self.last_sent = Instant::now();
+        if self.resamplefd.is_none() {
+            self.arm_timer(INTERRUPT_ACK_TIMEOUT);
+        }
     }
 
-    /// Must be called regularly to handle ack timeouts and re-send irqs.
-    pub fn handle_timeouts(&mut self) {
-        let passed = Instant::now().duration_since(self.last_sent);
-        let unacked = self.interrupt_status.load(Ordering::Acquire) != 0;
-        let ratelimit = Instant::now().duration_since(self.resent) <= RESEND_RATELIMIT;
-        if passed >= INTERRUPT_ACK_TIMEOUT && unacked && !ratelimit {
-            // interrupt timed out && has not been acked
+    fn arm_timer(&mut self, timeout: Duration) {
+        if let Err(e) = self.timer.reset(timeout, None) {
+            error!("cannot arm irq ack timer: {}", e);
+        }
+    }
+
+    /// The guest signaled the end of the interrupt.
+    fn handle_eoi(&mut self) {
+        if let Some(resamplefd) = &self.resamplefd {
+            if resamplefd.read().is_err() {
+                return;
+            }
+        }
+        // The guest finished its interrupt handler but did not see all used buffers.
+        if self.interrupt_status.load(Ordering::Acquire) != 0 {
             if let Err(e) = self.irqfd.write(1) {
                 log::error!("Failed write to eventfd when signalling queue: {}", e);
             } else {
                 self.total_ack_timeouted += 1;
-                self.resent = Instant::now();
-                log::debug!(
-                    "re-sending lost interrupt after {:.1}ms. Total lost {:.0}% ({}/{})",
-                    passed.as_micros() as f64 / 1000.0,
-                    100.0 * self.total_ack_timeouted as f64 / self.total_sent as f64,
-                    self.total_ack_timeouted,
-                    self.total_sent,
-                );
+                metrics::add(&METRICS.irq_ack_timeouts, 1);
+                log::debug!("re-sending interrupt after EOI");
+            }
+        }
+    }
+
+    /// The ack timer expired.
+    fn handle_timeout(&mut self) {
+        if self.interrupt_status.load(Ordering::Acquire) == 0 {
+            return;
+        }
+        let since_resent = Instant::now().duration_since(self.resent);
+        if since_resent <= RESEND_RATELIMIT {
+            self.arm_timer(RESEND_RATELIMIT - since_resent + Duration::from_nanos(1));
+            return;
+        }
+        // interrupt timed out && has not been acked
+        let passed = Instant::now().duration_since(self.last_sent);
+        if let Err(e) = self.irqfd.write(1) {
+            log::error!("Failed write to eventfd when signalling queue: {}", e);
+        } else {
+            self.total_ack_timeouted += 1;
+            metrics::add(&METRICS.irq_ack_timeouts, 1);
+            self.resent = Instant::now();
+            log::debug!(
+                "re-sending lost interrupt after {:.1}ms. Total lost {:.0}% ({}/{})",
+                passed.as_micros() as f64 / 1000.0,
+                100.0 * self.total_ack_timeouted as f64 / self.total_sent as f64,
+                self.total_ack_timeouted,
+                self.total_sent,
+            );
+        }
+        // wait for the ack of the re-sent interrupt
+        self.arm_timer(max(INTERRUPT_ACK_TIMEOUT, RESEND_RATELIMIT));
+    }
+}
+
+impl MutEventSubscriber for IrqAckHandler {
+    fn process(&mut self, events: Events, _ops: &mut EventOps) {
+        match events.data() {
+            RESAMPLE_DATA => self.handle_eoi(),
+            TIMER_DATA => {
+                // An irq sent since the timer expired re-armed it, which also resets the
+                // expiration. Reading it would block until the new timeout.
+                if let Ok(true) = self.timer.is_armed() {
+                    return;
+                }
+                if let Err(e) = self.timer.wait() {
+                    error!("cannot read irq ack timer: {}", e);
+                }
+                self.handle_timeout();
             }
+            data => error!("unexpected irq ack event: {}", data),
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        if let Some(resamplefd) = &self.resamplefd {
+            ops.add(Events::with_data(resamplefd, RESAMPLE_DATA, EventSet::IN))
+                .expect("Failed to init irq ack handler");
         }
+        ops.add(Events::with_data(&self.timer, TIMER_DATA, EventSet::IN))
+            .expect("Failed to init irq ack handler");
+    }
+}
+
+/// Continues the queues where the device of a previous vmsh process stopped. Descriptors
+/// that were made available but not used yet are processed again.
+pub fn resume_queues(cfg: &mut VirtioConfig<Queue>, mem: &GuestMemoryMmap) -> Result<()> {
+    for queue in cfg.queues.iter_mut().filter(|q| q.ready()) {
+        // struct virtq_used { le16 flags; le16 idx; ... }
+        let used_idx: u16 = try_with!(
+            mem.read_obj(GuestAddress(queue.used_ring() + 2)),
+            "cannot read used index at {:#x}",
+            queue.used_ring()
+        );
+        queue.set_next_avail(used_idx);
+        queue.set_next_used(used_idx);
     }
+    Ok(())
+}
+
+/// Writes `data` at `offset` into the configuration space of a device, the other fields are
+/// left alone. If the bytes differ from before, the configuration generation is increased, so
+/// that drivers reading fields with several accesses notice the update. Returns whether the
+/// configuration changed, the caller then signals a config change to the driver.
+pub fn update_config_space(
+    cfg: &mut VirtioConfig<Queue>,
+    offset: usize,
+    data: &[u8],
+) -> Result<bool> {
+    let len = cfg.config_space.len();
+    let field = match cfg.config_space.get_mut(offset..offset + data.len()) {
+        Some(field) => field,
+        None => bail!(
+            "cannot write {} bytes at offset {} of configuration space of {} bytes",
+            data.len(),
+            offset,
+            len
+        ),
+    };
+    if field == data {
+        return Ok(false);
+    }
+    field.copy_from_slice(data);
+    cfg.config_generation = cfg.config_generation.wrapping_add(1);
+    Ok(true)
+}
+
+/// Returns the device to the state before the driver initialized it, after the driver wrote 0
+/// to the status register. The queues handed to the queue handlers on activation are replaced by
+/// new ones and pending interrupts are cleared, so that the driver can probe the device again.
+/// The configuration space and its generation are kept.
+pub fn reset_virtio_config(
+    cfg: &mut VirtioConfig<Queue>,
+    num_queues: usize,
+    queue_size: u16,
+) -> result::Result<(), virtio_queue::Error> {
+    cfg.queues = (0..num_queues)
+        .map(|_| Queue::new(queue_size))
+        .collect::<result::Result<Vec<_>, _>>()?;
+    cfg.driver_features = 0;
+    cfg.device_features_select = 0;
+    cfg.driver_features_select = 0;
+    cfg.queue_select = 0;
+    cfg.device_status = 0;
+    cfg.device_activated = false;
+    // shared with the `IrqAckHandler`, which would otherwise re-send old interrupts
+    cfg.interrupt_status.store(0, Ordering::SeqCst);
+    Ok(())
 }
 
 pub fn register_ioeventfd(
@@ -166,11 +363,55 @@ pub fn register_ioeventfd(
     mmio_cfg: &MmioConfig,
     queue_idx: u64,
 ) -> Result<IoEventFd> {
-    let ioeventfd = vmm.ioeventfd_(
-        mmio_cfg.range.base().0 + VIRTIO_MMIO_QUEUE_NOTIFY_OFFSET,
-        4,
-        Some(queue_idx),
-    )?;
+    // virtio-pci drivers write the queue index as 16-bit value
+    let (offset, len) = match mmio_cfg.transport {
+        Transport::Mmio => (VIRTIO_MMIO_QUEUE_NOTIFY_OFFSET, 4),
+        Transport::Pci => (PCI_NOTIFY_OFFSET, 2),
+    };
+    let ioeventfd = vmm.ioeventfd_(mmio_cfg.range.base().0 + offset, len, Some(queue_idx))?;
 
     Ok(ioeventfd)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_space_update() {
+        let mut cfg = VirtioConfig::<Queue>::new(0, vec![], vec![0; 8]);
+        assert!(update_config_space(&mut cfg, 2, &[1, 2]).unwrap());
+        assert_eq!(cfg.config_space, [0, 0, 1, 2, 0, 0, 0, 0]);
+        assert_eq!(cfg.config_generation, 1);
+
+        // unchanged fields do not bump the generation
+        assert!(!update_config_space(&mut cfg, 2, &[1, 2]).unwrap());
+        assert_eq!(cfg.config_generation, 1);
+
+        assert!(update_config_space(&mut cfg, 7, &[1, 2]).is_err());
+        assert_eq!(cfg.config_generation, 1);
+    }
+
+    #[test]
+    fn config_reset() {
+        let mut cfg = VirtioConfig::<Queue>::new(1 << 32, vec![], vec![0; 8]);
+        cfg.driver_features = 1 << 32;
+        cfg.queue_select = 1;
+        cfg.device_status = 0xf;
+        cfg.device_activated = true;
+        cfg.interrupt_status
+            .store(VIRTIO_MMIO_INT_VRING, Ordering::SeqCst);
+        update_config_space(&mut cfg, 0, &[1]).unwrap();
+
+        reset_virtio_config(&mut cfg, 2, 16).unwrap();
+        assert_eq!(cfg.queues.len(), 2);
+        assert!(cfg.queues.iter().all(|q| q.max_size() == 16 && !q.ready()));
+        assert_eq!(cfg.driver_features, 0);
+        assert_eq!(cfg.queue_select, 0);
+        assert_eq!(cfg.device_status, 0);
+        assert!(!cfg.device_activated);
+        assert_eq!(cfg.interrupt_status.load(Ordering::SeqCst), 0);
+        assert_eq!(cfg.config_space[0], 1);
+        assert_eq!(cfg.config_generation, 1);
+    }
+}
@@ -0,0 +1,306 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// Author of further modifications: Peter Okelmann
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::borrow::{Borrow, BorrowMut};
+use std::ops::DerefMut;
+use std::sync::{Arc, Mutex};
+
+use event_manager::{MutEventSubscriber, RemoteEndpoint, Result as EvmgrResult, SubscriberId};
+use virtio_device::VirtioDeviceType;
+use virtio_device::{VirtioConfig, VirtioDeviceActions, VirtioMmioDevice, VirtioQueueNotifiable};
+use virtio_queue::Queue;
+use vm_device::bus::MmioAddress;
+use vm_device::device_manager::MmioManager;
+use vm_device::{DeviceMmio, MutDeviceMmio};
+use vm_memory::GuestMemoryMmap;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::devices::virtio::features::{
+    VIRTIO_F_IN_ORDER, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_VERSION_1,
+};
+use crate::devices::virtio::interrupt::DeviceInterrupt;
+use crate::devices::virtio::net::queue_handler::{NetQueueHandler, MAX_FRAME_SIZE};
+use crate::devices::virtio::net::tap::Tap;
+use crate::devices::virtio::pci::{Transport, VirtioPciDevice};
+use crate::devices::virtio::{reset_virtio_config, IrqAckHandler, MmioConfig, SingleFdSignalQueue};
+use crate::devices::MaybeIoRegionFd;
+use crate::kvm::hypervisor::{
+    ioevent::IoEvent, ioregionfd::IoRegionFd, userspaceioeventfd::UserspaceIoEventFd,
+};
+
+use super::{build_config_space, random_mac, Error, NetArgs, Result};
+use super::{NET_DEVICE_ID, VIRTIO_NET_F_MAC};
+
+pub(super) const RX_QUEUE_IDX: u16 = 0;
+pub(super) const TX_QUEUE_IDX: u16 = 1;
+
+pub struct Net {
+    virtio_cfg: VirtioConfig<Queue>,
+    pub mmio_cfg: MmioConfig,
+    endpoint: RemoteEndpoint<Arc<Mutex<dyn MutEventSubscriber + Send>>>,
+    pub irq_ack_handler: Arc<Mutex<IrqAckHandler>>,
+    irqfd: Arc<EventFd>,
+    pub ioregionfd: Option<IoRegionFd>,
+    pub uioefd: UserspaceIoEventFd,
+    mem: Arc<GuestMemoryMmap>,
+    queue_size: u16,
+    /// shared with the queue handler, they stay registered when the driver resets the device
+    rx_fd: Arc<IoEvent>,
+    tx_fd: Arc<IoEvent>,
+    tap: Tap,
+    /// only used when ioregionfd != None
+    sub_id: Option<SubscriberId>,
+}
+
+impl Net {
+    pub fn new<B>(mut args: NetArgs<B>) -> Result<Arc<Mutex<Self>>>
+    where
+        // We're using this (more convoluted) bound so we can pass both references and smart
+        // pointers such as mutex guards here.
+        B: DerefMut,
+        B::Target: MmioManager<D = Arc<dyn DeviceMmio + Send + Sync>>,
+    {
+        let tap = Tap::open(&args.tap).map_err(Error::Simple)?;
+
+        // The queue handling logic for this device uses the buffers in order, so we enable the
+        // corresponding feature as well.
+        let device_features = 1 << VIRTIO_F_VERSION_1
+            | 1 << VIRTIO_F_IN_ORDER
+            | 1 << VIRTIO_F_RING_EVENT_IDX
+            | 1 << VIRTIO_NET_F_MAC;
+
+        // A network device has one receive and one transmit queue.
+        let queues = vec![
+            Queue::new(args.common.queue_size).map_err(Error::QueueCreation)?,
+            Queue::new(args.common.queue_size).map_err(Error::QueueCreation)?,
+        ];
+
+        let mac = random_mac();
+        log::info!(
+            "net device on {} with mac {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            tap.name,
+            mac[0],
+            mac[1],
+            mac[2],
+            mac[3],
+            mac[4],
+            mac[5]
+        );
+        let config_space = build_config_space(&mac);
+        let virtio_cfg = VirtioConfig::new(device_features, queues, config_space);
+
+        // Used to send notifications to the driver.
+        let DeviceInterrupt { irqfd, resamplefd } = args
+            .common
+            .interrupts
+            .connect(&args.common.mmio_cfg)
+            .map_err(Error::Simple)?;
+
+        let mmio_cfg = args.common.mmio_cfg;
+
+        let irq_ack_handler = Arc::new(Mutex::new(
+            IrqAckHandler::new(
+                virtio_cfg.interrupt_status.clone(),
+                Arc::clone(&irqfd),
+                resamplefd,
+            )
+            .map_err(Error::Simple)?,
+        ));
+
+        let mut ioregionfd = None;
+        if args.common.vmm.use_ioregionfd() {
+            ioregionfd = Some(
+                args.common
+                    .vmm
+                    .ioregionfd(mmio_cfg.range.base().0, mmio_cfg.range.size() as usize)
+                    .map_err(Error::Simple)?,
+            );
+        }
+
+        let mut uioefd = UserspaceIoEventFd::default();
+        let rx_fd = IoEvent::register(
+            &args.common.vmm,
+            &mut uioefd,
+            &mmio_cfg,
+            RX_QUEUE_IDX as u64,
+        )
+        .map_err(Error::Simple)?;
+        let tx_fd = IoEvent::register(
+            &args.common.vmm,
+            &mut uioefd,
+            &mmio_cfg,
+            TX_QUEUE_IDX as u64,
+        )
+        .map_err(Error::Simple)?;
+
+        let net = Arc::new(Mutex::new(Net {
+            virtio_cfg,
+            mmio_cfg,
+            endpoint: args.common.event_mgr.remote_endpoint(),
+            irq_ack_handler,
+            irqfd,
+            ioregionfd,
+            mem: Arc::clone(&args.common.mem),
+            queue_size: args.common.queue_size,
+            rx_fd: Arc::new(rx_fd),
+            tx_fd: Arc::new(tx_fd),
+            tap,
+            uioefd,
+            sub_id: None,
+        }));
+
+        // Register the device on the MMIO bus.
+        args.common
+            .mmio_mgr
+            .register_mmio(mmio_cfg.range, net.clone())
+            .map_err(Error::Bus)?;
+
+        Ok(net)
+    }
+
+    fn _activate(&mut self) -> Result<()> {
+        if self.virtio_cfg.device_activated {
+            return Err(Error::AlreadyActivated);
+        }
+
+        // We do not support legacy drivers.
+        if self.virtio_cfg.driver_features & (1 << VIRTIO_F_VERSION_1) == 0 {
+            return Err(Error::BadFeatures(self.virtio_cfg.driver_features));
+        }
+
+        let driver_notify = SingleFdSignalQueue {
+            irqfd: self.irqfd.clone(),
+            interrupt_status: self.virtio_cfg.interrupt_status.clone(),
+            ack_handler: self.irq_ack_handler.clone(),
+        };
+
+        let rxq = self.virtio_cfg.queues.remove(RX_QUEUE_IDX.into());
+        let txq = self.virtio_cfg.queues.remove(RX_QUEUE_IDX.into());
+
+        let handler = Arc::new(Mutex::new(NetQueueHandler {
+            driver_notify,
+            rx_fd: Arc::clone(&self.rx_fd),
+            tx_fd: Arc::clone(&self.tx_fd),
+            tap: self.tap.try_clone().map_err(Error::Simple)?,
+            mem: Arc::clone(&self.mem),
+            rxq,
+            txq,
+            rx_frame: vec![0; MAX_FRAME_SIZE],
+            rx_len: 0,
+            tx_frame: Vec::with_capacity(MAX_FRAME_SIZE),
+        }));
+
+        // Register the queue handler with the `EventManager`. We record the `sub_id`
+        // (and/or keep a handler clone) to remove the subscriber when resetting the device
+        let sub_id = self
+            .endpoint
+            .call_blocking(move |mgr| -> EvmgrResult<SubscriberId> {
+                Ok(mgr.add_subscriber(handler))
+            })
+            .map_err(|e| {
+                log::warn!("{}", e);
+                Error::Endpoint(e)
+            })?;
+        self.sub_id = Some(sub_id);
+
+        log::debug!("activating device: ok");
+        self.virtio_cfg.device_activated = true;
+
+        Ok(())
+    }
+
+    fn _reset(&mut self) -> Result<()> {
+        // Once removed, the handler no longer processes the queues and can be dropped. A frame
+        // it read from the tap device but did not deliver yet is lost. The ioeventfds stay
+        // registered with the hypervisor for the next activation.
+        if let Some(sub_id) = self.sub_id.take() {
+            self.endpoint
+                .call_blocking(move |mgr| mgr.remove_subscriber(sub_id))
+                .map_err(|e| {
+                    log::warn!("{}", e);
+                    Error::Endpoint(e)
+                })?;
+        }
+        // notifications of the old queues must not be processed by the next handler
+        let _ = self.rx_fd.read();
+        let _ = self.tx_fd.read();
+        reset_virtio_config(&mut self.virtio_cfg, 2, self.queue_size).map_err(Error::QueueCreation)
+    }
+}
+
+impl MaybeIoRegionFd for Net {
+    fn get_ioregionfd(&mut self) -> &mut Option<IoRegionFd> {
+        &mut self.ioregionfd
+    }
+}
+
+// We now implement `WithVirtioConfig` and `WithDeviceOps` to get the automatic implementation
+// for `VirtioDevice`.
+impl VirtioDeviceType for Net {
+    fn device_type(&self) -> u32 {
+        NET_DEVICE_ID
+    }
+}
+
+impl Borrow<VirtioConfig<Queue>> for Net {
+    fn borrow(&self) -> &VirtioConfig<Queue> {
+        &self.virtio_cfg
+    }
+}
+
+impl BorrowMut<VirtioConfig<Queue>> for Net {
+    fn borrow_mut(&mut self) -> &mut VirtioConfig<Queue> {
+        &mut self.virtio_cfg
+    }
+}
+
+impl VirtioDeviceActions for Net {
+    type E = Error;
+
+    /// make sure to set self.vmm.wrapper to Some() before activating. Typically this is done by
+    /// activating during vmm.kvmrun_wrapped()
+    fn activate(&mut self) -> Result<()> {
+        let ret = self._activate();
+        if let Err(ref e) = ret {
+            log::warn!("failed to activate net device: {:?}", e);
+        }
+        ret
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        let ret = self._reset();
+        if let Err(ref e) = ret {
+            log::warn!("failed to reset net device: {:?}", e);
+        }
+        ret
+    }
+}
+
+impl VirtioQueueNotifiable for Net {
+    fn queue_notify(&mut self, val: u32) {
+        // with ioeventfds in KVM, only notifications replayed by vmsh end up here
+        self.uioefd.queue_notify(val);
+        log::trace!("queue_notify {}", val);
+    }
+}
+
+impl VirtioMmioDevice for Net {}
+
+impl VirtioPciDevice for Net {}
+
+impl MutDeviceMmio for Net {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
+        match self.mmio_cfg.transport {
+            Transport::Mmio => self.read(offset, data),
+            Transport::Pci => self.pci_read(offset, data),
+        }
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
+        match self.mmio_cfg.transport {
+            Transport::Mmio => self.write(offset, data),
+            Transport::Pci => self.pci_write(offset, data),
+        }
+    }
+}
@@ -0,0 +1,58 @@
+mod device;
+mod queue_handler;
+mod tap;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use event_manager::Error as EvmgrError;
+use vm_device::bus;
+
+use crate::devices::virtio::CommonArgs;
+use crate::result::VmshError;
+
+pub use device::Net;
+
+/// Network device ID as defined by the standard.
+pub const NET_DEVICE_ID: u32 = 1;
+
+/// Device has given MAC address.
+pub const VIRTIO_NET_F_MAC: u64 = 5;
+
+/// Size of `struct virtio_net_hdr_v1` that precedes every packet in both directions.
+pub(crate) const VIRTIO_NET_HDR_SIZE: usize = 12;
+
+#[derive(Debug)]
+pub enum Error {
+    AlreadyActivated,
+    BadFeatures(u64),
+    Bus(bus::Error),
+    Endpoint(EvmgrError),
+    QueueCreation(virtio_queue::Error),
+    Simple(VmshError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Returns a locally administered unicast mac address.
+fn random_mac() -> [u8; 6] {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0)
+        ^ std::process::id();
+    let b = nanos.to_le_bytes();
+    // same prefix as qemu uses
+    [0x52, 0x54, 0x00, b[0], b[1], b[2]]
+}
+
+fn build_config_space(mac: &[u8; 6]) -> Vec<u8> {
+    // we only advertise VIRTIO_NET_F_MAC, so the config space ends after the mac
+    mac.to_vec()
+}
+
+// Arguments required when building a network device.
+pub struct NetArgs<'a, B> {
+    pub common: CommonArgs<'a, B>,
+    /// Name of the tap device on the host.
+    pub tap: String,
+}
@@ -0,0 +1,243 @@
+use std::io::{self, Read, Write};
+use std::result;
+use std::sync::Arc;
+
+use event_manager::{EventOps, EventSet, Events, MutEventSubscriber};
+use log::{error, warn};
+use virtio_queue::{Queue, QueueOwnedT, QueueT};
+use vm_memory::{self, Bytes, GuestMemoryMmap};
+
+use super::device::{RX_QUEUE_IDX, TX_QUEUE_IDX};
+use super::tap::Tap;
+use super::VIRTIO_NET_HDR_SIZE;
+use crate::devices::virtio::SignalUsedQueue;
+use crate::kvm::hypervisor::ioevent::IoEvent;
+
+const TAP_DATA: u32 = 2;
+
+/// Largest frame we can receive: 64KiB + virtio_net_hdr_v1
+pub(super) const MAX_FRAME_SIZE: usize = 65536 + VIRTIO_NET_HDR_SIZE;
+
+#[derive(Debug)]
+pub enum Error {
+    GuestMemory(vm_memory::GuestMemoryError),
+    Queue(virtio_queue::Error),
+    Tap(io::Error),
+}
+
+impl From<vm_memory::GuestMemoryError> for Error {
+    fn from(e: vm_memory::GuestMemoryError) -> Self {
+        Error::GuestMemory(e)
+    }
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+/// Stops handling `source` after an error.
+fn handle_error<Msg: AsRef<str>>(s: Msg, source: Events, ops: &mut EventOps) {
+    error!("{}", s.as_ref());
+    // the fd may already be removed after an earlier error of the same event
+    if let Err(e) = ops.remove(source) {
+        error!("Failed to remove net event: {:?}", e);
+    }
+}
+
+pub(crate) struct NetQueueHandler<S: SignalUsedQueue> {
+    pub driver_notify: S,
+    pub rxq: Queue,
+    pub txq: Queue,
+    pub rx_fd: Arc<IoEvent>,
+    pub tx_fd: Arc<IoEvent>,
+    pub tap: Tap,
+    pub mem: Arc<GuestMemoryMmap>,
+    /// Frame read from the tap device that was not yet delivered to the guest
+    pub rx_frame: Vec<u8>,
+    pub rx_len: usize,
+    // we have this here to safe reallocations across packets
+    pub tx_frame: Vec<u8>,
+}
+
+impl<S> NetQueueHandler<S>
+where
+    S: SignalUsedQueue,
+{
+    /// Receive errors stop the source that failed: the tap device or the receive queue.
+    fn rx_error_source(&self, e: &Error) -> Events {
+        match e {
+            Error::Tap(_) => Events::empty(&self.tap),
+            _ => Events::empty(self.rx_fd.as_ref()),
+        }
+    }
+
+    /// Guest sends (tx), we write to the tap device
+    pub fn process_txq(&mut self) -> result::Result<(), Error> {
+        // To see why this is done in a loop, please look at the `Queue::enable_notification`
+        // comments in `vm_virtio`.
+        loop {
+            self.txq.disable_notification(self.mem.as_ref())?;
+
+            while let Some(mut chain) = self.txq.iter(self.mem.as_ref())?.next() {
+                self.tx_frame.clear();
+                let mut too_long = false;
+                while let Some(desc) = chain.next() {
+                    let start = self.tx_frame.len();
+                    // the length is chosen by the guest, do not let it allocate more than a frame
+                    let end = start + desc.len() as usize;
+                    if end > MAX_FRAME_SIZE {
+                        too_long = true;
+                        break;
+                    }
+                    self.tx_frame.resize(end, 0);
+                    chain
+                        .memory()
+                        .read_slice(&mut self.tx_frame[start..], desc.addr())?;
+                }
+                // the network stack retransmits dropped packets
+                if too_long {
+                    warn!(
+                        "dropping frame of more than {} bytes sent by the guest",
+                        MAX_FRAME_SIZE
+                    );
+                } else if let Err(e) = self.tap.write(&self.tx_frame) {
+                    warn!("cannot write frame to {}: {}", self.tap.name, e);
+                }
+                self.txq
+                    .add_used(self.mem.as_ref(), chain.head_index(), 0)?;
+
+                if self.txq.needs_notification(self.mem.as_ref())? {
+                    self.driver_notify.signal_used_queue(TX_QUEUE_IDX);
+                }
+            }
+
+            if !self.txq.enable_notification(self.mem.as_ref())? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Guest receives (rx), we read from the tap device until it would block or
+    /// the guest runs out of receive buffers.
+    pub fn process_rx(&mut self) -> result::Result<(), Error> {
+        loop {
+            if self.rx_len == 0 {
+                match self.tap.read(&mut self.rx_frame) {
+                    Ok(len) => self.rx_len = len,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                    Err(e) => return Err(Error::Tap(e)),
+                }
+                if self.rx_len < VIRTIO_NET_HDR_SIZE {
+                    self.rx_len = 0;
+                    continue;
+                }
+                // num_buffers is always one without VIRTIO_NET_F_MRG_RXBUF
+                self.rx_frame[10..12].copy_from_slice(&1u16.to_le_bytes());
+            }
+
+            self.rxq.disable_notification(self.mem.as_ref())?;
+            let mut chain = match self.rxq.iter(self.mem.as_ref())?.next() {
+                Some(chain) => chain,
+                None => {
+                    // Wait for the driver to add new buffers. We get notified via rx_fd.
+                    if self.rxq.enable_notification(self.mem.as_ref())? {
+                        continue;
+                    }
+                    return Ok(());
+                }
+            };
+
+            let mut written = 0;
+            while let Some(desc) = chain.next() {
+                if written == self.rx_len {
+                    break;
+                }
+                if !desc.is_write_only() {
+                    continue;
+                }
+                let len = std::cmp::min(desc.len() as usize, self.rx_len - written);
+                chain
+                    .memory()
+                    .write_slice(&self.rx_frame[written..written + len], desc.addr())?;
+                written += len;
+            }
+            if written < self.rx_len {
+                warn!(
+                    "dropping frame of {} bytes: receive buffer too small",
+                    self.rx_len
+                );
+            }
+            self.rx_len = 0;
+            self.rxq
+                .add_used(self.mem.as_ref(), chain.head_index(), written as u32)?;
+
+            if self.rxq.needs_notification(self.mem.as_ref())? {
+                self.driver_notify.signal_used_queue(RX_QUEUE_IDX);
+            }
+        }
+    }
+}
+
+impl<S: SignalUsedQueue> MutEventSubscriber for NetQueueHandler<S> {
+    fn process(&mut self, events: Events, ops: &mut EventOps) {
+        let _span =
+            tracing::trace_span!("queue-handler", device = "net", data = events.data()).entered();
+        if events.event_set() != EventSet::IN {
+            handle_error("Unexpected event_set", events, ops);
+            return;
+        }
+
+        match events.data() {
+            TAP_DATA => {
+                if let Err(e) = self.process_rx() {
+                    let source = self.rx_error_source(&e);
+                    handle_error(format!("Process rx error {:?}", e), source, ops);
+                }
+            }
+            data if data == RX_QUEUE_IDX as u32 => {
+                if self.rx_fd.read().is_err() {
+                    handle_error("Rx ioevent read", events, ops);
+                }
+                if let Err(e) = self.process_rx() {
+                    let source = self.rx_error_source(&e);
+                    handle_error(format!("Process rx error {:?}", e), source, ops);
+                }
+            }
+            data if data == TX_QUEUE_IDX as u32 => {
+                if self.tx_fd.read().is_err() {
+                    handle_error("Tx ioevent read", events, ops);
+                }
+                if let Err(e) = self.process_txq() {
+                    handle_error(format!("Process tx error {:?}", e), events, ops);
+                }
+            }
+            _ => handle_error("Unexpected data", events, ops),
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        ops.add(Events::with_data(
+            self.rx_fd.as_ref(),
+            RX_QUEUE_IDX as u32,
+            EventSet::IN,
+        ))
+        .expect("Failed to register rx ioeventfd for net queue handler");
+        ops.add(Events::with_data(
+            self.tx_fd.as_ref(),
+            TX_QUEUE_IDX as u32,
+            EventSet::IN,
+        ))
+        .expect("Failed to register tx ioeventfd for net queue handler");
+        // Edge triggered: process_rx() reads until the tap would block. If the guest
+        // runs out of buffers, we continue once it notifies us about new ones.
+        ops.add(Events::with_data(
+            &self.tap,
+            TAP_DATA,
+            EventSet::IN | EventSet::EDGE_TRIGGERED,
+        ))
+        .expect("Failed to register tap device for net queue handler");
+    }
+}
@@ -0,0 +1,112 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use libc::{c_char, c_int, c_short, IFF_NO_PI, IFF_TAP, IFF_VNET_HDR, IFNAMSIZ};
+use simple_error::{bail, try_with};
+
+use super::VIRTIO_NET_HDR_SIZE;
+use crate::result::Result;
+
+// _IOW('T', 202, int)
+const TUNSETIFF: libc::c_ulong = 0x400454ca;
+// _IOW('T', 216, int)
+const TUNSETVNETHDRSZ: libc::c_ulong = 0x400454d8;
+
+#[repr(C)]
+struct IfReq {
+    ifr_name: [c_char; IFNAMSIZ],
+    ifr_flags: c_short,
+    // struct ifreq is a union of 24 bytes
+    _pad: [u8; 22],
+}
+
+/// Host side of the network device. Frames read or written are prefixed with a
+/// `virtio_net_hdr_v1`, so they can be passed as-is to the virtqueues.
+pub struct Tap {
+    file: File,
+    pub name: String,
+}
+
+impl Tap {
+    pub fn open(name: &str) -> Result<Tap> {
+        if name.is_empty() || name.len() >= IFNAMSIZ {
+            bail!("invalid tap device name: '{}'", name);
+        }
+        let file = try_with!(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+                .open("/dev/net/tun"),
+            "cannot open /dev/net/tun"
+        );
+
+        let mut req = IfReq {
+            ifr_name: [0; IFNAMSIZ],
+            ifr_flags: (IFF_TAP | IFF_NO_PI | IFF_VNET_HDR) as c_short,
+            _pad: [0; 22],
+        };
+        for (dst, src) in req.ifr_name.iter_mut().zip(name.as_bytes()) {
+            *dst = *src as c_char;
+        }
+        let res = unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF as _, &req) };
+        if res < 0 {
+            bail!(
+                "cannot attach to tap device {}: {}",
+                name,
+                io::Error::last_os_error()
+            );
+        }
+
+        let hdr_size = VIRTIO_NET_HDR_SIZE as c_int;
+        let res = unsafe { libc::ioctl(file.as_raw_fd(), TUNSETVNETHDRSZ as _, &hdr_size) };
+        if res < 0 {
+            bail!(
+                "cannot set vnet header size of {}: {}",
+                name,
+                io::Error::last_os_error()
+            );
+        }
+
+        Ok(Tap {
+            file,
+            name: name.to_string(),
+        })
+    }
+
+    /// Opens another handle to the same tap device, e.g. for the queue handler.
+    pub fn try_clone(&self) -> Result<Tap> {
+        Ok(Tap {
+            file: try_with!(
+                self.file.try_clone(),
+                "cannot clone tap device {}",
+                self.name
+            ),
+            name: self.name.clone(),
+        })
+    }
+}
+
+impl Read for Tap {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for Tap {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for Tap {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
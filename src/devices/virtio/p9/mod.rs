@@ -0,0 +1,50 @@
+mod device;
+mod protocol;
+mod queue_handler;
+mod server;
+
+use std::path::PathBuf;
+
+use event_manager::Error as EvmgrError;
+use vm_device::bus;
+
+use crate::devices::virtio::CommonArgs;
+use crate::result::VmshError;
+
+pub use device::P9;
+
+/// 9P transport device ID as defined by the standard.
+pub const P9_DEVICE_ID: u32 = 9;
+
+/// The mount tag is present in the config space.
+pub const VIRTIO_9P_MOUNT_TAG: u64 = 0;
+
+/// Mount tag stage2 looks for.
+pub const VMSH_MOUNT_TAG: &str = "vmsh0";
+
+#[derive(Debug)]
+pub enum Error {
+    AlreadyActivated,
+    BadFeatures(u64),
+    Bus(bus::Error),
+    Endpoint(EvmgrError),
+    QueueCreation(virtio_queue::Error),
+    Simple(VmshError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn build_config_space(tag: &str) -> Vec<u8> {
+    // struct virtio_9p_config { le16 tag_len; u8 tag[]; }
+    let mut config = (tag.len() as u16).to_le_bytes().to_vec();
+    config.extend_from_slice(tag.as_bytes());
+    config
+}
+
+// Arguments required when building a 9p device.
+pub struct P9Args<'a, B> {
+    pub common: CommonArgs<'a, B>,
+    /// Host directory exported to the guest.
+    pub root: PathBuf,
+    pub tag: String,
+}
@@ -0,0 +1,202 @@
+//! Wire format of the 9P2000.L protocol (https://github.com/chaos/diod/blob/master/protocol.md).
+//! All integers are little endian, strings are prefixed with a u16 length.
+
+use std::fs::Metadata;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+
+pub const P9_RLERROR: u8 = 7;
+pub const P9_TSTATFS: u8 = 8;
+pub const P9_TLOPEN: u8 = 12;
+pub const P9_TLCREATE: u8 = 14;
+pub const P9_TSYMLINK: u8 = 16;
+pub const P9_TMKNOD: u8 = 18;
+pub const P9_TRENAME: u8 = 20;
+pub const P9_TREADLINK: u8 = 22;
+pub const P9_TGETATTR: u8 = 24;
+pub const P9_TSETATTR: u8 = 26;
+pub const P9_TXATTRWALK: u8 = 30;
+pub const P9_TREADDIR: u8 = 40;
+pub const P9_TFSYNC: u8 = 50;
+pub const P9_TLOCK: u8 = 52;
+pub const P9_TGETLOCK: u8 = 54;
+pub const P9_TLINK: u8 = 70;
+pub const P9_TMKDIR: u8 = 72;
+pub const P9_TRENAMEAT: u8 = 74;
+pub const P9_TUNLINKAT: u8 = 76;
+pub const P9_TVERSION: u8 = 100;
+pub const P9_TAUTH: u8 = 102;
+pub const P9_TATTACH: u8 = 104;
+pub const P9_TFLUSH: u8 = 108;
+pub const P9_TWALK: u8 = 110;
+pub const P9_TREAD: u8 = 116;
+pub const P9_TWRITE: u8 = 118;
+pub const P9_TCLUNK: u8 = 120;
+pub const P9_TREMOVE: u8 = 122;
+
+/// size[4] type[1] tag[2]
+pub const HEADER_SIZE: usize = 7;
+
+pub const QID_SIZE: usize = 13;
+pub const QTDIR: u8 = 0x80;
+pub const QTSYMLINK: u8 = 0x02;
+pub const QTFILE: u8 = 0x00;
+
+/// Identifies a file on the server.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Qid {
+    pub type_: u8,
+    pub version: u32,
+    pub path: u64,
+}
+
+impl From<&Metadata> for Qid {
+    fn from(m: &Metadata) -> Self {
+        let type_ = if m.file_type().is_dir() {
+            QTDIR
+        } else if m.file_type().is_symlink() {
+            QTSYMLINK
+        } else {
+            QTFILE
+        };
+        Qid {
+            type_,
+            version: m.mtime() as u32,
+            path: m.ino(),
+        }
+    }
+}
+
+fn short_message() -> io::Error {
+    io::Error::from_raw_os_error(libc::EPROTO)
+}
+
+/// Parses the fields of a message.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Reader { buf }
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(short_message());
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        let mut b = [0; 2];
+        b.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(b))
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(b))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    pub fn string(&mut self) -> io::Result<String> {
+        let len = self.u16()? as usize;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))
+    }
+
+    pub fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        self.take(len)
+    }
+}
+
+/// Builds a reply. The size field is filled in by `finish()`.
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new(type_: u8, tag: u16) -> Self {
+        let mut w = Writer {
+            buf: Vec::with_capacity(64),
+        };
+        w.u32(0);
+        w.u8(type_);
+        w.u16(tag);
+        w
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn string(&mut self, s: &str) {
+        // names longer than u16::MAX are rejected by Linux anyway
+        let len = std::cmp::min(s.len(), u16::MAX as usize);
+        self.u16(len as u16);
+        self.buf.extend_from_slice(&s.as_bytes()[..len]);
+    }
+
+    pub fn qid(&mut self, qid: &Qid) {
+        self.u8(qid.type_);
+        self.u32(qid.version);
+        self.u64(qid.path);
+    }
+
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_le_bytes());
+        self.buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reader_writer() {
+        let mut w = Writer::new(P9_TVERSION, 0xffff);
+        w.u32(8192);
+        w.string("9P2000.L");
+        let msg = w.finish();
+        assert_eq!(msg.len(), HEADER_SIZE + 4 + 2 + 8);
+
+        let mut r = Reader::new(&msg);
+        assert_eq!(r.u32().unwrap() as usize, msg.len());
+        assert_eq!(r.u8().unwrap(), P9_TVERSION);
+        assert_eq!(r.u16().unwrap(), 0xffff);
+        assert_eq!(r.u32().unwrap(), 8192);
+        assert_eq!(r.string().unwrap(), "9P2000.L");
+        assert!(r.u8().is_err());
+    }
+}
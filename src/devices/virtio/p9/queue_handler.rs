@@ -0,0 +1,152 @@
+use std::result;
+use std::sync::Arc;
+
+use event_manager::{EventOps, EventSet, Events, MutEventSubscriber};
+use log::{error, warn};
+use virtio_queue::{Queue, QueueOwnedT, QueueT};
+use vm_memory::{self, Bytes, GuestAddress, GuestMemoryMmap};
+
+use super::server::Server;
+use crate::devices::virtio::SignalUsedQueue;
+use crate::kvm::hypervisor::ioevent::IoEvent;
+
+const REQUEST_QUEUE_IDX: u16 = 0;
+
+#[derive(Debug)]
+pub enum Error {
+    GuestMemory(vm_memory::GuestMemoryError),
+    Queue(virtio_queue::Error),
+}
+
+impl From<vm_memory::GuestMemoryError> for Error {
+    fn from(e: vm_memory::GuestMemoryError) -> Self {
+        Error::GuestMemory(e)
+    }
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+/// Stops handling `source` after an error.
+fn handle_error<Msg: AsRef<str>>(s: Msg, source: Events, ops: &mut EventOps) {
+    error!("{}", s.as_ref());
+    // the fd may already be removed after an earlier error of the same event
+    if let Err(e) = ops.remove(source) {
+        error!("Failed to remove 9p event: {:?}", e);
+    }
+}
+
+pub(crate) struct P9QueueHandler<S: SignalUsedQueue> {
+    pub driver_notify: S,
+    pub queue: Queue,
+    pub ioeventfd: Arc<IoEvent>,
+    pub mem: Arc<GuestMemoryMmap>,
+    pub server: Server,
+    // we have this here to safe reallocations across requests
+    pub request: Vec<u8>,
+}
+
+impl<S> P9QueueHandler<S>
+where
+    S: SignalUsedQueue,
+{
+    /// Each chain holds a T-message in its readable descriptors followed by
+    /// space for the R-message in its writable descriptors.
+    fn process_queue(&mut self) -> result::Result<(), Error> {
+        // To see why this is done in a loop, please look at the `Queue::enable_notification`
+        // comments in `vm_virtio`.
+        loop {
+            self.queue.disable_notification(self.mem.as_ref())?;
+
+            while let Some(chain) = self.queue.iter(self.mem.as_ref())?.next() {
+                let head_index = chain.head_index();
+                self.request.clear();
+                let msize = self.server.msize() as usize;
+                let mut too_long = false;
+                let mut reply_bufs: Vec<(GuestAddress, usize)> = vec![];
+                for desc in chain {
+                    if desc.is_write_only() {
+                        reply_bufs.push((desc.addr(), desc.len() as usize));
+                    } else {
+                        // the length is chosen by the guest, do not let it allocate more than a
+                        // message
+                        let start = self.request.len();
+                        let len = std::cmp::min(desc.len() as usize, msize - start);
+                        too_long |= len < desc.len() as usize;
+                        self.request.resize(start + len, 0);
+                        self.mem
+                            .read_slice(&mut self.request[start..], desc.addr())?;
+                    }
+                }
+
+                let reply = if too_long {
+                    warn!("9p: rejecting request of more than {} bytes", msize);
+                    self.server.reject(&self.request, libc::EMSGSIZE)
+                } else {
+                    self.server.handle(&self.request)
+                };
+                let mut written = 0;
+                for (addr, len) in reply_bufs {
+                    if written == reply.len() {
+                        break;
+                    }
+                    let len = std::cmp::min(len, reply.len() - written);
+                    self.mem.write_slice(&reply[written..written + len], addr)?;
+                    written += len;
+                }
+                if written < reply.len() {
+                    error!(
+                        "9p: reply of {} bytes does not fit into the guest buffer",
+                        reply.len()
+                    );
+                }
+
+                self.queue
+                    .add_used(self.mem.as_ref(), head_index, written as u32)?;
+
+                if self.queue.needs_notification(self.mem.as_ref())? {
+                    self.driver_notify.signal_used_queue(REQUEST_QUEUE_IDX);
+                }
+            }
+
+            if !self.queue.enable_notification(self.mem.as_ref())? {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: SignalUsedQueue> MutEventSubscriber for P9QueueHandler<S> {
+    fn process(&mut self, events: Events, ops: &mut EventOps) {
+        let _span =
+            tracing::trace_span!("queue-handler", device = "9p", data = events.data()).entered();
+        if events.event_set() != EventSet::IN {
+            handle_error("Unexpected event_set", events, ops);
+            return;
+        }
+        if events.data() != REQUEST_QUEUE_IDX as u32 {
+            handle_error("Unexpected data", events, ops);
+            return;
+        }
+        if self.ioeventfd.read().is_err() {
+            handle_error("ioevent read", events, ops);
+            return;
+        }
+        if let Err(e) = self.process_queue() {
+            handle_error(format!("Process queue error {:?}", e), events, ops);
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        ops.add(Events::with_data(
+            self.ioeventfd.as_ref(),
+            REQUEST_QUEUE_IDX as u32,
+            EventSet::IN,
+        ))
+        .expect("Failed to register ioeventfd for 9p queue handler");
+    }
+}
@@ -0,0 +1,908 @@
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+
+use super::protocol::*;
+
+/// Largest message size we negotiate with the guest.
+pub const MAX_MSIZE: u32 = 128 * 1024;
+
+/// size[4] type[1] tag[2] count[4]
+const IO_HEADER_SIZE: u32 = HEADER_SIZE as u32 + 4;
+
+const V9FS_MAGIC: u32 = 0x0102_1997;
+
+/// Path of the *at() calls with `AT_EMPTY_PATH`, which act on the fd itself
+const EMPTY_PATH: &[u8] = b"\0";
+
+// Tgetattr/Tsetattr masks
+const P9_GETATTR_BASIC: u64 = 0x0000_07ff;
+const P9_SETATTR_MODE: u32 = 0x0000_0001;
+const P9_SETATTR_UID: u32 = 0x0000_0002;
+const P9_SETATTR_GID: u32 = 0x0000_0004;
+const P9_SETATTR_SIZE: u32 = 0x0000_0008;
+const P9_SETATTR_ATIME: u32 = 0x0000_0010;
+const P9_SETATTR_MTIME: u32 = 0x0000_0020;
+const P9_SETATTR_ATIME_SET: u32 = 0x0000_0080;
+const P9_SETATTR_MTIME_SET: u32 = 0x0000_0100;
+
+const P9_LOCK_SUCCESS: u8 = 0;
+const P9_LOCK_TYPE_UNLCK: u8 = 2;
+
+/// Linux open flags that we pass on to the host. O_CREAT & co. are handled by Tlcreate.
+const LOPEN_FLAGS: i32 = libc::O_ACCMODE
+    | libc::O_APPEND
+    | libc::O_TRUNC
+    | libc::O_DIRECT
+    | libc::O_SYNC
+    | libc::O_DSYNC
+    | libc::O_NOATIME;
+
+fn errno(e: i32) -> io::Error {
+    io::Error::from_raw_os_error(e)
+}
+
+fn cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| errno(libc::EINVAL))
+}
+
+fn check(res: libc::c_int) -> io::Result<()> {
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Opens `name` in the directory `dir` without following a symlink.
+fn open_at(dir: &File, name: &CStr, flags: libc::c_int, mode: u32) -> io::Result<File> {
+    let fd = unsafe {
+        libc::openat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            flags | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            mode,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Returns an O_PATH fd of `name` in the directory `dir`.
+fn open_path(dir: &File, name: &CStr) -> io::Result<File> {
+    open_at(dir, name, libc::O_PATH, 0)
+}
+
+/// The kernel resolves this path to the inode of `fd` and not by name, so unlike the path of
+/// the file it cannot be redirected by the guest. It never follows the file if it is a symlink.
+fn proc_path(fd: &File) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", fd.as_raw_fd()))
+}
+
+struct DirEntry {
+    qid: Qid,
+    type_: u8,
+    name: String,
+}
+
+struct Fid {
+    /// O_PATH fd of the file. Paths are never resolved again after the walk, so the guest cannot
+    /// leave the exported directory by replacing a walked directory with a symlink.
+    fd: File,
+    /// uid of the user that attached, used for new files
+    uid: u32,
+    file: Option<File>,
+    /// directory listing, refreshed on Treaddir with offset 0
+    dirents: Vec<DirEntry>,
+}
+
+/// A 9P2000.L server that exports a host directory. All operations are done relative to the fd
+/// of a fid and without following symlinks.
+pub struct Server {
+    root: PathBuf,
+    /// (device, inode) of `root`, ".." does not walk above it
+    root_id: (u64, u64),
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl Server {
+    pub fn new(root: PathBuf) -> Self {
+        Server {
+            root,
+            root_id: (0, 0),
+            msize: MAX_MSIZE,
+            fids: HashMap::new(),
+        }
+    }
+
+    /// Largest message the guest may send, as negotiated with Tversion.
+    pub fn msize(&self) -> u32 {
+        self.msize
+    }
+
+    /// Answers `req` with `code` without handling it.
+    pub fn reject(&self, req: &[u8], code: i32) -> Vec<u8> {
+        // tag[2] follows size[4] type[1]
+        let tag = req
+            .get(5..HEADER_SIZE)
+            .map_or(0, |t| u16::from_le_bytes([t[0], t[1]]));
+        reply_error(tag, code)
+    }
+
+    /// Handles a T-message and returns the matching R-message.
+    pub fn handle(&mut self, req: &[u8]) -> Vec<u8> {
+        let mut r = Reader::new(req);
+        let header = (|| -> io::Result<(u8, u16)> {
+            r.u32()?;
+            Ok((r.u8()?, r.u16()?))
+        })();
+        let (type_, tag) = match header {
+            Ok(h) => h,
+            Err(_) => {
+                warn!("9p: dropping short message of {} bytes", req.len());
+                return reply_error(0, libc::EPROTO);
+            }
+        };
+        match self.dispatch(type_, tag, &mut r) {
+            Ok(reply) => reply,
+            Err(e) => {
+                let code = e.raw_os_error().unwrap_or(libc::EIO);
+                debug!("9p: request {} failed: {}", type_, e);
+                reply_error(tag, code)
+            }
+        }
+    }
+
+    fn dispatch(&mut self, type_: u8, tag: u16, r: &mut Reader) -> io::Result<Vec<u8>> {
+        // unknown types are answered with Rlerror, so the reply type only matters for known ones
+        let mut w = Writer::new(type_.wrapping_add(1), tag);
+        match type_ {
+            P9_TVERSION => self.version(r, &mut w)?,
+            P9_TATTACH => self.attach(r, &mut w)?,
+            P9_TWALK => self.walk(r, &mut w)?,
+            P9_TGETATTR => self.getattr(r, &mut w)?,
+            P9_TSETATTR => self.setattr(r)?,
+            P9_TLOPEN => self.lopen(r, &mut w)?,
+            P9_TLCREATE => self.lcreate(r, &mut w)?,
+            P9_TREAD => self.read(r, &mut w)?,
+            P9_TWRITE => self.write(r, &mut w)?,
+            P9_TCLUNK => {
+                self.fids.remove(&r.u32()?);
+            }
+            P9_TREMOVE => self.remove(r)?,
+            P9_TREADDIR => self.readdir(r, &mut w)?,
+            P9_TSTATFS => self.statfs(r, &mut w)?,
+            P9_TMKDIR => self.mkdir(r, &mut w)?,
+            P9_TUNLINKAT => self.unlinkat(r)?,
+            P9_TRENAMEAT => self.renameat(r)?,
+            P9_TRENAME => self.rename(r)?,
+            P9_TREADLINK => self.readlink(r, &mut w)?,
+            P9_TSYMLINK => self.symlink(r, &mut w)?,
+            P9_TMKNOD => self.mknod(r, &mut w)?,
+            P9_TLINK => self.link(r)?,
+            P9_TFSYNC => self.fsync(r)?,
+            P9_TLOCK => {
+                // Locks are not shared with the host, the guest kernel serializes its own users.
+                w.u8(P9_LOCK_SUCCESS);
+            }
+            P9_TGETLOCK => self.getlock(r, &mut w)?,
+            // Requests are processed synchronously, so there is nothing to abort.
+            P9_TFLUSH => {}
+            P9_TAUTH | P9_TXATTRWALK => return Err(errno(libc::EOPNOTSUPP)),
+            _ => {
+                warn!("9p: unsupported request type {}", type_);
+                return Err(errno(libc::EOPNOTSUPP));
+            }
+        }
+        Ok(w.finish())
+    }
+
+    fn fid(&self, fid: u32) -> io::Result<&Fid> {
+        self.fids.get(&fid).ok_or_else(|| errno(libc::EBADF))
+    }
+
+    fn fid_mut(&mut self, fid: u32) -> io::Result<&mut Fid> {
+        self.fids.get_mut(&fid).ok_or_else(|| errno(libc::EBADF))
+    }
+
+    /// Returns the directory of `dfid` and `name` to be used with it.
+    fn child(&self, dfid: u32, name: &str) -> io::Result<(&File, CString)> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(errno(libc::EINVAL));
+        }
+        let name = CString::new(name).map_err(|_| errno(libc::EINVAL))?;
+        Ok((&self.fid(dfid)?.fd, name))
+    }
+
+    fn new_fid(&self, fd: File, uid: u32) -> Fid {
+        Fid {
+            fd,
+            uid,
+            file: None,
+            dirents: vec![],
+        }
+    }
+
+    /// Sets the owner of the newly created `name` in `dir` to the attached user.
+    fn chown_new(&self, dir: &File, name: &CStr, uid: u32, gid: u32) {
+        let res = check(unsafe {
+            libc::fchownat(
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                uid,
+                gid,
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        });
+        if let Err(e) = res {
+            debug!("9p: cannot chown {:?}: {}", name, e);
+        }
+    }
+
+    fn version(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let msize = r.u32()?;
+        let version = r.string()?;
+        // a new session starts
+        self.fids.clear();
+        self.msize = std::cmp::min(msize, MAX_MSIZE);
+        w.u32(self.msize);
+        if version.starts_with("9P2000.L") {
+            w.string("9P2000.L");
+        } else {
+            w.string("unknown");
+        }
+        Ok(())
+    }
+
+    fn attach(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let _afid = r.u32()?;
+        let _uname = r.string()?;
+        let _aname = r.string()?;
+        let uid = r.u32()?;
+        let fd = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_DIRECTORY)
+            .open(&self.root)?;
+        let meta = fd.metadata()?;
+        self.root_id = (meta.dev(), meta.ino());
+        let new = self.new_fid(fd, uid);
+        self.fids.insert(fid, new);
+        w.qid(&Qid::from(&meta));
+        Ok(())
+    }
+
+    fn walk(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let newfid = r.u32()?;
+        let nwname = r.u16()?;
+        let (mut fd, uid) = {
+            let f = self.fid(fid)?;
+            (f.fd.try_clone()?, f.uid)
+        };
+        let mut qids = vec![];
+        for i in 0..nwname {
+            let name = r.string()?;
+            let meta = fd.metadata()?;
+            // Never walk through symlinks or out of the exported directory. Symlinks and other
+            // files have no entries, so opening a name in them fails with ENOTDIR.
+            let next = match name.as_str() {
+                "." => fd.try_clone(),
+                ".." if (meta.dev(), meta.ino()) == self.root_id => fd.try_clone(),
+                _ if name.is_empty() || name.contains('/') => return Err(errno(libc::EINVAL)),
+                _ => CString::new(name.as_str())
+                    .map_err(|_| errno(libc::EINVAL))
+                    .and_then(|name| open_path(&fd, &name)),
+            };
+            match next.and_then(|next| Ok((next.metadata()?, next))) {
+                Ok((meta, next)) => {
+                    qids.push(Qid::from(&meta));
+                    fd = next;
+                }
+                Err(e) if i == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        if qids.len() == nwname as usize {
+            let new = self.new_fid(fd, uid);
+            self.fids.insert(newfid, new);
+        }
+        w.u16(qids.len() as u16);
+        for qid in &qids {
+            w.qid(qid);
+        }
+        Ok(())
+    }
+
+    fn getattr(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let _request_mask = r.u64()?;
+        let meta = self.fid(fid)?.fd.metadata()?;
+        w.u64(P9_GETATTR_BASIC);
+        w.qid(&Qid::from(&meta));
+        w.u32(meta.mode());
+        w.u32(meta.uid());
+        w.u32(meta.gid());
+        w.u64(meta.nlink());
+        w.u64(meta.rdev());
+        w.u64(meta.size());
+        w.u64(meta.blksize());
+        w.u64(meta.blocks());
+        w.u64(meta.atime() as u64);
+        w.u64(meta.atime_nsec() as u64);
+        w.u64(meta.mtime() as u64);
+        w.u64(meta.mtime_nsec() as u64);
+        w.u64(meta.ctime() as u64);
+        w.u64(meta.ctime_nsec() as u64);
+        // btime, gen and data_version are not part of the basic mask
+        for _ in 0..4 {
+            w.u64(0);
+        }
+        Ok(())
+    }
+
+    fn setattr(&mut self, r: &mut Reader) -> io::Result<()> {
+        let fid = r.u32()?;
+        let valid = r.u32()?;
+        let mode = r.u32()?;
+        let uid = r.u32()?;
+        let gid = r.u32()?;
+        let size = r.u64()?;
+        let atime = (r.u64()?, r.u64()?);
+        let mtime = (r.u64()?, r.u64()?);
+        let fd = &self.fid(fid)?.fd;
+        // changes the file of the fid itself, even if it is a symlink
+        let path = cstring(&proc_path(fd))?;
+
+        if valid & P9_SETATTR_MODE != 0 {
+            check(unsafe { libc::chmod(path.as_ptr(), mode & 0o7777) })?;
+        }
+        if valid & (P9_SETATTR_UID | P9_SETATTR_GID) != 0 {
+            let uid = if valid & P9_SETATTR_UID != 0 {
+                uid
+            } else {
+                u32::MAX
+            };
+            let gid = if valid & P9_SETATTR_GID != 0 {
+                gid
+            } else {
+                u32::MAX
+            };
+            check(unsafe {
+                libc::fchownat(
+                    fd.as_raw_fd(),
+                    EMPTY_PATH.as_ptr() as *const libc::c_char,
+                    uid,
+                    gid,
+                    libc::AT_EMPTY_PATH,
+                )
+            })?;
+        }
+        if valid & P9_SETATTR_SIZE != 0 {
+            // opening anything but a regular file could open a device
+            if !fd.metadata()?.is_file() {
+                return Err(errno(libc::EINVAL));
+            }
+            OpenOptions::new()
+                .write(true)
+                .open(proc_path(fd))?
+                .set_len(size)?;
+        }
+        if valid & (P9_SETATTR_ATIME | P9_SETATTR_MTIME) != 0 {
+            let time = |set: u32, explicit: u32, (sec, nsec): (u64, u64)| {
+                if valid & set == 0 {
+                    libc::timespec {
+                        tv_sec: 0,
+                        tv_nsec: libc::UTIME_OMIT,
+                    }
+                } else if valid & explicit == 0 {
+                    libc::timespec {
+                        tv_sec: 0,
+                        tv_nsec: libc::UTIME_NOW,
+                    }
+                } else {
+                    libc::timespec {
+                        tv_sec: sec as libc::time_t,
+                        tv_nsec: nsec as libc::c_long,
+                    }
+                }
+            };
+            let times = [
+                time(P9_SETATTR_ATIME, P9_SETATTR_ATIME_SET, atime),
+                time(P9_SETATTR_MTIME, P9_SETATTR_MTIME_SET, mtime),
+            ];
+            check(unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), 0) })?;
+        }
+        Ok(())
+    }
+
+    fn lopen(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let flags = r.u32()? as i32;
+        let f = self.fid_mut(fid)?;
+        let meta = f.fd.metadata()?;
+        let file = if meta.is_dir() {
+            OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_DIRECTORY)
+                .open(proc_path(&f.fd))?
+        } else if meta.is_file() {
+            let accmode = flags & libc::O_ACCMODE;
+            OpenOptions::new()
+                .read(accmode == libc::O_RDONLY || accmode == libc::O_RDWR)
+                .write(accmode == libc::O_WRONLY || accmode == libc::O_RDWR)
+                .custom_flags(flags & LOPEN_FLAGS & !libc::O_ACCMODE)
+                .open(proc_path(&f.fd))?
+        } else if meta.file_type().is_symlink() {
+            return Err(errno(libc::ELOOP));
+        } else {
+            // device nodes, fifos and sockets of the host are not opened for the guest
+            return Err(errno(libc::EOPNOTSUPP));
+        };
+        f.file = Some(file);
+        w.qid(&Qid::from(&meta));
+        // let the guest pick the io size based on msize
+        w.u32(0);
+        Ok(())
+    }
+
+    fn lcreate(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let name = r.string()?;
+        let flags = r.u32()? as i32;
+        let mode = r.u32()?;
+        let gid = r.u32()?;
+        let file = {
+            let (dir, name) = self.child(fid, &name)?;
+            let flags = (flags & LOPEN_FLAGS) | libc::O_CREAT | libc::O_EXCL;
+            let file = open_at(dir, &name, flags, mode & 0o7777)?;
+            self.chown_new(dir, &name, self.fid(fid)?.uid, gid);
+            file
+        };
+        let meta = file.metadata()?;
+        // the fid now refers to the new file
+        let f = self.fid_mut(fid)?;
+        f.fd = file.try_clone()?;
+        f.file = Some(file);
+        w.qid(&Qid::from(&meta));
+        w.u32(0);
+        Ok(())
+    }
+
+    fn read(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let offset = r.u64()?;
+        let count = std::cmp::min(r.u32()?, self.msize.saturating_sub(IO_HEADER_SIZE));
+        let file = self
+            .fid(fid)?
+            .file
+            .as_ref()
+            .ok_or_else(|| errno(libc::EBADF))?;
+        let mut buf = vec![0; count as usize];
+        let n = loop {
+            match file.read_at(&mut buf, offset) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                res => break res?,
+            }
+        };
+        w.u32(n as u32);
+        w.bytes(&buf[..n]);
+        Ok(())
+    }
+
+    fn write(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let offset = r.u64()?;
+        let count = r.u32()?;
+        let data = r.bytes(count as usize)?;
+        let file = self
+            .fid(fid)?
+            .file
+            .as_ref()
+            .ok_or_else(|| errno(libc::EBADF))?;
+        let n = loop {
+            match file.write_at(data, offset) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                res => break res?,
+            }
+        };
+        w.u32(n as u32);
+        Ok(())
+    }
+
+    fn remove(&mut self, r: &mut Reader) -> io::Result<()> {
+        let fid = r.u32()?;
+        // the fid is clunked even if the remove fails
+        self.fids.remove(&fid).ok_or_else(|| errno(libc::EBADF))?;
+        // A fid does not know its name. Linux guests use Tunlinkat and only fall back to
+        // Tremove if that is not supported.
+        Err(errno(libc::EOPNOTSUPP))
+    }
+
+    fn list_dir(&self, dir: &File) -> io::Result<Vec<DirEntry>> {
+        let path = proc_path(dir);
+        let mut entries = vec![];
+        for name in &[".", ".."] {
+            let meta = fs::symlink_metadata(path.join(name))?;
+            entries.push(DirEntry {
+                qid: Qid::from(&meta),
+                type_: libc::DT_DIR,
+                name: name.to_string(),
+            });
+        }
+        for entry in fs::read_dir(&path)? {
+            let entry = entry?;
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(name) => {
+                    debug!("9p: skipping non utf-8 file name {:?}", name);
+                    continue;
+                }
+            };
+            let meta = match entry.metadata() {
+                Ok(meta) => meta,
+                // removed in the meantime
+                Err(_) => continue,
+            };
+            let ft = meta.file_type();
+            let type_ = if ft.is_dir() {
+                libc::DT_DIR
+            } else if ft.is_symlink() {
+                libc::DT_LNK
+            } else if ft.is_file() {
+                libc::DT_REG
+            } else {
+                libc::DT_UNKNOWN
+            };
+            entries.push(DirEntry {
+                qid: Qid::from(&meta),
+                type_,
+                name,
+            });
+        }
+        Ok(entries)
+    }
+
+    fn readdir(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let offset = r.u64()?;
+        let count = std::cmp::min(r.u32()?, self.msize.saturating_sub(IO_HEADER_SIZE)) as usize;
+        if offset == 0 {
+            let entries = self.list_dir(&self.fid(fid)?.fd)?;
+            self.fid_mut(fid)?.dirents = entries;
+        }
+        let f = self.fid(fid)?;
+        let mut data = vec![];
+        for (i, entry) in f.dirents.iter().enumerate().skip(offset as usize) {
+            // qid[13] offset[8] type[1] name[s]
+            let size = QID_SIZE + 8 + 1 + 2 + entry.name.len();
+            if data.len() + size > count {
+                break;
+            }
+            let mut e = Writer::new(0, 0);
+            e.qid(&entry.qid);
+            e.u64(i as u64 + 1);
+            e.u8(entry.type_);
+            e.string(&entry.name);
+            data.extend_from_slice(&e.finish()[HEADER_SIZE..]);
+        }
+        w.u32(data.len() as u32);
+        w.bytes(&data);
+        Ok(())
+    }
+
+    fn statfs(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let fd = self.fid(fid)?.fd.as_raw_fd();
+        let mut st = MaybeUninit::<libc::statvfs>::uninit();
+        check(unsafe { libc::fstatvfs(fd, st.as_mut_ptr()) })?;
+        let st = unsafe { st.assume_init() };
+        w.u32(V9FS_MAGIC);
+        w.u32(st.f_bsize as u32);
+        w.u64(st.f_blocks);
+        w.u64(st.f_bfree);
+        w.u64(st.f_bavail);
+        w.u64(st.f_files);
+        w.u64(st.f_ffree);
+        w.u64(st.f_fsid);
+        w.u32(st.f_namemax as u32);
+        Ok(())
+    }
+
+    fn mkdir(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let dfid = r.u32()?;
+        let name = r.string()?;
+        let mode = r.u32()?;
+        let gid = r.u32()?;
+        let (dir, name) = self.child(dfid, &name)?;
+        check(unsafe { libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), mode & 0o7777) })?;
+        self.chown_new(dir, &name, self.fid(dfid)?.uid, gid);
+        w.qid(&Qid::from(&open_path(dir, &name)?.metadata()?));
+        Ok(())
+    }
+
+    fn unlinkat(&mut self, r: &mut Reader) -> io::Result<()> {
+        let dfid = r.u32()?;
+        let name = r.string()?;
+        let flags = r.u32()? as i32;
+        let (dir, name) = self.child(dfid, &name)?;
+        check(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), flags & libc::AT_REMOVEDIR) })
+    }
+
+    fn renameat(&mut self, r: &mut Reader) -> io::Result<()> {
+        let olddfid = r.u32()?;
+        let oldname = r.string()?;
+        let newdfid = r.u32()?;
+        let newname = r.string()?;
+        let (olddir, oldname) = self.child(olddfid, &oldname)?;
+        let (newdir, newname) = self.child(newdfid, &newname)?;
+        check(unsafe {
+            libc::renameat(
+                olddir.as_raw_fd(),
+                oldname.as_ptr(),
+                newdir.as_raw_fd(),
+                newname.as_ptr(),
+            )
+        })
+    }
+
+    fn rename(&mut self, r: &mut Reader) -> io::Result<()> {
+        let _fid = r.u32()?;
+        // see Tremove: Linux guests use Trenameat instead
+        Err(errno(libc::EOPNOTSUPP))
+    }
+
+    fn readlink(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let fd = self.fid(fid)?.fd.as_raw_fd();
+        let mut buf = vec![0u8; libc::PATH_MAX as usize];
+        let len = unsafe {
+            libc::readlinkat(
+                fd,
+                EMPTY_PATH.as_ptr() as *const libc::c_char,
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let target = std::str::from_utf8(&buf[..len as usize]).map_err(|_| errno(libc::EINVAL))?;
+        w.string(target);
+        Ok(())
+    }
+
+    fn symlink(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let dfid = r.u32()?;
+        let name = r.string()?;
+        let target = r.string()?;
+        let gid = r.u32()?;
+        let target = CString::new(target).map_err(|_| errno(libc::EINVAL))?;
+        let (dir, name) = self.child(dfid, &name)?;
+        check(unsafe { libc::symlinkat(target.as_ptr(), dir.as_raw_fd(), name.as_ptr()) })?;
+        self.chown_new(dir, &name, self.fid(dfid)?.uid, gid);
+        w.qid(&Qid::from(&open_path(dir, &name)?.metadata()?));
+        Ok(())
+    }
+
+    fn mknod(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let dfid = r.u32()?;
+        let name = r.string()?;
+        let mode = r.u32()?;
+        let major = r.u32()?;
+        let minor = r.u32()?;
+        let gid = r.u32()?;
+        // vmsh runs as root, device nodes would give the guest access to the devices of the host
+        match mode & libc::S_IFMT {
+            libc::S_IFREG | libc::S_IFIFO | libc::S_IFSOCK => {}
+            _ => {
+                debug!("9p: refusing mknod of device {}:{}", major, minor);
+                return Err(errno(libc::EPERM));
+            }
+        }
+        let (dir, name) = self.child(dfid, &name)?;
+        check(unsafe { libc::mknodat(dir.as_raw_fd(), name.as_ptr(), mode, 0) })?;
+        self.chown_new(dir, &name, self.fid(dfid)?.uid, gid);
+        w.qid(&Qid::from(&open_path(dir, &name)?.metadata()?));
+        Ok(())
+    }
+
+    fn link(&mut self, r: &mut Reader) -> io::Result<()> {
+        let dfid = r.u32()?;
+        let fid = r.u32()?;
+        let name = r.string()?;
+        let (dir, name) = self.child(dfid, &name)?;
+        let fd = self.fid(fid)?.fd.as_raw_fd();
+        check(unsafe {
+            libc::linkat(
+                fd,
+                EMPTY_PATH.as_ptr() as *const libc::c_char,
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                libc::AT_EMPTY_PATH,
+            )
+        })
+    }
+
+    fn fsync(&mut self, r: &mut Reader) -> io::Result<()> {
+        let fid = r.u32()?;
+        match &self.fid(fid)?.file {
+            Some(file) => file.sync_all(),
+            None => Err(errno(libc::EBADF)),
+        }
+    }
+
+    fn getlock(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let _fid = r.u32()?;
+        let _type = r.u8()?;
+        let start = r.u64()?;
+        let length = r.u64()?;
+        let proc_id = r.u32()?;
+        let client_id = r.string()?;
+        // see Tlock: there are never conflicting locks
+        w.u8(P9_LOCK_TYPE_UNLCK);
+        w.u64(start);
+        w.u64(length);
+        w.u32(proc_id);
+        w.string(&client_id);
+        Ok(())
+    }
+}
+
+fn reply_error(tag: u16, code: i32) -> Vec<u8> {
+    let mut w = Writer::new(P9_RLERROR, tag);
+    w.u32(code as u32);
+    w.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ioutils::tmp::tempdir;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn request(server: &mut Server, type_: u8, fill: impl FnOnce(&mut Writer)) -> Vec<u8> {
+        let mut w = Writer::new(type_, 1);
+        fill(&mut w);
+        server.handle(&w.finish())
+    }
+
+    /// errno of an Rlerror
+    fn error(reply: &[u8]) -> Option<i32> {
+        if reply[4] != P9_RLERROR {
+            return None;
+        }
+        let mut r = Reader::new(&reply[HEADER_SIZE..]);
+        Some(r.u32().unwrap() as i32)
+    }
+
+    fn attach(server: &mut Server) {
+        let reply = request(server, P9_TATTACH, |w| {
+            w.u32(0);
+            w.u32(u32::MAX);
+            w.string("root");
+            w.string("");
+            w.u32(0);
+        });
+        assert_eq!(error(&reply), None);
+    }
+
+    fn walk(server: &mut Server, fid: u32, newfid: u32, names: &[&str]) -> Vec<u8> {
+        request(server, P9_TWALK, |w| {
+            w.u32(fid);
+            w.u32(newfid);
+            w.u16(names.len() as u16);
+            for name in names {
+                w.string(name);
+            }
+        })
+    }
+
+    #[test]
+    fn setattr_does_not_follow_symlinks() {
+        let tmp = tempdir().unwrap();
+        let export = tmp.path().join("export");
+        fs::create_dir(&export).unwrap();
+        let secret = tmp.path().join("secret");
+        fs::write(&secret, "secret").unwrap();
+        fs::set_permissions(&secret, fs::Permissions::from_mode(0o600)).unwrap();
+        std::os::unix::fs::symlink(&secret, export.join("x")).unwrap();
+
+        let mut server = Server::new(export);
+        attach(&mut server);
+        assert_eq!(error(&walk(&mut server, 0, 1, &["x"])), None);
+        let reply = request(&mut server, P9_TSETATTR, |w| {
+            w.u32(1);
+            w.u32(P9_SETATTR_MODE | P9_SETATTR_SIZE);
+            w.u32(0o777);
+            w.u32(0);
+            w.u32(0);
+            w.u64(0);
+            for _ in 0..4 {
+                w.u64(0);
+            }
+        });
+        assert!(error(&reply).is_some());
+        assert_eq!(fs::read(&secret).unwrap(), b"secret");
+        let mode = fs::metadata(&secret).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o600);
+    }
+
+    #[test]
+    fn fid_stays_in_walked_directory() {
+        let tmp = tempdir().unwrap();
+        let export = tmp.path().join("export");
+        fs::create_dir_all(export.join("d")).unwrap();
+        let outside = tmp.path().join("outside");
+        fs::create_dir(&outside).unwrap();
+
+        let mut server = Server::new(export.clone());
+        attach(&mut server);
+        assert_eq!(error(&walk(&mut server, 0, 1, &["d"])), None);
+        // replace the walked directory with a symlink out of the export
+        fs::rename(export.join("d"), export.join("moved")).unwrap();
+        std::os::unix::fs::symlink(&outside, export.join("d")).unwrap();
+
+        let reply = request(&mut server, P9_TLCREATE, |w| {
+            w.u32(1);
+            w.string("f");
+            w.u32(libc::O_RDWR as u32);
+            w.u32(0o644);
+            w.u32(0);
+        });
+        assert_eq!(error(&reply), None);
+        assert!(export.join("moved/f").exists());
+        assert!(!outside.join("f").exists());
+
+        // the symlink is not walked through and ".." does not leave the export
+        let reply = walk(&mut server, 0, 2, &["d", "f"]);
+        assert_eq!(error(&reply), None);
+        // nwqid[2]: the walk stops at the symlink
+        assert_eq!(&reply[HEADER_SIZE..HEADER_SIZE + 2], &1u16.to_le_bytes());
+        assert_eq!(error(&walk(&mut server, 2, 3, &[])), Some(libc::EBADF));
+        let reply = walk(&mut server, 0, 2, &["..", "..", "moved"]);
+        assert_eq!(error(&reply), None);
+        assert_eq!(error(&walk(&mut server, 2, 3, &["f"])), None);
+    }
+
+    #[test]
+    fn mknod_refuses_devices() {
+        let tmp = tempdir().unwrap();
+        let mut server = Server::new(tmp.path().to_path_buf());
+        attach(&mut server);
+        let mknod = |server: &mut Server, name: &str, mode: u32| {
+            request(server, P9_TMKNOD, |w| {
+                w.u32(0);
+                w.string(name);
+                w.u32(mode);
+                w.u32(1);
+                w.u32(3);
+                w.u32(0);
+            })
+        };
+        let reply = mknod(&mut server, "null", libc::S_IFCHR | 0o666);
+        assert_eq!(error(&reply), Some(libc::EPERM));
+        assert!(!tmp.path().join("null").exists());
+        let reply = mknod(&mut server, "fifo", libc::S_IFIFO | 0o666);
+        assert_eq!(error(&reply), None);
+    }
+
+    #[test]
+    fn unknown_request_type() {
+        let tmp = tempdir().unwrap();
+        let mut server = Server::new(tmp.path().to_path_buf());
+        let reply = request(&mut server, u8::MAX, |_| {});
+        assert_eq!(error(&reply), Some(libc::EOPNOTSUPP));
+    }
+}
@@ -0,0 +1,463 @@
+// virtio over PCI (virtio 1.1, section 4.1).
+//
+// Every device gets a single 64-bit memory BAR that holds the common, ISR, device and notify
+// configuration structures. The BAR is trapped like any other virtio-mmio region (vm exits or
+// ioregionfd). The PCI configuration space of all devices lives in one small window emulated by
+// `PciBus`. Stage1 hands it to the guest kernel as the config space of a new PCI root bus.
+// That is why this is not a standard ECAM layout: every slot on bus 0 gets one page, and only
+// function 0 exists.
+
+use std::sync::atomic::Ordering;
+
+use simple_error::SimpleError;
+use virtio_device::{VirtioQueueNotifiable, WithDriverSelect};
+use virtio_queue::QueueT;
+use vm_device::bus::{MmioAddress, MmioRange};
+use vm_device::MutDeviceMmio;
+
+use crate::devices::MaybeIoRegionFd;
+use crate::kvm::hypervisor::ioregionfd::IoRegionFd;
+
+/// How virtio devices are exposed to the guest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transport {
+    /// virtio-mmio platform devices
+    Mmio,
+    /// virtio-pci devices on a PCI root bus created by stage1
+    Pci,
+}
+
+impl std::str::FromStr for Transport {
+    type Err = SimpleError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "mmio" => Ok(Transport::Mmio),
+            "pci" => Ok(Transport::Pci),
+            _ => Err(SimpleError::new(format!("unsupported transport: {}", s))),
+        }
+    }
+}
+
+/// Size of the memory BAR of each virtio-pci device. BARs must be naturally aligned.
+pub const PCI_BAR_SIZE: u64 = 0x4000;
+
+/// Size of the configuration space of one PCI function in the `PciBus` window.
+pub const PCI_SLOT_SIZE: u64 = 0x1000;
+
+// Offsets of the virtio structures inside the BAR.
+const COMMON_CFG_OFFSET: u64 = 0x0000;
+const COMMON_CFG_SIZE: u64 = 0x38;
+const ISR_CFG_OFFSET: u64 = 0x1000;
+const DEVICE_CFG_OFFSET: u64 = 0x2000;
+/// All queues share the same notification address, the queue index is written as value.
+pub const PCI_NOTIFY_OFFSET: u64 = 0x3000;
+const STRUCT_SIZE: u64 = 0x1000;
+
+// struct virtio_pci_common_cfg
+const DEVICE_FEATURE_SELECT: u64 = 0x00;
+const DEVICE_FEATURE: u64 = 0x04;
+const DRIVER_FEATURE_SELECT: u64 = 0x08;
+const DRIVER_FEATURE: u64 = 0x0c;
+const MSIX_CONFIG: u64 = 0x10;
+const NUM_QUEUES: u64 = 0x12;
+const DEVICE_STATUS: u64 = 0x14;
+const CONFIG_GENERATION: u64 = 0x15;
+const QUEUE_SELECT: u64 = 0x16;
+const QUEUE_SIZE: u64 = 0x18;
+const QUEUE_MSIX_VECTOR: u64 = 0x1a;
+const QUEUE_ENABLE: u64 = 0x1c;
+const QUEUE_NOTIFY_OFF: u64 = 0x1e;
+const QUEUE_DESC_LO: u64 = 0x20;
+const QUEUE_DESC_HI: u64 = 0x24;
+const QUEUE_AVAIL_LO: u64 = 0x28;
+const QUEUE_AVAIL_HI: u64 = 0x2c;
+const QUEUE_USED_LO: u64 = 0x30;
+const QUEUE_USED_HI: u64 = 0x34;
+
+// We do not provide a MSI-X capability, so the driver falls back to the legacy interrupt, see
+// `interrupt::GsiBackend`.
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
+// Device status bits that restrict which registers the driver may write.
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+
+/// Implemented by virtio devices that can be accessed through the virtio-pci BAR layout of
+/// this module, similar to `VirtioMmioDevice`.
+pub trait VirtioPciDevice: WithDriverSelect + VirtioQueueNotifiable {
+    fn pci_read(&mut self, offset: u64, data: &mut [u8]) {
+        match offset {
+            o if o < COMMON_CFG_OFFSET + COMMON_CFG_SIZE => {
+                let value = self.common_cfg_read(o - COMMON_CFG_OFFSET);
+                let bytes = value.to_le_bytes();
+                let len = data.len().min(bytes.len());
+                data[..len].copy_from_slice(&bytes[..len]);
+            }
+            o if (ISR_CFG_OFFSET..ISR_CFG_OFFSET + STRUCT_SIZE).contains(&o) => {
+                // Reading the ISR status acknowledges the interrupt.
+                let isr = self.interrupt_status().swap(0, Ordering::SeqCst);
+                data.iter_mut().for_each(|b| *b = 0);
+                if let Some(b) = data.first_mut() {
+                    *b = isr;
+                }
+            }
+            o if (DEVICE_CFG_OFFSET..DEVICE_CFG_OFFSET + STRUCT_SIZE).contains(&o) => {
+                self.read_config((o - DEVICE_CFG_OFFSET) as usize, data);
+            }
+            _ => {
+                log::warn!("unhandled virtio-pci read at offset {:#x}", offset);
+                data.iter_mut().for_each(|b| *b = 0);
+            }
+        }
+    }
+
+    fn pci_write(&mut self, offset: u64, data: &[u8]) {
+        match offset {
+            o if o < COMMON_CFG_OFFSET + COMMON_CFG_SIZE => {
+                let mut bytes = [0u8; 4];
+                let len = data.len().min(bytes.len());
+                bytes[..len].copy_from_slice(&data[..len]);
+                self.common_cfg_write(o - COMMON_CFG_OFFSET, u32::from_le_bytes(bytes));
+            }
+            o if (DEVICE_CFG_OFFSET..DEVICE_CFG_OFFSET + STRUCT_SIZE).contains(&o) => {
+                self.write_config((o - DEVICE_CFG_OFFSET) as usize, data);
+            }
+            o if (PCI_NOTIFY_OFFSET..PCI_NOTIFY_OFFSET + STRUCT_SIZE).contains(&o) => {
+                let mut bytes = [0u8; 4];
+                let len = data.len().min(bytes.len());
+                bytes[..len].copy_from_slice(&data[..len]);
+                self.queue_notify(u32::from_le_bytes(bytes));
+            }
+            _ => log::warn!("unhandled virtio-pci write at offset {:#x}", offset),
+        }
+    }
+
+    fn common_cfg_read(&self, offset: u64) -> u32 {
+        let queue = self.selected_queue();
+        match offset {
+            DEVICE_FEATURE_SELECT => self.device_features_select(),
+            DEVICE_FEATURE => match self.device_features_select() {
+                0 => self.device_features() as u32,
+                1 => (self.device_features() >> 32) as u32,
+                _ => 0,
+            },
+            DRIVER_FEATURE_SELECT => self.driver_features_select(),
+            DRIVER_FEATURE => match self.driver_features_select() {
+                0 => self.driver_features() as u32,
+                1 => (self.driver_features() >> 32) as u32,
+                _ => 0,
+            },
+            MSIX_CONFIG | QUEUE_MSIX_VECTOR => VIRTIO_MSI_NO_VECTOR as u32,
+            NUM_QUEUES => self.num_queues() as u32,
+            DEVICE_STATUS => self.device_status() as u32,
+            CONFIG_GENERATION => self.config_generation() as u32,
+            QUEUE_SELECT => self.queue_select() as u32,
+            QUEUE_SIZE => queue.map_or(0, |q| q.size() as u32),
+            QUEUE_ENABLE => queue.map_or(0, |q| q.ready() as u32),
+            QUEUE_NOTIFY_OFF => 0,
+            QUEUE_DESC_LO => queue.map_or(0, |q| q.desc_table() as u32),
+            QUEUE_DESC_HI => queue.map_or(0, |q| (q.desc_table() >> 32) as u32),
+            QUEUE_AVAIL_LO => queue.map_or(0, |q| q.avail_ring() as u32),
+            QUEUE_AVAIL_HI => queue.map_or(0, |q| (q.avail_ring() >> 32) as u32),
+            QUEUE_USED_LO => queue.map_or(0, |q| q.used_ring() as u32),
+            QUEUE_USED_HI => queue.map_or(0, |q| (q.used_ring() >> 32) as u32),
+            _ => {
+                log::warn!("unhandled virtio-pci common cfg read at {:#x}", offset);
+                0
+            }
+        }
+    }
+
+    fn common_cfg_write(&mut self, offset: u64, value: u32) {
+        let status = self.device_status();
+        let features_negotiable = status & STATUS_DRIVER != 0 && status & STATUS_FEATURES_OK == 0;
+        let queue_configurable = status & STATUS_FEATURES_OK != 0 && status & STATUS_DRIVER_OK == 0;
+
+        match offset {
+            DEVICE_FEATURE_SELECT => self.set_device_features_select(value),
+            DRIVER_FEATURE_SELECT => self.set_driver_features_select(value),
+            DRIVER_FEATURE if features_negotiable => {
+                let page = self.driver_features_select();
+                self.set_driver_features(page, value);
+            }
+            DEVICE_STATUS => self.set_device_status(value as u8),
+            QUEUE_SELECT => self.set_queue_select(value as u16),
+            // The driver may try to assign vectors, reading back VIRTIO_MSI_NO_VECTOR tells it
+            // that it has to use the legacy interrupt.
+            MSIX_CONFIG | QUEUE_MSIX_VECTOR => {}
+            QUEUE_SIZE | QUEUE_ENABLE | QUEUE_DESC_LO | QUEUE_DESC_HI | QUEUE_AVAIL_LO
+            | QUEUE_AVAIL_HI | QUEUE_USED_LO | QUEUE_USED_HI
+                if queue_configurable =>
+            {
+                let queue = match self.selected_queue_mut() {
+                    Some(q) => q,
+                    None => return,
+                };
+                match offset {
+                    QUEUE_SIZE => queue.set_size(value as u16),
+                    QUEUE_ENABLE => queue.set_ready(value == 1),
+                    QUEUE_DESC_LO => queue.set_desc_table_address(Some(value), None),
+                    QUEUE_DESC_HI => queue.set_desc_table_address(None, Some(value)),
+                    QUEUE_AVAIL_LO => queue.set_avail_ring_address(Some(value), None),
+                    QUEUE_AVAIL_HI => queue.set_avail_ring_address(None, Some(value)),
+                    QUEUE_USED_LO => queue.set_used_ring_address(Some(value), None),
+                    _ => queue.set_used_ring_address(None, Some(value)),
+                }
+            }
+            _ => log::warn!(
+                "ignoring virtio-pci common cfg write at {:#x} (status {:#x})",
+                offset,
+                status
+            ),
+        }
+    }
+}
+
+// PCI configuration space header (type 0).
+const PCI_VENDOR_ID: usize = 0x00;
+const PCI_DEVICE_ID: usize = 0x02;
+const PCI_COMMAND: usize = 0x04;
+const PCI_STATUS: usize = 0x06;
+const PCI_REVISION_ID: usize = 0x08;
+const PCI_CLASS_DEVICE: usize = 0x0a;
+const PCI_BASE_ADDRESS_0: usize = 0x10;
+const PCI_BASE_ADDRESS_1: usize = 0x14;
+const PCI_SUBSYSTEM_VENDOR_ID: usize = 0x2c;
+const PCI_SUBSYSTEM_ID: usize = 0x2e;
+const PCI_CAPABILITY_LIST: usize = 0x34;
+const PCI_INTERRUPT_LINE: usize = 0x3c;
+const PCI_INTERRUPT_PIN: usize = 0x3d;
+
+const PCI_CONFIG_SIZE: usize = 0x100;
+const PCI_STATUS_CAP_LIST: u16 = 0x10;
+const PCI_BASE_ADDRESS_MEM_TYPE_64: u32 = 0x04;
+const PCI_CLASS_OTHERS: u16 = 0xff00;
+const PCI_CAP_ID_VNDR: u8 = 0x09;
+
+const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
+/// Modern virtio devices use 0x1040 + virtio device id as PCI device id.
+const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040;
+
+// struct virtio_pci_cap::cfg_type
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+struct PciFunction {
+    config: [u8; PCI_CONFIG_SIZE],
+    bar: u64,
+    /// The driver wrote all ones to the low/high half of the BAR to determine its size.
+    bar_sizing: [bool; 2],
+}
+
+impl PciFunction {
+    fn new(device_type: u32, bar: u64, irq: u8) -> PciFunction {
+        let mut config = [0u8; PCI_CONFIG_SIZE];
+        let mut put16 = |off: usize, v: u16| config[off..off + 2].copy_from_slice(&v.to_le_bytes());
+        put16(PCI_VENDOR_ID, VIRTIO_PCI_VENDOR_ID);
+        put16(
+            PCI_DEVICE_ID,
+            VIRTIO_PCI_DEVICE_ID_BASE + device_type as u16,
+        );
+        put16(PCI_STATUS, PCI_STATUS_CAP_LIST);
+        put16(PCI_CLASS_DEVICE, PCI_CLASS_OTHERS);
+        put16(PCI_SUBSYSTEM_VENDOR_ID, VIRTIO_PCI_VENDOR_ID);
+        put16(PCI_SUBSYSTEM_ID, device_type as u16);
+        // virtio 1.0 devices have a revision id of at least 1
+        config[PCI_REVISION_ID] = 1;
+        config[PCI_INTERRUPT_LINE] = irq;
+        // INTA#
+        config[PCI_INTERRUPT_PIN] = 1;
+
+        let caps: [(u8, u64, u64); 4] = [
+            (
+                VIRTIO_PCI_CAP_COMMON_CFG,
+                COMMON_CFG_OFFSET,
+                COMMON_CFG_SIZE,
+            ),
+            (VIRTIO_PCI_CAP_NOTIFY_CFG, PCI_NOTIFY_OFFSET, STRUCT_SIZE),
+            (VIRTIO_PCI_CAP_ISR_CFG, ISR_CFG_OFFSET, STRUCT_SIZE),
+            (VIRTIO_PCI_CAP_DEVICE_CFG, DEVICE_CFG_OFFSET, STRUCT_SIZE),
+        ];
+        let mut pos = 0x40;
+        config[PCI_CAPABILITY_LIST] = pos as u8;
+        for (i, (cfg_type, offset, length)) in caps.iter().enumerate() {
+            // the notify capability has an additional notify_off_multiplier, which we leave at 0
+            let cap_len = if *cfg_type == VIRTIO_PCI_CAP_NOTIFY_CFG {
+                20
+            } else {
+                16
+            };
+            let next = if i + 1 == caps.len() {
+                0
+            } else {
+                pos + cap_len
+            };
+            config[pos] = PCI_CAP_ID_VNDR;
+            config[pos + 1] = next as u8;
+            config[pos + 2] = cap_len as u8;
+            config[pos + 3] = *cfg_type;
+            // bar
+            config[pos + 4] = 0;
+            config[pos + 8..pos + 12].copy_from_slice(&(*offset as u32).to_le_bytes());
+            config[pos + 12..pos + 16].copy_from_slice(&(*length as u32).to_le_bytes());
+            pos = next;
+        }
+
+        PciFunction {
+            config,
+            bar,
+            bar_sizing: [false, false],
+        }
+    }
+
+    fn read_dword(&self, reg: usize) -> u32 {
+        let size_mask = !(PCI_BAR_SIZE - 1);
+        match reg {
+            PCI_BASE_ADDRESS_0 if self.bar_sizing[0] => {
+                size_mask as u32 | PCI_BASE_ADDRESS_MEM_TYPE_64
+            }
+            PCI_BASE_ADDRESS_0 => self.bar as u32 | PCI_BASE_ADDRESS_MEM_TYPE_64,
+            PCI_BASE_ADDRESS_1 if self.bar_sizing[1] => (size_mask >> 32) as u32,
+            PCI_BASE_ADDRESS_1 => (self.bar >> 32) as u32,
+            r if r + 4 <= PCI_CONFIG_SIZE => {
+                let mut bytes = [0u8; 4];
+                bytes.copy_from_slice(&self.config[r..r + 4]);
+                u32::from_le_bytes(bytes)
+            }
+            _ => 0,
+        }
+    }
+
+    fn read(&self, offset: usize, data: &mut [u8]) {
+        let bytes = self.read_dword(offset & !3).to_le_bytes();
+        let start = offset & 3;
+        let len = data.len().min(bytes.len() - start);
+        data[..len].copy_from_slice(&bytes[start..start + len]);
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) {
+        match offset {
+            // The BAR address is fixed, we only have to support the size probing done by
+            // the kernel. It restores the original address afterwards.
+            PCI_BASE_ADDRESS_0 | PCI_BASE_ADDRESS_1 if data.len() == 4 => {
+                let all_ones = data.iter().all(|b| *b == 0xff);
+                self.bar_sizing[(offset - PCI_BASE_ADDRESS_0) / 4] = all_ones;
+            }
+            o if (PCI_COMMAND..PCI_COMMAND + 2).contains(&o) => {
+                let len = data.len().min(PCI_COMMAND + 2 - o);
+                self.config[o..o + len].copy_from_slice(&data[..len]);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Location of the PCI devices in the guest's physical address space, passed to stage1.
+#[derive(Clone, Copy, Debug)]
+pub struct PciWindow {
+    /// configuration space, one `PCI_SLOT_SIZE` page per device
+    pub config_addr: u64,
+    /// memory window that contains the BARs of all devices
+    pub mem_start: u64,
+    pub mem_end: u64,
+}
+
+/// Emulates the configuration space of all virtio-pci devices on the root bus created by stage1.
+pub struct PciBus {
+    pub range: MmioRange,
+    functions: Vec<PciFunction>,
+    pub ioregionfd: Option<IoRegionFd>,
+}
+
+impl PciBus {
+    pub fn new(range: MmioRange) -> PciBus {
+        PciBus {
+            range,
+            functions: vec![],
+            ioregionfd: None,
+        }
+    }
+
+    /// Adds a device to the next free slot. `bar` is the range the device is registered at.
+    pub fn add_device(&mut self, device_type: u32, bar: MmioRange, irq: u32) {
+        self.functions
+            .push(PciFunction::new(device_type, bar.base().0, irq as u8));
+    }
+
+    pub fn window(&self, bars: &[u64]) -> PciWindow {
+        PciWindow {
+            config_addr: self.range.base().0,
+            mem_start: bars.iter().copied().min().unwrap_or(0),
+            mem_end: bars.iter().map(|b| b + PCI_BAR_SIZE - 1).max().unwrap_or(0),
+        }
+    }
+
+    fn function(&mut self, offset: u64) -> Option<&mut PciFunction> {
+        let slot = (offset / PCI_SLOT_SIZE) as usize;
+        self.functions.get_mut(slot)
+    }
+}
+
+impl MaybeIoRegionFd for PciBus {
+    fn get_ioregionfd(&mut self) -> &mut Option<IoRegionFd> {
+        &mut self.ioregionfd
+    }
+}
+
+impl MutDeviceMmio for PciBus {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
+        let reg = (offset % PCI_SLOT_SIZE) as usize;
+        match self.function(offset) {
+            Some(f) => f.read(reg, data),
+            // empty slots read as all ones
+            None => data.iter_mut().for_each(|b| *b = 0xff),
+        }
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
+        let reg = (offset % PCI_SLOT_SIZE) as usize;
+        if let Some(f) = self.function(offset) {
+            f.write(reg, data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bar_sizing() {
+        let bar = MmioRange::new(MmioAddress(0xd000_0000), PCI_BAR_SIZE).unwrap();
+        let mut bus = PciBus::new(MmioRange::new(MmioAddress(0xc000_0000), 0x1000).unwrap());
+        bus.add_device(2, bar, 6);
+        let base = bus.range.base();
+
+        let mut data = [0u8; 4];
+        bus.mmio_read(base, PCI_VENDOR_ID as u64, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0x1042_1af4);
+
+        bus.mmio_read(base, PCI_BASE_ADDRESS_0 as u64, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0xd000_0004);
+
+        bus.mmio_write(base, PCI_BASE_ADDRESS_0 as u64, &[0xff; 4]);
+        bus.mmio_read(base, PCI_BASE_ADDRESS_0 as u64, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0xffff_c004);
+
+        bus.mmio_write(
+            base,
+            PCI_BASE_ADDRESS_0 as u64,
+            &0xd000_0000u32.to_le_bytes(),
+        );
+        bus.mmio_read(base, PCI_BASE_ADDRESS_0 as u64, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0xd000_0004);
+
+        // second slot is empty
+        bus.mmio_read(base, PCI_SLOT_SIZE, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0xffff_ffff);
+    }
+}
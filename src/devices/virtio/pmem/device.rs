@@ -0,0 +1,273 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// Author of further modifications: Peter Okelmann
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::borrow::{Borrow, BorrowMut};
+use std::ops::DerefMut;
+use std::sync::{Arc, Mutex};
+
+use event_manager::{MutEventSubscriber, RemoteEndpoint, Result as EvmgrResult, SubscriberId};
+use virtio_device::VirtioDeviceType;
+use virtio_device::{VirtioConfig, VirtioDeviceActions, VirtioMmioDevice, VirtioQueueNotifiable};
+use virtio_queue::Queue;
+use vm_device::bus::MmioAddress;
+use vm_device::device_manager::MmioManager;
+use vm_device::{DeviceMmio, MutDeviceMmio};
+use vm_memory::GuestMemoryMmap;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::devices::virtio::features::{
+    VIRTIO_F_IN_ORDER, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_VERSION_1,
+};
+use crate::devices::virtio::interrupt::DeviceInterrupt;
+use crate::devices::virtio::pci::{Transport, VirtioPciDevice};
+use crate::devices::virtio::pmem::queue_handler::PmemQueueHandler;
+use crate::devices::virtio::{reset_virtio_config, IrqAckHandler, MmioConfig, SingleFdSignalQueue};
+use crate::devices::MaybeIoRegionFd;
+use crate::kvm::hypervisor::{
+    ioevent::IoEvent, ioregionfd::IoRegionFd, memory::PhysMem,
+    userspaceioeventfd::UserspaceIoEventFd,
+};
+
+use super::{build_config_space, Error, PmemArgs, Result, PMEM_DEVICE_ID};
+
+pub(super) const REQUEST_QUEUE_IDX: u16 = 0;
+
+pub struct Pmem {
+    virtio_cfg: VirtioConfig<Queue>,
+    pub mmio_cfg: MmioConfig,
+    endpoint: RemoteEndpoint<Arc<Mutex<dyn MutEventSubscriber + Send>>>,
+    pub irq_ack_handler: Arc<Mutex<IrqAckHandler>>,
+    irqfd: Arc<EventFd>,
+    pub ioregionfd: Option<IoRegionFd>,
+    pub uioefd: UserspaceIoEventFd,
+    mem: Arc<GuestMemoryMmap>,
+    queue_size: u16,
+    /// shared with the queue handler, it stays registered when the driver resets the device
+    ioeventfd: Arc<IoEvent>,
+    /// the memory exposed to the guest, kept until the device is dropped
+    #[allow(dead_code)]
+    phys_mem: PhysMem<u8>,
+    /// only used when ioregionfd != None
+    sub_id: Option<SubscriberId>,
+}
+
+impl Pmem {
+    pub fn new<B>(mut args: PmemArgs<B>) -> Result<Arc<Mutex<Self>>>
+    where
+        // We're using this (more convoluted) bound so we can pass both references and smart
+        // pointers such as mutex guards here.
+        B: DerefMut,
+        B::Target: MmioManager<D = Arc<dyn DeviceMmio + Send + Sync>>,
+    {
+        // The queue handling logic for this device uses the buffers in order, so we enable the
+        // corresponding feature as well.
+        let device_features =
+            1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_F_IN_ORDER | 1 << VIRTIO_F_RING_EVENT_IDX;
+
+        // The driver only sends flush requests, on a single queue.
+        let queues = vec![Queue::new(args.common.queue_size).map_err(Error::QueueCreation)?];
+
+        let config_space = build_config_space(
+            args.mem.guest_phys_addr.value as u64,
+            args.mem.mem.size() as u64,
+        );
+        let virtio_cfg = VirtioConfig::new(device_features, queues, config_space);
+
+        // Used to send notifications to the driver.
+        let DeviceInterrupt { irqfd, resamplefd } = args
+            .common
+            .interrupts
+            .connect(&args.common.mmio_cfg)
+            .map_err(Error::Simple)?;
+
+        let mmio_cfg = args.common.mmio_cfg;
+
+        let irq_ack_handler = Arc::new(Mutex::new(
+            IrqAckHandler::new(
+                virtio_cfg.interrupt_status.clone(),
+                Arc::clone(&irqfd),
+                resamplefd,
+            )
+            .map_err(Error::Simple)?,
+        ));
+
+        let mut ioregionfd = None;
+        if args.common.vmm.use_ioregionfd() {
+            ioregionfd = Some(
+                args.common
+                    .vmm
+                    .ioregionfd(mmio_cfg.range.base().0, mmio_cfg.range.size() as usize)
+                    .map_err(Error::Simple)?,
+            );
+        }
+
+        let mut uioefd = UserspaceIoEventFd::default();
+        let ioeventfd = IoEvent::register(
+            &args.common.vmm,
+            &mut uioefd,
+            &mmio_cfg,
+            REQUEST_QUEUE_IDX as u64,
+        )
+        .map_err(Error::Simple)?;
+
+        let pmem = Arc::new(Mutex::new(Pmem {
+            virtio_cfg,
+            mmio_cfg,
+            endpoint: args.common.event_mgr.remote_endpoint(),
+            irq_ack_handler,
+            irqfd,
+            ioregionfd,
+            mem: Arc::clone(&args.common.mem),
+            queue_size: args.common.queue_size,
+            ioeventfd: Arc::new(ioeventfd),
+            phys_mem: args.mem,
+            uioefd,
+            sub_id: None,
+        }));
+
+        // Register the device on the MMIO bus.
+        args.common
+            .mmio_mgr
+            .register_mmio(mmio_cfg.range, pmem.clone())
+            .map_err(Error::Bus)?;
+
+        Ok(pmem)
+    }
+
+    fn _activate(&mut self) -> Result<()> {
+        if self.virtio_cfg.device_activated {
+            return Err(Error::AlreadyActivated);
+        }
+
+        // We do not support legacy drivers.
+        if self.virtio_cfg.driver_features & (1 << VIRTIO_F_VERSION_1) == 0 {
+            return Err(Error::BadFeatures(self.virtio_cfg.driver_features));
+        }
+
+        let driver_notify = SingleFdSignalQueue {
+            irqfd: self.irqfd.clone(),
+            interrupt_status: self.virtio_cfg.interrupt_status.clone(),
+            ack_handler: self.irq_ack_handler.clone(),
+        };
+
+        let queue = self.virtio_cfg.queues.remove(REQUEST_QUEUE_IDX.into());
+
+        let handler = Arc::new(Mutex::new(PmemQueueHandler {
+            driver_notify,
+            queue,
+            ioeventfd: Arc::clone(&self.ioeventfd),
+            mem: Arc::clone(&self.mem),
+        }));
+
+        // Register the queue handler with the `EventManager`. We record the `sub_id`
+        // (and/or keep a handler clone) to remove the subscriber when resetting the device
+        let sub_id = self
+            .endpoint
+            .call_blocking(move |mgr| -> EvmgrResult<SubscriberId> {
+                Ok(mgr.add_subscriber(handler))
+            })
+            .map_err(|e| {
+                log::warn!("{}", e);
+                Error::Endpoint(e)
+            })?;
+        self.sub_id = Some(sub_id);
+
+        log::debug!("activating device: ok");
+        self.virtio_cfg.device_activated = true;
+
+        Ok(())
+    }
+
+    fn _reset(&mut self) -> Result<()> {
+        // Once removed, the handler no longer processes the queue and can be dropped. The
+        // ioeventfd stays registered with the hypervisor for the next activation.
+        if let Some(sub_id) = self.sub_id.take() {
+            self.endpoint
+                .call_blocking(move |mgr| mgr.remove_subscriber(sub_id))
+                .map_err(|e| {
+                    log::warn!("{}", e);
+                    Error::Endpoint(e)
+                })?;
+        }
+        // notifications of the old queue must not be processed by the next handler
+        let _ = self.ioeventfd.read();
+        reset_virtio_config(&mut self.virtio_cfg, 1, self.queue_size).map_err(Error::QueueCreation)
+    }
+}
+
+impl MaybeIoRegionFd for Pmem {
+    fn get_ioregionfd(&mut self) -> &mut Option<IoRegionFd> {
+        &mut self.ioregionfd
+    }
+}
+
+// We now implement `WithVirtioConfig` and `WithDeviceOps` to get the automatic implementation
+// for `VirtioDevice`.
+impl VirtioDeviceType for Pmem {
+    fn device_type(&self) -> u32 {
+        PMEM_DEVICE_ID
+    }
+}
+
+impl Borrow<VirtioConfig<Queue>> for Pmem {
+    fn borrow(&self) -> &VirtioConfig<Queue> {
+        &self.virtio_cfg
+    }
+}
+
+impl BorrowMut<VirtioConfig<Queue>> for Pmem {
+    fn borrow_mut(&mut self) -> &mut VirtioConfig<Queue> {
+        &mut self.virtio_cfg
+    }
+}
+
+impl VirtioDeviceActions for Pmem {
+    type E = Error;
+
+    /// make sure to set self.vmm.wrapper to Some() before activating. Typically this is done by
+    /// activating during vmm.kvmrun_wrapped()
+    fn activate(&mut self) -> Result<()> {
+        let ret = self._activate();
+        if let Err(ref e) = ret {
+            log::warn!("failed to activate pmem device: {:?}", e);
+        }
+        ret
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        let ret = self._reset();
+        if let Err(ref e) = ret {
+            log::warn!("failed to reset pmem device: {:?}", e);
+        }
+        ret
+    }
+}
+
+impl VirtioQueueNotifiable for Pmem {
+    fn queue_notify(&mut self, val: u32) {
+        // with ioeventfds in KVM, only notifications replayed by vmsh end up here
+        self.uioefd.queue_notify(val);
+        log::trace!("queue_notify {}", val);
+    }
+}
+
+impl VirtioMmioDevice for Pmem {}
+
+impl VirtioPciDevice for Pmem {}
+
+impl MutDeviceMmio for Pmem {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
+        match self.mmio_cfg.transport {
+            Transport::Mmio => self.read(offset, data),
+            Transport::Pci => self.pci_read(offset, data),
+        }
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
+        match self.mmio_cfg.transport {
+            Transport::Mmio => self.write(offset, data),
+            Transport::Pci => self.pci_write(offset, data),
+        }
+    }
+}
@@ -0,0 +1,57 @@
+mod device;
+mod queue_handler;
+
+use event_manager::Error as EvmgrError;
+use vm_device::bus;
+
+use crate::devices::virtio::CommonArgs;
+use crate::kvm::hypervisor::memory::PhysMem;
+use crate::result::VmshError;
+
+pub use device::Pmem;
+
+/// Persistent memory device ID as defined by the standard.
+pub const PMEM_DEVICE_ID: u32 = 27;
+
+/// Linux places the memory of pmem devices in 2M-aligned sections.
+pub const PMEM_ALIGN: usize = 2 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum Error {
+    AlreadyActivated,
+    BadFeatures(u64),
+    Bus(bus::Error),
+    Endpoint(EvmgrError),
+    QueueCreation(virtio_queue::Error),
+    Simple(VmshError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// struct virtio_pmem_config: guest physical start address and size of the memory.
+fn build_config_space(start: u64, size: u64) -> Vec<u8> {
+    let mut config = start.to_le_bytes().to_vec();
+    config.extend_from_slice(&size.to_le_bytes());
+    config
+}
+
+// Arguments required when building a pmem device.
+pub struct PmemArgs<'a, B> {
+    pub common: CommonArgs<'a, B>,
+    /// Read-only memslot backed by the exposed file, see `PhysMemAllocator::phys_alloc_file`.
+    /// It is removed from the VM when the device is dropped.
+    pub mem: PhysMem<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_space() {
+        let config = build_config_space(0x1_0000_0000, 0x20_0000);
+        assert_eq!(config.len(), 16);
+        assert_eq!(config[..8], 0x1_0000_0000u64.to_le_bytes());
+        assert_eq!(config[8..], 0x20_0000u64.to_le_bytes());
+    }
+}
@@ -0,0 +1,132 @@
+use std::result;
+use std::sync::Arc;
+
+use event_manager::{EventOps, EventSet, Events, MutEventSubscriber};
+use log::{error, warn};
+use virtio_queue::{Queue, QueueOwnedT, QueueT};
+use vm_memory::{self, Bytes, GuestMemoryMmap};
+
+use super::device::REQUEST_QUEUE_IDX;
+use crate::devices::virtio::SignalUsedQueue;
+use crate::kvm::hypervisor::ioevent::IoEvent;
+
+/// Request type of struct virtio_pmem_req
+const VIRTIO_PMEM_REQ_TYPE_FLUSH: u32 = 0;
+/// Size of struct virtio_pmem_resp
+const RESP_SIZE: u32 = 4;
+
+#[derive(Debug)]
+pub enum Error {
+    GuestMemory(vm_memory::GuestMemoryError),
+    Queue(virtio_queue::Error),
+}
+
+impl From<vm_memory::GuestMemoryError> for Error {
+    fn from(e: vm_memory::GuestMemoryError) -> Self {
+        Error::GuestMemory(e)
+    }
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+/// Stops handling `source` after an error.
+fn handle_error<Msg: AsRef<str>>(s: Msg, source: Events, ops: &mut EventOps) {
+    error!("{}", s.as_ref());
+    // the fd may already be removed after an earlier error of the same event
+    if let Err(e) = ops.remove(source) {
+        error!("Failed to remove pmem event: {:?}", e);
+    }
+}
+
+pub(crate) struct PmemQueueHandler<S: SignalUsedQueue> {
+    pub driver_notify: S,
+    pub queue: Queue,
+    pub ioeventfd: Arc<IoEvent>,
+    pub mem: Arc<GuestMemoryMmap>,
+}
+
+impl<S> PmemQueueHandler<S>
+where
+    S: SignalUsedQueue,
+{
+    /// Answers flush requests. The guest cannot write to the memory, so there is nothing to
+    /// write back to the file.
+    fn process_queue(&mut self) -> result::Result<(), Error> {
+        // To see why this is done in a loop, please look at the `Queue::enable_notification`
+        // comments in `vm_virtio`.
+        loop {
+            self.queue.disable_notification(self.mem.as_ref())?;
+
+            while let Some(mut chain) = self.queue.iter(self.mem.as_ref())?.next() {
+                let mut req_type = None;
+                let mut used_len = 0;
+                while let Some(desc) = chain.next() {
+                    if !desc.is_write_only() {
+                        req_type = Some(chain.memory().read_obj::<u32>(desc.addr())?);
+                        continue;
+                    }
+                    if desc.len() < RESP_SIZE {
+                        break;
+                    }
+                    // virtio_pmem_resp.ret: 0 on success, the driver reports EIO otherwise
+                    let ret: u32 = match req_type.map(u32::from_le) {
+                        Some(VIRTIO_PMEM_REQ_TYPE_FLUSH) => 0,
+                        other => {
+                            warn!("unsupported pmem request type {:?}", other);
+                            1
+                        }
+                    };
+                    chain.memory().write_obj(ret.to_le(), desc.addr())?;
+                    used_len = RESP_SIZE;
+                    break;
+                }
+                self.queue
+                    .add_used(self.mem.as_ref(), chain.head_index(), used_len)?;
+
+                if self.queue.needs_notification(self.mem.as_ref())? {
+                    self.driver_notify.signal_used_queue(REQUEST_QUEUE_IDX);
+                }
+            }
+
+            if !self.queue.enable_notification(self.mem.as_ref())? {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: SignalUsedQueue> MutEventSubscriber for PmemQueueHandler<S> {
+    fn process(&mut self, events: Events, ops: &mut EventOps) {
+        let _span =
+            tracing::trace_span!("queue-handler", device = "pmem", data = events.data()).entered();
+        if events.event_set() != EventSet::IN {
+            handle_error("Unexpected event_set", events, ops);
+            return;
+        }
+        if events.data() != REQUEST_QUEUE_IDX as u32 {
+            handle_error("Unexpected data", events, ops);
+            return;
+        }
+        if self.ioeventfd.read().is_err() {
+            handle_error("ioevent read", events, ops);
+            return;
+        }
+        if let Err(e) = self.process_queue() {
+            handle_error(format!("Process queue error {:?}", e), events, ops);
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        ops.add(Events::with_data(
+            self.ioeventfd.as_ref(),
+            REQUEST_QUEUE_IDX as u32,
+            EventSet::IN,
+        ))
+        .expect("Failed to register ioeventfd for pmem queue handler");
+    }
+}
@@ -0,0 +1,30 @@
+mod device;
+mod queue_handler;
+
+use event_manager::Error as EvmgrError;
+use vm_device::bus;
+
+use crate::devices::virtio::CommonArgs;
+use crate::result::VmshError;
+
+pub use device::Rng;
+
+/// Entropy source device ID as defined by the standard.
+pub const RNG_DEVICE_ID: u32 = 4;
+
+#[derive(Debug)]
+pub enum Error {
+    AlreadyActivated,
+    BadFeatures(u64),
+    Bus(bus::Error),
+    Endpoint(EvmgrError),
+    QueueCreation(virtio_queue::Error),
+    Simple(VmshError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Arguments required when building a rng device.
+pub struct RngArgs<'a, B> {
+    pub common: CommonArgs<'a, B>,
+}
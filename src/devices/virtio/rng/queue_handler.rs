@@ -0,0 +1,129 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::result;
+use std::sync::Arc;
+
+use event_manager::{EventOps, EventSet, Events, MutEventSubscriber};
+use log::error;
+use virtio_queue::{Queue, QueueOwnedT, QueueT};
+use vm_memory::{self, Bytes, GuestMemoryMmap};
+
+use super::device::REQUEST_QUEUE_IDX;
+use crate::devices::virtio::SignalUsedQueue;
+use crate::kvm::hypervisor::ioevent::IoEvent;
+
+/// Upper bound of entropy handed out per request
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum Error {
+    GuestMemory(vm_memory::GuestMemoryError),
+    Queue(virtio_queue::Error),
+    Random(io::Error),
+}
+
+impl From<vm_memory::GuestMemoryError> for Error {
+    fn from(e: vm_memory::GuestMemoryError) -> Self {
+        Error::GuestMemory(e)
+    }
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+/// Stops handling `source` after an error.
+fn handle_error<Msg: AsRef<str>>(s: Msg, source: Events, ops: &mut EventOps) {
+    error!("{}", s.as_ref());
+    // the fd may already be removed after an earlier error of the same event
+    if let Err(e) = ops.remove(source) {
+        error!("Failed to remove rng event: {:?}", e);
+    }
+}
+
+pub(crate) struct RngQueueHandler<S: SignalUsedQueue> {
+    pub driver_notify: S,
+    pub queue: Queue,
+    pub ioeventfd: Arc<IoEvent>,
+    pub mem: Arc<GuestMemoryMmap>,
+    pub random: File,
+    // we have this here to safe reallocations across requests
+    pub buf: Vec<u8>,
+}
+
+impl<S> RngQueueHandler<S>
+where
+    S: SignalUsedQueue,
+{
+    /// Fills all writable buffers the driver offers with random bytes.
+    fn process_queue(&mut self) -> result::Result<(), Error> {
+        // To see why this is done in a loop, please look at the `Queue::enable_notification`
+        // comments in `vm_virtio`.
+        loop {
+            self.queue.disable_notification(self.mem.as_ref())?;
+
+            while let Some(mut chain) = self.queue.iter(self.mem.as_ref())?.next() {
+                let mut written = 0;
+                while let Some(desc) = chain.next() {
+                    if !desc.is_write_only() {
+                        continue;
+                    }
+                    let len = std::cmp::min(desc.len() as usize, MAX_REQUEST_SIZE - written);
+                    if len == 0 {
+                        break;
+                    }
+                    self.buf.resize(len, 0);
+                    self.random
+                        .read_exact(&mut self.buf)
+                        .map_err(Error::Random)?;
+                    chain.memory().write_slice(&self.buf, desc.addr())?;
+                    written += len;
+                }
+                self.queue
+                    .add_used(self.mem.as_ref(), chain.head_index(), written as u32)?;
+
+                if self.queue.needs_notification(self.mem.as_ref())? {
+                    self.driver_notify.signal_used_queue(REQUEST_QUEUE_IDX);
+                }
+            }
+
+            if !self.queue.enable_notification(self.mem.as_ref())? {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: SignalUsedQueue> MutEventSubscriber for RngQueueHandler<S> {
+    fn process(&mut self, events: Events, ops: &mut EventOps) {
+        let _span =
+            tracing::trace_span!("queue-handler", device = "rng", data = events.data()).entered();
+        if events.event_set() != EventSet::IN {
+            handle_error("Unexpected event_set", events, ops);
+            return;
+        }
+        if events.data() != REQUEST_QUEUE_IDX as u32 {
+            handle_error("Unexpected data", events, ops);
+            return;
+        }
+        if self.ioeventfd.read().is_err() {
+            handle_error("ioevent read", events, ops);
+            return;
+        }
+        if let Err(e) = self.process_queue() {
+            handle_error(format!("Process queue error {:?}", e), events, ops);
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        ops.add(Events::with_data(
+            self.ioeventfd.as_ref(),
+            REQUEST_QUEUE_IDX as u32,
+            EventSet::IN,
+        ))
+        .expect("Failed to register ioeventfd for rng queue handler");
+    }
+}
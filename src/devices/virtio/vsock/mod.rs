@@ -0,0 +1,64 @@
+mod device;
+mod muxer;
+mod packet;
+
+use std::path::PathBuf;
+
+use event_manager::Error as EvmgrError;
+use vm_device::bus;
+
+use crate::devices::virtio::CommonArgs;
+use crate::result::VmshError;
+
+pub use device::Vsock;
+
+/// Socket device ID as defined by the standard.
+pub const VSOCK_DEVICE_ID: u32 = 19;
+
+/// Well-known CID of the host.
+pub const VSOCK_HOST_CID: u64 = 2;
+
+/// CID assigned to the guest if not specified otherwise.
+pub const VSOCK_DEFAULT_GUEST_CID: u64 = 3;
+
+/// Host port stage2 connects to for `vmsh control <pid> new-shell`.
+pub const SHELL_VSOCK_PORT: u32 = 10022;
+
+/// Host port stage2 connects to for `--forward`.
+pub const FORWARD_VSOCK_PORT: u32 = 10023;
+
+/// Host port stage2 connects to for `vmsh cp`.
+pub const FILES_VSOCK_PORT: u32 = 10024;
+
+/// Host port stage2 connects to for `vmsh exec`.
+pub const EXEC_VSOCK_PORT: u32 = 10025;
+
+/// Host port stage2 connects to for signals forwarded to the command.
+pub const SIGNAL_VSOCK_PORT: u32 = 10026;
+
+#[derive(Debug)]
+pub enum Error {
+    AlreadyActivated,
+    BadFeatures(u64),
+    Bus(bus::Error),
+    Endpoint(EvmgrError),
+    QueueCreation(virtio_queue::Error),
+    Simple(VmshError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn build_config_space(guest_cid: u64) -> Vec<u8> {
+    // This has to be in little endian btw.
+    guest_cid.to_le_bytes().to_vec()
+}
+
+// Arguments required when building a vsock device.
+pub struct VsockArgs<'a, B> {
+    pub common: CommonArgs<'a, B>,
+    /// Context id of the guest.
+    pub guest_cid: u64,
+    /// Connections from the guest to host port `N` are forwarded to the unix
+    /// socket at `${uds_path}_N`.
+    pub uds_path: PathBuf,
+}
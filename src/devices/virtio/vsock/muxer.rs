@@ -0,0 +1,580 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::result;
+use std::sync::Arc;
+
+use event_manager::{EventOps, EventSet, Events, MutEventSubscriber};
+use log::{debug, error, warn};
+use virtio_queue::{Queue, QueueOwnedT, QueueT};
+use vm_memory::{self, Address, Bytes, GuestMemoryMmap};
+
+use super::device::{RX_QUEUE_IDX, TX_QUEUE_IDX};
+use super::packet::*;
+use super::VSOCK_HOST_CID;
+use crate::devices::virtio::SignalUsedQueue;
+use crate::kvm::hypervisor::ioevent::IoEvent;
+
+/// Event data of the first connection, lower values are used for the queues.
+const FIRST_CONNECTION_TOKEN: u32 = 16;
+
+/// Receive buffer we advertise to the guest for each connection.
+const CONN_BUF_ALLOC: u32 = 256 * 1024;
+
+/// Linux guests post receive buffers of 4KiB payload, so we never read more at once.
+const MAX_RX_PAYLOAD: usize = 4096;
+
+/// The guest never has credit for more than `CONN_BUF_ALLOC` bytes in one packet.
+const MAX_TX_PACKET: usize = VSOCK_HDR_SIZE + CONN_BUF_ALLOC as usize;
+
+#[derive(Debug)]
+pub enum Error {
+    GuestMemory(vm_memory::GuestMemoryError),
+    Queue(virtio_queue::Error),
+    EventManager(event_manager::Error),
+}
+
+impl From<vm_memory::GuestMemoryError> for Error {
+    fn from(e: vm_memory::GuestMemoryError) -> Self {
+        Error::GuestMemory(e)
+    }
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+impl From<event_manager::Error> for Error {
+    fn from(e: event_manager::Error) -> Self {
+        Error::EventManager(e)
+    }
+}
+
+/// Stops handling `source` after an error.
+fn handle_error<Msg: AsRef<str>>(s: Msg, source: Events, ops: &mut EventOps) {
+    error!("{}", s.as_ref());
+    // the fd may already be removed after an earlier error of the same event
+    if let Err(e) = ops.remove(source) {
+        error!("Failed to remove vsock event: {:?}", e);
+    }
+}
+
+/// (guest port, host port)
+type ConnKey = (u32, u32);
+
+/// A guest-initiated stream connection forwarded to a unix socket on the host.
+struct Connection {
+    stream: UnixStream,
+    token: u32,
+    /// the socket may have data to read
+    readable: bool,
+    /// we are waiting for the socket to become writable
+    want_out: bool,
+    /// the host side closed the socket
+    eof: bool,
+    /// data from the guest that could not be written to the socket yet
+    tx_buf: Vec<u8>,
+    /// bytes of guest data written to the socket
+    fwd_cnt: u32,
+    /// fwd_cnt we last told the guest about
+    last_fwd_cnt_sent: u32,
+    /// bytes sent to the guest
+    rx_cnt: u32,
+    /// receive buffer space of the guest socket
+    peer_buf_alloc: u32,
+    /// bytes the guest has consumed
+    peer_fwd_cnt: u32,
+}
+
+impl Connection {
+    /// How many bytes we can send to the guest without overflowing its receive buffer.
+    fn peer_credit(&self) -> u32 {
+        let in_flight = self.rx_cnt.wrapping_sub(self.peer_fwd_cnt);
+        self.peer_buf_alloc.saturating_sub(in_flight)
+    }
+
+    fn interest(&self) -> EventSet {
+        if self.want_out {
+            EventSet::IN | EventSet::OUT | EventSet::EDGE_TRIGGERED
+        } else {
+            EventSet::IN | EventSet::EDGE_TRIGGERED
+        }
+    }
+}
+
+/// Queue handler of the vsock device. Multiplexes guest connections to unix sockets:
+/// A connection to host port `N` is forwarded to `${uds_path}_N`.
+pub(crate) struct VsockMuxer<S: SignalUsedQueue> {
+    driver_notify: S,
+    rxq: Queue,
+    txq: Queue,
+    // The event queue is only used for transport resets, which we never send.
+    _evq: Queue,
+    rx_fd: Arc<IoEvent>,
+    tx_fd: Arc<IoEvent>,
+    mem: Arc<GuestMemoryMmap>,
+    guest_cid: u64,
+    uds_path: PathBuf,
+    conns: HashMap<ConnKey, Connection>,
+    tokens: HashMap<u32, ConnKey>,
+    next_token: u32,
+    /// control packets waiting for a receive buffer of the guest
+    pending: VecDeque<Header>,
+    /// packet that was not yet delivered to the guest
+    rx_pkt: Option<(Header, usize)>,
+    rx_data: Vec<u8>,
+    // we have this here to safe reallocations across packets
+    tx_pkt: Vec<u8>,
+}
+
+impl<S> VsockMuxer<S>
+where
+    S: SignalUsedQueue,
+{
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        driver_notify: S,
+        rxq: Queue,
+        txq: Queue,
+        evq: Queue,
+        rx_fd: Arc<IoEvent>,
+        tx_fd: Arc<IoEvent>,
+        mem: Arc<GuestMemoryMmap>,
+        guest_cid: u64,
+        uds_path: PathBuf,
+    ) -> Self {
+        VsockMuxer {
+            driver_notify,
+            rxq,
+            txq,
+            _evq: evq,
+            rx_fd,
+            tx_fd,
+            mem,
+            guest_cid,
+            uds_path,
+            conns: HashMap::new(),
+            tokens: HashMap::new(),
+            next_token: FIRST_CONNECTION_TOKEN,
+            pending: VecDeque::new(),
+            rx_pkt: None,
+            rx_data: vec![0; MAX_RX_PAYLOAD],
+            tx_pkt: Vec::with_capacity(VSOCK_HDR_SIZE + MAX_RX_PAYLOAD),
+        }
+    }
+
+    /// Builds a packet header for the connection `key` addressed to the guest.
+    fn reply(&self, key: ConnKey, op: u16) -> Header {
+        let fwd_cnt = self.conns.get(&key).map_or(0, |c| c.fwd_cnt);
+        Header {
+            src_cid: VSOCK_HOST_CID,
+            dst_cid: self.guest_cid,
+            src_port: key.1,
+            dst_port: key.0,
+            len: 0,
+            type_: VSOCK_TYPE_STREAM,
+            op,
+            flags: 0,
+            buf_alloc: CONN_BUF_ALLOC,
+            fwd_cnt,
+        }
+    }
+
+    fn close(&mut self, key: ConnKey, ops: &mut EventOps) {
+        if let Some(conn) = self.conns.remove(&key) {
+            self.tokens.remove(&conn.token);
+            if let Err(e) = ops.remove(Events::empty(&conn.stream)) {
+                warn!("cannot unregister vsock connection: {:?}", e);
+            }
+        }
+    }
+
+    fn reset(&mut self, key: ConnKey, ops: &mut EventOps) {
+        self.close(key, ops);
+        let rst = self.reply(key, VSOCK_OP_RST);
+        self.pending.push_back(rst);
+    }
+
+    fn connect(&mut self, key: ConnKey, hdr: &Header, ops: &mut EventOps) {
+        if self.conns.contains_key(&key) {
+            self.reset(key, ops);
+            return;
+        }
+        let path = format!("{}_{}", self.uds_path.display(), key.1);
+        let stream = match UnixStream::connect(&path).and_then(|s| {
+            s.set_nonblocking(true)?;
+            Ok(s)
+        }) {
+            Ok(s) => s,
+            Err(e) => {
+                debug!("vsock: cannot connect to {}: {}", path, e);
+                let rst = self.reply(key, VSOCK_OP_RST);
+                self.pending.push_back(rst);
+                return;
+            }
+        };
+        let token = self.next_token;
+        self.next_token = self.next_token.wrapping_add(1).max(FIRST_CONNECTION_TOKEN);
+        let conn = Connection {
+            stream,
+            token,
+            readable: true,
+            want_out: false,
+            eof: false,
+            tx_buf: vec![],
+            fwd_cnt: 0,
+            last_fwd_cnt_sent: 0,
+            rx_cnt: 0,
+            peer_buf_alloc: hdr.buf_alloc,
+            peer_fwd_cnt: hdr.fwd_cnt,
+        };
+        if let Err(e) = ops.add(Events::with_data(&conn.stream, token, conn.interest())) {
+            warn!("cannot register vsock connection to {}: {:?}", path, e);
+            let rst = self.reply(key, VSOCK_OP_RST);
+            self.pending.push_back(rst);
+            return;
+        }
+        debug!("vsock: guest port {} connected to {}", key.0, path);
+        self.conns.insert(key, conn);
+        self.tokens.insert(token, key);
+        let response = self.reply(key, VSOCK_OP_RESPONSE);
+        self.pending.push_back(response);
+    }
+
+    /// Writes buffered guest data to the socket.
+    fn flush(&mut self, key: ConnKey, ops: &mut EventOps) {
+        let conn = match self.conns.get_mut(&key) {
+            Some(conn) => conn,
+            None => return,
+        };
+        let mut written = 0;
+        let mut failed = false;
+        while written < conn.tx_buf.len() {
+            match conn.stream.write(&conn.tx_buf[written..]) {
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    debug!("vsock: cannot write to host socket: {}", e);
+                    failed = true;
+                    break;
+                }
+            }
+        }
+        if failed {
+            self.reset(key, ops);
+            return;
+        }
+        conn.tx_buf.drain(..written);
+        conn.fwd_cnt = conn.fwd_cnt.wrapping_add(written as u32);
+
+        let want_out = !conn.tx_buf.is_empty();
+        if want_out != conn.want_out {
+            conn.want_out = want_out;
+            let events = Events::with_data(&conn.stream, conn.token, conn.interest());
+            if let Err(e) = ops.modify(events) {
+                warn!("cannot modify vsock connection: {:?}", e);
+            }
+        }
+
+        // Announce freed buffer space before the guest runs out of credit.
+        if conn.fwd_cnt.wrapping_sub(conn.last_fwd_cnt_sent) >= CONN_BUF_ALLOC / 2 {
+            conn.last_fwd_cnt_sent = conn.fwd_cnt;
+            let update = self.reply(key, VSOCK_OP_CREDIT_UPDATE);
+            self.pending.push_back(update);
+        }
+    }
+
+    fn handle_tx_packet(&mut self, hdr: &Header, payload: &[u8], ops: &mut EventOps) {
+        let key = (hdr.src_port, hdr.dst_port);
+        if hdr.type_ != VSOCK_TYPE_STREAM || hdr.dst_cid != VSOCK_HOST_CID {
+            if hdr.op != VSOCK_OP_RST {
+                self.reset(key, ops);
+            }
+            return;
+        }
+        if hdr.op == VSOCK_OP_REQUEST {
+            self.connect(key, hdr, ops);
+            return;
+        }
+
+        let conn = match self.conns.get_mut(&key) {
+            Some(conn) => conn,
+            None => {
+                if hdr.op != VSOCK_OP_RST {
+                    self.reset(key, ops);
+                }
+                return;
+            }
+        };
+        conn.peer_buf_alloc = hdr.buf_alloc;
+        conn.peer_fwd_cnt = hdr.fwd_cnt;
+
+        match hdr.op {
+            VSOCK_OP_RW => {
+                if conn.tx_buf.len() + payload.len() > CONN_BUF_ALLOC as usize {
+                    warn!("vsock: guest exceeded its credit");
+                    self.reset(key, ops);
+                    return;
+                }
+                conn.tx_buf.extend_from_slice(payload);
+                self.flush(key, ops);
+            }
+            VSOCK_OP_CREDIT_REQUEST => {
+                conn.last_fwd_cnt_sent = conn.fwd_cnt;
+                let update = self.reply(key, VSOCK_OP_CREDIT_UPDATE);
+                self.pending.push_back(update);
+            }
+            VSOCK_OP_SHUTDOWN => {
+                let both = VSOCK_FLAGS_SHUTDOWN_RCV | VSOCK_FLAGS_SHUTDOWN_SEND;
+                if hdr.flags & both == both {
+                    self.reset(key, ops);
+                } else if hdr.flags & VSOCK_FLAGS_SHUTDOWN_SEND != 0 && conn.tx_buf.is_empty() {
+                    let _ = conn.stream.shutdown(Shutdown::Write);
+                }
+            }
+            VSOCK_OP_RST => self.close(key, ops),
+            // the guest got more credit, which is picked up by process_rx()
+            VSOCK_OP_CREDIT_UPDATE => {}
+            op => {
+                debug!("vsock: unexpected op {}", op);
+                self.reset(key, ops);
+            }
+        }
+    }
+
+    /// Guest sends (tx), we forward to the host sockets.
+    fn process_txq(&mut self, ops: &mut EventOps) -> result::Result<(), Error> {
+        // To see why this is done in a loop, please look at the `Queue::enable_notification`
+        // comments in `vm_virtio`.
+        loop {
+            self.txq.disable_notification(self.mem.as_ref())?;
+
+            while let Some(mut chain) = self.txq.iter(self.mem.as_ref())?.next() {
+                self.tx_pkt.clear();
+                let mut too_long = false;
+                while let Some(desc) = chain.next() {
+                    let start = self.tx_pkt.len();
+                    // the length is chosen by the guest, do not let it allocate more than a packet
+                    let end = start + desc.len() as usize;
+                    if end > MAX_TX_PACKET {
+                        too_long = true;
+                        break;
+                    }
+                    self.tx_pkt.resize(end, 0);
+                    chain
+                        .memory()
+                        .read_slice(&mut self.tx_pkt[start..], desc.addr())?;
+                }
+                self.txq
+                    .add_used(self.mem.as_ref(), chain.head_index(), 0)?;
+
+                if self.txq.needs_notification(self.mem.as_ref())? {
+                    self.driver_notify.signal_used_queue(TX_QUEUE_IDX);
+                }
+
+                let pkt = std::mem::take(&mut self.tx_pkt);
+                if too_long {
+                    warn!(
+                        "vsock: dropping packet of more than {} bytes",
+                        MAX_TX_PACKET
+                    );
+                    // the guest exceeded its credit, see handle_tx_packet()
+                    if let Some(hdr) = Header::parse(&pkt) {
+                        self.reset((hdr.src_port, hdr.dst_port), ops);
+                    }
+                } else {
+                    match Header::parse(&pkt) {
+                        Some(hdr) => {
+                            let end = std::cmp::min(pkt.len(), VSOCK_HDR_SIZE + hdr.len as usize);
+                            self.handle_tx_packet(&hdr, &pkt[VSOCK_HDR_SIZE..end], ops);
+                        }
+                        None => warn!("vsock: dropping short packet of {} bytes", pkt.len()),
+                    }
+                }
+                self.tx_pkt = pkt;
+            }
+
+            if !self.txq.enable_notification(self.mem.as_ref())? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Picks the next packet for the guest: control packets first, then data of
+    /// readable connections.
+    fn next_rx_packet(&mut self, ops: &mut EventOps) -> Option<(Header, usize)> {
+        if let Some(hdr) = self.pending.pop_front() {
+            return Some((hdr, 0));
+        }
+        loop {
+            let key = self
+                .conns
+                .iter()
+                .find(|(_, c)| c.readable && !c.eof && c.peer_credit() > 0)
+                .map(|(k, _)| *k)?;
+            let conn = self.conns.get_mut(&key)?;
+            let max = std::cmp::min(conn.peer_credit() as usize, MAX_RX_PAYLOAD);
+            match conn.stream.read(&mut self.rx_data[..max]) {
+                Ok(0) => {
+                    conn.eof = true;
+                    let mut hdr = self.reply(key, VSOCK_OP_SHUTDOWN);
+                    hdr.flags = VSOCK_FLAGS_SHUTDOWN_RCV | VSOCK_FLAGS_SHUTDOWN_SEND;
+                    return Some((hdr, 0));
+                }
+                Ok(n) => {
+                    conn.rx_cnt = conn.rx_cnt.wrapping_add(n as u32);
+                    conn.last_fwd_cnt_sent = conn.fwd_cnt;
+                    let mut hdr = self.reply(key, VSOCK_OP_RW);
+                    hdr.len = n as u32;
+                    return Some((hdr, n));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => conn.readable = false,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    debug!("vsock: cannot read from host socket: {}", e);
+                    self.reset(key, ops);
+                }
+            }
+        }
+    }
+
+    /// Guest receives (rx), we deliver packets until there is nothing left or the
+    /// guest runs out of receive buffers.
+    fn process_rx(&mut self, ops: &mut EventOps) -> result::Result<(), Error> {
+        loop {
+            if self.rx_pkt.is_none() {
+                self.rx_pkt = self.next_rx_packet(ops);
+            }
+            let (hdr, len) = match self.rx_pkt {
+                Some(pkt) => pkt,
+                None => return Ok(()),
+            };
+
+            self.rxq.disable_notification(self.mem.as_ref())?;
+            let mut chain = match self.rxq.iter(self.mem.as_ref())?.next() {
+                Some(chain) => chain,
+                None => {
+                    // Wait for the driver to add new buffers. We get notified via rx_fd.
+                    if self.rxq.enable_notification(self.mem.as_ref())? {
+                        continue;
+                    }
+                    return Ok(());
+                }
+            };
+
+            let hdr_bytes = hdr.to_bytes();
+            let total = VSOCK_HDR_SIZE + len;
+            let mut written = 0;
+            while let Some(desc) = chain.next() {
+                if written == total {
+                    break;
+                }
+                if !desc.is_write_only() {
+                    continue;
+                }
+                let mut addr = desc.addr();
+                let mut room = desc.len() as usize;
+                if written < VSOCK_HDR_SIZE {
+                    let n = std::cmp::min(room, VSOCK_HDR_SIZE - written);
+                    chain
+                        .memory()
+                        .write_slice(&hdr_bytes[written..written + n], addr)?;
+                    written += n;
+                    room -= n;
+                    addr = addr.unchecked_add(n as u64);
+                }
+                if written >= VSOCK_HDR_SIZE && room > 0 {
+                    let start = written - VSOCK_HDR_SIZE;
+                    let n = std::cmp::min(room, len - start);
+                    chain
+                        .memory()
+                        .write_slice(&self.rx_data[start..start + n], addr)?;
+                    written += n;
+                }
+            }
+            if written < total {
+                warn!(
+                    "vsock: truncated packet of {} bytes: receive buffer too small",
+                    total
+                );
+            }
+            self.rx_pkt = None;
+            self.rxq
+                .add_used(self.mem.as_ref(), chain.head_index(), written as u32)?;
+
+            if self.rxq.needs_notification(self.mem.as_ref())? {
+                self.driver_notify.signal_used_queue(RX_QUEUE_IDX);
+            }
+        }
+    }
+
+    fn process_connection(&mut self, token: u32, event_set: EventSet, ops: &mut EventOps) {
+        let key = match self.tokens.get(&token) {
+            Some(key) => *key,
+            None => return,
+        };
+        if event_set.intersects(EventSet::OUT) {
+            self.flush(key, ops);
+        }
+        if let Some(conn) = self.conns.get_mut(&key) {
+            if event_set.intersects(EventSet::IN | EventSet::HANG_UP | EventSet::READ_HANG_UP) {
+                conn.readable = true;
+            }
+            if event_set.intersects(EventSet::ERROR) {
+                self.reset(key, ops);
+            }
+        }
+    }
+}
+
+impl<S: SignalUsedQueue> MutEventSubscriber for VsockMuxer<S> {
+    fn process(&mut self, events: Events, ops: &mut EventOps) {
+        let _span =
+            tracing::trace_span!("queue-handler", device = "vsock", data = events.data()).entered();
+        match events.data() {
+            data if data == RX_QUEUE_IDX as u32 => {
+                if self.rx_fd.read().is_err() {
+                    handle_error("Rx ioevent read", events, ops);
+                }
+            }
+            data if data == TX_QUEUE_IDX as u32 => {
+                if self.tx_fd.read().is_err() {
+                    handle_error("Tx ioevent read", events, ops);
+                }
+                if let Err(e) = self.process_txq(ops) {
+                    handle_error(format!("Process tx error {:?}", e), events, ops);
+                }
+            }
+            data if data >= FIRST_CONNECTION_TOKEN => {
+                self.process_connection(data, events.event_set(), ops);
+            }
+            _ => handle_error("Unexpected data", events, ops),
+        }
+        // All events may produce packets for the guest or free up receive buffers.
+        if let Err(e) = self.process_rx(ops) {
+            let source = Events::empty(self.rx_fd.as_ref());
+            handle_error(format!("Process rx error {:?}", e), source, ops);
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        ops.add(Events::with_data(
+            self.rx_fd.as_ref(),
+            RX_QUEUE_IDX as u32,
+            EventSet::IN,
+        ))
+        .expect("Failed to register rx ioeventfd for vsock queue handler");
+        ops.add(Events::with_data(
+            self.tx_fd.as_ref(),
+            TX_QUEUE_IDX as u32,
+            EventSet::IN,
+        ))
+        .expect("Failed to register tx ioeventfd for vsock queue handler");
+    }
+}
@@ -0,0 +1,105 @@
+use std::convert::TryInto;
+
+/// Size of `struct virtio_vsock_hdr`
+pub const VSOCK_HDR_SIZE: usize = 44;
+
+pub const VSOCK_TYPE_STREAM: u16 = 1;
+
+pub const VSOCK_OP_REQUEST: u16 = 1;
+pub const VSOCK_OP_RESPONSE: u16 = 2;
+pub const VSOCK_OP_RST: u16 = 3;
+pub const VSOCK_OP_SHUTDOWN: u16 = 4;
+pub const VSOCK_OP_RW: u16 = 5;
+pub const VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+pub const VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+/// The peer will not receive any more data.
+pub const VSOCK_FLAGS_SHUTDOWN_RCV: u32 = 1;
+/// The peer will not send any more data.
+pub const VSOCK_FLAGS_SHUTDOWN_SEND: u32 = 2;
+
+/// Header that precedes every packet on the rx and tx queue.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Header {
+    pub src_cid: u64,
+    pub dst_cid: u64,
+    pub src_port: u32,
+    pub dst_port: u32,
+    pub len: u32,
+    pub type_: u16,
+    pub op: u16,
+    pub flags: u32,
+    pub buf_alloc: u32,
+    pub fwd_cnt: u32,
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap_or_default())
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap_or_default())
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap_or_default())
+}
+
+impl Header {
+    /// Parses a little endian header. Returns None if the buffer is too small.
+    pub fn parse(buf: &[u8]) -> Option<Header> {
+        if buf.len() < VSOCK_HDR_SIZE {
+            return None;
+        }
+        Some(Header {
+            src_cid: u64_at(buf, 0),
+            dst_cid: u64_at(buf, 8),
+            src_port: u32_at(buf, 16),
+            dst_port: u32_at(buf, 20),
+            len: u32_at(buf, 24),
+            type_: u16_at(buf, 28),
+            op: u16_at(buf, 30),
+            flags: u32_at(buf, 32),
+            buf_alloc: u32_at(buf, 36),
+            fwd_cnt: u32_at(buf, 40),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; VSOCK_HDR_SIZE] {
+        let mut buf = [0u8; VSOCK_HDR_SIZE];
+        buf[0..8].copy_from_slice(&self.src_cid.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.dst_cid.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.src_port.to_le_bytes());
+        buf[20..24].copy_from_slice(&self.dst_port.to_le_bytes());
+        buf[24..28].copy_from_slice(&self.len.to_le_bytes());
+        buf[28..30].copy_from_slice(&self.type_.to_le_bytes());
+        buf[30..32].copy_from_slice(&self.op.to_le_bytes());
+        buf[32..36].copy_from_slice(&self.flags.to_le_bytes());
+        buf[36..40].copy_from_slice(&self.buf_alloc.to_le_bytes());
+        buf[40..44].copy_from_slice(&self.fwd_cnt.to_le_bytes());
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_roundtrip() {
+        let hdr = Header {
+            src_cid: 3,
+            dst_cid: 2,
+            src_port: 1234,
+            dst_port: 5678,
+            len: 42,
+            type_: VSOCK_TYPE_STREAM,
+            op: VSOCK_OP_RW,
+            flags: VSOCK_FLAGS_SHUTDOWN_SEND,
+            buf_alloc: 65536,
+            fwd_cnt: 7,
+        };
+        assert_eq!(Header::parse(&hdr.to_bytes()), Some(hdr));
+        assert_eq!(Header::parse(&hdr.to_bytes()[..VSOCK_HDR_SIZE - 1]), None);
+    }
+}
@@ -0,0 +1,524 @@
+//! Prints the kernel log of the guest, like dmesg(1) inside it would.
+//!
+//! Neither the printk ring buffer nor its layout is exported in ksymtab. Both are part of the
+//! vmcoreinfo that the kernel prepares for crash dumps (`CONFIG_CRASH_CORE`), a page of
+//! `KEY=VALUE` lines that we find by scanning guest memory. Linux 5.10 replaced the `log_buf`
+//! of `struct printk_log` records with a lockless ring buffer (`prb`); both are supported.
+
+use log::{debug, info, warn};
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::guest_mem::{GuestMem, KernelMem};
+use crate::kernel::find_kernel;
+use crate::kvm;
+use crate::kvm::hypervisor::VmSelector;
+use crate::result::Result;
+
+/// `VMCOREINFO_BYTES`, the vmcoreinfo is a page-aligned buffer of this size
+const VMCOREINFO_SIZE: usize = 4096;
+/// Guest memory is scanned for the vmcoreinfo in chunks of this size
+const SCAN_CHUNK_SIZE: usize = 1 << 20;
+/// Refuse to copy larger buffers, the layout is probably wrong then
+const MAX_BUFFER_SIZE: usize = 1 << 30;
+
+/// `prb_desc.state_var` holds the id of the descriptor and its state in the top two bits.
+const DESC_FLAGS_SHIFT: u32 = 62;
+const DESC_ID_MASK: u64 = !(3 << DESC_FLAGS_SHIFT);
+const DESC_COMMITTED: u64 = 1;
+const DESC_FINALIZED: u64 = 2;
+/// Every data block starts with the id of its descriptor
+const DATA_BLOCK_HEADER: usize = 8;
+
+pub struct DmesgOptions {
+    pub pid: Pid,
+    pub vm: Option<VmSelector>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct Record {
+    ts_nsec: u64,
+    pub(crate) text: String,
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{:>5}.{:06}] {}",
+            self.ts_nsec / 1_000_000_000,
+            self.ts_nsec % 1_000_000_000 / 1000,
+            self.text
+        )
+    }
+}
+
+struct VmcoreInfo {
+    entries: HashMap<String, String>,
+}
+
+impl VmcoreInfo {
+    fn parse(data: &str) -> VmcoreInfo {
+        let entries = data
+            .lines()
+            .filter_map(|l| l.split_once('='))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        VmcoreInfo { entries }
+    }
+
+    fn get(&self, key: &str) -> Result<&str> {
+        Ok(require_with!(self.entries.get(key), "vmcoreinfo has no {}", key).as_str())
+    }
+
+    fn release(&self) -> Option<&str> {
+        self.entries.get("OSRELEASE").map(|r| r.as_str())
+    }
+
+    fn has_symbol(&self, name: &str) -> bool {
+        self.entries.contains_key(&format!("SYMBOL({})", name))
+    }
+
+    /// Address of `name`, written as hex without `0x`.
+    fn symbol(&self, name: &str) -> Result<usize> {
+        let value = self.get(&format!("SYMBOL({})", name))?;
+        Ok(try_with!(
+            usize::from_str_radix(value, 16),
+            "invalid address of {} in vmcoreinfo: {}",
+            name,
+            value
+        ))
+    }
+
+    fn decimal(&self, key: String) -> Result<usize> {
+        let value = self.get(&key)?;
+        Ok(try_with!(
+            value.parse::<usize>(),
+            "invalid {} in vmcoreinfo: {}",
+            key,
+            value
+        ))
+    }
+
+    /// Offset of a field, `name` is `struct.field`.
+    fn offset(&self, name: &str) -> Result<usize> {
+        self.decimal(format!("OFFSET({})", name))
+    }
+
+    fn size(&self, name: &str) -> Result<usize> {
+        self.decimal(format!("SIZE({})", name))
+    }
+}
+
+fn u16_at(buf: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(buf.get(off..off + 2)?.try_into().ok()?))
+}
+
+fn u64_at(buf: &[u8], off: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(buf.get(off..off + 8)?.try_into().ok()?))
+}
+
+/// Offsets in `struct printk_ringbuffer` and the structs it points to.
+struct PrbLayout {
+    desc_ring: usize,
+    text_data_ring: usize,
+    count_bits: usize,
+    descs: usize,
+    infos: usize,
+    head_id: usize,
+    tail_id: usize,
+    desc_size: usize,
+    state_var: usize,
+    text_begin: usize,
+    text_next: usize,
+    info_size: usize,
+    ts_nsec: usize,
+    text_len: usize,
+    size_bits: usize,
+    data: usize,
+}
+
+impl PrbLayout {
+    fn new(info: &VmcoreInfo) -> Result<PrbLayout> {
+        let text_blk_lpos = info.offset("prb_desc.text_blk_lpos")?;
+        Ok(PrbLayout {
+            desc_ring: info.offset("printk_ringbuffer.desc_ring")?,
+            text_data_ring: info.offset("printk_ringbuffer.text_data_ring")?,
+            count_bits: info.offset("prb_desc_ring.count_bits")?,
+            descs: info.offset("prb_desc_ring.descs")?,
+            infos: info.offset("prb_desc_ring.infos")?,
+            head_id: info.offset("prb_desc_ring.head_id")?,
+            tail_id: info.offset("prb_desc_ring.tail_id")?,
+            desc_size: info.size("prb_desc")?,
+            state_var: info.offset("prb_desc.state_var")?,
+            text_begin: text_blk_lpos + info.offset("prb_data_blk_lpos.begin")?,
+            text_next: text_blk_lpos + info.offset("prb_data_blk_lpos.next")?,
+            info_size: info.size("printk_info")?,
+            ts_nsec: info.offset("printk_info.ts_nsec")?,
+            text_len: info.offset("printk_info.text_len")?,
+            size_bits: info.offset("prb_data_ring.size_bits")?,
+            data: info.offset("prb_data_ring.data")?,
+        })
+    }
+}
+
+/// A lockless ring buffer copied out of the guest.
+struct Prb {
+    count_bits: u32,
+    head_id: u64,
+    tail_id: u64,
+    descs: Vec<u8>,
+    infos: Vec<u8>,
+    size_bits: u32,
+    data: Vec<u8>,
+}
+
+fn read_buffer(mem: &KernelMem, addr: usize, len: usize, what: &str) -> Result<Vec<u8>> {
+    if len > MAX_BUFFER_SIZE {
+        bail!("{} is too large ({} bytes)", what, len);
+    }
+    let mut buf = vec![0u8; len];
+    try_with!(mem.read(addr, &mut buf), "cannot read {}", what);
+    Ok(buf)
+}
+
+fn read_prb(mem: &KernelMem, layout: &PrbLayout, prb: usize) -> Result<Prb> {
+    let desc_ring = prb + layout.desc_ring;
+    let text_ring = prb + layout.text_data_ring;
+    let count_bits = mem.read_u32(desc_ring + layout.count_bits)?;
+    let size_bits = mem.read_u32(text_ring + layout.size_bits)?;
+    if count_bits >= 32 || size_bits >= 32 {
+        bail!("printk ring buffer at {:#x} looks corrupted", prb);
+    }
+    let count = 1usize << count_bits;
+    let descs = mem.read_u64(desc_ring + layout.descs)? as usize;
+    let infos = mem.read_u64(desc_ring + layout.infos)? as usize;
+    let data = mem.read_u64(text_ring + layout.data)? as usize;
+    Ok(Prb {
+        count_bits,
+        head_id: mem.read_u64(desc_ring + layout.head_id)?,
+        tail_id: mem.read_u64(desc_ring + layout.tail_id)?,
+        descs: read_buffer(mem, descs, count * layout.desc_size, "descriptors")?,
+        infos: read_buffer(mem, infos, count * layout.info_size, "printk infos")?,
+        size_bits,
+        data: read_buffer(mem, data, 1 << size_bits, "text data ring")?,
+    })
+}
+
+/// Text of the data block from `begin` to `next`. Blocks that do not fit at the end of the
+/// ring are stored at its start, their `next` is in the following wrap.
+fn block_text(prb: &Prb, begin: u64, next: u64) -> Option<&[u8]> {
+    // data-less records have the lowest bit set
+    if begin & 1 != 0 || next & 1 != 0 {
+        return None;
+    }
+    let mask = (1u64 << prb.size_bits) - 1;
+    let (start, len) = if begin >> prb.size_bits == next >> prb.size_bits {
+        (begin & mask, next.checked_sub(begin)?)
+    } else {
+        (0, next & mask)
+    };
+    let start = start as usize;
+    prb.data
+        .get(start + DATA_BLOCK_HEADER..start + len as usize)
+}
+
+/// Records from the oldest (`tail_id`) to the newest (`head_id`) descriptor. Descriptors that
+/// are reserved or already reused are skipped.
+fn prb_records(layout: &PrbLayout, prb: &Prb) -> Vec<Record> {
+    let mask = (1u64 << prb.count_bits) - 1;
+    let mut records = vec![];
+    let mut id = prb.tail_id & DESC_ID_MASK;
+    for _ in 0..=mask {
+        let idx = (id & mask) as usize;
+        let desc = idx * layout.desc_size;
+        let info = idx * layout.info_size;
+        let record = u64_at(&prb.descs, desc + layout.state_var).and_then(|sv| {
+            let state = sv >> DESC_FLAGS_SHIFT;
+            if sv & DESC_ID_MASK != id || (state != DESC_COMMITTED && state != DESC_FINALIZED) {
+                return None;
+            }
+            let begin = u64_at(&prb.descs, desc + layout.text_begin)?;
+            let next = u64_at(&prb.descs, desc + layout.text_next)?;
+            let text = block_text(prb, begin, next)?;
+            let text_len = u16_at(&prb.infos, info + layout.text_len)? as usize;
+            Some(Record {
+                ts_nsec: u64_at(&prb.infos, info + layout.ts_nsec)?,
+                text: String::from_utf8_lossy(&text[..text_len.min(text.len())]).into_owned(),
+            })
+        });
+        records.extend(record);
+        if id == prb.head_id & DESC_ID_MASK {
+            break;
+        }
+        id = id.wrapping_add(1) & DESC_ID_MASK;
+    }
+    records
+}
+
+/// Offsets in `struct printk_log`, the header of every record in `log_buf`.
+struct LogBufLayout {
+    header_size: usize,
+    ts_nsec: usize,
+    len: usize,
+    text_len: usize,
+}
+
+impl LogBufLayout {
+    fn new(info: &VmcoreInfo) -> Result<LogBufLayout> {
+        Ok(LogBufLayout {
+            header_size: info.size("printk_log")?,
+            ts_nsec: info.offset("printk_log.ts_nsec")?,
+            len: info.offset("printk_log.len")?,
+            text_len: info.offset("printk_log.text_len")?,
+        })
+    }
+}
+
+/// Records of `log_buf` from `first` to `next`. A record with `len == 0` marks the end of the
+/// buffer, the following record starts at its beginning.
+fn log_buf_records(layout: &LogBufLayout, buf: &[u8], first: usize, next: usize) -> Vec<Record> {
+    let mut records = vec![];
+    let mut idx = first;
+    while idx != next && records.len() < buf.len() {
+        let len = match u16_at(buf, idx + layout.len) {
+            Some(0) if idx != 0 => {
+                idx = 0;
+                continue;
+            }
+            Some(len) if len as usize >= layout.header_size => len as usize,
+            _ => {
+                warn!("log_buf record at {:#x} is corrupted", idx);
+                break;
+            }
+        };
+        let text = u16_at(buf, idx + layout.text_len).and_then(|text_len| {
+            let start = idx + layout.header_size;
+            buf.get(start..start + text_len as usize)
+        });
+        if let (Some(ts_nsec), Some(text)) = (u64_at(buf, idx + layout.ts_nsec), text) {
+            records.push(Record {
+                ts_nsec,
+                text: String::from_utf8_lossy(text).into_owned(),
+            });
+        }
+        idx += len;
+    }
+    records
+}
+
+fn read_log_buf(mem: &KernelMem, info: &VmcoreInfo) -> Result<Vec<Record>> {
+    let layout = LogBufLayout::new(info)?;
+    let buf = mem.read_u64(info.symbol("log_buf")?)? as usize;
+    let len = mem.read_u32(info.symbol("log_buf_len")?)? as usize;
+    let first = mem.read_u32(info.symbol("log_first_idx")?)? as usize;
+    let next = mem.read_u32(info.symbol("log_next_idx")?)? as usize;
+    let buf = read_buffer(mem, buf, len, "log_buf")?;
+    Ok(log_buf_records(&layout, &buf, first, next))
+}
+
+/// Searches the page-aligned vmcoreinfo in kernel memory. If `release` is known, a vmcoreinfo
+/// of another kernel (i.e. before kexec) is skipped.
+fn find_vmcoreinfo(mem: &KernelMem, release: Option<&str>) -> Result<VmcoreInfo> {
+    info!("search guest memory for the vmcoreinfo");
+    let mut chunk = vec![0u8; SCAN_CHUNK_SIZE];
+    for m in &mem.mappings {
+        for start in (0..m.len).step_by(SCAN_CHUNK_SIZE) {
+            let len = SCAN_CHUNK_SIZE.min(m.len - start);
+            if let Err(e) = mem.read(m.virt_start + start, &mut chunk[..len]) {
+                debug!("skip {:#x}: {}", m.virt_start + start, e);
+                continue;
+            }
+            for page in chunk[..len].chunks(VMCOREINFO_SIZE) {
+                if !page.starts_with(b"OSRELEASE=") {
+                    continue;
+                }
+                let end = page.iter().position(|c| *c == 0).unwrap_or(page.len());
+                let info = VmcoreInfo::parse(&String::from_utf8_lossy(&page[..end]));
+                if !info.has_symbol("prb") && !info.has_symbol("log_buf") {
+                    continue;
+                }
+                match (release, info.release()) {
+                    (Some(expected), Some(found)) if expected != found => {
+                        debug!("skip vmcoreinfo of kernel {}", found)
+                    }
+                    _ => return Ok(info),
+                }
+            }
+        }
+    }
+    bail!("cannot find the vmcoreinfo, is the guest kernel built with CONFIG_CRASH_CORE?")
+}
+
+/// Records in the kernel log of the guest, the VM has to be stopped. `release` is the one of
+/// the running kernel, if known.
+pub(crate) fn read_log(mem: &KernelMem, release: Option<&str>) -> Result<Vec<Record>> {
+    let info = find_vmcoreinfo(mem, release)?;
+    if info.has_symbol("prb") {
+        let layout = PrbLayout::new(&info)?;
+        // `prb` points to the static ring buffer or the larger one of `log_buf_len=`
+        let prb = mem.read_u64(info.symbol("prb")?)? as usize;
+        Ok(prb_records(&layout, &read_prb(mem, &layout, prb)?))
+    } else {
+        read_log_buf(mem, &info)
+    }
+}
+
+pub fn dmesg(opts: &DmesgOptions) -> Result<()> {
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid, opts.vm),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    // the ring buffer must not change while we copy it
+    vm.stop()?;
+    let guest_mem = try_with!(GuestMem::new(&vm), "cannot access guest memory");
+    let kernel = try_with!(find_kernel(&guest_mem, &vm), "cannot find guest kernel");
+    let release = match kernel.version(&vm) {
+        Ok(release) => Some(release),
+        Err(e) => {
+            warn!("cannot read kernel version: {}", e);
+            None
+        }
+    };
+    let mem = KernelMem::new(&vm, &guest_mem)?;
+    let records = read_log(&mem, release.as_deref())?;
+
+    let mut stdout = io::stdout().lock();
+    for record in records {
+        try_with!(writeln!(stdout, "{}", record), "cannot write to stdout");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Layout of Linux 6.1 on x86_64
+    const VMCOREINFO: &str = "OSRELEASE=6.1.0
+PAGESIZE=4096
+SYMBOL(prb)=ffffffff82a5e2c8
+OFFSET(printk_ringbuffer.desc_ring)=0
+OFFSET(printk_ringbuffer.text_data_ring)=40
+OFFSET(prb_desc_ring.count_bits)=0
+OFFSET(prb_desc_ring.descs)=8
+OFFSET(prb_desc_ring.infos)=16
+OFFSET(prb_desc_ring.head_id)=24
+OFFSET(prb_desc_ring.tail_id)=32
+SIZE(prb_desc)=24
+OFFSET(prb_desc.state_var)=0
+OFFSET(prb_desc.text_blk_lpos)=8
+OFFSET(prb_data_blk_lpos.begin)=0
+OFFSET(prb_data_blk_lpos.next)=8
+SIZE(printk_info)=88
+OFFSET(printk_info.ts_nsec)=8
+OFFSET(printk_info.text_len)=16
+OFFSET(prb_data_ring.size_bits)=0
+OFFSET(prb_data_ring.data)=8
+";
+
+    fn put(buf: &mut [u8], off: usize, bytes: &[u8]) {
+        buf[off..off + bytes.len()].copy_from_slice(bytes);
+    }
+
+    #[test]
+    fn parse_vmcoreinfo() {
+        let info = VmcoreInfo::parse(VMCOREINFO);
+        assert_eq!(info.release(), Some("6.1.0"));
+        assert_eq!(info.symbol("prb").unwrap(), 0xffffffff82a5e2c8);
+        assert_eq!(info.offset("prb_desc_ring.tail_id").unwrap(), 32);
+        assert_eq!(info.size("printk_info").unwrap(), 88);
+        assert!(!info.has_symbol("log_buf"));
+        assert!(info.offset("printk_log.len").is_err());
+    }
+
+    #[test]
+    fn decode_prb() {
+        let layout = PrbLayout::new(&VmcoreInfo::parse(VMCOREINFO)).unwrap();
+        let mut prb = Prb {
+            count_bits: 2,
+            tail_id: 5,
+            head_id: 7,
+            descs: vec![0; 4 * 24],
+            infos: vec![0; 4 * 88],
+            size_bits: 6,
+            data: vec![0; 64],
+        };
+        // id, state, text blocks (begin, next), timestamp, text
+        let records: &[(u64, u64, (u64, u64), u64, &[u8])] = &[
+            (5, DESC_FINALIZED, (64 + 16, 64 + 32), 1_500_000, b"first"),
+            // reserved, the kernel is still writing it
+            (6, 0, (64 + 32, 64 + 48), 0, b"skipped"),
+            // does not fit at the end anymore and wraps
+            (
+                7,
+                DESC_COMMITTED,
+                (64 + 48, 128 + 16),
+                2_000_000_000,
+                b"second",
+            ),
+        ];
+        for (id, state, (begin, next), ts, text) in records {
+            let idx = (*id & 3) as usize;
+            put(&mut prb.descs, idx * 24, &(id | state << 62).to_le_bytes());
+            put(&mut prb.descs, idx * 24 + 8, &begin.to_le_bytes());
+            put(&mut prb.descs, idx * 24 + 16, &next.to_le_bytes());
+            put(&mut prb.infos, idx * 88 + 8, &ts.to_le_bytes());
+            put(
+                &mut prb.infos,
+                idx * 88 + 16,
+                &(text.len() as u16).to_le_bytes(),
+            );
+            let start = (if begin >> 6 == next >> 6 {
+                begin & 63
+            } else {
+                0
+            }) as usize;
+            put(&mut prb.data, start, &id.to_le_bytes());
+            put(&mut prb.data, start + 8, text);
+        }
+        let records = prb_records(&layout, &prb);
+        assert_eq!(
+            records,
+            vec![
+                Record {
+                    ts_nsec: 1_500_000,
+                    text: "first".to_string()
+                },
+                Record {
+                    ts_nsec: 2_000_000_000,
+                    text: "second".to_string()
+                }
+            ]
+        );
+        assert_eq!(records[0].to_string(), "[    0.001500] first");
+    }
+
+    #[test]
+    fn decode_log_buf() {
+        let layout = LogBufLayout {
+            header_size: 16,
+            ts_nsec: 0,
+            len: 8,
+            text_len: 10,
+        };
+        let mut buf = vec![0u8; 80];
+        let mut record = |idx: usize, ts: u64, text: &[u8]| {
+            put(&mut buf, idx, &ts.to_le_bytes());
+            put(&mut buf, idx + 8, &24u16.to_le_bytes());
+            put(&mut buf, idx + 10, &(text.len() as u16).to_le_bytes());
+            put(&mut buf, idx + 16, text);
+        };
+        record(0, 3, b"new");
+        record(32, 1, b"old");
+        // the next record did not fit at 56 anymore
+        let records = log_buf_records(&layout, &buf, 32, 24);
+        let texts: Vec<&str> = records.iter().map(|r| r.text.as_str()).collect();
+        assert_eq!(texts, ["old", "new"]);
+    }
+}
@@ -0,0 +1,75 @@
+//! `vmsh exec`: runs a non-interactive command through stage2 with separate stdout and
+//! stderr, see `ioutils::exec`.
+
+use ioutils::exec::{self, Message, MAX_PAYLOAD};
+use nix::unistd::Pid;
+use serde_json::Value;
+use simple_error::{bail, try_with};
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::thread;
+
+use crate::control;
+use crate::result::Result;
+use crate::rpc;
+
+pub struct ExecOptions {
+    pub pid: Pid,
+    pub command: Vec<String>,
+}
+
+/// Sends our stdin to the command.
+fn forward_stdin(mut conn: UnixStream) {
+    let mut stdin = io::stdin();
+    let mut buf = vec![0u8; MAX_PAYLOAD];
+    loop {
+        let n = match stdin.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        if exec::write_message(&mut conn, &Message::Stdin(buf[..n].to_vec())).is_err() {
+            return;
+        }
+    }
+    let _ = exec::write_message(&mut conn, &Message::StdinEof);
+}
+
+/// Starts `command` in the guest, returns the connection to stage2 that runs it.
+pub(crate) fn start(pid: Pid, command: &[String]) -> Result<UnixStream> {
+    let mut reader = control::connect(pid)?;
+    rpc::call(&mut reader, "exec", Value::Null)?;
+    let mut conn = reader.into_inner();
+    try_with!(
+        exec::write_message(&mut conn, &Message::Command(command.to_vec())),
+        "cannot send command"
+    );
+    Ok(conn)
+}
+
+/// Runs the command and returns its exit code.
+pub fn exec(opts: &ExecOptions) -> Result<i32> {
+    let mut conn = start(opts.pid, &opts.command)?;
+    let input = try_with!(conn.try_clone(), "cannot clone connection");
+    // not joined, stdin might never reach EOF
+    let _ = thread::spawn(move || forward_stdin(input));
+
+    let mut stdout = io::stdout();
+    let mut stderr = io::stderr();
+    loop {
+        let msg = try_with!(exec::read_message(&mut conn), "cannot read from stage2");
+        match msg {
+            Some(Message::Stdout(data)) => try_with!(
+                stdout.write_all(&data).and_then(|_| stdout.flush()),
+                "cannot write to stdout"
+            ),
+            Some(Message::Stderr(data)) => try_with!(
+                stderr.write_all(&data).and_then(|_| stderr.flush()),
+                "cannot write to stderr"
+            ),
+            Some(Message::Exit(code)) => return Ok(code),
+            Some(Message::Error(e)) => bail!("cannot run {}: {}", opts.command.join(" "), e),
+            Some(msg) => bail!("unexpected message from stage2: {:?}", msg),
+            None => bail!("stage2 closed the connection before the command exited"),
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Port forwarding from the host into the network namespace of the guest command
+//! (`vmsh attach --forward 2222:22`).
+//!
+//! stage2 connects to `FORWARD_VSOCK_PORT` and serves streams multiplexed with
+//! `ioutils::mux` on that connection. Each TCP connection accepted on a forwarded host port
+//! becomes a stream that stage2 connects to the guest port on localhost.
+
+use ioutils::mux::Mux;
+use log::{debug, info, warn};
+use simple_error::{bail, try_with, SimpleError};
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use crate::devices::virtio::vsock::FORWARD_VSOCK_PORT;
+use crate::result::Result;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PortForward {
+    pub host_port: u16,
+    pub guest_port: u16,
+}
+
+impl FromStr for PortForward {
+    type Err = SimpleError;
+
+    /// Parses `HOST_PORT:GUEST_PORT`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (host, guest) = match s.split_once(':') {
+            Some(ports) => ports,
+            None => bail!("expected HOST_PORT:GUEST_PORT, got {}", s),
+        };
+        Ok(PortForward {
+            host_port: try_with!(host.parse(), "invalid host port {}", host),
+            guest_port: try_with!(guest.parse(), "invalid guest port {}", guest),
+        })
+    }
+}
+
+/// Stops forwarding when dropped.
+pub struct Forwarder {
+    stop: Arc<AtomicBool>,
+    channel_path: PathBuf,
+    addrs: Vec<SocketAddr>,
+}
+
+impl Drop for Forwarder {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        // wake up the accepting threads
+        for addr in &self.addrs {
+            let _ = TcpStream::connect(addr);
+        }
+        let _ = UnixStream::connect(&self.channel_path);
+        let _ = fs::remove_file(&self.channel_path);
+    }
+}
+
+/// Listens on the host ports of `forwards` and on the vsock socket of stage2. Connections
+/// accepted while stage2 is not connected are closed.
+pub fn start(vsock: &Path, forwards: &[PortForward]) -> Result<Forwarder> {
+    let channel_path = PathBuf::from(format!("{}_{}", vsock.display(), FORWARD_VSOCK_PORT));
+    if channel_path.exists() {
+        try_with!(
+            fs::remove_file(&channel_path),
+            "cannot remove {}",
+            channel_path.display()
+        );
+    }
+    let channel_listener = try_with!(
+        UnixListener::bind(&channel_path),
+        "cannot listen on {}",
+        channel_path.display()
+    );
+    let stop = Arc::new(AtomicBool::new(false));
+    let mux: Arc<Mutex<Option<Mux>>> = Arc::new(Mutex::new(None));
+
+    // streams of all host ports share the id space of the channel
+    let next_id = Arc::new(AtomicU32::new(0));
+    let mut addrs = vec![];
+    for forward in forwards {
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, forward.host_port));
+        let listener = try_with!(TcpListener::bind(addr), "cannot listen on {}", addr);
+        addrs.push(addr);
+        info!(
+            "forward {} to port {} in the guest",
+            addr, forward.guest_port
+        );
+        let stop = Arc::clone(&stop);
+        let mux = Arc::clone(&mux);
+        let next_id = Arc::clone(&next_id);
+        let guest_port = forward.guest_port;
+        let _ = thread::spawn(move || accept_connections(listener, guest_port, mux, next_id, stop));
+    }
+
+    let stop_channel = Arc::clone(&stop);
+    let _ = thread::spawn(move || {
+        for conn in channel_listener.incoming() {
+            if stop_channel.load(Ordering::Acquire) {
+                break;
+            }
+            match conn {
+                Ok(conn) => serve_channel(conn, Arc::clone(&mux)),
+                Err(e) => warn!("cannot accept connection of stage2: {}", e),
+            }
+        }
+    });
+
+    Ok(Forwarder {
+        stop,
+        channel_path,
+        addrs,
+    })
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn accept_connections(
+    listener: TcpListener,
+    guest_port: u16,
+    mux: Arc<Mutex<Option<Mux>>>,
+    next_id: Arc<AtomicU32>,
+    stop: Arc<AtomicBool>,
+) {
+    for stream in listener.incoming() {
+        if stop.load(Ordering::Acquire) {
+            break;
+        }
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("cannot accept connection: {}", e);
+                continue;
+            }
+        };
+        let mux = match lock(&mux).clone() {
+            Some(mux) => mux,
+            None => {
+                warn!("stage2 is not connected, close forwarded connection");
+                continue;
+            }
+        };
+        let id = next_id.fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = mux.open(id, guest_port, stream) {
+            warn!(
+                "cannot forward connection to guest port {}: {}",
+                guest_port, e
+            );
+        }
+    }
+}
+
+/// Replaces the channel of a previous stage2 connection, i.e. after re-attaching.
+fn serve_channel(conn: UnixStream, current: Arc<Mutex<Option<Mux>>>) {
+    let writer = match conn.try_clone() {
+        Ok(writer) => writer,
+        Err(e) => {
+            warn!("cannot clone connection of stage2: {}", e);
+            return;
+        }
+    };
+    let mux = Mux::new(Box::new(writer));
+    *lock(&current) = Some(mux.clone());
+    debug!("stage2 connected for port forwarding");
+    let _ = thread::spawn(move || {
+        let mut reader = conn;
+        // the host never accepts streams of the guest
+        if let Err(e) = mux.run(&mut reader, |_| None) {
+            warn!("port forwarding channel failed: {}", e);
+        }
+    });
+}
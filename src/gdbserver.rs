@@ -0,0 +1,524 @@
+//! GDB remote serial protocol server for the guest of an attached VM. Threads in gdb are the
+//! vcpus of the VM, numbered from 1, and memory is accessed by virtual addresses of the current
+//! vcpu. The VM is stopped while gdb is not continuing or stepping it.
+
+use kvm_bindings as kvmb;
+use log::{info, warn};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::uio::{process_vm_readv, RemoteIoVec};
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::cmp::min;
+use std::collections::HashMap;
+use std::io::{IoSliceMut, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+
+use crate::guest_mem::GuestMem;
+use crate::kvm;
+use crate::kvm::hypervisor::memory::process_write_scattered;
+use crate::kvm::hypervisor::{Hypervisor, VmSelector};
+use crate::page_math::page_size;
+use crate::result::Result;
+use crate::tracer::wrap_syscall::DebugEvent;
+
+pub struct GdbServerOptions {
+    pub pid: Pid,
+    pub vm: Option<VmSelector>,
+    pub listen: SocketAddr,
+}
+
+const INT3: u8 = 0xcc;
+/// x86 exception vector of int3
+const BP_VECTOR: u32 = 3;
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+const DEBUG_CONTROL: u32 = kvmb::KVM_GUESTDBG_ENABLE | kvmb::KVM_GUESTDBG_USE_SW_BP;
+
+/// Packet level of the protocol, without the optional no-ack mode.
+struct Connection {
+    stream: TcpStream,
+}
+
+enum Input {
+    Packet(String),
+    /// ^C from gdb
+    Interrupt,
+    Disconnected,
+}
+
+impl Connection {
+    fn read_byte(&mut self) -> Result<Option<u8>> {
+        let mut byte = [0u8; 1];
+        let n = try_with!(self.stream.read(&mut byte), "cannot read from gdb");
+        Ok(if n == 0 { None } else { Some(byte[0]) })
+    }
+
+    fn read(&mut self) -> Result<Input> {
+        loop {
+            let byte = match self.read_byte()? {
+                Some(b) => b,
+                None => return Ok(Input::Disconnected),
+            };
+            match byte {
+                b'$' => {}
+                0x03 => return Ok(Input::Interrupt),
+                // acks of our packets
+                _ => continue,
+            }
+            let mut data = vec![];
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(b) => data.push(b),
+                    None => return Ok(Input::Disconnected),
+                }
+            }
+            let mut checksum = [0u8; 2];
+            for c in checksum.iter_mut() {
+                *c = require_with!(self.read_byte()?, "gdb disconnected");
+            }
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|c| u8::from_str_radix(c, 16).ok());
+            if expected != Some(checksum_of(&data)) {
+                warn!("gdb packet with invalid checksum, request retransmission");
+                try_with!(self.stream.write_all(b"-"), "cannot write to gdb");
+                continue;
+            }
+            try_with!(self.stream.write_all(b"+"), "cannot write to gdb");
+            return Ok(Input::Packet(String::from_utf8_lossy(&data).into_owned()));
+        }
+    }
+
+    fn send(&mut self, data: &str) -> Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        try_with!(
+            self.stream.write_all(packet.as_bytes()),
+            "cannot write to gdb"
+        );
+        Ok(())
+    }
+
+    /// Checks without blocking longer than `timeout_ms` whether gdb wants to interrupt the VM.
+    fn interrupted(&mut self, timeout_ms: i32) -> Result<bool> {
+        let mut fds = [PollFd::new(self.stream.as_raw_fd(), PollFlags::POLLIN)];
+        if try_with!(poll(&mut fds, timeout_ms), "cannot poll gdb connection") == 0 {
+            return Ok(false);
+        }
+        // also true if gdb disconnected, which stops the VM as well
+        Ok(!matches!(self.read_byte()?, Some(b) if b != 0x03))
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(s: &str) -> Result<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        bail!("hex string of odd length: {}", s);
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            Ok(try_with!(
+                u8::from_str_radix(&s[i..i + 2], 16),
+                "invalid hex"
+            ))
+        })
+        .collect()
+}
+
+fn parse_hex(s: &str) -> Result<usize> {
+    Ok(try_with!(
+        usize::from_str_radix(s, 16),
+        "invalid hex number: {}",
+        s
+    ))
+}
+
+/// Parses `addr,len` as used by the memory and breakpoint packets.
+fn parse_addr_len(s: &str) -> Result<(usize, usize)> {
+    let (addr, len) = require_with!(s.split_once(','), "expected addr,len: {}", s);
+    Ok((parse_hex(addr)?, parse_hex(len)?))
+}
+
+enum Stop {
+    Debug { vcpu: usize, exception: u32 },
+    Interrupted,
+}
+
+enum Action {
+    Reply(String),
+    Resume { step: bool },
+    Detach,
+}
+
+struct GdbServer {
+    vm: Hypervisor,
+    mem: GuestMem,
+    /// index of the vcpu selected by gdb
+    vcpu: usize,
+    /// original byte of each software breakpoint by virtual address
+    breakpoints: HashMap<usize, u8>,
+}
+
+impl GdbServer {
+    fn stop_reply(&self, signal: u8) -> String {
+        format!("T{:02x}thread:{:x};", signal, self.vcpu + 1)
+    }
+
+    fn select_vcpu(&mut self, id: &str) -> String {
+        // -1 and 0 select any thread
+        if id == "-1" || id == "0" {
+            return "OK".to_string();
+        }
+        match usize::from_str_radix(id, 16) {
+            Ok(id) if id >= 1 && id <= self.vm.vcpus.len() => {
+                self.vcpu = id - 1;
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    /// General purpose registers in the order of gdb's amd64 target description. The floating
+    /// point registers that would follow are left out, gdb shows them as unavailable.
+    fn read_registers(&self) -> Result<String> {
+        let vcpu = &self.vm.vcpus[self.vcpu];
+        let regs = self.vm.get_regs(vcpu)?;
+        let sregs = self.vm.get_sregs(vcpu)?;
+        let mut data = vec![];
+        for r in [
+            regs.rax, regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp, regs.rsp,
+            regs.r8, regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15, regs.rip,
+        ] {
+            data.extend_from_slice(&r.to_le_bytes());
+        }
+        for r in [
+            regs.eflags as u32,
+            sregs.cs.selector as u32,
+            sregs.ss.selector as u32,
+            sregs.ds.selector as u32,
+            sregs.es.selector as u32,
+            sregs.fs.selector as u32,
+            sregs.gs.selector as u32,
+        ] {
+            data.extend_from_slice(&r.to_le_bytes());
+        }
+        Ok(encode_hex(&data))
+    }
+
+    /// Segment registers cannot be changed.
+    fn write_registers(&self, hex: &str) -> Result<()> {
+        let data = decode_hex(hex)?;
+        if data.len() < 17 * 8 + 4 {
+            bail!("register packet too short: {} bytes", data.len());
+        }
+        let reg = |i: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&data[i * 8..(i + 1) * 8]);
+            u64::from_le_bytes(bytes)
+        };
+        let vcpu = &self.vm.vcpus[self.vcpu];
+        let mut regs = self.vm.get_regs(vcpu)?;
+        regs.rax = reg(0);
+        regs.rbx = reg(1);
+        regs.rcx = reg(2);
+        regs.rdx = reg(3);
+        regs.rsi = reg(4);
+        regs.rdi = reg(5);
+        regs.rbp = reg(6);
+        regs.rsp = reg(7);
+        regs.r8 = reg(8);
+        regs.r9 = reg(9);
+        regs.r10 = reg(10);
+        regs.r11 = reg(11);
+        regs.r12 = reg(12);
+        regs.r13 = reg(13);
+        regs.r14 = reg(14);
+        regs.r15 = reg(15);
+        regs.rip = reg(16);
+        let mut eflags = [0u8; 4];
+        eflags.copy_from_slice(&data[17 * 8..17 * 8 + 4]);
+        regs.eflags = u32::from_le_bytes(eflags) as u64;
+        self.vm.set_regs(vcpu, &regs)
+    }
+
+    /// Translates `len` bytes at the virtual address `addr` of the current vcpu to addresses in
+    /// the hypervisor.
+    fn translate(&self, addr: usize, len: usize) -> Result<Vec<RemoteIoVec>> {
+        let cr3 = self.vm.get_sregs(&self.vm.vcpus[self.vcpu])?.cr3 as usize;
+        let mut iovs = vec![];
+        let end = addr + len;
+        let mut cur = addr;
+        while cur < end {
+            let page_end = (cur | (page_size() - 1)) + 1;
+            let piece = min(page_end, end) - cur;
+            let host_addr = match self.mem.translate(&self.vm, cr3, cur)? {
+                Some(a) => a,
+                None => bail!("address {:#x} is not mapped", cur),
+            };
+            iovs.push(RemoteIoVec {
+                base: host_addr,
+                len: piece,
+            });
+            cur += piece;
+        }
+        Ok(iovs)
+    }
+
+    fn read_memory(&self, addr: usize, len: usize) -> Result<Vec<u8>> {
+        let iovs = self.translate(addr, len)?;
+        let mut buf = vec![0u8; len];
+        try_with!(
+            process_vm_readv(self.vm.pid, &mut [IoSliceMut::new(&mut buf)], &iovs),
+            "cannot read hypervisor memory"
+        );
+        Ok(buf)
+    }
+
+    fn write_memory(&self, addr: usize, data: &[u8]) -> Result<()> {
+        self.vm.check_writable("writing guest memory")?;
+        let iovs = self.translate(addr, data.len())?;
+        try_with!(
+            process_write_scattered(self.vm.pid, data, &iovs),
+            "cannot write hypervisor memory"
+        );
+        Ok(())
+    }
+
+    fn insert_breakpoint(&mut self, addr: usize) -> Result<()> {
+        if self.breakpoints.contains_key(&addr) {
+            return Ok(());
+        }
+        let orig = self.read_memory(addr, 1)?[0];
+        self.write_memory(addr, &[INT3])?;
+        self.breakpoints.insert(addr, orig);
+        Ok(())
+    }
+
+    fn remove_breakpoint(&mut self, addr: usize) -> Result<()> {
+        if let Some(orig) = self.breakpoints.remove(&addr) {
+            self.write_memory(addr, &[orig])?;
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, packet: &str) -> Result<Action> {
+        let reply = |r: &str| -> Result<Action> { Ok(Action::Reply(r.to_string())) };
+        let (cmd, args) = packet.split_at(min(1, packet.len()));
+        match cmd {
+            "?" => Ok(Action::Reply(self.stop_reply(SIGTRAP))),
+            "g" => Ok(Action::Reply(self.read_registers()?)),
+            "G" => {
+                self.write_registers(args)?;
+                reply("OK")
+            }
+            "m" => {
+                let (addr, len) = parse_addr_len(args)?;
+                Ok(Action::Reply(encode_hex(&self.read_memory(addr, len)?)))
+            }
+            "M" => {
+                let (range, data) = require_with!(args.split_once(':'), "expected data");
+                let (addr, _) = parse_addr_len(range)?;
+                self.write_memory(addr, &decode_hex(data)?)?;
+                reply("OK")
+            }
+            "Z" | "z" => {
+                let (kind, range) = require_with!(args.split_once(','), "expected type");
+                // only software breakpoints
+                if kind != "0" {
+                    return reply("");
+                }
+                let (addr, _) = parse_addr_len(range)?;
+                if cmd == "Z" {
+                    self.insert_breakpoint(addr)?;
+                } else {
+                    self.remove_breakpoint(addr)?;
+                }
+                reply("OK")
+            }
+            "c" | "s" => {
+                if !args.is_empty() {
+                    let rip = parse_hex(args)? as u64;
+                    let vcpu = &self.vm.vcpus[self.vcpu];
+                    let mut regs = self.vm.get_regs(vcpu)?;
+                    regs.rip = rip;
+                    self.vm.set_regs(vcpu, &regs)?;
+                }
+                Ok(Action::Resume { step: cmd == "s" })
+            }
+            "H" => Ok(Action::Reply(self.select_vcpu(args.get(1..).unwrap_or("")))),
+            "T" => match usize::from_str_radix(args, 16) {
+                Ok(id) if id >= 1 && id <= self.vm.vcpus.len() => reply("OK"),
+                _ => reply("E01"),
+            },
+            "D" | "k" => Ok(Action::Detach),
+            _ => self.query(packet),
+        }
+    }
+
+    fn query(&mut self, packet: &str) -> Result<Action> {
+        let reply = match packet {
+            p if p.starts_with("qSupported") => "PacketSize=4000;swbreak+".to_string(),
+            "qAttached" => "1".to_string(),
+            "qC" => format!("QC{:x}", self.vcpu + 1),
+            "qfThreadInfo" => {
+                let ids = (1..=self.vm.vcpus.len())
+                    .map(|id| format!("{:x}", id))
+                    .collect::<Vec<_>>();
+                format!("m{}", ids.join(","))
+            }
+            "qsThreadInfo" => "l".to_string(),
+            // unsupported
+            _ => String::new(),
+        };
+        Ok(Action::Reply(reply))
+    }
+
+    /// Runs the VM until a vcpu hits a breakpoint, finishes a single step or gdb interrupts.
+    fn resume(&self, conn: &mut Connection, step: bool) -> Result<Stop> {
+        let vcpu = &self.vm.vcpus[self.vcpu];
+        if step {
+            self.vm
+                .set_guest_debug(vcpu, DEBUG_CONTROL | kvmb::KVM_GUESTDBG_SINGLESTEP)?;
+        }
+        let mut stop = None;
+        let res = self.vm.kvmrun_wrapped(|wrapper| {
+            let mut wrapper = try_with!(wrapper.lock(), "cannot lock wrapper");
+            let wrapper = require_with!(wrapper.as_mut(), "no kvm run wrapper");
+            let mut idle = false;
+            loop {
+                if conn.interrupted(if idle { 10 } else { 0 })? {
+                    stop = Some(Stop::Interrupted);
+                    return Ok(());
+                }
+                match wrapper.wait_for_debug_exit()? {
+                    DebugEvent::Exit(exit) => {
+                        let vcpu = self
+                            .vm
+                            .vcpus
+                            .iter()
+                            .position(|v| v.fd_num == exit.vcpu_fd)
+                            .unwrap_or(0);
+                        stop = Some(Stop::Debug {
+                            vcpu,
+                            exception: exit.exception,
+                        });
+                        return Ok(());
+                    }
+                    DebugEvent::Other => idle = false,
+                    DebugEvent::Idle => idle = true,
+                }
+            }
+        });
+        if step {
+            self.vm.set_guest_debug(vcpu, DEBUG_CONTROL)?;
+        }
+        res?;
+        Ok(require_with!(stop, "vm stopped without reason"))
+    }
+
+    /// Removes all breakpoints and disables guest debugging before the VM continues without
+    /// us.
+    fn cleanup(&mut self) -> Result<()> {
+        let addrs = self.breakpoints.keys().copied().collect::<Vec<_>>();
+        for addr in addrs {
+            self.remove_breakpoint(addr)?;
+        }
+        for vcpu in &self.vm.vcpus {
+            self.vm.set_guest_debug(vcpu, 0)?;
+        }
+        Ok(())
+    }
+
+    fn serve(&mut self, conn: &mut Connection) -> Result<()> {
+        loop {
+            let packet = match conn.read()? {
+                Input::Packet(p) => p,
+                // the VM is stopped already
+                Input::Interrupt => {
+                    conn.send(&self.stop_reply(SIGINT))?;
+                    continue;
+                }
+                Input::Disconnected => return Ok(()),
+            };
+            let action = match self.handle(&packet) {
+                Ok(action) => action,
+                Err(e) => {
+                    warn!("gdb packet {} failed: {}", packet, e);
+                    Action::Reply("E01".to_string())
+                }
+            };
+            match action {
+                Action::Reply(r) => conn.send(&r)?,
+                Action::Resume { step } => {
+                    let signal = match self.resume(conn, step)? {
+                        Stop::Debug { vcpu, exception } => {
+                            self.vcpu = vcpu;
+                            if exception == BP_VECTOR {
+                                // the vcpu stops on the int3, not after it
+                                conn.send(&format!("T05thread:{:x};swbreak:;", vcpu + 1))?;
+                                continue;
+                            }
+                            SIGTRAP
+                        }
+                        Stop::Interrupted => SIGINT,
+                    };
+                    conn.send(&self.stop_reply(signal))?;
+                }
+                Action::Detach => {
+                    conn.send("OK")?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+pub fn gdbserver(opts: &GdbServerOptions) -> Result<()> {
+    let listener = try_with!(
+        TcpListener::bind(opts.listen),
+        "cannot listen on {}",
+        opts.listen
+    );
+    info!("waiting for gdb on {}", opts.listen);
+    let (stream, peer) = try_with!(listener.accept(), "cannot accept gdb connection");
+    info!("gdb connected from {}", peer);
+    try_with!(stream.set_nodelay(true), "cannot set TCP_NODELAY");
+
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid, opts.vm),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    vm.stop()?;
+    let mem = try_with!(GuestMem::new(&vm), "cannot access guest memory");
+    for vcpu in &vm.vcpus {
+        try_with!(
+            vm.set_guest_debug(vcpu, DEBUG_CONTROL),
+            "cannot enable guest debugging"
+        );
+    }
+    let mut server = GdbServer {
+        vm,
+        mem,
+        vcpu: 0,
+        breakpoints: HashMap::new(),
+    };
+    let mut conn = Connection { stream };
+    let res = server.serve(&mut conn);
+    if let Err(e) = server.cleanup() {
+        warn!("cannot remove breakpoints: {}", e);
+    }
+    res?;
+    info!("gdb detached");
+    server.vm.resume()
+}
@@ -1,32 +1,50 @@
 use crate::cpu::Regs;
 use kvm_bindings as kvmb;
-use log::debug;
+use log::{debug, warn};
 use nix::sys::mman::ProtFlags;
 use simple_error::{bail, require_with, try_with};
 use std::cmp::{max, Ordering};
 use std::ops::Range;
 use std::sync::Arc;
+use vm_memory::remote_mem::process_read_bytes;
 
 use crate::kvm::hypervisor::memory::PhysMem;
 use crate::kvm::hypervisor::Hypervisor;
 use crate::page_math::huge_page_size;
 use crate::page_table::{
-    self, PageTable, PageTableFlags, PageTableIteratorValue, PhysAddr, VirtMem,
+    self, PageTable, PageTableFlags, PageTableIteratorValue, Paging, PhysAddr, VirtMem,
 };
 use crate::result::Result;
 
 pub struct GuestMem {
     maps: Arc<PhysHostMap>,
     regs: Regs,
+    /// Root of the kernel page table, the PML5 table with 5-level paging
     pml4: PhysAddr,
+    paging: Paging,
 }
 
+/// Upper half of the address space on x86_64, i.e. direct map, vmalloc, modules and kernel text
+pub const KERNEL_ADDRESS_SPACE: Range<usize> = 0xFFFF800000000000..usize::MAX;
+
+/// Lower half of the address space on x86_64, used by processes
+pub const USER_ADDRESS_SPACE: Range<usize> = 0..0x0000800000000000;
+
+/// Like `KERNEL_ADDRESS_SPACE`, but with 5-level paging
+pub const KERNEL_ADDRESS_SPACE_LA57: Range<usize> = 0xFF00000000000000..usize::MAX;
+
+/// Like `USER_ADDRESS_SPACE`, but with 5-level paging
+pub const USER_ADDRESS_SPACE_LA57: Range<usize> = 0..0x0100000000000000;
+
 // x86_64 & linux address to load the Linux kernel too
 const PHYS_ADDR_MASK: u64 = 0xFFFFFFFFFF000;
 
 // enable PCID support
 const X86_CR4_PCIDE: u64 = 0x00020000;
 
+// enable 5-level paging
+const X86_CR4_LA57: u64 = 0x00001000;
+
 fn get_page_table_addr(sregs: &kvmb::kvm_sregs) -> usize {
     (if sregs.cr4 & X86_CR4_PCIDE != 0 {
         sregs.cr3 & PHYS_ADDR_MASK
@@ -52,10 +70,10 @@ impl MappedMemory {
 
 fn prot_flags(ptflags: PageTableFlags) -> ProtFlags {
     let mut f = ProtFlags::PROT_READ;
-    if ptflags.contains(PageTableFlags::WRITABLE) {
+    if ptflags.is_writable() {
         f |= ProtFlags::PROT_WRITE;
     }
-    if !ptflags.contains(PageTableFlags::NO_EXECUTE) {
+    if ptflags.is_executable() {
         f |= ProtFlags::PROT_EXEC;
     }
     f
@@ -102,8 +120,10 @@ impl PhysHostMap {
         PhysHostMap { memslots: vec }
     }
 
-    pub fn last_range(&self) -> Option<Range<usize>> {
-        self.memslots.last().map(|v| v.0.clone())
+    /// Physical memory ranges backed by memslots
+    pub fn ranges(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        // our ranges include the last address
+        self.memslots.iter().map(|(r, _)| r.start..r.end + 1)
     }
 
     pub fn get_range(&self, phys_addr: usize) -> Option<(Range<usize>, isize)> {
@@ -142,6 +162,11 @@ impl GuestMem {
                 (m.phys_addr..m.phys_end() - 1, m.phys_to_host_offset())
             })));
         let first_core = &hv.vcpus[0];
+        if hv.in_nested_guest(first_core) {
+            warn!(
+                "vcpu 0 runs a nested guest, its page tables are not the ones of the guest kernel"
+            );
+        }
         let regs = try_with!(hv.get_regs(first_core), "failed to get vcpu registers");
         let sregs = try_with!(
             hv.get_sregs(first_core),
@@ -149,8 +174,13 @@ impl GuestMem {
         );
 
         let pt_addr = get_page_table_addr(&sregs);
+        let paging = if sregs.cr4 & X86_CR4_LA57 != 0 {
+            Paging::FiveLevel
+        } else {
+            Paging::FourLevel
+        };
 
-        debug!("pml4: {:#x}\n", pt_addr);
+        debug!("pml4: {:#x} ({:?} paging)\n", pt_addr, paging);
 
         let host_offset = require_with!(maps.get(pt_addr), "cannot find page table memory");
 
@@ -161,11 +191,50 @@ impl GuestMem {
                 value: pt_addr,
                 host_offset,
             },
+            paging,
         })
     }
 
-    pub fn last_memslot_range(&self) -> Option<Range<usize>> {
-        self.maps.last_range()
+    /// Re-reads the page table of the first vcpu, which might have switched to another process
+    /// while the VM was running. Needs a stopped VM.
+    pub fn reload_page_table(&mut self, hv: &Hypervisor) -> Result<()> {
+        let first_core = &hv.vcpus[0];
+        self.regs = try_with!(hv.get_regs(first_core), "failed to get vcpu registers");
+        let sregs = try_with!(
+            hv.get_sregs(first_core),
+            "failed to get vcpu special registers"
+        );
+        let pt_addr = get_page_table_addr(&sregs);
+        let host_offset = require_with!(self.maps.get(pt_addr), "cannot find page table memory");
+        self.pml4 = PhysAddr {
+            value: pt_addr,
+            host_offset,
+        };
+        Ok(())
+    }
+
+    pub fn paging(&self) -> Paging {
+        self.paging
+    }
+
+    /// Range of kernel addresses for the paging mode of the guest
+    pub fn kernel_address_space(&self) -> Range<usize> {
+        match self.paging {
+            Paging::FourLevel => KERNEL_ADDRESS_SPACE,
+            Paging::FiveLevel => KERNEL_ADDRESS_SPACE_LA57,
+        }
+    }
+
+    /// Range of user space addresses for the paging mode of the guest
+    pub fn user_address_space(&self) -> Range<usize> {
+        match self.paging {
+            Paging::FourLevel => USER_ADDRESS_SPACE,
+            Paging::FiveLevel => USER_ADDRESS_SPACE_LA57,
+        }
+    }
+
+    pub fn memslot_ranges(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        self.maps.ranges()
     }
 
     pub fn map_memory(
@@ -173,8 +242,107 @@ impl GuestMem {
         hv: Arc<Hypervisor>,
         phys_mem: PhysMem<u8>,
         map: &[MappedMemory],
+        huge_pages: bool,
     ) -> Result<VirtMem> {
-        page_table::map_memory(hv, phys_mem, &mut self.pml4, map, &self.maps)
+        page_table::map_memory(
+            hv,
+            phys_mem,
+            &mut self.pml4,
+            self.paging,
+            map,
+            &self.maps,
+            huge_pages,
+        )
+    }
+
+    /// Returns all memory mapped in the kernel address space that is backed by
+    /// guest memory. Pages that are contiguous in virtual and physical memory
+    /// and share the same protection are merged.
+    pub fn kernel_mappings(&self, hv: &Hypervisor) -> Result<Vec<MappedMemory>> {
+        if self.regs.cs & 3 == 3 {
+            warn!("vcpu stopped in userspace, with page table isolation the kernel is only partially mapped");
+        }
+        self.mappings(hv, &self.pml4, self.kernel_address_space())
+    }
+
+    /// Like `kernel_mappings`, but returns the user space memory of the process whose page
+    /// table is at `cr3`.
+    pub fn process_mappings(&self, hv: &Hypervisor, cr3: usize) -> Result<Vec<MappedMemory>> {
+        let pt_addr = cr3 & PHYS_ADDR_MASK as usize;
+        let host_offset = require_with!(
+            self.maps.get(pt_addr),
+            "page table {:#x} is not in guest memory",
+            pt_addr
+        );
+        let pml4 = PhysAddr {
+            value: pt_addr,
+            host_offset,
+        };
+        self.mappings(hv, &pml4, self.user_address_space())
+    }
+
+    /// Translates the virtual address `addr` with the page table at `cr3`. Returns the address
+    /// in the hypervisor, or None if `addr` is not mapped to guest memory.
+    pub fn translate(&self, hv: &Hypervisor, cr3: usize, addr: usize) -> Result<Option<usize>> {
+        let pt_addr = cr3 & PHYS_ADDR_MASK as usize;
+        let host_offset = require_with!(
+            self.maps.get(pt_addr),
+            "page table {:#x} is not in guest memory",
+            pt_addr
+        );
+        let pml4 = PhysAddr {
+            value: pt_addr,
+            host_offset,
+        };
+        let pml4 = try_with!(
+            PageTable::read(hv, &pml4, 0, self.paging.root_level()),
+            "cannot read pml4 page table"
+        );
+        let entry = match pml4.iter(hv, Arc::clone(&self.maps), addr..addr + 1).next() {
+            Some(e) => try_with!(e, "cannot read page table"),
+            None => return Ok(None),
+        };
+        let phys_addr = entry.entry.addr() as usize + (addr & (huge_page_size(entry.level) - 1));
+        Ok(self.maps.get(phys_addr).map(|host_offset| {
+            PhysAddr {
+                value: phys_addr,
+                host_offset,
+            }
+            .host_addr()
+        }))
+    }
+
+    fn mappings(
+        &self,
+        hv: &Hypervisor,
+        pml4: &PhysAddr,
+        range: Range<usize>,
+    ) -> Result<Vec<MappedMemory>> {
+        let pml4 = try_with!(
+            PageTable::read(hv, pml4, 0, self.paging.root_level()),
+            "cannot read pml4 page table"
+        );
+        let mut mappings: Vec<MappedMemory> = vec![];
+        for e in pml4.iter(hv, Arc::clone(&self.maps), range) {
+            let entry = try_with!(e, "cannot read page table");
+            let host_offset = match self.maps.get(entry.entry.addr() as usize) {
+                Some(offset) => offset,
+                // i.e. mmio of devices
+                None => continue,
+            };
+            let m = mapped_memory(&entry, host_offset);
+            if let Some(last) = mappings.last_mut() {
+                if last.virt_start.checked_add(last.len) == Some(m.virt_start)
+                    && last.phys_start.add(last.len) == m.phys_start
+                    && last.prot == m.prot
+                {
+                    last.len += m.len;
+                    continue;
+                }
+            }
+            mappings.push(m);
+        }
+        Ok(mappings)
     }
 
     pub fn find_kernel_sections(
@@ -187,9 +355,8 @@ impl GuestMem {
             bail!("program stopped in userspace. Linux kernel might be not mapped in thise mode");
         }
 
-        // level/virt_addr is wrong, but does not matter
         let pml4 = try_with!(
-            PageTable::read(hv, &self.pml4, 0, 0),
+            PageTable::read(hv, &self.pml4, 0, self.paging.root_level()),
             "cannot read pml4 page table"
         );
 
@@ -244,6 +411,63 @@ impl GuestMem {
     }
 }
 
+/// Reads kernel virtual memory through the page tables of the guest.
+pub struct KernelMem<'a> {
+    pub hv: &'a Hypervisor,
+    pub mappings: Vec<MappedMemory>,
+}
+
+impl<'a> KernelMem<'a> {
+    pub fn new(hv: &'a Hypervisor, guest_mem: &GuestMem) -> Result<KernelMem<'a>> {
+        Ok(KernelMem {
+            hv,
+            mappings: try_with!(
+                guest_mem.kernel_mappings(hv),
+                "cannot read kernel page tables"
+            ),
+        })
+    }
+
+    pub fn read(&self, addr: usize, buf: &mut [u8]) -> Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let cur = addr.wrapping_add(done);
+            let m = require_with!(
+                self.mappings
+                    .iter()
+                    .find(|m| m.virt_start <= cur && cur - m.virt_start < m.len),
+                "kernel address {:#x} is not mapped",
+                cur
+            );
+            let len = std::cmp::min(buf.len() - done, m.len - (cur - m.virt_start));
+            let host_addr = m.phys_start.host_addr() + (cur - m.virt_start);
+            try_with!(
+                process_read_bytes(
+                    self.hv.pid,
+                    &mut buf[done..done + len],
+                    host_addr as *const libc::c_void
+                ),
+                "cannot read kernel memory at {:#x}",
+                cur
+            );
+            done += len;
+        }
+        Ok(())
+    }
+
+    pub fn read_u64(&self, addr: usize) -> Result<u64> {
+        let mut buf = [0u8; 8];
+        self.read(addr, &mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    pub fn read_u32(&self, addr: usize) -> Result<u32> {
+        let mut buf = [0u8; 4];
+        self.read(addr, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::guest_mem::PhysHostMap;
@@ -0,0 +1,102 @@
+//! Waiting for the guest to change state, i.e. for stage1 to register or unregister devices.
+//! A guest that hangs or whose stage1 crashed never does, so every wait gives up after
+//! `timeout()` (`vmsh attach --guest-timeout`) and stops early once it is cancelled.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::result::{Result, VmshError};
+
+/// Used unless `set_timeout` was called.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+static TIMEOUT_MS: AtomicU64 = AtomicU64::new(DEFAULT_TIMEOUT.as_millis() as u64);
+
+/// How long the guest gets for every state change.
+pub fn timeout() -> Duration {
+    Duration::from_millis(TIMEOUT_MS.load(Ordering::Acquire))
+}
+
+pub fn set_timeout(timeout: Duration) {
+    TIMEOUT_MS.store(timeout.as_millis() as u64, Ordering::Release);
+}
+
+/// Calls `state` every `interval` until it returns a value, i.e. the state stage1 reached.
+/// `what` describes what we wait for in errors. Fails with `VmshError::GuestTimeout` after
+/// `timeout()` and with `VmshError::Cancelled` once `cancelled` returns true.
+pub fn poll<T>(
+    what: &str,
+    interval: Duration,
+    cancelled: impl FnMut() -> bool,
+    state: impl FnMut() -> Result<Option<T>>,
+) -> Result<T> {
+    poll_for(what, timeout(), interval, cancelled, state)
+}
+
+/// Like `poll`, but with a timeout other than the configured one.
+pub fn poll_for<T>(
+    what: &str,
+    timeout: Duration,
+    interval: Duration,
+    mut cancelled: impl FnMut() -> bool,
+    mut state: impl FnMut() -> Result<Option<T>>,
+) -> Result<T> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(state) = state()? {
+            return Ok(state);
+        }
+        if cancelled() {
+            return Err(VmshError::Cancelled(what.into()));
+        }
+        if Instant::now() >= deadline {
+            return Err(VmshError::GuestTimeout {
+                what: what.into(),
+                timeout,
+            });
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn poll_state() {
+        let mut n = 0;
+        let res = poll_for(
+            "ready",
+            DEFAULT_TIMEOUT,
+            Duration::ZERO,
+            || false,
+            || {
+                n += 1;
+                Ok(Some(n).filter(|n| *n == 3))
+            },
+        );
+        assert_eq!(res.ok(), Some(3));
+
+        let res = poll_for::<()>(
+            "ready",
+            Duration::ZERO,
+            Duration::ZERO,
+            || false,
+            || Ok(None),
+        );
+        assert_eq!(
+            res.err().map(|e| e.to_string()).as_deref(),
+            Some("guest did not respond within 0 seconds: ready")
+        );
+
+        let res = poll_for::<()>(
+            "ready",
+            DEFAULT_TIMEOUT,
+            Duration::ZERO,
+            || true,
+            || Ok(None),
+        );
+        assert!(matches!(res, Err(VmshError::Cancelled(_))));
+    }
+}
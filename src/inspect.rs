@@ -1,21 +1,248 @@
 //mod device;
 
 use crate::guest_mem::GuestMem;
-use crate::kernel::find_kernel;
+use crate::kernel::{find_kernel, Kernel};
 use crate::result::Result;
+use crate::symbols;
 use log::*;
 use nix::unistd::Pid;
+use serde::{Serialize, Serializer};
 use simple_error::try_with;
+use std::io::{self, Write};
+use std::path::PathBuf;
 
 use crate::kvm;
+use crate::kvm::hypervisor::{Hypervisor, VmSelector};
+use crate::tracer::wrap_syscall::exit_reason_name;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum InspectFormat {
+    /// Human readable log messages
+    Text,
+    /// A JSON document on stdout
+    Json,
+}
 
 pub struct InspectOptions {
     pub pid: Pid,
+    pub vm: Option<VmSelector>,
+    /// vmlinux or System.map of the guest kernel, to symbolize backtraces
+    pub symbols: Option<PathBuf>,
+    pub format: InspectFormat,
+}
+
+/// Addresses are written as hex strings, JSON numbers lose precision above 2^53.
+fn hex<S: Serializer>(value: &usize, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("{:#x}", value))
+}
+
+fn hex_opt<S: Serializer>(
+    value: &Option<usize>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match value {
+        Some(v) => hex(v, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[derive(Serialize)]
+struct MemslotReport {
+    #[serde(serialize_with = "hex")]
+    guest_phys_addr: usize,
+    #[serde(serialize_with = "hex")]
+    host_addr: usize,
+    size: usize,
+    prot: String,
+    pathname: String,
+}
+
+#[derive(Serialize)]
+struct VcpuReport {
+    idx: usize,
+    fd: i32,
+    exit_reason: u32,
+    /// The registers are the ones of a nested guest
+    nested_guest: bool,
+    #[serde(serialize_with = "hex")]
+    rip: usize,
+    #[serde(serialize_with = "hex")]
+    rsp: usize,
+    #[serde(serialize_with = "hex")]
+    cr0: usize,
+    #[serde(serialize_with = "hex")]
+    cr3: usize,
+    #[serde(serialize_with = "hex")]
+    cr4: usize,
+    #[serde(serialize_with = "hex")]
+    efer: usize,
+    userspace: bool,
+}
+
+#[derive(Serialize)]
+struct SectionReport {
+    #[serde(serialize_with = "hex")]
+    virt_addr: usize,
+    #[serde(serialize_with = "hex")]
+    phys_addr: usize,
+    size: usize,
+    prot: String,
+}
+
+#[derive(Serialize)]
+struct KernelReport {
+    #[serde(serialize_with = "hex")]
+    start: usize,
+    #[serde(serialize_with = "hex")]
+    end: usize,
+    version: Option<String>,
+    #[serde(serialize_with = "hex_opt")]
+    kaslr_offset: Option<usize>,
+    symbols: usize,
+    memory_maps: Vec<SectionReport>,
+}
+
+#[derive(Serialize)]
+struct VmReport {
+    vm_fd: i32,
+    memslots: Vec<MemslotReport>,
+    vcpus: Vec<VcpuReport>,
+    kernel: Option<KernelReport>,
+}
+
+#[derive(Serialize)]
+struct InspectReport {
+    pid: i32,
+    vms: Vec<VmReport>,
+}
+
+fn kernel_report(vm: &Hypervisor, kernel: &Kernel) -> KernelReport {
+    let version = match kernel.version(vm) {
+        Ok(v) => Some(v),
+        Err(e) => {
+            warn!("cannot detect kernel version: {}", e);
+            None
+        }
+    };
+    KernelReport {
+        start: kernel.range.start,
+        end: kernel.range.end,
+        version,
+        kaslr_offset: kernel.kaslr_offset(),
+        symbols: kernel.symbols.len(),
+        memory_maps: kernel
+            .memory_sections
+            .iter()
+            .map(|m| SectionReport {
+                virt_addr: m.virt_start,
+                phys_addr: m.phys_start.value,
+                size: m.len,
+                prot: format!("{:?}", m.prot),
+            })
+            .collect(),
+    }
+}
+
+fn vm_report(pid: Pid, selector: VmSelector) -> Result<VmReport> {
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(pid, Some(selector)),
+        "cannot get vms for process {}",
+        pid
+    );
+    vm.stop()?;
+
+    let memslots = vm
+        .get_maps()?
+        .iter()
+        .map(|m| MemslotReport {
+            guest_phys_addr: m.phys_addr,
+            host_addr: m.start,
+            size: m.size(),
+            prot: format!("{:?}", m.prot_flags),
+            pathname: m.pathname.clone(),
+        })
+        .collect();
+
+    let mut vcpus = vec![];
+    for vcpu in &vm.vcpus {
+        let regs = vm.get_regs(vcpu)?;
+        let sregs = vm.get_sregs(vcpu)?;
+        let exit_reason = match &vcpu.vcpu_map {
+            Some(map) => {
+                let map_ptr = map.start as *const kvm_bindings::kvm_run;
+                let reason_ptr: *const u32 = unsafe { &((*map_ptr).exit_reason) };
+                kvm::hypervisor::memory::process_read(pid, reason_ptr as *const libc::c_void)?
+            }
+            None => 0,
+        };
+        vcpus.push(VcpuReport {
+            idx: vcpu.idx,
+            fd: vcpu.fd_num,
+            exit_reason,
+            nested_guest: vm.in_nested_guest(vcpu),
+            rip: regs.rip as usize,
+            rsp: regs.rsp as usize,
+            cr0: sregs.cr0 as usize,
+            cr3: sregs.cr3 as usize,
+            cr4: sregs.cr4 as usize,
+            efer: sregs.efer as usize,
+            userspace: sregs.cs.selector & 3 == 3,
+        });
+    }
+
+    let mem = GuestMem::new(&vm)?;
+    let kernel = match find_kernel(&mem, &vm) {
+        Ok(kernel) => Some(kernel_report(&vm, &kernel)),
+        Err(e) => {
+            warn!("could not find kernel: {}", e);
+            None
+        }
+    };
+
+    Ok(VmReport {
+        vm_fd: vm.vm_fd,
+        memslots,
+        vcpus,
+        kernel,
+    })
+}
+
+/// Inspects the selected VM, or all VMs of the process if none was selected.
+fn inspect_json(opts: &InspectOptions) -> Result<()> {
+    let selectors = match opts.vm {
+        Some(selector) => vec![selector],
+        None => try_with!(
+            kvm::hypervisor::find_vms(opts.pid),
+            "cannot get vms for process {}",
+            opts.pid
+        )
+        .iter()
+        .map(|vm| VmSelector::Fd(vm.vm_fd))
+        .collect(),
+    };
+    let mut vms = vec![];
+    for selector in selectors {
+        vms.push(vm_report(opts.pid, selector)?);
+    }
+    let report = InspectReport {
+        pid: opts.pid.as_raw(),
+        vms,
+    };
+    let mut stdout = io::stdout().lock();
+    try_with!(
+        serde_json::to_writer_pretty(&mut stdout, &report),
+        "cannot write report"
+    );
+    try_with!(writeln!(stdout), "cannot write report");
+    Ok(())
 }
 
 pub fn inspect(opts: &InspectOptions) -> Result<()> {
+    if opts.format == InspectFormat::Json {
+        return inspect_json(opts);
+    }
     let vm = try_with!(
-        kvm::hypervisor::get_hypervisor(opts.pid),
+        kvm::hypervisor::get_hypervisor(opts.pid, opts.vm),
         "cannot get vms for process {}",
         opts.pid
     );
@@ -38,7 +265,11 @@ pub fn inspect(opts: &InspectOptions) -> Result<()> {
         let map_ptr = map.start as *const kvm_bindings::kvm_run;
         let kvm_run: kvm_bindings::kvm_run =
             kvm::hypervisor::memory::process_read(opts.pid, map_ptr as *const libc::c_void)?;
-        info!("kvm_run: exit_reason {}", kvm_run.exit_reason);
+        info!(
+            "kvm_run: exit_reason {} ({})",
+            kvm_run.exit_reason,
+            exit_reason_name(kvm_run.exit_reason)
+        );
 
         let reason_ptr: *const u32 = unsafe { &((*map_ptr).exit_reason) };
         let reason: u32 =
@@ -47,6 +278,14 @@ pub fn inspect(opts: &InspectOptions) -> Result<()> {
         info!("reason = {}", reason);
     }
 
+    for vcpu in &vm.vcpus {
+        match vm.get_nested_state(vcpu) {
+            Ok(Some(nested)) if nested.hypervisor => info!("vcpu {}: {}", vcpu.idx, nested),
+            Ok(_) => {}
+            Err(e) => warn!("cannot get nested state of vcpu {}: {}", vcpu.idx, e),
+        }
+    }
+
     let mem = GuestMem::new(&vm)?;
 
     match find_kernel(&mem, &vm) {
@@ -64,6 +303,17 @@ pub fn inspect(opts: &InspectOptions) -> Result<()> {
                 info!("{:#x} ({}kb, {:?})", m.virt_start, m.len / 1024, m.prot)
             }
             info!("{} found kernel symbols", kernel.symbols.len());
+            match kernel.version(&vm) {
+                Ok(v) => info!("kernel version: {}", v),
+                Err(e) => info!("cannot detect kernel version: {}", e),
+            }
+            if let Some(offset) = kernel.kaslr_offset() {
+                info!("kaslr offset: {:#x}", offset);
+            }
+            match symbols::kernel_symbols(&kernel, opts.symbols.as_deref()) {
+                Ok(syms) => symbols::log_backtraces(&vm, &mem, &kernel, &syms),
+                Err(e) => warn!("cannot load kernel symbols: {}", e),
+            }
         }
         Err(e) => info!("could not find kernel: {}", e),
     }
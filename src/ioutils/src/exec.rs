@@ -0,0 +1,136 @@
+//! Protocol of `vmsh exec` between vmsh and stage2.
+//!
+//! Messages are a kind (u8) and the payload length (u32, little endian) followed by the
+//! payload. The client starts with `Command`, then sends `Stdin` and `StdinEof`. stage2
+//! answers with `Stdout` and `Stderr` and finally `Exit` once the command exited and both
+//! outputs are drained. Commands killed by a signal exit with 128 + the signal number.
+
+use std::io::{self, Read, Write};
+
+const KIND_COMMAND: u8 = 0;
+const KIND_STDIN: u8 = 1;
+const KIND_STDIN_EOF: u8 = 2;
+const KIND_STDOUT: u8 = 3;
+const KIND_STDERR: u8 = 4;
+const KIND_EXIT: u8 = 5;
+const KIND_ERROR: u8 = 6;
+
+/// Largest payload of a message.
+pub const MAX_PAYLOAD: usize = 64 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    /// Program and arguments
+    Command(Vec<String>),
+    Stdin(Vec<u8>),
+    StdinEof,
+    Stdout(Vec<u8>),
+    Stderr(Vec<u8>),
+    Exit(i32),
+    /// The command could not be started
+    Error(String),
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads the next message, None at EOF.
+pub fn read_message(reader: &mut impl Read) -> io::Result<Option<Message>> {
+    let mut header = [0u8; 5];
+    if let Err(e) = reader.read_exact(&mut header) {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            return Ok(None);
+        }
+        return Err(e);
+    }
+    let len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if len > MAX_PAYLOAD {
+        return Err(invalid(format!(
+            "message of {} bytes exceeds maximum size",
+            len
+        )));
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    let msg = match header[0] {
+        KIND_COMMAND => {
+            let args = payload
+                .split(|b| *b == 0)
+                .map(|arg| String::from_utf8(arg.to_vec()))
+                .collect::<Result<Vec<_>, _>>();
+            Message::Command(args.map_err(|_| invalid("command is not valid utf-8".into()))?)
+        }
+        KIND_STDIN => Message::Stdin(payload),
+        KIND_STDIN_EOF => Message::StdinEof,
+        KIND_STDOUT => Message::Stdout(payload),
+        KIND_STDERR => Message::Stderr(payload),
+        KIND_EXIT if len == 4 => Message::Exit(i32::from_le_bytes([
+            payload[0], payload[1], payload[2], payload[3],
+        ])),
+        KIND_ERROR => Message::Error(String::from_utf8_lossy(&payload).into_owned()),
+        kind => {
+            return Err(invalid(format!(
+                "invalid message of kind {} and length {}",
+                kind, len
+            )))
+        }
+    };
+    Ok(Some(msg))
+}
+
+pub fn write_message(writer: &mut impl Write, msg: &Message) -> io::Result<()> {
+    let (kind, payload) = match msg {
+        Message::Command(args) => {
+            if args.iter().any(|arg| arg.contains('\0')) {
+                return Err(invalid("arguments cannot contain null bytes".into()));
+            }
+            (KIND_COMMAND, args.join("\0").into_bytes())
+        }
+        Message::Stdin(data) => (KIND_STDIN, data.clone()),
+        Message::StdinEof => (KIND_STDIN_EOF, vec![]),
+        Message::Stdout(data) => (KIND_STDOUT, data.clone()),
+        Message::Stderr(data) => (KIND_STDERR, data.clone()),
+        Message::Exit(code) => (KIND_EXIT, code.to_le_bytes().to_vec()),
+        Message::Error(msg) => (KIND_ERROR, msg.as_bytes().to_vec()),
+    };
+    if payload.len() > MAX_PAYLOAD {
+        return Err(invalid(format!(
+            "message of {} bytes exceeds maximum size",
+            payload.len()
+        )));
+    }
+    let mut buf = Vec::with_capacity(5 + payload.len());
+    buf.push(kind);
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&payload);
+    writer.write_all(&buf)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_roundtrip() {
+        let msgs = vec![
+            Message::Command(vec!["echo".into(), "a b".into()]),
+            Message::Stdin(b"input".to_vec()),
+            Message::StdinEof,
+            Message::Stdout(b"out".to_vec()),
+            Message::Stderr(vec![]),
+            Message::Exit(-1),
+            Message::Error("not found".into()),
+        ];
+        let mut buf = vec![];
+        for msg in &msgs {
+            write_message(&mut buf, msg).unwrap();
+        }
+        let mut reader = &buf[..];
+        for msg in &msgs {
+            assert_eq!(read_message(&mut reader).unwrap().as_ref(), Some(msg));
+        }
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+    }
+}
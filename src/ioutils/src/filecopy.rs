@@ -0,0 +1,146 @@
+//! File transfer protocol of `vmsh cp`, served by stage2.
+//!
+//! The client sends a single request line, stage2 answers with a response line:
+//!
+//! - `get <path>`: `ok <mode> <size>` followed by `size` bytes of the file
+//! - `put <mode> <size> <path>` followed by `size` bytes: `ok` once the file is written
+//!
+//! Failures are answered with `error <message>`. Modes are octal, paths extend to the end
+//! of the line and therefore cannot contain newlines.
+
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+/// Longest request or response line we accept.
+const MAX_LINE: usize = 4096;
+
+#[derive(Debug, PartialEq)]
+pub enum Request {
+    Get { path: PathBuf },
+    Put { path: PathBuf, mode: u32, size: u64 },
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads up to the next newline. Reads byte-wise, so that the data that follows stays in
+/// `reader`.
+pub fn read_line(reader: &mut impl Read) -> io::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        if reader.read(&mut byte)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed",
+            ));
+        }
+        if byte[0] == b'\n' {
+            break;
+        }
+        if line.len() >= MAX_LINE {
+            return Err(invalid("line too long".into()));
+        }
+        line.push(byte[0]);
+    }
+    String::from_utf8(line).map_err(|_| invalid("line is not valid utf-8".into()))
+}
+
+impl Request {
+    pub fn parse(line: &str) -> io::Result<Request> {
+        let (method, args) = line.split_once(' ').unwrap_or((line, ""));
+        match method {
+            "get" if !args.is_empty() => Ok(Request::Get {
+                path: PathBuf::from(args),
+            }),
+            "put" => {
+                let mut fields = args.splitn(3, ' ');
+                let mode = fields.next().and_then(|m| u32::from_str_radix(m, 8).ok());
+                let size = fields.next().and_then(|s| s.parse().ok());
+                match (mode, size, fields.next()) {
+                    (Some(mode), Some(size), Some(path)) if !path.is_empty() => Ok(Request::Put {
+                        path: PathBuf::from(path),
+                        mode,
+                        size,
+                    }),
+                    _ => Err(invalid(format!("invalid put request: {}", line))),
+                }
+            }
+            _ => Err(invalid(format!("invalid request: {}", line))),
+        }
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        let line = match self {
+            Request::Get { path } => format!("get {}", path.display()),
+            Request::Put { path, mode, size } => {
+                format!("put {:o} {} {}", mode, size, path.display())
+            }
+        };
+        if line.contains('\n') {
+            return Err(invalid("paths cannot contain newlines".into()));
+        }
+        writeln!(writer, "{}", line)?;
+        writer.flush()
+    }
+}
+
+pub fn write_ok(writer: &mut impl Write, fields: &[String]) -> io::Result<()> {
+    let mut line = String::from("ok");
+    for field in fields {
+        line.push(' ');
+        line.push_str(field);
+    }
+    writeln!(writer, "{}", line)?;
+    writer.flush()
+}
+
+pub fn write_error(writer: &mut impl Write, msg: &str) -> io::Result<()> {
+    writeln!(writer, "error {}", msg.replace('\n', " "))?;
+    writer.flush()
+}
+
+/// Reads a response, returns the fields of `ok` or the message of `error` as error.
+pub fn read_response(reader: &mut impl Read) -> io::Result<Vec<String>> {
+    let line = read_line(reader)?;
+    let (status, rest) = line.split_once(' ').unwrap_or((&line, ""));
+    match status {
+        "ok" => Ok(rest.split_whitespace().map(String::from).collect()),
+        "error" => Err(io::Error::other(rest.to_string())),
+        _ => Err(invalid(format!("invalid response: {}", line))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_roundtrip() {
+        let requests = vec![
+            Request::Get {
+                path: PathBuf::from("/etc/os release"),
+            },
+            Request::Put {
+                path: PathBuf::from("/tmp/a b"),
+                mode: 0o644,
+                size: 42,
+            },
+        ];
+        for req in requests {
+            let mut buf = vec![];
+            req.write(&mut buf).unwrap();
+            let line = read_line(&mut &buf[..]).unwrap();
+            assert_eq!(Request::parse(&line).unwrap(), req);
+        }
+    }
+
+    #[test]
+    fn error_response() {
+        let mut buf = vec![];
+        write_error(&mut buf, "no such file").unwrap();
+        let err = read_response(&mut &buf[..]).unwrap_err();
+        assert_eq!(err.to_string(), "no such file");
+    }
+}
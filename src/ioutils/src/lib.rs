@@ -1 +1,5 @@
+pub mod exec;
+pub mod filecopy;
+pub mod mux;
+pub mod seccomp;
 pub mod tmp;
@@ -0,0 +1,218 @@
+//! Multiplexes TCP connections over a single byte stream. vmsh and stage2 use it to forward
+//! ports into the guest over one vsock connection.
+//!
+//! Each frame starts with a header of stream id (u32), kind (u8) and payload length (u32),
+//! all little endian. `Open` carries the guest port (u16) as payload. Streams are opened by
+//! the host; either side closes a stream with `Close` once its socket reached EOF or failed.
+//! There is no flow control per stream: a peer that does not read stalls all streams.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+const KIND_OPEN: u8 = 0;
+const KIND_DATA: u8 = 1;
+const KIND_CLOSE: u8 = 2;
+
+const HEADER_SIZE: usize = 9;
+
+/// Largest payload of a data frame.
+pub const MAX_PAYLOAD: usize = 16 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    Open { id: u32, port: u16 },
+    Data { id: u32, data: Vec<u8> },
+    Close { id: u32 },
+}
+
+/// Reads the next frame, None at EOF.
+pub fn read_frame(reader: &mut impl Read) -> io::Result<Option<Frame>> {
+    let mut header = [0u8; HEADER_SIZE];
+    if let Err(e) = reader.read_exact(&mut header) {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            return Ok(None);
+        }
+        return Err(e);
+    }
+    let mut id = [0u8; 4];
+    id.copy_from_slice(&header[0..4]);
+    let id = u32::from_le_bytes(id);
+    let mut len = [0u8; 4];
+    len.copy_from_slice(&header[5..9]);
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_PAYLOAD {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes exceeds maximum size", len),
+        ));
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    let frame = match header[4] {
+        KIND_OPEN if len == 2 => Frame::Open {
+            id,
+            port: u16::from_le_bytes([payload[0], payload[1]]),
+        },
+        KIND_DATA => Frame::Data { id, data: payload },
+        KIND_CLOSE => Frame::Close { id },
+        kind => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid frame of kind {} and length {}", kind, len),
+            ))
+        }
+    };
+    Ok(Some(frame))
+}
+
+pub fn write_frame(writer: &mut impl Write, frame: &Frame) -> io::Result<()> {
+    let (id, kind, payload) = match frame {
+        Frame::Open { id, port } => (*id, KIND_OPEN, &port.to_le_bytes()[..]),
+        Frame::Data { id, data } => (*id, KIND_DATA, &data[..]),
+        Frame::Close { id } => (*id, KIND_CLOSE, &[][..]),
+    };
+    let mut buf = Vec::with_capacity(HEADER_SIZE + payload.len());
+    buf.extend_from_slice(&id.to_le_bytes());
+    buf.push(kind);
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(payload);
+    writer.write_all(&buf)?;
+    writer.flush()
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// One side of a multiplexed connection.
+#[derive(Clone)]
+pub struct Mux {
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+    streams: Arc<Mutex<HashMap<u32, TcpStream>>>,
+}
+
+impl Mux {
+    pub fn new(writer: Box<dyn Write + Send>) -> Mux {
+        Mux {
+            writer: Arc::new(Mutex::new(writer)),
+            streams: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn send(&self, frame: &Frame) -> io::Result<()> {
+        write_frame(&mut *lock(&self.writer), frame)
+    }
+
+    /// Asks the peer to connect stream `id` to `port` and forwards `stream` to it.
+    pub fn open(&self, id: u32, port: u16, stream: TcpStream) -> io::Result<()> {
+        self.send(&Frame::Open { id, port })?;
+        self.add(id, stream)
+    }
+
+    /// Forwards data of `stream` as stream `id` until it reaches EOF.
+    pub fn add(&self, id: u32, stream: TcpStream) -> io::Result<()> {
+        let mut reader = stream.try_clone()?;
+        lock(&self.streams).insert(id, stream);
+        let mux = self.clone();
+        let _ = thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_PAYLOAD];
+            loop {
+                let n = match reader.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => n,
+                };
+                let data = buf[..n].to_vec();
+                if mux.send(&Frame::Data { id, data }).is_err() {
+                    break;
+                }
+            }
+            // the stream is already gone if the peer closed it first
+            if lock(&mux.streams).remove(&id).is_some() {
+                let _ = mux.send(&Frame::Close { id });
+            }
+        });
+        Ok(())
+    }
+
+    /// Dispatches frames of `reader` to the streams until EOF. `connect` is called for
+    /// streams opened by the peer, it returns None if the port cannot be reached.
+    pub fn run(
+        &self,
+        reader: &mut impl Read,
+        mut connect: impl FnMut(u16) -> Option<TcpStream>,
+    ) -> io::Result<()> {
+        let res = self.dispatch(reader, &mut connect);
+        for (_, stream) in lock(&self.streams).drain() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        res
+    }
+
+    fn dispatch(
+        &self,
+        reader: &mut impl Read,
+        connect: &mut impl FnMut(u16) -> Option<TcpStream>,
+    ) -> io::Result<()> {
+        while let Some(frame) = read_frame(reader)? {
+            match frame {
+                Frame::Open { id, port } => match connect(port) {
+                    Some(stream) => self.add(id, stream)?,
+                    None => self.send(&Frame::Close { id })?,
+                },
+                Frame::Data { id, data } => {
+                    let mut streams = lock(&self.streams);
+                    let failed = match streams.get_mut(&id) {
+                        Some(stream) => stream.write_all(&data).is_err(),
+                        // data in flight of a stream we closed
+                        None => false,
+                    };
+                    if failed {
+                        if let Some(stream) = streams.remove(&id) {
+                            let _ = stream.shutdown(Shutdown::Both);
+                        }
+                        drop(streams);
+                        self.send(&Frame::Close { id })?;
+                    }
+                }
+                Frame::Close { id } => {
+                    if let Some(stream) = lock(&self.streams).remove(&id) {
+                        let _ = stream.shutdown(Shutdown::Both);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_roundtrip() {
+        let frames = vec![
+            Frame::Open { id: 1, port: 22 },
+            Frame::Data {
+                id: 1,
+                data: b"hello".to_vec(),
+            },
+            Frame::Close { id: 1 },
+        ];
+        let mut buf = vec![];
+        for frame in &frames {
+            write_frame(&mut buf, frame).unwrap();
+        }
+        let mut reader = &buf[..];
+        for frame in &frames {
+            assert_eq!(read_frame(&mut reader).unwrap().as_ref(), Some(frame));
+        }
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+    }
+}
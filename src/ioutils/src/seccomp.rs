@@ -0,0 +1,103 @@
+//! Seccomp filters compiled by vmsh and passed to stage2 on its command line.
+//!
+//! A filter is a classic BPF program, encoded as one hex string of 8-byte instructions:
+//! code (u16), jt (u8), jf (u8) and k (u32), all little endian.
+
+use std::io;
+
+/// Maximum number of instructions the kernel accepts (`BPF_MAXINSNS`).
+pub const MAX_INSTRUCTIONS: usize = 4096;
+
+pub const BPF_LD_W_ABS: u16 = 0x20;
+pub const BPF_JMP_JEQ_K: u16 = 0x15;
+pub const BPF_ALU_AND_K: u16 = 0x54;
+pub const BPF_RET_K: u16 = 0x06;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Instruction {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+impl Instruction {
+    pub fn stmt(code: u16, k: u32) -> Instruction {
+        Instruction {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    pub fn jump(code: u16, k: u32, jt: u8, jf: u8) -> Instruction {
+        Instruction { code, jt, jf, k }
+    }
+}
+
+pub fn encode(program: &[Instruction]) -> String {
+    let mut s = String::with_capacity(program.len() * 16);
+    for ins in program {
+        let mut bytes = Vec::with_capacity(8);
+        bytes.extend_from_slice(&ins.code.to_le_bytes());
+        bytes.push(ins.jt);
+        bytes.push(ins.jf);
+        bytes.extend_from_slice(&ins.k.to_le_bytes());
+        for b in bytes {
+            s.push_str(&format!("{:02x}", b));
+        }
+    }
+    s
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub fn decode(s: &str) -> io::Result<Vec<Instruction>> {
+    if s.is_empty() || !s.len().is_multiple_of(16) {
+        return Err(invalid(format!("invalid filter length {}", s.len())));
+    }
+    if s.len() / 16 > MAX_INSTRUCTIONS {
+        return Err(invalid(format!(
+            "filter has more than {} instructions",
+            MAX_INSTRUCTIONS
+        )));
+    }
+    let bytes = (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .ok_or_else(|| invalid("filter is not valid hex".into()))
+        })
+        .collect::<io::Result<Vec<u8>>>()?;
+    Ok(bytes
+        .chunks_exact(8)
+        .map(|b| Instruction {
+            code: u16::from_le_bytes([b[0], b[1]]),
+            jt: b[2],
+            jf: b[3],
+            k: u32::from_le_bytes([b[4], b[5], b[6], b[7]]),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn program_roundtrip() {
+        let program = vec![
+            Instruction::stmt(BPF_LD_W_ABS, 0),
+            Instruction::jump(BPF_JMP_JEQ_K, 59, 0, 1),
+            Instruction::stmt(BPF_RET_K, 0x0005_0001),
+            Instruction::stmt(BPF_RET_K, 0x7fff_0000),
+        ];
+        assert_eq!(decode(&encode(&program)).unwrap(), program);
+        assert!(decode("0").is_err());
+        assert!(decode("zz00000000000000").is_err());
+    }
+}
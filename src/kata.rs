@@ -0,0 +1,161 @@
+//! Finds the VM of a Kata container. Kata runs every pod in its own VM and keeps the state of
+//! each sandbox in `/run/vc/sbs/<sandbox-id>/persist.json`, the containers of the sandbox have
+//! their own `persist.json` in a subdirectory named after the container id.
+
+use log::info;
+use nix::unistd::Pid;
+use serde::Deserialize;
+use simple_error::{bail, try_with};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::kvm::hypervisor::flavor::Flavor;
+use crate::result::Result;
+
+pub const KATA_STATE_DIR: &str = "/run/vc/sbs";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SandboxState {
+    hypervisor_state: HypervisorState,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HypervisorState {
+    pid: i32,
+    #[serde(rename = "Type")]
+    kind: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerState {
+    #[serde(default)]
+    bundle_path: String,
+}
+
+/// The VM a Kata container runs in.
+pub struct Sandbox {
+    pub id: String,
+    /// Process of the hypervisor
+    pub pid: Pid,
+    pub hypervisor: Flavor,
+    /// Root filesystem of the container on the host, if it is a directory.
+    pub rootfs: Option<PathBuf>,
+}
+
+fn read_state<T: for<'a> Deserialize<'a>>(path: &Path) -> Result<T> {
+    let content = try_with!(fs::read_to_string(path), "cannot read {}", path.display());
+    Ok(try_with!(
+        serde_json::from_str(&content),
+        "cannot parse {}",
+        path.display()
+    ))
+}
+
+/// Maps the hypervisor names of the Kata configuration to the ones vmsh knows.
+fn hypervisor_flavor(kind: &str) -> Result<Flavor> {
+    match kind {
+        "qemu" => Ok(Flavor::Qemu),
+        "clh" => Ok(Flavor::CloudHypervisor),
+        "firecracker" => Ok(Flavor::Firecracker),
+        // dragonball runs inside the shim and has no process of its own
+        _ => bail!("unsupported kata hypervisor: {}", kind),
+    }
+}
+
+/// Returns the sandbox and the full id of the container `container_id` is a unique prefix of.
+fn find_container(state_dir: &Path, container_id: &str) -> Result<(PathBuf, String)> {
+    let sandboxes = try_with!(
+        fs::read_dir(state_dir),
+        "cannot read {}, is kata running?",
+        state_dir.display()
+    );
+    let mut found = vec![];
+    for sandbox in sandboxes {
+        let sandbox = try_with!(sandbox, "cannot read {}", state_dir.display()).path();
+        let containers = match fs::read_dir(&sandbox) {
+            Ok(containers) => containers,
+            Err(_) => continue,
+        };
+        for container in containers.flatten() {
+            let name = container.file_name().to_string_lossy().into_owned();
+            if name.starts_with(container_id) && container.path().is_dir() {
+                found.push((sandbox.clone(), name));
+            }
+        }
+    }
+    match found.len() {
+        0 => bail!("no kata container {} found", container_id),
+        1 => Ok(found.remove(0)),
+        _ => bail!("kata container id {} is ambiguous", container_id),
+    }
+}
+
+fn lookup_in(state_dir: &Path, container_id: &str) -> Result<Sandbox> {
+    let (sandbox_dir, container) = find_container(state_dir, container_id)?;
+    let sandbox: SandboxState = read_state(&sandbox_dir.join("persist.json"))?;
+    let state: ContainerState = read_state(&sandbox_dir.join(&container).join("persist.json"))?;
+    let rootfs = Path::new(&state.bundle_path).join("rootfs");
+    let id = sandbox_dir
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if sandbox.hypervisor_state.pid <= 0 {
+        bail!("kata sandbox {} has no running hypervisor", id);
+    }
+    Ok(Sandbox {
+        id,
+        pid: Pid::from_raw(sandbox.hypervisor_state.pid),
+        hypervisor: hypervisor_flavor(&sandbox.hypervisor_state.kind)?,
+        rootfs: if !state.bundle_path.is_empty() && rootfs.is_dir() {
+            Some(rootfs)
+        } else {
+            None
+        },
+    })
+}
+
+/// Finds the sandbox VM of the Kata container `container_id` (or a unique prefix of it).
+pub fn lookup(container_id: &str) -> Result<Sandbox> {
+    let sandbox = lookup_in(Path::new(KATA_STATE_DIR), container_id)?;
+    info!(
+        "kata container {} runs in sandbox {} ({:?} process {})",
+        container_id, sandbox.id, sandbox.hypervisor, sandbox.pid
+    );
+    Ok(sandbox)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_sandbox() {
+        let dir = std::env::temp_dir().join(format!("vmsh-kata-{}", std::process::id()));
+        let sandbox = dir.join("sbs").join("f00d");
+        let bundle = dir.join("bundle");
+        fs::create_dir_all(sandbox.join("c0ffee")).unwrap();
+        fs::create_dir_all(bundle.join("rootfs")).unwrap();
+        fs::write(
+            sandbox.join("persist.json"),
+            r#"{"State": {"State": "running"}, "HypervisorState": {"Pid": 4242, "Type": "clh"}}"#,
+        )
+        .unwrap();
+        fs::write(
+            sandbox.join("c0ffee").join("persist.json"),
+            format!(r#"{{"BundlePath": "{}"}}"#, bundle.display()),
+        )
+        .unwrap();
+
+        let found = lookup_in(&dir.join("sbs"), "c0f").unwrap();
+        assert_eq!(found.id, "f00d");
+        assert_eq!(found.pid, Pid::from_raw(4242));
+        assert_eq!(found.hypervisor, Flavor::CloudHypervisor);
+        assert_eq!(found.rootfs, Some(bundle.join("rootfs")));
+        assert!(lookup_in(&dir.join("sbs"), "beef").is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,19 +1,141 @@
 use log::{debug, info};
 use nix::sys::mman::ProtFlags;
-use simple_error::{require_with, try_with, SimpleError};
+use simple_error::{bail, require_with, try_with, SimpleError};
+use stage1_interface::KernelVersion;
 use std::collections::HashMap;
 use std::ffi::CStr;
 use std::mem::{self, size_of};
 use std::ops::Range;
 use vm_memory::remote_mem::process_read_bytes;
 
-use crate::guest_mem::{GuestMem, MappedMemory};
+use crate::btf;
+use crate::dmesg;
+use crate::guest_mem::{GuestMem, KernelMem, MappedMemory};
 use crate::kvm::hypervisor::Hypervisor;
 use crate::result::Result;
 
 /// Kernel range on x86_64
 pub const LINUX_KERNEL_KASLR_RANGE: Range<usize> = 0xFFFFFFFF80000000..0xFFFFFFFFC0000000;
 
+/// Address of `_text` if the kernel is not relocated (`__START_KERNEL` with the default
+/// CONFIG_PHYSICAL_START)
+pub const LINUX_KERNEL_DEFAULT_TEXT: usize = 0xFFFFFFFF81000000;
+
+/// Length of each field in `struct new_utsname`
+const UTSNAME_FIELD_LEN: usize = 65;
+
+/// Start of `linux_banner`, i.e. `Linux version 6.1.0-13-amd64 (debian-kernel@...) ...`
+const LINUX_BANNER: &[u8] = b"Linux version ";
+
+/// Words `VERMAGIC_STRING` consists of after the release, see include/linux/vermagic.h.
+/// Kernels with CONFIG_RANDSTRUCT add `RANDSTRUCT_<seed>`.
+const VERMAGIC_WORDS: &[&str] = &["SMP", "preempt", "preempt_rt", "mod_unload", "modversions"];
+
+/// Longest vermagic after the release we look for
+const VERMAGIC_MAX: usize = 256;
+
+/// Logged once the kernel is locked down, i.e.
+/// `Kernel is locked down from EFI Secure Boot mode; see man kernel_lockdown.7`
+const LOCKDOWN_MESSAGE: &str = "Kernel is locked down from ";
+
+/// Logged by x86 kernels booted with UEFI Secure Boot
+const SECURE_BOOT_MESSAGE: &str = "Secure boot enabled";
+
+/// Lockdown state of the kernel, see kernel_lockdown(7)
+#[derive(Debug, Default, PartialEq)]
+pub struct Lockdown {
+    /// What locked the kernel down, i.e. `EFI Secure Boot mode` or `command line`. None if
+    /// it is not locked down.
+    pub locked_down_by: Option<String>,
+    pub secure_boot: bool,
+}
+
+impl Lockdown {
+    /// Reads the state from the messages in the kernel log.
+    pub fn from_log<'a>(messages: impl IntoIterator<Item = &'a str>) -> Lockdown {
+        let mut lockdown = Lockdown::default();
+        for msg in messages {
+            if let Some(pos) = msg.find(LOCKDOWN_MESSAGE) {
+                let by = &msg[pos + LOCKDOWN_MESSAGE.len()..];
+                let by = by.split(';').next().unwrap_or(by);
+                lockdown.locked_down_by = Some(by.to_string());
+            }
+            if msg.contains(SECURE_BOOT_MESSAGE) {
+                lockdown.secure_boot = true;
+            }
+        }
+        lockdown
+    }
+}
+
+/// Parses the version at the start of a release like `5.10.0-21-amd64`, a missing patch level
+/// is 0.
+pub fn parse_release(release: &str) -> Option<KernelVersion> {
+    let mut parts = release.split(|c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    let patch = parts.next().and_then(|p| p.parse().ok()).unwrap_or(0);
+    Some(KernelVersion {
+        major,
+        minor,
+        patch,
+    })
+}
+
+/// Release in the first `linux_banner` in `mem`. Format strings that contain the same prefix
+/// are skipped, the release has to start with a digit.
+fn banner_release(mem: &[u8]) -> Option<String> {
+    let mut pos = 0;
+    while let Some(found) = find_subsequence(&mem[pos..], LINUX_BANNER) {
+        pos += found + LINUX_BANNER.len();
+        let rest = &mem[pos..];
+        let len = match rest.iter().position(|c| *c == b' ') {
+            Some(len) => len,
+            None => continue,
+        };
+        let release = &rest[..len];
+        if release.first().map_or(false, u8::is_ascii_digit)
+            && len < UTSNAME_FIELD_LEN
+            && release.iter().all(u8::is_ascii_graphic)
+        {
+            return Some(String::from_utf8_lossy(release).into_owned());
+        }
+    }
+    None
+}
+
+/// `VERMAGIC_STRING` of the kernel with `release` in `mem`, i.e.
+/// `6.1.0-13-amd64 SMP preempt mod_unload modversions `. The release in other strings like
+/// `linux_banner` is skipped, the vermagic is a string of its own.
+fn find_vermagic(mem: &[u8], release: &str) -> Option<String> {
+    let prefix = format!("{} ", release);
+    let mut pos = 0;
+    while let Some(found) = find_subsequence(&mem[pos..], prefix.as_bytes()) {
+        let start = pos + found;
+        pos = start + 1;
+        if start > 0 && mem[start - 1] != 0 {
+            continue;
+        }
+        let rest = &mem[start + prefix.len()..];
+        let len = match rest.iter().take(VERMAGIC_MAX).position(|c| *c == 0) {
+            Some(len) => len,
+            None => continue,
+        };
+        let words = match std::str::from_utf8(&rest[..len]) {
+            Ok(words) => words,
+            Err(_) => continue,
+        };
+        let known = words
+            .split(' ')
+            .filter(|w| !w.is_empty())
+            .all(|w| VERMAGIC_WORDS.contains(&w) || w.starts_with("RANDSTRUCT_"));
+        if known {
+            return Some(format!("{}{}", prefix, words));
+        }
+    }
+    None
+}
+
 fn find_subsequence(haystack: &[u8], needle: &[u8]) -> Option<usize> {
     haystack
         .windows(needle.len())
@@ -299,6 +421,134 @@ impl Kernel {
     pub fn space_after(&self) -> usize {
         LINUX_KERNEL_KASLR_RANGE.end - self.range.end
     }
+
+    /// How far the kernel was moved by KASLR. Assumes the kernel text starts at the first
+    /// mapped section, which holds as long as the kernel was not linked to another address.
+    pub fn kaslr_offset(&self) -> Option<usize> {
+        self.range.start.checked_sub(LINUX_KERNEL_DEFAULT_TEXT)
+    }
+
+    /// Release of the kernel as in `uname -r`. It is read from `init_uts_ns`, or from
+    /// `linux_banner` in kernels that do not export that.
+    pub fn version(&self, hv: &Hypervisor) -> Result<String> {
+        self.uts_release(hv).or_else(|e| {
+            debug!("{}, search for linux_banner", e);
+            self.find_banner(hv)
+        })
+    }
+
+    /// Reads the kernel image in chunks until `find` returns a value for one of them. `find`
+    /// gets each chunk together with its virtual address. Chunks overlap by `overlap` bytes, so
+    /// that what starts at the end of one chunk is found in the next one.
+    fn scan<T>(
+        &self,
+        hv: &Hypervisor,
+        overlap: usize,
+        mut find: impl FnMut(&[u8], usize) -> Option<T>,
+    ) -> Result<Option<T>> {
+        const CHUNK_SIZE: usize = 1 << 20;
+        let mut mem = vec![0; CHUNK_SIZE + overlap];
+        for section in &self.memory_sections {
+            for offset in (0..section.len).step_by(CHUNK_SIZE) {
+                let len = mem.len().min(section.len - offset);
+                let host_addr = section.phys_start.host_addr() + offset;
+                try_with!(
+                    process_read_bytes(hv.pid, &mut mem[..len], host_addr as *const libc::c_void),
+                    "cannot read kernel memory at {:#x}",
+                    section.virt_start + offset
+                );
+                if let Some(found) = find(&mem[..len], section.virt_start + offset) {
+                    return Ok(Some(found));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Searches the kernel image for `linux_banner`, which is not exported.
+    fn find_banner(&self, hv: &Hypervisor) -> Result<String> {
+        let overlap = LINUX_BANNER.len() + UTSNAME_FIELD_LEN;
+        let release = self.scan(hv, overlap, |mem, _| banner_release(mem))?;
+        Ok(require_with!(
+            release,
+            "cannot find linux_banner in the kernel"
+        ))
+    }
+
+    /// `VERMAGIC_STRING` the module loader compares with the one of each module. It is not
+    /// exported, so the image is searched for it.
+    pub fn vermagic(&self, hv: &Hypervisor) -> Result<String> {
+        let release = self.version(hv)?;
+        let overlap = release.len() + 1 + VERMAGIC_MAX;
+        let found = self.scan(hv, overlap, |mem, _| find_vermagic(mem, &release))?;
+        Ok(require_with!(
+            found,
+            "cannot find the vermagic of the kernel"
+        ))
+    }
+
+    /// Reads the BTF type information of kernels built with CONFIG_DEBUG_INFO_BTF. Its `.BTF`
+    /// section has no symbol, so the image is searched for the header.
+    pub fn btf(&self, hv: &Hypervisor) -> Result<Vec<u8>> {
+        let found = self.scan(hv, btf::HEADER_LEN, |mem, virt_start| {
+            // the section is at least 4 byte aligned
+            (0..mem.len().saturating_sub(btf::HEADER_LEN))
+                .step_by(4)
+                .find_map(|i| btf::data_len(&mem[i..]).map(|len| (virt_start + i, len)))
+        })?;
+        let (addr, len) = require_with!(found, "kernel has no BTF type information");
+        debug!("found BTF at {:#x} ({} bytes)", addr, len);
+        let mem = KernelMem {
+            hv,
+            mappings: self.memory_sections.clone(),
+        };
+        let mut data = vec![0; len];
+        mem.read(addr, &mut data)?;
+        Ok(data)
+    }
+
+    /// Whether the kernel is locked down. The state is not exported, so it is read from the
+    /// kernel log. Fails if the log cannot be found, i.e. without CONFIG_CRASH_CORE.
+    pub fn lockdown(&self, mem: &KernelMem) -> Result<Lockdown> {
+        let release = self.version(mem.hv).ok();
+        let records = dmesg::read_log(mem, release.as_deref())?;
+        Ok(Lockdown::from_log(records.iter().map(|r| r.text.as_str())))
+    }
+
+    fn uts_release(&self, hv: &Hypervisor) -> Result<String> {
+        let addr = *require_with!(
+            self.symbols.get("init_uts_ns"),
+            "kernel does not export init_uts_ns"
+        );
+        let section = require_with!(
+            self.memory_sections
+                .iter()
+                .find(|s| s.virt_start <= addr && addr < s.virt_start + s.len),
+            "init_uts_ns at {:#x} is not mapped",
+            addr
+        );
+        // older kernels have a `struct kref` in front of the utsname
+        let mut mem = vec![0; 8 + 6 * UTSNAME_FIELD_LEN];
+        let offset = addr - section.virt_start;
+        if offset + mem.len() > section.len {
+            bail!("init_uts_ns at {:#x} exceeds its section", addr);
+        }
+        let host_addr = section.phys_start.host_addr() + offset;
+        try_with!(
+            process_read_bytes(hv.pid, &mut mem, host_addr as *const libc::c_void),
+            "cannot read init_uts_ns"
+        );
+        let sysname = require_with!(
+            find_subsequence(&mem[..16], b"Linux\0"),
+            "no utsname found in init_uts_ns"
+        );
+        let release = &mem[sysname + 2 * UTSNAME_FIELD_LEN..sysname + 3 * UTSNAME_FIELD_LEN];
+        let len = require_with!(
+            release.iter().position(|c| *c == 0),
+            "kernel release is not terminated"
+        );
+        Ok(String::from_utf8_lossy(&release[..len]).into_owned())
+    }
 }
 
 pub fn find_kernel(guest_mem: &GuestMem, hv: &Hypervisor) -> Result<Kernel> {
@@ -354,3 +604,62 @@ pub fn find_kernel(guest_mem: &GuestMem, hv: &Hypervisor) -> Result<Kernel> {
         largest_gap,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn release() {
+        let mem = b"\0Linux version %s (%s)\0\
+                    Linux version 6.1.0-13-amd64 (debian-kernel@lists.debian.org) #1 SMP\0";
+        let release = banner_release(mem);
+        assert_eq!(release.as_deref(), Some("6.1.0-13-amd64"));
+        assert_eq!(
+            release.as_deref().and_then(parse_release),
+            Some(KernelVersion {
+                major: 6,
+                minor: 1,
+                patch: 0
+            })
+        );
+        assert_eq!(banner_release(b"Linux version 6.1"), None);
+        assert_eq!(parse_release("5.10-rc1").map(|v| v.patch), Some(0));
+    }
+
+    #[test]
+    fn vermagic() {
+        let mem = b"\0Linux version 6.1.0-13-amd64 (debian-kernel@lists.debian.org) #1 SMP\0\
+                    6.1.0-13-amd64 SMP preempt mod_unload modversions \0";
+        assert_eq!(
+            find_vermagic(mem, "6.1.0-13-amd64").as_deref(),
+            Some("6.1.0-13-amd64 SMP preempt mod_unload modversions ")
+        );
+        let mem = b"\x006.1.0 SMP mod_unload RANDSTRUCT_5e2f\0";
+        assert_eq!(
+            find_vermagic(mem, "6.1.0").as_deref(),
+            Some("6.1.0 SMP mod_unload RANDSTRUCT_5e2f")
+        );
+        assert_eq!(find_vermagic(b"\x006.1.0 (gcc 12.2)\0", "6.1.0"), None);
+    }
+
+    #[test]
+    fn lockdown() {
+        let log = [
+            "Linux version 6.1.0-13-amd64 (debian-kernel@lists.debian.org) #1 SMP",
+            "secureboot: Secure boot enabled",
+            "Kernel is locked down from EFI Secure Boot mode; see man kernel_lockdown.7",
+        ];
+        assert_eq!(
+            Lockdown::from_log(log.iter().copied()),
+            Lockdown {
+                locked_down_by: Some("EFI Secure Boot mode".into()),
+                secure_boot: true,
+            }
+        );
+        assert_eq!(
+            Lockdown::from_log(log[..1].iter().copied()),
+            Lockdown::default()
+        );
+    }
+}
@@ -1,8 +1,9 @@
+use std::fs::File;
 use std::sync::Arc;
 
 use crate::{
     guest_mem::{GuestMem, MappedMemory},
-    page_table::{estimate_page_table_size, VirtMem},
+    page_table::{estimate_page_table_size, VirtMem, PAGE_LEVEL},
 };
 use log::debug;
 use nix::sys::mman::ProtFlags;
@@ -12,14 +13,15 @@ use vm_device::bus::{MmioAddress, MmioRange};
 use crate::{page_math, result::Result};
 
 use super::hypervisor::{memory::PhysMem, Hypervisor};
+use super::memory_map::MemoryMap;
 
 pub struct PhysMemAllocator {
     pub hv: Arc<Hypervisor>,
     /// Physical guest memory
     pub guest_mem: GuestMem,
-    /// Physical address where we last allocated memory from.
-    /// After an allocating we substract the allocation size from this value.
-    next_allocation: usize,
+    /// Guest physical address space used by the hypervisor and our allocations.
+    /// We allocate from the top of the address space downwards.
+    memory_map: MemoryMap,
 }
 
 const EXTEND_CPU_INFO_FUNCTION: u32 = 0x80000001;
@@ -79,8 +81,8 @@ fn get_first_allocation(hv: &Arc<Hypervisor>) -> Result<usize> {
         "vm/cpu: phys_bits: {}, virt_bits: {}",
         vm_phys_bits, vm_virt_bits
     );
-    // we only implement 4-Level paging for now
-    if vm_virt_bits != 48 {
+    // 4-level paging or 5-level paging (LA57)
+    if vm_virt_bits != 48 && vm_virt_bits != 57 {
         bail!(
             "VM cpu uses {} bits for virtual addresses. This is unsupported at the moment",
             vm_virt_bits
@@ -105,6 +107,14 @@ pub struct VirtAlloc {
     pub prot: ProtFlags,
 }
 
+/// Guest-physical memory for virtual allocations that is not mapped into the guest page tables
+/// yet, so the guest cannot access it. See `PhysMemAllocator::virt_reserve`.
+pub struct UnmappedMem {
+    phys_mem: PhysMem<u8>,
+    pub mappings: Vec<MappedMemory>,
+    huge_pages: bool,
+}
+
 impl VirtAlloc {
     pub fn virt_end(&self) -> usize {
         self.virt_start + self.len
@@ -113,52 +123,83 @@ impl VirtAlloc {
 
 impl PhysMemAllocator {
     pub fn new(hv: Arc<Hypervisor>) -> Result<Self> {
-        let next_allocation = get_first_allocation(&hv)?;
+        let limit = get_first_allocation(&hv)?;
         let guest_mem = GuestMem::new(&hv)?;
+        let mut memory_map = MemoryMap::new(limit);
+        for range in guest_mem.memslot_ranges() {
+            memory_map.mark_used(range);
+        }
         Ok(Self {
             hv,
             guest_mem,
-            next_allocation,
-            //next_allocation: 0xd0000000 + 0x1000 * 2,
+            memory_map,
         })
     }
 
-    fn next_addr(&mut self, size: usize) -> Result<usize> {
-        let start = require_with!(self.next_allocation.checked_sub(size), "out of memory");
-        let last_range = require_with!(
-            self.guest_mem.last_memslot_range(),
-            "vm has no memory assigned"
+    fn next_addr(&mut self, size: usize, align: usize) -> Result<usize> {
+        let range = require_with!(
+            self.memory_map.reserve(size, align),
+            "cannot allocate {:#x} bytes of guest physical memory, no free range left. \
+             This might happen if the last vmsh run did not clean up memory correctly",
+            size
         );
-        let last_alloc = last_range.end;
-        if start < last_alloc {
-            bail!(
-                "cannot allocate memory at {:x}, our allocator conflicts with mapping at {:x}-{:x}. \
-                   This might happen if the last vmsh run did not clean up memory \
-                   correctly or the hypervisor has allocated memory at the very end of \
-                   the physical address space",
-                start, last_range.start, last_range.end
-            );
-        }
-        self.next_allocation = start;
-
-        Ok(start)
+        debug!(
+            "reserve guest physical memory {:#x}-{:#x}",
+            range.start, range.end
+        );
+        Ok(range.start)
     }
 
     pub fn phys_alloc(&mut self, size: usize, readonly: bool) -> Result<PhysMem<u8>> {
-        let old_start = self.next_allocation;
+        self.phys_alloc_aligned(size, 1, readonly)
+    }
+
+    /// Like `phys_alloc` but the start address is a multiple of `align` (a power of two).
+    pub fn phys_alloc_aligned(
+        &mut self,
+        size: usize,
+        align: usize,
+        readonly: bool,
+    ) -> Result<PhysMem<u8>> {
         let padded_size = page_math::page_align(size);
-        let start = self.next_addr(padded_size)?;
-        let res = self.hv.vm_add_mem(start as u64, padded_size, readonly);
-        if res.is_err() {
-            self.next_allocation = old_start;
-        }
-        res
+        let start = self.next_addr(padded_size, align)?;
+        // On failure the range stays reserved, it might collide with a memslot the
+        // hypervisor added after we attached.
+        self.hv.vm_add_mem(start as u64, padded_size, readonly)
     }
-    pub fn virt_alloc(&mut self, alloc: &[VirtAlloc]) -> Result<VirtMem> {
-        let len = alloc.iter().map(|a| a.len).sum();
-        let phys_mem = self.phys_alloc(len + estimate_page_table_size(len), false)?;
 
-        let mut next_addr = phys_mem.guest_phys_addr.clone();
+    /// Maps `file` read-only into the guest physical address space, see
+    /// `Hypervisor::vm_add_file`. The start address is a multiple of `align`.
+    pub fn phys_alloc_file(&mut self, file: &File, align: usize) -> Result<PhysMem<u8>> {
+        let size = try_with!(file.metadata(), "cannot get file size").len() as usize;
+        let start = self.next_addr(page_math::page_align(size), align)?;
+        self.hv.vm_add_file(start as u64, file)
+    }
+
+    /// Allocates guest-physical memory for `alloc`, including space for the page tables
+    /// needed to map it with `map`.
+    pub fn virt_reserve(&mut self, alloc: &[VirtAlloc]) -> Result<UnmappedMem> {
+        let len = alloc.iter().map(|a| a.len).sum();
+        let huge_page = page_math::huge_page_size(PAGE_LEVEL - 1);
+        // For large allocations, place the memory at the same offset within a 2M page as
+        // the virtual address, so that it can be mapped with 2M pages and we need fewer
+        // page tables.
+        let huge_pages = len >= huge_page;
+        let offset = match alloc.first() {
+            Some(a) if huge_pages => a.virt_start % huge_page,
+            _ => 0,
+        };
+        let phys_mem = if huge_pages {
+            self.phys_alloc_aligned(
+                offset + len + estimate_page_table_size(len),
+                huge_page,
+                false,
+            )?
+        } else {
+            self.phys_alloc(len + estimate_page_table_size(len), false)?
+        };
+
+        let mut next_addr = phys_mem.guest_phys_addr.add(offset);
 
         let mapped_mem = alloc
             .iter()
@@ -174,12 +215,33 @@ impl PhysMemAllocator {
             })
             .collect::<Vec<MappedMemory>>();
 
+        Ok(UnmappedMem {
+            phys_mem,
+            mappings: mapped_mem,
+            huge_pages,
+        })
+    }
+
+    /// Maps memory of `virt_reserve` into the guest page tables. Needs a stopped VM: the
+    /// page tables are read and written back as a whole.
+    pub fn map(&mut self, mem: UnmappedMem) -> Result<VirtMem> {
         self.guest_mem
-            .map_memory(self.hv.clone(), phys_mem, &mapped_mem)
+            .map_memory(self.hv.clone(), mem.phys_mem, &mem.mappings, mem.huge_pages)
+    }
+
+    pub fn virt_alloc(&mut self, alloc: &[VirtAlloc]) -> Result<VirtMem> {
+        let mem = self.virt_reserve(alloc)?;
+        self.map(mem)
     }
 
     pub fn alloc_mmio_range(&mut self, size: usize) -> Result<MmioRange> {
-        let start = self.next_addr(size)?;
+        self.alloc_aligned_mmio_range(size, 1)
+    }
+
+    /// Like `alloc_mmio_range` but the start address is a multiple of `align` (a power of two),
+    /// as required for PCI BARs.
+    pub fn alloc_aligned_mmio_range(&mut self, size: usize, align: usize) -> Result<MmioRange> {
+        let start = self.next_addr(size, align)?;
         Ok(try_with!(
             MmioRange::new(MmioAddress(start as u64), size as u64),
             "failed to allocate mmio range"
@@ -0,0 +1,124 @@
+//! Tracks which guest physical pages the guest writes to, using the dirty log of KVM.
+
+use kvm_bindings as kvmb;
+use log::warn;
+use simple_error::{bail, try_with};
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::kvm::hypervisor::Hypervisor;
+use crate::page_math::page_size;
+use crate::result::Result;
+use crate::tracer::proc::Mapping;
+
+/// Enables dirty logging for a set of memslots and disables it again when dropped.
+pub struct DirtyTracker {
+    hv: Arc<Hypervisor>,
+    slots: Vec<Mapping>,
+}
+
+impl DirtyTracker {
+    /// Starts tracking writes to all memslots overlapping with `ranges` of guest physical
+    /// memory.
+    pub fn new(hv: Arc<Hypervisor>, ranges: &[Range<usize>]) -> Result<DirtyTracker> {
+        let maps = try_with!(hv.get_maps(), "cannot get memslots");
+        let mut tracker = DirtyTracker { hv, slots: vec![] };
+        for slot in maps {
+            if !ranges
+                .iter()
+                .any(|r| slot.phys_addr < r.end && r.start < slot.phys_end())
+            {
+                continue;
+            }
+            // Reading the dirty log resets it, the hypervisor would miss pages it needs,
+            // i.e. for live migration or to redraw the framebuffer.
+            if slot.slot_flags & kvmb::KVM_MEM_LOG_DIRTY_PAGES != 0 {
+                bail!(
+                    "the hypervisor already tracks dirty pages of memslot {} ({:#x}-{:#x})",
+                    slot.slot,
+                    slot.phys_addr,
+                    slot.phys_end()
+                );
+            }
+            try_with!(
+                tracker
+                    .hv
+                    .set_memslot_flags(&slot, slot.slot_flags | kvmb::KVM_MEM_LOG_DIRTY_PAGES),
+                "cannot enable dirty logging for memslot {}",
+                slot.slot
+            );
+            tracker.slots.push(slot);
+        }
+        if tracker.slots.is_empty() {
+            bail!("no memslot found for the given guest physical memory");
+        }
+        Ok(tracker)
+    }
+
+    /// Returns the guest physical memory written since the last call or since tracking
+    /// started, as sorted ranges of pages.
+    pub fn dirty_ranges(&self) -> Result<Vec<Range<usize>>> {
+        let mut ranges = vec![];
+        for slot in &self.slots {
+            let bitmap = try_with!(
+                self.hv.dirty_log(slot),
+                "cannot get dirty log of memslot {}",
+                slot.slot
+            );
+            ranges.extend(bitmap_ranges(&bitmap, slot.phys_addr));
+        }
+        ranges.sort_by_key(|r| r.start);
+        Ok(ranges)
+    }
+}
+
+impl Drop for DirtyTracker {
+    fn drop(&mut self) {
+        for slot in &self.slots {
+            if let Err(e) = self.hv.set_memslot_flags(slot, slot.slot_flags) {
+                warn!(
+                    "cannot disable dirty logging for memslot {}: {}",
+                    slot.slot, e
+                );
+            }
+        }
+    }
+}
+
+/// Converts a dirty bitmap of a memslot at `phys_start` to ranges of physical memory.
+fn bitmap_ranges(bitmap: &[u64], phys_start: usize) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = vec![];
+    for (i, word) in bitmap.iter().enumerate() {
+        let mut word = *word;
+        while word != 0 {
+            let bit = word.trailing_zeros() as usize;
+            word &= word - 1;
+            let start = phys_start + (i * 64 + bit) * page_size();
+            match ranges.last_mut() {
+                Some(last) if last.end == start => last.end += page_size(),
+                _ => ranges.push(start..start + page_size()),
+            }
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dirty_bitmap() {
+        let p = page_size();
+        let base = 0x1_0000_0000;
+        assert!(bitmap_ranges(&[0, 0], base).is_empty());
+        assert_eq!(
+            bitmap_ranges(&[0b1101, 1 << 63, 1], base),
+            vec![
+                base..base + p,
+                base + 2 * p..base + 4 * p,
+                base + 127 * p..base + 129 * p,
+            ]
+        );
+    }
+}
@@ -1,8 +1,11 @@
 use log::warn;
 use nix::errno::Errno;
+use nix::sched::{setns, CloneFlags};
 use nix::sys::socket::UnixAddr;
 use nix::sys::socket::*;
+use nix::unistd::{getpid, Pid};
 use simple_error::{bail, try_with};
+use std::fs::File;
 use std::io::{IoSlice, IoSliceMut};
 use std::mem::{size_of, MaybeUninit};
 use std::os::unix::prelude::*;
@@ -12,6 +15,7 @@ use crate::kvm::hypervisor::memory::HvMem;
 use crate::kvm::tracee::{socklen_t, Tracee};
 use crate::result::Result;
 use crate::tracer::inject_syscall;
+use crate::tracer::proc::{self, pid_path};
 
 // inspired by https://github.com/Mic92/cntr/blob/492b2d9e9abc9ccd4f01a0134aab73df16393423/src/ipc.rs
 pub struct Socket {
@@ -26,8 +30,39 @@ impl Drop for Socket {
     }
 }
 
+/// Runs `f` on this thread inside the network namespace of `pid`. Abstract unix sockets are
+/// scoped by network namespace, so we have to bind ours where the hypervisor can see it, e.g.
+/// if the firecracker jailer was started with `--netns`.
+fn in_netns_of<T>(pid: Pid, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    if proc::namespace_inode(pid, "net")? == proc::namespace_inode(getpid(), "net")? {
+        return f();
+    }
+    let own = try_with!(
+        File::open("/proc/thread-self/ns/net"),
+        "cannot open own network namespace"
+    );
+    let path = pid_path(pid).join("ns").join("net");
+    let theirs = try_with!(File::open(&path), "cannot open {}", path.display());
+    try_with!(
+        setns(theirs.as_raw_fd(), CloneFlags::CLONE_NEWNET),
+        "cannot enter network namespace of {}",
+        pid
+    );
+    let res = f();
+    try_with!(
+        setns(own.as_raw_fd(), CloneFlags::CLONE_NEWNET),
+        "cannot return to own network namespace"
+    );
+    res
+}
+
 impl Socket {
-    pub fn new(anon_name: &str) -> Result<Socket> {
+    /// Binds a socket named `anon_name` in the network namespace of the hypervisor `hv_pid`.
+    pub fn new(anon_name: &str, hv_pid: Pid) -> Result<Socket> {
+        in_netns_of(hv_pid, || Socket::bind(anon_name))
+    }
+
+    fn bind(anon_name: &str) -> Result<Socket> {
         // socket
         let sock = try_with!(
             nix::sys::socket::socket(
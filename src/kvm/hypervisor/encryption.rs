@@ -0,0 +1,95 @@
+//! Detects VMs with encrypted memory (AMD SEV, Intel TDX). Reading their memory returns
+//! ciphertext and writing it corrupts the guest, so vmsh refuses them unless allowed with
+//! `allow_encrypted`, and then only reads from them.
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryEncryption {
+    None,
+    /// AMD SEV: memory is encrypted, registers are not
+    Sev,
+    /// AMD SEV-ES: memory and registers are encrypted
+    SevEs,
+    /// AMD SEV-SNP: like SEV-ES, private memory is not mapped in the hypervisor
+    SevSnp,
+    /// Intel TDX: private memory and registers are not accessible to the hypervisor
+    Tdx,
+}
+
+/// SEV policy bit requiring SEV-ES
+const SEV_POLICY_ES: u32 = 1 << 2;
+
+impl MemoryEncryption {
+    /// Interprets KVM_SEV_GUEST_STATUS. `ret` is the result of the ioctl, `policy` the guest
+    /// policy it returned, `intel` whether the host is an Intel cpu. Non-SEV VMs fail with
+    /// ENOTTY, SNP guests refuse SEV commands with EPERM and TDX guests refuse the unknown
+    /// command with EINVAL.
+    pub fn from_guest_status(ret: i32, policy: u32, intel: bool) -> MemoryEncryption {
+        match ret {
+            0 if policy & SEV_POLICY_ES != 0 => MemoryEncryption::SevEs,
+            0 => MemoryEncryption::Sev,
+            ret if ret == -libc::ENOTTY => MemoryEncryption::None,
+            _ if intel => MemoryEncryption::Tdx,
+            ret if ret == -libc::EPERM => MemoryEncryption::SevSnp,
+            _ => MemoryEncryption::Sev,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        *self != MemoryEncryption::None
+    }
+
+    /// Whether KVM can read the vcpu registers, i.e. with KVM_GET_REGS
+    pub fn has_protected_state(&self) -> bool {
+        !matches!(self, MemoryEncryption::None | MemoryEncryption::Sev)
+    }
+}
+
+impl fmt::Display for MemoryEncryption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            MemoryEncryption::None => "no memory encryption",
+            MemoryEncryption::Sev => "AMD SEV",
+            MemoryEncryption::SevEs => "AMD SEV-ES",
+            MemoryEncryption::SevSnp => "AMD SEV-SNP",
+            MemoryEncryption::Tdx => "Intel TDX",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guest_status() {
+        assert_eq!(
+            MemoryEncryption::from_guest_status(-libc::ENOTTY, 0, false),
+            MemoryEncryption::None
+        );
+        assert_eq!(
+            MemoryEncryption::from_guest_status(-libc::ENOTTY, 0, true),
+            MemoryEncryption::None
+        );
+        assert_eq!(
+            MemoryEncryption::from_guest_status(0, 0x1, false),
+            MemoryEncryption::Sev
+        );
+        assert_eq!(
+            MemoryEncryption::from_guest_status(0, 0x5, false),
+            MemoryEncryption::SevEs
+        );
+        assert_eq!(
+            MemoryEncryption::from_guest_status(-libc::EPERM, 0, false),
+            MemoryEncryption::SevSnp
+        );
+        assert_eq!(
+            MemoryEncryption::from_guest_status(-libc::EINVAL, 0, true),
+            MemoryEncryption::Tdx
+        );
+        assert!(!MemoryEncryption::Sev.has_protected_state());
+        assert!(MemoryEncryption::Tdx.has_protected_state());
+    }
+}
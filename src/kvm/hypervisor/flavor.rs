@@ -0,0 +1,207 @@
+//! Detects which hypervisor runs a VM. The KVM interface is the same for all of them, but their
+//! process layout and sandboxing differ.
+
+use log::{info, warn};
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::fs::read_to_string;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::kvm::hypervisor::find_vms;
+use crate::result::Result;
+use crate::tracer::proc::{self, pid_path};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flavor {
+    Qemu,
+    Firecracker,
+    CloudHypervisor,
+    Crosvm,
+    Kvmtool,
+    Unknown,
+}
+
+impl FromStr for Flavor {
+    type Err = String;
+
+    /// Parses the names accepted by `vmsh attach --hypervisor`.
+    fn from_str(s: &str) -> std::result::Result<Flavor, String> {
+        match s {
+            "qemu" => Ok(Flavor::Qemu),
+            "firecracker" => Ok(Flavor::Firecracker),
+            "cloud-hypervisor" => Ok(Flavor::CloudHypervisor),
+            "crosvm" => Ok(Flavor::Crosvm),
+            "kvmtool" => Ok(Flavor::Kvmtool),
+            _ => Err(format!("unknown hypervisor: {}", s)),
+        }
+    }
+}
+
+impl Flavor {
+    /// Guesses the hypervisor from a process name or the file name of its executable.
+    fn from_name(name: &str) -> Flavor {
+        let name = Path::new(name.trim())
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("");
+        if name.starts_with("qemu") {
+            Flavor::Qemu
+        } else if name.starts_with("firecracker") {
+            Flavor::Firecracker
+        // the comm is truncated to 15 characters: cloud-hyperviso
+        } else if name.starts_with("cloud-hyperviso") {
+            Flavor::CloudHypervisor
+        } else if name.starts_with("crosvm") {
+            Flavor::Crosvm
+        } else if name == "lkvm" || name == "kvmtool" {
+            Flavor::Kvmtool
+        } else {
+            Flavor::Unknown
+        }
+    }
+
+    /// Looks at the process name and, if that is not conclusive, at the executable in the
+    /// command line.
+    pub fn detect(pid: Pid) -> Flavor {
+        let dir = pid_path(pid);
+        let comm = read_to_string(dir.join("comm")).unwrap_or_default();
+        match Flavor::from_name(&comm) {
+            Flavor::Unknown => {}
+            flavor => return flavor,
+        }
+        let cmdline = read_to_string(dir.join("cmdline")).unwrap_or_default();
+        Flavor::from_name(cmdline.split('\0').next().unwrap_or(""))
+    }
+
+    /// How to turn off the seccomp filters of the hypervisor, if they block injected syscalls.
+    pub fn seccomp_hint(&self) -> &'static str {
+        match self {
+            Flavor::Firecracker => "start firecracker with --no-seccomp",
+            Flavor::CloudHypervisor => "start cloud-hypervisor with --seccomp false",
+            Flavor::Crosvm => "start crosvm with --disable-sandbox",
+            _ => "disable the seccomp filters of the hypervisor",
+        }
+    }
+
+    /// Interrupt line used if the free lines of the guest cannot be determined.
+    pub fn fallback_irq_num(&self) -> usize {
+        match self {
+            Flavor::Crosvm => 4,
+            _ => 6,
+        }
+    }
+}
+
+fn has_vms(pid: Pid) -> bool {
+    find_vms(pid).map_or(false, |vms| !vms.is_empty())
+}
+
+fn is_jailer(pid: Pid) -> bool {
+    read_to_string(pid_path(pid).join("comm")).map_or(false, |comm| comm.trim() == "jailer")
+}
+
+/// crosvm and cloud-hypervisor can run devices in separate processes. If `pid` is one of them,
+/// returns the process that owns the VM and its vcpus, which is the parent of the device
+/// processes in crosvm and may also be a child of the process started by the user.
+///
+/// The firecracker jailer forks firecracker into a new pid namespace unless it is started with
+/// `--daemonize`, so if `pid` is the jailer the VM is found among its descendants. `flavor`
+/// overrides the detected hypervisor of `pid`.
+pub fn vm_process(pid: Pid, flavor: Option<Flavor>) -> Result<Pid> {
+    if has_vms(pid) {
+        return Ok(pid);
+    }
+    let ppid = try_with!(
+        proc::task_status(pid, pid, "PPid"),
+        "cannot get parent of process {}",
+        pid
+    );
+    let mut candidates = match ppid.parse::<i32>() {
+        Ok(ppid) if ppid > 1 => vec![Pid::from_raw(ppid)],
+        _ => vec![],
+    };
+    candidates.extend(proc::descendants(pid));
+    let flavor = match flavor {
+        Some(flavor) => flavor,
+        None if is_jailer(pid) => Flavor::Firecracker,
+        None => Flavor::detect(pid),
+    };
+    for candidate in candidates {
+        if Flavor::detect(candidate) == flavor && has_vms(candidate) {
+            info!(
+                "process {} has no VM, use {:?} process {} instead",
+                pid, flavor, candidate
+            );
+            if let Some(inner) = proc::ns_pids(candidate)
+                .ok()
+                .and_then(|p| p.last().copied())
+            {
+                if inner != candidate {
+                    info!("process {} is {} in its pid namespace", candidate, inner);
+                }
+            }
+            return Ok(candidate);
+        }
+    }
+    Ok(pid)
+}
+
+/// Warns if all threads of the hypervisor have seccomp filters, which kill it once we inject a
+/// syscall it does not expect. Firecracker installs its filters on every thread and kills the
+/// process on the first violation, so there we refuse to attach.
+pub fn check_seccomp(pid: Pid, flavor: Flavor) -> Result<()> {
+    for tid in proc::threads(pid)? {
+        if !proc::has_seccomp_filter(pid, tid)? {
+            return Ok(());
+        }
+    }
+    if flavor == Flavor::Firecracker {
+        bail!(
+            "all threads of firecracker process {} have a seccomp filter, {}",
+            pid,
+            flavor.seccomp_hint()
+        );
+    }
+    warn!(
+        "all threads of {:?} process {} have a seccomp filter. If attaching fails, {}",
+        flavor,
+        pid,
+        flavor.seccomp_hint()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flavor_names() {
+        assert_eq!(Flavor::from_name("qemu-system-x86\n"), Flavor::Qemu);
+        assert_eq!(
+            Flavor::from_name("/nix/store/xxx-qemu/bin/qemu-kvm"),
+            Flavor::Qemu
+        );
+        assert_eq!(
+            Flavor::from_name("cloud-hyperviso\n"),
+            Flavor::CloudHypervisor
+        );
+        assert_eq!(
+            Flavor::from_name("/usr/bin/cloud-hypervisor"),
+            Flavor::CloudHypervisor
+        );
+        assert_eq!(Flavor::from_name("crosvm"), Flavor::Crosvm);
+        assert_eq!(Flavor::from_name("firecracker"), Flavor::Firecracker);
+        assert_eq!(Flavor::from_name("lkvm"), Flavor::Kvmtool);
+        assert_eq!(Flavor::from_name("bash"), Flavor::Unknown);
+        assert_eq!(Flavor::from_name(""), Flavor::Unknown);
+    }
+
+    #[test]
+    fn parse_flavor() {
+        assert_eq!("firecracker".parse(), Ok(Flavor::Firecracker));
+        assert_eq!("cloud-hypervisor".parse(), Ok(Flavor::CloudHypervisor));
+        assert!("auto".parse::<Flavor>().is_err());
+    }
+}
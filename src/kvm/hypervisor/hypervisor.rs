@@ -1,27 +1,34 @@
+use crate::arch;
 use crate::cpu;
 use crate::page_table::PhysAddr;
 use crate::tracer::inject_syscall;
 use kvm_bindings as kvmb;
-use libc::c_int;
+use libc::{c_int, c_ulong};
 use log::*;
 use nix::unistd::Pid;
 use simple_error::{bail, require_with, simple_error, try_with};
 use std::ffi::OsStr;
+use std::fs::{self, File};
 use std::mem::size_of;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::prelude::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, RwLock, RwLockWriteGuard};
 use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
 
+use super::encryption::MemoryEncryption;
 use super::ioeventfd::IoEventFd;
 use super::ioregionfd::IoRegionFd;
 use super::memory::*;
+use super::nested::NestedState;
+use super::userfaultfd::UserfaultFd;
 use crate::kvm::fd_transfer;
 use crate::kvm::ioctls;
+use crate::kvm::memslots::get_vcpu_maps;
 use crate::kvm::tracee::{kvm_msrs, Tracee};
 use crate::page_math::{self, compute_host_offset};
-use crate::result::Result;
-use crate::tracer::proc::{openpid, Mapping, PidHandle};
+use crate::result::{Result, VmshError};
+use crate::tracer::proc::{self, openpid, Mapping};
 use crate::tracer::wrap_syscall::KvmRunWrapper;
 
 #[allow(clippy::upper_case_acronyms)]
@@ -35,10 +42,23 @@ pub struct VCPU {
 }
 
 impl VCPU {
-    pub fn match_maps(vcpus: &mut Vec<VCPU>, vcpu_maps: &[Mapping]) {
+    /// `earlier_vms` are the VMs with lower fds in the same hypervisor, their vcpus have
+    /// mappings with the same names.
+    pub fn match_maps(vcpus: &mut Vec<VCPU>, vcpu_maps: &[Mapping], earlier_vms: &[KvmVm]) {
         for vcpu in vcpus {
             let name = format!("{}{}", VCPUFD_INODE_NAME_STARTS_WITH, vcpu.idx);
-            match vcpu_maps.iter().find(|map| map.pathname == name) {
+            // Vcpus of earlier VMs were most likely mapped before, mmap allocates top-down so
+            // their mappings are at higher addresses.
+            let skip = earlier_vms
+                .iter()
+                .filter(|vm| vm.vcpus.iter().any(|v| v.idx == vcpu.idx))
+                .count();
+            match vcpu_maps
+                .iter()
+                .rev()
+                .filter(|map| map.pathname == name)
+                .nth(skip)
+            {
                 Some(map) => vcpu.vcpu_map = Some(map.clone()),
                 None => warn!(
                     "no mapped memory of vcpu fd {} found called {}",
@@ -48,9 +68,46 @@ impl VCPU {
         }
     }
 
+    /// Looks up a vcpu that was created after we attached, i.e. by cpu hotplug. `known` are the
+    /// vcpus we already have, their mappings are not considered.
+    pub fn discover(pid: Pid, fd_num: RawFd, known: &[VCPU]) -> Result<Option<VCPU>> {
+        let link = proc::pid_path(pid).join("fd").join(fd_num.to_string());
+        let target = match fs::read_link(&link) {
+            Ok(target) => target,
+            // fd was closed again
+            Err(_) => return Ok(None),
+        };
+        let name = target.to_str().unwrap_or("");
+        let idx = match name.strip_prefix(VCPUFD_INODE_NAME_STARTS_WITH) {
+            Some(idx) => try_with!(idx.parse::<usize>(), "cannot parse number {}", idx),
+            None => return Ok(None),
+        };
+        // The hypervisor maps the vcpu right after creating it. mmap allocates top-down, so the
+        // newest unclaimed mapping is the one with the lowest address.
+        let vcpu_map = try_with!(get_vcpu_maps(pid), "cannot get vcpufd memory maps")
+            .into_iter()
+            .filter(|map| map.pathname == name)
+            .filter(|map| {
+                !known
+                    .iter()
+                    .any(|vcpu| vcpu.vcpu_map.as_ref().map(|m| m.start) == Some(map.start))
+            })
+            .min_by_key(|map| map.start);
+        if vcpu_map.is_none() {
+            // the hypervisor did not map it yet
+            return Ok(None);
+        }
+        info!("found new vcpu {} fd {}", idx, fd_num);
+        Ok(Some(VCPU {
+            idx,
+            fd_num,
+            vcpu_map,
+        }))
+    }
+
     pub fn map(&self) -> Result<&Mapping> {
         self.vcpu_map.as_ref().ok_or_else(|| {
-            simple_error!("vcpu_map must be initialized before use (programming error)")
+            simple_error!("vcpu_map must be initialized before use (programming error)").into()
         })
     }
 }
@@ -73,6 +130,22 @@ pub struct Hypervisor {
     pub(super) tracee: Arc<RwLock<Tracee>>,
     pub wrapper: Mutex<Option<KvmRunWrapper>>,
     transfer_ctx: Mutex<Option<TransferContext>>,
+    /// Detected on the first `stop`
+    encryption: Mutex<Option<MemoryEncryption>>,
+    /// Set when attaching, once the `MmioTransport` is chosen.
+    ioregionfd: AtomicBool,
+    /// Whether the memory of the hypervisor is write protected, see `memory::protect`.
+    protected: AtomicBool,
+    /// Irqfds we assigned, see `unregister_irqfds`
+    irqfds: Mutex<Vec<kvmb::kvm_irqfd>>,
+}
+
+impl Drop for Hypervisor {
+    fn drop(&mut self) {
+        if self.protected.load(Ordering::Acquire) {
+            unprotect(self.pid);
+        }
+    }
 }
 
 impl Hypervisor {
@@ -80,7 +153,16 @@ impl Hypervisor {
         Tracee::new(pid, vm_fd, None)
     }
 
-    pub fn setup_transfer_sockets(&mut self) -> Result<()> {
+    /// Whether mmio of this VM is served with ioregionfd rather than by intercepting mmio exits.
+    pub fn use_ioregionfd(&self) -> bool {
+        self.ioregionfd.load(Ordering::Acquire)
+    }
+
+    pub fn set_use_ioregionfd(&self, ioregionfd: bool) {
+        self.ioregionfd.store(ioregionfd, Ordering::Release);
+    }
+
+    pub fn setup_transfer_sockets(&self) -> Result<()> {
         let msg_hdr_mem = self.alloc_mem()?;
         let iov_mem = self.alloc_mem()?;
         let iov_buf_mem = self.alloc_mem::<[u8; 1]>()?;
@@ -91,7 +173,7 @@ impl Hypervisor {
         let vmsh_id = format!("vmsh_fd_transfer_{}", nix::unistd::getpid());
         let hypervisor_id = format!("vmsh_fd_transfer_{}", self.pid);
         let local_sock = try_with!(
-            fd_transfer::Socket::new(&vmsh_id),
+            fd_transfer::Socket::new(&vmsh_id, self.pid),
             "failed to create socket"
         );
         // remote_sock needs to outlive tracee or we run in a deadlock
@@ -114,14 +196,14 @@ impl Hypervisor {
             remote_sock.connect(proc, &vmsh_id, &addr_remote_mem)
         };
         try_with!(res, "failed to connect to local socket from hypervisor");
-        self.transfer_ctx = Mutex::new(Some(TransferContext {
+        *try_with!(self.transfer_ctx.lock(), "cannot take lock") = Some(TransferContext {
             local_sock,
             remote_sock,
             msg_hdr_mem,
             iov_mem,
             iov_buf_mem,
             cmsg_mem,
-        }));
+        });
         Ok(())
     }
 
@@ -155,9 +237,101 @@ impl Hypervisor {
             "cannot obtain tracee write lock: poinsoned"
         );
         tracee.attach()?;
+        drop(tracee);
+        self.check_encryption()
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn probe_encryption(&self) -> Result<MemoryEncryption> {
+        let cmd = self.alloc_mem()?;
+        let status = self.alloc_mem::<ioctls::kvm_sev_guest_status>()?;
+        let ret = {
+            let tracee = try_with!(
+                self.tracee.read(),
+                "cannot obtain tracee read lock: poinsoned"
+            );
+            tracee.sev_guest_status(&cmd, &status)?
+        };
+        let policy = if ret == 0 { status.read()?.policy } else { 0 };
+        // TDX guests fail with EINVAL, like SEV guests for other reasons
+        let cpuinfo = fs::read_to_string("/proc/cpuinfo").unwrap_or_default();
+        let intel = cpuinfo.contains("GenuineIntel");
+        Ok(MemoryEncryption::from_guest_status(ret, policy, intel))
+    }
+
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    fn probe_encryption(&self) -> Result<MemoryEncryption> {
+        Ok(MemoryEncryption::None)
+    }
+
+    /// Whether the memory of the VM is encrypted. Only known after the first `stop`.
+    pub fn encryption(&self) -> Result<MemoryEncryption> {
+        let encryption = try_with!(self.encryption.lock(), "cannot take lock");
+        Ok(encryption.unwrap_or(MemoryEncryption::None))
+    }
+
+    /// Refuses VMs with encrypted memory, unless `allow_encrypted` was called. Then the tracee
+    /// becomes read-only.
+    fn check_encryption(&self) -> Result<()> {
+        let mut cached = try_with!(self.encryption.lock(), "cannot take lock");
+        let (encryption, first_stop) = match *cached {
+            Some(encryption) => (encryption, false),
+            None => {
+                let encryption = self.probe_encryption().unwrap_or_else(|e| {
+                    warn!("cannot detect memory encryption of the vm: {}", e);
+                    MemoryEncryption::None
+                });
+                *cached = Some(encryption);
+                (encryption, true)
+            }
+        };
+        drop(cached);
+        if !encryption.is_encrypted() {
+            return Ok(());
+        }
+        if !ALLOW_ENCRYPTED.load(Ordering::Acquire) {
+            self.resume()?;
+            bail!(
+                "the vm uses {}: vmsh reads its memory as ciphertext and would corrupt the guest. \
+                 Use --allow-encrypted to read registers and the memory shared with the host",
+                encryption
+            );
+        }
+        let mut tracee = try_with!(
+            self.tracee.write(),
+            "cannot obtain tracee write lock: poinsoned"
+        );
+        if first_stop {
+            warn!(
+                "the vm uses {}, only reading registers and shared memory works",
+                encryption
+            );
+        }
+        tracee.set_encrypted();
+        self.protect(tracee.read_only_reason());
         Ok(())
     }
 
+    /// Makes writes to the memory of the hypervisor fail from now on, if the tracee is
+    /// read-only.
+    fn protect(&self, reason: Option<&'static str>) {
+        if let Some(reason) = reason {
+            if !self.protected.swap(true, Ordering::AcqRel) {
+                protect(self.pid, reason);
+            }
+        }
+    }
+
+    /// Fails in read-only mode. Writes to hypervisor memory are refused then anyway (see
+    /// `memory::check_write`), this fails before anything was changed.
+    pub fn check_writable(&self, what: &str) -> Result<()> {
+        let tracee = try_with!(
+            self.tracee.read(),
+            "cannot obtain tracee read lock: poinsoned"
+        );
+        tracee.check_writable(what)
+    }
+
     pub fn tracee_write_guard(&self) -> Result<RwLockWriteGuard<Tracee>> {
         let twg: RwLockWriteGuard<Tracee> = try_with!(
             self.tracee.write(),
@@ -174,6 +348,7 @@ impl Hypervisor {
         &self,
         mut f: impl FnMut(&Mutex<Option<KvmRunWrapper>>) -> Result<()>,
     ) -> Result<()> {
+        self.check_writable("intercepting KVM_RUN")?;
         // detach tracee and convert to owned wrapper
         let (was_attached, wrapper) = {
             let mut tracee = try_with!(
@@ -256,14 +431,132 @@ impl Hypervisor {
     ) -> Result<PhysMem<T>> {
         // must be a multiple of PAGESIZE
         let slot_len = page_math::page_align(size);
+        let slot = self.reserve_memslot(guest_addr, slot_len)?;
         let hv_memslot = self.alloc_mem_padded::<T>(slot_len)?;
+        self.set_memslot(slot, guest_addr, hv_memslot, readonly)
+    }
+
+    /// Adds `file` read-only at `guest_addr`. The hypervisor maps the file shared, so the guest
+    /// reads the page cache of the host and no copy of the file is made. The size of the file
+    /// must be a multiple of the page size.
+    pub fn vm_add_file(&self, guest_addr: u64, file: &File) -> Result<PhysMem<u8>> {
+        let size = try_with!(file.metadata(), "cannot get file size").len() as usize;
+        if size == 0 || size != page_math::page_align(size) {
+            bail!("file size {} is not a multiple of the page size", size);
+        }
+        let slot = self.reserve_memslot(guest_addr, size)?;
+        let hv_fd = self.transfer(&[file.as_raw_fd()])?[0];
+        let ptr = {
+            let tracee = try_with!(
+                self.tracee.write(),
+                "cannot obtain tracee write lock: poinsoned"
+            );
+            let ptr = tracee.mmap_file(size, hv_fd);
+            // the mapping keeps the file open
+            if let Err(e) = tracee.close(hv_fd) {
+                warn!("failed to close file in hypervisor: {}", e);
+            }
+            ptr? as isize
+        };
+        if (-4095..0).contains(&ptr) {
+            bail!(
+                "cannot mmap file in hypervisor: {}",
+                nix::errno::Errno::from_i32(-ptr as i32)
+            );
+        }
+        let hv_memslot = HvMem {
+            ptr: ptr as libc::uintptr_t,
+            size,
+            pid: self.pid,
+            tracee: self.tracee.clone(),
+            phantom: SendPhantom::default(),
+        };
+        self.set_memslot(slot, guest_addr, hv_memslot, true)
+    }
+
+    /// Takes ownership of memory a vmsh process left in the VM, see `PhysMem::to_kept`. It is
+    /// removed from the VM when dropped.
+    pub fn reclaim_mem(&self, kept: &KeptPhysMem) -> Result<PhysMem<u8>> {
+        let ioctl_arg = HvMem::<kvmb::kvm_userspace_memory_region> {
+            ptr: kept.ioctl_arg,
+            size: size_of::<kvmb::kvm_userspace_memory_region>(),
+            pid: self.pid,
+            tracee: self.tracee.clone(),
+            phantom: SendPhantom::default(),
+        };
+        let arg = match ioctl_arg.read() {
+            Ok(arg) => arg,
+            Err(e) => {
+                // the memory is not ours
+                std::mem::forget(ioctl_arg);
+                bail!("cannot read memslot of kept memory: {}", e);
+            }
+        };
+        if arg.userspace_addr != kept.ptr as u64
+            || arg.guest_phys_addr != kept.guest_phys_addr as u64
+            || arg.memory_size != kept.size as u64
+        {
+            std::mem::forget(ioctl_arg);
+            bail!(
+                "kept memory at {:#x} does not match memslot {} at {:#x}",
+                kept.guest_phys_addr,
+                arg.slot,
+                arg.guest_phys_addr
+            );
+        }
+        let mem = HvMem {
+            ptr: kept.ptr,
+            size: kept.size,
+            pid: self.pid,
+            tracee: self.tracee.clone(),
+            phantom: SendPhantom::default(),
+        };
+        Ok(PhysMem {
+            guest_phys_addr: PhysAddr {
+                value: kept.guest_phys_addr,
+                host_offset: compute_host_offset(kept.ptr, kept.guest_phys_addr),
+            },
+            mem,
+            ioctl_arg,
+        })
+    }
+
+    /// Returns a free memslot id for `len` bytes at `guest_addr`, if no memslot overlaps
+    /// with them.
+    fn reserve_memslot(&self, guest_addr: u64, len: usize) -> Result<u32> {
+        let maps = self.get_maps()?;
+        let range = guest_addr as usize..guest_addr as usize + len;
+        if let Some(m) = maps
+            .iter()
+            .find(|m| m.phys_addr < range.end && range.start < m.phys_end())
+        {
+            bail!(
+                "guest physical memory {:#x}-{:#x} is already used by memslot {} ({:#x}-{:#x})",
+                range.start,
+                range.end,
+                m.slot,
+                m.phys_addr,
+                m.phys_end()
+            );
+        }
+        self.free_memslot(&maps)
+    }
+
+    /// Makes `hv_memslot` available to the guest at `guest_addr` as memslot `slot`.
+    fn set_memslot<T: Copy>(
+        &self,
+        slot: u32,
+        guest_addr: u64,
+        hv_memslot: HvMem<T>,
+        readonly: bool,
+    ) -> Result<PhysMem<T>> {
         let mut flags = 0;
         flags |= if readonly { kvmb::KVM_MEM_READONLY } else { 0 };
         let arg = kvmb::kvm_userspace_memory_region {
-            slot: self.get_maps()?.len() as u32, // guess a hopfully available slot id
+            slot,
             flags,
             guest_phys_addr: guest_addr, // must be page aligned
-            memory_size: slot_len as u64,
+            memory_size: hv_memslot.size as u64,
             userspace_addr: hv_memslot.ptr as u64,
         };
         let arg_hv = self.alloc_mem()?;
@@ -275,7 +568,7 @@ impl Hypervisor {
         );
         let ret = tracee.vm_ioctl_with_ref(ioctls::KVM_SET_USER_MEMORY_REGION(), &arg_hv)?;
         if ret != 0 {
-            bail!("ioctl_with_ref failed: {}", ret)
+            return Err(VmshError::kvm_ioctl("KVM_SET_USER_MEMORY_REGION", ret));
         }
         let host_offset = compute_host_offset(hv_memslot.ptr, guest_addr as usize);
         Ok(PhysMem {
@@ -288,6 +581,75 @@ impl Hypervisor {
         })
     }
 
+    /// Changes the flags (KVM_MEM_*) of the memslot backing `slot`. KVM only allows to
+    /// toggle KVM_MEM_LOG_DIRTY_PAGES on existing memslots.
+    pub fn set_memslot_flags(&self, slot: &Mapping, flags: u32) -> Result<()> {
+        let arg = kvmb::kvm_userspace_memory_region {
+            slot: slot.slot,
+            flags,
+            guest_phys_addr: slot.phys_addr as u64,
+            memory_size: slot.size() as u64,
+            userspace_addr: slot.start as u64,
+        };
+        let arg_hv = self.alloc_mem()?;
+        arg_hv.write(&arg)?;
+        let tracee = try_with!(
+            self.tracee.read(),
+            "cannot obtain tracee read lock: poinsoned"
+        );
+        let ret = tracee.vm_ioctl_with_ref(ioctls::KVM_SET_USER_MEMORY_REGION(), &arg_hv)?;
+        if ret != 0 {
+            return Err(VmshError::kvm_ioctl("KVM_SET_USER_MEMORY_REGION", ret));
+        }
+        Ok(())
+    }
+
+    /// Returns the bitmap of pages in memslot `slot` written since the last call, one bit
+    /// per page starting at bit 0 of the first word. Requires dirty logging to be enabled
+    /// for the memslot.
+    pub fn dirty_log(&self, slot: &Mapping) -> Result<Vec<u64>> {
+        let pages = slot.size() / page_math::page_size();
+        let words = pages.div_ceil(64);
+        let bitmap = self.alloc_mem_padded::<u8>(page_math::page_align(words * 8))?;
+        let arg = ioctls::kvm_dirty_log {
+            slot: slot.slot,
+            padding1: 0,
+            dirty_bitmap: bitmap.ptr as u64,
+        };
+        let arg_hv = self.alloc_mem()?;
+        arg_hv.write(&arg)?;
+        {
+            let tracee = try_with!(
+                self.tracee.read(),
+                "cannot obtain tracee read lock: poinsoned"
+            );
+            let ret = tracee.vm_ioctl_with_ref(ioctls::KVM_GET_DIRTY_LOG(), &arg_hv)?;
+            if ret != 0 {
+                return Err(VmshError::kvm_ioctl("KVM_GET_DIRTY_LOG", ret));
+            }
+        }
+        let mut bytes = vec![0u8; words * 8];
+        try_with!(bitmap.read_bytes(&mut bytes), "cannot read dirty bitmap");
+        Ok(bytes
+            .chunks_exact(8)
+            .map(|w| u64::from_ne_bytes([w[0], w[1], w[2], w[3], w[4], w[5], w[6], w[7]]))
+            .collect())
+    }
+
+    /// Returns the lowest memslot id not used by any of `maps`.
+    fn free_memslot(&self, maps: &[Mapping]) -> Result<u32> {
+        let max_slots = try_with!(
+            self.check_extension(kvmb::KVM_CAP_NR_MEMSLOTS as c_int),
+            "cannot query number of memslots"
+        );
+        let slot = (0..max_slots.max(0) as u32).find(|id| maps.iter().all(|m| m.slot != *id));
+        Ok(require_with!(
+            slot,
+            "all {} memslots of the vm are in use",
+            max_slots
+        ))
+    }
+
     pub fn alloc_mem<T: Copy>(&self) -> Result<HvMem<T>> {
         self.alloc_mem_padded::<T>(size_of::<T>())
     }
@@ -310,6 +672,7 @@ impl Hypervisor {
         let ptr = tracee.mmap(size)?;
         Ok(HvMem {
             ptr: ptr as libc::uintptr_t,
+            size,
             pid: self.pid,
             tracee: self.tracee.clone(),
             phantom: SendPhantom::default(),
@@ -370,13 +733,59 @@ impl Hypervisor {
     pub fn irqfd(&self, gsi: u32) -> Result<EventFd> {
         let eventfd = try_with!(EventFd::new(EFD_NONBLOCK), "cannot create event fd");
         info!("irqfd {:?}, interupt gsi/nr {:?}", eventfd.as_raw_fd(), gsi);
+        self.register_irqfd(&eventfd, gsi, None)?;
+        Ok(eventfd)
+    }
+
+    /// Like `irqfd`, but KVM also signals the returned resamplefd when the guest acknowledges
+    /// the interrupt (EOI). The resamplefd is `None` if KVM does not support
+    /// KVM_CAP_IRQFD_RESAMPLE.
+    pub fn irqfd_resample(&self, gsi: u32) -> Result<(EventFd, Option<EventFd>)> {
+        let has_cap = self.check_extension(kvmb::KVM_CAP_IRQFD_RESAMPLE as c_int)?;
+        if has_cap <= 0 {
+            return Ok((self.irqfd(gsi)?, None));
+        }
+        let eventfd = try_with!(EventFd::new(EFD_NONBLOCK), "cannot create event fd");
+        let resamplefd = try_with!(EventFd::new(EFD_NONBLOCK), "cannot create event fd");
+        info!(
+            "irqfd {:?}, resamplefd {:?}, interupt gsi/nr {:?}",
+            eventfd.as_raw_fd(),
+            resamplefd.as_raw_fd(),
+            gsi
+        );
+        match self.register_irqfd(&eventfd, gsi, Some(&resamplefd)) {
+            Ok(()) => Ok((eventfd, Some(resamplefd))),
+            Err(e) => {
+                // i.e. gsis without irqchip route
+                warn!(
+                    "cannot register irqfd with resamplefd, fall back to irqfd: {}",
+                    e
+                );
+                Ok((self.irqfd(gsi)?, None))
+            }
+        }
+    }
+
+    fn register_irqfd(
+        &self,
+        eventfd: &EventFd,
+        gsi: u32,
+        resamplefd: Option<&EventFd>,
+    ) -> Result<()> {
         let hv_eventfd = self.transfer(vec![eventfd.as_raw_fd()].as_slice())?[0];
+        let (flags, hv_resamplefd) = match resamplefd {
+            Some(resamplefd) => (
+                kvmb::KVM_IRQFD_FLAG_RESAMPLE,
+                self.transfer(vec![resamplefd.as_raw_fd()].as_slice())?[0],
+            ),
+            None => (0, 0),
+        };
 
         let irqfd = kvmb::kvm_irqfd {
-            fd: hv_eventfd.as_raw_fd() as u32,
+            fd: hv_eventfd as u32,
             gsi,
-            flags: 0,
-            resamplefd: 0,
+            flags,
+            resamplefd: hv_resamplefd as u32,
             ..Default::default()
         };
         let mem = self.alloc_mem()?;
@@ -392,27 +801,111 @@ impl Hypervisor {
             )
         };
         if ret != 0 {
-            bail!("cannot register KVM_IRQFD via ioctl: {:?}", ret);
+            return Err(VmshError::kvm_ioctl("KVM_IRQFD", ret));
         }
+        try_with!(self.irqfds.lock(), "cannot lock irqfds").push(irqfd);
 
-        Ok(eventfd)
+        Ok(())
     }
 
-    pub fn userfaultfd(&self) -> Result<c_int> {
+    /// Deassigns the irqfds registered so far and closes them in the hypervisor. The eventfds
+    /// returned by `irqfd` no longer raise interrupts afterwards.
+    pub fn unregister_irqfds(&self) -> Result<()> {
+        let irqfds = std::mem::take(&mut *try_with!(self.irqfds.lock(), "cannot lock irqfds"));
+        let mem = self.alloc_mem()?;
         let tracee = try_with!(
             self.tracee.read(),
             "cannot obtain tracee read lock: poinsoned"
         );
-        let proc = tracee.try_get_proc()?;
+        for irqfd in irqfds {
+            let deassign = kvmb::kvm_irqfd {
+                fd: irqfd.fd,
+                gsi: irqfd.gsi,
+                flags: kvmb::KVM_IRQFD_FLAG_DEASSIGN,
+                ..Default::default()
+            };
+            mem.write(&deassign)?;
+            let ret = try_with!(
+                tracee.vm_ioctl_with_ref(ioctls::KVM_IRQFD(), &mem),
+                "kvm irqfd ioctl injection failed"
+            );
+            if ret != 0 {
+                return Err(VmshError::kvm_ioctl("KVM_IRQFD", ret));
+            }
+            let mut fds = vec![irqfd.fd as RawFd];
+            if irqfd.flags & kvmb::KVM_IRQFD_FLAG_RESAMPLE != 0 {
+                fds.push(irqfd.resamplefd as RawFd);
+            }
+            for fd in fds {
+                if let Err(e) = tracee.close(fd) {
+                    warn!("failed to close irqfd {} in hypervisor: {}", fd, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Requires Linux 5.6 for pidfd_getfd.
+    pub fn userfaultfd(&self) -> Result<UserfaultFd> {
+        UserfaultFd::new(self)
+    }
 
-        let uffd = proc.userfaultfd(libc::O_NONBLOCK)?;
-        if uffd <= 0 {
-            bail!("userfaultfd failed with {}", uffd);
+    /// Injects a message signaled interrupt. Unlike with irqfds, the hypervisor has to be stopped.
+    pub fn signal_msi(&self, msi: &kvmb::kvm_msi) -> Result<()> {
+        let mem = self.alloc_mem()?;
+        mem.write(msi)?;
+        let tracee = try_with!(
+            self.tracee.read(),
+            "cannot obtain tracee read lock: poinsoned"
+        );
+        let ret = try_with!(
+            tracee.vm_ioctl_with_ref(ioctls::KVM_SIGNAL_MSI(), &mem),
+            "kvm signal msi ioctl injection failed"
+        );
+        // 0 means the guest blocked the interrupt
+        if ret < 0 {
+            return Err(VmshError::kvm_ioctl("KVM_SIGNAL_MSI", ret));
         }
+        Ok(())
+    }
 
-        // TODO impl userfaultfd handling
+    /// Reads the kvmclock of the VM, the nanoseconds the guest sees as passed since it booted.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_clock(&self) -> Result<ioctls::kvm_clock_data> {
+        let mem = self.alloc_mem()?;
+        mem.write(&ioctls::kvm_clock_data::default())?;
+        let tracee = try_with!(
+            self.tracee.read(),
+            "cannot obtain tracee read lock: poinsoned"
+        );
+        let ret = try_with!(
+            tracee.vm_ioctl_with_ref(ioctls::KVM_GET_CLOCK(), &mem),
+            "kvm get clock ioctl injection failed"
+        );
+        if ret != 0 {
+            return Err(VmshError::kvm_ioctl("KVM_GET_CLOCK", ret));
+        }
+        mem.read()
+    }
 
-        Ok(-1)
+    /// Sets the kvmclock of the VM. Only `clock` and `flags` are used, KVM_CLOCK_REALTIME makes
+    /// KVM advance `clock` by the time passed since `realtime`.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_clock(&self, clock: &ioctls::kvm_clock_data) -> Result<()> {
+        let mem = self.alloc_mem()?;
+        mem.write(clock)?;
+        let tracee = try_with!(
+            self.tracee.read(),
+            "cannot obtain tracee read lock: poinsoned"
+        );
+        let ret = try_with!(
+            tracee.vm_ioctl_with_ref(ioctls::KVM_SET_CLOCK(), &mem),
+            "kvm set clock ioctl injection failed"
+        );
+        if ret != 0 {
+            return Err(VmshError::kvm_ioctl("KVM_SET_CLOCK", ret));
+        }
+        Ok(())
     }
 
     pub fn check_extension(&self, cap: c_int) -> Result<c_int> {
@@ -467,65 +960,140 @@ impl Hypervisor {
         tracee.get_irqchip(&mem)
     }
 
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn get_sregs(&self, vcpu: &VCPU) -> Result<kvmb::kvm_sregs> {
+    /// Runs a vcpu ioctl like KVM_GET_SREGS or KVM_SET_SREGS on the kvm structure `arg`.
+    /// Returns the result of the ioctl, i.e. the number of registers for KVM_GET_MSRS, and
+    /// `arg` as updated by the kernel.
+    pub fn vcpu_ioctl<T: Copy>(
+        &self,
+        vcpu: &VCPU,
+        request: c_ulong,
+        arg: &T,
+    ) -> Result<(c_int, T)> {
         let mem = self.alloc_mem()?;
+        mem.write(arg)?;
         let tracee = try_with!(
             self.tracee.write(),
             "cannot obtain tracee write lock: poinsoned"
         );
-        tracee.get_sregs(vcpu, &mem)
+        let ret = tracee.vcpu_ioctl_with_ref(vcpu, request, &mem)?;
+        if ret < 0 {
+            bail!("vcpu ioctl {:#x} failed: {}", request, ret);
+        }
+        Ok((ret, mem.read()?))
     }
 
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn get_regs(&self, vcpu: &VCPU) -> Result<cpu::Regs> {
+    pub fn get_sregs(&self, vcpu: &VCPU) -> Result<kvmb::kvm_sregs> {
         let mem = self.alloc_mem()?;
         let tracee = try_with!(
             self.tracee.write(),
             "cannot obtain tracee write lock: poinsoned"
         );
-        tracee.get_regs(vcpu, &mem)
+        tracee.get_sregs(vcpu, &mem)
+    }
+
+    pub fn get_regs(&self, vcpu: &VCPU) -> Result<cpu::Regs> {
+        arch::get_regs(self, vcpu)
     }
 
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub fn set_regs(&self, vcpu: &VCPU, regs: &cpu::Regs) -> Result<()> {
+        arch::set_regs(self, vcpu, regs)
+    }
+
+    /// Sets the `KVM_GUESTDBG_*` flags of `vcpu`. With `KVM_GUESTDBG_ENABLE` breakpoints and
+    /// single steps make ioctl(KVM_RUN) return with `KVM_EXIT_DEBUG`.
+    pub fn set_guest_debug(&self, vcpu: &VCPU, control: u32) -> Result<()> {
         let mem = self.alloc_mem()?;
-        let regs = kvmb::kvm_regs {
-            rax: regs.rax,
-            rbx: regs.rbx,
-            rcx: regs.rcx,
-            rdx: regs.rdx,
-            rsi: regs.rsi,
-            rdi: regs.rdi,
-            rsp: regs.rsp,
-            rbp: regs.rbp,
-            r8: regs.r8,
-            r9: regs.r9,
-            r10: regs.r10,
-            r11: regs.r11,
-            r12: regs.r12,
-            r13: regs.r13,
-            r14: regs.r14,
-            r15: regs.r15,
-            rip: regs.rip,
-            rflags: regs.eflags,
-        };
-        mem.write(&regs)?;
+        mem.write(&kvmb::kvm_guest_debug {
+            control,
+            ..Default::default()
+        })?;
         let tracee = try_with!(
             self.tracee.write(),
             "cannot obtain tracee write lock: poinsoned"
         );
-        tracee.set_regs(vcpu, &mem)
+        tracee.set_guest_debug(vcpu, &mem)
     }
 
+    /// Injects a non-maskable interrupt into `vcpu`, it is delivered once the vcpu runs.
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn get_fpu_regs(&self, vcpu: &VCPU) -> Result<cpu::FpuRegs> {
-        let mem = self.alloc_mem()?;
+    pub fn nmi(&self, vcpu: &VCPU) -> Result<()> {
         let tracee = try_with!(
             self.tracee.write(),
             "cannot obtain tracee write lock: poinsoned"
         );
-        tracee.get_fpu_regs(vcpu, &mem)
+        tracee.nmi(vcpu)
+    }
+
+    pub fn get_fpu_regs(&self, vcpu: &VCPU) -> Result<cpu::FpuRegs> {
+        arch::get_fpu_regs(self, vcpu)
+    }
+
+    /// Whether `vcpu` runs a nested guest. None if KVM has no nested state for it, i.e. without
+    /// KVM_CAP_NESTED_STATE.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_nested_state(&self, vcpu: &VCPU) -> Result<Option<NestedState>> {
+        let max_size = self.check_extension(ioctls::KVM_CAP_NESTED_STATE as c_int)?;
+        if max_size <= 0 {
+            return Ok(None);
+        }
+        // KVM_GET_NESTED_STATE fails unless the buffer fits the whole state
+        let mem = self.alloc_mem_padded::<ioctls::kvm_nested_state>(max_size as usize)?;
+        mem.write(&ioctls::kvm_nested_state {
+            flags: 0,
+            format: 0,
+            size: max_size as u32,
+            hdr: [0; 15],
+        })?;
+        let ret = {
+            let tracee = try_with!(
+                self.tracee.write(),
+                "cannot obtain tracee write lock: poinsoned"
+            );
+            tracee.vcpu_ioctl_with_ref(vcpu, ioctls::KVM_GET_NESTED_STATE(), &mem)?
+        };
+        if ret != 0 {
+            return Err(VmshError::kvm_ioctl("KVM_GET_NESTED_STATE", ret));
+        }
+        let state = mem.read()?;
+        Ok(NestedState::from_header(
+            state.flags,
+            state.format,
+            state.size,
+            &state.hdr,
+        ))
+    }
+
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    pub fn get_nested_state(&self, _vcpu: &VCPU) -> Result<Option<NestedState>> {
+        Ok(None)
+    }
+
+    /// Whether `vcpu` currently runs a nested guest (L2), so that its registers are not the ones
+    /// of the guest. False if that cannot be determined.
+    pub fn in_nested_guest(&self, vcpu: &VCPU) -> bool {
+        match self.get_nested_state(vcpu) {
+            Ok(state) => state.map_or(false, |s| s.guest_mode),
+            Err(e) => {
+                debug!("cannot get nested state of vcpu {}: {}", vcpu.idx, e);
+                false
+            }
+        }
+    }
+
+    /// Writes a single MSR of `vcpu`.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_msr(&self, vcpu: &VCPU, msr: &kvmb::kvm_msr_entry) -> Result<()> {
+        let msrs = kvm_msrs {
+            nmsrs: 1,
+            pad: 0,
+            entries: [*msr; 1],
+        };
+        let (n, _) = self.vcpu_ioctl(vcpu, ioctls::KVM_SET_MSRS(), &msrs)?;
+        if n != 1 {
+            bail!("vcpu {} does not support msr {:#x}", vcpu.idx, msr.index);
+        }
+        Ok(())
     }
 
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
@@ -550,7 +1118,29 @@ impl Hypervisor {
 pub const VMFD_INODE_NAME: &str = "anon_inode:kvm-vm";
 pub const VCPUFD_INODE_NAME_STARTS_WITH: &str = "anon_inode:kvm-vcpu:";
 
-fn find_vm_fd(handle: &PidHandle) -> Result<(Vec<RawFd>, Vec<VCPU>)> {
+/// Selects one of multiple VMs in the same hypervisor process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmSelector {
+    /// File descriptor number of the VM in the hypervisor.
+    Fd(RawFd),
+    /// Position in the list of VMs, ordered by file descriptor.
+    Index(usize),
+}
+
+/// A KVM VM found in a hypervisor process.
+#[derive(Debug)]
+pub struct KvmVm {
+    pub vm_fd: RawFd,
+    pub vcpus: Vec<VCPU>,
+}
+
+/// Lists the VMs of a hypervisor process, ordered by file descriptor.
+///
+/// The kernel does not tell which VM a vcpu fd belongs to. Since hypervisors create the vcpus
+/// of a VM through its fd, usually right after the VM itself, each vcpu is assigned to the VM
+/// with the closest lower fd number.
+pub fn find_vms(pid: Pid) -> Result<Vec<KvmVm>> {
+    let handle = try_with!(openpid(pid), "cannot open handle in proc");
     let mut vm_fds: Vec<RawFd> = vec![];
     let mut vcpu_fds: Vec<VCPU> = vec![];
     let fds = try_with!(
@@ -585,27 +1175,95 @@ fn find_vm_fd(handle: &PidHandle) -> Result<(Vec<RawFd>, Vec<VCPU>)> {
             })
         }
     }
-    let old_len = vcpu_fds.len();
-    vcpu_fds.dedup_by_key(|vcpu| vcpu.idx);
-    if old_len != vcpu_fds.len() {
-        bail!("found multiple vcpus with same id, assume multiple VMs in same hypervisor. This is not supported yet")
+    vm_fds.sort_unstable();
+    vcpu_fds.sort_by_key(|vcpu| vcpu.fd_num);
+
+    let mut vms = vm_fds
+        .into_iter()
+        .map(|vm_fd| KvmVm {
+            vm_fd,
+            vcpus: vec![],
+        })
+        .collect::<Vec<_>>();
+    for vcpu in vcpu_fds {
+        match vms.iter_mut().rev().find(|vm| vm.vm_fd < vcpu.fd_num) {
+            Some(vm) => vm.vcpus.push(vcpu),
+            None => warn!("cannot find the VM of vcpu fd {}", vcpu.fd_num),
+        }
+    }
+    for vm in &vms {
+        let mut ids = vm.vcpus.iter().map(|vcpu| vcpu.idx).collect::<Vec<_>>();
+        ids.sort_unstable();
+        let len = ids.len();
+        ids.dedup();
+        if len != ids.len() {
+            bail!(
+                "found multiple vcpus with same id for VM fd {}, cannot assign vcpus to VMs",
+                vm.vm_fd
+            )
+        }
+    }
+
+    Ok(vms)
+}
+
+fn select_vm(mut vms: Vec<KvmVm>, selector: Option<VmSelector>) -> Result<(KvmVm, Vec<KvmVm>)> {
+    let idx = match selector {
+        None if vms.len() > 1 => bail!(
+            "multiple VMs found (fds: {}), select one by fd or index",
+            vms.iter()
+                .map(|vm| vm.vm_fd.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        None => 0,
+        Some(VmSelector::Fd(fd)) => match vms.iter().position(|vm| vm.vm_fd == fd) {
+            Some(idx) => idx,
+            None => bail!("no VM with fd {} found", fd),
+        },
+        Some(VmSelector::Index(idx)) if idx < vms.len() => idx,
+        Some(VmSelector::Index(idx)) => {
+            bail!("VM index {} out of range, found {} VMs", idx, vms.len())
+        }
     };
+    let vm = vms.remove(idx);
+    vms.truncate(idx);
+    Ok((vm, vms))
+}
+
+/// Set by `set_read_only`, applies to all hypervisors attached afterwards.
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
 
-    Ok((vm_fds, vcpu_fds))
+/// Makes every hypervisor attached from now on refuse operations that change the VM, so
+/// inspecting a production VM cannot modify it by accident. See `Tracee::set_read_only`.
+pub fn set_read_only() {
+    READ_ONLY.store(true, Ordering::Release);
 }
 
-pub fn get_hypervisor(pid: Pid) -> Result<Hypervisor> {
-    let handle = try_with!(openpid(pid), "cannot open handle in proc");
+/// Set by `allow_encrypted`, applies to all hypervisors attached afterwards.
+static ALLOW_ENCRYPTED: AtomicBool = AtomicBool::new(false);
+
+/// Makes hypervisors accept VMs with encrypted memory (AMD SEV, Intel TDX) instead of refusing
+/// them. They are read-only then, as in `set_read_only`.
+pub fn allow_encrypted() {
+    ALLOW_ENCRYPTED.store(true, Ordering::Release);
+}
 
-    let (vm_fds, mut vcpus) = try_with!(find_vm_fd(&handle), "failed to access kvm fds");
-    if vm_fds.is_empty() {
+/// Attaches to the VM of the hypervisor process `pid`. `selector` is required if the process
+/// hosts more than one VM.
+pub fn get_hypervisor(pid: Pid, selector: Option<VmSelector>) -> Result<Hypervisor> {
+    let vms = try_with!(find_vms(pid), "failed to access kvm fds");
+    if vms.is_empty() {
         bail!("no KVM-VMs found. If this is qemu, does it enable KVM?");
     }
-    if vm_fds.len() > 1 {
-        bail!("multiple VMs found, this is not supported yet.");
-    }
+    let (vm, earlier_vms) = select_vm(vms, selector)?;
+    let mut vcpus = vm.vcpus;
 
-    let tracee = Hypervisor::attach(pid, vm_fds[0]);
+    let mut tracee = Hypervisor::attach(pid, vm.vm_fd);
+    if READ_ONLY.load(Ordering::Acquire) {
+        tracee.set_read_only();
+    }
+    let read_only = tracee.read_only_reason();
     let vcpu_maps = try_with!(tracee.get_vcpu_maps(), "cannot get vcpufd memory maps");
     if vcpus.is_empty() {
         bail!("found KVM instance but no VCPUs");
@@ -613,13 +1271,19 @@ pub fn get_hypervisor(pid: Pid) -> Result<Hypervisor> {
     if vcpu_maps.is_empty() {
         bail!("found VCPUs but no mappings of their fds");
     }
-    VCPU::match_maps(&mut vcpus, &vcpu_maps);
-    Ok(Hypervisor {
+    VCPU::match_maps(&mut vcpus, &vcpu_maps, &earlier_vms);
+    let hv = Hypervisor {
         pid,
         tracee: Arc::new(RwLock::new(tracee)),
-        vm_fd: vm_fds[0],
+        vm_fd: vm.vm_fd,
         vcpus,
         wrapper: Mutex::new(None),
         transfer_ctx: Mutex::new(None),
-    })
+        encryption: Mutex::new(None),
+        ioregionfd: AtomicBool::new(false),
+        protected: AtomicBool::new(false),
+        irqfds: Mutex::new(vec![]),
+    };
+    hv.protect(read_only);
+    Ok(hv)
 }
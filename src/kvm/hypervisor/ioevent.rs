@@ -7,7 +7,6 @@ use vmm_sys_util::eventfd::EventFd;
 use super::ioeventfd::IoEventFd;
 use super::userspaceioeventfd::UserspaceIoEventFd;
 use super::Hypervisor;
-use crate::devices::use_ioregionfd;
 use crate::devices::virtio::{register_ioeventfd, MmioConfig};
 use crate::result::Result;
 use std::ops::Deref;
@@ -27,7 +26,7 @@ impl IoEvent {
         mmio_cfg: &MmioConfig,
         queue_idx: u64,
     ) -> Result<IoEvent> {
-        if use_ioregionfd() {
+        if vmm.use_ioregionfd() {
             let eventfd = try_with!(
                 uioefd.userpace_ioeventfd(Some(queue_idx as u32)),
                 "cannot register userspace ioeventfd"
@@ -38,6 +37,9 @@ impl IoEvent {
                 register_ioeventfd(vmm, mmio_cfg, queue_idx),
                 "cannot register ioeventfd"
             );
+            // KVM handles the notifications of the guest, this is for the ones replayed by vmsh
+            let eventfd = try_with!(ioeventfd.try_clone(), "cannot clone ioeventfd");
+            uioefd.add(Some(queue_idx as u32), eventfd)?;
             Ok(IoEvent::IoEventFd(ioeventfd))
         }
     }
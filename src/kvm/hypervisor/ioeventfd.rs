@@ -1,6 +1,6 @@
 use kvm_bindings as kvmb;
 use log::*;
-use simple_error::{bail, try_with};
+use simple_error::try_with;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::prelude::RawFd;
 use std::sync::{Arc, RwLock};
@@ -10,7 +10,8 @@ use super::memory::HvMem;
 use super::Hypervisor;
 use crate::kvm::ioctls;
 use crate::kvm::tracee::Tracee;
-use crate::result::Result;
+use crate::result::{Result, VmshError};
+use crate::tracer::inject_syscall::SyscallBatch;
 use std::ops::Deref;
 
 pub struct IoEventFd {
@@ -75,7 +76,7 @@ impl IoEventFd {
             )
         };
         if ret != 0 {
-            bail!("cannot register KVM_IOEVENTFD via ioctl: {:?}", ret);
+            return Err(VmshError::kvm_ioctl("KVM_IOEVENTFD", ret));
         }
 
         Ok(IoEventFd {
@@ -111,12 +112,24 @@ impl Drop for IoEventFd {
             return;
         }
 
-        if let Err(e) = tracee.vm_ioctl_with_ref(ioctls::KVM_IOEVENTFD(), &self.hv_mem) {
-            warn!("IoEventfd: kvm ioeventfd ioctl injection failed: {}", e)
-        }
-
-        if let Err(e) = tracee.close(self.hv_eventfd) {
-            warn!("IoEventfd: failed to close eventfd in hypervisor: {}", e)
+        // deassign and close in one stop of the hypervisor
+        let mut batch = SyscallBatch::new();
+        tracee.batch_vm_ioctl_with_ref(&mut batch, ioctls::KVM_IOEVENTFD(), &self.hv_mem);
+        batch.close(self.hv_eventfd);
+        match tracee.run_batch(&batch).as_deref() {
+            Err(e) => warn!("IoEventfd: kvm ioeventfd ioctl injection failed: {}", e),
+            Ok([ioctl, close]) => {
+                if *ioctl != 0 {
+                    warn!("IoEventfd: kvm ioeventfd deassign failed: {}", ioctl);
+                }
+                if *close != 0 {
+                    warn!(
+                        "IoEventfd: failed to close eventfd in hypervisor: {}",
+                        close
+                    );
+                }
+            }
+            Ok(rets) => warn!("IoEventfd: unexpected batch results: {:?}", rets),
         }
     }
 }
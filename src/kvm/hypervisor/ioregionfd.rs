@@ -1,3 +1,4 @@
+use libc::c_int;
 use log::*;
 use nix::poll::{ppoll, PollFd, PollFlags};
 use nix::sys::socket::{socketpair, AddressFamily, SockFlag, SockType};
@@ -17,7 +18,8 @@ use crate::kvm::ioctls;
 use crate::kvm::kvm_ioregionfd::kvm_ioregion;
 use crate::kvm::kvm_ioregionfd::{self, ioregionfd_cmd, ioregionfd_resp};
 use crate::kvm::tracee::Tracee;
-use crate::result::Result;
+use crate::result::{Result, VmshError};
+use crate::tracer::inject_syscall::SyscallBatch;
 
 /// Implements the KVM IoRegionFd feature.
 pub struct IoRegionFd {
@@ -34,7 +36,7 @@ pub struct IoRegionFd {
 
 impl IoRegionFd {
     pub fn new(hv: &Hypervisor, guest_paddr: u64, len: usize) -> Result<Self> {
-        if Self::capability_present(hv)? {
+        if !Self::capability_present(hv)? {
             bail!("This operation requires KVM_CAP_IOREGIONFD which your KVM does not have.");
         }
 
@@ -58,22 +60,28 @@ impl IoRegionFd {
         );
         let hv_rf_hv = hv.transfer(vec![rf_hv.as_raw_fd()].as_slice())?[0];
         let hv_wf_hv = hv.transfer(vec![wf_hv.as_raw_fd()].as_slice())?[0];
-        let ioregion = kvm_ioregion::new(guest_paddr, len, hv_rf_hv, hv_wf_hv);
         let mem = hv.alloc_mem()?;
-        mem.write(&ioregion)?;
 
-        let ret = {
-            let tracee = try_with!(
-                hv.tracee.read(),
-                "cannot obtain tracee read lock: poinsoned"
+        // Posted writes let the vcpu continue without waiting for us to handle queue
+        // notifications. Kernels that do not know the flag reject it.
+        let mut ioregion = kvm_ioregion::new(
+            guest_paddr,
+            len,
+            hv_rf_hv,
+            hv_wf_hv,
+            kvm_ioregionfd::KVM_IOREGION_POSTED_WRITES,
+        );
+        let mut ret = Self::set_ioregion(hv, &mem, &ioregion)?;
+        if ret != 0 {
+            info!(
+                "ioregionfd does not support posted writes ({}), acknowledge every write",
+                ret
             );
-            try_with!(
-                tracee.vm_ioctl_with_ref(ioctls::KVM_SET_IOREGION(), &mem),
-                "kvm ioeventfd ioctl injection failed"
-            )
-        };
+            ioregion.flags = 0;
+            ret = Self::set_ioregion(hv, &mem, &ioregion)?;
+        }
         if ret != 0 {
-            bail!("ioregionfd ioctl failed with {}", ret);
+            return Err(VmshError::kvm_ioctl("KVM_SET_IOREGION", ret));
         }
 
         Ok(IoRegionFd {
@@ -89,6 +97,22 @@ impl IoRegionFd {
         })
     }
 
+    fn set_ioregion(
+        hv: &Hypervisor,
+        mem: &HvMem<kvm_ioregion>,
+        ioregion: &kvm_ioregion,
+    ) -> Result<c_int> {
+        mem.write(ioregion)?;
+        let tracee = try_with!(
+            hv.tracee.read(),
+            "cannot obtain tracee read lock: poinsoned"
+        );
+        Ok(try_with!(
+            tracee.vm_ioctl_with_ref(ioctls::KVM_SET_IOREGION(), mem),
+            "kvm ioeventfd ioctl injection failed"
+        ))
+    }
+
     pub fn fdclone(&mut self) -> RawIoRegionFd {
         let pollfds = vec![PollFd::new(self.wfile, PollFlags::POLLIN)];
         RawIoRegionFd {
@@ -104,7 +128,7 @@ impl IoRegionFd {
             hv.check_extension(kvm_ioregionfd::KVM_CAP_IOREGIONFD as i32),
             "cannot check kvm extension capabilities"
         );
-        Ok(has_cap == 0)
+        Ok(has_cap > 0)
     }
 }
 
@@ -130,37 +154,33 @@ impl Drop for IoRegionFd {
             return;
         }
 
-        match tracee.vm_ioctl_with_ref(ioctls::KVM_SET_IOREGION(), &self.hv_mem) {
+        // remove the region and close both fds in one stop of the hypervisor
+        let mut batch = SyscallBatch::new();
+        tracee.batch_vm_ioctl_with_ref(&mut batch, ioctls::KVM_SET_IOREGION(), &self.hv_mem);
+        batch.close(self.hv_rf_hv).close(self.hv_wf_hv);
+        match tracee.run_batch(&batch).as_deref() {
             Err(e) => warn!("IoRegionFd: kvm ioregionfd ioctl injection failed: {}", e),
-            Ok(ret) => {
-                if ret != 0 {
-                    warn!("IoRegionFd: kvm ioregionfd remove syscall failed: {}", ret);
+            Ok([ioctl, close_rf, close_wf]) => {
+                if *ioctl != 0 {
+                    warn!(
+                        "IoRegionFd: kvm ioregionfd remove syscall failed: {}",
+                        ioctl
+                    );
                 }
-            }
-        }
-
-        match tracee.close(self.hv_rf_hv) {
-            Err(e) => warn!("IoRegionFd: close injection failed: {}", e),
-            Ok(ret) => {
-                if ret != 0 {
+                if *close_rf != 0 {
                     warn!(
                         "IoRegionFd: failed to close hv_rf_hv in hypervisor: {}",
-                        ret
+                        close_rf
                     )
                 }
-            }
-        }
-
-        match tracee.close(self.hv_wf_hv) {
-            Err(e) => warn!("IoRegionFd: close injection failed: {}", e),
-            Ok(ret) => {
-                if ret != 0 {
+                if *close_wf != 0 {
                     warn!(
                         "IoRegionFd: failed to close hv_wf_hv in hypervisor: {}",
-                        ret
+                        close_wf
                     )
                 }
             }
+            Ok(rets) => warn!("IoRegionFd: unexpected batch results: {:?}", rets),
         }
 
         if let Err(e) = close(self.rf_hv) {
@@ -193,13 +213,22 @@ pub struct RawIoRegionFd {
 impl RawIoRegionFd {
     /// receive read and write events/commands
     pub fn read(&mut self) -> Result<Option<ioregionfd_cmd>> {
+        self.read_timeout(Duration::from_millis(300))
+    }
+
+    /// receive a command only if one is pending already
+    pub fn try_read(&mut self) -> Result<Option<ioregionfd_cmd>> {
+        self.read_timeout(Duration::from_millis(0))
+    }
+
+    fn read_timeout(&mut self, timeout: Duration) -> Result<Option<ioregionfd_cmd>> {
         let len = size_of::<ioregionfd_cmd>();
         let mut t_mem = MaybeUninit::<ioregionfd_cmd>::uninit();
         // safe, because slice.len() == len
         let t_slice = unsafe { std::slice::from_raw_parts_mut(t_mem.as_mut_ptr() as *mut u8, len) };
 
         // read
-        let timeout = TimeSpec::from(Duration::from_millis(300));
+        let timeout = TimeSpec::from(timeout);
         let nr_events = try_with!(
             ppoll(&mut self.pollfds, Some(timeout), None),
             "read/ppoll failed"
@@ -2,11 +2,14 @@ use crate::page_table::PhysAddr;
 use kvm_bindings as kvmb;
 use libc::c_void;
 use log::*;
+use nix::sys::uio::{process_vm_readv, process_vm_writev, RemoteIoVec};
 use nix::unistd::Pid;
-use simple_error::simple_error;
+use serde::{Deserialize, Serialize};
+use simple_error::{bail, simple_error, try_with};
+use std::io::{IoSlice, IoSliceMut};
 use std::marker::PhantomData;
-use std::mem::size_of;
-use std::sync::{Arc, RwLock};
+use std::ops::Range;
+use std::sync::{Arc, Mutex, RwLock};
 use vm_memory::remote_mem;
 
 use crate::kvm::ioctls;
@@ -14,11 +17,152 @@ use crate::kvm::tracee::Tracee;
 use crate::result::Result;
 
 pub fn process_read<T: Sized + Copy>(pid: Pid, addr: *const c_void) -> Result<T> {
-    remote_mem::process_read(pid, addr).map_err(|e| simple_error!("{}", e))
+    remote_mem::process_read(pid, addr).map_err(|e| simple_error!("{}", e).into())
+}
+
+/// Hypervisors in read-only mode with the reason, see `protect`.
+static PROTECTED: Mutex<Vec<(Pid, &'static str)>> = Mutex::new(Vec::new());
+/// Memory vmsh mapped into hypervisors itself, i.e. for ioctl arguments. It is not part of the
+/// VM, so it stays writable in read-only mode.
+static SCRATCH: Mutex<Vec<(Pid, Range<usize>)>> = Mutex::new(Vec::new());
+
+/// Makes writes to the memory of the hypervisor `pid` fail, except for the memory vmsh mapped
+/// into it (see `add_scratch`), until `unprotect` is called. `reason` ends the error message.
+pub fn protect(pid: Pid, reason: &'static str) {
+    let mut protected = PROTECTED.lock().unwrap_or_else(|e| e.into_inner());
+    protected.push((pid, reason));
+}
+
+/// Undoes one `protect` of `pid`.
+pub fn unprotect(pid: Pid) {
+    let mut protected = PROTECTED.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(idx) = protected.iter().position(|(p, _)| *p == pid) {
+        protected.remove(idx);
+    }
+}
+
+/// Why the memory of `pid` must not be written, if it is protected.
+pub fn protected(pid: Pid) -> Option<&'static str> {
+    let protected = PROTECTED.lock().unwrap_or_else(|e| e.into_inner());
+    protected.iter().find(|(p, _)| *p == pid).map(|(_, r)| *r)
+}
+
+/// Records that vmsh mapped `len` bytes at `addr` into `pid`, see `protect`.
+pub fn add_scratch(pid: Pid, addr: usize, len: usize) {
+    let mut scratch = SCRATCH.lock().unwrap_or_else(|e| e.into_inner());
+    scratch.push((pid, addr..addr.saturating_add(len)));
+}
+
+/// Forgets the mapping at `addr` recorded with `add_scratch`.
+pub fn remove_scratch(pid: Pid, addr: usize) {
+    let mut scratch = SCRATCH.lock().unwrap_or_else(|e| e.into_inner());
+    scratch.retain(|(p, range)| *p != pid || range.start != addr);
+}
+
+/// Fails if the `len` bytes at `addr` of the hypervisor `pid` must not be written. All writes to
+/// hypervisor memory go through this check.
+pub fn check_write(pid: Pid, addr: usize, len: usize) -> Result<()> {
+    let reason = match protected(pid) {
+        Some(reason) => reason,
+        None => return Ok(()),
+    };
+    let end = addr.saturating_add(len);
+    let scratch = SCRATCH.lock().unwrap_or_else(|e| e.into_inner());
+    if scratch
+        .iter()
+        .any(|(p, range)| *p == pid && range.start <= addr && end <= range.end)
+    {
+        return Ok(());
+    }
+    bail!(
+        "writing {} bytes of hypervisor memory at {:#x} is not allowed {}",
+        len,
+        addr,
+        reason
+    );
 }
 
 pub fn process_write<T: Sized + Copy>(pid: Pid, addr: *mut c_void, val: &T) -> Result<()> {
-    remote_mem::process_write(pid, addr, val).map_err(|e| simple_error!("{}", e))
+    check_write(pid, addr as usize, std::mem::size_of::<T>())?;
+    remote_mem::process_write(pid, addr, val).map_err(|e| simple_error!("{}", e).into())
+}
+
+/// Number of iovecs passed to a single process_vm_readv/writev call (must not exceed IOV_MAX)
+const IOV_BATCH: usize = 512;
+
+/// Writes each buffer of `writes` to the hypervisor address paired with it. Needs one
+/// process_vm_writev per `IOV_BATCH` buffers rather than one per buffer.
+pub fn process_write_vectored(pid: Pid, writes: &[(usize, &[u8])]) -> Result<()> {
+    for (addr, buf) in writes {
+        check_write(pid, *addr, buf.len())?;
+    }
+    for batch in writes.chunks(IOV_BATCH) {
+        let local = batch
+            .iter()
+            .map(|(_, buf)| IoSlice::new(buf))
+            .collect::<Vec<_>>();
+        let remote = batch
+            .iter()
+            .map(|(addr, buf)| RemoteIoVec {
+                base: *addr,
+                len: buf.len(),
+            })
+            .collect::<Vec<_>>();
+        let len = remote.iter().map(|iov| iov.len).sum::<usize>();
+        let written = try_with!(
+            process_vm_writev(pid, &local, &remote),
+            "cannot write to process"
+        );
+        if written != len {
+            bail!("short write, expected {}, written: {}", len, written);
+        }
+    }
+    Ok(())
+}
+
+/// Writes `data` spread over `iovs` of the hypervisor, which have to cover it exactly.
+pub fn process_write_scattered(pid: Pid, data: &[u8], iovs: &[RemoteIoVec]) -> Result<()> {
+    let mut writes = Vec::with_capacity(iovs.len());
+    let mut rest = data;
+    for iov in iovs {
+        if iov.len > rest.len() {
+            bail!("iovecs exceed the {} bytes to write", data.len());
+        }
+        let (buf, tail) = rest.split_at(iov.len);
+        writes.push((iov.base, buf));
+        rest = tail;
+    }
+    if !rest.is_empty() {
+        bail!("iovecs do not cover the {} bytes to write", data.len());
+    }
+    process_write_vectored(pid, &writes)
+}
+
+/// Fills each buffer of `reads` from the hypervisor address paired with it, see
+/// `process_write_vectored`.
+pub fn process_read_vectored(pid: Pid, reads: &mut [(usize, &mut [u8])]) -> Result<()> {
+    for batch in reads.chunks_mut(IOV_BATCH) {
+        let remote = batch
+            .iter()
+            .map(|(addr, buf)| RemoteIoVec {
+                base: *addr,
+                len: buf.len(),
+            })
+            .collect::<Vec<_>>();
+        let len = remote.iter().map(|iov| iov.len).sum::<usize>();
+        let mut local = batch
+            .iter_mut()
+            .map(|(_, buf)| IoSliceMut::new(buf))
+            .collect::<Vec<_>>();
+        let read = try_with!(
+            process_vm_readv(pid, &mut local, &remote),
+            "cannot read from process"
+        );
+        if read != len {
+            bail!("short read, expected {}, read: {}", len, read);
+        }
+    }
+    Ok(())
 }
 
 #[derive(Debug)]
@@ -41,6 +185,8 @@ unsafe impl<T> Sync for SendPhantom<T> {}
 #[derive(Debug)]
 pub struct HvMem<T: Copy> {
     pub ptr: libc::uintptr_t,
+    /// Size of the mapping, at least `size_of::<T>()`
+    pub(super) size: usize,
     pub(super) pid: Pid,
     pub(super) tracee: Arc<RwLock<Tracee>>,
     #[allow(dead_code)]
@@ -59,19 +205,72 @@ impl<T: Copy> Drop for HvMem<T> {
             }
             Ok(t) => t,
         };
-        if let Err(e) = tracee.munmap(self.ptr as *mut c_void, size_of::<T>()) {
+        if let Err(e) = tracee.munmap(self.ptr as *mut c_void, self.size) {
             warn!("failed to unmap memory from process: {}", e);
         }
     }
 }
 
 impl<T: Copy> HvMem<T> {
+    /// Size of the mapping in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
     pub fn read(&self) -> Result<T> {
         process_read(self.pid, self.ptr as *mut c_void)
     }
     pub fn write(&self, val: &T) -> Result<()> {
         process_write(self.pid, self.ptr as *mut c_void, val)
     }
+    /// Reads `buf.len()` bytes from the start of the mapping, which may exceed `T`.
+    pub fn read_bytes(&self, buf: &mut [u8]) -> Result<()> {
+        if buf.len() > self.size {
+            bail!(
+                "cannot read {} bytes from hypervisor memory of {} bytes",
+                buf.len(),
+                self.size
+            );
+        }
+        remote_mem::process_read_bytes(self.pid, buf, self.ptr as *const c_void)
+            .map_err(|e| simple_error!("{}", e).into())
+    }
+
+    /// Writes `buf` to the start of the mapping, which may exceed `T`.
+    pub fn write_bytes(&self, buf: &[u8]) -> Result<()> {
+        self.write_vectored(&[(0, buf)])
+    }
+
+    /// Writes each buffer of `writes` at the offset into the mapping paired with it.
+    pub fn write_vectored(&self, writes: &[(usize, &[u8])]) -> Result<()> {
+        let writes = writes
+            .iter()
+            .map(|(offset, buf)| Ok((self.remote_addr(*offset, buf.len())?, *buf)))
+            .collect::<Result<Vec<_>>>()?;
+        process_write_vectored(self.pid, &writes)
+    }
+
+    /// Fills each buffer of `reads` from the offset into the mapping paired with it.
+    pub fn read_vectored(&self, reads: &mut [(usize, &mut [u8])]) -> Result<()> {
+        let mut reads = reads
+            .iter_mut()
+            .map(|(offset, buf)| Ok((self.remote_addr(*offset, buf.len())?, &mut **buf)))
+            .collect::<Result<Vec<_>>>()?;
+        process_read_vectored(self.pid, &mut reads)
+    }
+
+    /// Address of `len` bytes at `offset` in the hypervisor, if they are within the mapping.
+    fn remote_addr(&self, offset: usize, len: usize) -> Result<usize> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.size => Ok(self.ptr + offset),
+            _ => bail!(
+                "cannot access {} bytes at offset {} of hypervisor memory of {} bytes",
+                len,
+                offset,
+                self.size
+            ),
+        }
+    }
 }
 
 /// Physical Memory attached to a VM. Backed by `PhysMem.mem`.
@@ -82,6 +281,31 @@ pub struct PhysMem<T: Copy> {
     pub guest_phys_addr: PhysAddr,
 }
 
+/// A `PhysMem` left in the VM when vmsh detaches, so that a later vmsh process can remove it
+/// with `Hypervisor::reclaim_mem`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeptPhysMem {
+    /// Hypervisor address of the memory
+    pub ptr: usize,
+    pub size: usize,
+    /// Hypervisor address of the kvm_userspace_memory_region the memslot was added with
+    pub ioctl_arg: usize,
+    pub guest_phys_addr: usize,
+}
+
+impl<T: Copy> PhysMem<T> {
+    /// Describes the memory for `Hypervisor::reclaim_mem`. The memory must not be dropped
+    /// afterwards, i.e. with `std::mem::forget`.
+    pub fn to_kept(&self) -> KeptPhysMem {
+        KeptPhysMem {
+            ptr: self.mem.ptr,
+            size: self.mem.size,
+            ioctl_arg: self.ioctl_arg.ptr,
+            guest_phys_addr: self.guest_phys_addr.value,
+        }
+    }
+}
+
 impl<T: Copy> Drop for PhysMem<T> {
     fn drop(&mut self) {
         // useful for debugging
@@ -126,3 +350,58 @@ impl<T: Copy> Drop for PhysMem<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vectored_access() {
+        let pid = nix::unistd::getpid();
+        let src = (0..3 * IOV_BATCH).map(|i| i as u8).collect::<Vec<_>>();
+        let mut dst = vec![0u8; src.len()];
+        let base = dst.as_mut_ptr() as usize;
+        // more buffers than fit into one process_vm_writev call
+        let writes = src
+            .iter()
+            .enumerate()
+            .map(|(i, s)| (base + i, std::slice::from_ref(s)))
+            .collect::<Vec<_>>();
+        process_write_vectored(pid, &writes).expect("cannot write");
+        assert_eq!(dst, src);
+
+        let mut first = [0u8; 2];
+        let mut second = [0u8; 3];
+        let mut reads = [
+            (src.as_ptr() as usize, &mut first[..]),
+            (src[10..].as_ptr() as usize, &mut second[..]),
+        ];
+        process_read_vectored(pid, &mut reads).expect("cannot read");
+        assert_eq!(first, [0, 1]);
+        assert_eq!(second, [10, 11, 12]);
+    }
+
+    #[test]
+    fn read_only() {
+        // no process has this pid, the check comes before the write
+        let pid = Pid::from_raw(i32::MAX);
+        let val = 0u64;
+        protect(pid, "in read-only mode");
+        let err = process_write(pid, 0x1000 as *mut c_void, &val)
+            .expect_err("protected hypervisor was written to");
+        assert!(err.to_string().contains("read-only"), "{}", err);
+        assert!(process_write_vectored(pid, &[(0x1000, &[0u8][..])])
+            .expect_err("protected hypervisor was written to")
+            .to_string()
+            .contains("read-only"));
+
+        add_scratch(pid, 0x1000, 0x1000);
+        assert!(check_write(pid, 0x1000, 8).is_ok());
+        assert!(check_write(pid, 0x1ff8, 16).is_err());
+        remove_scratch(pid, 0x1000);
+        assert!(check_write(pid, 0x1000, 8).is_err());
+
+        unprotect(pid);
+        assert!(check_write(pid, 0x1000, 8).is_ok());
+    }
+}
@@ -1,9 +1,14 @@
+pub mod encryption;
+pub mod flavor;
 #[allow(clippy::module_inception)]
 pub mod hypervisor;
 pub mod ioevent;
 pub mod ioeventfd;
 pub mod ioregionfd;
 pub mod memory;
+pub mod nested;
+pub mod shared_ram;
+pub mod userfaultfd;
 pub mod userspaceioeventfd;
 
 pub use self::hypervisor::*;
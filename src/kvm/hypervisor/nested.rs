@@ -0,0 +1,108 @@
+//! Nested virtualization: the guest (L1) runs VMs of its own (L2). While a vcpu runs L2, KVM
+//! returns the registers of L2, so its page tables and instruction pointer are not the ones of
+//! the guest kernel vmsh attaches to.
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NestedFormat {
+    Vmx,
+    Svm,
+}
+
+/// Summary of kvm_nested_state, see `Hypervisor::get_nested_state`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NestedState {
+    pub format: NestedFormat,
+    /// The vcpu runs L2
+    pub guest_mode: bool,
+    /// L1 runs a hypervisor (VMX: executed VMXON, SVM: has entered L2 before)
+    pub hypervisor: bool,
+    /// Guest physical address of the vmcs12/vmcb12 L1 uses for L2
+    pub control_block: Option<u64>,
+}
+
+const KVM_STATE_NESTED_FORMAT_VMX: u16 = 0;
+const KVM_STATE_NESTED_FORMAT_SVM: u16 = 1;
+const KVM_STATE_NESTED_GUEST_MODE: u16 = 1;
+
+/// Size of the kvm_nested_state header, more state follows once L1 used nested virtualization
+const HEADER_SIZE: u32 = 128;
+
+impl NestedState {
+    /// Parses the header of kvm_nested_state. `hdr` are the first words of the vmx or svm
+    /// header. Returns None for unknown formats.
+    pub fn from_header(flags: u16, format: u16, size: u32, hdr: &[u64]) -> Option<NestedState> {
+        let guest_mode = flags & KVM_STATE_NESTED_GUEST_MODE != 0;
+        match format {
+            KVM_STATE_NESTED_FORMAT_VMX => {
+                // vmxon_pa and vmcs12_pa are -1 if unset
+                let vmxon = hdr.first().copied().unwrap_or(u64::MAX);
+                let vmcs12 = hdr.get(1).copied().unwrap_or(u64::MAX);
+                Some(NestedState {
+                    format: NestedFormat::Vmx,
+                    guest_mode,
+                    hypervisor: vmxon != u64::MAX,
+                    control_block: Some(vmcs12).filter(|pa| *pa != u64::MAX),
+                })
+            }
+            KVM_STATE_NESTED_FORMAT_SVM => {
+                let vmcb = hdr.first().copied().unwrap_or(0);
+                Some(NestedState {
+                    format: NestedFormat::Svm,
+                    guest_mode,
+                    hypervisor: guest_mode || size > HEADER_SIZE,
+                    control_block: Some(vmcb).filter(|pa| *pa != 0),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for NestedState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.hypervisor {
+            return write!(f, "no nested guests");
+        }
+        let block = match self.format {
+            NestedFormat::Vmx => "vmcs12",
+            NestedFormat::Svm => "vmcb12",
+        };
+        if self.guest_mode {
+            write!(f, "runs a nested guest (L2)")?;
+        } else {
+            write!(f, "runs a hypervisor (L1)")?;
+        }
+        if let Some(pa) = self.control_block {
+            write!(f, ", {} at {:#x}", block, pa)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header() {
+        let none = NestedState::from_header(0, 0, 128, &[u64::MAX, u64::MAX]).unwrap();
+        assert!(!none.hypervisor);
+        assert!(!none.guest_mode);
+        assert_eq!(none.control_block, None);
+
+        let l2 = NestedState::from_header(1, 0, 8320, &[0x1000, 0x2000]).unwrap();
+        assert!(l2.hypervisor);
+        assert!(l2.guest_mode);
+        assert_eq!(l2.control_block, Some(0x2000));
+        assert_eq!(l2.to_string(), "runs a nested guest (L2), vmcs12 at 0x2000");
+
+        let svm = NestedState::from_header(0, 1, 4224, &[0x3000]).unwrap();
+        assert_eq!(svm.format, NestedFormat::Svm);
+        assert!(svm.hypervisor);
+        assert!(!svm.guest_mode);
+
+        assert!(NestedState::from_header(0, 7, 128, &[]).is_none());
+    }
+}
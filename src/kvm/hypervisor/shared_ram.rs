@@ -0,0 +1,284 @@
+//! Maps the guest RAM of the hypervisor into vmsh, so that devices can copy guest buffers with
+//! plain memory accesses instead of one process_vm_readv/writev per descriptor.
+//!
+//! Only memslots that the hypervisor backs with a shared file mapping (memfd, hugetlbfs, shm or a
+//! regular file) can be mapped a second time: we open the backing file through
+//! `/proc/<pid>/map_files` and map the same pages. Anonymous memory is private to the
+//! hypervisor, accesses to it keep going through the remote iovecs (see
+//! `memory::process_read_vectored`).
+
+use libc::c_void;
+use log::{debug, info, warn};
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+use nix::sys::uio::RemoteIoVec;
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::fs::OpenOptions;
+use std::num::NonZeroUsize;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+
+use crate::kvm::hypervisor::memory::{self, process_read_vectored, process_write_vectored};
+use crate::kvm::memslots::fetch_mappings;
+use crate::result::Result;
+use crate::tracer::proc::{self, Mapping};
+
+/// A memslot mapped into vmsh
+struct Region {
+    /// start of the memslot in the hypervisor
+    hv_start: usize,
+    len: usize,
+    ptr: *mut c_void,
+}
+
+pub struct SharedRam {
+    regions: Vec<Region>,
+}
+
+// The mapping itself is never changed after it was created, guest buffers are accessed with
+// the same care as through process_vm_readv/writev.
+unsafe impl Send for SharedRam {}
+unsafe impl Sync for SharedRam {}
+
+fn map_slot(pid: Pid, vmas: &[Mapping], slot: &Mapping, writable: bool) -> Result<Region> {
+    let vma = require_with!(
+        proc::find_mapping(vmas, slot.start),
+        "no mapping of memslot {} found",
+        slot.slot
+    );
+    if !vma.map_flags.contains(MapFlags::MAP_SHARED) || vma.inode == 0 {
+        bail!("memslot {} is backed by private memory", slot.slot);
+    }
+    if slot.end > vma.end {
+        bail!("memslot {} spans multiple mappings", slot.slot);
+    }
+    let path = format!("/proc/{}/map_files/{:x}-{:x}", pid, vma.start, vma.end);
+    let file = try_with!(
+        OpenOptions::new().read(true).write(writable).open(&path),
+        "cannot open {}",
+        path
+    );
+    let len = require_with!(NonZeroUsize::new(slot.size()), "memslot is empty");
+    let offset = vma.offset + (slot.start - vma.start) as u64;
+    let prot = if writable {
+        ProtFlags::PROT_READ | ProtFlags::PROT_WRITE
+    } else {
+        ProtFlags::PROT_READ
+    };
+    let ptr = try_with!(
+        unsafe {
+            mmap(
+                None,
+                len,
+                prot,
+                MapFlags::MAP_SHARED,
+                file.as_raw_fd(),
+                offset as libc::off_t,
+            )
+        },
+        "cannot mmap {}",
+        path
+    );
+    Ok(Region {
+        hv_start: slot.start,
+        len: len.get(),
+        ptr,
+    })
+}
+
+impl SharedRam {
+    /// A mapping that covers nothing, all accesses fall back to the remote iovecs.
+    pub fn empty() -> SharedRam {
+        SharedRam { regions: vec![] }
+    }
+
+    /// Maps all memslots in `slots` (as returned by `Hypervisor::get_maps`) that can be shared.
+    /// Never fails, memslots that cannot be mapped are left out. The memory of hypervisors in
+    /// read-only mode is mapped read-only.
+    pub fn map(pid: Pid, slots: &[Mapping]) -> SharedRam {
+        let vmas = match fetch_mappings(pid) {
+            Ok(vmas) => vmas,
+            Err(e) => {
+                warn!("cannot share guest memory: {}", e);
+                return SharedRam::empty();
+            }
+        };
+        let writable = memory::protected(pid).is_none();
+        let mut regions = vec![];
+        for slot in slots {
+            match map_slot(pid, &vmas, slot, writable) {
+                Ok(region) => regions.push(region),
+                Err(e) => debug!("access guest memory remotely: {}", e),
+            }
+        }
+        info!(
+            "mapped {} of {} memslots of the hypervisor",
+            regions.len(),
+            slots.len()
+        );
+        SharedRam { regions }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    /// Returns where `len` bytes at the hypervisor address `addr` are mapped in vmsh, if they
+    /// are mapped at all.
+    pub fn local_ptr(&self, addr: usize, len: usize) -> Option<*mut u8> {
+        let end = addr.checked_add(len)?;
+        self.regions
+            .iter()
+            .find(|r| r.hv_start <= addr && end <= r.hv_start + r.len)
+            .map(|r| unsafe { (r.ptr as *mut u8).add(addr - r.hv_start) })
+    }
+
+    /// Returns the `len` bytes at the hypervisor address `addr` if they are mapped.
+    ///
+    /// # Safety
+    ///
+    /// The guest may modify the memory while the slice is alive.
+    pub unsafe fn slice(&self, addr: usize, len: usize) -> Option<&[u8]> {
+        self.local_ptr(addr, len)
+            .map(|ptr| std::slice::from_raw_parts(ptr as *const u8, len))
+    }
+
+    /// Fills each buffer of `reads` from the hypervisor address paired with it. Mapped memory is
+    /// copied directly, everything else is read with process_vm_readv.
+    pub fn read_vectored(&self, pid: Pid, reads: &mut [(usize, &mut [u8])]) -> Result<()> {
+        let mut remote = vec![];
+        for (addr, buf) in reads.iter_mut() {
+            match self.local_ptr(*addr, buf.len()) {
+                Some(ptr) => unsafe { ptr::copy_nonoverlapping(ptr, buf.as_mut_ptr(), buf.len()) },
+                None => remote.push((*addr, &mut **buf)),
+            }
+        }
+        process_read_vectored(pid, &mut remote)
+    }
+
+    /// Writes each buffer of `writes` to the hypervisor address paired with it, see
+    /// `read_vectored`.
+    pub fn write_vectored(&self, pid: Pid, writes: &[(usize, &[u8])]) -> Result<()> {
+        let mut remote = vec![];
+        for (addr, buf) in writes {
+            memory::check_write(pid, *addr, buf.len())?;
+            match self.local_ptr(*addr, buf.len()) {
+                Some(ptr) => unsafe { ptr::copy_nonoverlapping(buf.as_ptr(), ptr, buf.len()) },
+                None => remote.push((*addr, *buf)),
+            }
+        }
+        process_write_vectored(pid, &remote)
+    }
+
+    /// Translates all of `iovs` or returns `None` if any of them is not mapped.
+    pub fn local_iovs(&self, iovs: &[RemoteIoVec]) -> Option<Vec<(*mut u8, usize)>> {
+        if self.is_empty() {
+            return None;
+        }
+        iovs.iter()
+            .map(|iov| self.local_ptr(iov.base, iov.len).map(|ptr| (ptr, iov.len)))
+            .collect()
+    }
+}
+
+impl Drop for SharedRam {
+    fn drop(&mut self) {
+        for region in &self.regions {
+            if let Err(e) = unsafe { munmap(region.ptr, region.len) } {
+                warn!("cannot unmap guest memory: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translate_iovs() {
+        let len = NonZeroUsize::new(0x2000).unwrap();
+        let ptr = unsafe {
+            mmap(
+                None,
+                len,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        }
+        .unwrap();
+        let ram = SharedRam {
+            regions: vec![Region {
+                hv_start: 0x10000,
+                len: len.get(),
+                ptr,
+            }],
+        };
+        let local = ptr as *mut u8;
+        assert_eq!(ram.local_ptr(0x10000, 0x2000), Some(local));
+        assert_eq!(
+            ram.local_ptr(0x11000, 0x10),
+            Some(local.wrapping_add(0x1000))
+        );
+        assert_eq!(ram.local_ptr(0x11000, 0x1001), None);
+        assert_eq!(ram.local_ptr(0xf000, 0x10), None);
+
+        let iovs = [
+            RemoteIoVec {
+                base: 0x10100,
+                len: 0x100,
+            },
+            RemoteIoVec {
+                base: 0x11f00,
+                len: 0x100,
+            },
+        ];
+        assert_eq!(
+            ram.local_iovs(&iovs),
+            Some(vec![
+                (local.wrapping_add(0x100), 0x100),
+                (local.wrapping_add(0x1f00), 0x100)
+            ])
+        );
+        assert_eq!(SharedRam::empty().local_iovs(&iovs), None);
+    }
+
+    #[test]
+    fn vectored_fallback() {
+        let len = NonZeroUsize::new(0x1000).unwrap();
+        let ptr = unsafe {
+            mmap(
+                None,
+                len,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        }
+        .unwrap();
+        // pretend the mapping lives at another address in the "hypervisor"
+        let ram = SharedRam {
+            regions: vec![Region {
+                hv_start: 0x10000,
+                len: len.get(),
+                ptr,
+            }],
+        };
+        let mut unmapped = [0u8; 4];
+        let pid = nix::unistd::getpid();
+        let unmapped_addr = unmapped.as_mut_ptr() as usize;
+        ram.write_vectored(pid, &[(0x10010, &[1, 2]), (unmapped_addr, &[3, 4, 5, 6])])
+            .unwrap();
+        assert_eq!(unmapped, [3, 4, 5, 6]);
+
+        let mut a = [0u8; 2];
+        let mut b = [0u8; 4];
+        ram.read_vectored(pid, &mut [(0x10010, &mut a), (unmapped_addr, &mut b)])
+            .unwrap();
+        assert_eq!(a, [1, 2]);
+        assert_eq!(b, [3, 4, 5, 6]);
+    }
+}
@@ -0,0 +1,289 @@
+use libc::c_int;
+use log::*;
+use nix::poll::{poll, PollFd, PollFlags};
+use simple_error::{bail, try_with};
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem::{size_of, MaybeUninit};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::slice;
+
+use super::Hypervisor;
+use crate::page_math::{page_size, page_start};
+use crate::result::Result;
+
+const UFFD_API: u64 = 0xAA;
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+const UFFD_PAGEFAULT_FLAG_WRITE: u64 = 1 << 0;
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1 << 0;
+
+// _IOWR(0xAA, 0x3F, struct uffdio_api)
+const UFFDIO_API: libc::c_ulong = 0xc018aa3f;
+// _IOWR(0xAA, 0x00, struct uffdio_register)
+const UFFDIO_REGISTER: libc::c_ulong = 0xc020aa00;
+// _IOR(0xAA, 0x01, struct uffdio_range)
+const UFFDIO_UNREGISTER: libc::c_ulong = 0x8010aa01;
+// _IOWR(0xAA, 0x03, struct uffdio_copy)
+const UFFDIO_COPY: libc::c_ulong = 0xc028aa03;
+// _IOWR(0xAA, 0x04, struct uffdio_zeropage)
+const UFFDIO_ZEROPAGE: libc::c_ulong = 0xc020aa04;
+
+#[repr(C)]
+#[allow(dead_code)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct UffdioRegister {
+    range: UffdioRange,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct UffdioCopy {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    copy: i64,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct UffdioZeropage {
+    range: UffdioRange,
+    mode: u64,
+    zeropage: i64,
+}
+
+/// struct uffd_msg, only the pagefault part of the union is used
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UffdMsg {
+    event: u8,
+    _reserved1: u8,
+    _reserved2: u16,
+    _reserved3: u32,
+    flags: u64,
+    address: u64,
+    _feat: u64,
+}
+
+/// A guest access to a page of a registered range that is not populated yet.
+#[derive(Debug)]
+pub struct PageFault {
+    /// page aligned address in the hypervisor
+    pub addr: usize,
+    pub is_write: bool,
+}
+
+/// Provides the content of pages on first access.
+pub trait PageFaultHandler {
+    /// Fill `page` with the content of the page at `fault.addr`. Return false to map a zero
+    /// page instead.
+    fn fault(&mut self, fault: &PageFault, page: &mut [u8]) -> Result<bool>;
+}
+
+fn uffd_ioctl<T>(fd: RawFd, request: libc::c_ulong, arg: &mut T) -> io::Result<()> {
+    let res = unsafe { libc::ioctl(fd, request as _, arg as *mut T) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Moves the file descriptor `fd` of process `pid` into our process. Requires Linux 5.6.
+fn steal_fd(pid: libc::pid_t, fd: RawFd) -> Result<File> {
+    let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) } as c_int;
+    if pidfd < 0 {
+        bail!("pidfd_open failed: {}", io::Error::last_os_error());
+    }
+    let pidfd = unsafe { File::from_raw_fd(pidfd) };
+    let res = unsafe { libc::syscall(libc::SYS_pidfd_getfd, pidfd.as_raw_fd(), fd, 0) };
+    if res < 0 {
+        bail!(
+            "pidfd_getfd for fd {} failed: {}",
+            fd,
+            io::Error::last_os_error()
+        );
+    }
+    Ok(unsafe { File::from_raw_fd(res as RawFd) })
+}
+
+/// A userfaultfd of the hypervisor process. Pages of registered ranges in the hypervisor are
+/// populated by us when they are accessed for the first time (i.e. by the guest), without
+/// stopping the hypervisor.
+///
+/// Only memory behind a memslot faults this way. Guest accesses to device space without a
+/// memslot are mmio exits and never reach the hypervisor memory, they are intercepted with
+/// `KvmRunWrapper` or ioregionfd instead.
+pub struct UserfaultFd {
+    file: File,
+}
+
+impl UserfaultFd {
+    pub fn new(hv: &Hypervisor) -> Result<UserfaultFd> {
+        // The userfaultfd is bound to the memory of the process that creates it.
+        let hv_uffd = {
+            let tracee = try_with!(
+                hv.tracee.read(),
+                "cannot obtain tracee read lock: poinsoned"
+            );
+            let proc = tracee.try_get_proc()?;
+            let hv_uffd = proc.userfaultfd(libc::O_CLOEXEC | libc::O_NONBLOCK)?;
+            if hv_uffd < 0 {
+                bail!("userfaultfd failed with {}", hv_uffd);
+            }
+            let file = steal_fd(hv.pid.as_raw(), hv_uffd);
+            match tracee.close(hv_uffd) {
+                Ok(0) => {}
+                Ok(ret) => warn!("failed to close userfaultfd in hypervisor: {}", ret),
+                Err(e) => warn!("close injection failed: {}", e),
+            }
+            file?
+        };
+
+        let mut api = UffdioApi {
+            api: UFFD_API,
+            features: 0,
+            ioctls: 0,
+        };
+        try_with!(
+            uffd_ioctl(hv_uffd.as_raw_fd(), UFFDIO_API, &mut api),
+            "userfaultfd api handshake failed"
+        );
+        Ok(UserfaultFd { file: hv_uffd })
+    }
+
+    /// Serve page faults of `len` bytes at `start` in the hypervisor. The range must not be
+    /// populated yet.
+    pub fn register(&self, start: usize, len: usize) -> Result<()> {
+        let mut register = UffdioRegister {
+            range: UffdioRange {
+                start: start as u64,
+                len: len as u64,
+            },
+            mode: UFFDIO_REGISTER_MODE_MISSING,
+            ioctls: 0,
+        };
+        try_with!(
+            uffd_ioctl(self.file.as_raw_fd(), UFFDIO_REGISTER, &mut register),
+            "cannot register {:#x}-{:#x} with userfaultfd",
+            start,
+            start + len
+        );
+        Ok(())
+    }
+
+    pub fn unregister(&self, start: usize, len: usize) -> Result<()> {
+        let mut range = UffdioRange {
+            start: start as u64,
+            len: len as u64,
+        };
+        try_with!(
+            uffd_ioctl(self.file.as_raw_fd(), UFFDIO_UNREGISTER, &mut range),
+            "cannot unregister {:#x}-{:#x} from userfaultfd",
+            start,
+            start + len
+        );
+        Ok(())
+    }
+
+    /// Populate the page at `addr` with `data`, this wakes up the faulting thread.
+    pub fn copy(&self, addr: usize, data: &[u8]) -> Result<()> {
+        let mut copy = UffdioCopy {
+            dst: addr as u64,
+            src: data.as_ptr() as u64,
+            len: data.len() as u64,
+            mode: 0,
+            copy: 0,
+        };
+        match uffd_ioctl(self.file.as_raw_fd(), UFFDIO_COPY, &mut copy) {
+            // another thread populated the page in the meantime
+            Err(e) if e.raw_os_error() == Some(libc::EEXIST) => Ok(()),
+            res => Ok(try_with!(res, "cannot copy page to {:#x}", addr)),
+        }
+    }
+
+    pub fn zero(&self, addr: usize, len: usize) -> Result<()> {
+        let mut zeropage = UffdioZeropage {
+            range: UffdioRange {
+                start: addr as u64,
+                len: len as u64,
+            },
+            mode: 0,
+            zeropage: 0,
+        };
+        match uffd_ioctl(self.file.as_raw_fd(), UFFDIO_ZEROPAGE, &mut zeropage) {
+            Err(e) if e.raw_os_error() == Some(libc::EEXIST) => Ok(()),
+            res => Ok(try_with!(res, "cannot map zero page at {:#x}", addr)),
+        }
+    }
+
+    /// Wait up to `timeout_ms` for a page fault.
+    pub fn read(&mut self, timeout_ms: c_int) -> Result<Option<PageFault>> {
+        let mut pollfds = [PollFd::new(self.file.as_raw_fd(), PollFlags::POLLIN)];
+        let nr_events = try_with!(poll(&mut pollfds, timeout_ms), "poll on userfaultfd failed");
+        if nr_events == 0 {
+            return Ok(None);
+        }
+        let mut msg = MaybeUninit::<UffdMsg>::uninit();
+        // safe, because the slice has the size of UffdMsg
+        let buf =
+            unsafe { slice::from_raw_parts_mut(msg.as_mut_ptr() as *mut u8, size_of::<UffdMsg>()) };
+        match self.file.read(buf) {
+            Ok(n) if n == buf.len() => {}
+            // the fault was resolved by someone else
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            Ok(n) => bail!("short read of {}b from userfaultfd", n),
+            Err(e) => bail!("cannot read from userfaultfd: {}", e),
+        }
+        // safe, because we read all bytes
+        let msg = unsafe { msg.assume_init() };
+        if msg.event != UFFD_EVENT_PAGEFAULT {
+            debug!("ignore userfaultfd event {:#x}", msg.event);
+            return Ok(None);
+        }
+        Ok(Some(PageFault {
+            addr: page_start(msg.address as usize),
+            is_write: msg.flags & UFFD_PAGEFAULT_FLAG_WRITE != 0,
+        }))
+    }
+
+    /// Wait up to `timeout_ms` for page faults and resolve them with `handler`. Returns the
+    /// number of resolved faults.
+    pub fn handle_faults(
+        &mut self,
+        handler: &mut dyn PageFaultHandler,
+        timeout_ms: c_int,
+    ) -> Result<usize> {
+        let mut page = vec![0u8; page_size()];
+        let mut handled = 0;
+        let mut timeout_ms = timeout_ms;
+        while let Some(fault) = self.read(timeout_ms)? {
+            trace!("userfault {:?}", fault);
+            if handler.fault(&fault, &mut page)? {
+                self.copy(fault.addr, &page)?;
+            } else {
+                self.zero(fault.addr, page.len())?;
+            }
+            handled += 1;
+            // only drain what is pending already
+            timeout_ms = 0;
+        }
+        Ok(handled)
+    }
+}
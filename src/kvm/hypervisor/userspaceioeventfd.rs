@@ -18,23 +18,25 @@ pub struct UserspaceIoEventFd {
 
 impl UserspaceIoEventFd {
     pub fn userpace_ioeventfd(&mut self, datamatch: Option<u32>) -> Result<EventFd> {
-        if datamatch.is_none() && !self.ioeventfds.is_empty() {
-            bail!("cannot add a userspace ioeventfd without datamatch when others with datamatch have already been registered");
-        }
-
         let fd = try_with!(
             EventFd::new(EFD_NONBLOCK),
             "cannot create non-blocking eventfd for uioefd"
         );
         log::info!("eventfd {:?} for ioregionfd", fd.as_raw_fd(),);
-        let uioefd = UIoEFd {
-            datamatch,
-            fd: try_with!(fd.try_clone(), "cannot clone uioefd"),
-        };
-        self.ioeventfds.push(uioefd);
+        self.add(datamatch, try_with!(fd.try_clone(), "cannot clone uioefd"))?;
         Ok(fd)
     }
 
+    /// Forwards writes to the QueueNotify register with value `datamatch` to `fd`. Used for
+    /// ioeventfds registered in KVM, so that vmsh can notify queues itself.
+    pub fn add(&mut self, datamatch: Option<u32>, fd: EventFd) -> Result<()> {
+        if datamatch.is_none() && !self.ioeventfds.is_empty() {
+            bail!("cannot add a userspace ioeventfd without datamatch when others with datamatch have already been registered");
+        }
+        self.ioeventfds.push(UIoEFd { datamatch, fd });
+        Ok(())
+    }
+
     /// Callback for writes to QueueNotify register. Forwards notification to the corresponding
     /// EventFd reader.
     pub fn queue_notify(&self, val: u32) {
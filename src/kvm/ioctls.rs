@@ -218,9 +218,23 @@ ioctl_iow_nr!(
     kvmb::kvm_userspace_memory_region
 );
 
+/// Like kvmb::kvm_dirty_log, but the bitmap is an address in the hypervisor
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct kvm_dirty_log {
+    pub slot: u32,
+    pub padding1: u32,
+    pub dirty_bitmap: u64,
+}
+
+ioctl_iow_nr!(KVM_GET_DIRTY_LOG, KVMIO, 0x42, kvm_dirty_log);
+
 // Available with KVM_CAP_IOREGIONFD
 ioctl_iow_nr!(KVM_SET_IOREGION, KVMIO, 0x49, kvm_ioregion);
 
+// Available with KVM_CAP_SIGNAL_MSI
+ioctl_iow_nr!(KVM_SIGNAL_MSI, KVMIO, 0xa5, kvmb::kvm_msi);
+
 ioctl_io_nr!(KVM_RUN, KVMIO, 0x80);
 
 // Ioctls for VM fds.
@@ -237,6 +251,10 @@ ioctl_io_nr!(KVM_RUN, KVMIO, 0x80);
 ioctl_ior_nr!(KVM_GET_REGS, KVMIO, 0x81, kvmb::kvm_regs);
 #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
 ioctl_iow_nr!(KVM_SET_REGS, KVMIO, 0x82, kvmb::kvm_regs);
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+ioctl_iow_nr!(KVM_GET_ONE_REG, KVMIO, 0xab, kvmb::kvm_one_reg);
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+ioctl_iow_nr!(KVM_SET_ONE_REG, KVMIO, 0xac, kvmb::kvm_one_reg);
 #[cfg(any(
     target_arch = "x86",
     target_arch = "x86_64",
@@ -244,6 +262,8 @@ ioctl_iow_nr!(KVM_SET_REGS, KVMIO, 0x82, kvmb::kvm_regs);
     target_arch = "powerpc64"
 ))]
 ioctl_ior_nr!(KVM_GET_SREGS, KVMIO, 0x83, kvmb::kvm_sregs);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iow_nr!(KVM_SET_SREGS, KVMIO, 0x84, kvmb::kvm_sregs);
 
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 ioctl_ior_nr!(KVM_GET_FPU, KVMIO, 0x8c, kvmb::kvm_fpu);
@@ -252,6 +272,8 @@ ioctl_iow_nr!(KVM_SET_FPU, KVMIO, 0x8d, kvmb::kvm_fpu);
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 ioctl_iowr_nr!(KVM_GET_MSRS, KVMIO, 0x88, kvmb::kvm_msrs);
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iow_nr!(KVM_SET_MSRS, KVMIO, 0x89, kvmb::kvm_msrs);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 
 /// according to arch/x86/include/asm/kvm_host.h
 pub const KVM_MAX_CPUID_ENTRIES: usize = 256;
@@ -268,3 +290,77 @@ ioctl_iowr_nr!(KVM_GET_CPUID2, KVMIO, 0x91, kvmb::kvm_cpuid2);
 
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 ioctl_iowr_nr!(KVM_GET_IRQCHIP, KVMIO, 0x62, kvmb::kvm_irqchip);
+
+/// kvm_clock_data as of Linux 5.16, kvm-bindings still has all fields after `flags` as padding.
+/// `realtime` and `host_tsc` are only valid with KVM_CLOCK_REALTIME and KVM_CLOCK_HOST_TSC.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct kvm_clock_data {
+    pub clock: u64,
+    pub flags: u32,
+    pub pad0: u32,
+    pub realtime: u64,
+    pub host_tsc: u64,
+    pub pad: [u32; 4],
+}
+
+// Available with KVM_CAP_ADJUST_CLOCK
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iow_nr!(KVM_SET_CLOCK, KVMIO, 0x7b, kvm_clock_data);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_ior_nr!(KVM_GET_CLOCK, KVMIO, 0x7c, kvm_clock_data);
+
+// Available with KVM_CAP_USER_NMI
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_io_nr!(KVM_NMI, KVMIO, 0x9a);
+
+// Available with KVM_CAP_SET_GUEST_DEBUG
+ioctl_iow_nr!(KVM_SET_GUEST_DEBUG, KVMIO, 0x9b, kvmb::kvm_guest_debug);
+
+/// Argument of KVM_MEMORY_ENCRYPT_OP for SEV, kvm_tdx_cmd of TDX has the same layout
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct kvm_sev_cmd {
+    pub id: u32,
+    pub pad0: u32,
+    pub data: u64,
+    pub error: u32,
+    pub sev_fd: u32,
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct kvm_sev_guest_status {
+    pub handle: u32,
+    pub policy: u32,
+    pub state: u32,
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub const KVM_SEV_GUEST_STATUS: u32 = 6;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iowr_nr!(KVM_MEMORY_ENCRYPT_OP, KVMIO, 0xba, std::os::raw::c_ulong);
+
+// Available with KVM_CAP_NESTED_STATE, which returns the size of the largest state
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub const KVM_CAP_NESTED_STATE: u32 = 157;
+
+/// Header of kvm_nested_state, the vmcs12/vmcb12 of the nested guest follows
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct kvm_nested_state {
+    pub flags: u16,
+    pub format: u16,
+    pub size: u32,
+    /// kvm_vmx_nested_state_hdr (vmxon_pa, vmcs12_pa, smm flags) or
+    /// kvm_svm_nested_state_hdr (vmcb_pa)
+    pub hdr: [u64; 15],
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_iowr_nr!(KVM_GET_NESTED_STATE, KVMIO, 0xbe, kvm_nested_state);
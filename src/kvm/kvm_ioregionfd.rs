@@ -18,17 +18,22 @@ pub struct kvm_ioregion {
 }
 
 impl kvm_ioregion {
-    pub fn new(guest_paddr: u64, len: usize, rfd: RawFd, wfd: RawFd) -> Self {
+    pub fn new(guest_paddr: u64, len: usize, rfd: RawFd, wfd: RawFd, flags: u32) -> Self {
         kvm_ioregion {
             guest_paddr,
             memory_size: len as u64,
             user_data: 0,
             rfd,
             wfd,
-            flags: 0,
+            flags,
             pad: [0; 28],
         }
     }
+
+    /// Writes to the region are not acknowledged, the vcpu continues right away.
+    pub fn posted_writes(&self) -> bool {
+        self.flags & KVM_IOREGION_POSTED_WRITES != 0
+    }
 }
 
 #[allow(non_camel_case_types)]
@@ -72,6 +77,14 @@ impl ioregionfd_cmd {
             Size::b64 => &data[0..8],
         }
     }
+    /// Both commands write the same data to the same register.
+    pub fn same_write(&self, other: &ioregionfd_cmd) -> bool {
+        matches!(self.info.cmd(), Cmd::Write)
+            && matches!(other.info.cmd(), Cmd::Write)
+            && self.offset == other.offset
+            && self.data() == other.data()
+    }
+
     pub fn data_mut(&mut self) -> &mut [u8] {
         let data = unsafe {
             std::slice::from_raw_parts_mut(
@@ -0,0 +1,119 @@
+//! Bookkeeping of the guest physical address space, so that memory and mmio ranges we add
+//! to the VM do not collide with memory of the hypervisor or our own allocations.
+//!
+//! The memory map (e820) of the guest is not accessible to us, since the kernel does not
+//! export it. Instead, everything backed by memslots and all addresses below 4GiB, where
+//! hypervisors place firmware and the 32-bit pci hole, are considered in use.
+
+use std::cmp::{max, min};
+use std::ops::Range;
+
+/// End of the 32-bit address space
+const LOW_MEMORY_END: usize = 1 << 32;
+
+pub struct MemoryMap {
+    /// Highest address we allocate from, exclusive
+    limit: usize,
+    /// Sorted, non-overlapping ranges that are used or already reserved
+    used: Vec<Range<usize>>,
+}
+
+impl MemoryMap {
+    pub fn new(limit: usize) -> MemoryMap {
+        let mut map = MemoryMap {
+            limit,
+            used: vec![],
+        };
+        map.mark_used(0..LOW_MEMORY_END);
+        map
+    }
+
+    /// Marks `range` as used, i.e. a memslot of the hypervisor.
+    pub fn mark_used(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        let mut merged = range;
+        let mut used = Vec::with_capacity(self.used.len() + 1);
+        for r in self.used.drain(..) {
+            if r.end < merged.start || merged.end < r.start {
+                used.push(r);
+            } else {
+                merged = min(r.start, merged.start)..max(r.end, merged.end);
+            }
+        }
+        let pos = used
+            .iter()
+            .position(|r| r.start > merged.start)
+            .unwrap_or(used.len());
+        used.insert(pos, merged);
+        self.used = used;
+    }
+
+    pub fn is_free(&self, range: &Range<usize>) -> bool {
+        range.end <= self.limit
+            && self
+                .used
+                .iter()
+                .all(|r| r.end <= range.start || range.end <= r.start)
+    }
+
+    /// Reserves `size` bytes at the highest free address that is a multiple of `align` (a
+    /// power of two). Returns None if no such gap is left.
+    pub fn reserve(&mut self, size: usize, align: usize) -> Option<Range<usize>> {
+        let mut gap_end = self.limit;
+        // walk the gaps between used ranges, starting with the highest one
+        for i in (0..=self.used.len()).rev() {
+            let below = i.checked_sub(1).and_then(|i| self.used.get(i));
+            let gap_start = below.map_or(0, |r| r.end);
+            if let Some(start) = gap_end.checked_sub(size).map(|s| s & !(align - 1)) {
+                if start >= gap_start {
+                    let range = start..start + size;
+                    self.mark_used(range.clone());
+                    return Some(range);
+                }
+            }
+            gap_end = min(gap_end, below.map_or(0, |r| r.start));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserve_below_used_ranges() {
+        let limit = 1 << 40;
+        let mut map = MemoryMap::new(limit);
+        assert!(!map.is_free(&(0xfee0_0000..0xfee0_1000)));
+
+        // a stale memslot at the top of the address space
+        map.mark_used(limit - 0x3000..limit - 0x1000);
+        assert_eq!(map.reserve(0x1000, 1), Some(limit - 0x1000..limit));
+        assert_eq!(map.reserve(0x1000, 1), Some(limit - 0x4000..limit - 0x3000));
+        assert_eq!(
+            map.reserve(0x1000, 0x20_0000),
+            Some(limit - 0x20_0000..limit - 0x1f_f000)
+        );
+        assert!(!map.is_free(&(limit - 0x5000..limit - 0x3000)));
+        assert!(map.is_free(&(limit - 0x5000..limit - 0x4000)));
+
+        // everything above 4GiB is taken
+        map.mark_used(LOW_MEMORY_END..limit);
+        assert_eq!(map.reserve(0x1000, 1), None);
+    }
+
+    #[test]
+    fn merge_used_ranges() {
+        let mut map = MemoryMap::new(1 << 36);
+        map.mark_used(0x2_0000_0000..0x2_0000_2000);
+        map.mark_used(0x2_0000_4000..0x2_0000_5000);
+        map.mark_used(0x2_0000_1000..0x2_0000_4000);
+        assert_eq!(
+            map.used,
+            vec![0..LOW_MEMORY_END, 0x2_0000_0000..0x2_0000_5000]
+        );
+    }
+}
@@ -25,6 +25,8 @@ pub struct MemSlot {
     base_gfn: u64,
     npages: c_ulong,
     userspace_addr: c_ulong,
+    id: u32,
+    flags: u32,
 }
 
 impl MemSlot {
@@ -66,6 +68,8 @@ struct memslot {
     gfn_t base_gfn;
     unsigned long npages;
     unsigned long userspace_addr;
+    u32 id;
+    u32 flags;
 };
 
 // KVM_MEM_SLOTS_NUM became to big to handle it in ebpf
@@ -120,6 +124,8 @@ void kvm_vm_ioctl(struct pt_regs *ctx, struct file *filp) {
         out_slot->base_gfn = slot->base_gfn;
         out_slot->npages = slot->npages;
         out_slot->userspace_addr = slot->userspace_addr;
+        out_slot->id = slot->id;
+        out_slot->flags = slot->flags;
         out->used_slots++;
 
         struct rb_node* left_child = node->rb_left;
@@ -143,6 +149,8 @@ void kvm_vm_ioctl(struct pt_regs *ctx, struct file *filp) {
       out_slot->base_gfn = in_slot->base_gfn;
       out_slot->npages = in_slot->npages;
       out_slot->userspace_addr = in_slot->userspace_addr;
+      out_slot->id = in_slot->id;
+      out_slot->flags = in_slot->flags;
     }
 #endif
 
@@ -164,7 +172,8 @@ fn bpf_prog(pid: Pid) -> Result<BPF> {
         return Err(simple_error!(
             "kernel release has not enough numbers: {:?}",
             raw_kernel_release
-        ));
+        )
+        .into());
     }
 
     let builder = try_with!(BPFBuilder::new(BPF_TEXT), "cannot compile bpf program");
@@ -231,6 +240,8 @@ We might miss physical memory allocations."
                 m.start = slot.start();
                 m.end = slot.end();
                 m.phys_addr = slot.physical_start();
+                m.slot = slot.id;
+                m.slot_flags = slot.flags;
                 Ok(m)
             }
             None => bail!(
@@ -270,7 +281,8 @@ pub fn get_vcpu_maps(pid: Pid) -> Result<Vec<Mapping>> {
         taged_maps.push((ai, vcpu_map));
     }
 
-    taged_maps.sort_unstable_by_key(|(i, _map)| *i);
+    // stable, maps of vcpus with the same number in different VMs stay ordered by address
+    taged_maps.sort_by_key(|(i, _map)| *i);
     let sorted_maps = taged_maps.into_iter().map(|(_i, map)| map).collect();
     Ok(sorted_maps)
 }
@@ -1,8 +1,10 @@
 pub mod allocator;
+pub mod dirty_log;
 pub mod fd_transfer;
 pub mod hypervisor;
 pub mod ioctls;
 pub mod kvm_ioregionfd;
+pub mod memory_map;
 pub mod memslots;
 pub mod tracee;
 pub use self::allocator::PhysMemAllocator;
@@ -1,19 +1,19 @@
-use crate::cpu;
 use kvm_bindings as kvmb;
 use libc::{c_int, c_ulong, c_void};
 use nix::unistd::Pid;
 use simple_error::{bail, try_with};
 use std::mem::MaybeUninit;
 use std::os::unix::prelude::RawFd;
-use std::ptr;
 
 use super::ioctls;
-use crate::kvm::hypervisor::{memory::HvMem, VCPU};
+use crate::kvm::hypervisor::memory::{self, HvMem};
+use crate::kvm::hypervisor::VCPU;
 use crate::kvm::ioctls::KVM_CHECK_EXTENSION;
 use crate::kvm::memslots::{get_maps, get_vcpu_maps};
-use crate::result::Result;
+use crate::result::{Result, VmshError};
 use crate::tracer::inject_syscall;
 use crate::tracer::inject_syscall::Process as Injectee;
+use crate::tracer::inject_syscall::SyscallBatch;
 use crate::tracer::proc::Mapping;
 
 /// In theory this is dynamic however for for simplicity we limit it to 1 entry to not have to rewrite our vm allocation stack
@@ -39,6 +39,9 @@ pub struct Tracee {
     /// other functions.
     /// This hold especially true for the destructor of for example `VmMem`.
     proc: Option<Injectee>,
+    /// Only ioctls that read the state of the VM are allowed, see `set_read_only`. Holds why,
+    /// for the error message.
+    read_only: Option<&'static str>,
 }
 
 #[allow(non_camel_case_types)]
@@ -49,9 +52,64 @@ pub type socklen_t = usize;
 #[cfg(not(all(target_os = "linux", target_env = "gnu")))]
 pub type socklen_t = libc::socklen_t;
 
+/// Whether `request` only reads the state of the VM or a vcpu.
+fn is_read_only_ioctl(request: c_ulong) -> bool {
+    use crate::kvm::ioctls::{
+        KVM_GET_CLOCK, KVM_GET_CPUID2, KVM_GET_FPU, KVM_GET_IRQCHIP, KVM_GET_MSRS,
+        KVM_GET_NESTED_STATE, KVM_GET_REGS, KVM_GET_SREGS,
+    };
+    [
+        KVM_CHECK_EXTENSION(),
+        KVM_GET_CLOCK(),
+        KVM_GET_CPUID2(),
+        KVM_GET_FPU(),
+        KVM_GET_IRQCHIP(),
+        KVM_GET_MSRS(),
+        KVM_GET_NESTED_STATE(),
+        KVM_GET_REGS(),
+        KVM_GET_SREGS(),
+    ]
+    .contains(&request)
+}
+
 impl Tracee {
     pub fn new(pid: Pid, vm_fd: RawFd, proc: Option<Injectee>) -> Tracee {
-        Tracee { pid, vm_fd, proc }
+        Tracee {
+            pid,
+            vm_fd,
+            proc,
+            read_only: None,
+        }
+    }
+
+    /// From now on refuse everything that changes the VM or the hypervisor: ioctls other than
+    /// the ones reading registers and VM state, and all injected syscalls except for allocating
+    /// and freeing the memory these ioctls take their arguments in. This cannot be undone.
+    pub fn set_read_only(&mut self) {
+        self.read_only.get_or_insert("in read-only mode");
+    }
+
+    /// Like `set_read_only`, for VMs with encrypted memory, see `hypervisor::allow_encrypted`.
+    pub fn set_encrypted(&mut self) {
+        self.read_only
+            .get_or_insert("on VMs with encrypted memory, it would corrupt the guest");
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only.is_some()
+    }
+
+    /// Why the tracee is read-only, if it is.
+    pub fn read_only_reason(&self) -> Option<&'static str> {
+        self.read_only
+    }
+
+    /// Fails in read-only mode, `what` describes the refused operation.
+    pub fn check_writable(&self, what: &str) -> Result<()> {
+        if let Some(reason) = self.read_only {
+            bail!("{} is not allowed {}", what, reason);
+        }
+        Ok(())
     }
 
     /// see Process#adopt
@@ -100,13 +158,26 @@ impl Tracee {
         self.proc.take()
     }
 
+    /// The injector for arbitrary syscalls, which is refused in read-only mode.
     pub fn try_get_proc(&self) -> Result<&Injectee> {
+        self.check_writable("injecting syscalls")?;
+        self.attached_proc()
+    }
+
+    fn attached_proc(&self) -> Result<&Injectee> {
         match &self.proc {
             None => bail!("programming error: tracee is not attached."),
             Some(proc) => Ok(proc),
         }
     }
 
+    fn check_ioctl(&self, request: c_ulong) -> Result<()> {
+        if !is_read_only_ioctl(request) {
+            self.check_writable(&format!("ioctl {:#x}", request))?;
+        }
+        Ok(())
+    }
+
     fn try_get_proc_mut(&mut self) -> Result<&mut Injectee> {
         match &mut self.proc {
             None => bail!("programming error: tracee is not attached."),
@@ -115,7 +186,8 @@ impl Tracee {
     }
 
     fn vm_ioctl(&self, request: c_ulong, arg: c_ulong) -> Result<c_int> {
-        let proc = self.try_get_proc()?;
+        self.check_ioctl(request)?;
+        let proc = self.attached_proc()?;
         proc.ioctl(self.vm_fd, request, arg)
     }
 
@@ -141,11 +213,38 @@ impl Tracee {
         self.vm_ioctl(request, arg.ptr as c_ulong)
     }
 
-    fn vcpu_ioctl(&self, vcpu: &VCPU, request: c_ulong, arg: c_ulong) -> Result<c_int> {
+    /// Like `vm_ioctl_with_ref`, but only adds the ioctl to `batch`.
+    pub fn batch_vm_ioctl_with_ref<T: Sized + Copy>(
+        &self,
+        batch: &mut SyscallBatch,
+        request: c_ulong,
+        arg: &HvMem<T>,
+    ) {
+        batch.ioctl(self.vm_fd, request, arg.ptr as c_ulong);
+    }
+
+    /// see Process#run_batch
+    pub fn run_batch(&self, batch: &SyscallBatch) -> Result<Vec<isize>> {
         let proc = self.try_get_proc()?;
+        proc.run_batch(batch)
+    }
+
+    fn vcpu_ioctl(&self, vcpu: &VCPU, request: c_ulong, arg: c_ulong) -> Result<c_int> {
+        self.check_ioctl(request)?;
+        let proc = self.attached_proc()?;
         proc.ioctl(vcpu.fd_num, request, arg)
     }
 
+    /// Like `vm_ioctl_with_ref` but for the vcpu fd of `vcpu`.
+    pub fn vcpu_ioctl_with_ref<T: Sized + Copy>(
+        &self,
+        vcpu: &VCPU,
+        request: c_ulong,
+        arg: &HvMem<T>,
+    ) -> Result<c_int> {
+        self.vcpu_ioctl(vcpu, request, arg.ptr as c_ulong)
+    }
+
     /// Make the kernel allocate anonymous memory (anywhere he likes, not bound to a file
     /// descriptor). This is not fully POSIX compliant, but works on linux.
     ///
@@ -157,13 +256,24 @@ impl Tracee {
     /// Safe for this crate, not so for the remote process being manipulated. Ensure that to write
     /// and read at most `size_of::<T> <= size` bytes.
     pub fn mmap(&self, length: libc::size_t) -> Result<*mut c_void> {
-        let proc = self.try_get_proc()?;
+        let proc = self.attached_proc()?;
         let addr = libc::AT_NULL as *mut c_void; // make kernel choose location for us
         let prot = libc::PROT_READ | libc::PROT_WRITE;
         let flags = libc::MAP_SHARED | libc::MAP_ANONYMOUS;
         let fd = -1; // ignored because of MAP_ANONYMOUS => should be -1
         let offset = 0; // MAP_ANON => should be 0
-        proc.mmap(addr, length, prot, flags, fd, offset)
+        let ptr = proc.mmap(addr, length, prot, flags, fd, offset)?;
+        // not part of the VM, so it can be written to in read-only mode
+        memory::add_scratch(self.pid, ptr as usize, length);
+        Ok(ptr)
+    }
+
+    /// Maps the first `length` bytes of the file `fd` (a file descriptor of the hypervisor)
+    /// read-only and shared into the hypervisor.
+    pub fn mmap_file(&self, length: libc::size_t, fd: RawFd) -> Result<*mut c_void> {
+        let proc = self.attached_proc()?;
+        let addr = libc::AT_NULL as *mut c_void;
+        proc.mmap(addr, length, libc::PROT_READ, libc::MAP_SHARED, fd, 0)
     }
 
     /// Guarantees not to allocate or follow pointers. Pure pointer calculus.
@@ -263,83 +373,49 @@ impl Tracee {
     }
 
     /// Set general-purpose pointer registers of VCPU
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn set_regs(&self, vcpu: &VCPU, regs: &HvMem<kvmb::kvm_regs>) -> Result<()> {
-        use crate::kvm::ioctls::KVM_SET_REGS;
-        try_with!(
-            self.vcpu_ioctl(vcpu, KVM_SET_REGS(), regs.ptr as c_ulong),
+    pub fn set_guest_debug(&self, vcpu: &VCPU, debug: &HvMem<kvmb::kvm_guest_debug>) -> Result<()> {
+        use crate::kvm::ioctls::KVM_SET_GUEST_DEBUG;
+        let ret = try_with!(
+            self.vcpu_ioctl(vcpu, KVM_SET_GUEST_DEBUG(), debug.ptr as c_ulong),
             "vcpu_ioctl failed"
         );
+        if ret != 0 {
+            return Err(VmshError::kvm_ioctl("KVM_SET_GUEST_DEBUG", ret));
+        }
         Ok(())
     }
 
-    /// Get general-purpose pointer registers of VCPU
+    /// Queues a non-maskable interrupt for `vcpu`.
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn get_regs(&self, vcpu: &VCPU, regs: &HvMem<kvmb::kvm_regs>) -> Result<cpu::Regs> {
-        use crate::kvm::ioctls::KVM_GET_REGS;
-        try_with!(
-            self.vcpu_ioctl(vcpu, KVM_GET_REGS(), regs.ptr as c_ulong),
-            "vcpu_ioctl failed"
-        );
-        let regs = try_with!(regs.read(), "cannot read registers");
-        Ok(cpu::Regs {
-            r15: regs.r15,
-            r14: regs.r14,
-            r13: regs.r13,
-            r12: regs.r12,
-            rbp: regs.rbp,
-            rbx: regs.rbx,
-            r11: regs.r11,
-            r10: regs.r10,
-            r9: regs.r9,
-            r8: regs.r8,
-            rax: regs.rax,
-            rcx: regs.rcx,
-            rdx: regs.rdx,
-            rsi: regs.rsi,
-            rdi: regs.rdi,
-            orig_rax: regs.rax,
-            rip: regs.rip,
-            cs: 0,
-            eflags: regs.rflags,
-            rsp: regs.rsp,
-            ss: 0,
-            fs_base: 0,
-            gs_base: 0,
-            ds: 0,
-            es: 0,
-            fs: 0,
-            gs: 0,
-        })
-    }
-
-    /// Get floating pointer registers of VCPU
+    pub fn nmi(&self, vcpu: &VCPU) -> Result<()> {
+        use crate::kvm::ioctls::KVM_NMI;
+        let ret = try_with!(self.vcpu_ioctl(vcpu, KVM_NMI(), 0), "vcpu_ioctl failed");
+        if ret != 0 {
+            return Err(VmshError::kvm_ioctl("KVM_NMI", ret));
+        }
+        Ok(())
+    }
+
+    /// Runs KVM_SEV_GUEST_STATUS with `cmd`, the guest status is written to `status`. Also
+    /// allowed in read-only mode: unlike the other commands of KVM_MEMORY_ENCRYPT_OP, it only
+    /// reads the state of the VM.
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn get_fpu_regs(&self, vcpu: &VCPU, regs: &HvMem<kvmb::kvm_fpu>) -> Result<cpu::FpuRegs> {
-        use crate::kvm::ioctls::KVM_GET_FPU;
-        try_with!(
-            self.vcpu_ioctl(vcpu, KVM_GET_FPU(), regs.ptr as c_ulong),
-            "vcpu_ioctl failed"
-        );
-        let regs = try_with!(regs.read(), "cannot read fpu registers");
-        let st_space = unsafe { ptr::read(&regs.fpr as *const [u8; 16] as *const [u32; 32]) };
-        let xmm_space =
-            unsafe { ptr::read(&regs.xmm as *const [[u8; 16]; 16] as *const [u32; 64]) };
-
-        Ok(cpu::FpuRegs {
-            cwd: regs.fcw,
-            swd: regs.fsw,
-            twd: regs.ftwx as u16,
-            fop: regs.last_opcode,
-            rip: regs.last_ip,
-            rdp: regs.last_dp,
-            mxcsr: regs.mxcsr,
-            mxcsr_mask: 0,
-            st_space,
-            xmm_space,
-            padding: [0; 12],
-            padding1: [0; 12],
-        })
+    pub fn sev_guest_status(
+        &self,
+        cmd: &HvMem<ioctls::kvm_sev_cmd>,
+        status: &HvMem<ioctls::kvm_sev_guest_status>,
+    ) -> Result<c_int> {
+        cmd.write(&ioctls::kvm_sev_cmd {
+            id: ioctls::KVM_SEV_GUEST_STATUS,
+            data: status.ptr as u64,
+            ..Default::default()
+        })?;
+        let proc = self.attached_proc()?;
+        proc.ioctl(
+            self.vm_fd,
+            ioctls::KVM_MEMORY_ENCRYPT_OP(),
+            cmd.ptr as c_ulong,
+        )
     }
 
     /// Get model-specific pointer registers of VCPU
@@ -362,8 +438,10 @@ impl Tracee {
     ///
     /// length in bytes.
     pub fn munmap(&self, addr: *mut c_void, length: libc::size_t) -> Result<()> {
-        let proc = self.try_get_proc()?;
-        proc.munmap(addr, length)
+        let proc = self.attached_proc()?;
+        proc.munmap(addr, length)?;
+        memory::remove_scratch(self.pid, addr as usize);
+        Ok(())
     }
 
     pub fn close(&self, fd: RawFd) -> Result<i32> {
@@ -387,3 +465,21 @@ impl Tracee {
         get_vcpu_maps(self.pid)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kvm::ioctls::{KVM_GET_REGS, KVM_SET_REGS, KVM_SET_USER_MEMORY_REGION};
+
+    #[test]
+    fn read_only() {
+        let mut tracee = Tracee::new(Pid::this(), -1, None);
+        assert!(tracee.check_ioctl(KVM_SET_REGS()).is_ok());
+        tracee.set_read_only();
+        assert!(tracee.check_ioctl(KVM_GET_REGS()).is_ok());
+        assert!(tracee.check_ioctl(KVM_SET_REGS()).is_err());
+        assert!(tracee.check_ioctl(KVM_SET_USER_MEMORY_REGION()).is_err());
+        assert!(tracee.try_get_proc().is_err());
+        assert!(tracee.check_writable("writing guest memory").is_err());
+    }
+}
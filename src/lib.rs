@@ -1,3 +1,9 @@
+//! vmsh attaches devices and a shell to running KVM virtual machines.
+//!
+//! Programs that embed vmsh start with `vm::Vm`. The other modules implement the
+//! subcommands of the `vmsh` binary and take their options as plain structs
+//! (i.e. `coredump::CoredumpOptions`), so they can be called directly as well.
+
 #![deny(clippy::print_stdout, clippy::print_stderr, clippy::unwrap_used)]
 // TODO: more checks
 //#![warn(
@@ -11,22 +17,52 @@
 //    cast_possible_wrap
 //)]
 
+pub mod arch;
 pub mod attach;
+pub mod boot;
+pub mod btf;
+pub mod check;
+pub mod chrome_trace;
+pub mod clock;
 pub mod console;
+pub mod control;
 pub mod coredump;
+pub mod cp;
 pub mod cpu;
+pub mod crash;
+pub mod daemon;
 pub mod debug;
 pub mod devices;
+pub mod dmesg;
 pub mod elf;
+pub mod exec;
+pub mod forward;
+pub mod gdbserver;
 pub mod guest_mem;
+pub mod guest_wait;
 pub mod inspect;
 pub mod interrutable_thread;
+pub mod kata;
 pub mod kernel;
 pub mod kvm;
 pub mod loader;
+pub mod mem;
+pub mod metrics;
+pub mod oci;
 pub mod page_math;
 pub mod page_table;
+pub mod progress;
+pub mod ps;
+pub mod regs;
 pub mod result;
+pub mod rpc;
+pub mod seccomp;
+pub mod session;
 pub mod signal_handler;
+pub mod snapshot;
 pub mod stage1;
+pub mod symbols;
 pub mod tracer;
+pub mod vm;
+pub mod watchdog;
+pub mod windump;
@@ -0,0 +1,89 @@
+//! Kernel symbols stage1 links against that are not exported under the same name by every
+//! kernel version. Symbols that only some kernels export at all (i.e. the pci host bridge
+//! functions) are looked up by stage1 itself at runtime instead.
+
+use log::debug;
+use std::collections::HashMap;
+
+struct Renamed {
+    /// Name stage1 is linked against, the one of the most recent kernels
+    name: &'static str,
+    /// Symbols of older kernels that can be used instead, in order of preference
+    alternatives: &'static [&'static str],
+}
+
+const RENAMED: &[Renamed] = &[
+    // usleep_range became an inline wrapper around usleep_range_state in Linux 5.16.
+    // usleep_range_state just takes an additional argument, which usleep_range ignores.
+    Renamed {
+        name: "usleep_range_state",
+        alternatives: &["usleep_range"],
+    },
+    // printk became a macro around _printk in Linux 5.16
+    Renamed {
+        name: "_printk",
+        alternatives: &["printk"],
+    },
+    // stage1 sets its final state with memset, but links against __memset so that it does not
+    // get the weak memset of compiler_builtins. Old kernels only have memset.
+    Renamed {
+        name: "__memset",
+        alternatives: &["memset"],
+    },
+];
+
+fn alternatives(name: &str) -> &'static [&'static str] {
+    RENAMED
+        .iter()
+        .find(|r| r.name == name)
+        .map_or(&[], |r| r.alternatives)
+}
+
+/// Looks up `name` in the kernel symbols, falling back to the names older kernels export it
+/// under.
+pub fn resolve(name: &str, syms: &HashMap<String, usize>) -> Option<usize> {
+    if let Some(sym) = syms.get(name) {
+        return Some(*sym);
+    }
+    alternatives(name).iter().find_map(|alt| {
+        let sym = syms.get(*alt)?;
+        debug!("kernel does not export {}, use {} instead", name, alt);
+        Some(*sym)
+    })
+}
+
+/// Describes symbols that could not be resolved, including the alternatives we tried.
+pub fn describe_missing(missing: &[String]) -> String {
+    missing
+        .iter()
+        .map(|name| match alternatives(name) {
+            [] => name.clone(),
+            alts => format!("{} (or {})", name, alts.join(", ")),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_renamed() {
+        let mut syms = HashMap::new();
+        syms.insert("printk".to_string(), 0x1000);
+        syms.insert("kernel_read".to_string(), 0x2000);
+        assert_eq!(resolve("kernel_read", &syms), Some(0x2000));
+        assert_eq!(resolve("_printk", &syms), Some(0x1000));
+        assert_eq!(resolve("usleep_range_state", &syms), None);
+
+        syms.insert("_printk".to_string(), 0x3000);
+        assert_eq!(resolve("_printk", &syms), Some(0x3000));
+
+        let missing = vec!["usleep_range_state".to_string(), "filp_open".to_string()];
+        assert_eq!(
+            describe_missing(&missing),
+            "usleep_range_state (or usleep_range), filp_open"
+        );
+    }
+}
@@ -0,0 +1,61 @@
+//! Layouts of the kernel structs stage1 passes to the kernel. stage1 knows the layouts of the
+//! kernel versions we tested, but members move between versions and with
+//! CONFIG_RANDSTRUCT, so if the guest kernel has BTF type information the offsets are read
+//! from it and passed to stage1 in `Stage1Args::layouts`.
+
+use log::{debug, warn};
+use stage1_interface::{PlatformDeviceInfoLayout, ResourceLayout, StructLayouts, WorkStructLayout};
+
+use crate::btf::Btf;
+use crate::kernel::Kernel;
+use crate::kvm::hypervisor::Hypervisor;
+use crate::result::Result;
+
+fn read_layouts(btf: &Btf) -> Result<StructLayouts> {
+    let info = btf.find_struct("platform_device_info")?;
+    let resource = btf.find_struct("resource")?;
+    let work = btf.find_struct("work_struct")?;
+    Ok(StructLayouts {
+        platform_device_info: PlatformDeviceInfoLayout {
+            size: info.size,
+            name: info.offset("name")?,
+            id: info.offset("id")?,
+            res: info.offset("res")?,
+            num_res: info.offset("num_res")?,
+        },
+        resource: ResourceLayout {
+            size: resource.size,
+            start: resource.offset("start")?,
+            end: resource.offset("end")?,
+            flags: resource.offset("flags")?,
+        },
+        work_struct: WorkStructLayout {
+            size: work.size,
+            entry: work.offset("entry")?,
+            func: work.offset("func")?,
+        },
+    })
+}
+
+/// Layouts of the guest kernel from its BTF type information.
+pub fn read(kernel: &Kernel, hv: &Hypervisor) -> Result<StructLayouts> {
+    let data = kernel.btf(hv)?;
+    read_layouts(&Btf::parse(&data)?)
+}
+
+/// Layouts of the guest kernel, `StructLayouts::UNKNOWN` if it has no (usable) BTF.
+pub fn probe(kernel: &Kernel, hv: &Hypervisor) -> StructLayouts {
+    match read(kernel, hv) {
+        Ok(layouts) => {
+            debug!("struct layouts of the guest kernel: {:?}", layouts);
+            layouts
+        }
+        Err(e) => {
+            warn!(
+                "cannot read struct layouts of the guest kernel, stage1 assumes them from its version: {}",
+                e
+            );
+            StructLayouts::UNKNOWN
+        }
+    }
+}
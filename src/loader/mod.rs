@@ -0,0 +1,733 @@
+use std::collections::HashMap;
+use std::mem::{size_of, size_of_val};
+use std::ptr;
+
+use elfloader::{
+    ElfBinary, ElfLoader, ElfLoaderErr, Entry, Flags, LoadableHeaders, RelocationEntry, VAddr,
+};
+use log::{debug, error, info, warn};
+use nix::sys::mman::ProtFlags;
+use simple_error::{bail, require_with, try_with};
+use stage1_interface::{DeviceState, KernelVersion, Stage1Args, MAX_DEVICES, MAX_STAGE2_PATHS};
+use xmas_elf::program;
+use xmas_elf::sections::{SectionData, SHN_UNDEF};
+use xmas_elf::symbol_table::{Binding, DynEntry64};
+
+use crate::arch::{self, Relocation};
+use crate::devices::virtio::pci::PciWindow;
+use crate::guest_mem::MappedMemory;
+use crate::kernel::{parse_release, Kernel, LINUX_KERNEL_KASLR_RANGE};
+use crate::kvm::allocator::{UnmappedMem, VirtAlloc};
+use crate::kvm::hypervisor::memory::{process_write, process_write_vectored};
+use crate::kvm::hypervisor::Hypervisor;
+use crate::kvm::PhysMemAllocator;
+use crate::page_math::{page_align, page_start};
+use crate::result::{Result, VmshError};
+use crate::stage1::{DeviceSlots, DeviceStatus, DriverStatus};
+use crate::try_core_res;
+
+pub(crate) mod compat;
+pub(crate) mod layouts;
+pub(crate) mod module;
+
+/// Where stage1 jumps to when it is done, see `LoadedBinary::set_return_address`
+const RETURN_ADDRESS_SYMBOL: &str = "VMSH_STAGE1_PC";
+
+/// Init function of the module that registers the devices, see `module`
+const MODULE_INIT_SYMBOL: &str = "vmsh_module_init";
+
+/// An elf binary parsed ahead of loading it. Parsing does not need the VM, so it can be done
+/// before the hypervisor is stopped.
+pub struct Binary<'a> {
+    /// parsed elf header of the binary
+    elf: ElfBinary<'a>,
+    /// reference to dynamic symbol table section of the elf binary
+    dyn_syms: &'a [DynEntry64],
+    /// defined symbols, relative to the load address
+    syms: HashMap<&'a str, usize>,
+    /// offset of the `_init_vmsh` function
+    init_func: usize,
+    /// offset of the `VMSH_STAGE1_ARGS` struct
+    vmsh_stage1_args: usize,
+}
+
+impl<'a> Binary<'a> {
+    pub fn parse(binary: &'a [u8]) -> Result<Binary<'a>> {
+        let elf = try_core_res!(ElfBinary::new(binary), "cannot parse elf binary");
+        let dyn_symbol_section = require_with!(
+            elf.file.find_section_by_name(".dynsym"),
+            "binary has no .dynsym section"
+        );
+        let dyn_symbol_table = dyn_symbol_section.get_data(&elf.file)?;
+        let dyn_syms = match dyn_symbol_table {
+            SectionData::DynSymbolTable64(entries) => entries,
+            _ => bail!(
+                "expected .dynsym to be a DynSymbolTable64, got: {:?}",
+                dyn_symbol_table
+            ),
+        };
+
+        // we use symtab instead of dynsym here because rust does not allow us
+        // to export our assembly trampoline (_init_vmsh) from the stage1 build
+        // via .dynsym
+        let symbol_section = require_with!(
+            elf.file.find_section_by_name(".symtab"),
+            "binary has no .symtab section"
+        );
+        let symbol_table = symbol_section.get_data(&elf.file)?;
+        let sym_entries = match symbol_table {
+            SectionData::SymbolTable64(entries) => entries,
+            _ => bail!(
+                "expected .symtab to be a SymbolTable64, got: {:?}",
+                symbol_table
+            ),
+        };
+
+        let syms = sym_entries
+            .iter()
+            .filter(|sym| sym.shndx() != SHN_UNDEF)
+            .map(|sym| {
+                let name = try_core_res!(sym.get_name(&elf.file), "cannot get name of function");
+                Ok((name, sym.value() as usize))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+
+        // A stage1 built with a different MAX_DEVICES would disagree about the layout of its
+        // arguments.
+        let args_sym = sym_entries
+            .iter()
+            .find(|sym| matches!(sym.get_name(&elf.file), Ok("VMSH_STAGE1_ARGS")));
+        if let Some(sym) = args_sym {
+            if sym.size() as usize != size_of::<Stage1Args>() {
+                bail!(
+                    "stage1 arguments have a size of {} bytes, expected {}. Was stage1 built with a different stage1_interface?",
+                    sym.size(),
+                    size_of::<Stage1Args>()
+                );
+            }
+        }
+
+        Ok(Binary {
+            init_func: *require_with!(syms.get("_init_vmsh"), "no _init_vmsh symbol found"),
+            vmsh_stage1_args: *require_with!(
+                syms.get("VMSH_STAGE1_ARGS"),
+                "no cleanup_vmsh_stage1 symbol found"
+            ),
+            elf,
+            dyn_syms,
+            syms,
+        })
+    }
+
+    /// Kernel symbols the binary links against that `kernel_syms` does not provide, including
+    /// the names older kernels export them under (see `compat`).
+    pub fn missing_symbols(&self, kernel_syms: &HashMap<String, usize>) -> Vec<String> {
+        let mut missing = self
+            .dyn_syms
+            .iter()
+            .filter(|sym| {
+                sym.shndx() == SHN_UNDEF && !matches!(sym.get_binding(), Ok(Binding::Weak))
+            })
+            .filter_map(|sym| sym.get_name(&self.elf.file).ok())
+            .filter(|name| {
+                !name.is_empty() && resolve_symbol(name, kernel_syms, &self.syms).is_none()
+            })
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        missing.sort();
+        missing.dedup();
+        missing
+    }
+
+    /// Virtual memory the loadable segments take, without the strings of the stage1 arguments.
+    pub fn load_size(&self) -> usize {
+        self.elf
+            .file
+            .program_iter()
+            .filter(|h| matches!(h.get_type(), Ok(program::Type::Load)))
+            .map(|h| page_align((h.virtual_addr() + h.mem_size()) as usize))
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+pub struct Loader<'a> {
+    /// the linux kernel we link our code against
+    kernel: &'a Kernel,
+    /// the virtual memory our binary is baked by, mapped after the upload
+    virt_mem: Option<UnmappedMem>,
+    /// To page align elf section we need to pad space before and after each section
+    /// These are offsets where within an allocation where the actual section starts
+    load_offsets: Vec<usize>,
+    allocator: &'a mut PhysMemAllocator,
+    /// elf section of type PT_LOAD
+    loadables: Vec<Loadable>,
+    /// the parsed elf file
+    binary: &'a Binary<'a>,
+    /// exported symbols from the elf binary above
+    lib_syms: HashMap<&'a str, usize>,
+    /// virtual address to `VMSH_STAGE1_ARGS` struct, used to write stage1 arguments
+    vmsh_stage1_args: usize,
+    /// How much space we need to reserve for strings for stage1_args.
+    /// Needs to be page aligned
+    string_arg_size: usize,
+    /// Kernel symbols required by the binary that the kernel does not export
+    missing_symbols: Vec<String>,
+    /// Host address of the GOT entry of `RETURN_ADDRESS_SYMBOL`
+    return_address: Option<usize>,
+    /// virtual address of the `vmsh_stage1_init` function
+    pub init_func: usize,
+}
+
+fn find_loadable(loadables: &mut [Loadable], addr: usize) -> Option<&mut Loadable> {
+    loadables
+        .iter_mut()
+        .find(|loadable| loadable.mapping.contains(addr))
+}
+
+impl<'a> Loader<'a> {
+    pub fn new(
+        binary: &'a Binary<'a>,
+        kernel: &'a Kernel,
+        allocator: &'a mut PhysMemAllocator,
+    ) -> Result<Loader<'a>> {
+        let vbase = kernel.largest_gap.start;
+
+        let syms = binary
+            .syms
+            .iter()
+            .map(|(name, offset)| (*name, vbase + offset))
+            .collect::<HashMap<_, _>>();
+
+        Ok(Loader {
+            kernel,
+            virt_mem: None,
+            load_offsets: vec![],
+            allocator,
+            loadables: vec![],
+            binary,
+            init_func: vbase + binary.init_func,
+            vmsh_stage1_args: vbase + binary.vmsh_stage1_args,
+            lib_syms: syms,
+            string_arg_size: 0,
+            missing_symbols: vec![],
+            return_address: None,
+        })
+    }
+
+    fn vbase(&self) -> usize {
+        self.kernel.largest_gap.start
+    }
+
+    /// Release of the guest kernel, which stage1 cannot reliably read itself (procfs might not
+    /// be mounted).
+    fn kernel_version(&self) -> KernelVersion {
+        let release = match self.kernel.version(&self.allocator.hv) {
+            Ok(release) => release,
+            Err(e) => {
+                warn!("cannot detect the version of the guest kernel: {}", e);
+                return KernelVersion::UNKNOWN;
+            }
+        };
+        match parse_release(&release) {
+            Some(version) => {
+                debug!("guest kernel: Linux {}", release);
+                version
+            }
+            None => {
+                warn!("cannot parse kernel release {}", release);
+                KernelVersion::UNKNOWN
+            }
+        }
+    }
+
+    /// Module that registers the devices through the module loader of the guest, empty if
+    /// stage1 has to register them itself.
+    fn module_image(&self) -> Vec<u8> {
+        let image = module::probe(self.kernel, &self.allocator.hv).and_then(|target| {
+            let init = *require_with!(
+                self.lib_syms.get(MODULE_INIT_SYMBOL),
+                "stage1 has no {} function",
+                MODULE_INIT_SYMBOL
+            );
+            module::build(&target, &module::name(), init)
+        });
+        match image {
+            Ok(image) => {
+                info!("stage1 registers its devices through the module loader of the guest");
+                image
+            }
+            Err(e) => {
+                info!("stage1 registers its devices directly: {}", e);
+                vec![]
+            }
+        }
+    }
+
+    fn write_stage1_args(
+        &mut self,
+        command: &[String],
+        stage2_fallback_paths: &[String],
+        irq_nums: &[usize],
+        mmio_ranges: Vec<u64>,
+        pci_window: Option<PciWindow>,
+        module: &[u8],
+    ) -> Result<(DeviceStatus, DriverStatus, DeviceSlots)> {
+        let kernel_version = self.kernel_version();
+        let layouts = layouts::probe(self.kernel, &self.allocator.hv);
+        let virt_mem = require_with!(self.virt_mem.as_ref(), "no virtual memory assigned");
+        let string_mapping =
+            require_with!(virt_mem.mappings.last(), "no virtual mappings found").clone();
+
+        if stage2_fallback_paths.len() >= MAX_STAGE2_PATHS {
+            bail!(
+                "cannot pass {} fallback paths for stage2, stage1 supports at most {}",
+                stage2_fallback_paths.len(),
+                MAX_STAGE2_PATHS - 1
+            );
+        }
+
+        let mut strings: Vec<u8> = Vec::with_capacity(self.string_arg_size);
+        let mut push_string = |arg: &String| {
+            let ptr = strings.len() + string_mapping.virt_start;
+            strings.extend_from_slice(arg.as_bytes());
+            // make string null-terminated
+            strings.push(b'\0');
+            ptr as *mut libc::c_char
+        };
+
+        let mut argv = command.iter().map(&mut push_string).collect::<Vec<_>>();
+        let mut fallback_paths = stage2_fallback_paths
+            .iter()
+            .map(&mut push_string)
+            .collect::<Vec<_>>();
+        let module_addr = strings.len() + string_mapping.virt_start;
+        strings.extend_from_slice(module);
+
+        self.loadables.push(Loadable {
+            content: strings,
+            mapping: string_mapping,
+            virt_offset: 0,
+        });
+        // make argv null-terminated
+        argv.push(ptr::null_mut());
+        fallback_paths.push(ptr::null_mut());
+
+        let addr = self.vmsh_stage1_args;
+        let loadable = require_with!(
+            find_loadable(&mut self.loadables, addr),
+            "could not find elf loadable for vmsh_stage1_args"
+        );
+        let start = addr - (loadable.mapping.virt_start + loadable.virt_offset);
+        let range = start..(start + size_of::<Stage1Args>());
+        if range.end > loadable.content.len() {
+            if range.end > loadable.mapping.len {
+                bail!(
+                    "stage1 args exceeds section by {:#x} bytes",
+                    loadable.mapping.len - range.end
+                );
+            }
+            loadable.content.resize(range.end, 0);
+        }
+        let stage1_args = loadable.content[range].as_mut_ptr() as *mut Stage1Args;
+        let stage1_args = unsafe { &mut (*stage1_args) };
+
+        if mmio_ranges.len() > MAX_DEVICES {
+            bail!(
+                "cannot register {} devices, stage1 supports at most {}",
+                mmio_ranges.len(),
+                MAX_DEVICES
+            );
+        }
+        stage1_args.argv[0..argv.len()].clone_from_slice(argv.as_slice());
+        stage1_args.stage2_fallback_paths[0..fallback_paths.len()]
+            .clone_from_slice(fallback_paths.as_slice());
+        stage1_args.device_addrs[0..mmio_ranges.len()].clone_from_slice(&mmio_ranges);
+        stage1_args.device_status = DeviceState::Initializing;
+        if irq_nums.len() > MAX_DEVICES {
+            bail!(
+                "got {} interrupt lines for {} slots",
+                irq_nums.len(),
+                MAX_DEVICES
+            );
+        }
+        stage1_args.irq_nums[0..irq_nums.len()].clone_from_slice(irq_nums);
+        stage1_args.kernel_version = kernel_version;
+        stage1_args.layouts = layouts;
+        if !module.is_empty() {
+            stage1_args.module_image = module_addr as libc::c_ulonglong;
+            stage1_args.module_len = module.len() as libc::c_ulonglong;
+        }
+        if let Some(pci) = pci_window {
+            stage1_args.pci_config_addr = pci.config_addr;
+            stage1_args.pci_mem_start = pci.mem_start;
+            stage1_args.pci_mem_end = pci.mem_end;
+        }
+
+        let stage1_args_addr = stage1_args as *const Stage1Args as usize;
+
+        let dev_offset =
+            &stage1_args.device_status as *const DeviceState as usize - stage1_args_addr;
+        let drv_offset =
+            &stage1_args.driver_status as *const DeviceState as usize - stage1_args_addr;
+        let addrs_offset = stage1_args.device_addrs.as_ptr() as usize - stage1_args_addr;
+        let dev_gen_offset =
+            &stage1_args.device_generation as *const libc::c_ulonglong as usize - stage1_args_addr;
+        let drv_gen_offset =
+            &stage1_args.driver_generation as *const libc::c_ulonglong as usize - stage1_args_addr;
+        let host_offset =
+            addr - loadable.mapping.virt_start + loadable.mapping.phys_start.host_addr();
+        Ok((
+            DeviceStatus {
+                host_addr: host_offset + dev_offset,
+            },
+            DriverStatus {
+                host_addr: host_offset + drv_offset,
+            },
+            DeviceSlots {
+                device_addrs: host_offset + addrs_offset,
+                device_generation: host_offset + dev_gen_offset,
+                driver_generation: host_offset + drv_gen_offset,
+            },
+        ))
+    }
+
+    pub fn load_binary(
+        &mut self,
+        command: &[String],
+        stage2_fallback_paths: &[String],
+        irq_nums: &[usize],
+        mmio_ranges: Vec<u64>,
+        pci_window: Option<PciWindow>,
+    ) -> Result<LoadedBinary> {
+        let binary = self.binary;
+        let module = self.module_image();
+
+        self.string_arg_size = page_align(
+            command
+                .iter()
+                .chain(stage2_fallback_paths)
+                .map(|c| c.len() + 1)
+                .sum::<usize>()
+                + module.len(),
+        );
+        let res = binary.elf.load(self);
+        if !self.missing_symbols.is_empty() {
+            return Err(VmshError::UnsupportedKernel(format!(
+                "guest kernel does not export symbols required by stage1: {}",
+                compat::describe_missing(&self.missing_symbols)
+            )));
+        }
+        try_core_res!(res, "cannot load elf binary");
+
+        let (device_status, driver_status, device_slots) = try_with!(
+            self.write_stage1_args(
+                command,
+                stage2_fallback_paths,
+                irq_nums,
+                mmio_ranges,
+                pci_window,
+                &module
+            ),
+            "failed to write stage1 arguments"
+        );
+
+        let mem = require_with!(self.virt_mem.take(), "BUG, no virtual memory assigned");
+        let return_address = require_with!(
+            self.return_address,
+            "binary does not reference {}",
+            RETURN_ADDRESS_SYMBOL
+        );
+        Ok(LoadedBinary {
+            mem,
+            loadables: std::mem::take(&mut self.loadables),
+            return_address,
+            device_status,
+            driver_status,
+            device_slots,
+        })
+    }
+}
+
+/// A binary relocated by `Loader::load_binary`. Its memory is not mapped into the guest yet, so
+/// it can be uploaded while the VM is running.
+pub struct LoadedBinary {
+    pub mem: UnmappedMem,
+    loadables: Vec<Loadable>,
+    /// Host address of the GOT entry of `RETURN_ADDRESS_SYMBOL`
+    return_address: usize,
+    pub device_status: DeviceStatus,
+    pub driver_status: DriverStatus,
+    pub device_slots: DeviceSlots,
+}
+
+impl LoadedBinary {
+    /// Copies the binary into its guest memory.
+    pub fn upload(&self, hv: &Hypervisor) -> Result<()> {
+        let writes = self
+            .loadables
+            .iter()
+            .map(|l| {
+                (
+                    l.mapping.phys_start.host_addr() + l.virt_offset,
+                    l.content.as_slice(),
+                )
+            })
+            .collect::<Vec<_>>();
+        process_write_vectored(hv.pid, &writes)
+    }
+
+    /// Sets the instruction stage1 returns to. It is only known once the VM is stopped for the
+    /// last time, after the upload.
+    pub fn set_return_address(&self, hv: &Hypervisor, addr: usize) -> Result<()> {
+        try_with!(
+            process_write(hv.pid, self.return_address as *mut libc::c_void, &addr),
+            "cannot write return address of stage1"
+        );
+        Ok(())
+    }
+}
+
+type ElfResult = std::result::Result<(), ElfLoaderErr>;
+
+struct Loadable {
+    content: Vec<u8>,
+    mapping: MappedMemory,
+    virt_offset: usize,
+}
+
+macro_rules! try_elf {
+    ($expr: expr, $str: expr) => {
+        match $expr {
+            Ok(val) => val,
+            Err(e) => {
+                error!("{}: {}", $str, e);
+                return Err(ElfLoaderErr::ElfParser { source: $str });
+            }
+        }
+    };
+}
+macro_rules! require_elf {
+    ($expr: expr, $str: expr) => {
+        match $expr {
+            Some(val) => val,
+            None => {
+                return Err(ElfLoaderErr::ElfParser { source: $str });
+            }
+        }
+    };
+}
+
+fn resolve_symbol(
+    name: &str,
+    syms: &HashMap<String, usize>,
+    lib_syms: &HashMap<&str, usize>,
+) -> Option<usize> {
+    if let Some(sym) = syms.get(name) {
+        return Some(*sym);
+    }
+
+    if let Some(sym) = lib_syms.get(name) {
+        return Some(*sym);
+    }
+
+    compat::resolve(name, syms)
+}
+
+impl<'a> ElfLoader for Loader<'a> {
+    fn allocate(&mut self, headers: LoadableHeaders) -> ElfResult {
+        let allocs = headers.map(|h| {
+            debug!(
+                "allocate base = {:#x} size = {:#x} flags = {}",
+                h.virtual_addr(),
+                h.mem_size(),
+                h.flags()
+            );
+            let mut prot = ProtFlags::PROT_READ;
+            if h.flags().is_execute() {
+                prot |= ProtFlags::PROT_EXEC;
+            }
+            if h.flags().is_write() {
+                prot |= ProtFlags::PROT_WRITE;
+            }
+            let virtual_addr = h.virtual_addr() as usize;
+            let start = page_start(virtual_addr);
+
+            VirtAlloc {
+                virt_start: self.vbase() + start,
+                virt_offset: virtual_addr - start,
+                len: page_align(virtual_addr + h.mem_size() as usize) - start,
+                prot,
+            }
+        });
+        let mut allocs = allocs.collect::<Vec<_>>();
+        allocs.sort_by_key(|k| k.virt_start);
+        let last_addr = match allocs.last() {
+            Some(a) => a.virt_end(),
+            None => {
+                return Err(ElfLoaderErr::ElfParser {
+                    source: "elf has no section",
+                })
+            }
+        };
+
+        // put strings for stage1 args before elf binary
+        let last = VirtAlloc {
+            virt_start: last_addr,
+            virt_offset: 0,
+            len: self.string_arg_size,
+            prot: ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+        };
+        if !LINUX_KERNEL_KASLR_RANGE.contains(&(last.virt_start + last.len)) {
+            error!("virtual memory allocation ({:#x}-{:#x}) does not fit into kernel aslr range ({:#x}-{:#x}).",
+                  last.virt_start, last.virt_start + last.len,
+                  LINUX_KERNEL_KASLR_RANGE.start, LINUX_KERNEL_KASLR_RANGE.end
+            );
+            allocs.iter().for_each(|a| {
+                error!(
+                    "{:#x}-{:#x} ({:?})",
+                    a.virt_start,
+                    a.virt_start + a.len,
+                    a.prot
+                );
+            });
+            return Err(ElfLoaderErr::ElfParser {
+                source: "virtual memory allocation does not fit into kernel aslr range",
+            });
+        }
+        allocs.push(last);
+
+        allocs.iter().for_each(|a| {
+            if a.virt_start > self.vbase() {
+                debug!(
+                    "{:#x}-{:#x} ({:?})",
+                    a.virt_start - self.vbase(),
+                    a.virt_start + a.len - self.vbase(),
+                    a.prot
+                );
+            }
+        });
+
+        if allocs.is_empty() {
+            return Err(ElfLoaderErr::ElfParser {
+                source: "no loadable sections found in elf file",
+            });
+        }
+        self.virt_mem = Some(try_elf!(
+            self.allocator.virt_reserve(&allocs),
+            "cannot allocate memory"
+        ));
+        self.load_offsets = allocs.iter().map(|v| v.virt_offset).collect::<Vec<_>>();
+
+        Ok(())
+    }
+
+    fn load(&mut self, flags: Flags, base: VAddr, region: &[u8]) -> ElfResult {
+        let start = self.vbase() + base as usize;
+        let end = self.vbase() + base as usize + region.len();
+        debug!(
+            "load region into = {:#x} -- {:#x} ({:?})",
+            start, end, flags
+        );
+        let mem = require_elf!(
+            self.virt_mem.as_ref(),
+            "BUG: no virtual memory was allocated"
+        );
+        let (idx, mapping) = require_elf!(
+            mem.mappings
+                .iter()
+                .enumerate()
+                .find(|(_, mapping)| mapping.virt_start == page_start(start)),
+            {
+                error!(
+                    "received loadable that was not allocated before at {:#x} ({:#x})",
+                    start, base
+                );
+                "BUG: received loadable that was not allocated before"
+            }
+        );
+        self.loadables.push(Loadable {
+            content: region.to_vec(),
+            mapping: mapping.clone(),
+            virt_offset: self.load_offsets[idx],
+        });
+        Ok(())
+    }
+
+    fn relocate(&mut self, entry: RelocationEntry) -> ElfResult {
+        let addr = self.vbase() + entry.offset as usize;
+        let syms = &self.kernel.symbols;
+        let lib_syms = &self.lib_syms;
+        let vbase = self.vbase();
+        let loadable = require_elf!(find_loadable(&mut self.loadables, addr), {
+            error!(
+                "no loadable found for relocation address: {:#x} ({:#x})",
+                addr, entry.offset as usize
+            );
+            "no loadable found for relocation address"
+        });
+        let start = addr - (loadable.mapping.virt_start + loadable.virt_offset);
+
+        let kind = match arch::relocation(&entry.rtype) {
+            Some(kind) => kind,
+            None => {
+                warn!("loader: unhandled relocation: {:?}", entry.rtype);
+                return Err(ElfLoaderErr::UnsupportedRelocationEntry);
+            }
+        };
+        let addend = match entry.addend {
+            Some(v) => v,
+            None => {
+                warn!("{:?} relocation has no addend", entry.rtype);
+                return Err(ElfLoaderErr::UnsupportedRelocationEntry);
+            }
+        };
+
+        match kind {
+            Relocation::Relative => {
+                // This is a relative relocation, add the offset (where we put our
+                // binary in the vspace) to the addend and we're done.
+                let dest_addr = vbase + addend as usize;
+                debug!("{:?} *{:#x} = {:#x}", entry.rtype, addr, dest_addr);
+                let range = start..(start + size_of_val(&dest_addr));
+                loadable.content[range].copy_from_slice(&dest_addr.to_ne_bytes());
+                Ok(())
+            }
+            Relocation::Symbol => {
+                let sym = &self.binary.dyn_syms[entry.index as usize];
+                if sym.get_binding()? == Binding::Weak {
+                    // we have some weak symbols that are included by default
+                    // but not used for anything in the kernel.
+                    // Seem to be safe to ignore
+                    return Ok(());
+                }
+
+                let sym_name = sym.get_name(&self.binary.elf.file)?;
+                debug!("{:?} *{:#x} = @ {}", entry.rtype, addr, sym_name);
+                if sym_name == RETURN_ADDRESS_SYMBOL {
+                    self.return_address = Some(
+                        loadable.mapping.phys_start.host_addr() + addr
+                            - loadable.mapping.virt_start,
+                    );
+                    return Ok(());
+                }
+                let symbol = match resolve_symbol(sym_name, syms, lib_syms) {
+                    Some(symbol) => symbol,
+                    None => {
+                        // keep going, so we can report all missing symbols at once
+                        error!("binary requires unknown symbol: {}", sym_name);
+                        if !self.missing_symbols.iter().any(|s| s == sym_name) {
+                            self.missing_symbols.push(sym_name.to_string());
+                        }
+                        return Ok(());
+                    }
+                };
+                let dest_addr = (symbol + addend as usize).to_ne_bytes();
+                let range = start..(start + size_of_val(&symbol));
+                loadable.content[range].clone_from_slice(&dest_addr);
+
+                Ok(())
+            }
+        }
+    }
+}
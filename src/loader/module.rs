@@ -0,0 +1,286 @@
+//! Registration of the devices through the module loader of the guest. vmsh fabricates a
+//! minimal kernel module whose init function is `vmsh_module_init` of the loaded stage1. stage2
+//! passes it to `init_module`, so the kernel runs the registration like the one of any other
+//! module and lists it as `vmsh_<pid>` in /proc/modules. If the kernel rejects the module, i.e.
+//! because it enforces signatures or module loading is disabled, stage1 registers the devices
+//! itself as before.
+//!
+//! The module has no code of its own, only the `struct module` with the address of the init
+//! function and the `.modinfo` the loader checks. `struct module` depends on the kernel config,
+//! so its layout is read from the BTF type information of the kernel. Kernels that check
+//! symbol versions (CONFIG_MODVERSIONS) would reject it, so the module path is only taken if
+//! the vermagic of the kernel does not contain `modversions`.
+
+use simple_error::{bail, require_with};
+
+use crate::btf::Btf;
+use crate::kernel::Kernel;
+use crate::kvm::hypervisor::Hypervisor;
+use crate::result::Result;
+
+/// `MODULE_NAME_LEN` on 64 bit
+const MODULE_NAME_LEN: usize = 56;
+
+const EM_X86_64: u16 = 62;
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHF_WRITE: u64 = 1;
+const SHF_ALLOC: u64 = 2;
+
+/// Size of `Elf64_Ehdr`
+const EHDR_LEN: usize = 64;
+/// Size of `Elf64_Shdr`
+const SHDR_LEN: usize = 64;
+/// Size of `Elf64_Sym`
+const SYM_LEN: usize = 24;
+
+/// What the module loader of the guest expects from a module.
+#[derive(Debug, PartialEq)]
+pub struct ModuleTarget {
+    pub vermagic: String,
+    /// size of `struct module`
+    pub size: u32,
+    /// offset of `struct module::name`
+    pub name: u32,
+    /// offset of `struct module::init`
+    pub init: u32,
+}
+
+/// Reads what a module for the guest kernel needs, fails if the kernel would not accept a
+/// module from vmsh.
+pub fn probe(kernel: &Kernel, hv: &Hypervisor) -> Result<ModuleTarget> {
+    if !cfg!(target_arch = "x86_64") {
+        bail!("modules are only built for x86_64");
+    }
+    // stage1 passes the module to stage2 on the pipe of the usermode driver API
+    if !kernel.symbols.contains_key("fork_usermode_driver") {
+        bail!("kernel cannot run usermode drivers");
+    }
+    let data = kernel.btf(hv)?;
+    let module = Btf::parse(&data)?.find_struct("module")?;
+    let vermagic = kernel.vermagic(hv)?;
+    if vermagic.split(' ').any(|w| w == "modversions") {
+        bail!("kernel checks symbol versions of modules");
+    }
+    Ok(ModuleTarget {
+        vermagic,
+        size: module.size,
+        name: module.offset("name")?,
+        init: module.offset("init")?,
+    })
+}
+
+/// Name the module is loaded with, unique for each vmsh process because the module stays
+/// loaded after stage1 is removed.
+pub fn name() -> String {
+    format!("vmsh_{}", std::process::id())
+}
+
+struct Section<'a> {
+    name: &'a str,
+    kind: u32,
+    flags: u64,
+    data: &'a [u8],
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+fn pad_to(out: &mut Vec<u8>, align: usize) {
+    out.resize((out.len() + align - 1) / align * align, 0);
+}
+
+/// Relocatable elf with the `sections` after the null section.
+fn write_elf(sections: &[Section]) -> Vec<u8> {
+    let mut shstrtab = vec![0u8];
+    let mut name_offsets = vec![];
+    for section in sections.iter().map(|s| s.name).chain(Some(".shstrtab")) {
+        name_offsets.push(shstrtab.len() as u32);
+        shstrtab.extend_from_slice(section.as_bytes());
+        shstrtab.push(0);
+    }
+    let shstrtab_section = Section {
+        name: ".shstrtab",
+        kind: SHT_STRTAB,
+        flags: 0,
+        data: &shstrtab,
+        link: 0,
+        info: 0,
+        align: 1,
+        entsize: 0,
+    };
+    let all = sections.iter().chain(Some(&shstrtab_section));
+
+    let mut out = vec![0u8; EHDR_LEN];
+    let mut offsets = vec![];
+    for section in all.clone() {
+        pad_to(&mut out, section.align as usize);
+        offsets.push(out.len() as u64);
+        out.extend_from_slice(section.data);
+    }
+    pad_to(&mut out, 8);
+    let shoff = out.len() as u64;
+    let shnum = sections.len() as u16 + 2;
+
+    // null section
+    out.extend_from_slice(&[0; SHDR_LEN]);
+    for ((section, offset), name) in all.zip(offsets).zip(name_offsets) {
+        out.extend_from_slice(&name.to_le_bytes());
+        out.extend_from_slice(&section.kind.to_le_bytes());
+        out.extend_from_slice(&section.flags.to_le_bytes());
+        // sh_addr
+        out.extend_from_slice(&0u64.to_le_bytes());
+        out.extend_from_slice(&offset.to_le_bytes());
+        out.extend_from_slice(&(section.data.len() as u64).to_le_bytes());
+        out.extend_from_slice(&section.link.to_le_bytes());
+        out.extend_from_slice(&section.info.to_le_bytes());
+        out.extend_from_slice(&section.align.to_le_bytes());
+        out.extend_from_slice(&section.entsize.to_le_bytes());
+    }
+
+    let mut ehdr = Vec::with_capacity(EHDR_LEN);
+    // ELFCLASS64, ELFDATA2LSB, EV_CURRENT, ELFOSABI_NONE
+    ehdr.extend_from_slice(b"\x7fELF\x02\x01\x01\x00");
+    ehdr.extend_from_slice(&[0; 8]);
+    // ET_REL
+    ehdr.extend_from_slice(&1u16.to_le_bytes());
+    ehdr.extend_from_slice(&EM_X86_64.to_le_bytes());
+    ehdr.extend_from_slice(&1u32.to_le_bytes());
+    // e_entry, e_phoff
+    ehdr.extend_from_slice(&[0; 16]);
+    ehdr.extend_from_slice(&shoff.to_le_bytes());
+    // e_flags
+    ehdr.extend_from_slice(&0u32.to_le_bytes());
+    ehdr.extend_from_slice(&(EHDR_LEN as u16).to_le_bytes());
+    // e_phentsize, e_phnum
+    ehdr.extend_from_slice(&[0; 4]);
+    ehdr.extend_from_slice(&(SHDR_LEN as u16).to_le_bytes());
+    ehdr.extend_from_slice(&shnum.to_le_bytes());
+    // .shstrtab is the last section
+    ehdr.extend_from_slice(&(shnum - 1).to_le_bytes());
+    out[..EHDR_LEN].copy_from_slice(&ehdr);
+    out
+}
+
+/// Builds the module `name` for `target` whose init function is at `init_func` in the guest.
+pub fn build(target: &ModuleTarget, name: &str, init_func: usize) -> Result<Vec<u8>> {
+    if name.len() >= MODULE_NAME_LEN {
+        bail!("module name {} is too long", name);
+    }
+    let mut this_module = vec![0u8; target.size as usize];
+    let name_field = require_with!(
+        this_module.get_mut(target.name as usize..target.name as usize + name.len()),
+        "struct module of {} bytes has no space for the name at {}",
+        target.size,
+        target.name
+    );
+    name_field.copy_from_slice(name.as_bytes());
+    let init = init_func.to_le_bytes();
+    let init_field = require_with!(
+        this_module.get_mut(target.init as usize..target.init as usize + init.len()),
+        "struct module of {} bytes has no space for the init function at {}",
+        target.size,
+        target.init
+    );
+    init_field.copy_from_slice(&init);
+
+    let modinfo = format!("license=GPL\0name={}\0vermagic={}\0", name, target.vermagic);
+    // the loader wants a symbol table, even if it only has the null symbol
+    let symtab = [0u8; SYM_LEN];
+    let strtab = [0u8];
+    let sections = [
+        Section {
+            name: ".modinfo",
+            kind: SHT_PROGBITS,
+            flags: SHF_ALLOC,
+            data: modinfo.as_bytes(),
+            link: 0,
+            info: 0,
+            align: 1,
+            entsize: 0,
+        },
+        Section {
+            name: ".gnu.linkonce.this_module",
+            kind: SHT_PROGBITS,
+            flags: SHF_ALLOC | SHF_WRITE,
+            data: &this_module,
+            link: 0,
+            info: 0,
+            // struct module is cacheline aligned
+            align: 64,
+            entsize: 0,
+        },
+        Section {
+            name: ".symtab",
+            kind: SHT_SYMTAB,
+            flags: 0,
+            data: &symtab,
+            // .strtab
+            link: 4,
+            // index of the first non-local symbol
+            info: 1,
+            align: 8,
+            entsize: SYM_LEN as u64,
+        },
+        Section {
+            name: ".strtab",
+            kind: SHT_STRTAB,
+            flags: 0,
+            data: &strtab,
+            link: 0,
+            info: 0,
+            align: 1,
+            entsize: 0,
+        },
+    ];
+    Ok(write_elf(&sections))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+    use xmas_elf::header::Machine;
+    use xmas_elf::sections::SectionData;
+    use xmas_elf::ElfFile;
+
+    #[test]
+    fn build_module() {
+        let target = ModuleTarget {
+            vermagic: "6.1.0 SMP preempt mod_unload ".into(),
+            size: 1280,
+            name: 24,
+            init: 376,
+        };
+        let image = build(&target, "vmsh_42", 0xffffffffc0001000).unwrap();
+        let elf = ElfFile::new(&image).unwrap();
+        assert!(elf.header.pt2.machine().as_machine() == Machine::X86_64);
+
+        let modinfo = elf.find_section_by_name(".modinfo").unwrap();
+        assert_eq!(
+            modinfo.raw_data(&elf),
+            &b"license=GPL\0name=vmsh_42\0vermagic=6.1.0 SMP preempt mod_unload \0"[..]
+        );
+
+        let this_module = elf
+            .find_section_by_name(".gnu.linkonce.this_module")
+            .unwrap();
+        let data = this_module.raw_data(&elf);
+        assert_eq!(data.len(), 1280);
+        assert_eq!(this_module.offset() % 64, 0);
+        assert_eq!(&data[24..32], b"vmsh_42\0");
+        let init = u64::from_le_bytes(data[376..384].try_into().unwrap());
+        assert_eq!(init, 0xffffffffc0001000);
+
+        let symtab = elf.find_section_by_name(".symtab").unwrap();
+        match symtab.get_data(&elf).unwrap() {
+            SectionData::SymbolTable64(syms) => assert_eq!(syms.len(), 1),
+            _ => panic!("no symbol table"),
+        }
+
+        let target = ModuleTarget { size: 64, ..target };
+        assert!(build(&target, "vmsh_42", 0).is_err());
+    }
+}
@@ -0,0 +1,174 @@
+use nix::sys::uio::{process_vm_readv, RemoteIoVec};
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::cmp::min;
+use std::fs;
+use std::io::{self, IoSliceMut, Write};
+use std::path::PathBuf;
+
+use crate::guest_mem::GuestMem;
+use crate::kvm;
+use crate::kvm::hypervisor::memory::process_write_scattered;
+use crate::kvm::hypervisor::{Hypervisor, VmSelector};
+use crate::result::Result;
+
+pub enum MemAction {
+    /// Read `len` bytes, as hexdump to stdout or raw into `output`
+    Read { len: usize, output: Option<PathBuf> },
+    /// Write the content of `input`
+    Write { input: PathBuf },
+}
+
+pub struct MemOptions {
+    pub pid: Pid,
+    pub vm: Option<VmSelector>,
+    pub addr: usize,
+    /// Treat `addr` as virtual address of the kernel, or of the process whose page table is at
+    /// `cr3`
+    pub virtual_addr: bool,
+    pub cr3: Option<usize>,
+    pub action: MemAction,
+}
+
+/// Parses an address like `0x1000` or `4096`.
+pub fn parse_addr(s: &str) -> Result<usize> {
+    let s = s.trim();
+    let res = match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse::<usize>(),
+    };
+    Ok(try_with!(res, "invalid address: {}", s))
+}
+
+/// Guest memory that is contiguous in the hypervisor
+struct Region {
+    guest_addr: usize,
+    host_addr: usize,
+    len: usize,
+}
+
+fn regions(vm: &Hypervisor, opts: &MemOptions) -> Result<Vec<Region>> {
+    if !opts.virtual_addr {
+        let maps = try_with!(vm.get_maps(), "cannot get guest memory");
+        return Ok(maps
+            .iter()
+            .map(|m| Region {
+                guest_addr: m.phys_addr,
+                host_addr: m.start,
+                len: m.size(),
+            })
+            .collect());
+    }
+    let mem = try_with!(GuestMem::new(vm), "cannot access guest memory");
+    let mappings = match opts.cr3 {
+        Some(cr3) => try_with!(
+            mem.process_mappings(vm, cr3),
+            "cannot read page tables at {:#x}",
+            cr3
+        ),
+        None => try_with!(mem.kernel_mappings(vm), "cannot read kernel page tables"),
+    };
+    Ok(mappings
+        .iter()
+        .map(|m| Region {
+            guest_addr: m.virt_start,
+            host_addr: m.phys_start.host_addr(),
+            len: m.len,
+        })
+        .collect())
+}
+
+/// Translates `len` bytes at the guest address `addr` to hypervisor memory. The range may
+/// span several regions, but must not contain holes.
+fn translate(regions: &[Region], addr: usize, len: usize) -> Result<Vec<RemoteIoVec>> {
+    let mut iovs = vec![];
+    let end = addr + len;
+    let mut cur = addr;
+    while cur < end {
+        let region = regions
+            .iter()
+            .find(|r| r.guest_addr <= cur && cur < r.guest_addr + r.len);
+        let region = match region {
+            Some(r) => r,
+            None => bail!("address {:#x} is not backed by guest memory", cur),
+        };
+        let piece = min(region.guest_addr + region.len, end) - cur;
+        iovs.push(RemoteIoVec {
+            base: region.host_addr + (cur - region.guest_addr),
+            len: piece,
+        });
+        cur += piece;
+    }
+    Ok(iovs)
+}
+
+/// Formats `data` like `hexdump -C`, with addresses starting at `addr`.
+fn hexdump(out: &mut dyn Write, addr: usize, data: &[u8]) -> io::Result<()> {
+    for (i, line) in data.chunks(16).enumerate() {
+        write!(out, "{:016x} ", addr + i * 16)?;
+        for j in 0..16 {
+            if j == 8 {
+                write!(out, " ")?;
+            }
+            match line.get(j) {
+                Some(b) => write!(out, " {:02x}", b)?,
+                None => write!(out, "   ")?,
+            }
+        }
+        let ascii = line
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect::<String>();
+        writeln!(out, "  |{}|", ascii)?;
+    }
+    Ok(())
+}
+
+pub fn mem(opts: &MemOptions) -> Result<()> {
+    if opts.cr3.is_some() && !opts.virtual_addr {
+        bail!("a page table can only be used with virtual addresses");
+    }
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid, opts.vm),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    // the page tables must not change while we walk them
+    vm.stop()?;
+    let regions = regions(&vm, opts)?;
+
+    match &opts.action {
+        MemAction::Read { len, output } => {
+            let iovs = translate(&regions, opts.addr, *len)?;
+            let mut buf = vec![0u8; *len];
+            let read = try_with!(
+                process_vm_readv(opts.pid, &mut [IoSliceMut::new(&mut buf)], &iovs),
+                "cannot read hypervisor memory"
+            );
+            buf.truncate(read);
+            match output {
+                Some(path) => try_with!(fs::write(path, &buf), "cannot write {}", path.display()),
+                None => try_with!(
+                    hexdump(&mut io::stdout().lock(), opts.addr, &buf),
+                    "cannot write to stdout"
+                ),
+            }
+        }
+        MemAction::Write { input } => {
+            vm.check_writable("writing guest memory")?;
+            let data = try_with!(fs::read(input), "cannot read {}", input.display());
+            let iovs = translate(&regions, opts.addr, data.len())?;
+            try_with!(
+                process_write_scattered(opts.pid, &data, &iovs),
+                "cannot write hypervisor memory"
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,208 @@
+//! Counters for long running attachments. They are served in the Prometheus text format over
+//! HTTP (`--metrics-addr`) and logged on SIGUSR2. SIGUSR1 is taken by `vmsh detach`.
+
+use log::{error, info, warn};
+use signal_hook::consts::signal::SIGUSR2;
+use signal_hook::iterator::Signals;
+use simple_error::try_with;
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::result::Result;
+
+pub struct Metrics {
+    pub mmio_exits: AtomicU64,
+    pub irqs_sent: AtomicU64,
+    pub irq_ack_timeouts: AtomicU64,
+    pub block_reads: AtomicU64,
+    pub block_read_bytes: AtomicU64,
+    pub block_writes: AtomicU64,
+    pub block_write_bytes: AtomicU64,
+    /// Flushes, discards and write zeroes requests
+    pub block_other_ops: AtomicU64,
+    /// Bytes the guest read from the console
+    pub console_rx_bytes: AtomicU64,
+    /// Bytes the guest wrote to the console
+    pub console_tx_bytes: AtomicU64,
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+/// Adds `n` to a counter, i.e. `&METRICS.irqs_sent`.
+pub fn add(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+impl Metrics {
+    const fn new() -> Metrics {
+        Metrics {
+            mmio_exits: AtomicU64::new(0),
+            irqs_sent: AtomicU64::new(0),
+            irq_ack_timeouts: AtomicU64::new(0),
+            block_reads: AtomicU64::new(0),
+            block_read_bytes: AtomicU64::new(0),
+            block_writes: AtomicU64::new(0),
+            block_write_bytes: AtomicU64::new(0),
+            block_other_ops: AtomicU64::new(0),
+            console_rx_bytes: AtomicU64::new(0),
+            console_tx_bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Name, help text and value of each counter
+    fn counters(&self) -> [(&'static str, &'static str, &AtomicU64); 10] {
+        [
+            (
+                "vmsh_mmio_exits_total",
+                "MMIO accesses of the guest handled by vmsh",
+                &self.mmio_exits,
+            ),
+            (
+                "vmsh_irqs_sent_total",
+                "Interrupts sent to the guest",
+                &self.irqs_sent,
+            ),
+            (
+                "vmsh_irq_ack_timeouts_total",
+                "Interrupts re-sent because the guest did not acknowledge them",
+                &self.irq_ack_timeouts,
+            ),
+            (
+                "vmsh_block_reads_total",
+                "Read requests of block devices",
+                &self.block_reads,
+            ),
+            (
+                "vmsh_block_read_bytes_total",
+                "Bytes read by block devices",
+                &self.block_read_bytes,
+            ),
+            (
+                "vmsh_block_writes_total",
+                "Write requests of block devices",
+                &self.block_writes,
+            ),
+            (
+                "vmsh_block_write_bytes_total",
+                "Bytes written by block devices",
+                &self.block_write_bytes,
+            ),
+            (
+                "vmsh_block_other_ops_total",
+                "Flush, discard and write zeroes requests of block devices",
+                &self.block_other_ops,
+            ),
+            (
+                "vmsh_console_rx_bytes_total",
+                "Bytes the guest read from the console",
+                &self.console_rx_bytes,
+            ),
+            (
+                "vmsh_console_tx_bytes_total",
+                "Bytes the guest wrote to the console",
+                &self.console_tx_bytes,
+            ),
+        ]
+    }
+
+    /// Formats the counters in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, help, value) in self.counters() {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+        out
+    }
+}
+
+fn respond(mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    // the path does not matter, read the request head so that clients see a clean close
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.ends_with(b"\r\n\r\n") && head.len() < 16 * 1024 {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let body = METRICS.render();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+}
+
+/// Serves the metrics over HTTP on `addr` in a background thread.
+pub fn serve(addr: SocketAddr) -> Result<()> {
+    let listener = try_with!(TcpListener::bind(addr), "cannot listen on {}", addr);
+    info!("serving metrics on http://{}/metrics", addr);
+    try_with!(
+        thread::Builder::new()
+            .name("metrics".into())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            if let Err(e) = respond(stream) {
+                                warn!("cannot send metrics: {}", e);
+                            }
+                        }
+                        Err(e) => error!("cannot accept metrics connection: {}", e),
+                    }
+                }
+            }),
+        "cannot spawn metrics thread"
+    );
+    Ok(())
+}
+
+/// Logs the metrics whenever vmsh receives SIGUSR2. Only the first call has an effect.
+pub fn log_on_signal() -> Result<()> {
+    static INSTALLED: AtomicBool = AtomicBool::new(false);
+    if INSTALLED.swap(true, Ordering::AcqRel) {
+        return Ok(());
+    }
+    let mut signals = try_with!(Signals::new([SIGUSR2]), "cannot handle SIGUSR2");
+    try_with!(
+        thread::Builder::new()
+            .name("metrics-signal".into())
+            .spawn(move || {
+                for _ in signals.forever() {
+                    info!("metrics:\n{}", METRICS.render());
+                }
+            }),
+        "cannot spawn metrics signal thread"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_counters() {
+        let metrics = Metrics::new();
+        add(&metrics.mmio_exits, 3);
+        add(&metrics.block_write_bytes, 4096);
+        add(&metrics.block_write_bytes, 512);
+        let text = metrics.render();
+        assert!(text.starts_with(
+            "# HELP vmsh_mmio_exits_total MMIO accesses of the guest handled by vmsh\n\
+             # TYPE vmsh_mmio_exits_total counter\n\
+             vmsh_mmio_exits_total 3\n"
+        ));
+        assert!(text.contains("\nvmsh_block_write_bytes_total 4608\n"));
+        assert_eq!(text.lines().count(), 30);
+    }
+}
@@ -0,0 +1,229 @@
+//! Builds the block device image from an OCI container image, i.e.
+//! `vmsh attach -f oci://docker.io/library/alpine:latest`.
+//!
+//! The image is pulled with skopeo into an OCI layout, its layers are unpacked with tar into a
+//! root directory and `mkfs.ext4 -d` turns that into an ext4 image, like the disk images of our
+//! tests (`nix/build-disk-image.nix`) are built.
+
+use ioutils::tmp::{tempdir, TempDir};
+use log::{debug, info};
+use serde::Deserialize;
+use simple_error::{bail, require_with, try_with};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::result::Result;
+
+pub const OCI_PREFIX: &str = "oci://";
+
+/// Added to the size of the root filesystem, for ext4 metadata and files written by the command.
+const IMAGE_HEADROOM: u64 = 256 * 1024 * 1024;
+
+#[derive(Deserialize)]
+struct Descriptor {
+    digest: String,
+}
+
+#[derive(Deserialize)]
+struct Index {
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    layers: Vec<Descriptor>,
+}
+
+/// An ext4 image in a temporary directory, removed on drop.
+pub struct OciImage {
+    _dir: TempDir,
+    pub path: PathBuf,
+}
+
+/// Files of lower layers that a layer removes.
+#[derive(Debug, PartialEq)]
+enum Whiteout {
+    /// Removes the file or directory at this path.
+    Path(PathBuf),
+    /// Removes everything in this directory.
+    Opaque(PathBuf),
+}
+
+fn whiteout(entry: &str) -> Option<Whiteout> {
+    let path = Path::new(entry.trim_start_matches("./"));
+    let name = path.file_name()?.to_str()?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    if name == ".wh..wh..opq" {
+        Some(Whiteout::Opaque(dir.to_path_buf()))
+    } else {
+        name.strip_prefix(".wh.")
+            .map(|name| Whiteout::Path(dir.join(name)))
+    }
+}
+
+fn run(cmd: &mut Command) -> Result<String> {
+    debug!("$ {:?}", cmd);
+    let output = try_with!(cmd.output(), "cannot run {:?}", cmd);
+    if !output.status.success() {
+        bail!(
+            "{:?} failed ({}): {}",
+            cmd,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn blob_path(layout: &Path, digest: &str) -> Result<PathBuf> {
+    let (algorithm, hash) = require_with!(digest.split_once(':'), "invalid digest {}", digest);
+    Ok(layout.join("blobs").join(algorithm).join(hash))
+}
+
+fn read_json<T: for<'a> Deserialize<'a>>(path: &Path) -> Result<T> {
+    let content = try_with!(fs::read_to_string(path), "cannot read {}", path.display());
+    Ok(try_with!(
+        serde_json::from_str(&content),
+        "cannot parse {}",
+        path.display()
+    ))
+}
+
+fn remove(path: &Path) -> Result<()> {
+    let res = match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => Err(e),
+    };
+    try_with!(res, "cannot remove {}", path.display());
+    Ok(())
+}
+
+/// Applies the whiteouts of a layer to the layers below it and unpacks the layer on top.
+fn unpack_layer(layer: &Path, rootfs: &Path) -> Result<()> {
+    let entries = run(Command::new("tar").arg("-tf").arg(layer))?;
+    for entry in entries.lines() {
+        match whiteout(entry) {
+            Some(Whiteout::Path(path)) => remove(&rootfs.join(path))?,
+            Some(Whiteout::Opaque(dir)) => {
+                let dir = rootfs.join(dir);
+                if let Ok(children) = fs::read_dir(&dir) {
+                    for child in children.flatten() {
+                        remove(&child.path())?;
+                    }
+                }
+            }
+            None => {}
+        }
+    }
+    // tar detects the compression of the layer by itself
+    run(Command::new("tar")
+        .arg("-xpf")
+        .arg(layer)
+        .arg("--numeric-owner")
+        .arg("--exclude=.wh.*")
+        .arg("-C")
+        .arg(rootfs))?;
+    Ok(())
+}
+
+fn tree_size(path: &Path) -> Result<u64> {
+    let meta = try_with!(fs::symlink_metadata(path), "cannot stat {}", path.display());
+    let mut size = meta.len();
+    if meta.is_dir() {
+        for entry in try_with!(fs::read_dir(path), "cannot read {}", path.display()) {
+            let entry = try_with!(entry, "cannot read {}", path.display());
+            size += tree_size(&entry.path())?;
+        }
+    }
+    Ok(size)
+}
+
+/// Returns the image reference if `backing` is an `oci://` url.
+pub fn image_reference(backing: &Path) -> Option<&str> {
+    backing.to_str()?.strip_prefix(OCI_PREFIX)
+}
+
+/// Pulls the image `reference` (i.e. `docker.io/library/alpine:latest`) and flattens it into
+/// an ext4 image.
+pub fn build_image(reference: &str) -> Result<OciImage> {
+    let dir = try_with!(tempdir(), "cannot create temporary directory");
+    let layout = dir.path().join("layout");
+    let rootfs = dir.path().join("rootfs");
+    let path = dir.path().join("image.ext4");
+
+    info!("pull {}", reference);
+    run(Command::new("skopeo")
+        .arg("copy")
+        .arg(format!("docker://{}", reference))
+        .arg(format!("oci:{}:vmsh", layout.display())))?;
+
+    let index: Index = read_json(&layout.join("index.json"))?;
+    let manifest = require_with!(index.manifests.first(), "{} has no manifest", reference);
+    let manifest: Manifest = read_json(&blob_path(&layout, &manifest.digest)?)?;
+    try_with!(
+        fs::create_dir(&rootfs),
+        "cannot create {}",
+        rootfs.display()
+    );
+    for layer in &manifest.layers {
+        unpack_layer(&blob_path(&layout, &layer.digest)?, &rootfs)?;
+    }
+    try_with!(
+        fs::remove_dir_all(&layout),
+        "cannot remove {}",
+        layout.display()
+    );
+
+    let size = tree_size(&rootfs)? * 3 / 2 + IMAGE_HEADROOM;
+    let image = try_with!(fs::File::create(&path), "cannot create {}", path.display());
+    try_with!(image.set_len(size), "cannot resize {}", path.display());
+    run(Command::new("mkfs.ext4")
+        .arg("-q")
+        .arg("-d")
+        .arg(&rootfs)
+        .arg(&path))?;
+    try_with!(
+        fs::remove_dir_all(&rootfs),
+        "cannot remove {}",
+        rootfs.display()
+    );
+    info!("built {} from {}", path.display(), reference);
+
+    Ok(OciImage { _dir: dir, path })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_whiteouts() {
+        assert_eq!(
+            whiteout("./etc/.wh.motd"),
+            Some(Whiteout::Path(PathBuf::from("etc/motd")))
+        );
+        assert_eq!(
+            whiteout("var/cache/.wh..wh..opq"),
+            Some(Whiteout::Opaque(PathBuf::from("var/cache")))
+        );
+        assert_eq!(
+            whiteout(".wh.tmp"),
+            Some(Whiteout::Path(PathBuf::from("tmp")))
+        );
+        assert_eq!(whiteout("etc/motd"), None);
+        assert_eq!(whiteout("etc/"), None);
+    }
+
+    #[test]
+    fn parse_references() {
+        assert_eq!(
+            image_reference(Path::new("oci://docker.io/library/alpine:latest")),
+            Some("docker.io/library/alpine:latest")
+        );
+        assert_eq!(image_reference(Path::new("/dev/null")), None);
+    }
+}
@@ -1,5 +1,7 @@
 use nix::unistd::{sysconf, SysconfVar};
 
+use crate::page_table::PAGE_LEVEL;
+
 pub fn page_size() -> usize {
     sysconf(SysconfVar::PAGE_SIZE)
         .expect("sysconf failed")
@@ -7,7 +9,7 @@ pub fn page_size() -> usize {
 }
 
 pub fn huge_page_size(level: u8) -> usize {
-    page_size() << (9 * (3 - level))
+    page_size() << (9 * (PAGE_LEVEL - level))
 }
 
 pub fn page_start(v: usize) -> usize {
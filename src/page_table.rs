@@ -1,91 +1,362 @@
-use std::borrow::Borrow;
 use std::cell::RefCell;
 use std::cmp::max;
 use std::collections::HashMap;
-use std::io::IoSlice;
 use std::mem::{size_of, size_of_val};
 use std::ops::Range;
 use std::rc::Rc;
 use std::sync::Arc;
 
 use crate::guest_mem::{MappedMemory, PhysHostMap};
-use crate::kvm::hypervisor::{memory::process_read, memory::PhysMem, Hypervisor};
-use crate::page_math::{is_page_aligned, page_align, page_size};
+use crate::kvm::hypervisor::memory::{process_read, process_write_vectored, KeptPhysMem, PhysMem};
+use crate::kvm::hypervisor::Hypervisor;
+use crate::page_math::{huge_page_size, is_page_aligned, page_align, page_size};
 use crate::result::Result;
-use bitflags::bitflags;
-use log::{error, info};
-use nix::sys::mman::ProtFlags;
-use nix::sys::uio::{process_vm_writev, RemoteIoVec};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
 use simple_error::{bail, require_with, try_with};
 use vm_memory::remote_mem::any_as_bytes;
 
 const ENTRY_COUNT: usize = 512;
-const LEVEL_COUNT: usize = 4;
-
-bitflags! {
-    /// Possible flags for a page table entry.
-    pub struct PageTableFlags: u64 {
-        /// Specifies whether the mapped frame or page table is loaded in memory.
-        const PRESENT =         1;
-        /// Controls whether writes to the mapped frames are allowed.
-        ///
-        /// If this bit is unset in a level 1 page table entry, the mapped frame is read-only.
-        /// If this bit is unset in a higher level page table entry the complete range of mapped
-        /// pages is read-only.
-        const WRITABLE =        1 << 1;
-        /// Controls whether accesses from userspace (i.e. ring 3) are permitted.
-        const USER_ACCESSIBLE = 1 << 2;
-        /// If this bit is set, a “write-through” policy is used for the cache, else a “write-back”
-        /// policy is used.
-        const WRITE_THROUGH =   1 << 3;
-        /// Disables caching for the pointed entry is cacheable.
-        const NO_CACHE =        1 << 4;
-        /// Set by the CPU when the mapped frame or page table is accessed.
-        const ACCESSED =        1 << 5;
-        /// Set by the CPU on a write to the mapped frame.
-        const DIRTY =           1 << 6;
-        /// Specifies that the entry maps a huge frame instead of a page table. Only allowed in
-        /// P2 or P3 tables.
-        const HUGE_PAGE =       1 << 7;
-        /// Indicates that the mapping is present in all address spaces, so it isn't flushed from
-        /// the TLB on an address space switch.
-        const GLOBAL =          1 << 8;
-        /// Available to the OS, can be used to store additional data, e.g. custom flags.
-        const BIT_9 =           1 << 9;
-        /// Available to the OS, can be used to store additional data, e.g. custom flags.
-        const BIT_10 =          1 << 10;
-        /// Available to the OS, can be used to store additional data, e.g. custom flags.
-        const BIT_11 =          1 << 11;
-        /// Available to the OS, can be used to store additional data, e.g. custom flags.
-        const BIT_52 =          1 << 52;
-        /// Available to the OS, can be used to store additional data, e.g. custom flags.
-        const BIT_53 =          1 << 53;
-        /// Available to the OS, can be used to store additional data, e.g. custom flags.
-        const BIT_54 =          1 << 54;
-        /// Available to the OS, can be used to store additional data, e.g. custom flags.
-        const BIT_55 =          1 << 55;
-        /// Available to the OS, can be used to store additional data, e.g. custom flags.
-        const BIT_56 =          1 << 56;
-        /// Available to the OS, can be used to store additional data, e.g. custom flags.
-        const BIT_57 =          1 << 57;
-        /// Available to the OS, can be used to store additional data, e.g. custom flags.
-        const BIT_58 =          1 << 58;
-        /// Available to the OS, can be used to store additional data, e.g. custom flags.
-        const BIT_59 =          1 << 59;
-        /// Available to the OS, can be used to store additional data, e.g. custom flags.
-        const BIT_60 =          1 << 60;
-        /// Available to the OS, can be used to store additional data, e.g. custom flags.
-        const BIT_61 =          1 << 61;
-        /// Available to the OS, can be used to store additional data, e.g. custom flags.
-        const BIT_62 =          1 << 62;
-        /// Forbid code execution from the mapped frames.
-        ///
-        /// Can be only used when the no-execute page protection feature is enabled in the EFER
-        /// register.
-        const NO_EXECUTE =      1 << 63;
+/// Levels of the deepest page table we support. Levels are numbered from the root of a
+/// 5-level page table, so the level of page table entries pointing to pages is the same
+/// regardless of the paging mode.
+const LEVEL_COUNT: usize = 5;
+/// Level of page table entries that map a single page
+pub const PAGE_LEVEL: u8 = LEVEL_COUNT as u8 - 1;
+
+/// Depth of the page table used by the guest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Paging {
+    /// 48-bit virtual addresses
+    FourLevel,
+    /// 57-bit virtual addresses, i.e. LA57 on x86_64
+    FiveLevel,
+}
+
+impl Paging {
+    /// Level of the table the page table register points to.
+    pub fn root_level(self) -> u8 {
+        match self {
+            Paging::FourLevel => 1,
+            Paging::FiveLevel => 0,
+        }
+    }
+
+    /// Number of significant bits in a virtual address, all bits above are sign-extended.
+    pub fn virt_addr_bits(self) -> u32 {
+        match self {
+            Paging::FourLevel => 48,
+            Paging::FiveLevel => 57,
+        }
+    }
+
+    fn from_root_level(level: u8) -> Paging {
+        if level == 0 {
+            Paging::FiveLevel
+        } else {
+            Paging::FourLevel
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod arch {
+    use bitflags::bitflags;
+    use nix::sys::mman::ProtFlags;
+
+    bitflags! {
+        /// Possible flags for a page table entry.
+        pub struct PageTableFlags: u64 {
+            /// Specifies whether the mapped frame or page table is loaded in memory.
+            const PRESENT =         1;
+            /// Controls whether writes to the mapped frames are allowed.
+            ///
+            /// If this bit is unset in a level 1 page table entry, the mapped frame is read-only.
+            /// If this bit is unset in a higher level page table entry the complete range of mapped
+            /// pages is read-only.
+            const WRITABLE =        1 << 1;
+            /// Controls whether accesses from userspace (i.e. ring 3) are permitted.
+            const USER_ACCESSIBLE = 1 << 2;
+            /// If this bit is set, a “write-through” policy is used for the cache, else a “write-back”
+            /// policy is used.
+            const WRITE_THROUGH =   1 << 3;
+            /// Disables caching for the pointed entry is cacheable.
+            const NO_CACHE =        1 << 4;
+            /// Set by the CPU when the mapped frame or page table is accessed.
+            const ACCESSED =        1 << 5;
+            /// Set by the CPU on a write to the mapped frame.
+            const DIRTY =           1 << 6;
+            /// Specifies that the entry maps a huge frame instead of a page table. Only allowed in
+            /// P2 or P3 tables.
+            const HUGE_PAGE =       1 << 7;
+            /// Indicates that the mapping is present in all address spaces, so it isn't flushed from
+            /// the TLB on an address space switch.
+            const GLOBAL =          1 << 8;
+            /// Available to the OS, can be used to store additional data, e.g. custom flags.
+            const BIT_9 =           1 << 9;
+            /// Available to the OS, can be used to store additional data, e.g. custom flags.
+            const BIT_10 =          1 << 10;
+            /// Available to the OS, can be used to store additional data, e.g. custom flags.
+            const BIT_11 =          1 << 11;
+            /// Available to the OS, can be used to store additional data, e.g. custom flags.
+            const BIT_52 =          1 << 52;
+            /// Available to the OS, can be used to store additional data, e.g. custom flags.
+            const BIT_53 =          1 << 53;
+            /// Available to the OS, can be used to store additional data, e.g. custom flags.
+            const BIT_54 =          1 << 54;
+            /// Available to the OS, can be used to store additional data, e.g. custom flags.
+            const BIT_55 =          1 << 55;
+            /// Available to the OS, can be used to store additional data, e.g. custom flags.
+            const BIT_56 =          1 << 56;
+            /// Available to the OS, can be used to store additional data, e.g. custom flags.
+            const BIT_57 =          1 << 57;
+            /// Available to the OS, can be used to store additional data, e.g. custom flags.
+            const BIT_58 =          1 << 58;
+            /// Available to the OS, can be used to store additional data, e.g. custom flags.
+            const BIT_59 =          1 << 59;
+            /// Available to the OS, can be used to store additional data, e.g. custom flags.
+            const BIT_60 =          1 << 60;
+            /// Available to the OS, can be used to store additional data, e.g. custom flags.
+            const BIT_61 =          1 << 61;
+            /// Available to the OS, can be used to store additional data, e.g. custom flags.
+            const BIT_62 =          1 << 62;
+            /// Forbid code execution from the mapped frames.
+            ///
+            /// Can be only used when the no-execute page protection feature is enabled in the EFER
+            /// register.
+            const NO_EXECUTE =      1 << 63;
+        }
+    }
+
+    /// Bits of an entry that hold the physical address.
+    pub const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+    impl PageTableFlags {
+        pub fn is_present(&self) -> bool {
+            self.contains(PageTableFlags::PRESENT)
+        }
+
+        /// Whether a present entry in a P2 or P3 table maps a huge frame instead of a table.
+        pub fn is_huge(&self) -> bool {
+            self.contains(PageTableFlags::HUGE_PAGE)
+        }
+
+        pub fn is_writable(&self) -> bool {
+            self.contains(PageTableFlags::WRITABLE)
+        }
+
+        pub fn is_executable(&self) -> bool {
+            !self.contains(PageTableFlags::NO_EXECUTE)
+        }
+    }
+
+    /// Flags for entries pointing to a page table
+    pub fn table_flags() -> PageTableFlags {
+        // we just use the same flags the linux kernel expects for page tables
+        PageTableFlags::PRESENT
+            | PageTableFlags::ACCESSED
+            | PageTableFlags::DIRTY
+            | PageTableFlags::WRITABLE
+    }
+
+    pub fn page_table_flags(p: ProtFlags) -> PageTableFlags {
+        // we need both present/accessed for a valid page table entry
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::ACCESSED;
+        if p.contains(ProtFlags::PROT_WRITE) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !p.contains(ProtFlags::PROT_EXEC) {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+
+    /// Flags for entries in a P2 table mapping a 2M page
+    pub fn huge_page_flags(p: ProtFlags) -> PageTableFlags {
+        page_table_flags(p) | PageTableFlags::HUGE_PAGE
+    }
+
+    /// Selects the PAT entry of huge pages. For 4K pages the same is done by bit 7 (HUGE_PAGE).
+    const HUGE_PAT: u64 = 1 << 12;
+
+    /// Splits the huge page `entry` of `size` bytes. Returns the physical address of the huge
+    /// page and the bits of all entries of the table replacing it, which map 4K pages if
+    /// `pages` is set and smaller huge pages otherwise.
+    pub fn split_huge_entry(entry: u64, size: u64, pages: bool) -> (u64, u64) {
+        let base = entry & ADDR_MASK & !(size - 1);
+        let mut bits = entry & !ADDR_MASK;
+        if pages {
+            bits &= !PageTableFlags::HUGE_PAGE.bits();
+            if entry & HUGE_PAT != 0 {
+                bits |= PageTableFlags::HUGE_PAGE.bits();
+            }
+        } else {
+            bits |= entry & HUGE_PAT;
+        }
+        (base, bits)
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arch {
+    use bitflags::bitflags;
+    use nix::sys::mman::ProtFlags;
+
+    bitflags! {
+        /// Possible flags for a stage 1 translation table descriptor with a 4KB granule.
+        pub struct PageTableFlags: u64 {
+            /// Specifies whether the descriptor is valid.
+            const VALID =           1;
+            /// Set for descriptors pointing to a table (level 0-2) or a page (level 3). Unset
+            /// descriptors at level 1 and 2 map a block, i.e. a huge page.
+            const TABLE =           1 << 1;
+            /// Index into MAIR_EL1, linux uses index 0 for normal memory (MT_NORMAL).
+            const ATTR_INDEX_1 =    1 << 2;
+            const ATTR_INDEX_2 =    1 << 3;
+            const ATTR_INDEX_4 =    1 << 4;
+            /// Non-secure bit.
+            const NS =              1 << 5;
+            /// AP[1]: accessible from EL0.
+            const USER =            1 << 6;
+            /// AP[2]: read-only.
+            const READ_ONLY =       1 << 7;
+            /// Shareability field, both bits set means inner shareable.
+            const SH0 =             1 << 8;
+            const SH1 =             1 << 9;
+            /// Access flag, without it the first access faults.
+            const ACCESSED =        1 << 10;
+            /// Not global: the mapping is specific to an address space.
+            const NOT_GLOBAL =      1 << 11;
+            /// Dirty bit modifier, used by linux as hardware dirty bit.
+            const DBM =             1 << 51;
+            /// Hint that the entry is part of a set of contiguous entries.
+            const CONTIGUOUS =      1 << 52;
+            /// Privileged execute never.
+            const PXN =             1 << 53;
+            /// Unprivileged execute never.
+            const UXN =             1 << 54;
+            /// Available to the OS, linux uses them for software dirty/special bits.
+            const BIT_55 =          1 << 55;
+            const BIT_56 =          1 << 56;
+            const BIT_57 =          1 << 57;
+            const BIT_58 =          1 << 58;
+        }
+    }
+
+    /// Bits of a descriptor that hold the output address (48-bit physical addresses).
+    pub const ADDR_MASK: u64 = 0x0000_ffff_ffff_f000;
+
+    impl PageTableFlags {
+        pub fn is_present(&self) -> bool {
+            self.contains(PageTableFlags::VALID)
+        }
+
+        /// Whether a valid descriptor at level 1 or 2 maps a block instead of a table.
+        pub fn is_huge(&self) -> bool {
+            !self.contains(PageTableFlags::TABLE)
+        }
+
+        pub fn is_writable(&self) -> bool {
+            !self.contains(PageTableFlags::READ_ONLY)
+        }
+
+        pub fn is_executable(&self) -> bool {
+            !self.contains(PageTableFlags::PXN)
+        }
+    }
+
+    /// Flags for descriptors pointing to a table
+    pub fn table_flags() -> PageTableFlags {
+        PageTableFlags::VALID | PageTableFlags::TABLE
+    }
+
+    /// Flags for page descriptors of kernel memory
+    pub fn page_table_flags(p: ProtFlags) -> PageTableFlags {
+        // normal memory (attribute index 0), inner shareable, never accessible from EL0
+        let mut flags = PageTableFlags::VALID
+            | PageTableFlags::TABLE
+            | PageTableFlags::SH0
+            | PageTableFlags::SH1
+            | PageTableFlags::ACCESSED
+            | PageTableFlags::UXN;
+        if !p.contains(ProtFlags::PROT_WRITE) {
+            flags |= PageTableFlags::READ_ONLY;
+        }
+        if !p.contains(ProtFlags::PROT_EXEC) {
+            flags |= PageTableFlags::PXN;
+        }
+        flags
+    }
+
+    /// Flags for level 2 block descriptors of kernel memory
+    pub fn huge_page_flags(p: ProtFlags) -> PageTableFlags {
+        page_table_flags(p) - PageTableFlags::TABLE
+    }
+
+    /// Splits the block descriptor `entry` of `size` bytes. Returns the output address of the
+    /// block and the bits of all descriptors of the table replacing it, which are page
+    /// descriptors if `pages` is set and smaller blocks otherwise.
+    pub fn split_huge_entry(entry: u64, size: u64, pages: bool) -> (u64, u64) {
+        let base = entry & ADDR_MASK & !(size - 1);
+        let mut bits = entry & !ADDR_MASK;
+        if pages {
+            bits |= PageTableFlags::TABLE.bits();
+        }
+        (base, bits)
+    }
+}
+
+/// Placeholder for architectures vmsh does not support yet, see `cpu`.
+#[cfg(any(target_arch = "powerpc64", target_arch = "s390x"))]
+mod arch {
+    use bitflags::bitflags;
+    use nix::sys::mman::ProtFlags;
+
+    bitflags! {
+        pub struct PageTableFlags: u64 {
+            const PRESENT = 1;
+        }
+    }
+
+    pub const ADDR_MASK: u64 = 0;
+
+    impl PageTableFlags {
+        pub fn is_present(&self) -> bool {
+            unimplemented!()
+        }
+
+        pub fn is_huge(&self) -> bool {
+            unimplemented!()
+        }
+
+        pub fn is_writable(&self) -> bool {
+            unimplemented!()
+        }
+
+        pub fn is_executable(&self) -> bool {
+            unimplemented!()
+        }
+    }
+
+    pub fn table_flags() -> PageTableFlags {
+        unimplemented!()
+    }
+
+    pub fn page_table_flags(_p: ProtFlags) -> PageTableFlags {
+        unimplemented!()
+    }
+
+    pub fn huge_page_flags(_p: ProtFlags) -> PageTableFlags {
+        unimplemented!()
+    }
+
+    pub fn split_huge_entry(_entry: u64, _size: u64, _pages: bool) -> (u64, u64) {
+        unimplemented!()
     }
 }
 
+pub use arch::*;
+
 #[derive(Clone, Copy, Debug, Default)]
 #[repr(transparent)]
 pub struct PageTableEntry {
@@ -120,7 +391,7 @@ impl PageTableEntry {
     /// Returns the physical address mapped by this entry, might be zero.
     #[inline]
     pub fn addr(&self) -> u64 {
-        self.entry & 0x000f_ffff_ffff_f000
+        self.entry & ADDR_MASK
     }
 }
 
@@ -136,6 +407,7 @@ pub struct PageTable {
 pub struct PageTableIterator<'a> {
     hv: &'a Hypervisor,
     phys_host_map: Arc<PhysHostMap>,
+    paging: Paging,
     page_table: PageTable,
     range: Range<usize>,
     count: usize,
@@ -177,15 +449,29 @@ impl PageTable {
         })
     }
 
+    /// Iterates over all mapped pages in `range`. The table has to be the root of the page
+    /// table, its level determines the paging mode.
     pub fn iter(
         self,
         hv: &Hypervisor,
         phys_host_map: Arc<PhysHostMap>,
         range: Range<usize>,
+    ) -> PageTableIterator {
+        let paging = Paging::from_root_level(self.level);
+        self.iter_inner(hv, phys_host_map, range, paging)
+    }
+
+    fn iter_inner(
+        self,
+        hv: &Hypervisor,
+        phys_host_map: Arc<PhysHostMap>,
+        range: Range<usize>,
+        paging: Paging,
     ) -> PageTableIterator {
         PageTableIterator {
             hv,
             phys_host_map,
+            paging,
             range,
             page_table: self,
             count: 0,
@@ -195,8 +481,8 @@ impl PageTable {
 }
 
 fn get_shift(level: u8) -> u8 {
-    assert!(level <= 3);
-    12 + 9 * (3 - level)
+    assert!(level <= PAGE_LEVEL);
+    12 + 9 * (PAGE_LEVEL - level)
 }
 
 fn get_index(virt: u64, level: u8) -> u64 {
@@ -207,7 +493,8 @@ pub fn table_align(pages: usize) -> usize {
     (pages + (ENTRY_COUNT - 1)) & !(ENTRY_COUNT - 1)
 }
 
-/// Upper bound of page tables memory we need to map physical memory of given size
+/// Upper bound of page tables memory we need to map physical memory of given size, assuming
+/// the deepest page table we support
 pub fn estimate_page_table_size(size: usize) -> usize {
     let pages = page_align(size) / page_size();
     let mut tables = pages;
@@ -221,8 +508,8 @@ pub fn estimate_page_table_size(size: usize) -> usize {
 
 pub struct VirtMem {
     hv: Arc<Hypervisor>,
-    /// List of tables we need to restore to their old state before exiting
-    old_tables: Vec<PageTable>,
+    /// Entries of guest page tables we need to restore before exiting
+    changed_entries: Vec<ChangedEntry>,
     /// physical memory used to hold page tables and bake virtual memory
     #[allow(unused)]
     phys_mem: PhysMem<u8>,
@@ -230,13 +517,67 @@ pub struct VirtMem {
     pub mappings: Vec<MappedMemory>,
 }
 
+/// An entry of a guest page table that vmsh changed
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+struct ChangedEntry {
+    /// Hypervisor address of the entry
+    host_addr: usize,
+    old: u64,
+    new: u64,
+}
+
+/// A `VirtMem` left in the guest when vmsh detaches, see `VirtMem::keep`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeptVirtMem {
+    phys_mem: KeptPhysMem,
+    changed_entries: Vec<ChangedEntry>,
+}
+
+impl KeptVirtMem {
+    /// The memory slot holding the memory and its page tables.
+    pub fn phys_mem(&self) -> &KeptPhysMem {
+        &self.phys_mem
+    }
+
+    /// Restores the entries of the guest page tables, see `restore_entries`. The memory stays
+    /// in the VM.
+    pub fn restore_page_tables(&self, hv: &Hypervisor) -> Result<()> {
+        restore_entries(hv, &self.changed_entries)
+    }
+}
+
+impl VirtMem {
+    /// Leaves the memory mapped in the guest. Another vmsh process can remove it with
+    /// `reclaim` once the guest no longer uses it.
+    pub fn keep(self) -> KeptVirtMem {
+        let kept = KeptVirtMem {
+            phys_mem: self.phys_mem.to_kept(),
+            changed_entries: self.changed_entries.clone(),
+        };
+        std::mem::forget(self);
+        kept
+    }
+
+    /// Takes ownership of memory kept by `keep`. When dropped, the page table entries are
+    /// restored and the memory is removed from the guest.
+    pub fn reclaim(hv: Arc<Hypervisor>, kept: &KeptVirtMem) -> Result<VirtMem> {
+        let phys_mem = hv.reclaim_mem(&kept.phys_mem)?;
+        Ok(VirtMem {
+            hv,
+            changed_entries: kept.changed_entries.clone(),
+            phys_mem,
+            mappings: vec![],
+        })
+    }
+}
+
 impl Drop for VirtMem {
     fn drop(&mut self) {
         // useful for debugging
         //use log::warn;
         //warn!("SKIP CLEANUP");
         //return;
-        if let Err(e) = commit_page_tables(&self.hv, &self.old_tables) {
+        if let Err(e) = restore_entries(&self.hv, &self.changed_entries) {
             error!("cannot restore old page tables: {}", e);
         }
     }
@@ -277,14 +618,7 @@ fn allocate_page_table(
     upsert_tables: &mut UpsertTable,
 ) -> PageTableRef {
     let table = Rc::new(RefCell::new(PageTable::empty(phys_addr.clone())));
-    // we just use the same flags the linux kernel expects for page tables
-    entry.set_addr(
-        phys_addr,
-        PageTableFlags::PRESENT
-            | PageTableFlags::ACCESSED
-            | PageTableFlags::DIRTY
-            | PageTableFlags::WRITABLE,
-    );
+    entry.set_addr(phys_addr, table_flags());
     upsert_tables.insert(phys_addr.value, Rc::clone(&table));
     phys_addr.value += size_of_val(&RefCell::borrow(&table).entries);
     table
@@ -318,23 +652,48 @@ fn read_page_table(
     Ok(pt)
 }
 
+/// Replaces the huge page mapped by `entry` at `level` with a page table mapping the same
+/// memory, so that we can walk below it.
+fn split_huge_page(
+    entry: &mut PageTableEntry,
+    level: u8,
+    phys_addr: &mut PhysAddr,
+    upsert_tables: &mut UpsertTable,
+) -> PageTableRef {
+    let next_level = level + 1;
+    let (base, bits) = split_huge_entry(
+        entry.entry,
+        huge_page_size(level) as u64,
+        next_level == PAGE_LEVEL,
+    );
+    let size = huge_page_size(next_level) as u64;
+    info!(
+        "split huge page at {:#x} into page table at {:#x}",
+        base, phys_addr.value
+    );
+    // We only map kernel memory, so the new table does not need to be accessible from
+    // userspace.
+    let table = allocate_page_table(entry, phys_addr, upsert_tables);
+    for (i, e) in table.borrow_mut().entries.iter_mut().enumerate() {
+        e.entry = (base + i as u64 * size) | bits;
+    }
+    table
+}
+
 fn get_page_table(
     hv: &Hypervisor,
     entry: &mut PageTableEntry,
+    level: u8,
     phys_addr: &mut PhysAddr,
     old_tables: &mut Vec<PageTable>,
     upsert_tables: &mut UpsertTable,
     phys_host_map: &PhysHostMap,
 ) -> Result<PageTableRef> {
-    // should be empty
-    if entry
-        .flags()
-        .contains(PageTableFlags::HUGE_PAGE | PageTableFlags::PRESENT)
-    {
-        bail!("found huge table in page table");
+    if entry.flags().is_present() && entry.flags().is_huge() {
+        return Ok(split_huge_page(entry, level, phys_addr, upsert_tables));
     }
 
-    if entry.flags().contains(PageTableFlags::PRESENT) {
+    if entry.flags().is_present() {
         let addr = entry.addr() as usize;
         if let Some(t) = upsert_tables.get(&addr) {
             Ok(Rc::clone(t))
@@ -347,132 +706,146 @@ fn get_page_table(
     }
 }
 
-fn get_start_idx(virt_addr: usize, idx: usize, level: u8) -> usize {
-    if idx != 0 {
-        0
-    } else {
-        get_index(virt_addr as u64, level) as usize
+/// Entries of `old_tables` that differ in the tables we write.
+fn changed_entries(old_tables: &[PageTable], upsert_tables: &UpsertTable) -> Vec<ChangedEntry> {
+    let mut changed = vec![];
+    for old in old_tables {
+        let new = match upsert_tables.get(&old.phys_addr.value) {
+            Some(new) => RefCell::borrow(new),
+            None => continue,
+        };
+        for (i, (o, n)) in old.entries.iter().zip(new.entries.iter()).enumerate() {
+            if o.entry != n.entry {
+                changed.push(ChangedEntry {
+                    host_addr: old.phys_addr.host_addr() + i * size_of::<PageTableEntry>(),
+                    old: o.entry,
+                    new: n.entry,
+                });
+            }
+        }
     }
+    changed
 }
 
-fn commit_page_tables(hv: &Hypervisor, tables: &[PageTable]) -> Result<()> {
-    let mut local_iovec = vec![];
-    let mut remote_iovec = vec![];
-    for t in tables {
-        let t = t.borrow();
-        let bytes = unsafe { any_as_bytes(&t.entries) };
-        local_iovec.push(IoSlice::new(bytes));
-        remote_iovec.push(RemoteIoVec {
-            base: t.phys_addr.host_addr(),
-            len: page_size(),
-        });
-    }
-    let written = try_with!(
-        process_vm_writev(hv.pid, local_iovec.as_slice(), remote_iovec.as_slice()),
-        "cannot write to process"
-    );
-    let expected = remote_iovec.len() * page_size();
-    if written != expected {
-        bail!("short write, expected {}, written: {}", expected, written);
+/// Restores the entries that still hold the values we wrote. The guest may have changed the
+/// others since, i.e. when it mapped memory next to ours, and owns them now.
+fn restore_entries(hv: &Hypervisor, entries: &[ChangedEntry]) -> Result<()> {
+    hv.check_writable("changing guest page tables")?;
+    let mut restored = vec![];
+    for e in entries {
+        let current: u64 = process_read(hv.pid, e.host_addr as *const libc::c_void)?;
+        if current == e.new {
+            restored.push((e.host_addr, e.old));
+        } else {
+            warn!(
+                "guest changed page table entry at {:#x} to {:#x}, leave it",
+                e.host_addr, current
+            );
+        }
     }
-    Ok(())
+    let writes = restored
+        .iter()
+        .map(|(addr, old)| (*addr, unsafe { any_as_bytes(old) }))
+        .collect::<Vec<_>>();
+    process_write_vectored(hv.pid, &writes)
 }
 
-fn page_table_flags(p: ProtFlags) -> PageTableFlags {
-    // we need both present/accessed for a valid page table entry
-    let mut flags = PageTableFlags::PRESENT | PageTableFlags::ACCESSED;
-    if p.contains(ProtFlags::PROT_WRITE) {
-        flags |= PageTableFlags::WRITABLE;
-    }
-    if !p.contains(ProtFlags::PROT_EXEC) {
-        flags |= PageTableFlags::NO_EXECUTE;
-    }
-    flags
+fn commit_page_tables(hv: &Hypervisor, tables: &[PageTable]) -> Result<()> {
+    hv.check_writable("changing guest page tables")?;
+    let writes = tables
+        .iter()
+        .map(|t| (t.phys_addr.host_addr(), unsafe { any_as_bytes(&t.entries) }))
+        .collect::<Vec<_>>();
+    process_write_vectored(hv.pid, &writes)
 }
 
-fn map_memory_single(
-    hv: &Hypervisor,
-    pml4: &mut PageTable,
+/// State shared while mapping memory into the page table
+struct Mapper<'a> {
+    hv: &'a Hypervisor,
+    upsert_tables: UpsertTable,
+    old_tables: Vec<PageTable>,
+    /// Where to allocate the next page table
+    pt_addr: PhysAddr,
+    phys_host_map: &'a PhysHostMap,
+    /// Map 2M pages where virtual and physical addresses are aligned
+    huge_pages: bool,
+}
 
-    m: &MappedMemory,
-    upsert_tables: &mut UpsertTable,
-    old_tables: &mut Vec<PageTable>,
-    pt_addr: &mut PhysAddr,
-    phys_host_map: &PhysHostMap,
-) -> Result<()> {
-    let mut phys_addr = m.phys_start.clone();
-    let mut len = m.len;
-    let start0 = get_index(m.virt_start as u64, 0) as usize;
-    'outer: for (i0, entry0) in pml4.entries[start0..].iter_mut().enumerate() {
-        let start1 = get_start_idx(m.virt_start, i0, 1);
-        let pt1 = get_page_table(
-            hv,
-            entry0,
-            pt_addr,
-            old_tables,
-            upsert_tables,
-            phys_host_map,
-        )?;
-        let mut pt1 = pt1.borrow_mut();
-
-        for (i1, entry1) in pt1.entries[start1..].iter_mut().enumerate() {
-            let start2 = get_start_idx(m.virt_start, i1, 2);
-            let pt2 = get_page_table(
-                hv,
-                entry1,
-                pt_addr,
-                old_tables,
-                upsert_tables,
-                phys_host_map,
-            )?;
-            let mut pt2 = pt2.borrow_mut();
-
-            for (i2, entry2) in pt2.entries[start2..].iter_mut().enumerate() {
-                let start3 = get_start_idx(m.virt_start, i2, 3);
-                let pt3 = get_page_table(
-                    hv,
-                    entry2,
-                    pt_addr,
-                    old_tables,
-                    upsert_tables,
-                    phys_host_map,
-                )?;
-                let mut pt3 = pt3.borrow_mut();
-
-                for (i, entry3) in pt3.entries[start3..].iter_mut().enumerate() {
-                    if entry3.flags().contains(PageTableFlags::PRESENT) {
-                        bail!(
-                            "found already mapped page in page table at {:#x}",
-                            entry2.addr() as usize + i + start3
-                        );
-                    }
-                    entry3.set_addr(&phys_addr, page_table_flags(m.prot));
-                    phys_addr.value += page_size();
-                    len -= page_size();
-                    if len == 0 {
-                        break 'outer;
-                    }
+impl<'a> Mapper<'a> {
+    /// Maps pages of `m` into `table`, beginning at `virt_addr`, until either all pages are
+    /// mapped or the end of the table is reached.
+    fn map_table(
+        &mut self,
+        table: &mut PageTable,
+        level: u8,
+        m: &MappedMemory,
+        virt_addr: &mut usize,
+        phys_addr: &mut PhysAddr,
+    ) -> Result<()> {
+        let end = m.virt_start + m.len;
+        let start = get_index(*virt_addr as u64, level) as usize;
+        for entry in table.entries[start..].iter_mut() {
+            if *virt_addr == end {
+                break;
+            }
+            if level == PAGE_LEVEL {
+                if entry.flags().is_present() {
+                    bail!(
+                        "found already mapped page in page table at {:#x}",
+                        *virt_addr
+                    );
                 }
+                entry.set_addr(phys_addr, page_table_flags(m.prot));
+                phys_addr.value += page_size();
+                *virt_addr += page_size();
+                continue;
+            }
+            let size = huge_page_size(level);
+            if self.huge_pages
+                && level == PAGE_LEVEL - 1
+                && !entry.flags().is_present()
+                && *virt_addr % size == 0
+                && phys_addr.value % size == 0
+                && end - *virt_addr >= size
+            {
+                entry.set_addr(phys_addr, huge_page_flags(m.prot));
+                phys_addr.value += size;
+                *virt_addr += size;
+                continue;
             }
+            let next = get_page_table(
+                self.hv,
+                entry,
+                level,
+                &mut self.pt_addr,
+                &mut self.old_tables,
+                &mut self.upsert_tables,
+                self.phys_host_map,
+            )?;
+            let mut next = next.borrow_mut();
+            self.map_table(&mut next, level + 1, m, virt_addr, phys_addr)?;
         }
+        Ok(())
     }
-    Ok(())
 }
 
 /// Maps a list of physical memory chunks at phys_addr with a length of u64 to virt_addr.
-/// The list must to be physical continous and sorted.
+/// The list must to be physical continous and sorted. With `huge_pages`, 2M pages are used
+/// where both addresses are aligned to 2M.
 /// To allocate page tables it uses space at the end of given physical memory address.
 /// There must be enough space after the last mapping to store these pagetable.
 pub fn map_memory(
     hv: Arc<Hypervisor>,
     phys_mem: PhysMem<u8>,
     pml4_addr: &mut PhysAddr,
+    paging: Paging,
     mappings: &[MappedMemory],
     phys_host_map: &PhysHostMap,
+    huge_pages: bool,
 ) -> Result<VirtMem> {
     // New/modified tables to be written to guest
     let mut upsert_tables: UpsertTable = HashMap::new();
-    // Tables that we need to revert to their old content
+    // Guest tables as they were before, to find the entries we change
     let mut old_tables: Vec<PageTable> = vec![];
     let pml4 = try_with!(
         read_page_table(
@@ -501,19 +874,38 @@ pub fn map_memory(
         }
     }
     let last_mapping = &mappings[mappings.len() - 1];
-    let mut pt_addr = last_mapping.phys_start.add(last_mapping.len);
+    let mut mapper = Mapper {
+        hv: &hv,
+        upsert_tables,
+        old_tables,
+        pt_addr: last_mapping.phys_start.add(last_mapping.len),
+        phys_host_map,
+        huge_pages,
+    };
 
     for mapping in mappings {
-        map_memory_single(
-            &hv,
+        let mut virt_addr = mapping.virt_start;
+        let mut phys_addr = mapping.phys_start.clone();
+        mapper.map_table(
             &mut pml4.borrow_mut(),
+            paging.root_level(),
             mapping,
-            &mut upsert_tables,
-            &mut old_tables,
-            &mut pt_addr,
-            phys_host_map,
+            &mut virt_addr,
+            &mut phys_addr,
         )?;
+        if virt_addr != mapping.virt_start + mapping.len {
+            bail!(
+                "{:#x} is outside of the {}-bit virtual address space",
+                mapping.virt_start,
+                paging.virt_addr_bits()
+            );
+        }
     }
+    let Mapper {
+        upsert_tables,
+        old_tables,
+        ..
+    } = mapper;
 
     // this is expensive but avoids duplicating code
     let tables = &upsert_tables
@@ -524,7 +916,7 @@ pub fn map_memory(
 
     Ok(VirtMem {
         hv,
-        old_tables,
+        changed_entries: changed_entries(&old_tables, &upsert_tables),
         phys_mem,
         mappings: mappings.to_vec(),
     })
@@ -541,8 +933,8 @@ impl PageTableIteratorValue {
     /// Size of mapped page
     pub fn size(&self) -> u64 {
         assert!(
-            self.entry.flags().contains(PageTableFlags::PRESENT)
-                && (self.level == 3 || self.entry.flags().contains(PageTableFlags::HUGE_PAGE))
+            self.entry.flags().is_present()
+                && (self.level == PAGE_LEVEL || self.entry.flags().is_huge())
         );
         1 << get_shift(self.level)
     }
@@ -569,14 +961,15 @@ impl<'a> Iterator for PageTableIterator<'a> {
             self.count += 1;
             let mut virt_addr = pt.virt_addr + (idx << get_shift(pt.level));
             // sign extend most significant bit
-            if virt_addr >> 47 != 0 {
-                virt_addr |= 0xFFFF << 48
+            let bits = self.paging.virt_addr_bits();
+            if virt_addr >> (bits - 1) != 0 {
+                virt_addr |= u64::MAX << bits
             }
-            if !entry.flags().contains(PageTableFlags::PRESENT) {
+            if !entry.flags().is_present() {
                 continue;
             }
 
-            if pt.level == 3 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            if pt.level == PAGE_LEVEL || entry.flags().is_huge() {
                 return Some(Ok(PageTableIteratorValue {
                     virt_addr,
                     level: pt.level,
@@ -598,7 +991,12 @@ impl<'a> Iterator for PageTableIterator<'a> {
                 self.range.end
             };
 
-            let mut inner = next_pt.iter(self.hv, Arc::clone(&self.phys_host_map), start..end);
+            let mut inner = next_pt.iter_inner(
+                self.hv,
+                Arc::clone(&self.phys_host_map),
+                start..end,
+                self.paging,
+            );
             if let Some(next) = &inner.next() {
                 self.inner = Some(Box::new(inner));
                 return Some(next.clone());
@@ -612,7 +1010,13 @@ impl<'a> Iterator for PageTableIterator<'a> {
 mod tests {
     use crate::page_math::page_size;
 
-    use super::{estimate_page_table_size, ENTRY_COUNT, LEVEL_COUNT};
+    use super::{
+        changed_entries, estimate_page_table_size, get_index, get_shift, ChangedEntry, PageTable,
+        Paging, PhysAddr, UpsertTable, ENTRY_COUNT, LEVEL_COUNT, PAGE_LEVEL,
+    };
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
     #[test]
     fn test_page_table_size() {
         assert_eq!(estimate_page_table_size(1), page_size() * LEVEL_COUNT);
@@ -622,4 +1026,66 @@ mod tests {
             page_size() + page_size() * LEVEL_COUNT
         );
     }
+
+    #[test]
+    fn test_page_table_levels() {
+        assert_eq!(get_shift(PAGE_LEVEL), 12);
+        assert_eq!(get_shift(Paging::FourLevel.root_level()), 39);
+        assert_eq!(get_shift(Paging::FiveLevel.root_level()), 48);
+        // kernel text
+        assert_eq!(get_index(0xffff_ffff_8000_0000, 0), 0x1ff);
+        assert_eq!(get_index(0xffff_ffff_8000_0000, 1), 0x1ff);
+        assert_eq!(get_index(0xffff_ffff_8000_0000, 2), 0x1fe);
+        // direct map with 5-level paging
+        assert_eq!(get_index(0xff11_0000_0000_0000, 0), 0x111);
+    }
+
+    #[test]
+    fn test_changed_entries() {
+        let addr = PhysAddr {
+            value: 0x1000,
+            host_offset: 0x7000_0000,
+        };
+        let mut old = PageTable::empty(addr.clone());
+        old.entries[3].entry = 0x5003;
+        let mut new = old.clone();
+        new.entries[7].entry = 0x9003;
+        let mut upsert_tables = UpsertTable::new();
+        upsert_tables.insert(addr.value, Rc::new(RefCell::new(new)));
+        // tables we allocated are not restored
+        let ours = PhysAddr {
+            value: 0x2000,
+            host_offset: 0x7000_0000,
+        };
+        upsert_tables.insert(ours.value, Rc::new(RefCell::new(PageTable::empty(ours))));
+
+        assert_eq!(
+            changed_entries(&[old], &upsert_tables),
+            vec![ChangedEntry {
+                host_addr: 0x7000_1000 + 7 * 8,
+                old: 0,
+                new: 0x9003,
+            }]
+        );
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_split_huge_entry() {
+        use super::{split_huge_entry, PageTableFlags};
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::HUGE_PAGE
+            | PageTableFlags::NO_EXECUTE;
+        let entry = 0x4020_0000 | flags.bits();
+        let (base, bits) = split_huge_entry(entry, 1 << 21, true);
+        assert_eq!(base, 0x4020_0000);
+        assert_eq!(bits, (flags - PageTableFlags::HUGE_PAGE).bits());
+        let (base, bits) = split_huge_entry(entry, 1 << 30, false);
+        assert_eq!(base, 0x4000_0000);
+        assert_eq!(bits, flags.bits());
+        // the PAT bit moves to bit 7 in entries mapping 4K pages
+        let (_, bits) = split_huge_entry(entry | 1 << 12, 1 << 21, true);
+        assert_eq!(bits, flags.bits());
+    }
 }
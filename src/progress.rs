@@ -0,0 +1,127 @@
+//! Reports the phases of an attach (discover, stop, alloc, load, inject, wait-for-driver) and
+//! how long each took. Phases are logged at debug level (`-v`), so a slow attach shows where it
+//! hangs, and can be written as JSON (`--timing-out`) to compare attaches across versions.
+
+use log::{debug, warn};
+use serde::Serialize;
+use simple_error::try_with;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::result::Result;
+
+#[derive(Serialize, Debug, PartialEq)]
+struct PhaseTiming {
+    phase: &'static str,
+    ms: f64,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    pid: i32,
+    /// Set if the attach failed in the last phase
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    total_ms: f64,
+    phases: &'a [PhaseTiming],
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+pub struct Progress {
+    pid: i32,
+    start: Instant,
+    current: Option<(&'static str, Instant)>,
+    phases: Vec<PhaseTiming>,
+    /// Where the timings are written by `finish`, or when dropped during a failed attach
+    out: Option<PathBuf>,
+}
+
+impl Progress {
+    pub fn new(pid: i32, out: Option<PathBuf>) -> Progress {
+        Progress {
+            pid,
+            start: Instant::now(),
+            current: None,
+            phases: vec![],
+            out,
+        }
+    }
+
+    fn end_phase(&mut self) {
+        if let Some((phase, start)) = self.current.take() {
+            let elapsed = start.elapsed();
+            debug!("attach: {} took {:?}", phase, elapsed);
+            self.phases.push(PhaseTiming {
+                phase,
+                ms: millis(elapsed),
+            });
+        }
+    }
+
+    /// Ends the current phase and starts `phase`.
+    pub fn phase(&mut self, phase: &'static str) {
+        self.end_phase();
+        debug!("attach: {}...", phase);
+        self.current = Some((phase, Instant::now()));
+    }
+
+    fn write(&self, path: &Path, error: Option<&str>) -> Result<()> {
+        let report = Report {
+            pid: self.pid,
+            error,
+            total_ms: millis(self.start.elapsed()),
+            phases: &self.phases,
+        };
+        let file = try_with!(File::create(path), "cannot create {}", path.display());
+        try_with!(
+            serde_json::to_writer_pretty(file, &report),
+            "cannot write {}",
+            path.display()
+        );
+        Ok(())
+    }
+
+    /// Ends the last phase and writes the timings, if requested.
+    pub fn finish(&mut self) -> Result<()> {
+        self.end_phase();
+        debug!("attach took {:?}", self.start.elapsed());
+        match self.out.take() {
+            Some(path) => self.write(&path, None),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        // the attach failed before `finish`
+        if let Some(path) = self.out.take() {
+            let failed = self.current.map(|(phase, _)| phase);
+            self.end_phase();
+            let error = failed.map(|phase| format!("failed during {}", phase));
+            if let Err(e) = self.write(&path, error.as_deref()) {
+                warn!("{}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phases() {
+        let mut progress = Progress::new(1, None);
+        progress.phase("discover");
+        progress.phase("stop");
+        progress.finish().unwrap();
+        let names = progress.phases.iter().map(|p| p.phase).collect::<Vec<_>>();
+        assert_eq!(names, ["discover", "stop"]);
+        assert!(progress.phases.iter().all(|p| p.ms >= 0.0));
+    }
+}
@@ -0,0 +1,293 @@
+//! Lists the processes of the guest by walking the task list of its kernel.
+
+use log::{debug, warn};
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::io::{self, Write};
+
+use crate::guest_mem::{GuestMem, KernelMem};
+use crate::kernel::{find_kernel, parse_release, Kernel};
+use crate::kvm;
+use crate::kvm::hypervisor::VmSelector;
+use crate::mem::parse_addr;
+use crate::result::Result;
+
+/// Upper bound of `sizeof(struct task_struct)`, which is ~10 KiB with debug options
+const TASK_STRUCT_MAX_SIZE: usize = 0x4000;
+/// `TASK_COMM_LEN`
+const COMM_LEN: usize = 16;
+/// Stop walking lists that do not close after this many entries
+const MAX_TASKS: usize = 1 << 16;
+
+/// Offsets of the fields in `struct task_struct` that we read.
+#[derive(Clone, Copy, Debug)]
+pub struct TaskOffsets {
+    /// `struct list_head tasks`
+    pub tasks: usize,
+    /// `pid_t pid`, followed by `pid_t tgid`
+    pub pid: usize,
+    /// `char comm[TASK_COMM_LEN]`
+    pub comm: usize,
+    /// `long state` before Linux 5.14, `unsigned int __state` since
+    pub state: usize,
+}
+
+/// Offsets given on the command line, they take precedence over detected ones.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TaskOffsetOverrides {
+    pub tasks: Option<usize>,
+    pub pid: Option<usize>,
+    pub comm: Option<usize>,
+    pub state: Option<usize>,
+}
+
+pub struct PsOptions {
+    pub pid: Pid,
+    pub vm: Option<VmSelector>,
+    pub offsets: TaskOffsetOverrides,
+}
+
+/// Offset of `state` by kernel version. It follows `struct thread_info`, which is the first
+/// member on x86_64 since Linux 4.9 and grew by `syscall_work` in 5.11.
+const STATE_OFFSETS: &[((u16, u16), usize)] = &[((5, 11), 0x18), ((4, 9), 0x10)];
+
+/// Parses `tasks=0x...,pid=...,comm=...,state=...`. Missing fields are detected.
+pub fn parse_offsets(s: &str) -> Result<TaskOffsetOverrides> {
+    let mut overrides = TaskOffsetOverrides::default();
+    for field in s.split(',').filter(|f| !f.is_empty()) {
+        let (name, value) = match field.split_once('=') {
+            Some(kv) => kv,
+            None => bail!("expected <field>=<offset>, got {}", field),
+        };
+        let value = Some(parse_addr(value)?);
+        match name.trim() {
+            "tasks" => overrides.tasks = value,
+            "pid" => overrides.pid = value,
+            "comm" => overrides.comm = value,
+            "state" => overrides.state = value,
+            _ => bail!("unknown task_struct field: {}", name),
+        }
+    }
+    Ok(overrides)
+}
+
+fn state_offset(release: &str) -> Option<usize> {
+    let version = parse_release(release).map(|v| (v.major, v.minor))?;
+    STATE_OFFSETS
+        .iter()
+        .find(|(since, _)| version >= *since)
+        .map(|(_, offset)| *offset)
+}
+
+/// Single letter state as shown by ps(1)
+fn state_name(state: u32) -> char {
+    const TASK_INTERRUPTIBLE: u32 = 0x1;
+    const TASK_UNINTERRUPTIBLE: u32 = 0x2;
+    const TASK_STOPPED: u32 = 0x4;
+    const TASK_TRACED: u32 = 0x8;
+    const EXIT_DEAD: u32 = 0x10;
+    const EXIT_ZOMBIE: u32 = 0x20;
+    const TASK_PARKED: u32 = 0x40;
+    const TASK_NOLOAD: u32 = 0x400;
+    match state {
+        0 => 'R',
+        s if s & TASK_NOLOAD != 0 => 'I',
+        s if s & TASK_INTERRUPTIBLE != 0 => 'S',
+        s if s & TASK_UNINTERRUPTIBLE != 0 => 'D',
+        s if s & TASK_STOPPED != 0 => 'T',
+        s if s & TASK_TRACED != 0 => 't',
+        s if s & EXIT_DEAD != 0 => 'X',
+        s if s & EXIT_ZOMBIE != 0 => 'Z',
+        s if s & TASK_PARKED != 0 => 'P',
+        _ => '?',
+    }
+}
+
+fn read_comm(mem: &KernelMem, addr: usize) -> Result<String> {
+    let mut buf = [0u8; COMM_LEN];
+    mem.read(addr, &mut buf)?;
+    let len = require_with!(
+        buf.iter().position(|c| *c == 0),
+        "comm at {:#x} is not terminated",
+        addr
+    );
+    Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+fn valid_comm(comm: &str) -> bool {
+    !comm.is_empty() && comm.bytes().all(|c| c.is_ascii_graphic() || c == b' ')
+}
+
+/// Follows the list at `init_task + tasks` and returns the addresses of all tasks, excluding
+/// init_task itself. Fails if the list does not look like a list of tasks.
+fn walk_tasks(mem: &KernelMem, init_task: usize, tasks: usize, comm: usize) -> Result<Vec<usize>> {
+    let head = init_task + tasks;
+    let mut found = vec![];
+    let mut prev = head;
+    let mut cur = mem.read_u64(head)? as usize;
+    while cur != head {
+        if found.len() >= MAX_TASKS {
+            bail!("task list at {:#x} does not end", head);
+        }
+        if mem.read_u64(cur.wrapping_add(8))? as usize != prev {
+            bail!("task list at {:#x} is corrupted", head);
+        }
+        let task = cur.wrapping_sub(tasks);
+        if !valid_comm(&read_comm(mem, task.wrapping_add(comm))?) {
+            bail!("{:#x} does not look like a task", task);
+        }
+        found.push(task);
+        prev = cur;
+        cur = mem.read_u64(cur)? as usize;
+    }
+    Ok(found)
+}
+
+/// Finds `comm` of init_task, which is `swapper/0`, and `tasks`, the longest list of tasks
+/// before it. Returns both offsets and the tasks in the list.
+fn find_task_list(
+    mem: &KernelMem,
+    init_task: usize,
+    overrides: &TaskOffsetOverrides,
+) -> Result<(usize, usize, Vec<usize>)> {
+    let mut task = vec![0u8; TASK_STRUCT_MAX_SIZE];
+    mem.read(init_task, &mut task)?;
+
+    let comm = match overrides.comm {
+        Some(comm) => comm,
+        None => require_with!(
+            task.windows(7).position(|w| w == b"swapper"),
+            "cannot find comm of init_task"
+        ),
+    };
+
+    let candidates = match overrides.tasks {
+        Some(tasks) => vec![tasks],
+        None => (0..comm).step_by(8).collect(),
+    };
+    let (tasks, list) = require_with!(
+        candidates
+            .into_iter()
+            .filter_map(|off| Some((off, walk_tasks(mem, init_task, off, comm).ok()?)))
+            .max_by_key(|(_, list)| list.len()),
+        "cannot find task list in init_task"
+    );
+    Ok((comm, tasks, list))
+}
+
+/// Command of the first task after init_task, i.e. init. Until the kernel executed the init
+/// program, it is still called like init_task.
+pub(crate) fn init_comm(mem: &KernelMem, init_task: usize) -> Result<String> {
+    let (comm, _, list) = find_task_list(mem, init_task, &TaskOffsetOverrides::default())?;
+    let init = require_with!(list.first(), "the kernel did not start init yet");
+    read_comm(mem, init + comm)
+}
+
+/// Finds the offsets by looking at init_task (see `find_task_list`). The first two tasks in
+/// the list are init and kthreadd.
+fn detect_offsets(
+    mem: &KernelMem,
+    kernel: &Kernel,
+    init_task: usize,
+    overrides: &TaskOffsetOverrides,
+) -> Result<(TaskOffsets, Vec<usize>)> {
+    let (comm, tasks, list) = find_task_list(mem, init_task, overrides)?;
+    if list.len() < 2 {
+        bail!("expected at least init and kthreadd in the task list");
+    }
+
+    let pid = match overrides.pid {
+        Some(pid) => pid,
+        None => {
+            let pid_of = |t: usize, off: usize| mem.read_u32(t + off).ok();
+            require_with!(
+                (tasks..comm).step_by(4).find(|off| {
+                    [(init_task, 0), (list[0], 1), (list[1], 2)]
+                        .iter()
+                        .all(|(t, pid)| {
+                            pid_of(*t, *off) == Some(*pid) && pid_of(*t, off + 4) == Some(*pid)
+                        })
+                }),
+                "cannot find pid in task_struct"
+            )
+        }
+    };
+
+    let state = match overrides.state {
+        Some(state) => state,
+        None => {
+            let release = kernel.version(mem.hv)?;
+            require_with!(
+                state_offset(&release),
+                "no task_struct offsets known for kernel {}, use --offsets",
+                release
+            )
+        }
+    };
+
+    let offsets = TaskOffsets {
+        tasks,
+        pid,
+        comm,
+        state,
+    };
+    debug!("task_struct offsets: {:?}", offsets);
+    Ok((offsets, list))
+}
+
+pub fn ps(opts: &PsOptions) -> Result<()> {
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid, opts.vm),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    // the task list must not change while we walk it
+    vm.stop()?;
+    let guest_mem = try_with!(GuestMem::new(&vm), "cannot access guest memory");
+    let kernel = try_with!(find_kernel(&guest_mem, &vm), "cannot find guest kernel");
+    let init_task = *require_with!(
+        kernel.symbols.get("init_task"),
+        "kernel does not export init_task"
+    );
+    let mem = KernelMem::new(&vm, &guest_mem)?;
+    let (offsets, tasks) = detect_offsets(&mem, &kernel, init_task, &opts.offsets)?;
+
+    let mut stdout = io::stdout().lock();
+    try_with!(
+        writeln!(stdout, "    PID S COMMAND"),
+        "cannot write to stdout"
+    );
+    for task in tasks {
+        let line = read_comm(&mem, task + offsets.comm).and_then(|comm| {
+            let pid = mem.read_u32(task + offsets.pid)?;
+            let state = mem.read_u32(task + offsets.state)?;
+            Ok(format!("{:>7} {} {}", pid, state_name(state), comm))
+        });
+        match line {
+            Ok(line) => try_with!(writeln!(stdout, "{}", line), "cannot write to stdout"),
+            Err(e) => warn!("cannot read task at {:#x}: {}", task, e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ps::{parse_offsets, state_offset};
+
+    #[test]
+    fn offsets() {
+        let o = parse_offsets("tasks=0x458,comm=1848").expect("valid offsets");
+        assert_eq!(o.tasks, Some(0x458));
+        assert_eq!(o.comm, Some(1848));
+        assert_eq!(o.pid, None);
+        assert!(parse_offsets("mm=0x10").is_err());
+    }
+
+    #[test]
+    fn state_offsets() {
+        assert_eq!(state_offset("5.10.52"), Some(0x10));
+        assert_eq!(state_offset("6.1.0-rc1"), Some(0x18));
+        assert_eq!(state_offset("3.10.0"), None);
+    }
+}
@@ -0,0 +1,260 @@
+//! `vmsh regs`: prints (or changes) the registers of the vcpus, for a quick look at a guest
+//! kernel without attaching gdb.
+
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::io::{self, Write};
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use kvm_bindings as kvmb;
+
+use crate::cpu::Regs;
+use crate::kvm;
+use crate::kvm::hypervisor::{Hypervisor, VmSelector, VCPU};
+use crate::mem::parse_addr;
+use crate::result::Result;
+
+pub struct RegsOptions {
+    pub pid: Pid,
+    pub vm: Option<VmSelector>,
+    /// Only show this vcpu, all by default
+    pub vcpu: Option<usize>,
+    /// General purpose registers to change before printing, i.e. `("rip", 0xffffffff81000000)`
+    pub set: Vec<(String, u64)>,
+}
+
+/// Parses `name=value` as given to `--set`, the value is hex with 0x prefix or decimal.
+pub fn parse_assignment(s: &str) -> Result<(String, u64)> {
+    let (name, value) = match s.split_once('=') {
+        Some(assignment) => assignment,
+        None => bail!("expected <register>=<value>, got {}", s),
+    };
+    Ok((name.trim().to_lowercase(), parse_addr(value)? as u64))
+}
+
+/// MSRs that are useful when debugging a kernel: syscall entry points, per-cpu bases and
+/// where the local APIC is mapped.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const MSRS: &[(&str, u32)] = &[
+    ("efer", 0xc000_0080),
+    ("star", 0xc000_0081),
+    ("lstar", 0xc000_0082),
+    ("cstar", 0xc000_0083),
+    ("sfmask", 0xc000_0084),
+    ("fs_base", 0xc000_0100),
+    ("gs_base", 0xc000_0101),
+    ("kernel_gs_base", 0xc000_0102),
+    ("apic_base", 0x1b),
+    ("sysenter_cs", 0x174),
+    ("sysenter_esp", 0x175),
+    ("sysenter_eip", 0x176),
+    ("pat", 0x277),
+];
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn set_register(regs: &mut Regs, name: &str, value: u64) -> Result<()> {
+    let reg = match name {
+        "rax" => &mut regs.rax,
+        "rbx" => &mut regs.rbx,
+        "rcx" => &mut regs.rcx,
+        "rdx" => &mut regs.rdx,
+        "rsi" => &mut regs.rsi,
+        "rdi" => &mut regs.rdi,
+        "rsp" => &mut regs.rsp,
+        "rbp" => &mut regs.rbp,
+        "r8" => &mut regs.r8,
+        "r9" => &mut regs.r9,
+        "r10" => &mut regs.r10,
+        "r11" => &mut regs.r11,
+        "r12" => &mut regs.r12,
+        "r13" => &mut regs.r13,
+        "r14" => &mut regs.r14,
+        "r15" => &mut regs.r15,
+        "rip" => &mut regs.rip,
+        "rflags" => &mut regs.eflags,
+        _ => bail!(
+            "cannot set {}, only general purpose registers, rip and rflags can be set",
+            name
+        ),
+    };
+    *reg = value;
+    Ok(())
+}
+
+/// MSRs of `MSRS` the vcpu supports
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn read_msrs(vm: &Hypervisor, vcpu: &VCPU) -> Result<Vec<(&'static str, u64)>> {
+    let mut values = vec![];
+    // one at a time: KVM_GET_MSRS stops at the first msr the vcpu does not support
+    for (name, index) in MSRS {
+        let msrs = kvm::tracee::kvm_msrs {
+            nmsrs: 1,
+            pad: 0,
+            entries: [kvmb::kvm_msr_entry {
+                index: *index,
+                ..Default::default()
+            }],
+        };
+        let (n, msrs) = vm.vcpu_ioctl(vcpu, kvm::ioctls::KVM_GET_MSRS(), &msrs)?;
+        if n == 1 {
+            values.push((*name, msrs.entries[0].data));
+        }
+    }
+    Ok(values)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn write_vcpu(out: &mut dyn Write, vm: &Hypervisor, vcpu: &VCPU) -> Result<()> {
+    let r = vm.get_regs(vcpu)?;
+    let s = vm.get_sregs(vcpu)?;
+    let fpu = vm.get_fpu_regs(vcpu)?;
+    let msrs = read_msrs(vm, vcpu)?;
+
+    let general = [
+        ("rax", r.rax),
+        ("rbx", r.rbx),
+        ("rcx", r.rcx),
+        ("rdx", r.rdx),
+        ("rsi", r.rsi),
+        ("rdi", r.rdi),
+        ("rbp", r.rbp),
+        ("rsp", r.rsp),
+        ("r8", r.r8),
+        ("r9", r.r9),
+        ("r10", r.r10),
+        ("r11", r.r11),
+        ("r12", r.r12),
+        ("r13", r.r13),
+        ("r14", r.r14),
+        ("r15", r.r15),
+        ("rip", r.rip),
+        ("rflags", r.eflags),
+        ("cr0", s.cr0),
+        ("cr2", s.cr2),
+        ("cr3", s.cr3),
+        ("cr4", s.cr4),
+        ("cr8", s.cr8),
+        ("mxcsr", fpu.mxcsr as u64),
+    ];
+    let segments = [
+        ("cs", s.cs),
+        ("ds", s.ds),
+        ("es", s.es),
+        ("fs", s.fs),
+        ("gs", s.gs),
+        ("ss", s.ss),
+        ("tr", s.tr),
+    ];
+
+    let nested = if vm.in_nested_guest(vcpu) {
+        " (nested guest)"
+    } else {
+        ""
+    };
+    try_with!(
+        writeln!(out, "vcpu {}{}:", vcpu.idx, nested),
+        "cannot write to stdout"
+    );
+    for (name, value) in general.iter().chain(msrs.iter()) {
+        try_with!(
+            writeln!(out, "  {:<14} {:#018x}", name, value),
+            "cannot write to stdout"
+        );
+    }
+    for (name, seg) in &segments {
+        try_with!(
+            writeln!(
+                out,
+                "  {:<14} {:#06x} base={:#x} limit={:#x} dpl={}",
+                name, seg.selector, seg.base, seg.limit, seg.dpl
+            ),
+            "cannot write to stdout"
+        );
+    }
+    for (name, table) in &[("gdt", s.gdt), ("idt", s.idt)] {
+        try_with!(
+            writeln!(
+                out,
+                "  {:<14} base={:#x} limit={:#x}",
+                name, table.base, table.limit
+            ),
+            "cannot write to stdout"
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn set_register(_regs: &mut Regs, _name: &str, _value: u64) -> Result<()> {
+    bail!("setting registers is only supported on x86")
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn write_vcpu(_out: &mut dyn Write, _vm: &Hypervisor, _vcpu: &VCPU) -> Result<()> {
+    bail!("printing registers is only supported on x86")
+}
+
+pub fn regs(opts: &RegsOptions) -> Result<()> {
+    if !opts.set.is_empty() && opts.vcpu.is_none() {
+        bail!("--set needs --vcpu to select the vcpu to change");
+    }
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid, opts.vm),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    vm.stop()?;
+    let encryption = vm.encryption()?;
+    if encryption.has_protected_state() {
+        bail!(
+            "{} protects the vcpu registers from the hypervisor",
+            encryption
+        );
+    }
+    let vcpus = vm
+        .vcpus
+        .iter()
+        .filter(|vcpu| opts.vcpu.map_or(true, |idx| vcpu.idx == idx))
+        .collect::<Vec<_>>();
+    if let Some(idx) = opts.vcpu {
+        if vcpus.is_empty() {
+            bail!("vm has no vcpu {}, it has {}", idx, vm.vcpus.len());
+        }
+    }
+
+    if !opts.set.is_empty() {
+        vm.check_writable("setting registers")?;
+        let vcpu = vcpus[0];
+        let mut regs = vm.get_regs(vcpu)?;
+        for (name, value) in &opts.set {
+            set_register(&mut regs, name, *value)?;
+        }
+        try_with!(
+            vm.set_regs(vcpu, &regs),
+            "cannot set registers of vcpu {}",
+            vcpu.idx
+        );
+    }
+
+    let mut stdout = io::stdout().lock();
+    for vcpu in vcpus {
+        write_vcpu(&mut stdout, &vm, vcpu)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assignment() {
+        assert_eq!(
+            parse_assignment("RIP=0xffffffff81000000").unwrap(),
+            ("rip".to_string(), 0xffffffff81000000)
+        );
+        assert_eq!(parse_assignment("rax=42").unwrap(), ("rax".to_string(), 42));
+        assert!(parse_assignment("rax").is_err());
+        assert!(parse_assignment("rax=zz").is_err());
+    }
+}
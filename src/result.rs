@@ -1,7 +1,69 @@
+use libc::c_int;
+use nix::errno::Errno;
 use simple_error::SimpleError;
 use std::result;
+use std::time::Duration;
+use thiserror::Error;
 
-pub type Result<T> = result::Result<T, SimpleError>;
+use crate::watchdog::Failure;
+
+/// Errors of vmsh. Failures callers may want to handle get their own variant, everything else
+/// is a message in `Other`. `try_with!`, `bail!` and `require_with!` produce `Other` through
+/// the `From` implementations below.
+#[derive(Debug, Error)]
+pub enum VmshError {
+    /// Attaching to the hypervisor or to its VM failed, i.e. the process is not a KVM
+    /// hypervisor or is already traced.
+    #[error("cannot attach to process {pid}: {source}")]
+    Attach { pid: i32, source: Box<VmshError> },
+    /// The session ended, i.e. because the hypervisor exited.
+    #[error("vm is no longer attached")]
+    Detached,
+    /// The hypervisor exited or hung while vmsh was attached, see `watchdog`.
+    #[error("hypervisor failed: {0}")]
+    HypervisorFailed(Failure),
+    /// The guest kernel lacks something stage1 needs, i.e. exported symbols.
+    #[error("unsupported guest kernel: {0}")]
+    UnsupportedKernel(String),
+    /// A KVM ioctl injected into the hypervisor failed.
+    #[error("{ioctl} failed: {errno}")]
+    KvmIoctl { ioctl: &'static str, errno: Errno },
+    /// The guest did not react in time, i.e. stage1 does not run.
+    #[error("guest did not respond within {} seconds: {what}", .timeout.as_secs())]
+    GuestTimeout { what: String, timeout: Duration },
+    /// Waiting for the guest was given up, i.e. because vmsh is stopping.
+    #[error("cancelled while waiting for the guest: {0}")]
+    Cancelled(String),
+    /// Setting up or running a device failed.
+    #[error("device error: {0}")]
+    Device(String),
+    #[error("{0}")]
+    Other(#[from] SimpleError),
+}
+
+impl VmshError {
+    /// Error of an injected ioctl that returned `ret`, which is `-errno` on failure.
+    pub fn kvm_ioctl(ioctl: &'static str, ret: c_int) -> VmshError {
+        VmshError::KvmIoctl {
+            ioctl,
+            errno: Errno::from_i32(-ret),
+        }
+    }
+}
+
+impl From<&str> for VmshError {
+    fn from(s: &str) -> VmshError {
+        VmshError::Other(SimpleError::new(s))
+    }
+}
+
+impl From<String> for VmshError {
+    fn from(s: String) -> VmshError {
+        VmshError::Other(SimpleError::new(s))
+    }
+}
+
+pub type Result<T> = result::Result<T, VmshError>;
 
 #[macro_export]
 macro_rules! try_core_res {
@@ -18,3 +80,31 @@ macro_rules! try_core_res {
         },
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_error::{bail, try_with};
+
+    fn parse(s: &str) -> Result<u32> {
+        if s.is_empty() {
+            bail!("empty input");
+        }
+        Ok(try_with!(s.parse::<u32>(), "invalid number {}", s))
+    }
+
+    #[test]
+    fn macros_produce_other() {
+        assert!(matches!(parse(""), Err(VmshError::Other(_))));
+        let err = parse("x").err().map(|e| e.to_string());
+        assert_eq!(
+            err.as_deref(),
+            Some("invalid number x, invalid digit found in string")
+        );
+        let err = VmshError::kvm_ioctl("KVM_IRQFD", -22);
+        assert_eq!(
+            err.to_string(),
+            "KVM_IRQFD failed: EINVAL: Invalid argument"
+        );
+    }
+}
@@ -0,0 +1,128 @@
+//! Newline-delimited JSON-RPC 2.0 over unix sockets, shared by the daemon and the control
+//! socket of `vmsh attach --daemon`.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use simple_error::{bail, try_with, SimpleError};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+
+use crate::result::{Result, VmshError};
+
+pub(crate) const PARSE_ERROR: i64 = -32700;
+pub(crate) const METHOD_NOT_FOUND: i64 = -32601;
+pub(crate) const INVALID_PARAMS: i64 = -32602;
+pub(crate) const SERVER_ERROR: i64 = -32000;
+pub(crate) const UNAUTHORIZED: i64 = -32001;
+
+#[derive(Deserialize)]
+pub(crate) struct Request {
+    #[serde(default)]
+    pub id: Value,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Serialize)]
+pub(crate) struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: impl Into<String>) -> RpcError {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<SimpleError> for RpcError {
+    fn from(e: SimpleError) -> RpcError {
+        RpcError::new(SERVER_ERROR, e.to_string())
+    }
+}
+
+impl From<VmshError> for RpcError {
+    fn from(e: VmshError) -> RpcError {
+        RpcError::new(SERVER_ERROR, e.to_string())
+    }
+}
+
+pub(crate) fn parse_params<T: serde::de::DeserializeOwned>(
+    params: &Value,
+) -> std::result::Result<T, RpcError> {
+    serde_json::from_value(params.clone())
+        .map_err(|e| RpcError::new(INVALID_PARAMS, format!("invalid params: {}", e)))
+}
+
+pub(crate) fn write_response(
+    stream: &mut UnixStream,
+    id: Value,
+    res: std::result::Result<Value, RpcError>,
+) -> Result<()> {
+    let msg = match res {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+        Err(error) => json!({"jsonrpc": "2.0", "id": id, "error": error}),
+    };
+    let mut line = try_with!(serde_json::to_vec(&msg), "cannot serialize response");
+    line.push(b'\n');
+    try_with!(stream.write_all(&line), "cannot write response");
+    Ok(())
+}
+
+/// Reads the next request. Malformed requests are answered with a parse error and skipped.
+/// Returns `None` once the client hung up.
+pub(crate) fn read_request(
+    reader: &mut BufReader<UnixStream>,
+    writer: &mut UnixStream,
+) -> Result<Option<Request>> {
+    loop {
+        let mut line = String::new();
+        let n = try_with!(reader.read_line(&mut line), "cannot read request");
+        if n == 0 {
+            return Ok(None);
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(req) => return Ok(Some(req)),
+            Err(e) => {
+                let err = RpcError::new(PARSE_ERROR, format!("invalid request: {}", e));
+                write_response(writer, Value::Null, Err(err))?;
+            }
+        }
+    }
+}
+
+/// Sends a request and waits for the response. Errors of the server are returned as error.
+pub(crate) fn call(
+    reader: &mut BufReader<UnixStream>,
+    method: &str,
+    params: Value,
+) -> Result<Value> {
+    let req = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params});
+    let mut line = try_with!(serde_json::to_vec(&req), "cannot serialize request");
+    line.push(b'\n');
+    try_with!(reader.get_mut().write_all(&line), "cannot send request");
+    let mut line = String::new();
+    let n = try_with!(reader.read_line(&mut line), "cannot read response");
+    if n == 0 {
+        bail!("connection closed without response");
+    }
+    let mut resp: Value = try_with!(serde_json::from_str(&line), "invalid response");
+    if let Some(error) = resp.get("error") {
+        let message = error
+            .get("message")
+            .and_then(Value::as_str)
+            .unwrap_or("unknown error");
+        bail!("{} failed: {}", method, message);
+    }
+    Ok(resp
+        .get_mut("result")
+        .map(Value::take)
+        .unwrap_or(Value::Null))
+}
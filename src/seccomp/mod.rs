@@ -0,0 +1,283 @@
+//! Compiles OCI seccomp profiles, the `linux.seccomp` section of an OCI runtime
+//! configuration, into a BPF filter that stage2 installs for the command.
+
+use ioutils::seccomp::{
+    Instruction, BPF_ALU_AND_K, BPF_JMP_JEQ_K, BPF_LD_W_ABS, BPF_RET_K, MAX_INSTRUCTIONS,
+};
+use log::warn;
+use serde::Deserialize;
+use simple_error::{bail, try_with};
+use std::fs;
+use std::path::Path;
+
+use crate::result::Result;
+
+mod syscalls;
+
+const AUDIT_ARCH_X86_64: u32 = 0xc000_003e;
+
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_KILL_THREAD: u32 = 0x0000_0000;
+const SECCOMP_RET_TRAP: u32 = 0x0003_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_TRACE: u32 = 0x7ff0_0000;
+const SECCOMP_RET_LOG: u32 = 0x7ffc_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+/// Offsets in `struct seccomp_data`
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+const DATA_ARGS: u32 = 16;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Profile {
+    default_action: String,
+    default_errno_ret: Option<u32>,
+    #[serde(default)]
+    architectures: Vec<String>,
+    #[serde(default)]
+    syscalls: Vec<SyscallRule>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SyscallRule {
+    names: Vec<String>,
+    action: String,
+    errno_ret: Option<u32>,
+    #[serde(default)]
+    args: Vec<ArgCondition>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ArgCondition {
+    index: u32,
+    value: u64,
+    #[serde(default)]
+    value_two: u64,
+    op: String,
+}
+
+fn action(name: &str, errno_ret: Option<u32>) -> Result<u32> {
+    Ok(match name {
+        "SCMP_ACT_KILL" | "SCMP_ACT_KILL_THREAD" => SECCOMP_RET_KILL_THREAD,
+        "SCMP_ACT_KILL_PROCESS" => SECCOMP_RET_KILL_PROCESS,
+        "SCMP_ACT_TRAP" => SECCOMP_RET_TRAP,
+        "SCMP_ACT_ERRNO" => SECCOMP_RET_ERRNO | (errno_ret.unwrap_or(libc::EPERM as u32) & 0xffff),
+        "SCMP_ACT_TRACE" => SECCOMP_RET_TRACE | (errno_ret.unwrap_or(0) & 0xffff),
+        "SCMP_ACT_LOG" => SECCOMP_RET_LOG,
+        "SCMP_ACT_ALLOW" => SECCOMP_RET_ALLOW,
+        _ => bail!("unsupported seccomp action {}", name),
+    })
+}
+
+fn syscall_nr(name: &str) -> Option<u32> {
+    syscalls::SYSCALLS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, nr)| *nr)
+}
+
+/// Instructions checking a condition, jumping `fail` instructions past the condition if it
+/// does not hold.
+fn condition(cond: &ArgCondition, fail: usize) -> Result<Vec<Instruction>> {
+    if cond.index >= 6 {
+        bail!("invalid argument index {}", cond.index);
+    }
+    // arguments are 64-bit, compare the low and high word separately
+    let lo = DATA_ARGS + cond.index * 8;
+    let hi = lo + 4;
+    let split = |v: u64| (v as u32, (v >> 32) as u32);
+    let jump = |fail: usize| -> Result<u8> {
+        if fail > u8::MAX as usize {
+            bail!("seccomp rule is too large");
+        }
+        Ok(fail as u8)
+    };
+    let load = |offset| Instruction::stmt(BPF_LD_W_ABS, offset);
+    let ins = match cond.op.as_str() {
+        "SCMP_CMP_EQ" => {
+            let (v_lo, v_hi) = split(cond.value);
+            vec![
+                load(lo),
+                Instruction::jump(BPF_JMP_JEQ_K, v_lo, 0, jump(fail + 2)?),
+                load(hi),
+                Instruction::jump(BPF_JMP_JEQ_K, v_hi, 0, jump(fail)?),
+            ]
+        }
+        "SCMP_CMP_NE" => {
+            let (v_lo, v_hi) = split(cond.value);
+            vec![
+                load(lo),
+                Instruction::jump(BPF_JMP_JEQ_K, v_lo, 0, 2),
+                load(hi),
+                Instruction::jump(BPF_JMP_JEQ_K, v_hi, jump(fail)?, 0),
+            ]
+        }
+        "SCMP_CMP_MASKED_EQ" => {
+            let (m_lo, m_hi) = split(cond.value);
+            let (v_lo, v_hi) = split(cond.value_two);
+            vec![
+                load(lo),
+                Instruction::stmt(BPF_ALU_AND_K, m_lo),
+                Instruction::jump(BPF_JMP_JEQ_K, v_lo, 0, jump(fail + 3)?),
+                load(hi),
+                Instruction::stmt(BPF_ALU_AND_K, m_hi),
+                Instruction::jump(BPF_JMP_JEQ_K, v_hi, 0, jump(fail)?),
+            ]
+        }
+        op => bail!("unsupported seccomp argument comparison {}", op),
+    };
+    Ok(ins)
+}
+
+fn compile(profile: &Profile) -> Result<Vec<Instruction>> {
+    if !profile.architectures.is_empty()
+        && !profile
+            .architectures
+            .iter()
+            .any(|a| a == "SCMP_ARCH_X86_64")
+    {
+        bail!("seccomp profile does not cover SCMP_ARCH_X86_64");
+    }
+    let default_action = action(&profile.default_action, profile.default_errno_ret)?;
+
+    let mut program = vec![
+        Instruction::stmt(BPF_LD_W_ABS, DATA_ARCH),
+        Instruction::jump(BPF_JMP_JEQ_K, AUDIT_ARCH_X86_64, 1, 0),
+        Instruction::stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+    ];
+    // argument checks overwrite the syscall number in the accumulator
+    let mut nr_loaded = false;
+    for rule in &profile.syscalls {
+        let ret = action(&rule.action, rule.errno_ret)?;
+        for name in &rule.names {
+            let nr = match syscall_nr(name) {
+                Some(nr) => nr,
+                None => {
+                    warn!("ignore unknown syscall {} in seccomp profile", name);
+                    continue;
+                }
+            };
+            // a condition that does not hold jumps past the return of this rule
+            let mut conditions = Vec::new();
+            for cond in rule.args.iter().rev() {
+                let mut ins = condition(cond, conditions.len() + 1)?;
+                ins.extend(conditions);
+                conditions = ins;
+            }
+            if !nr_loaded {
+                program.push(Instruction::stmt(BPF_LD_W_ABS, DATA_NR));
+            }
+            let skip = conditions.len() + 1;
+            if skip > u8::MAX as usize {
+                bail!("seccomp rule for {} is too large", name);
+            }
+            program.push(Instruction::jump(BPF_JMP_JEQ_K, nr, 0, skip as u8));
+            nr_loaded = conditions.is_empty();
+            program.extend(conditions);
+            program.push(Instruction::stmt(BPF_RET_K, ret));
+        }
+    }
+    program.push(Instruction::stmt(BPF_RET_K, default_action));
+
+    if program.len() > MAX_INSTRUCTIONS {
+        bail!(
+            "seccomp filter has {} instructions, the kernel accepts at most {}",
+            program.len(),
+            MAX_INSTRUCTIONS
+        );
+    }
+    Ok(program)
+}
+
+/// Compiles the profile at `path` to the encoded filter passed to stage2.
+pub fn compile_file(path: &Path) -> Result<String> {
+    let content = try_with!(fs::read_to_string(path), "cannot read {}", path.display());
+    let profile: Profile = try_with!(
+        serde_json::from_str(&content),
+        "cannot parse seccomp profile {}",
+        path.display()
+    );
+    let program = try_with!(
+        compile(&profile),
+        "cannot compile seccomp profile {}",
+        path.display()
+    );
+    Ok(ioutils::seccomp::encode(&program))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the filter for a syscall with the given number and arguments.
+    fn run(program: &[Instruction], nr: u32, args: [u64; 6]) -> u32 {
+        let mut data = vec![];
+        data.extend_from_slice(&nr.to_le_bytes());
+        data.extend_from_slice(&AUDIT_ARCH_X86_64.to_le_bytes());
+        data.extend_from_slice(&0u64.to_le_bytes());
+        for arg in &args {
+            data.extend_from_slice(&arg.to_le_bytes());
+        }
+        let (mut pc, mut acc) = (0, 0u32);
+        loop {
+            let ins = program[pc];
+            pc += 1;
+            match ins.code {
+                BPF_LD_W_ABS => {
+                    let o = ins.k as usize;
+                    acc = u32::from_le_bytes([data[o], data[o + 1], data[o + 2], data[o + 3]]);
+                }
+                BPF_ALU_AND_K => acc &= ins.k,
+                BPF_JMP_JEQ_K if acc == ins.k => pc += ins.jt as usize,
+                BPF_JMP_JEQ_K => pc += ins.jf as usize,
+                BPF_RET_K => return ins.k,
+                code => panic!("unexpected instruction {:#x}", code),
+            }
+        }
+    }
+
+    #[test]
+    fn compile_profile() {
+        let profile: Profile = serde_json::from_str(
+            r#"{
+                "defaultAction": "SCMP_ACT_ERRNO",
+                "architectures": ["SCMP_ARCH_X86_64"],
+                "syscalls": [
+                    {"names": ["read", "write"], "action": "SCMP_ACT_ALLOW"},
+                    {"names": ["personality"], "action": "SCMP_ACT_ALLOW",
+                     "args": [{"index": 0, "value": 8, "op": "SCMP_CMP_EQ"}]},
+                    {"names": ["socket"], "action": "SCMP_ACT_ALLOW",
+                     "args": [{"index": 0, "value": 40, "op": "SCMP_CMP_NE"},
+                              {"index": 1, "value": 255, "valueTwo": 1, "op": "SCMP_CMP_MASKED_EQ"}]},
+                    {"names": ["no_such_syscall"], "action": "SCMP_ACT_ALLOW"}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let program = compile(&profile).unwrap();
+        let eperm = SECCOMP_RET_ERRNO | libc::EPERM as u32;
+        let nr = |name| syscall_nr(name).unwrap();
+        assert_eq!(run(&program, nr("write"), [0; 6]), SECCOMP_RET_ALLOW);
+        assert_eq!(run(&program, nr("open"), [0; 6]), eperm);
+        assert_eq!(
+            run(&program, nr("personality"), [8, 0, 0, 0, 0, 0]),
+            SECCOMP_RET_ALLOW
+        );
+        assert_eq!(
+            run(&program, nr("personality"), [1 << 32 | 8, 0, 0, 0, 0, 0]),
+            eperm
+        );
+        assert_eq!(
+            run(&program, nr("socket"), [2, 0x101, 0, 0, 0, 0]),
+            SECCOMP_RET_ALLOW
+        );
+        assert_eq!(run(&program, nr("socket"), [40, 1, 0, 0, 0, 0]), eperm);
+        assert_eq!(run(&program, nr("socket"), [2, 2, 0, 0, 0, 0]), eperm);
+        // rules after a rule with arguments still match
+        assert_eq!(run(&program, nr("read"), [0; 6]), SECCOMP_RET_ALLOW);
+    }
+}
@@ -0,0 +1,224 @@
+//! State of an attached vmsh session, kept on disk so that vmsh can detach from a VM and
+//! re-attach to the devices and stage2 it left behind.
+
+use log::{info, warn};
+use nix::errno::Errno;
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use simple_error::{bail, require_with, try_with};
+use std::fs::{self, DirBuilder};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::devices::mmio::MmioWrite;
+use crate::page_table::KeptVirtMem;
+use crate::result::Result;
+use crate::stage1::{DeviceSlots, DeviceStatus, DriverStatus};
+
+/// Session files and control sockets of attached VMs
+pub const SESSION_DIR: &str = "/run/vmsh";
+
+/// How long `detach` waits for the attached vmsh process to exit
+const DETACH_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize)]
+pub struct Session {
+    /// vmsh process serving the devices, 0 if detached
+    pub vmsh_pid: i32,
+    /// Start time of the hypervisor, to detect reused pids
+    pub hypervisor_start_time: u64,
+    pub irq_nums: Vec<usize>,
+    pub mmio_addrs: Vec<u64>,
+    /// Host addresses of the fields of `Stage1Args` in the hypervisor
+    pub device_status: usize,
+    pub driver_status: usize,
+    pub device_addrs: usize,
+    pub device_generation: usize,
+    pub driver_generation: usize,
+    /// Register writes of the guest drivers, only saved on detach
+    pub mmio_writes: Vec<MmioWrite>,
+    /// Memory of stage1, removed from the guest when the last vmsh process of the session
+    /// stops without detaching
+    #[serde(default)]
+    pub stage1_mem: Option<KeptVirtMem>,
+}
+
+/// Creates `SESSION_DIR`. Only root may enter it, the control sockets in it open shells in the
+/// VMs.
+pub fn create_session_dir() -> Result<()> {
+    try_with!(
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(SESSION_DIR),
+        "cannot create {}",
+        SESSION_DIR
+    );
+    // an existing directory keeps its mode
+    try_with!(
+        fs::set_permissions(SESSION_DIR, fs::Permissions::from_mode(0o700)),
+        "cannot set permissions of {}",
+        SESSION_DIR
+    );
+    Ok(())
+}
+
+fn session_path(hypervisor: Pid) -> PathBuf {
+    PathBuf::from(SESSION_DIR).join(format!("{}.json", hypervisor))
+}
+
+/// Clock ticks after boot at which the process was started, see proc(5).
+pub fn process_start_time(pid: Pid) -> Result<u64> {
+    let path = format!("/proc/{}/stat", pid);
+    let stat = try_with!(fs::read_to_string(&path), "cannot read {}", path);
+    // the command name in parentheses may contain spaces
+    let fields = require_with!(stat.rsplit_once(')'), "invalid format of {}", path).1;
+    let start_time = require_with!(
+        fields.split_whitespace().nth(19),
+        "no start time in {}",
+        path
+    );
+    Ok(try_with!(
+        start_time.parse(),
+        "invalid start time in {}",
+        path
+    ))
+}
+
+fn process_alive(pid: Pid) -> bool {
+    !matches!(signal::kill(pid, None), Err(Errno::ESRCH))
+}
+
+impl Session {
+    pub fn new(
+        hypervisor: Pid,
+        irq_nums: &[usize],
+        mmio_addrs: Vec<u64>,
+        device_status: &DeviceStatus,
+        driver_status: &DriverStatus,
+        device_slots: &DeviceSlots,
+    ) -> Result<Session> {
+        Ok(Session {
+            vmsh_pid: nix::unistd::getpid().as_raw(),
+            hypervisor_start_time: process_start_time(hypervisor)?,
+            irq_nums: irq_nums.to_vec(),
+            mmio_addrs,
+            device_status: device_status.host_addr,
+            driver_status: driver_status.host_addr,
+            device_addrs: device_slots.device_addrs,
+            device_generation: device_slots.device_generation,
+            driver_generation: device_slots.driver_generation,
+            mmio_writes: vec![],
+            stage1_mem: None,
+        })
+    }
+
+    /// Reads the session of the VM in `hypervisor`. Sessions of an earlier process with the
+    /// same pid are ignored.
+    pub fn load(hypervisor: Pid) -> Result<Option<Session>> {
+        let path = session_path(hypervisor);
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => bail!("cannot read {}: {}", path.display(), e),
+        };
+        let session: Session = try_with!(
+            serde_json::from_str(&content),
+            "cannot parse {}",
+            path.display()
+        );
+        if session.hypervisor_start_time != process_start_time(hypervisor)? {
+            warn!("ignore stale session of an earlier process {}", hypervisor);
+            return Ok(None);
+        }
+        Ok(Some(session))
+    }
+
+    pub fn save(&self, hypervisor: Pid) -> Result<()> {
+        create_session_dir()?;
+        let path = session_path(hypervisor);
+        let tmp = path.with_extension("json.tmp");
+        let content = try_with!(serde_json::to_string(self), "cannot serialize session");
+        try_with!(fs::write(&tmp, content), "cannot write {}", tmp.display());
+        try_with!(
+            fs::rename(&tmp, &path),
+            "cannot rename {} to {}",
+            tmp.display(),
+            path.display()
+        );
+        Ok(())
+    }
+
+    pub fn remove(hypervisor: Pid) -> Result<()> {
+        let path = session_path(hypervisor);
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                bail!("cannot remove {}: {}", path.display(), e)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// True if a vmsh process serves the devices of this session.
+    pub fn attached(&self) -> bool {
+        self.vmsh_pid != 0 && process_alive(Pid::from_raw(self.vmsh_pid))
+    }
+
+    pub fn stage1_fields(&self) -> (DeviceStatus, DriverStatus, DeviceSlots) {
+        (
+            DeviceStatus {
+                host_addr: self.device_status,
+            },
+            DriverStatus {
+                host_addr: self.driver_status,
+            },
+            DeviceSlots {
+                device_addrs: self.device_addrs,
+                device_generation: self.device_generation,
+                driver_generation: self.driver_generation,
+            },
+        )
+    }
+}
+
+pub struct DetachOptions {
+    pub pid: Pid,
+}
+
+/// Asks the vmsh process attached to the VM to detach and waits until it exited.
+pub fn detach(opts: &DetachOptions) -> Result<()> {
+    let session = require_with!(
+        Session::load(opts.pid)?,
+        "no detachable vmsh session found for process {}",
+        opts.pid
+    );
+    if session.vmsh_pid == 0 {
+        bail!("vmsh session of process {} is already detached", opts.pid);
+    }
+    if !session.attached() {
+        bail!(
+            "vmsh process {} of the session exited without detaching",
+            session.vmsh_pid
+        );
+    }
+    let vmsh = Pid::from_raw(session.vmsh_pid);
+    info!("detach vmsh process {}", vmsh);
+    try_with!(
+        signal::kill(vmsh, Signal::SIGUSR1),
+        "cannot signal vmsh process {}",
+        vmsh
+    );
+    let start = Instant::now();
+    while process_alive(vmsh) {
+        if start.elapsed() > DETACH_TIMEOUT {
+            bail!("timeout while waiting for vmsh process {} to detach", vmsh);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    match Session::load(opts.pid)? {
+        Some(session) if session.vmsh_pid == 0 => Ok(()),
+        _ => bail!("vmsh process {} exited without detaching", vmsh),
+    }
+}
@@ -1,21 +1,220 @@
-use log::{error, info};
+//! Signals of vmsh. SIGTERM and SIGINT stop vmsh, SIGUSR1 detaches it.
+//!
+//! If vmsh was attached with `--vsock`, stage2 connects to `SIGNAL_VSOCK_PORT`. As long as
+//! that connection is open, `vmsh attach` passes SIGINT, SIGTERM and SIGHUP on to the command
+//! instead of stopping: every signal is written as a single byte with its number and stage2
+//! sends it to the foreground job of the command. A second signal within `FORCE_STOP_INTERVAL`
+//! stops vmsh regardless.
+//!
+//! Once the command exited, stage2 sends its exit code as `ioutils::exec::Message::Exit` on the
+//! same connection. vmsh stops then and `vmsh attach` exits with that code.
+
+use ioutils::exec::{self, Message};
+use log::{error, info, warn};
+use nix::sys::signal::Signal;
+use simple_error::try_with;
+use std::convert::TryFrom;
+use std::fs;
+use std::io::Write;
+use std::os::raw::c_int;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use signal_hook::consts::signal::{SIGINT, SIGTERM};
+use signal_hook::consts::signal::{SIGHUP, SIGINT, SIGTERM, SIGUSR1};
 use signal_hook::iterator::Signals;
 
-pub fn setup(sender: Sender<()>) {
+use crate::devices::virtio::vsock::SIGNAL_VSOCK_PORT;
+use crate::result::Result;
+
+/// Set when vmsh received SIGUSR1, i.e. from `vmsh detach`.
+static DETACH_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Connection of stage2 and its number, signals are forwarded while it is set.
+static GUEST: Mutex<Option<(usize, UnixStream)>> = Mutex::new(None);
+static NEXT_CONNECTION: AtomicUsize = AtomicUsize::new(0);
+
+/// Exit code of the command, once stage2 reported it.
+static EXIT_CODE: Mutex<Option<i32>> = Mutex::new(None);
+
+/// Signals received in this interval after a forwarded one stop vmsh.
+const FORCE_STOP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether vmsh should leave the session running in the guest instead of tearing it down.
+pub fn detach_requested() -> bool {
+    DETACH_REQUESTED.load(Ordering::Acquire)
+}
+
+/// Detaches like SIGUSR1, for requests that do not arrive as signal.
+pub fn request_detach(sender: &Sender<()>) {
+    DETACH_REQUESTED.store(true, Ordering::Release);
+    if let Err(err) = sender.send(()) {
+        error!("error sending detach request: {:?}", err);
+    }
+}
+
+/// Exit code of the command in the guest, None if it did not exit while vmsh was attached.
+pub fn exit_code() -> Option<i32> {
+    EXIT_CODE.lock().map_or(None, |code| *code)
+}
+
+fn guest_connected() -> bool {
+    GUEST.lock().map_or(false, |guest| guest.is_some())
+}
+
+/// Sends `signal` to the command in the guest, returns false if stage2 is not connected.
+fn forward_to_guest(signal: Signal) -> bool {
+    let mut guest = match GUEST.lock() {
+        Ok(guest) => guest,
+        Err(_) => return false,
+    };
+    let conn = match guest.as_mut() {
+        Some((_, conn)) => conn,
+        None => return false,
+    };
+    if let Err(e) = conn.write_all(&[signal as u8]) {
+        warn!("cannot forward {} to the guest: {}", signal, e);
+        *guest = None;
+        return false;
+    }
+    true
+}
+
+/// Kills the command in the guest and waits up to `timeout` until it exited. Used for
+/// `--kill-on-detach` before the devices are removed.
+pub fn kill_guest_command(timeout: Duration) {
+    if !forward_to_guest(Signal::SIGKILL) {
+        warn!("cannot kill the command, stage2 is not connected");
+        return;
+    }
+    let start = Instant::now();
+    while guest_connected() {
+        if start.elapsed() > timeout {
+            warn!("command did not exit after SIGKILL");
+            return;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    info!("killed the command in the guest");
+}
+
+/// Stops accepting connections of stage2 when dropped.
+pub struct SignalChannel {
+    stop: Arc<AtomicBool>,
+    path: PathBuf,
+}
+
+impl Drop for SignalChannel {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        // wake up the accepting thread
+        let _ = UnixStream::connect(&self.path);
+        let _ = fs::remove_file(&self.path);
+        if let Ok(mut guest) = GUEST.lock() {
+            *guest = None;
+        }
+    }
+}
+
+/// Waits for the exit code of the command until stage2 closes `conn`.
+fn watch_connection(id: usize, mut conn: UnixStream, stop: Sender<()>) {
+    loop {
+        match exec::read_message(&mut conn) {
+            Ok(Some(Message::Exit(code))) => {
+                info!("command in the guest exited with {}", code);
+                if let Ok(mut exit_code) = EXIT_CODE.lock() {
+                    *exit_code = Some(code);
+                }
+                // vmsh might be stopping already
+                let _ = stop.send(());
+            }
+            Ok(Some(msg)) => warn!("unexpected message from stage2: {:?}", msg),
+            Ok(None) => break,
+            Err(e) => {
+                warn!("cannot read from stage2: {}", e);
+                break;
+            }
+        }
+    }
+    if let Ok(mut guest) = GUEST.lock() {
+        if matches!(*guest, Some((current, _)) if current == id) {
+            *guest = None;
+        }
+    }
+}
+
+/// Listens on the vsock socket of stage2 for the connection signals are forwarded on. `stop`
+/// is notified when the command exited.
+pub fn listen(vsock: &Path, stop: Sender<()>) -> Result<SignalChannel> {
+    let path = PathBuf::from(format!("{}_{}", vsock.display(), SIGNAL_VSOCK_PORT));
+    if path.exists() {
+        try_with!(fs::remove_file(&path), "cannot remove {}", path.display());
+    }
+    let listener = try_with!(
+        UnixListener::bind(&path),
+        "cannot listen on {}",
+        path.display()
+    );
+    let stopped = Arc::new(AtomicBool::new(false));
+    let stop_listener = Arc::clone(&stopped);
+    let _ = thread::spawn(move || {
+        for conn in listener.incoming() {
+            if stop_listener.load(Ordering::Acquire) {
+                break;
+            }
+            let conn = match conn.and_then(|conn| Ok((conn.try_clone()?, conn))) {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("cannot accept connection of stage2: {}", e);
+                    continue;
+                }
+            };
+            let id = NEXT_CONNECTION.fetch_add(1, Ordering::SeqCst);
+            if let Ok(mut guest) = GUEST.lock() {
+                *guest = Some((id, conn.0));
+            }
+            let stop = stop.clone();
+            let _ = thread::spawn(move || watch_connection(id, conn.1, stop));
+        }
+    });
+    Ok(SignalChannel {
+        stop: stopped,
+        path,
+    })
+}
+
+fn spawn_handler(sender: Sender<()>, signals: &'static [c_int], forward: bool) {
     let _ = std::thread::spawn(move || {
-        let mut signals = match Signals::new([SIGTERM, SIGINT]) {
+        let mut signals = match Signals::new(signals) {
             Ok(v) => v,
             Err(e) => {
                 error!("error setting up signal handler: {:?}", e);
                 return;
             }
         };
+        let mut last_forwarded: Option<Instant> = None;
         loop {
-            for _ in signals.pending() {
-                info!("stopping vmsh...");
+            for signal in signals.pending() {
+                if signal == SIGUSR1 {
+                    info!("detaching vmsh...");
+                    DETACH_REQUESTED.store(true, Ordering::Release);
+                } else if forward
+                    && last_forwarded.map_or(true, |t| t.elapsed() > FORCE_STOP_INTERVAL)
+                    && Signal::try_from(signal).map_or(false, forward_to_guest)
+                {
+                    info!(
+                        "forwarded signal {} to the command, repeat it to stop vmsh",
+                        signal
+                    );
+                    last_forwarded = Some(Instant::now());
+                    continue;
+                } else {
+                    info!("stopping vmsh...");
+                }
                 if let Err(err) = sender.send(()) {
                     error!("error sending signal: {:?}", err);
                 }
@@ -23,3 +222,55 @@ pub fn setup(sender: Sender<()>) {
         }
     });
 }
+
+pub fn setup(sender: Sender<()>) {
+    spawn_handler(sender, &[SIGTERM, SIGINT], false);
+}
+
+/// Like `setup`, but SIGUSR1 also stops vmsh and sets `detach_requested`. SIGINT, SIGTERM and
+/// SIGHUP are forwarded to the command while stage2 is connected (see `listen`).
+pub fn setup_detachable(sender: Sender<()>) {
+    spawn_handler(sender, &[SIGTERM, SIGINT, SIGHUP, SIGUSR1], true);
+}
+
+#[cfg(test)]
+mod tests {
+    use ioutils::tmp::tempdir;
+    use std::io::Read;
+    use std::sync::mpsc::channel;
+
+    use super::*;
+
+    fn wait_until(cond: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !cond() {
+            assert!(start.elapsed() < Duration::from_secs(5), "timeout");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn forward_signals() {
+        let dir = tempdir().unwrap();
+        let vsock = dir.path().join("vsock");
+        let (sender, receiver) = channel();
+        let _channel = listen(&vsock, sender).unwrap();
+        assert!(!forward_to_guest(Signal::SIGINT));
+
+        let path = format!("{}_{}", vsock.display(), SIGNAL_VSOCK_PORT);
+        let mut stage2 = UnixStream::connect(path).unwrap();
+        wait_until(guest_connected);
+        assert!(forward_to_guest(Signal::SIGINT));
+        assert!(forward_to_guest(Signal::SIGHUP));
+        let mut buf = [0u8; 2];
+        stage2.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [libc::SIGINT as u8, libc::SIGHUP as u8]);
+
+        exec::write_message(&mut stage2, &Message::Exit(130)).unwrap();
+        receiver.recv().unwrap();
+        assert_eq!(exit_code(), Some(130));
+        drop(stage2);
+        wait_until(|| !guest_connected());
+        assert!(!forward_to_guest(Signal::SIGTERM));
+    }
+}
@@ -0,0 +1,646 @@
+//! Saves the memory and vcpu registers of a VM to a directory and restores them later into
+//! the same VM.
+//!
+//! Only state that we can reach from outside of the hypervisor is saved: guest RAM backed by
+//! memslots and the registers of each vcpu. Emulated devices, the interrupt controller and
+//! clocks live in the hypervisor and are left as they are, so a restore only works while the
+//! hypervisor still runs with the same device configuration.
+//!
+//! An incremental snapshot only contains the pages that differ from a full snapshot, its base.
+//! The KVM dirty log does not outlive the vmsh process that enabled it, so the changed pages are
+//! found by comparing the memory with the one of the base.
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use kvm_bindings as kvmb;
+use log::info;
+use nix::sys::uio::{process_vm_readv, RemoteIoVec};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use simple_error::{bail, require_with, try_with};
+use std::cmp::min;
+use std::fs::{self, File};
+use std::io::IoSliceMut;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::kvm;
+use crate::kvm::dirty_log::DirtyTracker;
+use crate::kvm::hypervisor::memory::process_write_vectored;
+use crate::kvm::hypervisor::{Hypervisor, VmSelector, VCPU};
+use crate::page_math::page_size;
+use crate::result::Result;
+use crate::tracer::proc::Mapping;
+
+pub struct SnapshotOptions {
+    pub pid: Pid,
+    pub vm: Option<VmSelector>,
+    /// Directory the snapshot is written to
+    pub path: PathBuf,
+    /// Copy memory while the VM keeps running and only stop it to copy the pages written in
+    /// the meantime
+    pub live: bool,
+    /// Full snapshot to save only the changed pages against
+    pub base: Option<PathBuf>,
+}
+
+pub struct RestoreOptions {
+    pub pid: Pid,
+    pub vm: Option<VmSelector>,
+    /// Directory of a snapshot created by `snapshot`
+    pub path: PathBuf,
+}
+
+/// Version 2 added incremental snapshots, older snapshots are read as full ones
+const SNAPSHOT_VERSION: u32 = 2;
+
+/// Written last, a directory without it contains an incomplete snapshot
+const METADATA_FILE: &str = "snapshot.json";
+
+/// Bytes copied between the hypervisor and a memory file at once
+const COPY_CHUNK: usize = 16 << 20;
+
+#[derive(Serialize, Deserialize)]
+struct Metadata {
+    version: u32,
+    /// Directory of the full snapshot an incremental snapshot is based on
+    #[serde(default)]
+    base: Option<PathBuf>,
+    memory: Vec<MemoryRegion>,
+    vcpus: usize,
+}
+
+impl Metadata {
+    fn read(dir: &Path) -> Result<Metadata> {
+        let path = dir.join(METADATA_FILE);
+        let json = try_with!(fs::read(&path), "cannot read {}", path.display());
+        let metadata: Metadata =
+            try_with!(serde_json::from_slice(&json), "invalid {}", path.display());
+        if metadata.version == 0 || metadata.version > SNAPSHOT_VERSION {
+            bail!(
+                "unsupported snapshot version {}, expected at most {}",
+                metadata.version,
+                SNAPSHOT_VERSION
+            );
+        }
+        Ok(metadata)
+    }
+
+    /// Reads the full snapshot in `dir` to be used as base.
+    fn read_base(dir: &Path) -> Result<Metadata> {
+        let metadata = Metadata::read(dir)?;
+        if let Some(base) = &metadata.base {
+            bail!(
+                "{} is an incremental snapshot, use its base {} instead",
+                dir.display(),
+                base.display()
+            );
+        }
+        Ok(metadata)
+    }
+
+    /// Opens the memory file of the memslot at `slot`, which must have the same size.
+    fn open_region(&self, dir: &Path, slot: &Mapping) -> Result<File> {
+        let region = require_with!(
+            self.memory
+                .iter()
+                .find(|r| r.phys_addr == slot.phys_addr && r.size == slot.size()),
+            "{} has no memslot at {:#x} with {} bytes, the memory layout changed since the snapshot",
+            dir.display(),
+            slot.phys_addr,
+            slot.size()
+        );
+        let path = dir.join(&region.file);
+        Ok(try_with!(
+            File::open(&path),
+            "cannot open {}",
+            path.display()
+        ))
+    }
+}
+
+/// Content of one memslot
+#[derive(Serialize, Deserialize)]
+struct MemoryRegion {
+    phys_addr: usize,
+    size: usize,
+    file: String,
+    /// Offsets of the pages in `file` that differ from the base, `None` if all pages are saved
+    #[serde(default)]
+    changed: Option<Vec<Range<usize>>>,
+}
+
+/// Memory of one memslot in the base snapshot
+struct BaseMemory {
+    file: File,
+    /// Pages that were saved because they differ from the base
+    changed: Vec<bool>,
+    buf: Vec<u8>,
+}
+
+impl BaseMemory {
+    fn new(file: File, size: usize) -> BaseMemory {
+        BaseMemory {
+            file,
+            changed: vec![false; size / page_size()],
+            buf: vec![],
+        }
+    }
+
+    fn changed_ranges(&self) -> Vec<Range<usize>> {
+        let mut ranges: Vec<Range<usize>> = vec![];
+        for (page, _) in self.changed.iter().enumerate().filter(|(_, c)| **c) {
+            let start = page * page_size();
+            match ranges.last_mut() {
+                Some(last) if last.end == start => last.end += page_size(),
+                _ => ranges.push(start..start + page_size()),
+            }
+        }
+        ranges
+    }
+}
+
+/// Where the memory of one memslot is saved to
+struct MemoryFile {
+    file: File,
+    base: Option<BaseMemory>,
+}
+
+impl MemoryFile {
+    /// Writes `data` read at `offset` of the memslot. With a base only the pages that differ from
+    /// it are written, the others stay holes in the file.
+    fn write(&mut self, data: &[u8], offset: usize) -> Result<()> {
+        let base = match &mut self.base {
+            Some(base) => base,
+            None => {
+                try_with!(
+                    self.file.write_all_at(data, offset as u64),
+                    "cannot write memory file"
+                );
+                return Ok(());
+            }
+        };
+        base.buf.resize(data.len(), 0);
+        try_with!(
+            base.file.read_exact_at(&mut base.buf, offset as u64),
+            "cannot read memory file of the base snapshot"
+        );
+        let pages = data.chunks(page_size()).zip(base.buf.chunks(page_size()));
+        for (i, (new, old)) in pages.enumerate() {
+            let page = offset / page_size() + i;
+            // a page saved earlier is saved again, it may have changed back to the base
+            if new != old || base.changed[page] {
+                base.changed[page] = true;
+                try_with!(
+                    self.file
+                        .write_all_at(new, (offset + i * page_size()) as u64),
+                    "cannot write memory file"
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+fn vcpu_file(idx: usize) -> String {
+    format!("vcpu-{}.bin", idx)
+}
+
+fn memory_file(slot: &Mapping) -> String {
+    format!("memory-{:x}.bin", slot.phys_addr)
+}
+
+/// Copies `range` (offsets into `slot`) from the hypervisor to the same offsets in `file`.
+fn save_memory(pid: Pid, slot: &Mapping, file: &mut MemoryFile, range: Range<usize>) -> Result<()> {
+    let mut buf = vec![0u8; min(COPY_CHUNK, range.len())];
+    let mut offset = range.start;
+    while offset < range.end {
+        let len = min(range.end - offset, buf.len());
+        let src_iovs = [RemoteIoVec {
+            base: slot.start + offset,
+            len,
+        }];
+        let read = try_with!(
+            process_vm_readv(pid, &mut [IoSliceMut::new(&mut buf[..len])], &src_iovs),
+            "cannot read guest memory at {:#x}",
+            slot.phys_addr + offset
+        );
+        if read != len {
+            bail!(
+                "short read from guest memory at {:#x}: {} of {} bytes",
+                slot.phys_addr + offset,
+                read,
+                len
+            );
+        }
+        file.write(&buf[..len], offset)?;
+        offset += len;
+    }
+    Ok(())
+}
+
+/// Copies `range` (offsets into `slot`) from `file` to the hypervisor.
+fn restore_memory(pid: Pid, slot: &Mapping, file: &File, range: Range<usize>) -> Result<()> {
+    let mut buf = vec![0u8; min(COPY_CHUNK, range.len())];
+    let mut offset = range.start;
+    while offset < range.end {
+        let len = min(range.end - offset, buf.len());
+        try_with!(
+            file.read_exact_at(&mut buf[..len], offset as u64),
+            "cannot read memory file"
+        );
+        try_with!(
+            process_write_vectored(pid, &[(slot.start + offset, &buf[..len])]),
+            "cannot write guest memory at {:#x}",
+            slot.phys_addr + offset
+        );
+        offset += len;
+    }
+    Ok(())
+}
+
+/// Model specific registers that are not part of kvm_sregs but needed to resume a running
+/// kernel (syscall entry points, per-cpu base, page attributes). The time stamp counter is
+/// left out so that guest time keeps moving forward after a restore.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const SNAPSHOT_MSRS: &[u32] = &[
+    0x174,       // MSR_IA32_SYSENTER_CS
+    0x175,       // MSR_IA32_SYSENTER_ESP
+    0x176,       // MSR_IA32_SYSENTER_EIP
+    0x277,       // MSR_IA32_CR_PAT
+    0xc000_0081, // MSR_STAR
+    0xc000_0082, // MSR_LSTAR
+    0xc000_0083, // MSR_CSTAR
+    0xc000_0084, // MSR_SYSCALL_MASK
+    0xc000_0102, // MSR_KERNEL_GS_BASE
+    0xc000_0103, // MSR_TSC_AUX
+];
+
+/// Registers of one vcpu, stored as is in `vcpu-<idx>.bin`
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct VcpuState {
+    regs: kvmb::kvm_regs,
+    sregs: kvmb::kvm_sregs,
+    fpu: kvmb::kvm_fpu,
+    /// Number of valid entries in `msrs`, MSRs the vcpu does not support are skipped
+    nmsrs: u32,
+    msrs: [kvmb::kvm_msr_entry; SNAPSHOT_MSRS.len()],
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl VcpuState {
+    fn save(vm: &Hypervisor, vcpu: &VCPU) -> Result<VcpuState> {
+        let (_, regs) = vm.vcpu_ioctl(
+            vcpu,
+            kvm::ioctls::KVM_GET_REGS(),
+            &kvmb::kvm_regs::default(),
+        )?;
+        let (_, sregs) = vm.vcpu_ioctl(
+            vcpu,
+            kvm::ioctls::KVM_GET_SREGS(),
+            &kvmb::kvm_sregs::default(),
+        )?;
+        let (_, fpu) =
+            vm.vcpu_ioctl(vcpu, kvm::ioctls::KVM_GET_FPU(), &kvmb::kvm_fpu::default())?;
+        let mut state = VcpuState {
+            regs,
+            sregs,
+            fpu,
+            nmsrs: 0,
+            msrs: [Default::default(); SNAPSHOT_MSRS.len()],
+        };
+        // one at a time: KVM_GET_MSRS stops at the first msr the vcpu does not support
+        for index in SNAPSHOT_MSRS {
+            let msrs = kvm::tracee::kvm_msrs {
+                nmsrs: 1,
+                pad: 0,
+                entries: [kvmb::kvm_msr_entry {
+                    index: *index,
+                    ..Default::default()
+                }],
+            };
+            let (n, msrs) = vm.vcpu_ioctl(vcpu, kvm::ioctls::KVM_GET_MSRS(), &msrs)?;
+            if n != 1 {
+                info!(
+                    "vcpu {} does not support msr {:#x}, skip it",
+                    vcpu.idx, index
+                );
+                continue;
+            }
+            state.msrs[state.nmsrs as usize] = msrs.entries[0];
+            state.nmsrs += 1;
+        }
+        Ok(state)
+    }
+
+    fn restore(&self, vm: &Hypervisor, vcpu: &VCPU) -> Result<()> {
+        // sregs first, the mode they set determines how the other registers are interpreted
+        vm.vcpu_ioctl(vcpu, kvm::ioctls::KVM_SET_SREGS(), &self.sregs)?;
+        vm.vcpu_ioctl(vcpu, kvm::ioctls::KVM_SET_REGS(), &self.regs)?;
+        vm.vcpu_ioctl(vcpu, kvm::ioctls::KVM_SET_FPU(), &self.fpu)?;
+        for entry in &self.msrs[..self.nmsrs as usize] {
+            let msrs = kvm::tracee::kvm_msrs {
+                nmsrs: 1,
+                pad: 0,
+                entries: [*entry],
+            };
+            let (n, _) = vm.vcpu_ioctl(vcpu, kvm::ioctls::KVM_SET_MSRS(), &msrs)?;
+            if n != 1 {
+                bail!("cannot set msr {:#x} of vcpu {}", entry.index, vcpu.idx);
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, path: &Path) -> Result<()> {
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                (self as *const VcpuState) as *const u8,
+                std::mem::size_of::<VcpuState>(),
+            )
+        };
+        try_with!(fs::write(path, bytes), "cannot write {}", path.display());
+        Ok(())
+    }
+
+    fn read(path: &Path) -> Result<VcpuState> {
+        let bytes = try_with!(fs::read(path), "cannot read {}", path.display());
+        if bytes.len() != std::mem::size_of::<VcpuState>() {
+            bail!(
+                "{} has {} bytes, expected {}: snapshot of a different vmsh version?",
+                path.display(),
+                bytes.len(),
+                std::mem::size_of::<VcpuState>()
+            );
+        }
+        let state = unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const VcpuState) };
+        if state.nmsrs as usize > SNAPSHOT_MSRS.len() {
+            bail!("{} is corrupted", path.display());
+        }
+        Ok(state)
+    }
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+struct VcpuState {}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+impl VcpuState {
+    fn save(_vm: &Hypervisor, _vcpu: &VCPU) -> Result<VcpuState> {
+        bail!("saving vcpu registers is only supported on x86")
+    }
+    fn restore(&self, _vm: &Hypervisor, _vcpu: &VCPU) -> Result<()> {
+        bail!("restoring vcpu registers is only supported on x86")
+    }
+    fn write(&self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+    fn read(_path: &Path) -> Result<VcpuState> {
+        bail!("restoring vcpu registers is only supported on x86")
+    }
+}
+
+/// Copies all memslots while the VM is running and then, stopped, the pages the guest wrote
+/// in the meantime. Leaves the VM stopped.
+fn save_memory_live(
+    vm: &Arc<Hypervisor>,
+    slots: &[Mapping],
+    files: &mut [MemoryFile],
+) -> Result<()> {
+    let ranges = slots
+        .iter()
+        .map(|s| s.phys_addr..s.phys_end())
+        .collect::<Vec<_>>();
+    let tracker = try_with!(
+        DirtyTracker::new(Arc::clone(vm), &ranges),
+        "cannot track guest writes, try again without --live"
+    );
+    vm.resume()?;
+    for (slot, file) in slots.iter().zip(files.iter_mut()) {
+        save_memory(vm.pid, slot, file, 0..slot.size())?;
+    }
+    vm.stop()?;
+
+    let dirty = try_with!(tracker.dirty_ranges(), "cannot get dirty pages");
+    let pages = dirty.iter().map(|r| r.len()).sum::<usize>() / page_size();
+    info!("copy {} pages written during the snapshot", pages);
+    for range in dirty {
+        let (slot, file) = require_with!(
+            slots
+                .iter()
+                .zip(files.iter_mut())
+                .find(|(s, _)| s.phys_addr <= range.start && range.start < s.phys_end()),
+            "dirty page at {:#x} is outside of all memslots",
+            range.start
+        );
+        save_memory(
+            vm.pid,
+            slot,
+            file,
+            range.start - slot.phys_addr..range.end - slot.phys_addr,
+        )?;
+    }
+    // disable dirty logging before the guest continues
+    drop(tracker);
+    Ok(())
+}
+
+pub fn snapshot(opts: &SnapshotOptions) -> Result<()> {
+    try_with!(
+        fs::create_dir_all(&opts.path),
+        "cannot create {}",
+        opts.path.display()
+    );
+    let vm = Arc::new(try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid, opts.vm),
+        "cannot get vms for process {}",
+        opts.pid
+    ));
+    vm.stop()?;
+    let slots = try_with!(vm.get_maps(), "cannot get memslots");
+    let base = match &opts.base {
+        Some(dir) => {
+            let dir = try_with!(fs::canonicalize(dir), "cannot find {}", dir.display());
+            let metadata = Metadata::read_base(&dir)?;
+            Some((dir, metadata))
+        }
+        None => None,
+    };
+
+    let mut files = vec![];
+    for slot in &slots {
+        let path = opts.path.join(memory_file(slot));
+        let file = try_with!(File::create(&path), "cannot create {}", path.display());
+        try_with!(
+            file.set_len(slot.size() as u64),
+            "cannot resize {}",
+            path.display()
+        );
+        let base = match &base {
+            Some((dir, metadata)) => Some(BaseMemory::new(
+                metadata.open_region(dir, slot)?,
+                slot.size(),
+            )),
+            None => None,
+        };
+        files.push(MemoryFile { file, base });
+    }
+
+    if opts.live {
+        save_memory_live(&vm, &slots, &mut files)?;
+    } else {
+        for (slot, file) in slots.iter().zip(files.iter_mut()) {
+            save_memory(vm.pid, slot, file, 0..slot.size())?;
+        }
+    }
+    let memory = slots
+        .iter()
+        .zip(&files)
+        .map(|(slot, file)| MemoryRegion {
+            phys_addr: slot.phys_addr,
+            size: slot.size(),
+            file: memory_file(slot),
+            changed: file.base.as_ref().map(BaseMemory::changed_ranges),
+        })
+        .collect();
+
+    for vcpu in &vm.vcpus {
+        let state = try_with!(
+            VcpuState::save(&vm, vcpu),
+            "cannot read registers of vcpu {}",
+            vcpu.idx
+        );
+        state.write(&opts.path.join(vcpu_file(vcpu.idx)))?;
+    }
+
+    let metadata = Metadata {
+        version: SNAPSHOT_VERSION,
+        base: base.map(|(dir, _)| dir),
+        memory,
+        vcpus: vm.vcpus.len(),
+    };
+    let path = opts.path.join(METADATA_FILE);
+    let json = try_with!(
+        serde_json::to_vec_pretty(&metadata),
+        "cannot serialize snapshot metadata"
+    );
+    try_with!(fs::write(&path, json), "cannot write {}", path.display());
+
+    vm.resume()?;
+    info!("saved snapshot to {}", opts.path.display());
+    Ok(())
+}
+
+pub fn restore(opts: &RestoreOptions) -> Result<()> {
+    let metadata = Metadata::read(&opts.path)?;
+    let base = match &metadata.base {
+        Some(dir) => Some(Metadata::read_base(dir)?),
+        None => None,
+    };
+
+    let vm = try_with!(
+        kvm::hypervisor::get_hypervisor(opts.pid, opts.vm),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    vm.check_writable("restoring a snapshot")?;
+    vm.stop()?;
+
+    // check everything before the first write, so a mismatch leaves the VM untouched
+    if vm.vcpus.len() != metadata.vcpus {
+        bail!(
+            "snapshot has {} vcpus, but the vm has {}",
+            metadata.vcpus,
+            vm.vcpus.len()
+        );
+    }
+    let slots = try_with!(vm.get_maps(), "cannot get memslots");
+    let mut regions = vec![];
+    for region in &metadata.memory {
+        let slot = require_with!(
+            slots
+                .iter()
+                .find(|s| s.phys_addr == region.phys_addr && s.size() == region.size),
+            "vm has no memslot at {:#x} with {} bytes, the memory layout changed since the snapshot",
+            region.phys_addr,
+            region.size
+        );
+        let path = opts.path.join(&region.file);
+        let file = try_with!(File::open(&path), "cannot open {}", path.display());
+        let base_file = match (&metadata.base, &base, &region.changed) {
+            (Some(dir), Some(base), Some(changed)) => Some((base.open_region(dir, slot)?, changed)),
+            (None, _, None) => None,
+            _ => bail!(
+                "{} mixes full and incremental memory",
+                opts.path.join(METADATA_FILE).display()
+            ),
+        };
+        regions.push((slot, file, base_file));
+    }
+    let states = vm
+        .vcpus
+        .iter()
+        .map(|vcpu| VcpuState::read(&opts.path.join(vcpu_file(vcpu.idx))))
+        .collect::<Result<Vec<_>>>()?;
+
+    for (slot, file, base_file) in &regions {
+        match base_file {
+            Some((base_file, changed)) => {
+                restore_memory(vm.pid, slot, base_file, 0..slot.size())?;
+                for range in changed.iter() {
+                    restore_memory(vm.pid, slot, file, range.clone())?;
+                }
+            }
+            None => restore_memory(vm.pid, slot, file, 0..slot.size())?,
+        }
+    }
+    for (vcpu, state) in vm.vcpus.iter().zip(&states) {
+        try_with!(
+            state.restore(&vm, vcpu),
+            "cannot restore registers of vcpu {}",
+            vcpu.idx
+        );
+    }
+
+    vm.resume()?;
+    info!("restored snapshot from {}", opts.path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ioutils::tmp::tempdir;
+
+    #[test]
+    fn incremental_memory() {
+        let tmp = tempdir().unwrap();
+        let page = page_size();
+        let base = tmp.path().join("base.bin");
+        fs::write(&base, vec![1u8; 4 * page]).unwrap();
+        let path = tmp.path().join("memory.bin");
+        let mut memory = MemoryFile {
+            file: File::create(&path).unwrap(),
+            base: Some(BaseMemory::new(File::open(&base).unwrap(), 4 * page)),
+        };
+        memory.file.set_len(4 * page as u64).unwrap();
+
+        let mut data = vec![1u8; 4 * page];
+        data[page] = 2;
+        data[3 * page] = 3;
+        memory.write(&data, 0).unwrap();
+        // changed back to the base after it was saved
+        memory.write(&vec![1u8; page], page).unwrap();
+
+        let changed = memory.base.as_ref().unwrap().changed_ranges();
+        assert_eq!(changed, vec![page..2 * page, 3 * page..4 * page]);
+        let saved = fs::read(&path).unwrap();
+        assert_eq!(saved[page], 1);
+        assert_eq!(saved[3 * page], 3);
+        // unchanged pages are not written
+        assert_eq!(saved[0], 0);
+    }
+}
@@ -2,9 +2,11 @@
 
 use chlorine::{c_char, c_ulonglong};
 
-/// Holds the device we create by this code, so we can unregister it later
-pub const MAX_DEVICES: usize = 3;
+/// Holds the device we create by this code, so we can unregister it later.
+/// vmsh checks the size of `VMSH_STAGE1_ARGS` in the stage1 binary, so both sides agree on it.
+pub const MAX_DEVICES: usize = 16;
 pub const MAX_ARGV: usize = 256;
+pub const MAX_STAGE2_PATHS: usize = 8;
 /// ideally we could have our own IRQ here... 6 seems so far shareable with other devices
 
 #[derive(PartialEq, Copy, Clone, Debug)]
@@ -15,6 +17,105 @@ pub enum DeviceState {
     Ready = 2,
     Terminating = 3,
     Error = 4,
+    /// Set by vmsh to unload stage1. stage1 answers with the same state once its devices
+    /// are unregistered and its work item is done, after that vmsh can remove its memory.
+    Unloading = 5,
+    /// stage2 could not be written completely to the guest, i.e. because its filesystem is
+    /// full. Set by stage1 instead of `Error`.
+    Corrupted = 6,
+}
+
+/// Release of the guest kernel, detected by vmsh from the kernel memory.
+#[derive(PartialEq, Copy, Clone, Debug)]
+#[repr(C)]
+pub struct KernelVersion {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+}
+
+impl KernelVersion {
+    /// Set if vmsh could not find the release of the kernel.
+    pub const UNKNOWN: KernelVersion = KernelVersion {
+        major: 0,
+        minor: 0,
+        patch: 0,
+    };
+
+    /// Whether the kernel is at least `major.minor`.
+    pub fn at_least(&self, major: u16, minor: u16) -> bool {
+        (self.major, self.minor) >= (major, minor)
+    }
+}
+
+/// Offsets of the members of `struct platform_device_info` stage1 sets, the others are 0.
+#[derive(PartialEq, Copy, Clone, Debug)]
+#[repr(C)]
+pub struct PlatformDeviceInfoLayout {
+    pub size: u32,
+    pub name: u32,
+    pub id: u32,
+    pub res: u32,
+    pub num_res: u32,
+}
+
+/// Offsets of the members of `struct resource` stage1 sets, the others are 0.
+#[derive(PartialEq, Copy, Clone, Debug)]
+#[repr(C)]
+pub struct ResourceLayout {
+    pub size: u32,
+    pub start: u32,
+    pub end: u32,
+    pub flags: u32,
+}
+
+/// Offsets of the members of `struct work_struct` stage1 sets.
+#[derive(PartialEq, Copy, Clone, Debug)]
+#[repr(C)]
+pub struct WorkStructLayout {
+    pub size: u32,
+    pub entry: u32,
+    pub func: u32,
+}
+
+/// Layout of the kernel structs stage1 passes to the kernel, read by vmsh from the BTF type
+/// information of the guest kernel. A `size` of 0 means that the layout is unknown, then stage1
+/// uses the layout of the kernel version.
+#[derive(PartialEq, Copy, Clone, Debug)]
+#[repr(C)]
+pub struct StructLayouts {
+    pub platform_device_info: PlatformDeviceInfoLayout,
+    pub resource: ResourceLayout,
+    pub work_struct: WorkStructLayout,
+}
+
+impl StructLayouts {
+    /// Set if the kernel has no BTF type information.
+    pub const UNKNOWN: StructLayouts = StructLayouts {
+        platform_device_info: PlatformDeviceInfoLayout {
+            size: 0,
+            name: 0,
+            id: 0,
+            res: 0,
+            num_res: 0,
+        },
+        resource: ResourceLayout {
+            size: 0,
+            start: 0,
+            end: 0,
+            flags: 0,
+        },
+        work_struct: WorkStructLayout {
+            size: 0,
+            entry: 0,
+            func: 0,
+        },
+    };
+
+    /// Whether the layouts of `struct platform_device_info` and `struct resource` are known.
+    pub fn has_platform_device(&self) -> bool {
+        self.platform_device_info.size != 0 && self.resource.size != 0
+    }
 }
 
 #[repr(C)]
@@ -24,8 +125,30 @@ pub struct Stage1Args {
     /// null terminated array
     /// the first argument is always stage2_path, the actual arguments come after
     pub argv: [*mut c_char; MAX_ARGV],
-    /// HACK we need to set IRQs depending on the hypervisor
-    pub irq_num: usize,
+    /// null terminated array of paths stage2 is written to if writing to `argv[0]` fails,
+    /// i.e. because the filesystem is read-only. stage1 replaces `argv[0]` with the path used.
+    pub stage2_fallback_paths: [*mut c_char; MAX_STAGE2_PATHS],
+    /// interrupt line of each device slot, picked from lines the guest does not use
+    pub irq_nums: [usize; MAX_DEVICES],
+    /// physical address of the PCI configuration space of our devices, 0 if the devices use
+    /// virtio-mmio. Each device has one page, function 0 only.
+    pub pci_config_addr: c_ulonglong,
+    /// physical memory window that contains the BARs of the PCI devices
+    pub pci_mem_start: c_ulonglong,
+    pub pci_mem_end: c_ulonglong,
+    /// release of the guest kernel, `KernelVersion::UNKNOWN` if vmsh could not find it
+    pub kernel_version: KernelVersion,
+    /// layout of kernel structs, `StructLayouts::UNKNOWN` if the kernel has no BTF
+    pub layouts: StructLayouts,
+    /// kernel module stage2 loads to register the devices, see `loader::module` of vmsh. 0 if
+    /// stage1 registers them itself.
+    pub module_image: c_ulonglong,
+    pub module_len: c_ulonglong,
+    /// incremented by vmsh after changing `device_addrs` while stage1 is running
+    pub device_generation: c_ulonglong,
+    /// set to `device_generation` by stage1 once the devices have been updated. Addresses of
+    /// devices that could not be registered are reset to 0.
+    pub driver_generation: c_ulonglong,
     pub device_status: DeviceState,
     pub driver_status: DeviceState,
 }
@@ -1,30 +1,37 @@
+use crate::arch::Registers;
 use crate::cpu::Regs;
 use libc::c_void;
 use log::{debug, info};
+use nix::sys::mman::ProtFlags;
 /// This module loads kernel code into the VM that we want to attach to.
 use simple_error::bail;
 use simple_error::try_with;
-use stage1_interface::DeviceState;
+use stage1_interface::{DeviceState, MAX_DEVICES};
+use std::mem::size_of;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Sender;
 use std::sync::Arc;
 use std::time::Duration;
 
+use crate::devices::virtio::pci::PciWindow;
+use crate::guest_mem::GuestMem;
+use crate::guest_wait;
 use crate::interrutable_thread::InterrutableThread;
 use crate::kernel::find_kernel;
 use crate::kvm;
 use crate::kvm::hypervisor::{memory::process_read, memory::process_write, Hypervisor};
-use crate::loader::Loader;
-use crate::page_table::VirtMem;
-use crate::result::Result;
+use crate::loader::{Binary, LoadedBinary, Loader};
+use crate::page_table::{KeptVirtMem, VirtMem};
+use crate::result::{Result, VmshError};
 
 const STAGE1_LIB: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/libstage1.so"));
 
 pub struct Stage1 {
-    #[allow(unused)]
-    virt_mem: VirtMem,
+    /// Stays in the guest when dropped, see `remove`
+    mem: KeptVirtMem,
     pub device_status: Option<DeviceStatus>,
     pub driver_status: Option<DriverStatus>,
+    pub device_slots: Option<DeviceSlots>,
     regs: Regs,
 }
 
@@ -53,51 +60,227 @@ impl DriverStatus {
     }
 }
 
+/// Location of the device addresses in stage1, used to add or remove devices while stage1 is
+/// running.
+#[derive(Clone)]
+pub struct DeviceSlots {
+    pub device_addrs: usize,
+    pub device_generation: usize,
+    pub driver_generation: usize,
+}
+
+impl DeviceSlots {
+    /// Sets the address of device `idx`, 0 removes the device. Blocks until stage1 has
+    /// registered or unregistered the device in the guest.
+    pub fn update(&self, hv: &Hypervisor, idx: usize, addr: u64) -> Result<()> {
+        if idx >= MAX_DEVICES {
+            bail!(
+                "device index {} exceeds stage1 limit of {}",
+                idx,
+                MAX_DEVICES
+            );
+        }
+        let addr_ptr = (self.device_addrs + idx * size_of::<u64>()) as *mut c_void;
+        try_with!(
+            process_write(hv.pid, addr_ptr, &addr),
+            "failed to write device address to hypervisor memory"
+        );
+        let generation: u64 = try_with!(
+            process_read(hv.pid, self.device_generation as *mut c_void),
+            "failed to read device generation from hypervisor memory"
+        );
+        let generation = generation.wrapping_add(1);
+        try_with!(
+            process_write(hv.pid, self.device_generation as *mut c_void, &generation),
+            "failed to write device generation to hypervisor memory"
+        );
+
+        // stage1 checks for device updates at least every 500ms
+        guest_wait::poll(
+            "stage1 to acknowledge the device update",
+            Duration::from_millis(10),
+            || false,
+            || {
+                let acked: u64 = try_with!(
+                    process_read(hv.pid, self.driver_generation as *mut c_void),
+                    "failed to read driver generation from hypervisor memory"
+                );
+                Ok(Some(()).filter(|_| acked == generation))
+            },
+        )?;
+
+        // stage1 resets the address if it cannot register the device
+        let current: u64 = try_with!(
+            process_read(hv.pid, addr_ptr),
+            "failed to read device address from hypervisor memory"
+        );
+        if current != addr {
+            bail!("stage1 failed to register device at {:#x}", addr);
+        }
+        Ok(())
+    }
+}
+
+/// How long vcpu 0 may run until it executes code stage1 can return to.
+const RETURN_ADDRESS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Why stage1 cannot continue at the instruction of `regs` after it ran, None if it can.
+fn return_address_problem(
+    hv: &Hypervisor,
+    guest_mem: &GuestMem,
+    regs: &Regs,
+) -> Result<Option<&'static str>> {
+    // the registers are the ones of the nested guest then
+    if hv.in_nested_guest(&hv.vcpus[0]) {
+        return Ok(Some("vcpu runs a nested guest"));
+    }
+    if regs.is_userspace() {
+        return Ok(Some("vcpu runs userspace"));
+    }
+    let ip = regs.ip() as usize;
+    let executable = guest_mem.kernel_mappings(hv)?.iter().any(|m| {
+        m.virt_start <= ip && ip - m.virt_start < m.len && m.prot.contains(ProtFlags::PROT_EXEC)
+    });
+    if !executable {
+        return Ok(Some("instruction is not mapped executable"));
+    }
+    Ok(None)
+}
+
+/// Registers of vcpu 0 when stage1 is entered. stage1 jumps back to their instruction
+/// (`VMSH_STAGE1_PC`) when it is done, so it has to be kernel code that is mapped executable in
+/// the guest page tables. If the vcpu was stopped elsewhere, i.e. in userspace or in a nested
+/// guest, the VM runs until the vcpu enters the kernel, usually for an interrupt or syscall. Needs a stopped VM
+/// and returns with it stopped.
+fn return_regs(hv: &Hypervisor, guest_mem: &GuestMem) -> Result<Regs> {
+    let mut running = false;
+    let res = guest_wait::poll_for(
+        "vcpu 0 to run kernel code",
+        RETURN_ADDRESS_TIMEOUT,
+        Duration::from_millis(1),
+        || false,
+        || {
+            if running {
+                hv.stop()?;
+                running = false;
+            }
+            let regs = try_with!(hv.get_regs(&hv.vcpus[0]), "failed to get vm registers");
+            match return_address_problem(hv, guest_mem, &regs)? {
+                None => return Ok(Some(regs)),
+                Some(problem) => debug!("cannot return to {:#x}: {}", regs.ip(), problem),
+            }
+            hv.resume()?;
+            running = true;
+            Ok(None)
+        },
+    );
+    if running {
+        hv.stop()?;
+    }
+    res
+}
+
+/// Copies stage1 (several MB with stage2) into the guest while the VM runs. The guest cannot
+/// see the memory before it is mapped into its page tables. Needs a stopped VM and returns
+/// with it stopped.
+fn upload(hv: &Hypervisor, loaded: &LoadedBinary) -> Result<()> {
+    let _span = tracing::info_span!("upload-stage1").entered();
+    hv.resume()?;
+    let res = loaded.upload(hv);
+    hv.stop()?;
+    try_with!(res, "failed to upload binary to vm");
+    Ok(())
+}
+
+/// Parses the stage1 library. Unlike loading it, this does not need a stopped VM.
+pub fn parse_binary() -> Result<Binary<'static>> {
+    Binary::parse(STAGE1_LIB)
+}
+
 impl Stage1 {
+    /// `binary` is the result of `parse_binary`.
     pub fn new(
+        binary: &Binary<'static>,
         mut allocator: kvm::PhysMemAllocator,
         command: &[String],
-        irq_num: usize,
+        stage2_fallback_paths: &[String],
+        irq_nums: &[usize],
         mmio_ranges: Vec<u64>,
+        pci_window: Option<PciWindow>,
     ) -> Result<Stage1> {
         let kernel = find_kernel(&allocator.guest_mem, &allocator.hv)?;
 
-        let mut regs = try_with!(
-            allocator.hv.get_regs(&allocator.hv.vcpus[0]),
-            "failed to get vm registers"
-        );
-
         let mut loader = try_with!(
-            Loader::new(STAGE1_LIB, &kernel, regs.ip() as usize, &mut allocator),
+            Loader::new(binary, &kernel, &mut allocator),
             "cannot load stage1"
         );
 
         let init_func = loader.init_func;
 
-        let (virt_mem, device_status, driver_status) = try_with!(
-            loader.load_binary(command, irq_num, mmio_ranges),
+        let loaded = try_with!(
+            loader.load_binary(
+                command,
+                stage2_fallback_paths,
+                irq_nums,
+                mmio_ranges,
+                pci_window
+            ),
             "cannot load stage1"
         );
+        upload(&allocator.hv, &loaded)?;
+
+        // From here on the VM stays stopped until stage1 runs.
+        let mut regs = return_regs(&allocator.hv, &allocator.guest_mem)?;
+        loaded.set_return_address(&allocator.hv, regs.ip() as usize)?;
+        try_with!(
+            allocator.guest_mem.reload_page_table(&allocator.hv),
+            "cannot read page table of vcpu 0"
+        );
+        let LoadedBinary {
+            mem,
+            device_status,
+            driver_status,
+            device_slots,
+            ..
+        } = loaded;
+        let virt_mem = try_with!(allocator.map(mem), "cannot map stage1");
 
         debug!(
             "load stage1 ({} kB) into vm at address {}",
             STAGE1_LIB.len() / 1024,
             virt_mem.mappings[0].virt_start
         );
+        let mem = virt_mem.keep();
 
-        if regs.is_userspace() {
-            bail!("vcpu was stopped in userspace. This is not supported");
-        }
         regs.set_ip(init_func as u64);
 
         Ok(Stage1 {
-            virt_mem,
+            mem,
             device_status: Some(device_status),
             driver_status: Some(driver_status),
+            device_slots: Some(device_slots),
             regs,
         })
     }
 
+    /// Leaves stage1 and the devices it registered in the guest when vmsh detaches. The
+    /// memory of stage1 stays mapped, so that another vmsh process can take over and remove
+    /// it with `VirtMem::reclaim` once stage1 has stopped.
+    pub fn keep_in_guest(self) -> KeptVirtMem {
+        self.mem
+    }
+
+    /// The memory of stage1 in the guest.
+    pub fn mem(&self) -> &KeptVirtMem {
+        &self.mem
+    }
+
+    /// Restores the guest page tables and removes the memory of stage1. stage1 must not run
+    /// anymore.
+    pub fn remove(self, hv: Arc<Hypervisor>) -> Result<()> {
+        VirtMem::reclaim(hv, &self.mem).map(drop)
+    }
+
     pub fn spawn(
         &self,
         hv: Arc<Hypervisor>,
@@ -129,27 +312,35 @@ fn stage1_thread(
     should_stop: Arc<AtomicBool>,
 ) -> Result<()> {
     let mut initialized = false;
-    loop {
-        match try_with!(driver_status.check(hv), "cannot check driver state") {
+    let res = guest_wait::poll(
+        "stage1 to start",
+        Duration::from_millis(100),
+        || should_stop.load(Ordering::Relaxed),
+        || match try_with!(driver_status.check(hv), "cannot check driver state") {
             DeviceState::Initializing => {
                 if !initialized {
                     info!("stage1 driver initializing...");
                 }
                 initialized = true;
+                Ok(None)
             }
-            DeviceState::Undefined => {}
-            DeviceState::Terminating => {
+            DeviceState::Undefined => Ok(None),
+            DeviceState::Terminating | DeviceState::Unloading => {
                 bail!("guest driver is in unexpecting terminating state");
             }
             DeviceState::Error => {
                 bail!("guest driver failed with error");
             }
-            DeviceState::Ready => break,
-        };
-        if should_stop.load(Ordering::Relaxed) {
-            break;
-        }
-        std::thread::sleep(Duration::from_millis(100));
+            DeviceState::Corrupted => {
+                bail!("stage2 binary in the guest is incomplete, is the guest filesystem full?");
+            }
+            DeviceState::Ready => Ok(Some(())),
+        },
+    );
+    match res {
+        // vmsh is stopping
+        Err(VmshError::Cancelled(_)) => return Ok(()),
+        res => res?,
     }
 
     info!("stage1 driver started");
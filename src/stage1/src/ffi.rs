@@ -51,6 +51,7 @@ pub const O_RDONLY: c_int = 0;
 pub const O_WRONLY: c_int = 1;
 pub const O_RDWR: c_int = 2;
 pub const O_CREAT: c_int = 64;
+pub const O_TRUNC: c_int = 0o1000;
 
 // kernel structures
 pub type phys_addr_t = usize;
@@ -156,6 +157,80 @@ pub struct execute_work {
 
 pub type workqueue_struct = c_void;
 
+pub type pci_bus = c_void;
+
+pub type pci_config_read_t = unsafe extern "C" fn(
+    bus: *mut pci_bus,
+    devfn: c_uint,
+    offset: c_int,
+    size: c_int,
+    val: *mut u32,
+) -> c_int;
+pub type pci_config_write_t = unsafe extern "C" fn(
+    bus: *mut pci_bus,
+    devfn: c_uint,
+    offset: c_int,
+    size: c_int,
+    val: u32,
+) -> c_int;
+
+// from the linux kernel, see `struct pci_ops`
+#[repr(C)]
+pub struct pci_ops {
+    pub add_bus: Option<unsafe extern "C" fn(bus: *mut pci_bus) -> c_int>,
+    pub remove_bus: Option<unsafe extern "C" fn(bus: *mut pci_bus)>,
+    pub map_bus: Option<
+        unsafe extern "C" fn(bus: *mut pci_bus, devfn: c_uint, offset: c_int) -> *mut c_void,
+    >,
+    pub read: Option<pci_config_read_t>,
+    pub write: Option<pci_config_write_t>,
+}
+
+// from the linux kernel, see `struct pci_sysdata` on x86
+#[repr(C)]
+pub struct pci_sysdata {
+    pub domain: c_int,
+    pub node: c_int,
+    // acpi companion, iommu, fwnode etc. depending on the kernel configuration, must be NULL
+    pub padding: [u8; 64],
+}
+
+// These functions are only present if the kernel has PCI support and are therefore looked up
+// with __symbol_get when the PCI transport is used.
+pub type pci_scan_root_bus_t = unsafe extern "C" fn(
+    parent: *mut device,
+    bus: c_int,
+    ops: *mut pci_ops,
+    sysdata: *mut c_void,
+    resources: *mut list_head,
+) -> *mut pci_bus;
+pub type pci_add_resource_t = unsafe extern "C" fn(resources: *mut list_head, res: *mut resource);
+pub type pci_free_resource_list_t = unsafe extern "C" fn(resources: *mut list_head);
+pub type pci_bus_fn_t = unsafe extern "C" fn(bus: *mut pci_bus);
+pub type ioremap_t = unsafe extern "C" fn(offset: resource_size_t, size: c_ulong) -> *mut c_void;
+pub type iounmap_t = unsafe extern "C" fn(addr: *mut c_void);
+
+#[repr(C)]
+pub struct path {
+    pub mnt: *mut c_void,
+    pub dentry: *mut c_void,
+}
+
+/// `struct umd_info` of Linux 5.9 and newer
+#[repr(C)]
+pub struct umd_info {
+    pub driver_name: *const c_char,
+    pub pipe_to_umh: *mut file,
+    pub pipe_from_umh: *mut file,
+    pub wd: path,
+    pub tgid: *mut c_void,
+}
+
+pub type umd_load_blob_t =
+    unsafe extern "C" fn(info: *mut umd_info, data: *const c_void, len: size_t) -> c_int;
+pub type umd_fn_t = unsafe extern "C" fn(info: *mut umd_info) -> c_int;
+pub type put_pid_t = unsafe extern "C" fn(pid: *mut c_void);
+
 pub unsafe fn kernel_read_4_13(
     file: *mut file,
     pos: loff_t,
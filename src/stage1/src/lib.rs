@@ -2,16 +2,24 @@
 #![allow(non_camel_case_types)]
 
 mod ffi;
+#[macro_use]
 mod printk;
+#[macro_use]
+mod symbols;
+mod pci;
+mod umd;
 
 use chlorine::c_ulong;
 use core::include_bytes;
+use core::iter;
+use core::mem::{size_of, size_of_val};
 use core::panic::PanicInfo;
 use core::ptr;
-use core::str;
 use ffi::resource;
 use ffi::ssize_t;
-use stage1_interface::{DeviceState, Stage1Args, MAX_ARGV, MAX_DEVICES};
+use stage1_interface::{
+    DeviceState, KernelVersion, Stage1Args, StructLayouts, MAX_ARGV, MAX_DEVICES, MAX_STAGE2_PATHS,
+};
 
 use chlorine::{c_char, c_int, c_long, c_uint, c_void, size_t};
 use ffi::loff_t;
@@ -27,7 +35,17 @@ const STAGE2_EXE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/stage2"));
 static mut VMSH_STAGE1_ARGS: Stage1Args = Stage1Args {
     device_addrs: [0; MAX_DEVICES],
     argv: [ptr::null_mut(); MAX_ARGV],
-    irq_num: 0,
+    stage2_fallback_paths: [ptr::null_mut(); MAX_STAGE2_PATHS],
+    irq_nums: [0; MAX_DEVICES],
+    pci_config_addr: 0,
+    pci_mem_start: 0,
+    pci_mem_end: 0,
+    kernel_version: KernelVersion::UNKNOWN,
+    layouts: StructLayouts::UNKNOWN,
+    module_image: 0,
+    module_len: 0,
+    device_generation: 0,
+    driver_generation: 0,
     device_status: DeviceState::Undefined,
     driver_status: DeviceState::Undefined,
 };
@@ -140,6 +158,57 @@ static mut INFO_5_0: ffi::platform_device_info_5_0 = ffi::platform_device_info_5
     properties: ptr::null(),
 };
 
+// `struct platform_device_info` and its resources in the layout of
+// `VMSH_STAGE1_ARGS.layouts`, u64 to align them like the kernel structs
+static mut INFO_BUF: [u64; 32] = [0; 32];
+static mut RESOURCES_BUF: [u64; 32] = [0; 32];
+
+/// Writes `value` at `base + offset` of `buf`, fails if it does not fit.
+unsafe fn put<T>(buf: &mut [u64], base: u32, offset: u32, value: T) -> Result<(), ()> {
+    let start = base.checked_add(offset).ok_or(())? as usize;
+    let end = start.checked_add(size_of::<T>()).ok_or(())?;
+    if end > size_of_val(buf) {
+        return Err(());
+    }
+    ptr::write_unaligned((buf.as_mut_ptr() as *mut u8).add(start) as *mut T, value);
+    Ok(())
+}
+
+/// Fills `INFO_BUF` and `RESOURCES_BUF` using the struct layouts vmsh read from the kernel.
+unsafe fn fill_info_buf(id: c_int, base: usize, size: usize, irq: usize) -> Result<(), ()> {
+    let info = VMSH_STAGE1_ARGS.layouts.platform_device_info;
+    let res = VMSH_STAGE1_ARGS.layouts.resource;
+    INFO_BUF = [0; 32];
+    RESOURCES_BUF = [0; 32];
+    if info.size as usize > size_of_val(&INFO_BUF) {
+        return Err(());
+    }
+    let resources = [
+        (base, base + size - 1, ffi::IORESOURCE_MEM),
+        (irq, irq, ffi::IORESOURCE_IRQ),
+    ];
+    let mut offset = 0;
+    for (start, end, flags) in resources.iter() {
+        put(&mut RESOURCES_BUF, offset, res.start, *start)?;
+        put(&mut RESOURCES_BUF, offset, res.end, *end)?;
+        put(&mut RESOURCES_BUF, offset, res.flags, *flags)?;
+        offset = offset.checked_add(res.size).ok_or(())?;
+    }
+    if offset as usize > size_of_val(&RESOURCES_BUF) {
+        return Err(());
+    }
+    let name = MMIO_DRIVER_NAME.as_ptr() as *const c_char;
+    put(&mut INFO_BUF, 0, info.name, name)?;
+    put(&mut INFO_BUF, 0, info.id, id)?;
+    put(
+        &mut INFO_BUF,
+        0,
+        info.res,
+        RESOURCES_BUF.as_ptr() as *const resource,
+    )?;
+    put(&mut INFO_BUF, 0, info.num_res, resources.len() as c_uint)
+}
+
 unsafe fn register_virtio_mmio(
     id: c_int,
     base: usize,
@@ -153,9 +222,15 @@ unsafe fn register_virtio_mmio(
     RESOURCES[1].start = irq;
     RESOURCES[1].end = irq;
 
-    let dev = if version.major < 5 || version.major == 5 && version.minor == 0 {
+    let dev = if VMSH_STAGE1_ARGS.layouts.has_platform_device() {
+        if fill_info_buf(id, base, size, irq).is_err() {
+            printkln!("stage1: platform_device_info of the kernel is too large");
+            return Err(-ffi::EINVAL);
+        }
+        ffi::platform_device_register_full(INFO_BUF.as_ptr() as *const ffi::platform_device_info)
+    } else if !version.at_least(5, 1) {
         INFO_5_0.id = id;
-        if version.major < 4 || version.major == 4 && version.minor < 5 {
+        if !version.at_least(4, 5) {
             RESOURCES_4_4[0].start = RESOURCES[0].start;
             RESOURCES_4_4[0].end = RESOURCES[0].end;
             RESOURCES_4_4[1].start = RESOURCES[1].start;
@@ -276,130 +351,186 @@ impl Drop for KFile {
     }
 }
 
-struct KernelVersion {
-    major: u16,
-    minor: u16,
-    patch: u16,
+/// Release of the kernel as vmsh found it in the kernel memory.
+unsafe fn get_kernel_version() -> KernelVersion {
+    let v = VMSH_STAGE1_ARGS.kernel_version;
+    if v == KernelVersion::UNKNOWN {
+        printkln!("stage1: warning: vmsh did not find the kernel version, assume 5.5");
+        return KernelVersion {
+            major: 5,
+            minor: 5,
+            patch: 0,
+        };
+    }
+    printkln!(
+        "stage1: linux version %u.%u.%u",
+        v.major as c_uint,
+        v.minor as c_uint,
+        v.patch as c_uint
+    );
+    v
+}
+
+fn usleep_range(min: c_ulong, max: c_ulong) {
+    unsafe {
+        ffi::usleep_range_state(min, max, ffi::TASK_UNINTERRUPTIBLE);
+    }
+}
+
+// cannot put this onto the stack without stackoverflows?
+const NO_DEVICE: Option<PlatformDevice> = None;
+static mut DEVICES: [Option<PlatformDevice>; MAX_DEVICES] = [NO_DEVICE; MAX_DEVICES];
+
+unsafe fn unregister_devices() {
+    DEVICES.iter_mut().for_each(|d| {
+        d.take();
+    });
+    pci::unregister();
 }
 
-static mut BUF: [u8; 256] = [0; 256];
+/// Registers devices whose address was set and unregisters devices whose address was cleared
+/// by vmsh since the last call.
+unsafe fn update_devices(version: &KernelVersion) -> Result<(), ()> {
+    let mut res = Ok(());
+    let devices = VMSH_STAGE1_ARGS
+        .device_addrs
+        .iter_mut()
+        .zip(DEVICES.iter_mut())
+        .enumerate();
+    for (i, (addr, dev)) in devices {
+        let base = ptr::read_volatile(addr);
+        if base == 0 {
+            if dev.take().is_some() {
+                printkln!("stage1: removed dev %d", i as c_int);
+            }
+            continue;
+        }
+        if dev.is_some() {
+            continue;
+        }
+        printkln!("stage1: init dev at 0x%llx", base);
+        match register_virtio_mmio(
+            MMIO_DEVICE_ID + (i as i32),
+            base as usize,
+            MMIO_SIZE,
+            VMSH_STAGE1_ARGS.irq_nums[i],
+            version,
+        ) {
+            Ok(v) => *dev = Some(v),
+            Err(e) => {
+                printkln!("stage1: failed to register mmio device: errno=%d", e);
+                ptr::write_volatile(addr, 0);
+                res = Err(());
+            }
+        }
+    }
+    res
+}
 
-fn parse_version_part(split: Option<&[u8]>, full_version: &str) -> Result<u16, ()> {
-    if let Some(number) = split {
-        if let Ok(num_str) = str::from_utf8(number) {
-            if let Ok(num) = num_str.parse::<u16>() {
-                return Ok(num);
+/// Creates the file for stage2 at `argv[0]` or the first fallback path that works, i.e. if
+/// /dev does not exist or the root filesystem is read-only. `argv[0]` is updated to the path
+/// used, stage2 removes itself from there. A stale stage2 left at the path is truncated, so that
+/// `verify_stage2` does not see its trailing bytes.
+unsafe fn open_stage2(linux_4_13_or_older: bool) -> Result<KFile, ()> {
+    let primary = VMSH_STAGE1_ARGS.argv[0];
+    let fallbacks = VMSH_STAGE1_ARGS
+        .stage2_fallback_paths
+        .iter()
+        .take_while(|path| !path.is_null());
+    for path in iter::once(&primary).chain(fallbacks) {
+        match KFile::open(
+            *path,
+            ffi::O_WRONLY | ffi::O_CREAT | ffi::O_TRUNC,
+            0o755,
+            linux_4_13_or_older,
+        ) {
+            Ok(f) => {
+                VMSH_STAGE1_ARGS.argv[0] = *path;
+                return Ok(f);
             }
+            Err(e) => printkln!("stage1: cannot open %s: errno=%d", *path, e),
         }
     }
-    printkln!("stage1: cannot parse version %s", full_version.as_ptr());
     Err(())
 }
 
-unsafe fn get_kernel_version() -> Result<KernelVersion, ()> {
-    let path = c_str!("/proc/sys/kernel/osrelease").as_ptr() as *const i8;
-    // might fail if the older kernel version has TCP disabled...
-    let is_4_13_or_older =
-        !ffi::__symbol_get(c_str!("tcp_prequeue").as_ptr() as *const c_char).is_null();
-    printkln!(
-        "stage1: detected old linux 4.13 version: %d",
-        is_4_13_or_older as c_int
-    );
-    let mut file = match KFile::open(path, ffi::O_RDONLY, 0, is_4_13_or_older) {
+static mut VERIFY_BUF: [u8; 4096] = [0; 4096];
+
+/// Reads stage2 back and compares it with our copy. Writes to full or broken filesystems
+/// can be truncated without returning an error, executing such a binary crashes in odd ways.
+unsafe fn verify_stage2(linux_4_13_or_older: bool) -> Result<(), ()> {
+    let mut file = match KFile::open(
+        VMSH_STAGE1_ARGS.argv[0],
+        ffi::O_RDONLY,
+        0,
+        linux_4_13_or_older,
+    ) {
         Ok(f) => f,
         Err(e) => {
             printkln!(
-                "stage1: warning: cannot open /proc/sys/kernel/osrelease: %d",
+                "stage1: cannot open %s for verification: errno=%d",
+                VMSH_STAGE1_ARGS.argv[0],
                 e
             );
-            // procfs not mounted? -> assume newer kernel
-            return Ok(KernelVersion {
-                major: 5,
-                minor: 5,
-                patch: 0,
-            });
-        }
-    };
-    // leave one byte null to make it a valid c string
-    let buf = match BUF.get_mut(0..BUF.len() - 1) {
-        Some(f) => f,
-        None => {
-            printkln!("stage1: BUG! cannot take slice of buffer");
             return Err(());
         }
     };
-    let count = match file.read_all(buf, 0) {
-        Ok(n) => n,
-        Err(e) => {
+    let mut pos: usize = 0;
+    loop {
+        let n = match file.read_all(&mut VERIFY_BUF, pos as loff_t) {
+            Ok(n) => n,
+            Err(e) => {
+                printkln!(
+                    "stage1: cannot read %s: errno=%d",
+                    VMSH_STAGE1_ARGS.argv[0],
+                    e
+                );
+                return Err(());
+            }
+        };
+        if n == 0 {
+            break;
+        }
+        if VERIFY_BUF.get(..n) != STAGE2_EXE.get(pos..pos + n) {
             printkln!(
-                "stage1: failed to read /proc/sys/kernel/osrelease: %d: %s",
-                e,
-                buf.as_ptr()
+                "stage1: %s differs from stage2 at offset %zu",
+                VMSH_STAGE1_ARGS.argv[0],
+                pos
             );
             return Err(());
         }
-    };
-    let read_buf = match BUF.get(0..count) {
-        Some(f) => f,
-        None => {
-            printkln!("stage1: kernel overflowed read buffer");
-            return Err(());
-        }
-    };
-    let version_str = match str::from_utf8(read_buf) {
-        Ok(s) => s,
-        Err(_) => {
-            printkln!("stage1: /proc/sys/kernel/osrelease is not a valid utf-8 string");
-            return Err(());
-        }
-    };
-
-    let pos = version_str.find(|c: char| c != '.' && !c.is_ascii_digit());
-    let kernel = if let Some(pos) = pos {
-        match version_str.get(..pos) {
-            Some(s) => s,
-            None => {
-                printkln!("stage1: could split off kernel version part");
-                return Err(());
-            }
-        }
-    } else {
-        version_str
-    };
-    let mut split = kernel.as_bytes().splitn(3, |c| *c == b'.');
-    let major = parse_version_part(split.next(), version_str)?;
-    let minor = parse_version_part(split.next(), version_str)?;
-    let patch = parse_version_part(split.next(), version_str)?;
-    let v = KernelVersion {
-        major,
-        minor,
-        patch,
-    };
-    printkln!(
-        "stage1: detected linux version %u.%u.%u",
-        v.major as c_uint,
-        v.minor as c_uint,
-        v.patch as c_uint
-    );
-
-    Ok(v)
-}
-
-fn usleep_range(min: c_ulong, max: c_ulong) {
-    unsafe {
-        ffi::usleep_range_state(min, max, ffi::TASK_UNINTERRUPTIBLE);
+        pos += n;
+    }
+    if pos != STAGE2_EXE.len() {
+        printkln!(
+            "stage1: %s is truncated (%zu != %zu)",
+            VMSH_STAGE1_ARGS.argv[0],
+            pos,
+            STAGE2_EXE.len()
+        );
+        return Err(());
     }
+    Ok(())
 }
 
-// cannot put this onto the stack without stackoverflows?
-static mut DEVICES: [Option<PlatformDevice>; MAX_DEVICES] = [None, None, None];
-
-unsafe fn run_stage2() -> Result<(), ()> {
-    let version = get_kernel_version()?;
-
-    if VMSH_STAGE1_ARGS.irq_num == 0 {
-        printkln!("stage1: no irq number set in stage1 args");
-        return Err(());
+/// Registers the pci bus or the mmio devices vmsh set up.
+unsafe fn register_devices(version: &KernelVersion) -> Result<(), ()> {
+    if VMSH_STAGE1_ARGS.pci_config_addr != 0 {
+        printkln!(
+            "stage1: init pci bus at 0x%llx",
+            VMSH_STAGE1_ARGS.pci_config_addr
+        );
+        if pci::register(
+            VMSH_STAGE1_ARGS.pci_config_addr as usize,
+            VMSH_STAGE1_ARGS.pci_mem_start as usize,
+            VMSH_STAGE1_ARGS.pci_mem_end as usize,
+        )
+        .is_err()
+        {
+            printkln!("stage1: failed to register pci devices");
+            return Err(());
+        }
+        return Ok(());
     }
 
     for (i, addr) in VMSH_STAGE1_ARGS.device_addrs.iter().enumerate() {
@@ -411,8 +542,8 @@ unsafe fn run_stage2() -> Result<(), ()> {
             MMIO_DEVICE_ID + (i as i32),
             *addr as usize,
             MMIO_SIZE,
-            VMSH_STAGE1_ARGS.irq_num,
-            &version,
+            VMSH_STAGE1_ARGS.irq_nums[i],
+            version,
         ) {
             Ok(v) => {
                 if let Some(elem) = DEVICES.get_mut(i) {
@@ -431,44 +562,82 @@ unsafe fn run_stage2() -> Result<(), ()> {
             }
         };
     }
+    Ok(())
+}
+
+/// Release of the kernel for `vmsh_module_init`, set before stage2 loads the module.
+static mut KERNEL_VERSION: KernelVersion = KernelVersion::UNKNOWN;
+
+/// Init function of the module vmsh built (see `loader::module` of vmsh). The module loader
+/// of the guest calls it once stage2 loaded the module with init_module.
+#[no_mangle]
+pub extern "C" fn vmsh_module_init() -> c_int {
+    printkln!("stage1: register devices from the module loader");
+    unsafe {
+        if register_devices(&KERNEL_VERSION).is_err() {
+            // stage1 tries again without the module loader
+            unregister_devices();
+            return -ffi::ENODEV;
+        }
+    }
+    0
+}
+
+/// The module that registers the devices, empty if vmsh did not build one.
+unsafe fn module_image() -> &'static [u8] {
+    let image = VMSH_STAGE1_ARGS.module_image as *const u8;
+    if image.is_null() {
+        return &[];
+    }
+    core::slice::from_raw_parts(image, VMSH_STAGE1_ARGS.module_len as usize)
+}
+
+unsafe fn run_stage2() -> Result<KernelVersion, DeviceState> {
+    let version = get_kernel_version();
+    KERNEL_VERSION = version;
+
+    if VMSH_STAGE1_ARGS.irq_nums[0] == 0 {
+        printkln!("stage1: no irq number set in stage1 args");
+        return Err(DeviceState::Error);
+    }
+
+    // stage2 loads the module, which registers the devices, so it has to run from memory
+    let module = module_image();
+    if module.is_empty() || !version.at_least(5, 9) {
+        register_devices(&version).map_err(|()| DeviceState::Error)?;
+    }
+
+    // preferred, nothing is written to the filesystems of the guest
+    if version.at_least(5, 9) {
+        let args = VMSH_STAGE1_ARGS.argv.get(1..).unwrap_or(&[]);
+        let loaded = |errno: c_int| {
+            if errno == 0 {
+                return Ok(());
+            }
+            printkln!(
+                "stage1: guest did not load the module: errno=%d, register devices directly",
+                errno
+            );
+            register_devices(&version)
+        };
+        match umd::spawn(STAGE2_EXE, args, module, loaded) {
+            umd::Spawn::Started => return Ok(version),
+            umd::Spawn::Failed => return Err(DeviceState::Error),
+            umd::Spawn::Unsupported => {
+                printkln!("stage1: cannot run stage2 from memory, write it to the filesystem")
+            }
+        }
+        if !module.is_empty() {
+            register_devices(&version).map_err(|()| DeviceState::Error)?;
+        }
+    }
 
     // we never delete this file, however deleting files is complex and requires accessing
     // internal structs that might change.
-    let linux_4_13_or_older = version.major < 4 || version.major == 4 && version.minor < 13;
-    let mut file = match KFile::open(
-        VMSH_STAGE1_ARGS.argv[0],
-        ffi::O_WRONLY | ffi::O_CREAT,
-        0o755,
-        linux_4_13_or_older,
-    ) {
+    let linux_4_13_or_older = !version.at_least(4, 13);
+    let mut file = match open_stage2(linux_4_13_or_older) {
         Ok(f) => f,
-        Err(e) => {
-            if e == -ffi::ENOENT {
-                // /dev/ does not exists, let's try /
-                match KFile::open(
-                    c_str!("/.vmsh").as_ptr() as *const c_char,
-                    ffi::O_WRONLY | ffi::O_CREAT,
-                    0o755,
-                    linux_4_13_or_older,
-                ) {
-                    Ok(f) => {
-                        VMSH_STAGE1_ARGS.argv[0] = c_str!("/.vmsh").as_ptr() as *mut c_char;
-                        f
-                    }
-                    Err(e) => {
-                        printkln!("stage1: cannot open /.vmsh: errno=%d", e);
-                        return Err(());
-                    }
-                }
-            } else {
-                printkln!(
-                    "stage1: cannot open %s: errno=%d",
-                    VMSH_STAGE1_ARGS.argv[0],
-                    e
-                );
-                return Err(());
-            }
-        }
+        Err(()) => return Err(DeviceState::Error),
     };
     match file.write_all(STAGE2_EXE, 0) {
         Ok(n) => {
@@ -479,7 +648,7 @@ unsafe fn run_stage2() -> Result<(), ()> {
                     n,
                     STAGE2_EXE.len()
                 );
-                return Err(());
+                return Err(DeviceState::Corrupted);
             }
         }
         Err(res) => {
@@ -488,11 +657,19 @@ unsafe fn run_stage2() -> Result<(), ()> {
                 VMSH_STAGE1_ARGS.argv[0],
                 res
             );
-            return Err(());
+            return Err(if res == ffi::ENOSPC {
+                DeviceState::Corrupted
+            } else {
+                DeviceState::Error
+            });
         }
     }
     drop(file);
 
+    if verify_stage2(linux_4_13_or_older).is_err() {
+        return Err(DeviceState::Corrupted);
+    }
+
     let mut envp: [*mut c_char; 1] = [ptr::null_mut()];
 
     loop {
@@ -511,9 +688,9 @@ unsafe fn run_stage2() -> Result<(), ()> {
         }
         if res != 0 {
             printkln!("stage1: failed to spawn stage2: errno=%d", res);
-            return Err(());
+            return Err(DeviceState::Error);
         }
-        return Ok(());
+        return Ok(version);
     }
 }
 
@@ -549,32 +726,43 @@ unsafe extern "C" fn spawn_stage2() {
         return;
     }
     printkln!("stage1: initializing drivers");
-    let res = run_stage2();
-    if res.is_ok() {
-        printkln!("stage1: ready");
-        VMSH_STAGE1_ARGS.driver_status = DeviceState::Ready;
-    } else {
-        printkln!("stage1: failed");
-        DEVICES.iter_mut().for_each(|d| {
-            d.take();
-        });
-        VMSH_STAGE1_ARGS.driver_status = DeviceState::Error;
-        return;
+    let version = match run_stage2() {
+        Ok(version) => {
+            printkln!("stage1: ready");
+            VMSH_STAGE1_ARGS.driver_status = DeviceState::Ready;
+            version
+        }
+        Err(state) => {
+            printkln!("stage1: failed");
+            unregister_devices();
+            VMSH_STAGE1_ARGS.driver_status = state;
+            return;
+        }
     };
 
+    // vmsh adds or removes devices at runtime by changing `device_addrs`
+    let mut generation = 0;
     while VMSH_STAGE1_ARGS.device_status == DeviceState::Ready {
+        let requested = ptr::read_volatile(&VMSH_STAGE1_ARGS.device_generation);
+        if requested != generation && VMSH_STAGE1_ARGS.pci_config_addr == 0 {
+            if update_devices(&version).is_err() {
+                printkln!("stage1: failed to update devices");
+            }
+            generation = requested;
+            ptr::write_volatile(&mut VMSH_STAGE1_ARGS.driver_generation, generation);
+        }
         usleep_range(50 * 1000, 500 * 1000);
     }
 
-    DEVICES.iter_mut().for_each(|d| {
-        d.take();
-    });
+    unregister_devices();
     VMSH_STAGE1_ARGS.driver_status = DeviceState::Terminating;
 }
 
 #[link(name = "trampoline", kind = "static")]
 extern "C" {
     pub fn _init_vmsh();
+    /// Calls `stage2_worker` and sets the driver status to `DeviceState::Unloading` after it.
+    fn _stage2_worker(work: *mut ffi::work_struct);
 }
 
 #[no_mangle]
@@ -583,10 +771,23 @@ unsafe fn linker_hack() {
     _init_vmsh();
 }
 
-extern "C" fn stage2_worker(_work: *mut ffi::work_struct) {
+/// Returns the driver status that `_stage2_worker` sets to `DeviceState::Unloading` once we
+/// returned, or null if stage1 stays in memory.
+#[no_mangle]
+extern "C" fn stage2_worker(_work: *mut ffi::work_struct) -> *mut DeviceState {
     printkln!("stage1: spawn stage2");
-    unsafe { spawn_stage2() };
-    printkln!("stage1: finished");
+    unsafe {
+        spawn_stage2();
+        if VMSH_STAGE1_ARGS.device_status != DeviceState::Unloading {
+            printkln!("stage1: finished");
+            return ptr::null_mut();
+        }
+        printkln!("stage1: unloading");
+        // the workqueue does not access the item anymore once we return
+        THREAD_SPAWN_WORK.entry.prev = &mut THREAD_SPAWN_WORK.entry;
+        THREAD_SPAWN_WORK.entry.next = &mut THREAD_SPAWN_WORK.entry;
+        &mut VMSH_STAGE1_ARGS.driver_status
+    }
 }
 
 static mut THREAD_SPAWN_WORK: ffi::work_struct = ffi::work_struct {
@@ -595,17 +796,42 @@ static mut THREAD_SPAWN_WORK: ffi::work_struct = ffi::work_struct {
         next: ptr::null_mut(),
         prev: ptr::null_mut(),
     },
-    func: stage2_worker,
+    func: _stage2_worker,
     padding: [0; 100],
 };
 
+/// Whether `ffi::work_struct` has the layout vmsh read from the kernel, if it found one.
+unsafe fn work_struct_matches() -> bool {
+    let layout = VMSH_STAGE1_ARGS.layouts.work_struct;
+    if layout.size == 0 {
+        return true;
+    }
+    let base = &THREAD_SPAWN_WORK as *const ffi::work_struct as usize;
+    let entry = &THREAD_SPAWN_WORK.entry as *const ffi::list_head as usize - base;
+    let func = &THREAD_SPAWN_WORK.func as *const _ as usize - base;
+    layout.size as usize <= size_of::<ffi::work_struct>()
+        && layout.entry as usize == entry
+        && layout.func as usize == func
+}
+
 #[no_mangle]
 fn init_vmsh() {
     printkln!("stage1: init");
     unsafe {
-        let wq: *mut *mut ffi::workqueue_struct =
-            ffi::__symbol_get(c_str!("system_wq").as_ptr() as *mut c_char)
+        if !work_struct_matches() {
+            printkln!("stage1: work_struct of the kernel has an unsupported layout");
+            VMSH_STAGE1_ARGS.driver_status = DeviceState::Error;
+            return;
+        }
+        // system_wq was renamed to system_percpu_wq in Linux 6.17, the old name is kept
+        // as a deprecated alias for now
+        let mut wq: *mut *mut ffi::workqueue_struct =
+            ffi::__symbol_get(c_str!("system_percpu_wq").as_ptr() as *mut c_char)
                 as *mut *mut ffi::workqueue_struct;
+        if wq.is_null() {
+            wq = ffi::__symbol_get(c_str!("system_wq").as_ptr() as *mut c_char)
+                as *mut *mut ffi::workqueue_struct;
+        }
         if wq.is_null() {
             printkln!("stage1: failed to get reference on system work queue (system_wq)");
             return;
@@ -0,0 +1,136 @@
+//! Registers the virtio-pci devices of vmsh on a new PCI root bus.
+//!
+//! The configuration space window has one page per slot on bus 0 and only function 0, see
+//! `devices::virtio::pci` in vmsh. All PCI functions are looked up at runtime so that stage1
+//! still loads on kernels without PCI support. Claiming the BARs requires Linux 4.9 or newer.
+//! The device interrupt is taken from the interrupt line register, which is only honoured if
+//! the firmware does not provide interrupt routing for our bus.
+
+use chlorine::{c_char, c_int, c_uint, c_ulong, c_void};
+use core::ptr;
+use stage1_interface::MAX_DEVICES;
+
+use crate::ffi;
+use crate::symbols::symbol;
+
+const PCI_SLOT_SIZE: usize = 0x1000;
+/// Domain number of our root bus, chosen to not collide with domains of the firmware.
+const VMSH_PCI_DOMAIN: c_int = 0x5653;
+const NUMA_NO_NODE: c_int = -1;
+
+static mut CONFIG_BASE: *mut u8 = ptr::null_mut();
+static mut ROOT_BUS: *mut ffi::pci_bus = ptr::null_mut();
+static mut IOUNMAP: Option<ffi::iounmap_t> = None;
+static mut STOP_ROOT_BUS: Option<ffi::pci_bus_fn_t> = None;
+static mut REMOVE_ROOT_BUS: Option<ffi::pci_bus_fn_t> = None;
+
+static mut PCI_OPS: ffi::pci_ops = ffi::pci_ops {
+    add_bus: None,
+    remove_bus: None,
+    map_bus: Some(map_bus),
+    read: None,
+    write: None,
+};
+
+// we put this in static memory to avoid stack overflows
+static mut SYSDATA: ffi::pci_sysdata = ffi::pci_sysdata {
+    domain: VMSH_PCI_DOMAIN,
+    node: NUMA_NO_NODE,
+    padding: [0; 64],
+};
+
+static PCI_WINDOW_NAME: &[u8; 9] = b"vmsh-pci\0";
+
+static mut WINDOW: ffi::resource = ffi::resource {
+    start: 0,
+    end: 0,
+    name: ptr::null(),
+    flags: ffi::IORESOURCE_MEM,
+    desc: 0,
+    parent: ptr::null_mut(),
+    sibling: ptr::null_mut(),
+    child: ptr::null_mut(),
+};
+
+static mut RESOURCES: ffi::list_head = ffi::list_head {
+    next: ptr::null_mut(),
+    prev: ptr::null_mut(),
+};
+
+unsafe extern "C" fn map_bus(_bus: *mut ffi::pci_bus, devfn: c_uint, offset: c_int) -> *mut c_void {
+    let slot = (devfn >> 3) as usize;
+    let function = devfn & 0x7;
+    if CONFIG_BASE.is_null()
+        || function != 0
+        || slot >= MAX_DEVICES
+        || offset < 0
+        || offset as usize >= PCI_SLOT_SIZE
+    {
+        return ptr::null_mut();
+    }
+    CONFIG_BASE.add(slot * PCI_SLOT_SIZE + offset as usize) as *mut c_void
+}
+
+pub unsafe fn register(config_addr: usize, mem_start: usize, mem_end: usize) -> Result<(), ()> {
+    let scan_root_bus: ffi::pci_scan_root_bus_t = symbol!("pci_scan_root_bus");
+    let add_resource: ffi::pci_add_resource_t = symbol!("pci_add_resource");
+    let free_resource_list: ffi::pci_free_resource_list_t = symbol!("pci_free_resource_list");
+    let claim_resources: ffi::pci_bus_fn_t = symbol!("pci_bus_claim_resources");
+    let add_devices: ffi::pci_bus_fn_t = symbol!("pci_bus_add_devices");
+    PCI_OPS.read = Some(symbol!("pci_generic_config_read"));
+    PCI_OPS.write = Some(symbol!("pci_generic_config_write"));
+    STOP_ROOT_BUS = Some(symbol!("pci_stop_root_bus"));
+    REMOVE_ROOT_BUS = Some(symbol!("pci_remove_root_bus"));
+    IOUNMAP = Some(symbol!("iounmap"));
+    // ioremap used to be an inline wrapper around ioremap_nocache before Linux 5.6
+    let ioremap: ffi::ioremap_t = match symbol(c_str!("ioremap")) {
+        Some(f) => f,
+        None => symbol!("ioremap_nocache"),
+    };
+
+    CONFIG_BASE = ioremap(config_addr, (MAX_DEVICES * PCI_SLOT_SIZE) as c_ulong) as *mut u8;
+    if CONFIG_BASE.is_null() {
+        printkln!("stage1: cannot map pci configuration space");
+        return Err(());
+    }
+
+    WINDOW.start = mem_start;
+    WINDOW.end = mem_end;
+    WINDOW.name = PCI_WINDOW_NAME.as_ptr() as *const c_char;
+    RESOURCES.next = &mut RESOURCES;
+    RESOURCES.prev = &mut RESOURCES;
+    add_resource(&mut RESOURCES, &mut WINDOW);
+
+    let sysdata = &mut SYSDATA as *mut ffi::pci_sysdata as *mut c_void;
+    let bus = scan_root_bus(ptr::null_mut(), 0, &mut PCI_OPS, sysdata, &mut RESOURCES);
+    if bus.is_null() {
+        printkln!("stage1: failed to scan pci root bus");
+        free_resource_list(&mut RESOURCES);
+        unregister();
+        return Err(());
+    }
+    ROOT_BUS = bus;
+    // The BARs are fixed, so we claim them instead of letting the kernel assign new addresses.
+    claim_resources(bus);
+    add_devices(bus);
+
+    Ok(())
+}
+
+pub unsafe fn unregister() {
+    if !ROOT_BUS.is_null() {
+        if let Some(stop) = STOP_ROOT_BUS {
+            stop(ROOT_BUS);
+        }
+        if let Some(remove) = REMOVE_ROOT_BUS {
+            remove(ROOT_BUS);
+        }
+        ROOT_BUS = ptr::null_mut();
+    }
+    if !CONFIG_BASE.is_null() {
+        if let Some(iounmap) = IOUNMAP {
+            iounmap(CONFIG_BASE as *mut c_void);
+        }
+        CONFIG_BASE = ptr::null_mut();
+    }
+}
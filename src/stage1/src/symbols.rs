@@ -0,0 +1,26 @@
+//! Kernel functions that are looked up at runtime, because not every kernel exports them.
+
+use chlorine::{c_char, c_void};
+use core::mem;
+
+use crate::ffi;
+
+/// Returns the kernel function `name` or None if the kernel does not export it.
+pub unsafe fn symbol<T>(name: &str) -> Option<T> {
+    let sym = ffi::__symbol_get(name.as_ptr() as *const c_char);
+    if sym.is_null() {
+        printkln!("stage1: kernel does not export %s", name.as_ptr());
+        return None;
+    }
+    Some(mem::transmute_copy::<*mut c_void, T>(&sym))
+}
+
+/// Returns the kernel function `name` or `Err(())` from the calling function.
+macro_rules! symbol {
+    ($name:expr) => {
+        match $crate::symbols::symbol(c_str!($name)) {
+            Some(f) => f,
+            None => return Err(()),
+        }
+    };
+}
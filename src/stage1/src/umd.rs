@@ -0,0 +1,180 @@
+//! Runs stage2 from memory with the usermode driver API of Linux 5.9 and newer. The kernel
+//! copies the binary to a private tmpfs mount, so nothing is written to the filesystems of
+//! the guest. The API is only built with `CONFIG_USERMODE_DRIVER`.
+
+use chlorine::{c_char, c_int, c_void};
+use core::ptr;
+
+use crate::ffi;
+use crate::symbols::symbol;
+
+/// Name stage2 is executed with, it reads its arguments from stdin when started like this.
+/// Same as `IN_MEMORY_NAME` in stage2.
+const NAME: &str = "vmsh-stage2\0";
+
+static mut INFO: ffi::umd_info = ffi::umd_info {
+    driver_name: ptr::null(),
+    pipe_to_umh: ptr::null_mut(),
+    pipe_from_umh: ptr::null_mut(),
+    wd: ffi::path {
+        mnt: ptr::null_mut(),
+        dentry: ptr::null_mut(),
+    },
+    tgid: ptr::null_mut(),
+};
+
+unsafe fn write_all(file: *mut ffi::file, mut data: &[u8]) -> Result<(), ()> {
+    let mut pos: ffi::loff_t = 0;
+    while !data.is_empty() {
+        let res = ffi::kernel_write(file, data.as_ptr() as *const c_void, data.len(), &mut pos);
+        if res == -(ffi::EINTR as ffi::ssize_t) || res == -(ffi::EAGAIN as ffi::ssize_t) {
+            continue;
+        }
+        if res <= 0 {
+            printkln!("stage1: cannot pass arguments to stage2: errno=%d", -res);
+            return Err(());
+        }
+        data = match data.get(res as usize..) {
+            Some(rest) => rest,
+            None => return Err(()),
+        };
+    }
+    Ok(())
+}
+
+/// Writes `count` as decimal digits followed by a null byte.
+unsafe fn write_count(mut count: usize) -> Result<(), ()> {
+    let mut digits = [0u8; 21];
+    let mut start = digits.len() - 1;
+    loop {
+        start = match start.checked_sub(1) {
+            Some(start) => start,
+            None => return Err(()),
+        };
+        match digits.get_mut(start) {
+            Some(d) => *d = b'0' + (count % 10) as u8,
+            None => return Err(()),
+        }
+        count /= 10;
+        if count == 0 {
+            break;
+        }
+    }
+    // the last digit stays 0 as terminator
+    write_all(INFO.pipe_to_umh, digits.get(start..).unwrap_or(&[]))
+}
+
+/// Writes the number of arguments followed by the null-terminated strings of `args`, so that
+/// stage2 notices if we fail in between.
+unsafe fn write_args(args: &[*mut c_char]) -> Result<(), ()> {
+    let args = args.iter().take_while(|arg| !arg.is_null());
+    write_count(args.clone().count())?;
+    for arg in args {
+        let mut len = 0;
+        while *arg.add(len) != 0 {
+            len += 1;
+        }
+        // including the null byte
+        write_all(
+            INFO.pipe_to_umh,
+            core::slice::from_raw_parts(*arg as *const u8, len + 1),
+        )?;
+    }
+    Ok(())
+}
+
+/// Reads the errno of init_module stage2 reports for the module, as digits followed by a null
+/// byte. -EIO if stage2 exited before.
+unsafe fn read_module_result() -> c_int {
+    let mut errno: c_int = 0;
+    let mut digits = 0;
+    let mut pos: ffi::loff_t = 0;
+    let mut byte = 0u8;
+    loop {
+        let res = ffi::kernel_read(
+            INFO.pipe_from_umh,
+            &mut byte as *mut u8 as *mut c_void,
+            1,
+            &mut pos,
+        );
+        if res == -(ffi::EINTR as ffi::ssize_t) || res == -(ffi::EAGAIN as ffi::ssize_t) {
+            continue;
+        }
+        if res != 1 {
+            break;
+        }
+        if byte == 0 {
+            return errno;
+        }
+        // errnos have at most 4 digits
+        if !byte.is_ascii_digit() || digits == 4 {
+            break;
+        }
+        errno = errno * 10 + (byte - b'0') as c_int;
+        digits += 1;
+    }
+    -ffi::EIO
+}
+
+pub enum Spawn {
+    /// The kernel cannot run usermode drivers, stage2 has to be written to a file
+    Unsupported,
+    Started,
+    /// stage2 was started but did not get its arguments or its devices, it exits on its own
+    Failed,
+}
+
+unsafe fn start(exe: &[u8]) -> Result<(), ()> {
+    let load_blob: ffi::umd_load_blob_t = symbol!("umd_load_blob");
+    let unload_blob: ffi::umd_fn_t = symbol!("umd_unload_blob");
+    let fork_driver: ffi::umd_fn_t = symbol!("fork_usermode_driver");
+
+    INFO.driver_name = NAME.as_ptr() as *const c_char;
+    let res = load_blob(&mut INFO, exe.as_ptr() as *const c_void, exe.len());
+    if res != 0 {
+        printkln!("stage1: cannot load stage2 into memory: errno=%d", res);
+        return Err(());
+    }
+    let res = fork_driver(&mut INFO);
+    // the running binary keeps the tmpfs alive
+    unload_blob(&mut INFO);
+    if res != 0 {
+        printkln!("stage1: cannot run stage2 from memory: errno=%d", res);
+        return Err(());
+    }
+    Ok(())
+}
+
+/// Starts `exe` with `args`, excluding the program name. If `module` is not empty, stage2 loads
+/// it first and `loaded` gets the errno of init_module. stage2 waits until `loaded` returned.
+pub unsafe fn spawn(
+    exe: &[u8],
+    args: &[*mut c_char],
+    module: &[u8],
+    loaded: impl FnOnce(c_int) -> Result<(), ()>,
+) -> Spawn {
+    let put_pid: ffi::put_pid_t = match symbol(c_str!("put_pid")) {
+        Some(f) => f,
+        None => return Spawn::Unsupported,
+    };
+    if start(exe).is_err() {
+        return Spawn::Unsupported;
+    }
+    let mut res = write_args(args)
+        .and_then(|()| write_count(module.len()))
+        .and_then(|()| write_all(INFO.pipe_to_umh, module));
+    if res.is_ok() && !module.is_empty() {
+        res = loaded(read_module_result());
+    }
+    // stage2 does not write to stdout before replacing it, except for the module result
+    ffi::filp_close(INFO.pipe_to_umh, ptr::null_mut());
+    ffi::filp_close(INFO.pipe_from_umh, ptr::null_mut());
+    INFO.pipe_to_umh = ptr::null_mut();
+    INFO.pipe_from_umh = ptr::null_mut();
+    put_pid(INFO.tgid);
+    INFO.tgid = ptr::null_mut();
+    match res {
+        Ok(()) => Spawn::Started,
+        Err(()) => Spawn::Failed,
+    }
+}
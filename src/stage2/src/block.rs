@@ -155,6 +155,13 @@ impl BlockDevice {
                 }
             };
         }
+        if let Some(ctx) = selinux_context {
+            bail!(
+                "could not mount image with selinux context {}. Tried the following supported filesystems: {}",
+                ctx,
+                filesystems.join(",")
+            );
+        }
         bail!(
             "could not mount image. Tried the following supported filesystems: {}",
             filesystems.join(",")
@@ -1,111 +1,132 @@
-use log::warn;
-use nix::unistd;
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::Write;
+//! Moves stage2 into the cgroups of the target process, so that the command and everything
+//! else we spawn is accounted like the rest of the workload. Handles both legacy (v1)
+//! hierarchies and the unified (v2) hierarchy.
+
+use nix::unistd::{self, Pid};
+use simple_error::{bail, try_with};
+use std::fs::{self, File};
 use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use crate::procfs;
-use crate::types::{Error, Result};
+use crate::result::Result;
 
-fn get_subsystems() -> Result<Vec<String>> {
-    let path = "/proc/cgroups";
-    let f = try_with!(File::open(&path), "failed to open /proc/cgroups");
-    let reader = BufReader::new(f);
-    let mut subsystems: Vec<String> = Vec::new();
-    for l in reader.lines() {
-        let line = try_with!(l, "failed to read /proc/cgroups");
-        if line.starts_with('#') {
-            continue;
-        }
-        let fields: Vec<&str> = line.split('\t').collect();
-        if fields.len() >= 4 && fields[3] != "0" {
-            subsystems.push(fields[0].to_string());
-        }
-    }
-    Ok(subsystems)
+/// A mounted cgroup hierarchy.
+struct Mount {
+    /// Path of the mounted cgroup within the hierarchy
+    root: PathBuf,
+    mountpoint: PathBuf,
+    /// Controllers of v1 hierarchies, i.e. `cpu` or `name=systemd`, empty for v2
+    controllers: Vec<String>,
+    unified: bool,
 }
 
-fn get_mounts() -> Result<HashMap<String, String>> {
-    let subsystems = try_with!(get_subsystems(), "failed to obtain cgroup subsystems");
+fn get_mounts() -> Result<Vec<Mount>> {
     let path = "/proc/self/mountinfo";
     // example:
     //
     // 36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue
     // (1)(2)(3)   (4)   (5)      (6)      (7)   (8) (9)   (10)         (11)
-    let f = try_with!(File::open(&path), "failed to read /proc/self/mountinfo");
+    //
+    // (7) are zero or more optional fields terminated by (8)
+    let f = try_with!(File::open(path), "failed to open {}", path);
     let reader = BufReader::new(f);
-    let mut mountpoints: HashMap<String, String> = HashMap::new();
+    let mut mounts = Vec::new();
     for l in reader.lines() {
-        let line = try_with!(l, "failed to read '{}'", path);
+        let line = try_with!(l, "failed to read {}", path);
         let fields: Vec<&str> = line.split(' ').collect();
-        if fields.len() < 11 || fields[9] != "cgroup" {
+        let separator = match fields.iter().skip(6).position(|f| *f == "-") {
+            Some(pos) => pos + 6,
+            None => continue,
+        };
+        if fields.len() < separator + 4 {
             continue;
         }
-        for option in fields[10].split(',') {
-            let name = option.strip_prefix("name=").unwrap_or(option).to_string();
-            if !subsystems.contains(&name) {
-                mountpoints.insert(name, fields[4].to_string());
-            }
-        }
+        let unified = match fields[separator + 1] {
+            "cgroup2" => true,
+            "cgroup" => false,
+            _ => continue,
+        };
+        let controllers = if unified {
+            vec![]
+        } else {
+            fields[separator + 3].split(',').map(String::from).collect()
+        };
+        mounts.push(Mount {
+            root: PathBuf::from(fields[3]),
+            mountpoint: PathBuf::from(fields[4]),
+            controllers,
+            unified,
+        });
     }
-    Ok(mountpoints)
+    Ok(mounts)
 }
 
-fn get_cgroups(pid: unistd::Pid) -> Result<Vec<String>> {
-    let path = procfs::get_path().join(format!("{}/cgroup", pid));
-    let f = try_with!(File::open(&path), "failed to read {}", path.display());
+/// Returns the controllers (empty for v2) and path of every cgroup of `pid`.
+fn get_cgroups(pid: Pid) -> Result<Vec<(String, PathBuf)>> {
+    let path = procfs::get_path().join(pid.to_string()).join("cgroup");
+    let f = try_with!(File::open(&path), "failed to open {}", path.display());
     let reader = BufReader::new(f);
-    let mut cgroups: Vec<String> = Vec::new();
+    let mut cgroups = Vec::new();
     for l in reader.lines() {
-        let line = try_with!(l, "failed to read '{}'", path.display());
-        let fields: Vec<&str> = line.split(":/").collect();
-        if fields.len() >= 2 {
-            cgroups.push(fields[1].to_string());
+        let line = try_with!(l, "failed to read {}", path.display());
+        // hierarchy-ID:controller-list:cgroup-path
+        let mut fields = line.splitn(3, ':');
+        if let (Some(_), Some(controllers), Some(cgroup)) =
+            (fields.next(), fields.next(), fields.next())
+        {
+            cgroups.push((controllers.to_string(), PathBuf::from(cgroup)));
         }
     }
     Ok(cgroups)
 }
 
-fn cgroup_path(cgroup: &str, mountpoints: &HashMap<String, String>) -> Option<PathBuf> {
-    for c in cgroup.split(',') {
-        let m = mountpoints.get(c);
-        if let Some(path) = m {
-            let mut tasks_path = PathBuf::from(path);
-            tasks_path.push(cgroup);
-            tasks_path.push("tasks");
-            return Some(tasks_path);
+fn find_mount<'a>(mounts: &'a [Mount], controllers: &str) -> Option<&'a Mount> {
+    mounts.iter().find(|m| {
+        if controllers.is_empty() {
+            return m.unified;
         }
-    }
-    None
+        !m.unified
+            && controllers
+                .split(',')
+                .all(|c| m.controllers.iter().any(|o| o == c))
+    })
 }
 
-// TODO add implementation for unified cgroups, cgmanager, lxcfs
-// -> on the long run everything will be done with unified cgroups hopefully
+/// Directory of `cgroup` below the mountpoint, None if the mount does not contain it.
+fn cgroup_dir(mount: &Mount, cgroup: &Path) -> Option<PathBuf> {
+    let relative = cgroup.strip_prefix(&mount.root).ok()?;
+    Some(mount.mountpoint.join(relative))
+}
 
-pub fn move_to(pid: unistd::Pid, target_pid: unistd::Pid) -> Result<()> {
+/// Moves us into the cgroups of `target_pid` in every mounted hierarchy. With `path`,
+/// the cgroup at this path is used in all hierarchies instead. Has to be called before
+/// joining the cgroup and mount namespace of the target and while we are single-threaded.
+pub fn enter(target_pid: Pid, path: Option<&Path>) -> Result<()> {
     let cgroups = try_with!(
         get_cgroups(target_pid),
         "failed to get cgroups of {}",
         target_pid
     );
-    let mountpoints = try_with!(get_mounts(), "failed to get cgroup mountpoints");
-    for cgroup in cgroups {
-        let p = cgroup_path(&cgroup, &mountpoints);
-        if let Some(path) = p {
-            match File::create(&path) {
-                Ok(mut buffer) => {
-                    try_with!(
-                        write!(buffer, "{}", pid),
-                        "failed to enter {} cgroup",
-                        cgroup
-                    );
-                }
-                Err(err) => {
-                    eprintln!("failed to enter {} namespace: {}", cgroup, err);
-                }
-            }
+    let mounts = try_with!(get_mounts(), "failed to get cgroup mountpoints");
+    let mut entered = 0;
+    for (controllers, cgroup) in cgroups {
+        let cgroup = path.unwrap_or(&cgroup);
+        let dir = match find_mount(&mounts, &controllers).and_then(|m| cgroup_dir(m, cgroup)) {
+            Some(dir) => dir,
+            // hierarchy is not mounted
+            None => continue,
+        };
+        let procs = dir.join("cgroup.procs");
+        match fs::write(&procs, unistd::getpid().to_string()) {
+            Ok(()) => entered += 1,
+            Err(e) => eprintln!("failed to enter cgroup {}: {}", dir.display(), e),
+        }
+    }
+    if entered == 0 {
+        match path {
+            Some(path) => bail!("cannot enter cgroup {} in any hierarchy", path.display()),
+            None => bail!("cannot enter any cgroup of {}", target_pid),
         }
     }
     Ok(())
@@ -1,22 +1,54 @@
-use nix::{self, unistd};
-use simple_error::try_with;
+use nix::unistd::{self, Gid, Group, Uid, User};
+use simple_error::{bail, try_with};
 use std::collections::HashMap;
 use std::env;
 use std::ffi::OsString;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::os::unix::ffi::OsStringExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
 use std::process::Child;
 use std::process::Command;
 
 use crate::procfs;
+use crate::pty::Pty;
 use crate::result::Result;
+use crate::seccomp::Filter;
 
 pub struct Cmd {
     environment: HashMap<OsString, OsString>,
     command: String,
     arguments: Vec<String>,
     home: Option<OsString>,
+    /// variables given by the user, they take precedence over the ones of the container
+    overrides: Vec<(OsString, OsString)>,
+    workdir: Option<PathBuf>,
+    credentials: Option<(Uid, Gid)>,
+    seccomp: Option<Filter>,
+}
+
+fn parse_uid(user: &str) -> Result<(Uid, Option<Gid>, Option<User>)> {
+    if let Ok(uid) = user.parse::<u32>() {
+        let uid = Uid::from_raw(uid);
+        let entry = try_with!(User::from_uid(uid), "cannot look up user {}", uid);
+        return Ok((uid, entry.as_ref().map(|u| u.gid), entry));
+    }
+    let entry = try_with!(User::from_name(user), "cannot look up user {}", user);
+    match entry {
+        Some(entry) => Ok((entry.uid, Some(entry.gid), Some(entry))),
+        None => bail!("no user {} in /etc/passwd", user),
+    }
+}
+
+fn parse_gid(group: &str) -> Result<Gid> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(Gid::from_raw(gid));
+    }
+    match try_with!(Group::from_name(group), "cannot look up group {}", group) {
+        Some(entry) => Ok(entry.gid),
+        None => bail!("no group {} in /etc/group", group),
+    }
 }
 
 fn read_environment(pid: unistd::Pid) -> Result<HashMap<OsString, OsString>> {
@@ -68,9 +100,60 @@ impl Cmd {
             arguments,
             home,
             environment: variables,
+            overrides: vec![],
+            workdir: None,
+            credentials: None,
+            seccomp: None,
         })
     }
-    pub fn spawn(mut self) -> Result<Child> {
+
+    /// Adds `KEY=VALUE` variables to the environment.
+    pub fn env(&mut self, vars: &[String]) -> Result<()> {
+        for var in vars {
+            match var.split_once('=') {
+                Some((key, value)) if !key.is_empty() => self
+                    .overrides
+                    .push((OsString::from(key), OsString::from(value))),
+                _ => bail!("invalid environment variable {}, expected KEY=VALUE", var),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn workdir(&mut self, dir: &Path) {
+        self.workdir = Some(dir.to_path_buf());
+    }
+
+    /// Runs the command as `user`, which is a name or uid optionally followed by `:` and a
+    /// group name or gid. Without group, the primary group of the user is used.
+    pub fn user(&mut self, user: &str) -> Result<()> {
+        let (user, group) = match user.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (user, None),
+        };
+        let (uid, primary_gid, entry) = parse_uid(user)?;
+        let gid = match (group, primary_gid) {
+            (Some(group), _) => parse_gid(group)?,
+            (None, Some(gid)) => gid,
+            (None, None) => unistd::getgid(),
+        };
+        if let Some(entry) = entry {
+            if self.home.is_none() {
+                self.home = Some(entry.dir.into_os_string());
+            }
+            self.overrides
+                .insert(0, (OsString::from("USER"), OsString::from(&entry.name)));
+        }
+        self.credentials = Some((uid, gid));
+        Ok(())
+    }
+    /// Sandboxes the command with `filter`. Only applies to `spawn`.
+    pub fn seccomp(&mut self, filter: Filter) {
+        self.seccomp = Some(filter);
+    }
+
+    /// Builds the command with the environment of the container.
+    pub fn into_command(mut self) -> Command {
         let default_path =
             OsString::from("/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin");
         self.environment.insert(
@@ -82,16 +165,34 @@ impl Cmd {
             self.environment.insert(OsString::from("HOME"), path);
         }
 
-        let child = Command::new(&self.command)
-            .args(&self.arguments)
-            .envs(self.environment)
-            .spawn();
-        Ok(try_with!(
-            child,
-            "failed to spawn {} {}",
-            self.command,
-            self.arguments.join(" ")
-        ))
+        self.environment.extend(self.overrides);
+
+        let mut cmd = Command::new(&self.command);
+        cmd.args(&self.arguments).envs(self.environment);
+        if let Some(dir) = self.workdir {
+            cmd.current_dir(dir);
+        }
+        if let Some((uid, gid)) = self.credentials {
+            cmd.uid(uid.as_raw()).gid(gid.as_raw());
+        }
+        cmd
+    }
+
+    /// Spawns the command, with `pty` as its terminal if given.
+    pub fn spawn(mut self, pty: Option<&Pty>) -> Result<Child> {
+        let description = format!("{} {}", self.command, self.arguments.join(" "));
+        let seccomp = self.seccomp.take();
+        let mut cmd = self.into_command();
+        if let Some(pty) = pty {
+            pty.set_terminal(&mut cmd)?;
+        }
+        // last, so that the filter does not need to allow our own setup
+        if let Some(filter) = seccomp {
+            unsafe {
+                cmd.pre_exec(move || filter.apply());
+            }
+        }
+        Ok(try_with!(cmd.spawn(), "failed to spawn {}", description))
     }
 
     // TODO: maybe in future
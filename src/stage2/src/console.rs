@@ -1,15 +1,28 @@
 use std::collections::BinaryHeap;
+use std::convert::TryFrom;
 use std::fs::{self, File};
-use std::os::unix::prelude::{AsRawFd, FromRawFd};
-use std::path::PathBuf;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::symlink;
+use std::os::unix::prelude::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
 
+use crate::dir::mkdir_p;
+use crate::pty::{copy_winsize, Pty};
 use crate::result::Result;
 
 use nix::errno::Errno;
 use nix::fcntl::OFlag;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::signal::{kill, SigSet, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
 use nix::sys::stat;
-use nix::{fcntl, unistd};
-use simple_error::{bail, try_with};
+use nix::sys::termios::{self, SetArg, Termios};
+use nix::unistd::Pid;
+use nix::{fcntl, mount, unistd};
+use simple_error::{bail, try_with, SimpleError};
+
+/// Passed on to the foreground job of the command, i.e. when vmsh stops stage2.
+const FORWARDED_SIGNALS: &[Signal] = &[Signal::SIGINT, Signal::SIGQUIT, Signal::SIGTERM];
 
 // Linux assigns consoles linear so later added devices get a higher number.
 // In theory just assuming vmsh is the last console added is racy however
@@ -61,3 +74,142 @@ pub fn setup() -> Result<()> {
 
     Ok(())
 }
+
+/// Guests that boot without devpts (i.e. from a minimal initramfs) cannot allocate ptys, mount
+/// an instance of our own.
+pub fn ensure_devpts() -> Result<()> {
+    if Path::new("/dev/pts/ptmx").exists() {
+        return Ok(());
+    }
+    try_with!(mkdir_p(&"/dev/pts"), "cannot create /dev/pts");
+    try_with!(
+        mount::mount(
+            Some("devpts"),
+            "/dev/pts",
+            Some("devpts"),
+            mount::MsFlags::MS_NOSUID | mount::MsFlags::MS_NOEXEC,
+            Some("newinstance,ptmxmode=0666,mode=620"),
+        ),
+        "cannot mount devpts"
+    );
+    if !Path::new("/dev/ptmx").exists() {
+        try_with!(symlink("pts/ptmx", "/dev/ptmx"), "cannot create /dev/ptmx");
+    }
+    Ok(())
+}
+
+/// Restores the terminal settings of the console when dropped.
+struct RawMode {
+    fd: RawFd,
+    orig: Termios,
+}
+
+impl RawMode {
+    fn new(fd: RawFd) -> Result<RawMode> {
+        let orig = try_with!(termios::tcgetattr(fd), "cannot get terminal attributes");
+        let mut raw = orig.clone();
+        termios::cfmakeraw(&mut raw);
+        try_with!(
+            termios::tcsetattr(fd, SetArg::TCSANOW, &raw),
+            "cannot set terminal to raw mode"
+        );
+        Ok(RawMode { fd, orig })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(self.fd, SetArg::TCSANOW, &self.orig);
+    }
+}
+
+/// Sends `signal` to the foreground job of the pty, which is `child` unless its shell started
+/// another job.
+fn signal_foreground(master: &File, child: Pid, signal: Signal) {
+    let pgrp = unistd::tcgetpgrp(master.as_raw_fd()).unwrap_or(child);
+    if let Err(e) = kill(Pid::from_raw(-pgrp.as_raw()), signal) {
+        eprintln!("cannot forward {} to process group {}: {}", signal, pgrp, e);
+    }
+}
+
+/// Forwards between the console (stdin/stdout) and the pty of `child` until the command closed
+/// it. The console becomes our controlling terminal to receive SIGWINCH, which the virtio
+/// console driver raises when vmsh reports a new size, and SIGHUP once it hangs up.
+///
+/// Returning closes the master, so on EOF or hangup of the console the kernel hangs up the pty
+/// and sends SIGHUP and SIGCONT to the session of the command, stopped jobs included.
+pub fn forward(pty: Pty, child: Pid) -> Result<()> {
+    let mut master = pty.into_master();
+    let console = libc::STDIN_FILENO;
+
+    let mut mask = SigSet::empty();
+    for signal in FORWARDED_SIGNALS {
+        mask.add(*signal);
+    }
+    // fails with EPERM if we are a process group leader already, TIOCSCTTY tells us
+    // whether it worked
+    let _ = unistd::setsid();
+    if unsafe { libc::ioctl(console, libc::TIOCSCTTY, 0) } == 0 {
+        mask.add(Signal::SIGWINCH);
+        mask.add(Signal::SIGHUP);
+    } else {
+        eprintln!("cannot make the console our controlling terminal, resizes are not forwarded");
+    }
+    try_with!(mask.thread_block(), "cannot block forwarded signals");
+    let mut signals = try_with!(
+        SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK),
+        "cannot create signalfd"
+    );
+    let _raw = RawMode::new(console)?;
+    copy_winsize(console, master.as_raw_fd());
+
+    let mut stdout = io::stdout();
+    let mut buf = [0u8; 4096];
+    loop {
+        let mut fds = [
+            PollFd::new(console, PollFlags::POLLIN),
+            PollFd::new(master.as_raw_fd(), PollFlags::POLLIN),
+            PollFd::new(signals.as_raw_fd(), PollFlags::POLLIN),
+        ];
+        match poll(&mut fds, -1) {
+            Err(Errno::EINTR) => continue,
+            res => {
+                try_with!(res, "poll failed");
+            }
+        }
+        let revents = |i: usize| fds[i].revents().unwrap_or_else(PollFlags::empty);
+
+        if !revents(2).is_empty() {
+            while let Ok(Some(info)) = signals.read_signal() {
+                match Signal::try_from(info.ssi_signo as i32) {
+                    Ok(Signal::SIGWINCH) => copy_winsize(console, master.as_raw_fd()),
+                    Ok(Signal::SIGHUP) => return Ok(()),
+                    Ok(signal) => signal_foreground(&master, child, signal),
+                    Err(_) => {}
+                }
+            }
+        }
+        if revents(0).contains(PollFlags::POLLIN) {
+            match unistd::read(console, &mut buf) {
+                Ok(0) | Err(Errno::EIO) => return Ok(()),
+                Err(Errno::EAGAIN) | Err(Errno::EINTR) => {}
+                Err(e) => return Err(SimpleError::with("cannot read from console", e)),
+                Ok(n) => try_with!(master.write_all(&buf[..n]), "cannot write to pty"),
+            }
+        } else if revents(0).intersects(PollFlags::POLLHUP | PollFlags::POLLERR) {
+            return Ok(());
+        }
+        if !revents(1).is_empty() {
+            match master.read(&mut buf) {
+                // the command and all its children closed the pty
+                Ok(0) => return Ok(()),
+                Err(e) if e.raw_os_error() == Some(libc::EIO) => return Ok(()),
+                Err(e) => return Err(SimpleError::with("cannot read from pty", e)),
+                Ok(n) => {
+                    try_with!(stdout.write_all(&buf[..n]), "cannot write to console");
+                    try_with!(stdout.flush(), "cannot write to console");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! Serves `vmsh exec`, see `ioutils::exec`. Connections are handed out like for shells.
+
+use ioutils::exec::{self, Message, MAX_PAYLOAD};
+use nix::unistd::Pid;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{ChildStdin, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::cmd::Cmd;
+use crate::result::Result;
+use crate::vsock;
+
+/// Same as `devices::virtio::vsock::EXEC_VSOCK_PORT` in vmsh.
+const EXEC_VSOCK_PORT: u32 = 10025;
+
+fn connect() -> Result<File> {
+    vsock::connect_host(EXEC_VSOCK_PORT)
+}
+
+type Writer = Arc<Mutex<File>>;
+
+fn send(writer: &Writer, msg: &Message) -> io::Result<()> {
+    match writer.lock() {
+        Ok(mut w) => exec::write_message(&mut *w, msg),
+        Err(poisoned) => exec::write_message(&mut *poisoned.into_inner(), msg),
+    }
+}
+
+fn forward_output(
+    mut output: impl Read + Send + 'static,
+    writer: Writer,
+    wrap: fn(Vec<u8>) -> Message,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut buf = vec![0u8; MAX_PAYLOAD];
+        loop {
+            let n = match output.read(&mut buf) {
+                Ok(0) | Err(_) => return,
+                Ok(n) => n,
+            };
+            if send(&writer, &wrap(buf[..n].to_vec())).is_err() {
+                return;
+            }
+        }
+    })
+}
+
+/// Writes stdin of the client to the command until `StdinEof` or the client hung up.
+fn forward_input(mut conn: File, mut stdin: ChildStdin) {
+    // the command might exit without reading its input
+    while let Ok(Some(msg)) = exec::read_message(&mut conn) {
+        match msg {
+            Message::Stdin(data) if stdin.write_all(&data).is_err() => return,
+            Message::StdinEof => return,
+            _ => {}
+        }
+    }
+}
+
+fn run(conn: File, args: Vec<String>, target_pid: Pid, home: Option<OsString>) -> io::Result<i32> {
+    let mut args = args.into_iter();
+    let command = match args.next() {
+        Some(command) if !command.is_empty() => command,
+        _ => return Err(io::Error::other("no command given")),
+    };
+    let cmd = Cmd::new(Some(command), args.collect(), target_pid, home)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    let mut child = cmd
+        .into_command()
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let writer = Arc::new(Mutex::new(conn.try_clone()?));
+    let stdout = child
+        .stdout
+        .take()
+        .map(|out| forward_output(out, Arc::clone(&writer), Message::Stdout));
+    let stderr = child
+        .stderr
+        .take()
+        .map(|err| forward_output(err, Arc::clone(&writer), Message::Stderr));
+    if let Some(stdin) = child.stdin.take() {
+        let _ = thread::spawn(move || forward_input(conn, stdin));
+    }
+    let status = child.wait()?;
+    for output in stdout.into_iter().chain(stderr) {
+        let _ = output.join();
+    }
+    let code = exit_code(status);
+    send(&writer, &Message::Exit(code))?;
+    Ok(code)
+}
+
+/// Exit code of a command like a shell reports it: 128 + the signal number if it was killed.
+pub fn exit_code(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    }
+}
+
+fn handle(conn: File, args: Vec<String>, target_pid: Pid, home: Option<OsString>) {
+    let mut error_conn = match conn.try_clone() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("cannot clone connection: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = run(conn, args, target_pid, home) {
+        let _ = exec::write_message(&mut error_conn, &Message::Error(e.to_string()));
+    }
+}
+
+/// Serves commands in the background. Does nothing if vmsh does not listen for them.
+pub fn serve(target_pid: Pid, home: Option<OsString>) {
+    let mut conn = match connect() {
+        Ok(conn) => conn,
+        Err(_) => return,
+    };
+    let _ = thread::spawn(move || loop {
+        match exec::read_message(&mut conn) {
+            Ok(Some(Message::Command(args))) => {
+                let home = home.clone();
+                let _ = thread::spawn(move || handle(conn, args, target_pid, home));
+            }
+            // vmsh closed the idle connection
+            Ok(None) => {}
+            Ok(Some(msg)) => eprintln!("unexpected exec message: {:?}", msg),
+            Err(e) => eprintln!("cannot read exec request: {}", e),
+        }
+        conn = loop {
+            match connect() {
+                Ok(conn) => break conn,
+                Err(_) => thread::sleep(Duration::from_secs(1)),
+            }
+        };
+    });
+}
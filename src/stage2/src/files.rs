@@ -0,0 +1,87 @@
+//! Serves `vmsh cp`. Like for shells, we keep an idle connection to the host that vmsh hands
+//! to the next `vmsh cp` and open a new one once a request arrived.
+
+use ioutils::filecopy::{self, Request};
+use std::fs::{self, File, Permissions};
+use std::io::{self, Read};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use crate::result::Result;
+use crate::vsock;
+
+/// Same as `devices::virtio::vsock::FILES_VSOCK_PORT` in vmsh.
+const FILES_VSOCK_PORT: u32 = 10024;
+
+fn connect() -> Result<File> {
+    vsock::connect_host(FILES_VSOCK_PORT)
+}
+
+fn get(conn: &mut File, path: &Path) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(io::Error::other(format!(
+            "{} is not a regular file",
+            path.display()
+        )));
+    }
+    let fields = [
+        format!("{:o}", metadata.permissions().mode() & 0o7777),
+        metadata.len().to_string(),
+    ];
+    filecopy::write_ok(conn, &fields)?;
+    // the size is already sent, the client notices truncated files by the early EOF
+    io::copy(&mut (&mut file).take(metadata.len()), conn)?;
+    Ok(())
+}
+
+fn put(conn: &mut File, path: &Path, mode: u32, size: u64) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    let written = io::copy(&mut (&mut *conn).take(size), &mut file)?;
+    if written != size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("received {} of {} bytes", written, size),
+        ));
+    }
+    fs::set_permissions(path, Permissions::from_mode(mode))?;
+    filecopy::write_ok(conn, &[])
+}
+
+fn handle(mut conn: File, line: &str) {
+    let res = match Request::parse(line) {
+        Ok(Request::Get { path }) => get(&mut conn, &path),
+        Ok(Request::Put { path, mode, size }) => put(&mut conn, &path, mode, size),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = res {
+        // fails if the header of `get` was already sent, the client sees the short read then
+        let _ = filecopy::write_error(&mut conn, &e.to_string());
+    }
+}
+
+/// Serves file transfers in the background. Does nothing if vmsh does not listen for them.
+pub fn serve() {
+    let mut conn = match connect() {
+        Ok(conn) => conn,
+        Err(_) => return,
+    };
+    let _ = thread::spawn(move || loop {
+        match filecopy::read_line(&mut conn) {
+            Ok(line) => {
+                let _ = thread::spawn(move || handle(conn, &line));
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+            Err(e) => eprintln!("cannot read file transfer request: {}", e),
+        }
+        conn = loop {
+            match connect() {
+                Ok(conn) => break conn,
+                Err(_) => thread::sleep(Duration::from_secs(1)),
+            }
+        };
+    });
+}
@@ -0,0 +1,55 @@
+//! Guest side of `vmsh attach --forward`: streams multiplexed on a vsock connection to the
+//! host are connected to ports on localhost in our network namespace.
+
+use ioutils::mux::Mux;
+use simple_error::try_with;
+use std::fs::File;
+use std::net::{Ipv4Addr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use crate::result::Result;
+use crate::vsock;
+
+/// Same as `devices::virtio::vsock::FORWARD_VSOCK_PORT` in vmsh.
+const FORWARD_VSOCK_PORT: u32 = 10023;
+
+fn connect() -> Result<File> {
+    vsock::connect_host(FORWARD_VSOCK_PORT)
+}
+
+fn run(mut conn: File) -> Result<()> {
+    let writer = try_with!(conn.try_clone(), "cannot clone vsock connection");
+    let mux = Mux::new(Box::new(writer));
+    let res = mux.run(&mut conn, |port| {
+        match TcpStream::connect((Ipv4Addr::LOCALHOST, port)) {
+            Ok(stream) => Some(stream),
+            Err(e) => {
+                eprintln!("cannot forward connection to port {}: {}", port, e);
+                None
+            }
+        }
+    });
+    try_with!(res, "port forwarding failed");
+    Ok(())
+}
+
+/// Forwards ports in the background. Does nothing if vmsh was attached without `--forward`.
+pub fn serve() {
+    let mut conn = match connect() {
+        Ok(conn) => conn,
+        Err(_) => return,
+    };
+    let _ = thread::spawn(move || loop {
+        if let Err(e) = run(conn) {
+            eprintln!("{}", e);
+        }
+        // vmsh might be detached, reconnect once it is attached again
+        conn = loop {
+            match connect() {
+                Ok(conn) => break conn,
+                Err(_) => thread::sleep(Duration::from_secs(1)),
+            }
+        };
+    });
+}
@@ -1,5 +1,5 @@
 use nix::unistd::Pid;
-use simple_error::try_with;
+use simple_error::{bail, try_with};
 use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
 use std::io::BufReader;
@@ -17,16 +17,18 @@ enum LSMKind {
 }
 
 impl LSMKind {
+    /// Where the label of `pid`, or our own without pid, is read from.
     pub fn profile_path(&self, pid: Option<Pid>) -> PathBuf {
+        let process = pid.map_or(String::from("self"), |p| p.to_string());
+        procfs::get_path().join(process).join("attr/current")
+    }
+
+    /// Where we write the label to inherit. SELinux applies it on the next execve of this
+    /// thread, so it only affects the processes we spawn.
+    pub fn inherit_path(&self) -> PathBuf {
         match *self {
-            LSMKind::AppArmor => {
-                let process = pid.map_or(String::from("self"), |p| p.to_string());
-                procfs::get_path().join(process).join("attr/current")
-            }
-            LSMKind::SELinux => {
-                let process = pid.map_or(String::from("thread-self"), |p| p.to_string());
-                procfs::get_path().join(process).join("attr/exec")
-            }
+            LSMKind::AppArmor => self.profile_path(None),
+            LSMKind::SELinux => procfs::get_path().join("thread-self/attr/exec"),
         }
     }
 }
@@ -35,6 +37,7 @@ pub struct LSMProfile {
     label: String,
     kind: LSMKind,
     label_file: File,
+    label_path: PathBuf,
 }
 
 fn is_apparmor_enabled() -> Result<bool> {
@@ -73,6 +76,14 @@ fn is_selinux_enabled() -> Result<bool> {
     Ok(false)
 }
 
+/// Without selinuxfs mounted we assume enforcing mode, which only affects error messages.
+fn is_selinux_enforcing() -> bool {
+    match std::fs::read_to_string("/sys/fs/selinux/enforce") {
+        Ok(enforce) => enforce.trim() == "1",
+        Err(_) => true,
+    }
+}
+
 fn check_type() -> Result<Option<LSMKind>> {
     if try_with!(
         is_apparmor_enabled(),
@@ -102,7 +113,8 @@ fn read_proclabel(path: &Path, kind: &LSMKind) -> Result<String> {
         let fields: Vec<&str> = attr.trim_end().splitn(2, ' ').collect();
         Ok(fields[0].to_owned())
     } else {
-        Ok(attr)
+        // selinux contexts are null-terminated
+        Ok(attr.trim_end_matches(['\0', '\n']).to_owned())
     }
 }
 
@@ -127,12 +139,14 @@ pub fn read_profile(pid: Pid) -> Result<Option<LSMProfile>> {
             return Ok(None);
         }
 
-        let res = OpenOptions::new().write(true).open(&own_path);
+        let label_path = kind.inherit_path();
+        let res = OpenOptions::new().write(true).open(&label_path);
 
         return Ok(Some(LSMProfile {
             kind,
             label: target_label,
-            label_file: try_with!(res, "failed to open {}", own_path.display()),
+            label_file: try_with!(res, "failed to open {}", label_path.display()),
+            label_path,
         }));
     }
     Ok(None)
@@ -145,8 +159,22 @@ impl LSMProfile {
             LSMKind::SELinux => self.label,
         };
 
-        let res = self.label_file.write_all(attr.as_bytes());
-        try_with!(res, "failed to write '{}' to /proc/self/attr/current", attr);
+        if let Err(e) = self.label_file.write_all(attr.as_bytes()) {
+            if self.kind == LSMKind::SELinux && is_selinux_enforcing() {
+                bail!(
+                    "failed to write '{}' to {}: {}. SELinux is enforcing, the policy might not allow a transition from our context to the one of the target",
+                    attr,
+                    self.label_path.display(),
+                    e
+                );
+            }
+            bail!(
+                "failed to write '{}' to {}: {}",
+                attr,
+                self.label_path.display(),
+                e
+            );
+        }
         Ok(())
     }
 
@@ -158,7 +186,15 @@ impl LSMProfile {
                     mount_context::parse_selinux_context(pid),
                     "failed to parse selinux mount options"
                 );
-                Ok(Some(context))
+                match context {
+                    Some(context) => Ok(Some(context)),
+                    // not a container, label our files like the root directory of the target
+                    None => Ok(Some(try_with!(
+                        mount_context::root_label(pid),
+                        "failed to get selinux label of / of {}",
+                        pid
+                    ))),
+                }
             }
         }
     }
@@ -1,55 +1,210 @@
 use kmsg::kmsg_log;
+use nix::errno::Errno;
 use nix::sys::statfs::{statfs, FsType};
 use nix::unistd;
 use nix::unistd::Pid;
 use simple_error::{bail, try_with};
 use std::ffi::OsString;
 use std::fs;
+use std::io::{BufRead, Read, Write};
 use std::os::unix::fs::MetadataExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::{env, io};
 use user_namespace::IdMap;
 
-use crate::block::find_vmsh_blockdev;
 use crate::cmd::Cmd;
 use crate::dir::mkdir_p;
+use crate::pty::Pty;
 use crate::result::Result;
+use crate::rootfs::find_vmsh_rootfs;
 
 mod block;
 mod capabilities;
+mod cgroup;
 mod cmd;
 mod console;
 mod dir;
+mod exec;
+mod files;
+mod forward;
 mod kmsg;
 mod lsm;
 mod mount_context;
 mod mountns;
 mod namespace;
+mod p9;
 mod procfs;
+mod pty;
 mod result;
+mod rootfs;
+mod seccomp;
+mod shells;
+mod signals;
 mod sys_ext;
 mod user_namespace;
+mod vsock;
 
 struct Options {
+    /// where stage1 wrote our binary to, None if we run from memory
+    exe: Option<PathBuf>,
     target_pid: Pid,
     command: Option<String>,
     args: Vec<String>,
     home: Option<OsString>,
+    /// `KEY=VALUE` pairs added to the environment of the command
+    env: Vec<String>,
+    workdir: Option<PathBuf>,
+    /// user name or uid, optionally followed by `:` and group name or gid
+    user: Option<String>,
+    /// namespaces of the target process we stay out of
+    skip_namespaces: Vec<String>,
+    /// cgroup used instead of the ones of the target process
+    cgroup: Option<PathBuf>,
+    seccomp: Option<seccomp::Filter>,
 }
 
-fn cleanup_vmsh_exe() {
-    if let Err(e) = fs::remove_file("/proc/self/exe") {
-        if e.kind() != io::ErrorKind::NotFound {
-            return;
+/// Namespaces that can be skipped, the mount namespace is always joined.
+const SKIPPABLE_NAMESPACES: &[&str] = &["uts", "cgroup", "pid", "net", "ipc", "user"];
+
+fn option_value(args: &mut impl Iterator<Item = String>, name: &str) -> Result<String> {
+    match args.next() {
+        Some(value) => Ok(value),
+        None => bail!("{} requires a value", name),
+    }
+}
+
+/// Parses `[--env KEY=VALUE]... [--workdir DIR] [--user USER] [--skip-namespace NS]...
+/// [--cgroup PATH] [--seccomp FILTER] [--] [COMMAND [ARGS]...]` as passed by vmsh.
+fn parse_args(exe: Option<PathBuf>, mut args: impl Iterator<Item = String>) -> Result<Options> {
+    let mut opts = Options {
+        exe,
+        target_pid: Pid::from_raw(1),
+        command: None,
+        args: vec![],
+        home: None,
+        env: vec![],
+        workdir: None,
+        user: None,
+        skip_namespaces: vec![],
+        cgroup: None,
+        seccomp: None,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--env" => opts.env.push(option_value(&mut args, "--env")?),
+            "--workdir" => {
+                opts.workdir = Some(PathBuf::from(option_value(&mut args, "--workdir")?))
+            }
+            "--user" => opts.user = Some(option_value(&mut args, "--user")?),
+            "--skip-namespace" => {
+                let name = option_value(&mut args, "--skip-namespace")?;
+                if !SKIPPABLE_NAMESPACES.contains(&name.as_str()) {
+                    bail!("cannot skip {} namespace", name);
+                }
+                opts.skip_namespaces.push(name);
+            }
+            "--cgroup" => opts.cgroup = Some(PathBuf::from(option_value(&mut args, "--cgroup")?)),
+            "--seccomp" => {
+                let filter = option_value(&mut args, "--seccomp")?;
+                opts.seccomp = Some(seccomp::Filter::decode(&filter)?);
+            }
+            "--" => {
+                opts.command = args.next();
+                break;
+            }
+            _ => {
+                opts.command = Some(arg);
+                break;
+            }
         }
     }
-    if let Err(e) = fs::remove_file("/dev/.vmsh") {
+    opts.args = args.collect();
+    Ok(opts)
+}
+
+/// Name stage1 runs us with if we run from memory. Same as `umd::NAME` in stage1.
+const IN_MEMORY_NAME: &str = "vmsh-stage2";
+
+/// Reads a null-terminated field stage1 wrote to stdin.
+fn read_field(input: &mut impl BufRead) -> Result<Vec<u8>> {
+    let mut field = Vec::new();
+    try_with!(
+        input.read_until(0, &mut field),
+        "cannot read arguments from stdin"
+    );
+    if field.pop() != Some(0) {
+        bail!("arguments from stage1 are truncated");
+    }
+    Ok(field)
+}
+
+fn read_count(input: &mut impl BufRead) -> Result<usize> {
+    let field = read_field(input)?;
+    match String::from_utf8_lossy(&field).parse::<usize>() {
+        Ok(count) => Ok(count),
+        Err(_) => bail!("invalid count from stage1"),
+    }
+}
+
+/// Reads what stage1 passes on stdin if we run from memory: the number of arguments followed
+/// by the arguments, each terminated by a null byte, and the length of the module that
+/// registers our devices followed by the module, see `load_module`.
+fn read_stdin_args() -> Result<(Vec<String>, Vec<u8>)> {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let count = read_count(&mut input)?;
+    let args = (0..count)
+        .map(|_| read_field(&mut input).map(|arg| String::from_utf8_lossy(&arg).into_owned()))
+        .collect::<Result<Vec<_>>>()?;
+    let mut module = vec![0; read_count(&mut input)?];
+    try_with!(
+        input.read_exact(&mut module),
+        "module from stage1 is truncated"
+    );
+    Ok((args, module))
+}
+
+/// Loads the module vmsh built to register our devices and reports the errno of init_module
+/// to stage1 on stdout. stage1 registers the devices itself if it failed and closes stdin
+/// once they are registered.
+fn load_module(module: &[u8]) -> Result<()> {
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_init_module,
+            module.as_ptr(),
+            module.len(),
+            b"\0".as_ptr(),
+        )
+    };
+    let errno = if res == 0 {
+        0
+    } else {
+        let errno = Errno::last();
+        kmsg_log(&format!("[stage2] cannot load module: {}\n", errno));
+        errno as i32
+    };
+    let mut stdout = io::stdout();
+    try_with!(
+        stdout
+            .write_all(format!("{}\0", errno).as_bytes())
+            .and_then(|()| stdout.flush()),
+        "cannot report module to stage1"
+    );
+    try_with!(
+        io::copy(&mut io::stdin(), &mut io::sink()),
+        "cannot wait for stage1 to register devices"
+    );
+    Ok(())
+}
+
+/// Removes our binary, stage1 wrote it to the path it executed us from.
+fn cleanup_vmsh_exe(exe: &Path) {
+    if let Err(e) = fs::remove_file(exe) {
         if e.kind() != io::ErrorKind::NotFound {
-            return;
+            eprintln!("cannot remove {}: {}", exe.display(), e);
         }
     }
-    let _ = fs::remove_file("/.vmsh");
 }
 
 const NONE: Option<&'static [u8]> = None;
@@ -123,14 +278,21 @@ fn run_stage2(opts: &Options) -> Result<()> {
     try_with!(console::setup(), "failed to setup console");
 
     // cleanup ourself
-    cleanup_vmsh_exe();
+    if let Some(exe) = &opts.exe {
+        cleanup_vmsh_exe(exe);
+    }
 
     // make sure /proc, /dev and /sys is set up
     try_with!(ensure_procfs(), "cannot set up /proc");
     try_with!(ensure_sysfs(), "cannot set up /sys");
     try_with!(ensure_devtmpfs(), "cannot set up /dev");
+    if let Err(e) = console::ensure_devpts() {
+        eprintln!("{}", e);
+    }
+    // used if the target has no devpts we can allocate a pty from
+    let guest_pty = Pty::new().ok();
 
-    let dev = try_with!(find_vmsh_blockdev(), "cannot find block_device");
+    let dev = try_with!(find_vmsh_rootfs(), "cannot find vmsh device");
 
     let (uid_map, gid_map) = try_with!(
         IdMap::new_from_pid(opts.target_pid),
@@ -189,8 +351,9 @@ fn run_stage2(opts: &Options) -> Result<()> {
         namespace::USER,
     ];
 
+    let skipped = |kind: &namespace::Kind| opts.skip_namespaces.iter().any(|n| n == kind.name);
     for kind in other_kinds {
-        if !supported_namespaces.contains(kind.name) {
+        if !supported_namespaces.contains(kind.name) || skipped(kind) {
             continue;
         }
         if kind.is_same(opts.target_pid) {
@@ -204,10 +367,23 @@ fn run_stage2(opts: &Options) -> Result<()> {
         ));
     }
 
+    // cgroups are looked up in our own mount and cgroup namespace
+    match (
+        cgroup::enter(opts.target_pid, opts.cgroup.as_deref()),
+        &opts.cgroup,
+    ) {
+        (Err(e), Some(_)) => return Err(e),
+        (Err(e), None) => eprintln!("{}, run command outside of the cgroups of the target", e),
+        (Ok(()), _) => {}
+    }
+
     try_with!(mount_namespace.apply(), "failed to apply mount namespace");
 
     let mount_ns = mountns::setup(&dev, mount_namespace, &mount_label)?;
-    let dropped_groups = if supported_namespaces.contains(namespace::USER.name) {
+    // without the user namespace, we keep our credentials and capabilities
+    let keep_credentials = skipped(&namespace::USER);
+    let switch_user = supported_namespaces.contains(namespace::USER.name) && !keep_credentials;
+    let dropped_groups = if switch_user {
         unistd::setgroups(&[]).is_ok()
     } else {
         false
@@ -217,7 +393,7 @@ fn run_stage2(opts: &Options) -> Result<()> {
         try_with!(ns.apply(), "failed to apply namespace");
     }
 
-    if supported_namespaces.contains(namespace::USER.name) {
+    if switch_user {
         if let Err(e) = unistd::setgroups(&[]) {
             if !dropped_groups {
                 try_with!(Err(e), "could not set groups");
@@ -227,47 +403,79 @@ fn run_stage2(opts: &Options) -> Result<()> {
         try_with!(unistd::setuid(container_uid), "could not set user id");
     }
 
-    try_with!(
-        capabilities::drop(process_status.effective_capabilities),
-        "failed to apply capabilities"
-    );
+    if !keep_credentials {
+        try_with!(
+            capabilities::drop(process_status.effective_capabilities),
+            "failed to apply capabilities"
+        );
+    }
 
     if let Some(profile) = lsm_profile {
         try_with!(profile.inherit_profile(), "failed to inherit lsm profile");
     }
 
-    let cmd = Cmd::new(
+    let mut cmd = Cmd::new(
         opts.command.clone(),
         opts.args.clone(),
         opts.target_pid,
         opts.home.clone(),
     )?;
+    cmd.env(&opts.env)?;
+    if let Some(workdir) = &opts.workdir {
+        cmd.workdir(workdir);
+    }
+    if let Some(user) = &opts.user {
+        cmd.user(user)?;
+    }
+    if let Some(filter) = &opts.seccomp {
+        cmd.seccomp(filter.clone());
+    }
 
-    let mut child = cmd.spawn()?;
+    let pty = match (Pty::new(), guest_pty) {
+        (Ok(pty), _) => Some(pty),
+        (Err(e), Some(pty)) => {
+            eprintln!("{}, use a pty of the guest", e);
+            Some(pty)
+        }
+        (Err(e), None) => {
+            eprintln!("{}, run command without terminal", e);
+            None
+        }
+    };
+    let mut child = cmd.spawn(pty.as_ref())?;
     // now that we have our child, we can drop temporary mount points
 
     drop(mount_ns);
+    shells::serve(opts.target_pid, opts.home.clone());
+    forward::serve();
+    files::serve();
+    exec::serve(opts.target_pid, opts.home.clone());
+    signals::serve(Pid::from_raw(child.id() as i32));
+    if let Some(pty) = pty {
+        if let Err(e) = console::forward(pty, Pid::from_raw(child.id() as i32)) {
+            eprintln!("{}", e);
+        }
+    }
     let status = try_with!(child.wait(), "failed to wait for child process");
     eprintln!("process finished with {}", status);
+    signals::report_exit(exec::exit_code(status));
     Ok(())
 }
 
 fn main() {
     kmsg_log("[stage2] start\n");
-    let args = env::args().collect::<Vec<_>>();
-    let command = if args.len() > 2 {
-        Some(args[1].clone())
-    } else {
-        None
-    };
-    // TODO
-    let opts = Options {
-        command,
-        target_pid: Pid::from_raw(1),
-        args: args[2..].to_vec(),
-        home: None,
+    let mut args = env::args();
+    let opts = match args.next() {
+        Some(name) if name == IN_MEMORY_NAME => read_stdin_args().and_then(|(args, module)| {
+            if !module.is_empty() {
+                load_module(&module)?;
+            }
+            parse_args(None, args.into_iter())
+        }),
+        exe => parse_args(exe.map(PathBuf::from), args),
     };
-    if let Err(e) = run_stage2(&opts) {
+    let res = opts.and_then(|opts| run_stage2(&opts));
+    if let Err(e) = res {
         // print to both allocated pty and kmsg
         kmsg_log(&format!("[stage2] {}\n", e));
         eprintln!("{}", &e);
@@ -1,8 +1,10 @@
 use nix::unistd::Pid;
 use simple_error::{bail, try_with};
+use std::ffi::CString;
 use std::fs::File;
 use std::io::prelude::*;
-use std::io::BufReader;
+use std::io::{self, BufReader};
+use std::os::unix::ffi::OsStrExt;
 
 use crate::procfs;
 use crate::result::Result;
@@ -31,15 +33,46 @@ fn find_mount_options(p: Pid) -> Result<String> {
     bail!("did not find / in {}", path.display())
 }
 
-pub fn parse_selinux_context(p: Pid) -> Result<String> {
+/// Returns the `context=` mount option of /, which container engines set for the rootfs.
+pub fn parse_selinux_context(p: Pid) -> Result<Option<String>> {
     let options = try_with!(find_mount_options(p), "failed to parse mount options of /");
     let needle = "context=\"";
     if let Some(index) = options.find(needle) {
-        if let Some(context) = options[(index + needle.len())..].split('"').next() {
-            return Ok(String::from(context));
+        let rest = &options[(index + needle.len())..];
+        if let Some(end) = rest.find('"') {
+            return Ok(Some(String::from(&rest[..end])));
         } else {
             bail!("missing quotes selinux context: {}", options);
         };
     }
-    bail!("no selinux mount option found for / entry: {}", options)
+    Ok(None)
+}
+
+/// Reads the selinux label of the root directory of `p`.
+pub fn root_label(p: Pid) -> Result<String> {
+    let path = procfs::get_path().join(format!("{}/root", p));
+    let cpath = try_with!(
+        CString::new(path.as_os_str().as_bytes()),
+        "invalid path {}",
+        path.display()
+    );
+    let name = b"security.selinux\0";
+    let mut buf = [0u8; 256];
+    let len = unsafe {
+        libc::getxattr(
+            cpath.as_ptr(),
+            name.as_ptr() as *const libc::c_char,
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+        )
+    };
+    if len < 0 {
+        try_with!(
+            Err(io::Error::last_os_error()),
+            "getxattr(security.selinux) of {} failed",
+            path.display()
+        );
+    }
+    let label = String::from_utf8_lossy(&buf[..len as usize]);
+    Ok(label.trim_end_matches('\0').to_owned())
 }
@@ -14,10 +14,10 @@ use std::io;
 use std::os::unix::prelude::*;
 use std::path::PathBuf;
 
-use crate::block::BlockDevice;
 use crate::dir::mkdir_p;
 use crate::namespace::{self, MOUNT};
 use crate::result::Result;
+use crate::rootfs::RootFs;
 
 pub struct MountNamespace {
     new_namespace: namespace::Namespace,
@@ -204,7 +204,7 @@ pub fn setup_bindmounts(mounts: &[&str]) -> Result<()> {
 }
 
 pub fn setup(
-    device: &BlockDevice,
+    device: &RootFs,
     container_namespace: namespace::Namespace,
     mount_label: &Option<String>,
 ) -> Result<MountNamespace> {
@@ -0,0 +1,57 @@
+use nix::mount::{self, MsFlags};
+use simple_error::try_with;
+use std::fs;
+use std::path::Path;
+
+use crate::result::Result;
+
+/// Mount tag of the 9p device exported by vmsh
+const VMSH_MOUNT_TAG: &str = "vmsh0";
+
+/// Matches the largest msize the vmsh 9p server negotiates.
+const MSIZE: u32 = 128 * 1024;
+
+pub struct P9Share {
+    tag: String,
+}
+
+impl P9Share {
+    pub fn mount(&self, mountpoint: &Path, selinux_context: &Option<String>) -> Result<()> {
+        let mut options = format!("trans=virtio,version=9p2000.L,msize={}", MSIZE);
+        if let Some(ctx) = selinux_context {
+            options.push_str(&format!(",context=\"{}\"", ctx));
+        }
+        try_with!(
+            mount::mount(
+                Some(self.tag.as_str()),
+                mountpoint,
+                Some("9p"),
+                MsFlags::empty(),
+                Some(options.as_str()),
+            ),
+            "mount(\"{}\", \"{}\", \"9p\") failed",
+            self.tag,
+            mountpoint.display()
+        );
+        Ok(())
+    }
+}
+
+/// Returns the vmsh 9p share if the 9pnet_virtio driver has bound to it.
+pub fn find_vmsh_share() -> Option<P9Share> {
+    let dir = fs::read_dir("/sys/bus/virtio/drivers/9pnet_virtio").ok()?;
+    for entry in dir.flatten() {
+        let tag = match fs::read_to_string(entry.path().join("mount_tag")) {
+            Ok(tag) => tag,
+            // not a device
+            Err(_) => continue,
+        };
+        let tag = tag.trim_end_matches(['\n', '\0']);
+        if tag == VMSH_MOUNT_TAG {
+            return Some(P9Share {
+                tag: tag.to_string(),
+            });
+        }
+    }
+    None
+}
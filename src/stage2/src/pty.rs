@@ -0,0 +1,138 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::prelude::{AsRawFd, CommandExt, FromRawFd, RawFd};
+use std::process::{Command, Stdio};
+use std::{mem, ptr};
+
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::pty::openpty;
+use nix::sys::termios::Termios;
+use nix::unistd;
+use simple_error::{try_with, SimpleError};
+
+use crate::result::Result;
+
+/// Pseudoterminal for the command, so that it gets its own controlling terminal and the size of
+/// the vmsh console.
+pub struct Pty {
+    master: File,
+    slave: File,
+}
+
+pub fn get_winsize(fd: RawFd) -> Option<libc::winsize> {
+    let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+    match unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut ws) } {
+        0 => Some(ws),
+        _ => None,
+    }
+}
+
+/// Copies the size of the console to the pty, the kernel sends SIGWINCH to its foreground job.
+pub fn copy_winsize(from: RawFd, to: RawFd) {
+    if let Some(ws) = get_winsize(from) {
+        unsafe { libc::ioctl(to, libc::TIOCSWINSZ, &ws) };
+    }
+}
+
+const JOB_CONTROL_SIGNALS: &[libc::c_int] = &[
+    libc::SIGHUP,
+    libc::SIGINT,
+    libc::SIGQUIT,
+    libc::SIGTSTP,
+    libc::SIGTTIN,
+    libc::SIGTTOU,
+    libc::SIGCHLD,
+    libc::SIGCONT,
+];
+
+impl Pty {
+    pub fn new() -> Result<Pty> {
+        Pty::with_size(get_winsize(libc::STDIN_FILENO))
+    }
+
+    /// Like `new`, but with the given size instead of the one of the console.
+    pub fn with_size(ws: Option<libc::winsize>) -> Result<Pty> {
+        let pty = try_with!(
+            openpty(ws.as_ref(), None::<&Termios>),
+            "cannot allocate pty"
+        );
+        Ok(Pty {
+            master: unsafe { File::from_raw_fd(pty.master) },
+            slave: unsafe { File::from_raw_fd(pty.slave) },
+        })
+    }
+
+    /// Makes the pty the controlling terminal and stdio of `cmd`, which runs in a new session
+    /// as foreground process group.
+    pub fn set_terminal(&self, cmd: &mut Command) -> Result<()> {
+        let stdio = || -> Result<Stdio> {
+            Ok(Stdio::from(try_with!(
+                self.slave.try_clone(),
+                "cannot duplicate pty"
+            )))
+        };
+        cmd.stdin(stdio()?).stdout(stdio()?).stderr(stdio()?);
+        unsafe {
+            cmd.pre_exec(|| {
+                // The usermode helper that started stage2 may have left signals blocked or
+                // ignored, job control of the shell needs the defaults.
+                let mut empty: libc::sigset_t = mem::zeroed();
+                libc::sigemptyset(&mut empty);
+                libc::sigprocmask(libc::SIG_SETMASK, &empty, ptr::null_mut());
+                for sig in JOB_CONTROL_SIGNALS {
+                    libc::signal(*sig, libc::SIG_DFL);
+                }
+                unistd::setsid()?;
+                if libc::ioctl(libc::STDIN_FILENO, libc::TIOCSCTTY, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                unistd::tcsetpgrp(libc::STDIN_FILENO, unistd::getpid())?;
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// Closes our end of the slave, otherwise we never see EOF/EIO on the master.
+    pub fn into_master(self) -> File {
+        self.master
+    }
+
+    /// Forwards between `conn` and the pty until either side closed.
+    pub fn forward_stream(self, mut conn: File) -> Result<()> {
+        let Pty { mut master, slave } = self;
+        drop(slave);
+
+        let mut buf = [0u8; 4096];
+        loop {
+            let mut fds = [
+                PollFd::new(conn.as_raw_fd(), PollFlags::POLLIN),
+                PollFd::new(master.as_raw_fd(), PollFlags::POLLIN),
+            ];
+            match poll(&mut fds, -1) {
+                Err(Errno::EINTR) => continue,
+                res => {
+                    try_with!(res, "poll failed");
+                }
+            }
+            let ready = |i: usize| fds[i].revents().is_some_and(|r| !r.is_empty());
+
+            if ready(0) {
+                let n = try_with!(conn.read(&mut buf), "cannot read from connection");
+                if n == 0 {
+                    return Ok(());
+                }
+                try_with!(master.write_all(&buf[..n]), "cannot write to pty");
+            }
+            if ready(1) {
+                match master.read(&mut buf) {
+                    Ok(0) => return Ok(()),
+                    Err(e) if e.raw_os_error() == Some(libc::EIO) => return Ok(()),
+                    Err(e) => return Err(SimpleError::with("cannot read from pty", e)),
+                    Ok(n) => try_with!(conn.write_all(&buf[..n]), "cannot write to connection"),
+                }
+            }
+        }
+    }
+}
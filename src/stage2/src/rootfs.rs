@@ -0,0 +1,29 @@
+use simple_error::try_with;
+use std::path::Path;
+
+use crate::block::{find_vmsh_blockdev, BlockDevice};
+use crate::p9::{self, P9Share};
+use crate::result::Result;
+
+/// The filesystem vmsh provides, depending on the share mode chosen on the host.
+pub enum RootFs {
+    Block(BlockDevice),
+    P9(P9Share),
+}
+
+impl RootFs {
+    pub fn mount(&self, mountpoint: &Path, selinux_context: &Option<String>) -> Result<()> {
+        match self {
+            RootFs::Block(dev) => dev.mount(mountpoint, selinux_context),
+            RootFs::P9(share) => share.mount(mountpoint, selinux_context),
+        }
+    }
+}
+
+pub fn find_vmsh_rootfs() -> Result<RootFs> {
+    if let Some(share) = p9::find_vmsh_share() {
+        return Ok(RootFs::P9(share));
+    }
+    let dev = try_with!(find_vmsh_blockdev(), "cannot find block device");
+    Ok(RootFs::Block(dev))
+}
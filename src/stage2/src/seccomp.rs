@@ -0,0 +1,52 @@
+//! Installs the seccomp filter vmsh compiled from an OCI profile, see `ioutils::seccomp`.
+
+use simple_error::try_with;
+use std::io;
+
+use crate::result::Result;
+
+#[derive(Clone)]
+pub struct Filter {
+    program: Vec<libc::sock_filter>,
+}
+
+impl Filter {
+    pub fn decode(encoded: &str) -> Result<Filter> {
+        let program = try_with!(ioutils::seccomp::decode(encoded), "invalid seccomp filter");
+        Ok(Filter {
+            program: program
+                .iter()
+                .map(|ins| libc::sock_filter {
+                    code: ins.code,
+                    jt: ins.jt,
+                    jf: ins.jf,
+                    k: ins.k,
+                })
+                .collect(),
+        })
+    }
+
+    /// Applies the filter to the calling thread. Does not allocate, so it can be used
+    /// between fork and exec.
+    pub fn apply(&self) -> io::Result<()> {
+        let prog = libc::sock_fprog {
+            len: self.program.len() as libc::c_ushort,
+            filter: self.program.as_ptr() as *mut libc::sock_filter,
+        };
+        // allows unprivileged users to install the filter
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let res = unsafe {
+            libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &prog as *const libc::sock_fprog,
+            )
+        };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+//! Additional shells, requested with `vmsh control <pid> new-shell`.
+//!
+//! If vmsh forwards vsock connections, we keep one idle connection to the host on
+//! `SHELL_VSOCK_PORT`. vmsh hands it to a client and writes the terminal size as
+//! `<cols> <rows>\n`, then we start a login shell on a new pty for it and open the next idle
+//! connection.
+
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
+
+use crate::cmd::Cmd;
+use crate::pty::Pty;
+use crate::result::Result;
+use crate::vsock;
+
+/// Same as `devices::virtio::vsock::SHELL_VSOCK_PORT` in vmsh.
+const SHELL_VSOCK_PORT: u32 = 10022;
+
+fn connect() -> Result<File> {
+    vsock::connect_host(SHELL_VSOCK_PORT)
+}
+
+/// Waits for the request of vmsh. Returns None if vmsh closed the connection instead.
+fn read_size(conn: &mut File) -> Result<Option<Option<libc::winsize>>> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    // byte-wise, everything after the newline belongs to the shell
+    loop {
+        if try_with!(conn.read(&mut byte), "cannot read shell request") == 0 {
+            return Ok(None);
+        }
+        if byte[0] == b'\n' {
+            break;
+        }
+        if line.len() > 32 {
+            bail!("shell request too long");
+        }
+        line.push(byte[0]);
+    }
+    let line = String::from_utf8_lossy(&line);
+    let mut fields = line
+        .split_whitespace()
+        .map(|f| f.parse::<u16>().unwrap_or(0));
+    let ws = match (fields.next(), fields.next()) {
+        (Some(cols), Some(rows)) if cols > 0 && rows > 0 => Some(libc::winsize {
+            ws_row: rows,
+            ws_col: cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        }),
+        _ => None,
+    };
+    Ok(Some(ws))
+}
+
+fn run_shell(
+    conn: File,
+    ws: Option<libc::winsize>,
+    target_pid: Pid,
+    home: Option<OsString>,
+) -> Result<()> {
+    let cmd = Cmd::new(None, vec![], target_pid, home)?;
+    let pty = Pty::with_size(ws)?;
+    let mut child = cmd.spawn(Some(&pty))?;
+    let res = pty.forward_stream(conn);
+    try_with!(child.wait(), "failed to wait for shell");
+    res
+}
+
+/// Serves shells in the background. Does nothing if vmsh does not listen for them, i.e. if
+/// the VM has no vmsh vsock device.
+pub fn serve(target_pid: Pid, home: Option<OsString>) {
+    let mut conn = match connect() {
+        Ok(conn) => conn,
+        Err(_) => return,
+    };
+    let _ = thread::spawn(move || loop {
+        match read_size(&mut conn) {
+            Ok(Some(ws)) => {
+                let home = home.clone();
+                let _ = thread::spawn(move || {
+                    if let Err(e) = run_shell(conn, ws, target_pid, home) {
+                        eprintln!("{}", e);
+                    }
+                });
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("{}", e),
+        }
+        // vmsh might be detached, the next one will pick up the connection again
+        conn = loop {
+            match connect() {
+                Ok(conn) => break conn,
+                Err(_) => thread::sleep(Duration::from_secs(1)),
+            }
+        };
+    });
+}
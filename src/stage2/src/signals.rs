@@ -0,0 +1,106 @@
+//! Guest side of signal forwarding: vmsh writes the number of every signal it passes on as a
+//! single byte to a vsock connection, we send it to the foreground job of the command. Once the
+//! command exited, its exit code goes back to vmsh as `ioutils::exec::Message::Exit`.
+
+use ioutils::exec::{self, Message};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::Read;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::result::Result;
+use crate::vsock;
+
+/// Same as `devices::virtio::vsock::SIGNAL_VSOCK_PORT` in vmsh.
+const SIGNAL_VSOCK_PORT: u32 = 10026;
+
+/// Current connection to vmsh, the exit code is written to it.
+static HOST: Mutex<Option<File>> = Mutex::new(None);
+
+fn connect() -> Result<File> {
+    let conn = vsock::connect_host(SIGNAL_VSOCK_PORT)?;
+    if let (Ok(mut host), Ok(writer)) = (HOST.lock(), conn.try_clone()) {
+        *host = Some(writer);
+    }
+    Ok(conn)
+}
+
+/// Foreground process group of the terminal of `child` (`tpgid` in `/proc/<pid>/stat`).
+fn foreground_group(child: Pid) -> Option<Pid> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", child)).ok()?;
+    // the command name in parentheses may contain spaces
+    let fields = stat.get(stat.rfind(')')? + 1..)?;
+    // state ppid pgrp session tty_nr tpgid
+    let tpgid = fields.split_whitespace().nth(5)?.parse::<i32>().ok()?;
+    if tpgid > 0 {
+        Some(Pid::from_raw(tpgid))
+    } else {
+        None
+    }
+}
+
+fn deliver(child: Pid, signal: Signal) {
+    // SIGKILL (`--kill-on-detach`) is meant for the whole command, not only the current job
+    let target = match foreground_group(child) {
+        Some(pgrp) if signal != Signal::SIGKILL => Pid::from_raw(-pgrp.as_raw()),
+        // with a pty the command leads its own session and process group
+        _ => Pid::from_raw(-child.as_raw()),
+    };
+    if kill(target, signal).is_err() {
+        if let Err(e) = kill(child, signal) {
+            eprintln!("cannot forward {} to process {}: {}", signal, child, e);
+        }
+    }
+}
+
+fn run(mut conn: File, child: Pid) {
+    let mut buf = [0u8; 16];
+    loop {
+        let n = match conn.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => n,
+        };
+        for signo in &buf[..n] {
+            match Signal::try_from(*signo as i32) {
+                Ok(signal) => deliver(child, signal),
+                Err(_) => eprintln!("vmsh forwarded invalid signal {}", signo),
+            }
+        }
+    }
+}
+
+/// Forwards signals of vmsh to `child` in the background. Does nothing if vmsh was attached
+/// without `--vsock`.
+pub fn serve(child: Pid) {
+    let mut conn = match connect() {
+        Ok(conn) => conn,
+        Err(_) => return,
+    };
+    let _ = thread::spawn(move || loop {
+        run(conn, child);
+        // vmsh might be detached, reconnect once it is attached again
+        conn = loop {
+            match connect() {
+                Ok(conn) => break conn,
+                Err(_) => thread::sleep(Duration::from_secs(1)),
+            }
+        };
+    });
+}
+
+/// Tells vmsh the exit code of the command. It is lost if vmsh is detached at the moment.
+pub fn report_exit(code: i32) {
+    let mut host = match HOST.lock() {
+        Ok(host) => host,
+        Err(_) => return,
+    };
+    if let Some(conn) = host.as_mut() {
+        if let Err(e) = exec::write_message(conn, &Message::Exit(code)) {
+            eprintln!("cannot report exit code to vmsh: {}", e);
+        }
+    }
+}
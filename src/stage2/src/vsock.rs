@@ -0,0 +1,30 @@
+use nix::sys::socket::{self, AddressFamily, SockFlag, SockType, VsockAddr};
+use simple_error::try_with;
+use std::fs::File;
+use std::os::unix::prelude::FromRawFd;
+
+use crate::result::Result;
+
+/// Well-known CID of the host.
+const VMADDR_CID_HOST: u32 = 2;
+
+/// Connects to `port` of the host. vmsh forwards it to a unix socket if attached with
+/// `--vsock`.
+pub fn connect_host(port: u32) -> Result<File> {
+    let fd = try_with!(
+        socket::socket(
+            AddressFamily::Vsock,
+            SockType::Stream,
+            SockFlag::SOCK_CLOEXEC,
+            None
+        ),
+        "cannot create vsock socket"
+    );
+    let conn = unsafe { File::from_raw_fd(fd) };
+    try_with!(
+        socket::connect(fd, &VsockAddr::new(VMADDR_CID_HOST, port)),
+        "cannot connect to host port {}",
+        port
+    );
+    Ok(conn)
+}
@@ -0,0 +1,217 @@
+//! Symbols of the guest kernel, to turn addresses into `function+offset`.
+
+use log::{debug, info, warn};
+use simple_error::{bail, require_with, try_with};
+use std::collections::HashMap;
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+use vm_memory::remote_mem::process_read_bytes;
+use xmas_elf::sections::{SectionData, SHN_UNDEF};
+use xmas_elf::symbol_table::{Entry, Type};
+use xmas_elf::ElfFile;
+
+use crate::guest_mem::GuestMem;
+use crate::kernel::Kernel;
+use crate::kvm::hypervisor::Hypervisor;
+use crate::page_math::page_size;
+use crate::result::Result;
+use crate::try_core_res;
+
+/// Bytes of the kernel stack that are searched for return addresses
+const STACK_SCAN_SIZE: usize = 8192;
+
+pub struct Symbols {
+    /// sorted by address
+    by_addr: Vec<(usize, String)>,
+    by_name: HashMap<String, usize>,
+}
+
+impl Symbols {
+    fn new(by_name: HashMap<String, usize>) -> Symbols {
+        let mut by_addr = by_name
+            .iter()
+            .map(|(name, addr)| (*addr, name.clone()))
+            .collect::<Vec<_>>();
+        by_addr.sort();
+        Symbols { by_addr, by_name }
+    }
+
+    /// The symbols exported by the kernel (ksymtab), found in guest memory by `find_kernel`.
+    /// Static functions are missing, so addresses may resolve to the wrong function.
+    pub fn from_kernel(kernel: &Kernel) -> Symbols {
+        Symbols::new(kernel.symbols.clone())
+    }
+
+    /// Reads an unstripped vmlinux or a System.map. Addresses are the ones the kernel was linked
+    /// at, see `relocate`.
+    pub fn from_file(path: &Path) -> Result<Symbols> {
+        let data = try_with!(fs::read(path), "cannot read {}", path.display());
+        if data.starts_with(b"\x7fELF") {
+            Symbols::from_vmlinux(&data)
+        } else {
+            Symbols::from_system_map(&String::from_utf8_lossy(&data))
+        }
+    }
+
+    fn from_vmlinux(data: &[u8]) -> Result<Symbols> {
+        let elf = try_core_res!(ElfFile::new(data), "cannot parse vmlinux");
+        let section = require_with!(
+            elf.find_section_by_name(".symtab"),
+            "vmlinux has no .symtab section, is it stripped?"
+        );
+        let entries = match try_core_res!(section.get_data(&elf), "cannot read .symtab") {
+            SectionData::SymbolTable64(entries) => entries,
+            _ => bail!("expected .symtab to be a SymbolTable64"),
+        };
+        let mut symbols = HashMap::new();
+        for sym in entries {
+            if sym.shndx() == SHN_UNDEF || !matches!(sym.get_type(), Ok(Type::Func)) {
+                continue;
+            }
+            if let Ok(name) = sym.get_name(&elf) {
+                symbols.insert(name.to_string(), sym.value() as usize);
+            }
+        }
+        Ok(Symbols::new(symbols))
+    }
+
+    /// Lines of the form `ffffffff81000000 T _text`. Only text symbols are used.
+    fn from_system_map(map: &str) -> Result<Symbols> {
+        let mut symbols = HashMap::new();
+        for line in map.lines() {
+            let mut fields = line.split_whitespace();
+            let (addr, kind, name) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(addr), Some(kind), Some(name)) => (addr, kind, name),
+                _ => continue,
+            };
+            if kind != "T" && kind != "t" {
+                continue;
+            }
+            let addr = try_with!(
+                usize::from_str_radix(addr, 16),
+                "invalid address in System.map: {}",
+                line
+            );
+            symbols.insert(name.to_string(), addr);
+        }
+        if symbols.is_empty() {
+            bail!("no text symbols found, expected a System.map or vmlinux");
+        }
+        Ok(Symbols::new(symbols))
+    }
+
+    /// Moves the symbols to where the running kernel was loaded to (KASLR), by comparing them
+    /// with the symbols found in guest memory.
+    pub fn relocate(&mut self, kernel: &Kernel) -> Result<()> {
+        let (name, runtime_addr) = require_with!(
+            kernel
+                .symbols
+                .iter()
+                .find(|(name, _)| self.by_name.contains_key(*name)),
+            "no symbol of the file was found in the running kernel, does it belong to another kernel?"
+        );
+        let offset = runtime_addr.wrapping_sub(self.by_name[name]);
+        debug!("kernel is relocated by {:#x}", offset);
+        for addr in self.by_name.values_mut() {
+            *addr = addr.wrapping_add(offset);
+        }
+        for (addr, _) in &mut self.by_addr {
+            *addr = addr.wrapping_add(offset);
+        }
+        Ok(())
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<usize> {
+        self.by_name.get(name).copied()
+    }
+
+    /// Returns the symbol at or before `addr` and the offset of `addr` to it.
+    pub fn resolve(&self, addr: usize) -> Option<(&str, usize)> {
+        let idx = match self.by_addr.binary_search_by_key(&addr, |(a, _)| *a) {
+            Ok(idx) => idx,
+            Err(0) => return None,
+            Err(idx) => idx - 1,
+        };
+        let (start, name) = &self.by_addr[idx];
+        Some((name.as_str(), addr - start))
+    }
+
+    pub fn format(&self, addr: usize) -> String {
+        match self.resolve(addr) {
+            Some((name, offset)) => format!("{:#x} <{}+{:#x}>", addr, name, offset),
+            None => format!("{:#x}", addr),
+        }
+    }
+}
+
+/// Symbols of the running kernel, from `path` if given, otherwise the ones in guest memory.
+pub fn kernel_symbols(kernel: &Kernel, path: Option<&Path>) -> Result<Symbols> {
+    match path {
+        Some(path) => {
+            let mut symbols = Symbols::from_file(path)?;
+            symbols.relocate(kernel)?;
+            Ok(symbols)
+        }
+        None => Ok(Symbols::from_kernel(kernel)),
+    }
+}
+
+/// Instruction pointer of the vcpu followed by the values on its stack that point into the
+/// kernel. Like the `?` entries in Linux oopses, not all of them are return addresses of
+/// active frames.
+pub fn backtrace(
+    hv: &Hypervisor,
+    mem: &GuestMem,
+    text: &Range<usize>,
+    vcpu: usize,
+) -> Result<Vec<usize>> {
+    let vcpu = &hv.vcpus[vcpu];
+    let regs = hv.get_regs(vcpu)?;
+    let sregs = hv.get_sregs(vcpu)?;
+    if sregs.cs.selector & 3 == 3 {
+        bail!("vcpu is in userspace");
+    }
+    let mut frames = vec![regs.rip as usize];
+    let mut addr = regs.rsp as usize;
+    let end = addr + STACK_SCAN_SIZE;
+    while addr < end {
+        let host_addr = match mem.translate(hv, sregs.cr3 as usize, addr)? {
+            Some(a) => a,
+            None => break,
+        };
+        let page_end = (addr | (page_size() - 1)) + 1;
+        let mut stack = vec![0u8; page_end - addr];
+        try_with!(
+            process_read_bytes(hv.pid, &mut stack, host_addr as *const libc::c_void),
+            "cannot read stack at {:#x}",
+            addr
+        );
+        for word in stack.chunks_exact(8) {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(word);
+            let value = u64::from_le_bytes(bytes) as usize;
+            if text.contains(&value) {
+                frames.push(value);
+            }
+        }
+        addr = page_end;
+    }
+    Ok(frames)
+}
+
+/// Logs a symbolized backtrace of every vcpu that is stopped in the kernel.
+pub fn log_backtraces(hv: &Hypervisor, mem: &GuestMem, kernel: &Kernel, symbols: &Symbols) {
+    for i in 0..hv.vcpus.len() {
+        match backtrace(hv, mem, &kernel.range, i) {
+            Ok(frames) => {
+                info!("vcpu {} backtrace:", i);
+                for (n, addr) in frames.iter().enumerate() {
+                    let unreliable = if n == 0 { "" } else { "? " };
+                    info!("  {}{}", unreliable, symbols.format(*addr));
+                }
+            }
+            Err(e) => warn!("no backtrace for vcpu {}: {}", i, e),
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! Records every syscall vmsh injects into the hypervisor (number, arguments, return value and
+//! time) as one JSON object per line, so operators can review what vmsh did to their VMM.
+
+use libc::c_ulong;
+use log::warn;
+use nix::unistd::Pid;
+use serde::Serialize;
+use simple_error::try_with;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::result::Result;
+
+static LOG: Mutex<Option<File>> = Mutex::new(None);
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    /// Seconds since the unix epoch
+    time: f64,
+    pid: i32,
+    nr: c_ulong,
+    name: &'static str,
+    args: &'a [c_ulong],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ret: Option<isize>,
+    /// Set if the syscall could not be injected, i.e. because the process exited
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Syscalls issued by `inject_syscall::Process`
+fn syscall_name(nr: c_ulong) -> &'static str {
+    match nr as libc::c_long {
+        libc::SYS_ioctl => "ioctl",
+        libc::SYS_getpid => "getpid",
+        libc::SYS_mmap => "mmap",
+        libc::SYS_munmap => "munmap",
+        libc::SYS_socket => "socket",
+        libc::SYS_close => "close",
+        libc::SYS_bind => "bind",
+        libc::SYS_connect => "connect",
+        libc::SYS_recvmsg => "recvmsg",
+        libc::SYS_userfaultfd => "userfaultfd",
+        _ => "unknown",
+    }
+}
+
+/// Appends all injected syscalls of this process to `path` from now on.
+pub fn open(path: &Path) -> Result<()> {
+    let file = try_with!(
+        OpenOptions::new().create(true).append(true).open(path),
+        "cannot open audit log {}",
+        path.display()
+    );
+    let mut log = try_with!(LOG.lock(), "cannot lock audit log");
+    *log = Some(file);
+    Ok(())
+}
+
+fn format_entry(time: SystemTime, pid: Pid, args: &[c_ulong; 7], res: &Result<isize>) -> String {
+    let entry = Entry {
+        time: time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |d| d.as_secs_f64()),
+        pid: pid.as_raw(),
+        nr: args[0],
+        name: syscall_name(args[0]),
+        args: &args[1..],
+        ret: res.as_ref().ok().copied(),
+        error: res.as_ref().err().map(|e| e.to_string()),
+    };
+    let mut line = serde_json::to_string(&entry).expect("audit log entries are always valid json");
+    line.push('\n');
+    line
+}
+
+/// Records a syscall injected into `pid`. `args` are the syscall number followed by the
+/// arguments. Failing to write the log does not fail the syscall.
+pub(crate) fn record(pid: Pid, args: &[c_ulong; 7], res: &Result<isize>) {
+    let mut log = match LOG.lock() {
+        Ok(log) => log,
+        Err(_) => return,
+    };
+    if let Some(file) = log.as_mut() {
+        let line = format_entry(SystemTime::now(), pid, args, res);
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            warn!("cannot write audit log: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn format_entries() {
+        let time = UNIX_EPOCH + Duration::from_millis(1500);
+        let args = [libc::SYS_close as c_ulong, 7, 0, 0, 0, 0, 0];
+        assert_eq!(
+            format_entry(time, Pid::from_raw(42), &args, &Ok(0)),
+            format!(
+                "{{\"time\":1.5,\"pid\":42,\"nr\":{},\"name\":\"close\",\"args\":[7,0,0,0,0,0],\"ret\":0}}\n",
+                libc::SYS_close
+            )
+        );
+        let res: Result<isize> = Err("process exited with: 1".into());
+        let line = format_entry(time, Pid::from_raw(42), &args, &res);
+        assert!(line.ends_with(",\"error\":\"process exited with: 1\"}\n"));
+    }
+}
@@ -1,18 +1,78 @@
 use libc::{c_int, c_long, c_ulong, c_void, off_t, pid_t, size_t, ssize_t, SYS_munmap};
 use libc::{SYS_getpid, SYS_ioctl, SYS_mmap};
 use log::debug;
+use nix::sys::signal::Signal;
 use nix::sys::wait::{waitpid, WaitStatus};
 use nix::unistd::Pid;
 use simple_error::{bail, try_with};
+use std::mem::size_of;
 use std::os::unix::prelude::RawFd;
+use std::sync::Mutex;
 use std::thread::{current, ThreadId};
 
+use super::audit_log;
 use super::ptrace::attach_seize;
+use crate::arch::Registers;
 use crate::cpu::{self, Regs};
+use crate::kvm::hypervisor::memory::{self, process_read, process_write};
 use crate::kvm::hypervisor::VCPU;
 use crate::result::Result;
 use crate::tracer::{ptrace, Tracer};
 
+macro_rules! syscall_args {
+    ($nr:expr) => {
+        [$nr, 0, 0, 0, 0, 0, 0]
+    };
+
+    ($nr:expr, $a1:expr) => {
+        [$nr, $a1 as c_ulong, 0, 0, 0, 0, 0]
+    };
+
+    ($nr:expr, $a1:expr, $a2:expr) => {
+        [$nr, $a1 as c_ulong, $a2 as c_ulong, 0, 0, 0, 0]
+    };
+
+    ($nr:expr, $a1:expr, $a2:expr, $a3:expr) => {
+        [$nr, $a1 as c_ulong, $a2 as c_ulong, $a3 as c_ulong, 0, 0, 0]
+    };
+
+    ($nr:expr, $a1:expr, $a2:expr, $a3:expr, $a4:expr) => {
+        [
+            $nr,
+            $a1 as c_ulong,
+            $a2 as c_ulong,
+            $a3 as c_ulong,
+            $a4 as c_ulong,
+            0,
+            0,
+        ]
+    };
+
+    ($nr:expr, $a1:expr, $a2:expr, $a3:expr, $a4:expr, $a5:expr) => {
+        [
+            $nr,
+            $a1 as c_ulong,
+            $a2 as c_ulong,
+            $a3 as c_ulong,
+            $a4 as c_ulong,
+            $a5 as c_ulong,
+            0,
+        ]
+    };
+
+    ($nr:expr, $a1:expr, $a2:expr, $a3:expr, $a4:expr, $a5:expr, $a6:expr) => {
+        [
+            $nr,
+            $a1 as c_ulong,
+            $a2 as c_ulong,
+            $a3 as c_ulong,
+            $a4 as c_ulong,
+            $a5 as c_ulong,
+            $a6 as c_ulong,
+        ]
+    };
+}
+
 #[derive(Debug)]
 pub struct Process {
     process_idx: usize,
@@ -21,6 +81,69 @@ pub struct Process {
     /// Must never be None during operation. Only deinit() (called by drop) may take() this.
     threads: Option<Vec<ptrace::Thread>>,
     owner: Option<ThreadId>,
+    /// Mapped by the first `run_batch`
+    batch_area: Mutex<Option<BatchArea>>,
+}
+
+/// Mapping of `cpu::BATCH_TEXT` in the tracee
+const BATCH_TEXT_SIZE: usize = 4096;
+
+/// Number of syscalls `Process::run_batch` executes per stop of the tracee
+const BATCH_TABLE_LEN: usize = 64;
+
+/// Entries of `[nr, arg1, ..., arg6, ret]` as expected by `cpu::BATCH_TEXT`
+type BatchTable = [[c_ulong; 8]; BATCH_TABLE_LEN];
+
+/// Memory of the batch trampoline in the tracee
+#[derive(Debug, Clone, Copy)]
+struct BatchArea {
+    /// `cpu::BATCH_TEXT`, read-only and executable
+    text: usize,
+    /// `BatchTable`, writable
+    table: usize,
+}
+
+/// Syscalls that do not depend on each others results, so that `Process::run_batch` can run
+/// them in one stop of the tracee instead of one per syscall.
+#[derive(Debug, Default)]
+pub struct SyscallBatch {
+    syscalls: Vec<[c_ulong; 7]>,
+}
+
+impl SyscallBatch {
+    pub fn new() -> SyscallBatch {
+        SyscallBatch::default()
+    }
+
+    pub fn ioctl(&mut self, fd: RawFd, request: c_ulong, arg: c_ulong) -> &mut SyscallBatch {
+        self.syscalls.push(syscall_args!(
+            SYS_ioctl as c_ulong,
+            fd as c_ulong,
+            request,
+            arg
+        ));
+        self
+    }
+
+    pub fn close(&mut self, fd: RawFd) -> &mut SyscallBatch {
+        self.syscalls
+            .push(syscall_args!(libc::SYS_close as c_ulong, fd));
+        self
+    }
+
+    pub fn munmap(&mut self, addr: *mut c_void, length: size_t) -> &mut SyscallBatch {
+        self.syscalls
+            .push(syscall_args!(SYS_munmap as c_ulong, addr, length));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.syscalls.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.syscalls.is_empty()
+    }
 }
 
 /// save and overwrite main thread state
@@ -47,6 +170,7 @@ fn init(threads: &[ptrace::Thread], process_idx: usize) -> Result<(Regs, c_long)
 /// First call: return Some(_). From now on no further operations must be done on this object.
 /// Second call: return None
 fn deinit(p: &mut Process) -> Option<Vec<ptrace::Thread>> {
+    release_batch_area(p);
     match &mut p.threads {
         // may have been take()en already
         Some(threads) => {
@@ -64,6 +188,25 @@ fn deinit(p: &mut Process) -> Option<Vec<ptrace::Thread>> {
     }
 }
 
+/// Unmaps the batch trampoline, it is mapped again by the next `run_batch`.
+fn release_batch_area(p: &Process) {
+    if p.threads.is_none() {
+        return;
+    }
+    let area = match p.batch_area.lock() {
+        Ok(mut area) => area.take(),
+        Err(_) => None,
+    };
+    if let Some(area) = area {
+        let text = p.munmap(area.text as *mut c_void, BATCH_TEXT_SIZE);
+        let table = p.munmap(area.table as *mut c_void, size_of::<BatchTable>());
+        memory::remove_scratch(p.pid(), area.table);
+        if let Err(e) = text.and(table) {
+            debug!("cannot unmap batch trampoline: {}", e);
+        }
+    }
+}
+
 pub fn from_tracer(t: Tracer) -> Result<Process> {
     let (saved_regs, saved_text) = init(&t.threads, t.process_idx)?;
 
@@ -73,6 +216,7 @@ pub fn from_tracer(t: Tracer) -> Result<Process> {
         saved_text,
         threads: Some(t.threads),
         owner: t.owner,
+        batch_area: Mutex::new(None),
     })
 }
 
@@ -97,63 +241,10 @@ pub fn attach(pid: Pid) -> Result<Process> {
         saved_text,
         threads: Some(threads),
         owner: Some(current().id()),
+        batch_area: Mutex::new(None),
     })
 }
 
-macro_rules! syscall_args {
-    ($regs:expr, $nr:expr) => {
-        ($regs).prepare_syscall(&[$nr, 0, 0, 0, 0, 0, 0])
-    };
-
-    ($regs:expr, $nr:expr, $a1:expr) => {
-        ($regs).prepare_syscall(&[$nr, $a1 as c_ulong, 0, 0, 0, 0, 0])
-    };
-
-    ($regs:expr, $nr:expr, $a1:expr, $a2:expr) => {
-        ($regs).prepare_syscall(&[$nr, $a1 as c_ulong, $a2 as c_ulong, 0, 0, 0, 0])
-    };
-
-    ($regs:expr, $nr:expr, $a1:expr, $a2:expr, $a3:expr) => {
-        $regs.prepare_syscall(&[$nr, $a1 as c_ulong, $a2 as c_ulong, $a3 as c_ulong, 0, 0, 0])
-    };
-
-    ($regs:expr, $nr:expr, $a1:expr, $a2:expr, $a3:expr, $a4:expr) => {
-        $regs.prepare_syscall(&[
-            $nr,
-            $a1 as c_ulong,
-            $a2 as c_ulong,
-            $a3 as c_ulong,
-            $a4 as c_ulong,
-            0,
-            0,
-        ])
-    };
-
-    ($regs:expr, $nr:expr, $a1:expr, $a2:expr, $a3:expr, $a4:expr, $a5:expr) => {
-        $regs.prepare_syscall(&[
-            $nr,
-            $a1 as c_ulong,
-            $a2 as c_ulong,
-            $a3 as c_ulong,
-            $a4 as c_ulong,
-            $a5 as c_ulong,
-            0,
-        ])
-    };
-
-    ($regs:expr, $nr:expr, $a1:expr, $a2:expr, $a3:expr, $a4:expr, $a5:expr, $a6:expr) => {
-        $regs.prepare_syscall(&[
-            $nr,
-            $a1 as c_ulong,
-            $a2 as c_ulong,
-            $a3 as c_ulong,
-            $a4 as c_ulong,
-            $a5 as c_ulong,
-            $a6 as c_ulong,
-        ])
-    };
-}
-
 impl Process {
     // PID of the traced process
     pub fn pid(&self) -> Pid {
@@ -215,20 +306,14 @@ impl Process {
     }
 
     pub fn ioctl(&self, fd: RawFd, request: c_ulong, arg: c_ulong) -> Result<c_int> {
-        let args = syscall_args!(
-            self.saved_regs,
-            SYS_ioctl as c_ulong,
-            fd as c_ulong,
-            request,
-            arg
-        );
+        let args = syscall_args!(SYS_ioctl as c_ulong, fd as c_ulong, request, arg);
 
         self.syscall(&args).map(|v| v as c_int)
     }
 
     #[allow(dead_code)]
     pub fn getpid(&self) -> Result<pid_t> {
-        let args = syscall_args!(self.saved_regs, SYS_getpid as c_ulong);
+        let args = syscall_args!(SYS_getpid as c_ulong);
 
         self.syscall(&args).map(|v| v as c_int)
     }
@@ -242,40 +327,25 @@ impl Process {
         fd: RawFd,
         offset: off_t,
     ) -> Result<*mut c_void> {
-        let args = syscall_args!(
-            self.saved_regs,
-            SYS_mmap as c_ulong,
-            addr,
-            length,
-            prot,
-            flags,
-            fd,
-            offset
-        );
+        let args = syscall_args!(SYS_mmap as c_ulong, addr, length, prot, flags, fd, offset);
 
         self.syscall(&args).map(|v| v as *mut c_void)
     }
 
     pub fn munmap(&self, addr: *mut c_void, length: libc::size_t) -> Result<()> {
-        let args = syscall_args!(self.saved_regs, SYS_munmap as c_ulong, addr, length);
+        let args = syscall_args!(SYS_munmap as c_ulong, addr, length);
 
         self.syscall(&args).map(drop)
     }
 
     pub fn socket(&self, domain: c_int, ty: c_int, protocol: c_int) -> Result<c_int> {
-        let args = syscall_args!(
-            self.saved_regs,
-            libc::SYS_socket as c_ulong,
-            domain,
-            ty,
-            protocol
-        );
+        let args = syscall_args!(libc::SYS_socket as c_ulong, domain, ty, protocol);
 
         self.syscall(&args).map(|v| v as c_int)
     }
 
     pub fn close(&self, fd: RawFd) -> Result<c_int> {
-        let args = syscall_args!(self.saved_regs, libc::SYS_close as c_ulong, fd);
+        let args = syscall_args!(libc::SYS_close as c_ulong, fd);
 
         self.syscall(&args).map(|v| v as c_int)
     }
@@ -286,13 +356,7 @@ impl Process {
         address: *const libc::sockaddr,
         address_len: libc::socklen_t,
     ) -> Result<c_int> {
-        let args = syscall_args!(
-            self.saved_regs,
-            libc::SYS_bind as c_ulong,
-            socket,
-            address,
-            address_len
-        );
+        let args = syscall_args!(libc::SYS_bind as c_ulong, socket, address, address_len);
 
         self.syscall(&args).map(|v| v as c_int)
     }
@@ -303,35 +367,160 @@ impl Process {
         address: *const libc::sockaddr,
         len: libc::socklen_t,
     ) -> Result<c_int> {
-        let args = syscall_args!(
-            self.saved_regs,
-            libc::SYS_connect as c_ulong,
-            socket,
-            address,
-            len
-        );
+        let args = syscall_args!(libc::SYS_connect as c_ulong, socket, address, len);
 
         self.syscall(&args).map(|v| v as c_int)
     }
 
     pub fn recvmsg(&self, fd: c_int, msg: *mut libc::msghdr, flags: c_int) -> Result<ssize_t> {
-        let args = syscall_args!(
-            self.saved_regs,
-            libc::SYS_recvmsg as c_ulong,
-            fd,
-            msg,
-            flags
-        );
+        let args = syscall_args!(libc::SYS_recvmsg as c_ulong, fd, msg, flags);
 
         self.syscall(&args).map(|v| v as ssize_t)
     }
 
     pub fn userfaultfd(&self, flags: c_int) -> Result<c_int> {
-        let args = syscall_args!(self.saved_regs, libc::SYS_userfaultfd as c_ulong, flags);
+        let args = syscall_args!(libc::SYS_userfaultfd as c_ulong, flags);
 
         self.syscall(&args).map(|v| v as c_int)
     }
 
+    /// Runs all syscalls of `batch` and returns their results in the same order. Up to
+    /// `BATCH_TABLE_LEN` syscalls are executed by a trampoline in a single stop of the tracee.
+    pub fn run_batch(&self, batch: &SyscallBatch) -> Result<Vec<isize>> {
+        let area = match self.batch_area() {
+            Ok(area) => area,
+            Err(e) => {
+                debug!(
+                    "cannot map batch trampoline, inject syscalls one by one: {}",
+                    e
+                );
+                return batch
+                    .syscalls
+                    .iter()
+                    .map(|args| self.syscall(args))
+                    .collect();
+            }
+        };
+        let mut results = Vec::with_capacity(batch.len());
+        for chunk in batch.syscalls.chunks(BATCH_TABLE_LEN) {
+            match self.inject_batch(area, chunk) {
+                Ok(rets) => {
+                    for (args, ret) in chunk.iter().zip(rets) {
+                        audit_log::record(self.pid(), args, &Ok(ret));
+                        results.push(ret);
+                    }
+                }
+                Err(e) => {
+                    let msg = format!("batch failed: {}", e);
+                    for args in chunk {
+                        audit_log::record(self.pid(), args, &Err(msg.as_str().into()));
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    fn batch_area(&self) -> Result<BatchArea> {
+        let mut area = try_with!(self.batch_area.lock(), "cannot lock batch area");
+        if let Some(area) = *area {
+            return Ok(area);
+        }
+        let text = self.mmap_anonymous(BATCH_TEXT_SIZE, libc::PROT_READ | libc::PROT_EXEC)?;
+        let table = match self
+            .mmap_anonymous(size_of::<BatchTable>(), libc::PROT_READ | libc::PROT_WRITE)
+        {
+            Ok(table) => table,
+            Err(e) => {
+                let _ = self.munmap(text as *mut c_void, BATCH_TEXT_SIZE);
+                return Err(e);
+            }
+        };
+        // ptrace writes to read-only memory
+        for (i, word) in cpu::BATCH_TEXT.chunks(size_of::<c_long>()).enumerate() {
+            let mut bytes = [0u8; size_of::<c_long>()];
+            bytes[..word.len()].copy_from_slice(word);
+            try_with!(
+                unsafe {
+                    self.main_thread().write(
+                        (text + i * size_of::<c_long>()) as *mut c_void,
+                        c_long::from_ne_bytes(bytes) as *mut c_void,
+                    )
+                },
+                "cannot write batch trampoline"
+            );
+        }
+        // not part of the VM, so it can be written to in read-only mode
+        memory::add_scratch(self.pid(), table, size_of::<BatchTable>());
+        let new = BatchArea { text, table };
+        *area = Some(new);
+        Ok(new)
+    }
+
+    fn mmap_anonymous(&self, length: size_t, prot: c_int) -> Result<usize> {
+        let addr = self.mmap(
+            std::ptr::null_mut(),
+            length,
+            prot,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )? as isize;
+        if (-4095..0).contains(&addr) {
+            bail!("mmap failed: {}", nix::errno::Errno::from_i32(-addr as i32));
+        }
+        Ok(addr as usize)
+    }
+
+    fn inject_batch(&self, area: BatchArea, syscalls: &[[c_ulong; 7]]) -> Result<Vec<isize>> {
+        self.check_owner()?;
+        let mut table: BatchTable = [[0; 8]; BATCH_TABLE_LEN];
+        for (entry, args) in table.iter_mut().zip(syscalls) {
+            entry[..7].copy_from_slice(args);
+        }
+        process_write(self.pid(), area.table as *mut c_void, &table)?;
+        let regs = self.saved_regs.prepare_batch(
+            area.text as u64,
+            area.table as u64,
+            syscalls.len() as u64,
+        );
+        try_with!(
+            self.main_thread().setregs(&regs),
+            "cannot set registers of batch trampoline"
+        );
+        // not a valid syscall number, so that an interrupted syscall is not restarted
+        #[cfg(target_arch = "aarch64")]
+        try_with!(
+            self.main_thread().set_syscall(u64::MAX),
+            "cannot reset syscall number"
+        );
+        loop {
+            try_with!(self.main_thread().cont(None), "cannot run batch trampoline");
+            let status = try_with!(waitpid(self.main_thread().tid, None), "waitpid failed");
+            match status {
+                WaitStatus::Stopped(_, Signal::SIGTRAP) => break,
+                WaitStatus::Exited(_, status) => bail!("process exited with: {}", status),
+                WaitStatus::Signaled(_, signal, _) => bail!("process was killed by {}", signal),
+                _ => {}
+            }
+        }
+        let result_regs = try_with!(self.main_thread().getregs(), "cannot get batch results");
+        // x86 reports the address after int3, arm64 the one of brk
+        let text = area.text as u64..=(area.text + cpu::BATCH_TEXT.len()) as u64;
+        if !text.contains(&result_regs.ip()) {
+            bail!(
+                "batch trampoline stopped at unexpected address {:#x}",
+                result_regs.ip()
+            );
+        }
+        let table: BatchTable = process_read(self.pid(), area.table as *const c_void)?;
+        Ok(table[..syscalls.len()]
+            .iter()
+            .map(|entry| entry[7] as isize)
+            .collect())
+    }
+
     fn wait_for_syscall(&self) -> Result<()> {
         loop {
             try_with!(self.main_thread().syscall(), "ptrace_syscall() failed");
@@ -345,15 +534,29 @@ impl Process {
         }
     }
 
-    fn syscall(&self, regs: &Regs) -> Result<isize> {
+    /// `args` are the syscall number followed by the arguments. Every syscall is recorded in
+    /// the audit log, if one was opened.
+    fn syscall(&self, args: &[c_ulong; 7]) -> Result<isize> {
+        let res = self.inject(&self.saved_regs.prepare_syscall(args));
+        audit_log::record(self.pid(), args, &res);
+        res
+    }
+
+    fn inject(&self, regs: &Regs) -> Result<isize> {
         self.check_owner()?;
         try_with!(
             self.main_thread().setregs(regs),
             "cannot set system call args"
         );
-        // FIXME: on arm we would need PTRACE_SET_SYSCALL
         // stops before syscall
         try_with!(self.wait_for_syscall(), "failed to trap before syscall");
+        // arm64 takes the syscall number from x8 before the syscall-enter-stop. If the tracee
+        // was stopped in a restartable syscall, that is not the number we have set.
+        #[cfg(target_arch = "aarch64")]
+        try_with!(
+            self.main_thread().set_syscall(regs.syscall_nr()),
+            "cannot set syscall number"
+        );
         // traps after syscall
         try_with!(self.wait_for_syscall(), "failed to trap after syscall");
         let result_regs = try_with!(self.main_thread().getregs(), "cannot syscall results");
@@ -442,6 +645,10 @@ int main() {
         let pid = Pid::from_raw(child.id() as i32);
         let mut proc = attach(pid).expect("cannot attach with ptrace");
         assert_eq!(proc.getpid().expect("getpid failed"), pid.as_raw());
+        let mut batch = SyscallBatch::new();
+        batch.close(-1).close(-1);
+        let rets = proc.run_batch(&batch).expect("batch failed");
+        assert_eq!(rets, vec![-libc::EBADF as isize; 2]);
 
         proc.disown().expect("cannot disown");
         let different_thread = std::thread::spawn(move || {
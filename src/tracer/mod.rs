@@ -1,3 +1,4 @@
+pub mod audit_log;
 pub mod inject_syscall;
 pub mod proc;
 pub mod ptrace;
@@ -27,6 +27,9 @@ pub struct Mapping {
 
     // only for VM mappings, 0 otherwise
     pub phys_addr: usize,
+    // memslot id and flags (KVM_MEM_*), only for VM mappings
+    pub slot: u32,
+    pub slot_flags: u32,
 }
 
 impl Mapping {
@@ -152,6 +155,8 @@ fn parse_line(line: &str) -> Result<Mapping> {
         inode,
         pathname,
         phys_addr: 0,
+        slot: 0,
+        slot_flags: 0,
     })
 }
 
@@ -207,3 +212,139 @@ impl PidHandle {
         Ok(maps)
     }
 }
+
+/// Value of `field` in a `/proc/<pid>/status` file, i.e. `PPid` or `Seccomp`.
+fn status_field<'a>(status: &'a str, field: &str) -> Option<&'a str> {
+    status.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        (name == field).then(|| value.trim())
+    })
+}
+
+/// Reads `field` from the status of thread `tid` of process `pid`.
+pub fn task_status(pid: Pid, tid: Pid, field: &str) -> Result<String> {
+    let path = pid_path(pid)
+        .join("task")
+        .join(tid.as_raw().to_string())
+        .join("status");
+    let status = try_with!(
+        std::fs::read_to_string(&path),
+        "cannot read {}",
+        path.display()
+    );
+    let value = require_with!(
+        status_field(&status, field),
+        "no {} in {}",
+        field,
+        path.display()
+    );
+    Ok(value.to_string())
+}
+
+/// `Seccomp` in the status of threads that installed a seccomp filter
+pub const SECCOMP_MODE_FILTER: &str = "2";
+
+/// Whether thread `tid` of `pid` has a seccomp filter that may block injected syscalls.
+pub fn has_seccomp_filter(pid: Pid, tid: Pid) -> Result<bool> {
+    Ok(task_status(pid, tid, "Seccomp")? == SECCOMP_MODE_FILTER)
+}
+
+/// Threads of `pid`, starting with the main thread.
+pub fn threads(pid: Pid) -> Result<Vec<Pid>> {
+    let dir = pid_path(pid).join("task");
+    let entries = try_with!(read_dir(&dir), "failed to open directory {}", dir.display());
+    let mut tids = vec![];
+    for entry in entries {
+        let entry = try_with!(entry, "failed to read directory {}", dir.display());
+        let file_name = entry.file_name();
+        let file_name = require_with!(file_name.to_str(), "cannot convert filename to string");
+        let tid = try_with!(file_name.parse::<c_int>(), "invalid tid {}", file_name);
+        tids.push(Pid::from_raw(tid));
+    }
+    tids.sort_by_key(|tid| *tid != pid);
+    Ok(tids)
+}
+
+/// Processes started by the threads of `pid`.
+pub fn children(pid: Pid) -> Result<Vec<Pid>> {
+    let mut children = vec![];
+    for tid in threads(pid)? {
+        let path = pid_path(pid)
+            .join("task")
+            .join(tid.as_raw().to_string())
+            .join("children");
+        // the thread might have exited
+        let list = match std::fs::read_to_string(&path) {
+            Ok(list) => list,
+            Err(_) => continue,
+        };
+        for child in list.split_whitespace() {
+            let child = try_with!(child.parse::<c_int>(), "invalid pid {}", child);
+            children.push(Pid::from_raw(child));
+        }
+    }
+    Ok(children)
+}
+
+/// Processes started by `pid` or its children, breadth first.
+pub fn descendants(pid: Pid) -> Vec<Pid> {
+    let mut found = children(pid).unwrap_or_default();
+    let mut i = 0;
+    while i < found.len() {
+        let more = children(found[i]).unwrap_or_default();
+        found.extend(more);
+        i += 1;
+    }
+    found
+}
+
+/// The pids of `pid` from the namespace of vmsh down to its own namespace, i.e. `[4321, 1]` for
+/// the init process of a pid namespace created by a jailer.
+pub fn ns_pids(pid: Pid) -> Result<Vec<Pid>> {
+    let nspid = task_status(pid, pid, "NSpid")?;
+    parse_ns_pids(&nspid)
+}
+
+fn parse_ns_pids(nspid: &str) -> Result<Vec<Pid>> {
+    nspid
+        .split_whitespace()
+        .map(|p| {
+            Ok(Pid::from_raw(try_with!(
+                p.parse::<c_int>(),
+                "invalid pid {}",
+                p
+            )))
+        })
+        .collect()
+}
+
+/// Identifies the namespace of the given `kind` (i.e. `net` or `pid`) a process is in.
+pub fn namespace_inode(pid: Pid, kind: &str) -> Result<u64> {
+    let path = pid_path(pid).join("ns").join(kind);
+    let meta = try_with!(std::fs::metadata(&path), "cannot stat {}", path.display());
+    Ok(std::os::unix::fs::MetadataExt::ino(&meta))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_nspid() {
+        assert_eq!(
+            parse_ns_pids("4321\t1").unwrap(),
+            vec![Pid::from_raw(4321), Pid::from_raw(1)]
+        );
+        assert_eq!(parse_ns_pids("42").unwrap(), vec![Pid::from_raw(42)]);
+        assert!(parse_ns_pids("4321 x").is_err());
+    }
+
+    #[test]
+    fn parse_status() {
+        let status = "Name:\tcrosvm\nUmask:\t0022\nPPid:\t1234\nSeccomp:\t2\nSeccomp_filters:\t1\n";
+        assert_eq!(status_field(status, "PPid"), Some("1234"));
+        assert_eq!(status_field(status, "Seccomp"), Some(SECCOMP_MODE_FILTER));
+        assert_eq!(status_field(status, "Seccomp_filters"), Some("1"));
+        assert_eq!(status_field(status, "Tgid"), None);
+    }
+}
@@ -1,12 +1,11 @@
 use crate::cpu::Regs;
-use libc::{c_long, c_void, pid_t};
+use libc::{c_long, c_void};
 use nix::errno::Errno;
 use nix::sys::ptrace::{self, AddressType, Request, RequestType};
 use nix::sys::wait::waitpid;
 use nix::sys::wait::WaitPidFlag;
 use nix::unistd::Pid;
-use simple_error::{require_with, try_with};
-use std::fs;
+use simple_error::try_with;
 use std::{mem, ptr};
 
 use crate::result::Result;
@@ -19,11 +18,13 @@ pub struct Thread {
 }
 
 /// Get user registers, as with `ptrace(PTRACE_GETREGS, ...)`
+#[cfg(target_arch = "x86_64")]
 fn getregs(pid: Pid) -> nix::Result<Regs> {
     ptrace_get_data::<Regs>(Request::PTRACE_GETREGS, pid)
 }
 
 /// Set user registers, as with `ptrace(PTRACE_SETREGS, ...)`
+#[cfg(target_arch = "x86_64")]
 fn setregs(pid: Pid, regs: &Regs) -> nix::Result<()> {
     let res = unsafe {
         libc::ptrace(
@@ -36,6 +37,66 @@ fn setregs(pid: Pid, regs: &Regs) -> nix::Result<()> {
     Errno::result(res).map(drop)
 }
 
+/// arm64 has no PTRACE_GETREGS, general purpose registers are read as NT_PRSTATUS regset.
+#[cfg(target_arch = "aarch64")]
+fn getregs(pid: Pid) -> nix::Result<Regs> {
+    let mut regs = Regs::default();
+    let mut iov = libc::iovec {
+        iov_base: &mut regs as *mut _ as *mut c_void,
+        iov_len: mem::size_of::<Regs>(),
+    };
+    let res = unsafe {
+        libc::ptrace(
+            Request::PTRACE_GETREGSET as RequestType,
+            libc::pid_t::from(pid),
+            libc::NT_PRSTATUS as usize as *mut c_void,
+            &mut iov as *mut _ as *mut c_void,
+        )
+    };
+    Errno::result(res)?;
+    if iov.iov_len != mem::size_of::<Regs>() {
+        return Err(Errno::EIO);
+    }
+    Ok(regs)
+}
+
+/// Set user registers as NT_PRSTATUS regset
+#[cfg(target_arch = "aarch64")]
+fn setregs(pid: Pid, regs: &Regs) -> nix::Result<()> {
+    let mut iov = libc::iovec {
+        iov_base: regs as *const _ as *mut c_void,
+        iov_len: mem::size_of::<Regs>(),
+    };
+    let res = unsafe {
+        libc::ptrace(
+            Request::PTRACE_SETREGSET as RequestType,
+            libc::pid_t::from(pid),
+            libc::NT_PRSTATUS as usize as *mut c_void,
+            &mut iov as *mut _ as *mut c_void,
+        )
+    };
+    Errno::result(res).map(drop)
+}
+
+/// from arch/arm64/include/uapi/asm/ptrace.h
+#[cfg(target_arch = "aarch64")]
+const PTRACE_SET_SYSCALL: RequestType = 23;
+
+/// Change the number of the syscall the tracee is about to execute. Only valid in a
+/// syscall-enter-stop, as with `ptrace(PTRACE_SET_SYSCALL, ...)`
+#[cfg(target_arch = "aarch64")]
+fn set_syscall(pid: Pid, nr: u64) -> nix::Result<()> {
+    let res = unsafe {
+        libc::ptrace(
+            PTRACE_SET_SYSCALL,
+            libc::pid_t::from(pid),
+            ptr::null_mut::<c_void>(),
+            nr as usize as *mut c_void,
+        )
+    };
+    Errno::result(res).map(drop)
+}
+
 /// Stop tracee while being attached, as with `ptrace(PTRACE_INTERRUPT, ...)`
 fn interrupt(pid: Pid) -> nix::Result<()> {
     let res = unsafe {
@@ -53,6 +114,7 @@ fn interrupt(pid: Pid) -> nix::Result<()> {
 /// Some ptrace get requests populate structs or larger elements than `c_long`
 /// and therefore use the data field to return values. This function handles these
 /// requests.
+#[cfg(target_arch = "x86_64")]
 fn ptrace_get_data<T>(request: Request, pid: Pid) -> nix::Result<T> {
     let mut data = mem::MaybeUninit::uninit();
     let res = unsafe {
@@ -80,6 +142,15 @@ impl Thread {
         ))
     }
 
+    #[cfg(target_arch = "aarch64")]
+    pub fn set_syscall(&self, nr: u64) -> Result<()> {
+        try_with!(
+            set_syscall(self.tid, nr),
+            "cannot set syscall number with ptrace"
+        );
+        Ok(())
+    }
+
     pub fn detach(&self) -> Result<()> {
         try_with!(
             ptrace::detach(self.tid, None),
@@ -104,6 +175,15 @@ impl Thread {
         Ok(())
     }
 
+    /// Message of the last ptrace event, i.e. the tid of a new thread after
+    /// `PTRACE_EVENT_CLONE`
+    pub fn event_msg(&self) -> Result<c_long> {
+        Ok(try_with!(
+            ptrace::getevent(self.tid),
+            "cannot get ptrace event message"
+        ))
+    }
+
     pub fn syscall_info(&self) -> Result<SyscallInfo> {
         let info = try_with!(
             get_syscall_info(self.tid),
@@ -141,9 +221,13 @@ impl Thread {
 
 pub fn attach_seize(tid: Pid) -> Result<()> {
     // seize seems to be more modern and versatile than `ptrace::attach()`: continue, stop and
-    // detach from tracees at (almost) any time
+    // detach from tracees at (almost) any time. Threads cloned by the tracee are traced as well
+    // and start with a PTRACE_EVENT_STOP.
     try_with!(
-        ptrace::seize(tid, ptrace::Options::PTRACE_O_TRACESYSGOOD),
+        ptrace::seize(
+            tid,
+            ptrace::Options::PTRACE_O_TRACESYSGOOD | ptrace::Options::PTRACE_O_TRACECLONE
+        ),
         "cannot seize the process"
     );
     try_with!(interrupt(tid), "cannot interrupt/stop the tracee");
@@ -153,30 +237,34 @@ pub fn attach_seize(tid: Pid) -> Result<()> {
     Ok(())
 }
 
+/// Stops all threads of `pid`. Also returns the index of the thread syscalls are injected into:
+/// the main thread, unless a seccomp filter might block our syscalls there and another thread
+/// has none. Firecracker and cloud-hypervisor install filters only on some of their threads.
 pub fn attach_all_threads(pid: Pid) -> Result<(Vec<Thread>, usize)> {
-    let dir = proc::pid_path(pid).join("task");
-    let threads_dir = try_with!(
-        fs::read_dir(&dir),
-        "failed to open directory {}",
-        dir.display()
-    );
-    let mut process_idx = 0;
-
     let mut threads = vec![];
-
-    for (i, thread_name) in threads_dir.enumerate() {
-        let entry = try_with!(thread_name, "failed to read directory {}", dir.display());
-        let file_name = entry.file_name();
-        let file_name = require_with!(file_name.to_str(), "cannot convert filename to string");
-        let raw_tid = try_with!(file_name.parse::<pid_t>(), "invalid tid {}", file_name);
-        let tid = Pid::from_raw(raw_tid);
-        if tid == pid {
-            process_idx = i;
-        }
+    for tid in proc::threads(pid)? {
         if let Ok(t) = attach_seize(tid).map(|_| Thread { tid }) {
             threads.push(t);
         }
     }
+    let filtered = |t: &Thread| proc::has_seccomp_filter(pid, t.tid).unwrap_or(false);
+    let mut process_idx = threads.iter().position(|t| t.tid == pid).unwrap_or(0);
+    if threads.get(process_idx).map_or(false, filtered) {
+        match threads.iter().position(|t| !filtered(t)) {
+            Some(idx) => {
+                log::info!(
+                    "main thread of {} has a seccomp filter, inject syscalls into thread {}",
+                    pid,
+                    threads[idx].tid
+                );
+                process_idx = idx;
+            }
+            None => log::debug!(
+                "all threads of {} have a seccomp filter, injected syscalls might fail",
+                pid
+            ),
+        }
+    }
     Ok((threads, process_idx))
 }
 
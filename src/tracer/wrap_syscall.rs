@@ -8,13 +8,15 @@ use nix::{
     sys::wait::{waitpid, WaitStatus},
 };
 use nix::{sys::signal::Signal, unistd::getpgrp};
-use simple_error::bail;
 use simple_error::try_with;
+use simple_error::{bail, require_with};
+use std::os::unix::io::RawFd;
 use std::{
     fmt,
     thread::{current, ThreadId},
 };
 
+use crate::arch::Registers;
 use crate::kvm::hypervisor::{self, VCPU};
 use crate::kvm::ioctls;
 use crate::result::Result;
@@ -50,6 +52,7 @@ impl MmioRw {
         }
     }
 
+    /// None for exits other than KVM_EXIT_MMIO, which the hypervisor handles.
     #[must_use]
     pub fn from(kvm_run: &kvmb::kvm_run, pid: Pid, vcpu_map: Mapping) -> Option<MmioRw> {
         match kvm_run.exit_reason {
@@ -57,9 +60,23 @@ impl MmioRw {
                 // Safe because the exit_reason (which comes from the kernel) told us which
                 // union field to use.
                 let mmio: &MmioRwRaw = unsafe { &kvm_run.__bindgen_anon_1.mmio };
+                if mmio.len == 0 || mmio.len as usize > MMIO_RW_DATA_MAX {
+                    warn!(
+                        "ignoring mmio exit at {:#x} with invalid length {}",
+                        mmio.phys_addr, mmio.len
+                    );
+                    return None;
+                }
                 Some(MmioRw::new(mmio, pid, vcpu_map))
             }
-            _ => None,
+            reason => {
+                trace!(
+                    "kvm-run exit reason {} in {}",
+                    exit_reason_name(reason),
+                    pid
+                );
+                None
+            }
         }
     }
 
@@ -112,6 +129,78 @@ impl MmioRw {
     }
 }
 
+/// Name of a KVM_EXIT_* reason of kvm_run, for logs
+pub fn exit_reason_name(reason: u32) -> &'static str {
+    const NAMES: &[&str] = &[
+        "unknown",
+        "exception",
+        "io",
+        "hypercall",
+        "debug",
+        "hlt",
+        "mmio",
+        "irq-window-open",
+        "shutdown",
+        "fail-entry",
+        "intr",
+        "set-tpr",
+        "tpr-access",
+        "s390-sieic",
+        "s390-reset",
+        "dcr",
+        "nmi",
+        "internal-error",
+        "osi",
+        "papr-hcall",
+        "s390-ucontrol",
+        "watchdog",
+        "s390-tsch",
+        "epr",
+        "system-event",
+        "s390-stsi",
+        "ioapic-eoi",
+        "hyperv",
+        "arm-nisv",
+        "x86-rdmsr",
+        "x86-wrmsr",
+        "dirty-ring-full",
+        "ap-reset-hold",
+        "x86-bus-lock",
+        "xen",
+        "riscv-sbi",
+        "riscv-csr",
+        "notify",
+        "loongarch-iocsr",
+        "memory-fault",
+        "tdx",
+    ];
+    NAMES.get(reason as usize).copied().unwrap_or("unknown")
+}
+
+/// A vcpu stopped at a breakpoint or after a single step, see `KvmRunWrapper::wait_for_debug_exit`.
+#[derive(Debug)]
+pub struct DebugExit {
+    pub vcpu_fd: RawFd,
+    /// x86 exception vector, 1 (#DB) for single steps, 3 (#BP) for breakpoints
+    pub exception: u32,
+}
+
+pub enum DebugEvent {
+    Exit(DebugExit),
+    /// a thread stopped for another reason and was resumed
+    Other,
+    /// no thread stopped
+    Idle,
+}
+
+/// ioctl(KVM_RUN) returned in a thread
+struct KvmRunExit {
+    kvm_run: kvmb::kvm_run,
+    tid: Pid,
+    vcpu_fd: RawFd,
+    vcpu_map: Mapping,
+}
+
 impl fmt::Display for MmioRw {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         if self.is_write {
@@ -138,6 +227,8 @@ struct Thread {
     ptthread: ptrace::Thread,
     is_running: bool,
     in_syscall: bool,
+    /// first argument of the current syscall, arm64 overwrites it with the return value
+    syscall_arg1: u64,
 }
 
 impl Thread {
@@ -146,6 +237,7 @@ impl Thread {
             ptthread,
             is_running: false,
             in_syscall: false, // ptrace (in practice) never attaches to a process while it is in a syscall
+            syscall_arg1: 0,
         }
     }
 
@@ -180,7 +272,8 @@ impl Thread {
     }
 }
 
-/// TODO respect and handle newly spawned threads as well
+/// Threads spawned by the hypervisor while we are attached (i.e. for hotplugged vcpus) are traced
+/// automatically.
 pub struct KvmRunWrapper {
     process_idx: usize,
     threads: Vec<Thread>,
@@ -312,21 +405,61 @@ impl KvmRunWrapper {
     pub fn wait_for_ioctl(&mut self) -> Result<Option<MmioRw>> {
         self.check_owner()?;
         self.stop_on_syscall()?;
-        let status = try_with!(self.waitpid(), "cannot waitpid");
-        let mmio = try_with!(self.process_status(status), "cannot process status");
+        let status = try_with!(self.waitpid(false), "cannot waitpid");
+        let exit = try_with!(self.process_status(status), "cannot process status");
 
-        Ok(mmio)
+        Ok(exit.and_then(|e| MmioRw::from(&e.kvm_run, e.tid, e.vcpu_map)))
+    }
+
+    /// Like `wait_for_ioctl`, but returns when a vcpu with guest debugging enabled stops at a
+    /// breakpoint or single step. The hypervisor sees an interrupted ioctl(KVM_RUN) instead of
+    /// `KVM_EXIT_DEBUG` and runs the vcpu again once we let it continue. Does not block, so
+    /// that the caller can check for other events in between.
+    pub fn wait_for_debug_exit(&mut self) -> Result<DebugEvent> {
+        self.check_owner()?;
+        self.stop_on_syscall()?;
+        let status = try_with!(self.waitpid(true), "cannot waitpid");
+        if let WaitStatus::StillAlive = status {
+            return Ok(DebugEvent::Idle);
+        }
+        let exit = match try_with!(self.process_status(status), "cannot process status") {
+            Some(exit) if exit.kvm_run.exit_reason == kvmb::KVM_EXIT_DEBUG => exit,
+            _ => return Ok(DebugEvent::Other),
+        };
+        let thread = require_with!(
+            self.threads.iter().find(|t| t.ptthread.tid == exit.tid),
+            "thread {} disappeared",
+            exit.tid
+        );
+        let mut regs = try_with!(thread.ptthread.getregs(), "cannot get registers");
+        regs.set_syscall_ret(-libc::EINTR as u64);
+        try_with!(thread.ptthread.setregs(&regs), "cannot set registers");
+
+        // Safe because the exit_reason told us which union field to use.
+        let debug = unsafe { exit.kvm_run.__bindgen_anon_1.debug };
+        Ok(DebugEvent::Exit(DebugExit {
+            vcpu_fd: exit.vcpu_fd,
+            exception: debug.arch.exception,
+        }))
     }
 
-    fn waitpid(&mut self) -> Result<WaitStatus> {
+    /// With `nohang` returns `WaitStatus::StillAlive` if no thread stopped.
+    fn waitpid(&mut self, nohang: bool) -> Result<WaitStatus> {
+        let mut flags = nix::sys::wait::WaitPidFlag::__WALL;
+        if nohang {
+            flags |= nix::sys::wait::WaitPidFlag::WNOHANG;
+        }
         loop {
             let status = try_with!(
                 waitpid(
                     Some(Pid::from_raw(-self.process_group.as_raw())),
-                    Some(nix::sys::wait::WaitPidFlag::__WALL)
+                    Some(flags)
                 ),
                 "cannot wait for ioctl syscall"
             );
+            if let WaitStatus::StillAlive = status {
+                return Ok(status);
+            }
             if let Some(pid) = status.pid() {
                 let res = self
                     .threads
@@ -336,15 +469,34 @@ impl KvmRunWrapper {
                     thread.is_running = false;
                     return Ok(status);
                 }
+                // a new thread may report its first stop before its parent reports the clone
+                if let WaitStatus::PtraceEvent(_, _, libc::PTRACE_EVENT_STOP) = status {
+                    self.add_thread(pid, false);
+                    return Ok(status);
+                }
             }
         }
     }
 
-    fn process_status(&mut self, status: WaitStatus) -> Result<Option<MmioRw>> {
+    fn process_status(&mut self, status: WaitStatus) -> Result<Option<KvmRunExit>> {
         match status {
             WaitStatus::PtraceSyscall(pid) => {
                 return self.stopped(pid);
             }
+            WaitStatus::PtraceEvent(pid, _, libc::PTRACE_EVENT_CLONE) => {
+                let thread = match self.threads.iter().find(|t| t.ptthread.tid == pid) {
+                    Some(t) => t,
+                    None => bail!("received clone event for unkown process: {}", pid),
+                };
+                let tid = try_with!(
+                    thread.ptthread.event_msg(),
+                    "cannot get tid of thread cloned by {}",
+                    pid
+                );
+                // It is still on its way to the initial stop, which we wait for like for any
+                // other running thread.
+                self.add_thread(Pid::from_raw(tid as libc::pid_t), true);
+            }
             WaitStatus::Exited(tid, status) => {
                 warn!("thread {} exited with: {}", tid, status);
                 self.drop_thread(tid);
@@ -354,6 +506,16 @@ impl KvmRunWrapper {
         Ok(None)
     }
 
+    fn add_thread(&mut self, tid: Pid, is_running: bool) {
+        if self.threads.iter().any(|t| t.ptthread.tid == tid) {
+            return;
+        }
+        debug!("trace new thread {}", tid);
+        let mut thread = Thread::new(ptrace::Thread { tid });
+        thread.is_running = is_running;
+        self.threads.push(thread);
+    }
+
     fn drop_thread(&mut self, tid: Pid) {
         let idx = self
             .threads
@@ -373,7 +535,7 @@ impl KvmRunWrapper {
         }
     }
 
-    fn stopped(&mut self, pid: Pid) -> Result<Option<MmioRw>> {
+    fn stopped(&mut self, pid: Pid) -> Result<Option<KvmRunExit>> {
         let thread: &mut Thread = match self
             .threads
             .iter_mut()
@@ -398,6 +560,7 @@ impl KvmRunWrapper {
 
         if thread.in_syscall {
             trace!("kvm-run enter {}", pid);
+            thread.syscall_arg1 = ioctl_fd;
             return Ok(None);
         } else {
             trace!("kvm-run exit {}", pid);
@@ -415,23 +578,33 @@ impl KvmRunWrapper {
         }
 
         // fulfilled precondition: ioctl(KVM_RUN) just returned
-        let vcpu = match self
-            .vcpus
-            .iter()
-            .find(|vcpu| vcpu.fd_num == ioctl_fd as i32)
-        {
-            Some(vcpu) => vcpu,
-            None => {
-                warn!("Caught ioctl(KVM_RUN) for unknown vcpu_fd {}.", ioctl_fd);
-                return Ok(None);
+        let ioctl_fd = thread.syscall_arg1 as RawFd;
+        let tid = thread.ptthread.tid;
+        if !self.vcpus.iter().any(|vcpu| vcpu.fd_num == ioctl_fd) {
+            let process = self.threads[self.process_idx].ptthread.tid;
+            match VCPU::discover(process, ioctl_fd, &self.vcpus)? {
+                Some(vcpu) => self.vcpus.push(vcpu),
+                None => {
+                    warn!("Caught ioctl(KVM_RUN) for unknown vcpu_fd {}.", ioctl_fd);
+                    return Ok(None);
+                }
             }
-        };
+        }
+        let vcpu = require_with!(
+            self.vcpus.iter().find(|vcpu| vcpu.fd_num == ioctl_fd),
+            "vcpu_fd {} disappeared",
+            ioctl_fd
+        );
         let map_ptr = vcpu.map()?.start as *const kvm_bindings::kvm_run;
         let kvm_run: kvm_bindings::kvm_run =
             hypervisor::memory::process_read(pid, map_ptr.cast::<libc::c_void>())?;
-        let mmio = MmioRw::from(&kvm_run, thread.ptthread.tid, vcpu.map()?.clone());
 
-        Ok(mmio)
+        Ok(Some(KvmRunExit {
+            kvm_run,
+            tid,
+            vcpu_fd: ioctl_fd,
+            vcpu_map: vcpu.map()?.clone(),
+        }))
     }
 
     fn _check_siginfo(thread: &Thread) -> Result<()> {
@@ -0,0 +1,131 @@
+//! Entry point for programs that embed vmsh instead of running the `vmsh` binary.
+//!
+//! A `Vm` is an attached session that runs in a background thread, like the sessions of
+//! `vmsh daemon`. It stays attached until it is detached or dropped:
+//!
+//! ```no_run
+//! # fn run(opts: vmsh::attach::AttachOptions) -> vmsh::result::Result<()> {
+//! let vm = vmsh::vm::Vm::attach(opts)?;
+//! let id = vm.add_block_device(std::path::Path::new("/tmp/disk.img"), true)?;
+//! vm.remove_block_device(id)?;
+//! vm.detach()
+//! # }
+//! ```
+//!
+//! Unlike `vmsh attach`, no signal handlers are installed and nothing is printed; log
+//! messages go to the `log` crate.
+
+use log::error;
+use nix::unistd::Pid;
+use simple_error::simple_error;
+use std::path::Path;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Weak};
+use std::thread::{self, JoinHandle};
+
+use crate::attach::{self, AttachOptions};
+use crate::coredump::{self, CoredumpOptions};
+use crate::devices::DeviceContext;
+use crate::result::{Result, VmshError};
+
+/// A VM vmsh is attached to.
+pub struct Vm {
+    pid: Pid,
+    stop: Sender<()>,
+    devices: Weak<DeviceContext>,
+    session: Option<JoinHandle<Result<()>>>,
+}
+
+impl Vm {
+    /// Attaches to the VM in the hypervisor process `opts.pid` and returns once the devices
+    /// are running. If `opts.command` is not empty, stage2 spawns it in the guest.
+    pub fn attach(opts: AttachOptions) -> Result<Vm> {
+        let pid = opts.pid;
+        let (stop, receiver) = channel();
+        let (started_sender, started) = channel();
+        let sender = stop.clone();
+        let session = thread::Builder::new()
+            .name(format!("vmsh-{}", pid))
+            .spawn(move || {
+                attach::attach_until(&opts, sender, receiver, |devices| {
+                    let _ = started_sender.send(devices);
+                })
+            });
+        let session = match session {
+            Ok(session) => session,
+            Err(e) => return Err(simple_error!("cannot spawn session thread: {}", e).into()),
+        };
+        match started.recv() {
+            Ok(devices) => Ok(Vm {
+                pid,
+                stop,
+                devices,
+                session: Some(session),
+            }),
+            // the session ended before the devices were started
+            Err(_) => match session.join() {
+                Ok(Ok(())) => Err(VmshError::Detached),
+                Ok(Err(e)) => Err(e),
+                Err(_) => Err(simple_error!("session thread panicked").into()),
+            },
+        }
+    }
+
+    /// Pid of the hypervisor
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    fn devices(&self) -> Result<Arc<DeviceContext>> {
+        self.devices.upgrade().ok_or(VmshError::Detached)
+    }
+
+    /// Adds `path` as block device to the running VM and returns its id. Requires
+    /// `hotplug_slots` > 0 in the options of `attach`.
+    pub fn add_block_device(&self, path: &Path, read_only: bool) -> Result<usize> {
+        self.devices()?
+            .add_disk(path, read_only)
+            .map_err(|e| VmshError::Device(e.to_string()))
+    }
+
+    /// Removes a block device added with `add_block_device`.
+    pub fn remove_block_device(&self, id: usize) -> Result<()> {
+        self.devices()?
+            .remove_disk(id)
+            .map_err(|e| VmshError::Device(e.to_string()))
+    }
+
+    /// Writes a coredump of a VM. This does not need an attached session: attaching and
+    /// dumping are both done by ptracing the hypervisor, so a `Vm` of the same hypervisor
+    /// has to be detached first.
+    pub fn coredump(opts: &CoredumpOptions) -> Result<()> {
+        coredump::generate_coredump(opts)
+    }
+
+    /// Stops the command and the devices in the guest and waits until the hypervisor runs on
+    /// its own again.
+    pub fn detach(mut self) -> Result<()> {
+        self.stop_session()
+    }
+
+    fn stop_session(&mut self) -> Result<()> {
+        let session = match self.session.take() {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+        // the session might already be shutting down
+        let _ = self.stop.send(());
+        match session.join() {
+            Ok(res) => res,
+            Err(_) => Err(simple_error!("session thread panicked").into()),
+        }
+    }
+}
+
+impl Drop for Vm {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop_session() {
+            error!("{}", e);
+        }
+    }
+}
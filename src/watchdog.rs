@@ -0,0 +1,214 @@
+//! Watches the hypervisor while vmsh is attached. Threads of vmsh wait for the hypervisor in
+//! `waitpid` or on its sockets, so if it exits or hangs they would wait forever. The watchdog
+//! notices through a pidfd and the states of its threads and stops the session instead.
+
+use log::{error, warn};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::unistd::Pid;
+use simple_error::bail;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::raw::c_int;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::result::Result;
+use crate::tracer::proc;
+
+/// How often the threads of the hypervisor are checked.
+const CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The hypervisor counts as hung once none of its threads ran for this long.
+const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Why the hypervisor cannot continue.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Failure {
+    Exited(Pid),
+    /// None of the threads ran for `STALL_TIMEOUT`, `state` is the one of the first thread.
+    Hung {
+        pid: Pid,
+        state: String,
+    },
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::Exited(pid) => write!(f, "process {} exited", pid),
+            Failure::Hung { pid, state } => write!(
+                f,
+                "process {} did not run for {} seconds (state {})",
+                pid,
+                STALL_TIMEOUT.as_secs(),
+                state
+            ),
+        }
+    }
+}
+
+/// Stops the watchdog when dropped.
+pub struct Watchdog {
+    stop: Arc<AtomicBool>,
+    failure: Arc<Mutex<Option<Failure>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    /// Why the session was stopped, None if the hypervisor is fine.
+    pub fn failure(&self) -> Option<Failure> {
+        self.failure.lock().map_or(None, |failure| failure.clone())
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Requires Linux 5.3, before that the exit is only noticed from the state of the process.
+fn pidfd_open(pid: Pid) -> Result<File> {
+    let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid.as_raw(), 0) } as c_int;
+    if pidfd < 0 {
+        bail!("pidfd_open failed: {}", io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(pidfd) })
+}
+
+/// Whether a thread in this state (`State` in /proc/<pid>/status) does not make progress:
+/// uninterruptible sleep or stopped by a signal. Threads stopped by our tracer (`t`) are fine.
+fn is_stalled(state: &str) -> bool {
+    matches!(state.chars().next(), Some('D') | Some('T'))
+}
+
+fn is_dead(state: &str) -> bool {
+    matches!(state.chars().next(), Some('Z') | Some('X'))
+}
+
+/// The state the hypervisor is stuck in, if none of its threads can run.
+fn stuck_state(pid: Pid) -> Result<Option<String>> {
+    let mut states = vec![];
+    for tid in proc::threads(pid)? {
+        // threads might exit meanwhile
+        if let Ok(state) = proc::task_status(pid, tid, "State") {
+            if !is_stalled(&state) && !is_dead(&state) {
+                return Ok(None);
+            }
+            states.push(state);
+        }
+    }
+    Ok(states.into_iter().next())
+}
+
+/// Returns why the hypervisor cannot continue, once it exited or hung.
+fn watch(pid: Pid, pidfd: Option<&File>, stop: &AtomicBool) -> Option<Failure> {
+    let mut stalled_since: Option<Instant> = None;
+    while !stop.load(Ordering::Acquire) {
+        match pidfd {
+            Some(pidfd) => {
+                let mut fds = [PollFd::new(pidfd.as_raw_fd(), PollFlags::POLLIN)];
+                match poll(&mut fds, CHECK_INTERVAL.as_millis() as c_int) {
+                    Ok(n) if n > 0 => return Some(Failure::Exited(pid)),
+                    Ok(_) | Err(nix::errno::Errno::EINTR) => {}
+                    Err(e) => warn!("cannot poll pidfd of the hypervisor: {}", e),
+                }
+            }
+            None => thread::sleep(CHECK_INTERVAL),
+        }
+        // processes that exited might stay a zombie until their parent reaps them
+        match proc::task_status(pid, pid, "State") {
+            Err(_) => return Some(Failure::Exited(pid)),
+            Ok(state) if is_dead(&state) => return Some(Failure::Exited(pid)),
+            Ok(_) => {}
+        }
+        match stuck_state(pid) {
+            Ok(Some(state)) => {
+                let since = *stalled_since.get_or_insert_with(Instant::now);
+                if since.elapsed() >= STALL_TIMEOUT {
+                    return Some(Failure::Hung { pid, state });
+                }
+            }
+            Ok(None) => stalled_since = None,
+            Err(_) => {}
+        }
+    }
+    None
+}
+
+/// Watches hypervisor `pid` in the background. Once it exited or hung, the reason is stored
+/// for `Watchdog::failure` and a message is sent to `sender`, which stops the session.
+pub fn spawn(pid: Pid, sender: Sender<()>) -> Result<Watchdog> {
+    let pidfd = match pidfd_open(pid) {
+        Ok(pidfd) => Some(pidfd),
+        Err(e) => {
+            warn!("{}, only the process state is watched", e);
+            None
+        }
+    };
+    let stop = Arc::new(AtomicBool::new(false));
+    let failure = Arc::new(Mutex::new(None));
+    let thread_stop = Arc::clone(&stop);
+    let thread_failure = Arc::clone(&failure);
+    let res = thread::Builder::new()
+        .name(String::from("watchdog"))
+        .spawn(move || {
+            let reason = match watch(pid, pidfd.as_ref(), &thread_stop) {
+                Some(reason) => reason,
+                None => return,
+            };
+            error!("hypervisor failed: {}", reason);
+            if let Ok(mut failure) = thread_failure.lock() {
+                *failure = Some(reason);
+            }
+            // vmsh might be stopping already
+            let _ = sender.send(());
+        });
+    let thread = match res {
+        Ok(thread) => thread,
+        Err(e) => bail!("cannot spawn watchdog thread: {}", e),
+    };
+    Ok(Watchdog {
+        stop,
+        failure,
+        thread: Some(thread),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn thread_states() {
+        assert!(is_stalled("D (disk sleep)"));
+        assert!(is_stalled("T (stopped)"));
+        assert!(!is_stalled("t (tracing stop)"));
+        assert!(!is_stalled("S (sleeping)"));
+        assert!(is_dead("Z (zombie)"));
+    }
+
+    #[test]
+    fn hypervisor_exits() {
+        let mut child = Command::new("sleep").arg("100").spawn().unwrap();
+        let pid = Pid::from_raw(child.id() as i32);
+        let (sender, receiver) = channel();
+        let watchdog = spawn(pid, sender).unwrap();
+        assert_eq!(watchdog.failure(), None);
+
+        child.kill().unwrap();
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(watchdog.failure(), Some(Failure::Exited(pid)));
+        child.wait().unwrap();
+    }
+}
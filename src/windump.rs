@@ -0,0 +1,150 @@
+//! Header of a Windows full memory dump (`MEMORY.DMP`, `DUMP_HEADER64`) for `vmsh coredump
+//! --format windows`. The header is followed by all pages of the physical memory runs it lists.
+//!
+//! We cannot find the debugger data block (`KdDebuggerDataBlock`) of the guest from outside,
+//! so WinDbg has to locate the kernel itself (it scans for it, or use `.reload` with symbols).
+
+use simple_error::bail;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cpu::Regs;
+use crate::page_math::page_size;
+use crate::result::Result;
+
+/// `sizeof(DUMP_HEADER64)`, the first page is at this offset
+pub const HEADER_SIZE: usize = 0x2000;
+
+const SIGNATURE: &[u8; 4] = b"PAGE";
+const VALID_DUMP: &[u8; 4] = b"DU64";
+/// Free build of Windows NT
+const MAJOR_VERSION: u32 = 0xf;
+const IMAGE_FILE_MACHINE_AMD64: u32 = 0x8664;
+/// Complete memory dump
+const DUMP_TYPE_FULL: u32 = 1;
+/// CONTEXT_AMD64 | CONTEXT_CONTROL | CONTEXT_INTEGER | CONTEXT_SEGMENTS
+const CONTEXT_FLAGS: u32 = 0x0010_0007;
+/// The header has room for this many runs
+const MAX_RUNS: usize = 43;
+/// Between 1601-01-01 (FILETIME) and 1970-01-01
+const FILETIME_UNIX_OFFSET: u64 = 11_644_473_600;
+
+// offsets in DUMP_HEADER64
+const OFF_MAJOR_VERSION: usize = 0x8;
+const OFF_DIRECTORY_TABLE_BASE: usize = 0x10;
+const OFF_MACHINE_IMAGE_TYPE: usize = 0x30;
+const OFF_NUMBER_PROCESSORS: usize = 0x34;
+const OFF_PHYSICAL_MEMORY_BLOCK: usize = 0x88;
+const OFF_CONTEXT: usize = 0x348;
+const OFF_DUMP_TYPE: usize = 0xf98;
+const OFF_REQUIRED_DUMP_SPACE: usize = 0xfa0;
+const OFF_SYSTEM_TIME: usize = 0xfa8;
+const OFF_COMMENT: usize = 0xfb0;
+
+fn put_u16(buf: &mut [u8], off: usize, val: u16) {
+    buf[off..off + 2].copy_from_slice(&val.to_le_bytes());
+}
+
+fn put_u32(buf: &mut [u8], off: usize, val: u32) {
+    buf[off..off + 4].copy_from_slice(&val.to_le_bytes());
+}
+
+fn put_u64(buf: &mut [u8], off: usize, val: u64) {
+    buf[off..off + 8].copy_from_slice(&val.to_le_bytes());
+}
+
+/// Writes the registers of the first vcpu as x64 `CONTEXT` to `buf`.
+fn write_context(buf: &mut [u8], regs: &Regs) {
+    put_u32(buf, 0x30, CONTEXT_FLAGS);
+    for (off, seg) in &[
+        (0x38, regs.cs),
+        (0x3a, regs.ds),
+        (0x3c, regs.es),
+        (0x3e, regs.fs),
+        (0x40, regs.gs),
+        (0x42, regs.ss),
+    ] {
+        put_u16(buf, *off, *seg as u16);
+    }
+    put_u32(buf, 0x44, regs.eflags as u32);
+    let gprs = [
+        regs.rax, regs.rcx, regs.rdx, regs.rbx, regs.rsp, regs.rbp, regs.rsi, regs.rdi, regs.r8,
+        regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15, regs.rip,
+    ];
+    for (i, val) in gprs.iter().enumerate() {
+        put_u64(buf, 0x78 + i * 8, *val);
+    }
+}
+
+/// Header for a dump of the physical memory `runs` (guest-physical address and size, page
+/// aligned). `regs` and `cr3` are the ones of the first vcpu.
+pub fn header(runs: &[(usize, usize)], cpus: usize, regs: &Regs, cr3: u64) -> Result<Vec<u8>> {
+    if runs.len() > MAX_RUNS {
+        bail!(
+            "windows dumps are limited to {} memory ranges, got {}",
+            MAX_RUNS,
+            runs.len()
+        );
+    }
+    let mut buf = vec![0u8; HEADER_SIZE];
+    buf[0..4].copy_from_slice(SIGNATURE);
+    buf[4..8].copy_from_slice(VALID_DUMP);
+    put_u32(&mut buf, OFF_MAJOR_VERSION, MAJOR_VERSION);
+    put_u64(&mut buf, OFF_DIRECTORY_TABLE_BASE, cr3);
+    put_u32(&mut buf, OFF_MACHINE_IMAGE_TYPE, IMAGE_FILE_MACHINE_AMD64);
+    put_u32(&mut buf, OFF_NUMBER_PROCESSORS, cpus as u32);
+
+    let pages = runs
+        .iter()
+        .map(|(_, size)| size / page_size())
+        .sum::<usize>();
+    put_u32(&mut buf, OFF_PHYSICAL_MEMORY_BLOCK, runs.len() as u32);
+    put_u64(&mut buf, OFF_PHYSICAL_MEMORY_BLOCK + 8, pages as u64);
+    for (i, (addr, size)) in runs.iter().enumerate() {
+        let run = OFF_PHYSICAL_MEMORY_BLOCK + 16 + i * 16;
+        put_u64(&mut buf, run, (addr / page_size()) as u64);
+        put_u64(&mut buf, run + 8, (size / page_size()) as u64);
+    }
+
+    write_context(&mut buf[OFF_CONTEXT..], regs);
+    put_u32(&mut buf, OFF_DUMP_TYPE, DUMP_TYPE_FULL);
+    let dump_size = HEADER_SIZE + pages * page_size();
+    put_u64(&mut buf, OFF_REQUIRED_DUMP_SPACE, dump_size as u64);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    put_u64(
+        &mut buf,
+        OFF_SYSTEM_TIME,
+        (now + FILETIME_UNIX_OFFSET) * 10_000_000,
+    );
+    let comment = b"vmsh coredump";
+    buf[OFF_COMMENT..OFF_COMMENT + comment.len()].copy_from_slice(comment);
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    #[test]
+    fn dump_header() {
+        let regs = Regs {
+            rip: 0xfffff80012345678,
+            rax: 1,
+            cs: 0x10,
+            ..Default::default()
+        };
+        let runs = [(0x1000, 0x9f000), (0x100000, 0x100000)];
+        let buf = header(&runs, 2, &regs, 0x1aa000).unwrap();
+        assert_eq!(&buf[0..8], b"PAGEDU64");
+        let u64_at = |off: usize| u64::from_le_bytes(buf[off..off + 8].try_into().unwrap());
+        assert_eq!(u64_at(OFF_DIRECTORY_TABLE_BASE), 0x1aa000);
+        // number of pages, then the first run
+        assert_eq!(u64_at(OFF_PHYSICAL_MEMORY_BLOCK + 8), 0x9f + 0x100);
+        assert_eq!(u64_at(OFF_PHYSICAL_MEMORY_BLOCK + 16), 1);
+        assert_eq!(u64_at(OFF_CONTEXT + 0x78), 1);
+        assert_eq!(u64_at(OFF_CONTEXT + 0xf8), 0xfffff80012345678);
+        assert!(header(&[(0, 0x1000); MAX_RUNS + 1], 1, &regs, 0).is_err());
+    }
+}
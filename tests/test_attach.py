@@ -1,6 +1,7 @@
 import os
 
 import conftest
+import pytest
 
 from nix import notos_image
 
@@ -50,6 +51,10 @@ def test_attach(
                     )
                     > 0
                 )
+                # the guest kernel lists the module if stage1 used its module loader
+                if "register devices from the module loader" in res.stdout:
+                    modules = vm.ssh_cmd(["cat", "/proc/modules"], check=False)
+                    assert "vmsh_" in modules.stdout
             try:
                 os.kill(vmsh.pid, 0)
             except ProcessLookupError:
@@ -63,6 +68,58 @@ def test_attach(
             assert res.returncode == 0
 
 
+# steps of `Rollback` in src/attach.rs
+ROLLBACK_STEPS = [
+    "stop the hypervisor",
+    "open transfer sockets",
+    "register irqfds",
+    "add the memory slot of stage1",
+    "map stage1 into the guest page tables",
+    "set the registers of stage1",
+    "save the session",
+]
+
+
+@pytest.mark.parametrize("step", ROLLBACK_STEPS)
+def test_attach_rollback(
+    helpers: conftest.Helpers, monkeypatch: pytest.MonkeyPatch, step: str
+) -> None:
+    with helpers.busybox_image() as img, helpers.spawn_qemu(
+        helpers.notos_image()
+    ) as vm:
+        vm.wait_for_ssh()
+        attach = [
+            "attach",
+            "--backing-file",
+            str(img),
+            str(vm.pid),
+            "--",
+            "/bin/sh",
+            "-c",
+            "echo works",
+        ]
+        # debug builds of vmsh fail the attach after this step
+        monkeypatch.setenv("VMSH_FAIL_AT", step)
+        vmsh = helpers.spawn_vmsh_command(attach)
+        monkeypatch.delenv("VMSH_FAIL_AT")
+        with vmsh:
+            vmsh.wait_until_line(
+                f"fail after {step}", lambda line: f"fail after {step}" in line
+            )
+            assert vmsh.wait() != 0
+
+        # the guest continues as if vmsh was never there
+        res = vm.ssh_cmd(["echo", "ping"], check=False)
+        assert res.stdout == "ping\n"
+
+        vmsh = helpers.spawn_vmsh_command(attach)
+        with vmsh:
+            vmsh.wait_until_line(
+                "stage1 driver started",
+                lambda line: "stage1 driver started" in line,
+            )
+
+
 def test_attach_multiple_cpus(helpers: conftest.Helpers) -> None:
     test_attach(helpers=helpers, vcpus=8)
 
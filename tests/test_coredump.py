@@ -49,3 +49,9 @@ def test_coredump(helpers: conftest.Helpers) -> None:
         helpers.run_vmsh_command(["coredump", str(vm.pid), core_path])
         with open(core_path, "rb") as fd:
             check_coredump(fd, qemu_regs, vm)
+
+        sparse_path = os.path.join(temp, "core-sparse")
+        helpers.run_vmsh_command(["coredump", "--sparse", str(vm.pid), sparse_path])
+        with open(sparse_path, "rb") as fd:
+            check_coredump(fd, qemu_regs, vm)
+        assert os.stat(sparse_path).st_blocks <= os.stat(core_path).st_blocks
@@ -2,7 +2,7 @@ import os
 import subprocess
 import time
 from contextlib import contextmanager
-from typing import Iterator, Optional, Tuple
+from typing import Iterator, List, Optional, Tuple
 
 import conftest
 
@@ -114,13 +114,28 @@ def wait_for_tmux_output(tmux_session: str, needle: str) -> None:
         assert False, "Machine takes too long to boot"
 
 
-def hypervisor_test(helpers: conftest.Helpers, flake_name: str, command: str) -> None:
+def child_processes(pid: int) -> List[int]:
+    with open(f"/proc/{pid}/task/{pid}/children") as f:
+        return [int(child) for child in f.read().split()]
+
+
+def hypervisor_test(
+    helpers: conftest.Helpers,
+    flake_name: str,
+    command: str,
+    device_process: bool = False,
+) -> None:
     print(f"test {command}")
     with run_hypervisor(flake_name, command) as (
         pid,
         tmux_session,
     ), helpers.busybox_image() as img:
         wait_for_tmux_output(tmux_session, "Welcome to NixOS")
+        if device_process:
+            # vmsh has to find the VM in the parent process
+            children = child_processes(pid)
+            assert children, f"{command} has no device processes"
+            pid = children[0]
 
         vmsh = helpers.spawn_vmsh_command(
             [
@@ -154,10 +169,18 @@ def test_crosvm(helpers: conftest.Helpers) -> None:
     hypervisor_test(helpers, ".#crosvm-example", "crosvm")
 
 
+def test_crosvm_device_process(helpers: conftest.Helpers) -> None:
+    hypervisor_test(helpers, ".#crosvm-example", "crosvm", device_process=True)
+
+
 def test_kvmtool(helpers: conftest.Helpers) -> None:
     hypervisor_test(helpers, ".#kvmtool-example", "lkvm")
 
 
+def test_cloud_hypervisor(helpers: conftest.Helpers) -> None:
+    hypervisor_test(helpers, ".#cloud-hypervisor-example", "cloud-hypervisor")
+
+
 # also tests qemu
 def test_qemu_and_change_password(helpers: conftest.Helpers) -> None:
     # XXX not portable name